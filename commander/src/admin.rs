@@ -1,22 +1,48 @@
 use crate::admin::AdminCommandOuputMode::HumanReadableShort;
+use crate::group_by::Groups;
+use crate::saved_queries::expand_saved_query;
+use crate::watch::print_refresh;
 use crate::CommanderSyntheticOutput;
+use anyhow::{anyhow, Context};
+use atty::Stream;
+use chrono::{DateTime, Local};
 use clap::Subcommand;
 use colored::Colorize;
-use funtonic::config::CommanderConfig;
+use funtonic::config::{compression_encoding, CommanderConfig};
+use funtonic::crypto::keygen::base64_fingerprint;
 use funtonic::crypto::signed_payload::encode_and_sign;
+use funtonic::data_encoding;
 use funtonic::executor_meta::ExecutorMeta;
-use funtonic::task_server::{AdminDroppedExecutorJsonResponse, AdminListExecutorKeysJsonResponse};
+use funtonic::task_server::{
+    result_checksum, AdminAuthorizedKeyJsonResponse, AdminDroppedExecutorJsonResponse,
+    AdminErrorJsonResponse, AdminListExecutorKeysJsonResponse,
+    AdminRejectedExecutorKeysJsonResponse, AdminRevokedExecutorKeyJsonResponse,
+    AdminRunningTaskJsonResponse, AdminVerifyTaskJsonResponse, AdminWhoAmIJsonResponse,
+    VerifiedWith,
+};
 use funtonic::tokio;
+use funtonic::tonic::codec::CompressionEncoding;
 use funtonic::tonic::transport::Channel;
+use funtonic::tonic::Code;
+use funtonic::version::{Version, VersionReq};
 use grpc_service::grpc_protocol::admin_request::RequestType;
 use grpc_service::grpc_protocol::admin_request_response::ResponseKind;
+use grpc_service::grpc_protocol::admin_service_client::AdminServiceClient;
 use grpc_service::grpc_protocol::commander_service_client::CommanderServiceClient;
-use grpc_service::grpc_protocol::{AdminRequest, Empty};
+use grpc_service::grpc_protocol::set_executor_tag::Operation;
+use grpc_service::grpc_protocol::{
+    AdminError, AdminErrorCode, AdminRequest, Empty, FetchResultChunk, PublicKey, ReleaseResult,
+    ResultHandle, SetExecutorTag, SetFailpoint,
+};
 use prettytable::format::consts::*;
 use prettytable::*;
+use rustyline::DefaultEditor;
 use std::collections::BTreeMap;
 use std::str::FromStr;
-use tokio::time::Duration;
+use std::time::Duration;
+
+/// Chunks requested when fetching a large response, the taskserver may send smaller ones
+const RESULT_CHUNK_BYTES: u64 = 1024 * 1024;
 
 #[derive(Subcommand, Debug)]
 #[command(rename_all = "kebab")]
@@ -24,10 +50,35 @@ pub enum AdminCommand {
     /// Get connected executors and their meta as json
     ListConnectedExecutors {
         query: Option<String>,
+        /// Only display these tags, `:` separated paths into nested tags (`env,os:type,roles`)
+        #[arg(long = "fields", value_delimiter = ',')]
+        fields: Vec<String>,
+        /// Do not display these tags
+        #[arg(long = "hide-fields", value_delimiter = ',')]
+        hide_fields: Vec<String>,
+        /// Count the executors by value of these tags (`os:type`) instead of listing them
+        #[arg(long = "group-by")]
+        group_by: Vec<String>,
+        /// Refresh the listing every SECS seconds, highlighting the executors which appeared or
+        /// disappeared, until Ctrl-C
+        #[arg(
+            long = "watch",
+            value_name = "SECS",
+            conflicts_with = "group_by",
+            value_parser = clap::value_parser!(u64).range(1..)
+        )]
+        watch: Option<u64>,
     },
     /// Get all known executors and their meta as json
     ListKnownExecutors {
         query: Option<String>,
+        /// Count the executors by value of these tags (`os:type`) instead of listing them
+        #[arg(long = "group-by")]
+        group_by: Vec<String>,
+        /// Only list the executors whose version matches this requirement, eg: `<0.5.0` or
+        /// `>=0.3, <0.5`
+        #[arg(long = "outdated", value_name = "SEMVER_REQ")]
+        outdated: Option<VersionReq>,
     },
     /// Get all running tasks as json
     ListRunningTasks,
@@ -35,7 +86,7 @@ pub enum AdminCommand {
     ///
     /// Remove the executor from the taskserver database, close drop the communication channel if present
     /// this should trigger a reconnect of the executor, and thus an update of the executor's metadata
-    /// If the executor is not alive it will be forgotten.
+    /// If the executor is not alive it will be forgotten. Its overridden tags are forgotten too.
     DropExecutor {
         /// the client_id of the executor to drop
         query: String,
@@ -43,8 +94,27 @@ pub enum AdminCommand {
     /// List executors public keys
     ListExecutorKeys,
     /// Approve an executor public key (*) can be used to approve all pending keys
+    ///
+    /// A key conflicting with the trusted key of the executor (reinstalled host or impersonation)
+    /// is only approved with --force.
     ApproveExecutorKey {
         executor: String,
+        /// replace the trusted key of the executor by its conflicting pending key
+        #[arg(long)]
+        force: bool,
+    },
+    /// Reject a pending executor public key, (*) can be used to reject all pending keys
+    ///
+    /// The executor key is pending again the next time it tries to register.
+    RejectExecutorKey {
+        executor: String,
+    },
+    /// Revoke a trusted executor public key
+    ///
+    /// The executor is disconnected, its key must be approved again before it can register.
+    RevokeExecutorKey {
+        /// the client_id of the executor
+        executor: String,
     },
     /// List authorized keys
     ///
@@ -52,10 +122,68 @@ pub enum AdminCommand {
     /// configuration file & is completed with authorized keys from each executors that connects to it
     ListAuthorizedKeys,
     ListAdminAuthorizedKeys,
+    /// Authorize a key on the taskserver, the taskserver keeps it across restarts
+    AddAuthorizedKey {
+        key_id: String,
+        /// Public key (base64 encoded)
+        public_key: String,
+    },
+    /// Remove a key from the taskserver authorized keys
+    ///
+    /// Executors may report the key again when they register if they still authorize it.
+    RemoveAuthorizedKey {
+        key_id: String,
+    },
+    /// Verify the executor signatures of a task results
+    ///
+    /// The taskserver must retain signatures (retain_signatures: true), results are verified
+    /// against the current executor key or the keys it used before a new one was approved.
+    VerifyTask {
+        task_id: String,
+    },
+    /// Override a tag of the known executors matching the query
+    ///
+    /// The override is kept by the taskserver and applied again each time the executor
+    /// registers: it is not lost when the executor reports its own tags. The tag is removed if
+    /// no value is given. Overriding a tag replaces the overrides of the tags nested in it.
+    SetTag {
+        query: String,
+        /// `:` separated path of the tag, eg: `os_info:type`
+        path: String,
+        value: Option<String>,
+        /// do not ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Inject failures in the taskserver, for resilience testing
+    ///
+    /// Requires a taskserver built with the `failpoints` feature. Failpoints: drop_executor_channel
+    /// (N: Nth task dispatch), delay_task_execution (milliseconds), launch_task_internal
+    /// (N: next N launches); `off` disables a failpoint.
+    SetFailpoint {
+        name: String,
+        config: String,
+    },
+    /// Describe the signing key as known by the taskserver
+    ///
+    /// Works with regular authorized keys too: they are only told what they can do.
+    #[command(name = "whoami")]
+    WhoAmI,
+    /// Diagnose the deployment: taskserver connection, keys, matching executors, their clocks &
+    /// versions
+    ///
+    /// Each check passes, warns or fails; the command fails if any check fails. The executors
+    /// report their clock through a diagnostic task, older executors do not answer it.
+    Doctor {
+        query: String,
+        /// Wait this long for the executors to report their clock, in seconds
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+    },
 }
 
 #[derive(thiserror::Error, Debug)]
-#[error("Output mode must be one of json, pretty-json or human-readable")]
+#[error("Output mode must be one of json, pretty-json, human-readable, plain or csv")]
 pub struct InvalidOutputMode;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -64,6 +192,11 @@ pub enum AdminCommandOuputMode {
     PrettyJson,
     HumanReadableShort,
     HumanReadableLong,
+    /// One client_id (or task_id) per line, human readable for the commands not listing executors
+    /// or tasks
+    Plain,
+    /// Human readable columns, for the commands listing executors or tasks
+    Csv,
 }
 
 impl FromStr for AdminCommandOuputMode {
@@ -76,17 +209,87 @@ impl FromStr for AdminCommandOuputMode {
             "pretty-json" | "pjs" => Ok(PrettyJson),
             "human-readable" | "hr" => Ok(HumanReadableShort),
             "human-readable-long" | "hrl" => Ok(HumanReadableLong),
+            "plain" => Ok(Plain),
+            "csv" => Ok(Csv),
             _ => Err(InvalidOutputMode),
         }
     }
 }
 
 impl AdminCommand {
+    /// Expand the `@name` saved queries
+    fn with_saved_queries(
+        mut self,
+        saved_queries: &BTreeMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        match &mut self {
+            AdminCommand::ListConnectedExecutors {
+                query: Some(query), ..
+            }
+            | AdminCommand::ListKnownExecutors {
+                query: Some(query), ..
+            }
+            | AdminCommand::DropExecutor { query }
+            | AdminCommand::SetTag { query, .. } => {
+                *query = expand_saved_query(query, saved_queries)?;
+            }
+            _ => (),
+        }
+        Ok(self)
+    }
+
+    /// Keep the executors whose version matches `--outdated`
+    fn filter_outdated(&self, raw_json: String) -> Result<String, serde_json::Error> {
+        match self {
+            AdminCommand::ListKnownExecutors {
+                outdated: Some(outdated),
+                ..
+            } => {
+                let mut executors: BTreeMap<String, ExecutorMeta> =
+                    serde_json::from_str(&raw_json)?;
+                executors.retain(|_, meta| outdated.matches_str(meta.version()));
+                serde_json::to_string(&executors)
+            }
+            _ => Ok(raw_json),
+        }
+    }
+
+    /// Keep or hide the `--fields` & `--hide-fields` tags of the listed executors. The executors
+    /// are grouped by `--group-by` on all their tags, projected away or not.
+    fn project_tags(&self, raw_json: String) -> Result<String, serde_json::Error> {
+        match self {
+            AdminCommand::ListConnectedExecutors {
+                fields,
+                hide_fields,
+                group_by,
+                ..
+            } if group_by.is_empty() && (!fields.is_empty() || !hide_fields.is_empty()) => {
+                let mut executors: BTreeMap<String, ExecutorMeta> =
+                    serde_json::from_str(&raw_json)?;
+                for meta in executors.values_mut() {
+                    if !fields.is_empty() {
+                        meta.project_tags(fields);
+                    }
+                    meta.hide_tags(hide_fields);
+                }
+                serde_json::to_string(&executors)
+            }
+            _ => Ok(raw_json),
+        }
+    }
+
     fn display_formatted_output(
         &self,
         raw_json: &str,
         output_mode: AdminCommandOuputMode,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        if let AdminCommand::ListConnectedExecutors { group_by, .. }
+        | AdminCommand::ListKnownExecutors { group_by, .. } = self
+        {
+            if !group_by.is_empty() {
+                return display_groups(raw_json, group_by, output_mode);
+            }
+        }
         match output_mode {
             AdminCommandOuputMode::Json => println!("{}", raw_json),
             AdminCommandOuputMode::PrettyJson => println!(
@@ -96,10 +299,16 @@ impl AdminCommand {
                     &raw_json
                 )?)?
             ),
+            AdminCommandOuputMode::Plain | AdminCommandOuputMode::Csv => {
+                match self.scripting_output(raw_json, output_mode)? {
+                    Some(output) => print!("{}", output),
+                    None => self.display_formatted_output(raw_json, HumanReadableShort)?,
+                }
+            }
             AdminCommandOuputMode::HumanReadableLong
             | AdminCommandOuputMode::HumanReadableShort => match self {
-                AdminCommand::ListConnectedExecutors { query }
-                | AdminCommand::ListKnownExecutors { query } => {
+                AdminCommand::ListConnectedExecutors { query, .. }
+                | AdminCommand::ListKnownExecutors { query, .. } => {
                     println!(
                         "Executors matching query: {}",
                         query.as_ref().unwrap_or(&"*".to_string())
@@ -107,19 +316,53 @@ impl AdminCommand {
                     let executors: BTreeMap<String, ExecutorMeta> =
                         serde_json::from_str(&raw_json)?;
                     if executors.len() > 0 {
+                        let latest = latest_version(executors.values());
                         let mut table = Table::new();
                         if output_mode == HumanReadableShort {
                             table.set_format(*FORMAT_NO_BORDER_LINE_SEPARATOR);
                         }
-                        table.set_titles(row!["client_id", "version", "meta"]);
+                        table.set_titles(row![
+                            "client_id",
+                            "instance",
+                            "version",
+                            "connected",
+                            "uptime",
+                            "meta"
+                        ]);
                         for (client_id, meta) in &executors {
-                            let version = meta.version();
+                            let mut client_id = if meta.overridden() {
+                                format!("{} {}", client_id.green(), "(overridden)".yellow())
+                            } else {
+                                client_id.green().to_string()
+                            };
+                            if meta.duplicate_registrations() > 0 {
+                                client_id = format!(
+                                    "{} {}",
+                                    client_id,
+                                    format!(
+                                        "({} duplicates rejected)",
+                                        meta.duplicate_registrations()
+                                    )
+                                    .red()
+                                );
+                            }
+                            let version = match (meta.version().parse::<Version>(), &latest) {
+                                (Ok(version), Some(latest)) if &version < latest => {
+                                    meta.version().red().to_string()
+                                }
+                                _ => meta.version().to_string(),
+                            };
+                            let instance = meta.short_instance_id().unwrap_or_default().to_string();
+                            let connected = humanized_elapsed_time(meta.connected_at());
+                            let uptime = humanized_elapsed_time(meta.started_at());
                             let meta = if output_mode == HumanReadableShort {
                                 serde_json::to_string(&meta.tags())?
                             } else {
                                 serde_yaml::to_string(&meta.tags())?[4..].to_string()
                             };
-                            table.add_row(row![client_id.green(), version, meta]);
+                            table.add_row(row![
+                                client_id, instance, version, connected, uptime, meta
+                            ]);
                         }
                         table.printstd();
                         println!("Found {} executors", executors.len().to_string().green());
@@ -128,10 +371,24 @@ impl AdminCommand {
                     }
                 }
                 AdminCommand::ListRunningTasks => {
-                    let tokens: Vec<String> = serde_json::from_str(&raw_json)?;
-                    for token in tokens {
-                        println!("{}", token);
+                    let tasks: BTreeMap<String, AdminRunningTaskJsonResponse> =
+                        serde_json::from_str(raw_json)?;
+                    if !tasks.is_empty() {
+                        let mut table = Table::new();
+                        if output_mode == HumanReadableShort {
+                            table.set_format(*FORMAT_NO_BORDER_LINE_SEPARATOR);
+                        }
+                        table.set_titles(row!["task_id", "client_id", "age"]);
+                        for (task_id, task) in &tasks {
+                            table.add_row(row![
+                                task_id,
+                                task.client_id.green(),
+                                humanize_duration(chrono::Duration::seconds(task.age_secs as i64))
+                            ]);
+                        }
+                        table.printstd();
                     }
+                    println!("Found {} running tasks", tasks.len().to_string().green());
                 }
                 AdminCommand::DropExecutor { query } => {
                     let dropped_executors: BTreeMap<String, AdminDroppedExecutorJsonResponse> =
@@ -165,27 +422,73 @@ impl AdminCommand {
                     println!("{}", "Trusted executors".green());
                     let mut table = Table::new();
                     table.set_format(*FORMAT_NO_BORDER_LINE_SEPARATOR);
-                    table.set_titles(row!["client_id", "key"]);
+                    table.set_titles(row!["client_id", "fingerprint", "key"]);
                     for (client_id, key) in &keys.trusted_executor_keys {
-                        table.add_row(row![client_id.green(), key]);
+                        table.add_row(row![client_id.green(), key_fingerprint(key), key]);
                     }
                     table.printstd();
 
                     println!("{}", "Waiting for approval executors".red());
                     let mut table = Table::new();
                     table.set_format(*FORMAT_NO_BORDER_LINE_SEPARATOR);
-                    table.set_titles(row!["client_id", "key"]);
+                    table.set_titles(row!["client_id", "fingerprint", "key"]);
                     for (client_id, key) in &keys.unapproved_executor_keys {
-                        table.add_row(row![client_id.red(), key]);
+                        table.add_row(row![client_id.red(), key_fingerprint(key), key]);
                     }
                     table.printstd();
+
+                    if !keys.conflicting_executor_keys.is_empty() {
+                        println!(
+                            "{}",
+                            "Conflicting keys (approve with --force to replace the trusted key)"
+                                .red()
+                                .bold()
+                        );
+                        let mut table = Table::new();
+                        table.set_format(*FORMAT_NO_BORDER_LINE_SEPARATOR);
+                        table.set_titles(row![
+                            "client_id",
+                            "fingerprint",
+                            "trusted fingerprint",
+                            "detected_at"
+                        ]);
+                        for (client_id, conflict) in &keys.conflicting_executor_keys {
+                            table.add_row(row![
+                                client_id.red(),
+                                key_fingerprint(&conflict.key).red(),
+                                key_fingerprint(&conflict.trusted_key),
+                                conflict.detected_at
+                            ]);
+                        }
+                        table.printstd();
+                    }
+                }
+                AdminCommand::ApproveExecutorKey { .. } => {}
+                // displayed by the doctor itself
+                AdminCommand::Doctor { .. } => {}
+                AdminCommand::RejectExecutorKey { .. } => {
+                    let rejected: AdminRejectedExecutorKeysJsonResponse =
+                        serde_json::from_str(raw_json)?;
+                    if rejected.rejected.is_empty() {
+                        println!("No pending key");
+                    }
+                    for client_id in &rejected.rejected {
+                        println!("Pending key of {} rejected", client_id.red());
+                    }
+                }
+                AdminCommand::RevokeExecutorKey { executor } => {
+                    let revoked: AdminRevokedExecutorKeyJsonResponse =
+                        serde_json::from_str(raw_json)?;
+                    println!(
+                        "Key of {} revoked, connected: {}",
+                        executor.red(),
+                        colored_bool(revoked.removed_from_connected)
+                    );
                 }
-                AdminCommand::ApproveExecutorKey {
-                    executor: _executor,
-                } => {}
 
                 AdminCommand::ListAuthorizedKeys | AdminCommand::ListAdminAuthorizedKeys => {
-                    let keys: BTreeMap<String, String> = serde_json::from_str(&raw_json)?;
+                    let keys: BTreeMap<String, AdminAuthorizedKeyJsonResponse> =
+                        serde_json::from_str(raw_json)?;
                     let title = match self {
                         AdminCommand::ListAuthorizedKeys => "Authorized Keys",
                         AdminCommand::ListAdminAuthorizedKeys => "Admin Authorized Keys",
@@ -194,17 +497,272 @@ impl AdminCommand {
                     println!("{}", title.green());
                     let mut table = Table::new();
                     table.set_format(*FORMAT_NO_BORDER_LINE_SEPARATOR);
-                    table.set_titles(row!["key_id", "key"]);
+                    table.set_titles(row!["key_id", "key", "expires_at", "allowed_queries"]);
                     for (key_id, key) in &keys {
-                        table.add_row(row![key_id.green(), key]);
+                        match key {
+                            AdminAuthorizedKeyJsonResponse::Key(key) => {
+                                table.add_row(row![key_id.green(), key, "never", "*"]);
+                            }
+                            AdminAuthorizedKeyJsonResponse::Detailed {
+                                key,
+                                expires_at,
+                                allowed_queries,
+                            } => {
+                                let expires_at = match expires_at {
+                                    Some(expires_at) => expires_at.yellow(),
+                                    None => "never".normal(),
+                                };
+                                let allowed_queries = match allowed_queries {
+                                    Some(queries) => queries.join(" or ").yellow(),
+                                    None => "*".normal(),
+                                };
+                                table.add_row(row![
+                                    key_id.green(),
+                                    key,
+                                    expires_at,
+                                    allowed_queries
+                                ]);
+                            }
+                        }
                     }
                     table.printstd();
                 }
+                AdminCommand::VerifyTask { task_id } => {
+                    let report: AdminVerifyTaskJsonResponse = serde_json::from_str(raw_json)?;
+                    println!("Task {} on {}", task_id, report.client_id.green());
+                    let mut table = Table::new();
+                    if output_mode == HumanReadableShort {
+                        table.set_format(*FORMAT_NO_BORDER_LINE_SEPARATOR);
+                    }
+                    table.set_titles(row!["key_id", "signature", "result"]);
+                    for payload in &report.payloads {
+                        let signature = match (&payload.verified_with, &payload.error) {
+                            (Some(VerifiedWith::CurrentKey), _) => "valid".green().to_string(),
+                            (Some(VerifiedWith::ArchivedKey { replaced_at }), _) => {
+                                format!("{} (key replaced on {})", "valid".green(), replaced_at)
+                            }
+                            (None, error) => format!(
+                                "{}: {}",
+                                "INVALID".red(),
+                                error.as_deref().unwrap_or("unknown error")
+                            ),
+                        };
+                        table.add_row(row![
+                            payload.key_id,
+                            signature,
+                            payload.execution_result.as_deref().unwrap_or("")
+                        ]);
+                    }
+                    table.printstd();
+                    if report.verified {
+                        println!("{}", "All results verified".green());
+                    } else {
+                        println!("{}", "Some results cannot be verified!".red());
+                    }
+                }
+                AdminCommand::SetFailpoint { name, config } => {
+                    println!("Failpoint {} set to {}", name, config);
+                }
+                AdminCommand::AddAuthorizedKey { key_id, .. } => {
+                    println!("Key {} authorized", key_id.green());
+                }
+                AdminCommand::RemoveAuthorizedKey { key_id } => {
+                    println!("Key {} removed", key_id.red());
+                }
+                AdminCommand::WhoAmI => {
+                    let whoami: AdminWhoAmIJsonResponse = serde_json::from_str(raw_json)?;
+                    let mut table = Table::new();
+                    table.set_format(*FORMAT_NO_BORDER_LINE_SEPARATOR);
+                    table.add_row(row!["key_id", whoami.key_id.green()]);
+                    table.add_row(row!["authorized", colored_bool(whoami.authorized)]);
+                    table.add_row(row!["admin", colored_bool(whoami.admin)]);
+                    table.add_row(row!["allowed", whoami.allowed.join(", ")]);
+                    if let Some(allowed_queries) = &whoami.allowed_queries {
+                        table.add_row(row!["allowed queries", allowed_queries.join(", ")]);
+                    }
+                    if let (Some(version), Some(protocol_version)) =
+                        (&whoami.version, &whoami.protocol_version)
+                    {
+                        table.add_row(row![
+                            "taskserver",
+                            format!("{} (protocol {})", version, protocol_version)
+                        ]);
+                    }
+                    table.printstd();
+                }
+                AdminCommand::SetTag { path, value, .. } => {
+                    let client_ids: Vec<String> = serde_json::from_str(raw_json)?;
+                    for client_id in &client_ids {
+                        println!("{}", client_id.green());
+                    }
+                    let operation = match value {
+                        Some(value) => format!("set to {}", value),
+                        None => "removed".to_string(),
+                    };
+                    println!(
+                        "Tag {} {} on {} executors",
+                        path,
+                        operation,
+                        client_ids.len().to_string().green()
+                    );
+                }
             },
         }
 
         Ok(())
     }
+
+    /// `plain` & `csv` outputs of the commands listing executors or running tasks, None for the
+    /// other commands
+    fn scripting_output(
+        &self,
+        raw_json: &str,
+        output_mode: AdminCommandOuputMode,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let csv = output_mode == AdminCommandOuputMode::Csv;
+        let rows: Vec<Vec<String>> = match self {
+            AdminCommand::ListConnectedExecutors { .. }
+            | AdminCommand::ListKnownExecutors { .. } => {
+                let executors: BTreeMap<String, ExecutorMeta> = serde_json::from_str(raw_json)?;
+                let mut rows = vec![];
+                for (client_id, meta) in executors {
+                    rows.push(vec![
+                        client_id,
+                        meta.version().to_string(),
+                        meta.connected_at().unwrap_or_default().to_string(),
+                        meta.started_at().unwrap_or_default().to_string(),
+                        serde_json::to_string(meta.tags())?,
+                    ]);
+                }
+                rows
+            }
+            AdminCommand::ListRunningTasks => {
+                let tasks: BTreeMap<String, AdminRunningTaskJsonResponse> =
+                    serde_json::from_str(raw_json)?;
+                tasks
+                    .into_iter()
+                    .map(|(task_id, task)| vec![task_id, task.client_id, task.age_secs.to_string()])
+                    .collect()
+            }
+            AdminCommand::DropExecutor { .. } => {
+                let dropped_executors: BTreeMap<String, AdminDroppedExecutorJsonResponse> =
+                    serde_json::from_str(raw_json)?;
+                dropped_executors
+                    .into_iter()
+                    .map(|(client_id, dropped_status)| {
+                        vec![
+                            client_id,
+                            dropped_status.removed_from_known.to_string(),
+                            dropped_status.removed_from_connected.to_string(),
+                        ]
+                    })
+                    .collect()
+            }
+            _ => return Ok(None),
+        };
+        let mut output = String::new();
+        if csv {
+            let titles: &[&str] = match self {
+                AdminCommand::DropExecutor { .. } => &["client_id", "known", "connected"],
+                AdminCommand::ListRunningTasks => &["task_id", "client_id", "age_secs"],
+                // rfc3339 dates rather than the elapsed times of the table
+                _ => &["client_id", "version", "connected_at", "started_at", "meta"],
+            };
+            output.push_str(&titles.join(","));
+            output.push('\n');
+        }
+        for row in rows {
+            if csv {
+                let fields: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+                output.push_str(&fields.join(","));
+            } else {
+                output.push_str(&row[0]);
+            }
+            output.push('\n');
+        }
+        Ok(Some(output))
+    }
+}
+
+/// Executors counts by value of the `group_by` tags, instead of the executors listing
+fn display_groups(
+    raw_json: &str,
+    group_by: &[String],
+    output_mode: AdminCommandOuputMode,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let executors: BTreeMap<String, ExecutorMeta> = serde_json::from_str(raw_json)?;
+    let groups = Groups::of(executors.values(), group_by);
+    match output_mode {
+        AdminCommandOuputMode::Json => println!("{}", serde_json::to_string(&groups)?),
+        AdminCommandOuputMode::PrettyJson => {
+            println!("{}", serde_json::to_string_pretty(&groups)?)
+        }
+        AdminCommandOuputMode::Csv => {
+            let titles: Vec<&str> = group_by.iter().map(String::as_str).collect();
+            println!("{},count", titles.join(","));
+            for row in groups.rows() {
+                let fields: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+                println!("{}", fields.join(","));
+            }
+        }
+        AdminCommandOuputMode::Plain
+        | AdminCommandOuputMode::HumanReadableShort
+        | AdminCommandOuputMode::HumanReadableLong => groups.print(group_by),
+    }
+    Ok(())
+}
+
+/// Quoted when needed, RFC 4180 style
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Error reported by the taskserver while handling an admin command
+#[derive(thiserror::Error, Debug)]
+#[error("{}", .0.message)]
+pub struct AdminCommandError(pub AdminError);
+
+impl AdminCommandError {
+    /// Process exit code matching the kind of error
+    ///
+    /// - 1: unknown error (or taskserver too old to send structured errors)
+    /// - 2: invalid query or request
+    /// - 3: permission denied
+    /// - 4: executor or key not found
+    /// - 5: taskserver internal error
+    pub fn exit_code(&self) -> i32 {
+        match self.0.code() {
+            AdminErrorCode::Unknown => 1,
+            AdminErrorCode::InvalidQuery | AdminErrorCode::InvalidRequest => 2,
+            AdminErrorCode::PermissionDenied => 3,
+            AdminErrorCode::NotFound => 4,
+            AdminErrorCode::Internal => 5,
+        }
+    }
+
+    fn display(&self, output_mode: AdminCommandOuputMode) -> Result<(), serde_json::Error> {
+        let json = AdminErrorJsonResponse::from(&self.0);
+        match output_mode {
+            AdminCommandOuputMode::Json => println!("{}", serde_json::to_string(&json)?),
+            AdminCommandOuputMode::PrettyJson => {
+                println!("{}", serde_json::to_string_pretty(&json)?)
+            }
+            AdminCommandOuputMode::HumanReadableLong
+            | AdminCommandOuputMode::HumanReadableShort
+            | AdminCommandOuputMode::Plain
+            | AdminCommandOuputMode::Csv => {
+                eprintln!("{} ({})", json.message.red(), json.code);
+                for (key, value) in &json.details {
+                    eprintln!("  {}: {}", key, value);
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 fn colored_bool(b: bool) -> String {
@@ -214,20 +772,27 @@ fn colored_bool(b: bool) -> String {
     }
 }
 
+/// Same fingerprint as `utils show-public-key`
+fn key_fingerprint(base64_key: &str) -> String {
+    base64_fingerprint(base64_key).unwrap_or_else(|_| "invalid key".red().to_string())
+}
+
+/// `channel` is connected to the admin listener of the taskserver
 pub async fn handle_admin_command(
-    mut client: CommanderServiceClient<Channel>,
+    channel: Channel,
     commander_config: &CommanderConfig,
     admin_command: AdminCommand,
     output_mode: AdminCommandOuputMode,
 ) -> Result<CommanderSyntheticOutput, Box<dyn std::error::Error>> {
+    let admin_command = admin_command.with_saved_queries(&commander_config.saved_queries)?;
     // grpc prost typing is just awful piece of crap.
     let request = match &admin_command {
-        AdminCommand::ListConnectedExecutors { query } => AdminRequest {
+        AdminCommand::ListConnectedExecutors { query, .. } => AdminRequest {
             request_type: Some(RequestType::ListConnectedExecutors(
                 query.clone().unwrap_or("*".into()),
             )),
         },
-        AdminCommand::ListKnownExecutors { query } => AdminRequest {
+        AdminCommand::ListKnownExecutors { query, .. } => AdminRequest {
             request_type: Some(RequestType::ListKnownExecutors(
                 query.clone().unwrap_or("*".into()),
             )),
@@ -242,8 +807,18 @@ pub async fn handle_admin_command(
         AdminCommand::ListExecutorKeys => AdminRequest {
             request_type: Some(RequestType::ListExecutorKeys(Empty {})),
         },
-        AdminCommand::ApproveExecutorKey { executor } => AdminRequest {
-            request_type: Some(RequestType::ApproveExecutorKey(executor.clone())),
+        AdminCommand::ApproveExecutorKey { executor, force } => AdminRequest {
+            request_type: Some(if *force {
+                RequestType::ForceApproveExecutorKey(executor.clone())
+            } else {
+                RequestType::ApproveExecutorKey(executor.clone())
+            }),
+        },
+        AdminCommand::RejectExecutorKey { executor } => AdminRequest {
+            request_type: Some(RequestType::RejectExecutorKey(executor.clone())),
+        },
+        AdminCommand::RevokeExecutorKey { executor } => AdminRequest {
+            request_type: Some(RequestType::RevokeExecutorKey(executor.clone())),
         },
         AdminCommand::ListAuthorizedKeys => AdminRequest {
             request_type: Some(RequestType::ListAuthorizedKeys(Empty {})),
@@ -251,23 +826,429 @@ pub async fn handle_admin_command(
         AdminCommand::ListAdminAuthorizedKeys => AdminRequest {
             request_type: Some(RequestType::ListAdminAuthorizedKeys(Empty {})),
         },
+        AdminCommand::VerifyTask { task_id } => AdminRequest {
+            request_type: Some(RequestType::VerifyTask(task_id.clone())),
+        },
+        AdminCommand::SetTag {
+            query,
+            path,
+            value,
+            yes,
+        } => {
+            if !yes {
+                let known = send_admin_request(
+                    &channel,
+                    commander_config,
+                    AdminRequest {
+                        request_type: Some(RequestType::ListKnownExecutors(query.clone())),
+                    },
+                    Some(output_mode),
+                )
+                .await?;
+                let count = serde_json::from_str::<BTreeMap<String, ExecutorMeta>>(&known)?.len();
+                confirm_set_tag(path, count)?;
+            }
+            AdminRequest {
+                request_type: Some(RequestType::SetExecutorTag(SetExecutorTag {
+                    query: query.clone(),
+                    tag_path: path.clone(),
+                    operation: Some(match value {
+                        Some(value) => Operation::Value(value.clone()),
+                        None => Operation::Remove(Empty {}),
+                    }),
+                })),
+            }
+        }
+        AdminCommand::SetFailpoint { name, config } => AdminRequest {
+            request_type: Some(RequestType::SetFailpoint(SetFailpoint {
+                name: name.clone(),
+                config: config.clone(),
+            })),
+        },
+        AdminCommand::AddAuthorizedKey { key_id, public_key } => AdminRequest {
+            request_type: Some(RequestType::AddAuthorizedKey(PublicKey {
+                key_id: key_id.clone(),
+                key_bytes: data_encoding::BASE64
+                    .decode(public_key.as_bytes())
+                    .context("Unable to decode base64 encoded key")?,
+                expires_at_secs: 0,
+            })),
+        },
+        AdminCommand::RemoveAuthorizedKey { key_id } => AdminRequest {
+            request_type: Some(RequestType::RemoveAuthorizedKey(key_id.clone())),
+        },
+        AdminCommand::WhoAmI => AdminRequest {
+            request_type: Some(RequestType::WhoAmI(Empty {})),
+        },
+        AdminCommand::Doctor { .. } => {
+            return Err(anyhow!("The doctor is not a single admin request").into())
+        }
     };
 
-    let request = funtonic::tonic::Request::new(encode_and_sign(
+    if let AdminCommand::ListConnectedExecutors {
+        query,
+        watch: Some(interval_secs),
+        ..
+    } = &admin_command
+    {
+        let query = query.as_deref().unwrap_or("*");
+        let mut previous: Option<BTreeMap<String, ExecutorMeta>> = None;
+        let mut j = String::new();
+        loop {
+            // signed again on each refresh, a signed payload is only valid for a while
+            let refresh = send_admin_request(
+                &channel,
+                commander_config,
+                request.clone(),
+                Some(output_mode),
+            );
+            let refreshed = tokio::select! {
+                refreshed = refresh => refreshed,
+                _ = tokio::signal::ctrl_c() => break,
+            };
+            // eg: the taskserver is restarting, retried on the next tick
+            match refreshed.and_then(|refreshed| Ok(admin_command.project_tags(refreshed)?)) {
+                Ok(refreshed) => {
+                    j = refreshed;
+                    let current: BTreeMap<String, ExecutorMeta> = serde_json::from_str(&j)?;
+                    print_refresh(query, *interval_secs, previous.as_ref(), &current)?;
+                    previous = Some(current);
+                }
+                Err(e) => eprintln!(
+                    "{} {}",
+                    format!("Unable to refresh, retrying in {}s:", interval_secs).red(),
+                    e
+                ),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(*interval_secs)) => {}
+                _ = tokio::signal::ctrl_c() => break,
+            }
+        }
+        return Ok(CommanderSyntheticOutput::Admin(j));
+    }
+
+    let j = send_admin_request(&channel, commander_config, request, Some(output_mode)).await?;
+    let j = admin_command.project_tags(admin_command.filter_outdated(j)?)?;
+    admin_command.display_formatted_output(&j, output_mode)?;
+    Ok(CommanderSyntheticOutput::Admin(j))
+}
+
+/// Sign & send an admin request, returns the json response. Errors reported by the taskserver
+/// are displayed according to `output_mode`, if any.
+pub(crate) async fn send_admin_request(
+    channel: &Channel,
+    commander_config: &CommanderConfig,
+    request: AdminRequest,
+    output_mode: Option<AdminCommandOuputMode>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    match admin_response(channel, commander_config, request, output_mode).await? {
+        ResponseKind::JsonResponse(j) => Ok(j),
+        ResponseKind::ResultHandle(handle) => {
+            fetch_result(channel, commander_config, handle, output_mode).await
+        }
+        _ => Err(anyhow!("Unexpected admin response").into()),
+    }
+}
+
+async fn admin_response(
+    channel: &Channel,
+    commander_config: &CommanderConfig,
+    request: AdminRequest,
+    output_mode: Option<AdminCommandOuputMode>,
+) -> Result<ResponseKind, Box<dyn std::error::Error>> {
+    let request = encode_and_sign(
         request,
         &commander_config.ed25519_key,
-        Duration::from_secs(60),
-    )?);
+        commander_config.signature_validity(),
+    )?;
 
-    let response = client.admin(request).await?.into_inner();
+    let compression = compression_encoding(commander_config.compression.as_deref())?;
+    let mut admin_client =
+        AdminServiceClient::new(channel.clone()).accept_compressed(CompressionEncoding::Gzip);
+    if let Some(encoding) = compression {
+        admin_client = admin_client.send_compressed(encoding);
+    }
+    let response = match admin_client.admin(request.clone()).await {
+        // taskservers predating the AdminService
+        Err(status) if status.code() == Code::Unimplemented => {
+            let mut client = CommanderServiceClient::new(channel.clone())
+                .accept_compressed(CompressionEncoding::Gzip);
+            if let Some(encoding) = compression {
+                client = client.send_compressed(encoding);
+            }
+            client.admin(request).await?
+        }
+        response => response?,
+    }
+    .into_inner();
     match response.response_kind.unwrap() {
-        ResponseKind::Error(e) => {
-            eprintln!("{}", e.red());
-            std::process::exit(1);
+        ResponseKind::Error(message) => {
+            // older taskservers only send the error message
+            let error = AdminCommandError(response.structured_error.unwrap_or(AdminError {
+                code: AdminErrorCode::Unknown as i32,
+                message,
+                details: Default::default(),
+            }));
+            if let Some(output_mode) = output_mode {
+                error.display(output_mode)?;
+            }
+            Err(error.into())
+        }
+        response_kind => Ok(response_kind),
+    }
+}
+
+/// Fetch a json response kept by the taskserver because it is too large to be sent at once
+async fn fetch_result(
+    channel: &Channel,
+    commander_config: &CommanderConfig,
+    handle: ResultHandle,
+    output_mode: Option<AdminCommandOuputMode>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut result = Vec::with_capacity(handle.size as usize);
+    while (result.len() as u64) < handle.size {
+        let request = AdminRequest {
+            request_type: Some(RequestType::FetchResultChunk(FetchResultChunk {
+                id: handle.id.clone(),
+                offset: result.len() as u64,
+                len: RESULT_CHUNK_BYTES,
+            })),
+        };
+        match admin_response(channel, commander_config, request, output_mode).await? {
+            ResponseKind::ResultChunk(chunk) if !chunk.is_empty() => result.extend(chunk),
+            _ => return Err(anyhow!("Result {} is truncated", handle.id).into()),
         }
-        ResponseKind::JsonResponse(j) => {
-            admin_command.display_formatted_output(&j, output_mode)?;
-            Ok(CommanderSyntheticOutput::Admin(j))
+    }
+    admin_response(
+        channel,
+        commander_config,
+        AdminRequest {
+            request_type: Some(RequestType::ReleaseResult(ReleaseResult {
+                id: handle.id.clone(),
+            })),
+        },
+        output_mode,
+    )
+    .await?;
+    if result_checksum(&result) != handle.sha256 {
+        return Err(anyhow!("Result {} is corrupted (checksum mismatch)", handle.id).into());
+    }
+    Ok(String::from_utf8(result)?)
+}
+
+/// Ask the user to confirm a tag override when run from a terminal
+fn confirm_set_tag(path: &str, count: usize) -> anyhow::Result<()> {
+    if count == 0 {
+        return Ok(());
+    }
+    if atty::isnt(Stream::Stdin) {
+        eprintln!("stdin not a tty, overriding tag {path} of {count} executors anyway!");
+        return Ok(());
+    }
+    let mut rl = DefaultEditor::new()?;
+    let line = rl.readline(&format!(
+        "Tag {path} will be overridden on {count} executors, continue (y/N)? "
+    ))?;
+    if line.eq_ignore_ascii_case("y") || line.eq_ignore_ascii_case("yes") {
+        Ok(())
+    } else {
+        Err(anyhow!("Cancelled!"))
+    }
+}
+
+/// Most recent version reported by the executors, unparsable versions are ignored
+fn latest_version<'a>(executors: impl Iterator<Item = &'a ExecutorMeta>) -> Option<Version> {
+    executors
+        .filter_map(|meta| meta.version().parse().ok())
+        .max()
+}
+
+/// Time elapsed since a rfc3339 date, "-" if unknown
+pub(crate) fn humanized_elapsed_time(since: Option<&str>) -> String {
+    since
+        .and_then(|since| DateTime::parse_from_rfc3339(since).ok())
+        .map(|since| humanize_duration(Local::now().signed_duration_since(since)))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Two most significant units of a duration, eg: `3d 4h`, `12m 5s`
+fn humanize_duration(duration: chrono::Duration) -> String {
+    let secs = duration.num_seconds().max(0);
+    let (days, hours, minutes, seconds) = (
+        secs / 86400,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+    );
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        humanize_duration, humanized_elapsed_time, latest_version, AdminCommand,
+        AdminCommandOuputMode,
+    };
+    use chrono::Duration;
+    use funtonic::executor_meta::ExecutorMeta;
+    use std::collections::BTreeMap;
+
+    const EXECUTORS: &str = r#"{
+        "db-1": {"client_id": "db-1", "version": "0.21.5", "tags": {"role": "db"}},
+        "web-1": {
+            "client_id": "web-1",
+            "version": "0.21.5",
+            "tags": {"roles": ["web", "api"]},
+            "started_at": "2024-03-01T10:00:00+00:00",
+            "connected_at": "2024-03-02T08:30:00+00:00"
         }
+    }"#;
+
+    fn scripting_output(command: AdminCommand, raw_json: &str, csv: bool) -> String {
+        let output_mode = if csv {
+            AdminCommandOuputMode::Csv
+        } else {
+            AdminCommandOuputMode::Plain
+        };
+        command
+            .scripting_output(raw_json, output_mode)
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn plain_output() {
+        let list = || AdminCommand::ListKnownExecutors {
+            query: None,
+            group_by: vec![],
+            outdated: None,
+        };
+        assert_eq!("db-1\nweb-1\n", scripting_output(list(), EXECUTORS, false));
+        assert_eq!("", scripting_output(list(), "{}", false));
+        assert_eq!(
+            "web-1\n",
+            scripting_output(
+                AdminCommand::DropExecutor {
+                    query: "web-1".to_string()
+                },
+                r#"{"web-1": {"removed_from_connected": true, "removed_from_known": false}}"#,
+                false
+            )
+        );
+        assert_eq!(
+            "task-1\n",
+            scripting_output(
+                AdminCommand::ListRunningTasks,
+                r#"{"task-1": {"client_id": "web-1", "age_secs": 42}}"#,
+                false
+            )
+        );
+        assert!(AdminCommand::WhoAmI
+            .scripting_output("{}", AdminCommandOuputMode::Plain)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn outdated_executors() {
+        let executors = r#"{
+            "db-1": {"client_id": "db-1", "version": "0.21.5", "tags": {}},
+            "web-1": {"client_id": "web-1", "version": "0.19.2", "tags": {}},
+            "web-2": {"client_id": "web-2", "version": "dev", "tags": {}}
+        }"#;
+        let list = AdminCommand::ListKnownExecutors {
+            query: None,
+            group_by: vec![],
+            outdated: Some("<0.21".parse().unwrap()),
+        };
+        let outdated = list.filter_outdated(executors.to_string()).unwrap();
+        assert_eq!("web-1\n", scripting_output(list, &outdated, false));
+
+        let executors: BTreeMap<String, ExecutorMeta> = serde_json::from_str(executors).unwrap();
+        assert_eq!(
+            Some("0.21.5".parse().unwrap()),
+            latest_version(executors.values())
+        );
+        assert_eq!(
+            None,
+            latest_version(BTreeMap::<String, ExecutorMeta>::new().values())
+        );
+    }
+
+    #[test]
+    fn projected_tags() {
+        let list = |group_by: Vec<String>| AdminCommand::ListConnectedExecutors {
+            query: None,
+            fields: vec!["roles".to_string()],
+            hide_fields: vec![],
+            group_by,
+            watch: None,
+        };
+        let tags = |raw_json: &str| {
+            let executors: BTreeMap<String, ExecutorMeta> = serde_json::from_str(raw_json).unwrap();
+            executors["db-1"].tags().len()
+        };
+        let projected = list(vec![]).project_tags(EXECUTORS.to_string()).unwrap();
+        assert_eq!(0, tags(&projected));
+        // the executors are grouped on all their tags
+        let grouped = list(vec!["role".to_string()])
+            .project_tags(EXECUTORS.to_string())
+            .unwrap();
+        assert_eq!(1, tags(&grouped));
+    }
+
+    #[test]
+    fn csv_output() {
+        assert_eq!(
+            "client_id,version,connected_at,started_at,meta\n\
+             db-1,0.21.5,,,\"{\"\"role\"\":\"\"db\"\"}\"\n\
+             web-1,0.21.5,2024-03-02T08:30:00+00:00,2024-03-01T10:00:00+00:00,\
+             \"{\"\"roles\"\":[\"\"web\"\",\"\"api\"\"]}\"\n",
+            scripting_output(
+                AdminCommand::ListConnectedExecutors {
+                    query: None,
+                    fields: vec![],
+                    hide_fields: vec![],
+                    group_by: vec![],
+                    watch: None,
+                },
+                EXECUTORS,
+                true
+            )
+        );
+        assert_eq!(
+            "client_id,known,connected\nweb-1,false,true\n",
+            scripting_output(
+                AdminCommand::DropExecutor {
+                    query: "web-1".to_string()
+                },
+                r#"{"web-1": {"removed_from_connected": true, "removed_from_known": false}}"#,
+                true
+            )
+        );
+        assert_eq!("\"a\nb\"", super::csv_field("a\nb"));
+    }
+
+    #[test]
+    fn humanized_durations() {
+        assert_eq!("0s", humanize_duration(Duration::seconds(-3)));
+        assert_eq!("42s", humanize_duration(Duration::seconds(42)));
+        assert_eq!("12m 5s", humanize_duration(Duration::seconds(12 * 60 + 5)));
+        assert_eq!("2h 0m", humanize_duration(Duration::hours(2)));
+        assert_eq!(
+            "3d 4h",
+            humanize_duration(Duration::days(3) + Duration::hours(4) + Duration::minutes(59))
+        );
+        assert_eq!("-", humanized_elapsed_time(None));
+        assert_eq!("-", humanized_elapsed_time(Some("yesterday")));
     }
 }
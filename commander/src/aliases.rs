@@ -0,0 +1,144 @@
+//! Commands of the commander configuration run by name with positional parameters, eg:
+//! `commander run '*' --alias restart-service nginx`
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+
+/// Command of the alias `name`, its `{1}`, `{2}`... placeholders replaced by the shell quoted
+/// `parameters`
+pub fn expand_alias(
+    name: &str,
+    aliases: &BTreeMap<String, String>,
+    parameters: &[String],
+) -> anyhow::Result<String> {
+    let template = aliases.get(name).ok_or_else(|| {
+        anyhow!(
+            "Unknown alias {}, available: {}",
+            name,
+            aliases.keys().cloned().collect::<Vec<_>>().join(", ")
+        )
+    })?;
+    let mut command = String::new();
+    let mut used = vec![false; parameters.len()];
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find('{') {
+        command.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let placeholder = rest[1..]
+            .find('}')
+            .map(|end| &rest[1..end + 1])
+            .filter(|index| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()));
+        match placeholder {
+            Some(index) => {
+                let parameter = index
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| index.checked_sub(1))
+                    .and_then(|position| {
+                        let parameter = parameters.get(position)?;
+                        used[position] = true;
+                        Some(parameter)
+                    })
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Alias {} needs a parameter {{{}}}, {} given",
+                            name,
+                            index,
+                            parameters.len()
+                        )
+                    })?;
+                command.push_str(&shell_quote(parameter));
+                rest = &rest[index.len() + 2..];
+            }
+            // not a placeholder, eg: awk '{print $1}'
+            None => {
+                command.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    command.push_str(rest);
+    if let Some(unused) = used.iter().position(|used| !used) {
+        return Err(anyhow!(
+            "Alias {} does not use the parameter {{{}}} ({})",
+            name,
+            unused + 1,
+            parameters[unused]
+        ));
+    }
+    Ok(command)
+}
+
+/// Single quoted unless made of characters the shell does not interpret
+fn shell_quote(parameter: &str) -> String {
+    let plain = !parameter.is_empty()
+        && parameter
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c));
+    if plain {
+        parameter.to_string()
+    } else {
+        format!("'{}'", parameter.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::expand_alias;
+    use std::collections::BTreeMap;
+
+    fn aliases() -> BTreeMap<String, String> {
+        [
+            (
+                "restart-service",
+                "sudo systemctl restart {1} && systemctl is-active {1}",
+            ),
+            ("grep-log", "grep {2} {1} | awk '{print $1}'"),
+        ]
+        .into_iter()
+        .map(|(name, command)| (name.to_string(), command.to_string()))
+        .collect()
+    }
+
+    fn expand(name: &str, parameters: &[&str]) -> anyhow::Result<String> {
+        let parameters: Vec<String> = parameters.iter().map(|p| p.to_string()).collect();
+        expand_alias(name, &aliases(), &parameters)
+    }
+
+    #[test]
+    fn substitution() {
+        assert_eq!(
+            "sudo systemctl restart nginx && systemctl is-active nginx",
+            expand("restart-service", &["nginx"]).unwrap()
+        );
+        assert_eq!(
+            "grep error /var/log/syslog | awk '{print $1}'",
+            expand("grep-log", &["/var/log/syslog", "error"]).unwrap()
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(
+            "grep 'connection refused' '/var/log/my app.log' | awk '{print $1}'",
+            expand("grep-log", &["/var/log/my app.log", "connection refused"]).unwrap()
+        );
+        assert_eq!(
+            r#"grep 'it'\''s; rm -rf /' '' | awk '{print $1}'"#,
+            expand("grep-log", &["", "it's; rm -rf /"]).unwrap()
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let error = expand("grep-log", &["/var/log/syslog"]).unwrap_err();
+        assert!(error.to_string().contains("{2}"), "{}", error);
+        let error = expand("restart-service", &["nginx", "apache"]).unwrap_err();
+        assert!(error.to_string().contains("apache"), "{}", error);
+        let error = expand("restart", &[]).unwrap_err();
+        assert!(
+            error.to_string().contains("grep-log, restart-service"),
+            "{}",
+            error
+        );
+    }
+}
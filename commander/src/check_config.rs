@@ -0,0 +1,412 @@
+//! `utils check-config`: catch configuration mistakes before deploying a configuration file
+use clap::ValueEnum;
+use colored::Colorize;
+use funtonic::config::{
+    CertificateExpiry, CommanderConfig, ExecutorConfig, ExpiryStatus, ServerConfig, TlsConfig,
+};
+use funtonic::crypto::keygen::public_key_from_pkcs8;
+use funtonic::crypto::keystore::check_expiry;
+use funtonic::crypto::signed_payload::DELEGATION_PURPOSE;
+use funtonic::data_encoding;
+use funtonic::file_utils::{parse_yaml_from_file, read};
+use funtonic::prost::Message;
+use funtonic::task_server::key_scopes::KeyScopes;
+use grpc_service::grpc_protocol::Delegation;
+use http::Uri;
+use prettytable::format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR;
+use prettytable::{row, Table};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigRole {
+    Executor,
+    Commander,
+    Server,
+}
+
+impl ConfigRole {
+    /// Role of a configuration file named after the default configuration files
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.file_name()?.to_str()? {
+            "executor.yml" => Some(ConfigRole::Executor),
+            "commander.yml" => Some(ConfigRole::Commander),
+            "server.yml" => Some(ConfigRole::Server),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ConfigCheck {
+    pub check: String,
+    pub passed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// what a passed check found, e.g. the expiry date of a certificate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ConfigReport {
+    pub checks: Vec<ConfigCheck>,
+}
+
+#[derive(Error, Debug)]
+#[error("{0} configuration check(s) failed")]
+pub struct ConfigCheckFailed(pub usize);
+
+impl ConfigReport {
+    fn add(&mut self, check: impl Into<String>, result: Result<(), String>) {
+        self.checks.push(ConfigCheck {
+            check: check.into(),
+            passed: result.is_ok(),
+            error: result.err(),
+            details: None,
+        });
+    }
+
+    fn add_passed(&mut self, check: impl Into<String>, details: String) {
+        self.checks.push(ConfigCheck {
+            check: check.into(),
+            passed: true,
+            error: None,
+            details: Some(details),
+        });
+    }
+
+    pub fn print(&self) {
+        let mut table = Table::new();
+        table.set_format(*FORMAT_NO_BORDER_LINE_SEPARATOR);
+        table.set_titles(row!["check", "result", "details"]);
+        for check in &self.checks {
+            let result = if check.passed {
+                "ok".green()
+            } else {
+                "FAILED".red()
+            };
+            table.add_row(row![
+                check.check,
+                result,
+                check
+                    .error
+                    .as_deref()
+                    .or(check.details.as_deref())
+                    .unwrap_or_default()
+            ]);
+        }
+        table.printstd();
+    }
+
+    pub fn failed(&self) -> usize {
+        self.checks.iter().filter(|check| !check.passed).count()
+    }
+
+    fn parse<C: DeserializeOwned>(&mut self, path: &Path) -> Option<C> {
+        match parse_yaml_from_file(path) {
+            Ok(config) => {
+                self.add("parse", Ok(()));
+                Some(config)
+            }
+            Err(e) => {
+                self.add("parse", Err(format!("{:#}", e)));
+                None
+            }
+        }
+    }
+
+    fn check_keys(&mut self, section: &str, keys: &BTreeMap<String, String>) {
+        for (id, key) in keys {
+            self.add(
+                format!("{}.{}", section, id),
+                data_encoding::BASE64
+                    .decode(key.as_bytes())
+                    .map_err(|e| format!("not base64: {}", e))
+                    .and_then(|bytes| match bytes.len() {
+                        32 => Ok(()),
+                        len => Err(format!("{} bytes long, an ed25519 key is 32 bytes", len)),
+                    }),
+            );
+        }
+    }
+
+    fn check_tls(&mut self, tls: &Option<TlsConfig>) {
+        if let Some(tls) = tls {
+            for (name, path, certificate) in [
+                ("tls.ca_cert", &tls.ca_cert, true),
+                ("tls.cert", &tls.cert, true),
+                ("tls.key", &tls.key, false),
+            ] {
+                let pem = check_pem(path);
+                let pem_encoded = pem.is_ok();
+                self.add(name, pem);
+                if certificate && pem_encoded {
+                    self.check_certificate_expiry(name, path, tls.expiry_warning_days());
+                }
+            }
+        }
+    }
+
+    /// Fails once expired, the expiry date is reported otherwise
+    fn check_certificate_expiry(&mut self, name: &str, path: &str, warning_days: u64) {
+        let check = format!("{} expiry", name);
+        match CertificateExpiry::read(name, path) {
+            Ok(expiry) => match expiry.status(warning_days) {
+                ExpiryStatus::Valid => {
+                    self.add_passed(check, format!("expires on {}", expiry.not_after()))
+                }
+                ExpiryStatus::ExpiresSoon(days) => self.add_passed(
+                    check,
+                    format!("expires in {} days, on {}", days, expiry.not_after()),
+                ),
+                ExpiryStatus::Expired => {
+                    self.add(check, Err(format!("expired on {}", expiry.not_after())))
+                }
+            },
+            Err(e) => self.add(check, Err(format!("{:#}", e))),
+        }
+    }
+}
+
+fn check_pem(path: &str) -> Result<(), String> {
+    let content = read(path).map_err(|e| format!("{:#}", anyhow::Error::from(e)))?;
+    if String::from_utf8_lossy(&content).contains("-----BEGIN ") {
+        Ok(())
+    } else {
+        Err(format!("{} is not PEM encoded", path))
+    }
+}
+
+/// The delegation must be granted to the key of the configuration & still be valid
+fn check_delegation(config: &CommanderConfig) -> Result<(), String> {
+    let certificate = config
+        .delegation_certificate()
+        .map_err(|e| format!("{:#}", e))?
+        .unwrap_or_default();
+    let delegation = Delegation::decode(certificate.payload.as_slice())
+        .map_err(|e| format!("not a delegation: {}", e))?;
+    if delegation.purpose != DELEGATION_PURPOSE {
+        return Err("not a delegation".to_string());
+    }
+    let delegate = delegation.delegate.unwrap_or_default();
+    if delegate.key_id != config.ed25519_key.id {
+        return Err(format!(
+            "delegated to {}, not to {}",
+            delegate.key_id, config.ed25519_key.id
+        ));
+    }
+    check_expiry(&certificate).map_err(|e| e.to_string())
+}
+
+fn check_url(url: &str) -> Result<(), String> {
+    Uri::from_str(url)
+        .map(|_| ())
+        .map_err(|e| format!("invalid url {}: {}", url, e))
+}
+
+pub fn check_config(path: &Path, role: ConfigRole) -> ConfigReport {
+    let mut report = ConfigReport::default();
+    match role {
+        ConfigRole::Executor => {
+            if let Some(config) = report.parse::<ExecutorConfig>(path) {
+                if config.server_urls().is_empty() {
+                    report.add(
+                        "server_url",
+                        Err("set server_url or server_urls".to_string()),
+                    );
+                }
+                if !config.server_url.is_empty() {
+                    report.add("server_url", check_url(&config.server_url));
+                }
+                for url in &config.server_urls {
+                    report.add("server_urls", check_url(url));
+                }
+                if let Some(monitoring_bind_address) = &config.monitoring_bind_address {
+                    report.add(
+                        "monitoring_bind_address",
+                        monitoring_bind_address
+                            .parse::<SocketAddr>()
+                            .map(|_| ())
+                            .map_err(|e| {
+                                format!("invalid address {}: {}", monitoring_bind_address, e)
+                            }),
+                    );
+                }
+                report.check_keys("authorized_keys", &config.authorized_keys);
+                report.check_tls(&config.tls);
+            }
+        }
+        ConfigRole::Commander => {
+            if let Some(config) = report.parse::<CommanderConfig>(path) {
+                report.add("server_url", check_url(&config.server_url));
+                for (name, url) in &config.servers {
+                    report.add(format!("servers.{}", name), check_url(url));
+                }
+                let key = &config.ed25519_key;
+                if let Some(encrypted) = &key.pkcs8_encrypted {
+                    // the passphrase is not asked
+                    report.add(
+                        "ed25519_key (encrypted)",
+                        encrypted.check(&key.id).map_err(|e| e.to_string()),
+                    );
+                } else {
+                    report.add(
+                        "ed25519_key",
+                        key.to_bytes()
+                            .map_err(|e| format!("pkcs8 is not base64: {}", e))
+                            .and_then(|pkcs8| {
+                                public_key_from_pkcs8(&pkcs8)
+                                    .map_err(|e| format!("invalid pkcs8 key: {}", e))
+                            })
+                            .and_then(|public_key| match &key.public_key {
+                                Some(expected)
+                                    if *expected != data_encoding::BASE64.encode(&public_key) =>
+                                {
+                                    Err("public_key does not match the pkcs8 key".to_string())
+                                }
+                                _ => Ok(()),
+                            }),
+                    );
+                }
+                if config.delegation.is_some() {
+                    report.add("delegation", check_delegation(&config));
+                }
+                report.check_tls(&config.tls);
+            }
+        }
+        ConfigRole::Server => {
+            if let Some(config) = report.parse::<ServerConfig>(path) {
+                report.add(
+                    "bind_address",
+                    config
+                        .bind_address
+                        .parse::<SocketAddr>()
+                        .map(|_| ())
+                        .map_err(|e| format!("invalid address {}: {}", config.bind_address, e)),
+                );
+                if let Some(admin_bind_address) = &config.admin_bind_address {
+                    report.add(
+                        "admin_bind_address",
+                        admin_bind_address
+                            .parse::<SocketAddr>()
+                            .map(|_| ())
+                            .map_err(|e| format!("invalid address {}: {}", admin_bind_address, e)),
+                    );
+                }
+                report.check_keys("authorized_keys", &config.authorized_public_keys());
+                report.add(
+                    "authorized_keys.allowed_queries",
+                    KeyScopes::new(config.authorized_key_scopes())
+                        .map(|_| ())
+                        .map_err(|e| e.to_string()),
+                );
+                report.check_keys("admin_authorized_keys", &config.admin_authorized_keys);
+                report.check_tls(&config.tls);
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_config, ConfigRole};
+    use funtonic::crypto::keygen::generate_base64_encoded_keys;
+    use std::path::Path;
+
+    fn failed_checks(path: &Path, role: ConfigRole) -> Vec<String> {
+        check_config(path, role)
+            .checks
+            .into_iter()
+            .filter(|check| !check.passed)
+            .map(|check| check.check)
+            .collect()
+    }
+
+    #[test]
+    fn check_config_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let (key, authorized_keys) = generate_base64_encoded_keys("tests");
+
+        let commander = dir.path().join("commander.yml");
+        std::fs::write(
+            &commander,
+            format!(
+                "server_url: http://127.0.0.1:54010\ned25519_key:\n  id: tests\n  pkcs8: {}\n",
+                key.pkcs8
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            Some(ConfigRole::Commander),
+            ConfigRole::from_path(&commander)
+        );
+        assert!(failed_checks(&commander, ConfigRole::Commander).is_empty());
+        // not an executor configuration
+        assert_eq!(
+            vec!["parse"],
+            failed_checks(&commander, ConfigRole::Executor)
+        );
+
+        let cert = dir.path().join("cert.pem");
+        std::fs::write(&cert, "-----BEGIN CERTIFICATE-----\n").unwrap();
+        let server = dir.path().join("server.yml");
+        std::fs::write(
+            &server,
+            format!(
+                "bind_address: 127.0.0.1:54010\ndata_directory: /tmp\n\
+                 authorized_keys:\n  tests: {}\n  short: {{key: AAAA, allowed_queries: ['env:staging and']}}\n\
+                 admin_authorized_keys:\n  tests: '#!'\n\
+                 tls:\n  ca_cert: {cert}\n  cert: {cert}\n  key: {}\n",
+                authorized_keys["tests"],
+                dir.path().join("missing.pem").display(),
+                cert = cert.display(),
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                "authorized_keys.short",
+                "authorized_keys.allowed_queries",
+                "admin_authorized_keys.tests",
+                // PEM encoded, but not a certificate
+                "tls.ca_cert expiry",
+                "tls.cert expiry",
+                "tls.key"
+            ],
+            failed_checks(&server, ConfigRole::Server)
+        );
+
+        let executor = dir.path().join("executor.yml");
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("../integration/tls");
+        std::fs::write(
+            &executor,
+            format!(
+                "client_id: exec\ntags: {{}}\nauthorized_keys: {{}}\n\
+                 server_url: http://127.0.0.1:54010\n\
+                 tls:\n  ca_cert: {}\n  cert: {}\n  key: {}\n",
+                fixtures.join("funtonic-ca.pem").display(),
+                fixtures.join("executor.pem").display(),
+                fixtures.join("executor-key.pem").display(),
+            ),
+        )
+        .unwrap();
+        let report = check_config(&executor, ConfigRole::Executor);
+        let cert_expiry = report
+            .checks
+            .iter()
+            .find(|check| check.check == "tls.cert expiry")
+            .unwrap();
+        assert!(cert_expiry.passed);
+        assert_eq!(
+            Some("expires on 2123-03-03T18:28:00+00:00"),
+            cert_expiry.details.as_deref()
+        );
+    }
+}
@@ -1,33 +1,56 @@
-use crate::{CommanderSyntheticOutput, ExecutorState};
+use crate::aliases::expand_alias;
+use crate::key_rotation::rotate_key;
+use crate::latency::LatencyProbe;
+use crate::local::local_responses;
+use crate::prefix::OutputPrefix;
+use crate::progress::RunTracker;
+use crate::render::{RenderEvent, Renderer, DEFAULT_RENDER_BUFFER_LINES};
+use crate::safeguard::{
+    applicable_policies, confirm_matching, safeguard_command, safeguard_script, tty_prompt,
+    UnsafeCommands,
+};
+use crate::saved_queries::expand_saved_query;
+use crate::transcript::{read_transcript, TranscriptWriter};
+use crate::{connect, CommanderSyntheticOutput, ExecutorState};
 use anyhow::{anyhow, Context};
 use atty::Stream;
 use clap::{Args, Subcommand};
 use colored::{Color, Colorize};
 use directories::ProjectDirs;
+use funtonic::capabilities::Capabilities;
 use funtonic::config::CommanderConfig;
-use funtonic::crypto::signed_payload::encode_and_sign;
+use funtonic::crypto::signed_payload::{encode_and_sign, EncodePayloadError};
 use funtonic::data_encoding;
+use funtonic::executor_meta::Tag;
+use funtonic::tokio;
+use funtonic::tokio::sync::mpsc;
+use funtonic::tokio::task::JoinHandle;
 use funtonic::tonic::{self, Request};
+use futures::StreamExt;
 use grpc_service::grpc_protocol::commander_service_client::CommanderServiceClient;
+use grpc_service::grpc_protocol::execute_command::Program;
 use grpc_service::grpc_protocol::launch_task_request_payload::Task;
 use grpc_service::grpc_protocol::launch_task_response::TaskResponse;
 use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
 use grpc_service::grpc_protocol::task_output::Output;
 use grpc_service::grpc_protocol::{
-    ExecuteCommand, LaunchTaskRequest, LaunchTaskRequestPayload, PublicKey,
+    CancelTasksRequest, ExecuteCommand, LaunchTaskRequest, LaunchTaskRequestPayload,
+    LaunchTaskResponse, PublicKey, ResolveQueryRequest, ResolvedExecutor, Script,
+    TaskExecutionResult,
 };
 use indicatif::ProgressBar;
 use query_parser::parse;
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
-use shellish_parse::ParseOptions;
+use serde::Serialize;
 use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::error::Error;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 use tonic::transport::Channel;
 
-#[derive(Args, Debug, Clone, Default)]
+#[derive(Args, Debug, Clone)]
 pub struct CommandOptions {
     /// Raw output, remote stderr/out will be printed as soon at they arrive without any other information
     #[arg(short = 'r', long = "raw")]
@@ -41,6 +64,201 @@ pub struct CommandOptions {
     /// testing opt
     #[arg(long = "no_std_process_return")]
     pub no_std_process_return: bool,
+    /// Output lines buffered while the terminal is slower than the executors; in raw mode the
+    /// oldest lines are dropped once the buffer is full
+    #[arg(long = "render-buffer-lines", default_value_t = DEFAULT_RENDER_BUFFER_LINES)]
+    pub render_buffer_lines: usize,
+    /// Only print a json summary once the command is completed (executors states & outputs, or
+    /// matching executors on dry run)
+    #[arg(long = "json")]
+    pub json: bool,
+    /// Only print the executors states summary, on stderr, once the command is completed
+    #[arg(short = 'q', long = "quiet", conflicts_with_all = ["raw", "json"])]
+    pub quiet: bool,
+    /// Save the responses received from the taskserver to this file, see `replay`. In
+    /// interactive mode each command overwrites the file.
+    #[arg(long = "record")]
+    pub record: Option<PathBuf>,
+    /// With several taskservers configured, abort if any of them fails instead of running the
+    /// command on the others
+    #[arg(long = "require-all")]
+    pub require_all: bool,
+    /// Do not ask for a confirmation before running unsafe commands, the commands forbidden by
+    /// the safeguard policies are still refused
+    #[arg(short = 'y', long = "yes")]
+    pub yes: bool,
+    /// Do not ask for a confirmation when the query matches more than `confirm_above` executors
+    #[arg(long = "force")]
+    pub force: bool,
+    /// Pad the prefix of the live output lines to the longest matching executor
+    #[arg(long = "align")]
+    pub align: bool,
+    /// Prefix of the live output lines: `{host}`, `{short}` (hostname up to the first dot) and
+    /// `{state}` are replaced
+    #[arg(long = "prefix-format", value_name = "FORMAT")]
+    pub prefix_format: Option<String>,
+}
+
+impl Default for CommandOptions {
+    fn default() -> Self {
+        Self {
+            raw: false,
+            group: false,
+            no_progress: false,
+            no_std_process_return: false,
+            render_buffer_lines: DEFAULT_RENDER_BUFFER_LINES,
+            json: false,
+            quiet: false,
+            record: None,
+            require_all: false,
+            yes: false,
+            force: false,
+            align: false,
+            prefix_format: None,
+        }
+    }
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct BatchOptions {
+    /// Run the command on at most N matching executors at a time, waiting for a batch to complete
+    /// before starting the next one
+    #[arg(short = 'b', long = "batch-size")]
+    pub batch_size: Option<usize>,
+    /// Delay in seconds between two batches
+    #[arg(long = "batch-delay", default_value_t = 0, requires = "batch_size")]
+    pub batch_delay: u64,
+    /// Do not start the next batch if the command failed on any executor of the current batch
+    #[arg(long = "fail-fast", requires = "batch_size")]
+    pub fail_fast: bool,
+}
+
+/// Alternatives to the positional query, for queries that do not fit comfortably on a command line
+#[derive(Args, Debug, Clone, Default)]
+pub struct QueryOptions {
+    /// Target query, `-` reads it from stdin
+    #[arg(
+        long = "query",
+        id = "query_option",
+        value_name = "QUERY",
+        conflicts_with = "query_file"
+    )]
+    pub query: Option<String>,
+    /// Read the target query from a file
+    #[arg(long = "query-file")]
+    pub query_file: Option<PathBuf>,
+    /// Print the canonical form of the target query
+    #[arg(short = 'v', long = "verbose")]
+    pub verbose: bool,
+}
+
+impl QueryOptions {
+    /// Whether the query is not given as a positional argument
+    fn is_set(&self) -> bool {
+        self.query.is_some() || self.query_file.is_some()
+    }
+
+    /// Validated target query, either the positional one or from `--query`/`--query-file`, with
+    /// `@name` saved queries expanded
+    pub fn resolve(
+        &self,
+        positional: Option<String>,
+        saved_queries: &BTreeMap<String, String>,
+    ) -> anyhow::Result<String> {
+        let query = match (&self.query, &self.query_file) {
+            (Some(query), _) if query == "-" => {
+                let query = std::io::read_to_string(std::io::stdin())
+                    .context("Unable to read the query from stdin")?;
+                normalize_query(&query, "stdin")?
+            }
+            (Some(query), _) => query.clone(),
+            (None, Some(path)) => load_query_file(path)?,
+            (None, None) => positional.ok_or_else(|| anyhow!("No target query"))?,
+        };
+        let query = expand_saved_query(&query, saved_queries)?;
+        let parsed = parse(&query)?;
+        if self.verbose {
+            eprintln!("Target query: {}", parsed);
+        }
+        Ok(query)
+    }
+}
+
+/// With `--query` or `--query-file`, the positional arguments of `run` are the command: a
+/// positional argument before the option is a query given twice
+pub fn check_run_query(run: &clap::ArgMatches) -> Result<(), String> {
+    let positional = match run.index_of("query") {
+        Some(index) => index,
+        None => return Ok(()),
+    };
+    for (id, option) in [("query_option", "--query"), ("query_file", "--query-file")] {
+        if matches!(run.index_of(id), Some(index) if positional < index) {
+            return Err(format!(
+                "the positional query cannot be used with {}, the command must follow it",
+                option
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn load_query_file(path: &Path) -> anyhow::Result<String> {
+    let query = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read query file {}", path.display()))?;
+    normalize_query(&query, &path.display().to_string())
+}
+
+/// Standard input sent with the command, refused when larger than `max_bytes`
+fn read_stdin(input: impl Read, max_bytes: usize) -> anyhow::Result<Vec<u8>> {
+    let mut stdin = Vec::new();
+    input
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut stdin)
+        .context("Unable to read stdin")?;
+    if stdin.len() > max_bytes {
+        return Err(anyhow!(
+            "The standard input exceeds {} bytes, see max_stdin_bytes",
+            max_bytes
+        ));
+    }
+    Ok(stdin)
+}
+
+/// Script sent with `run --script`, refused when larger than `max_bytes` or not a text file
+fn read_script(path: &Path, max_bytes: usize) -> anyhow::Result<Script> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Unable to open script {}", path.display()))?;
+    let mut content = Vec::new();
+    file.take(max_bytes as u64 + 1)
+        .read_to_end(&mut content)
+        .with_context(|| format!("Unable to read script {}", path.display()))?;
+    if content.len() > max_bytes {
+        return Err(anyhow!(
+            "The script {} exceeds {} bytes, see max_script_bytes",
+            path.display(),
+            max_bytes
+        ));
+    }
+    let content = match String::from_utf8(content) {
+        Ok(content) if !content.contains('\0') => content,
+        _ => return Err(anyhow!("{} is not a text file", path.display())),
+    };
+    Ok(Script {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        content,
+    })
+}
+
+/// Queries assembled by other tools may span several lines, with any line ending
+fn normalize_query(query: &str, source: &str) -> anyhow::Result<String> {
+    let query = query.replace(['\r', '\n'], " ").trim().to_string();
+    if query.is_empty() {
+        return Err(anyhow!("No query found in {}", source));
+    }
+    Ok(query)
 }
 
 #[derive(Subcommand, Debug)]
@@ -50,8 +268,43 @@ pub enum Cmd {
     Run {
         #[command(flatten)]
         options: CommandOptions,
-        /// Target query
-        query: String,
+        #[command(flatten)]
+        batch: BatchOptions,
+        /// Print the executors that would receive the command, without running it
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+        /// Send the standard input to the command, it is read entirely before the command is
+        /// sent (`max_stdin_bytes` at most)
+        #[arg(long = "stdin")]
+        stdin: bool,
+        /// Run the command of this alias of the configuration, the command arguments being its
+        /// parameters
+        #[arg(long = "alias")]
+        alias: Option<String>,
+        /// Run this script file instead of a command: it is sent whole to the executors, which
+        /// run it from a temporary file (`max_script_bytes` at most)
+        #[arg(long = "script", value_name = "FILE", conflicts_with = "alias")]
+        script: Option<PathBuf>,
+        /// Run the command through the `become_command` of the executors (`sudo -n` by default),
+        /// it is rejected when it would ask for a password
+        #[arg(long = "become")]
+        with_become: bool,
+        /// Run the command on this machine only, without connecting to the taskserver: it is
+        /// rendered as the output of the executor `localhost`, to try a command or an output mode
+        /// before running it on the executors
+        #[arg(
+            long = "local",
+            conflicts_with_all = [
+                "dry_run", "script", "with_become", "batch_size", "query_option", "query_file"
+            ]
+        )]
+        local: bool,
+        #[command(flatten)]
+        query_options: QueryOptions,
+        /// Target query; with --query, --query-file or --local all the positional arguments are
+        /// the command, they must follow --query or --query-file
+        #[arg(required_unless_present_any = ["query_option", "query_file", "local"])]
+        query: Option<String>,
         command: Vec<String>,
     },
     /// Run commands in interactive mode
@@ -59,20 +312,57 @@ pub enum Cmd {
     Int {
         #[command(flatten)]
         options: CommandOptions,
+        #[command(flatten)]
+        query_options: QueryOptions,
         /// Target query
-        query: String,
+        #[arg(
+            required_unless_present_any = ["query_option", "query_file"],
+            conflicts_with_all = ["query_option", "query_file"]
+        )]
+        query: Option<String>,
     },
     /// Manage authorized keys on executors
     #[command(name = "keys")]
     Keys {
         #[command(flatten)]
         options: CommandOptions,
+        #[command(flatten)]
+        query_options: QueryOptions,
         /// Target query: All matching executor will install the specified key in their
         /// authorized key.
-        query: String,
+        #[arg(
+            required_unless_present_any = ["query_option", "query_file"],
+            conflicts_with_all = ["query_option", "query_file"]
+        )]
+        query: Option<String>,
         #[command(subcommand)]
         key_cmd: KeyCmd,
     },
+    /// Run a no-op command on targeted executors and report how long it took on each of them
+    #[command(name = "ping")]
+    Ping {
+        #[command(flatten)]
+        options: CommandOptions,
+        /// Break the latency down: connection, signing, taskserver matching & dispatch, executor
+        /// start...
+        #[arg(long = "measure")]
+        measure: bool,
+        #[command(flatten)]
+        query_options: QueryOptions,
+        /// Target query
+        #[arg(
+            required_unless_present_any = ["query_option", "query_file"],
+            conflicts_with_all = ["query_option", "query_file"]
+        )]
+        query: Option<String>,
+    },
+}
+
+impl Cmd {
+    /// `run --local`, no taskserver is involved
+    pub fn is_local(&self) -> bool {
+        matches!(self, Cmd::Run { local: true, .. })
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -84,6 +374,9 @@ pub enum KeyCmd {
         key_id: String,
         /// Public key (base64 encoded)
         public_key: String,
+        /// The key is rejected by executors & the taskserver after this number of days
+        #[arg(long = "expires-in", value_name = "DAYS")]
+        expires_in: Option<u64>,
     },
     /// Revoke a key on executors
     #[command(name = "revoke")]
@@ -91,6 +384,20 @@ pub enum KeyCmd {
         // Identifier of the public key on executors
         key_id: String,
     },
+    /// Generate a new key pair, authorize it on executors and print it. The commander key must be
+    /// an admin key.
+    #[command(name = "rotate")]
+    Rotate {
+        /// Identifier of the new key
+        #[arg(long = "new-key-name")]
+        new_key_name: String,
+        /// Then revoke the commander key on the same executors
+        #[arg(long = "revoke-old")]
+        revoke_old: bool,
+        /// Write the new key to this file instead of stdout
+        #[arg(long = "out")]
+        out: Option<PathBuf>,
+    },
 }
 
 pub async fn handle_cmd(
@@ -98,29 +405,60 @@ pub async fn handle_cmd(
     commander_config: &CommanderConfig,
     cmd: Cmd,
 ) -> Result<CommanderSyntheticOutput, Box<dyn Error>> {
-    if let Cmd::Int { mut options, query } = cmd {
+    if let Cmd::Int {
+        mut options,
+        query_options,
+        query,
+    } = cmd
+    {
         // interactive mode
 
-        //check the query is parsable
-        parse(&query)?;
+        let mut query = query_options.resolve(query, &commander_config.saved_queries)?;
+        let unsafe_commands = UnsafeCommands::new(&commander_config.unsafe_commands())?;
 
-        // craft a special command to retrieve the list of connected executors
-        {
-            let mut options = CommandOptions::default();
-            options.no_std_process_return = true;
+        // craft a special command to retrieve the list of connected executors, and count them
+        let mut matching = {
+            let listing_options = CommandOptions {
+                no_std_process_return: true,
+                ..Default::default()
+            };
             let request = tonic::Request::new(LaunchTaskRequest {
                 payload: Some(encode_and_sign(
                     LaunchTaskRequestPayload {
-                        task: Some(Task::ExecuteCommand(ExecuteCommand { command: "".into() })),
+                        task: Some(Task::ExecuteCommand(ExecuteCommand {
+                            program: Some(Program::Command("".into())),
+                            ..Default::default()
+                        })),
                     },
                     &commander_config.ed25519_key,
-                    Duration::from_secs(60),
+                    commander_config.signature_validity(),
                 )?),
 
                 predicate: query.clone(),
+                capabilities: Capabilities::local().into(),
+                delegation: commander_config.delegation_certificate()?,
             });
-            do_handle_cmd(client.clone(), request, options.clone()).await?;
-        }
+            match do_handle_cmd(
+                client.clone(),
+                commander_config,
+                request,
+                listing_options,
+                Interrupts::ctrl_c(),
+            )
+            .await?
+            {
+                CommanderSyntheticOutput::Executor { states, .. } => {
+                    let client_ids: Vec<String> = states.into_values().flatten().collect();
+                    if let (Some(confirm_above), false) =
+                        (commander_config.confirm_above, options.force)
+                    {
+                        confirm_matching(&client_ids, confirm_above, tty_prompt)?;
+                    }
+                    Some(client_ids.len())
+                }
+                _ => None,
+            }
+        };
 
         // do not exit process on return
         options.no_std_process_return = true;
@@ -147,7 +485,31 @@ pub async fn handle_cmd(
                 Ok(line) => {
                     let _ = rl.add_history_entry(line.as_str()); // ignore result
 
-                    if let Err(e) = safeguard_command(&line) {
+                    if let Some(path) = line.strip_prefix(":loadquery") {
+                        match load_query_file(Path::new(path.trim())).and_then(|loaded| {
+                            let loaded =
+                                expand_saved_query(&loaded, &commander_config.saved_queries)?;
+                            parse(&loaded)?;
+                            Ok(loaded)
+                        }) {
+                            Ok(loaded) => {
+                                query = loaded;
+                                matching = None;
+                            }
+                            Err(e) => eprintln!("{:#}", e),
+                        }
+                        continue;
+                    }
+
+                    let policies = applicable_policies(
+                        std::slice::from_ref(&client),
+                        commander_config,
+                        &query,
+                    )
+                    .await?;
+                    if let Err(e) =
+                        safeguard_command(&line, &unsafe_commands, &policies, matching, options.yes)
+                    {
                         eprintln!("{e}");
                         continue;
                     }
@@ -155,15 +517,27 @@ pub async fn handle_cmd(
                     let request = tonic::Request::new(LaunchTaskRequest {
                         payload: Some(encode_and_sign(
                             LaunchTaskRequestPayload {
-                                task: Some(Task::ExecuteCommand(ExecuteCommand { command: line })),
+                                task: Some(Task::ExecuteCommand(ExecuteCommand {
+                                    program: Some(Program::Command(line)),
+                                    ..Default::default()
+                                })),
                             },
                             &commander_config.ed25519_key,
-                            Duration::from_secs(60),
+                            commander_config.signature_validity(),
                         )?),
 
                         predicate: query.clone(),
+                        capabilities: Capabilities::local().into(),
+                        delegation: commander_config.delegation_certificate()?,
                     });
-                    do_handle_cmd(client.clone(), request, options.clone()).await?;
+                    do_handle_cmd(
+                        client.clone(),
+                        commander_config,
+                        request,
+                        options.clone(),
+                        Interrupts::ctrl_c(),
+                    )
+                    .await?;
                 }
                 Err(ReadlineError::Interrupted) => {
                     break;
@@ -182,319 +556,1388 @@ pub async fn handle_cmd(
         }
         std::process::exit(0);
     } else {
-        let (request, options) = match cmd {
+        let (request, options, taskservers) = match cmd {
             Cmd::Run {
                 options,
+                batch,
+                dry_run,
+                stdin,
+                alias,
+                script,
+                with_become,
+                local: _,
+                query_options,
                 query,
-                command,
+                mut command,
             } => {
-                //check the query is parsable
-                parse(&query)?;
-                let command = command.join(" ");
+                if stdin && query_options.query.as_deref() == Some("-") {
+                    return Err(anyhow!("--stdin cannot be used with --query -").into());
+                }
+                let query = if query_options.is_set() {
+                    // the first positional argument is not the query but the command
+                    command.splice(0..0, query);
+                    query_options.resolve(None, &commander_config.saved_queries)?
+                } else {
+                    query_options.resolve(query, &commander_config.saved_queries)?
+                };
+                let command =
+                    expanded_command(alias.as_deref(), command, commander_config, &options)?;
+                let script = match &script {
+                    Some(_) if !command.is_empty() => {
+                        return Err(anyhow!("--script cannot be used with a command").into())
+                    }
+                    Some(path) => Some(read_script(path, commander_config.max_script_bytes())?),
+                    None => None,
+                };
+                let command = match &script {
+                    Some(script) => format!("script {}", script.name),
+                    None => command,
+                };
+
+                if dry_run {
+                    return handle_dry_run(
+                        client,
+                        commander_config,
+                        &query,
+                        &command,
+                        options.json,
+                    )
+                    .await;
+                }
+
+                // batched runs are only sent to `server_url`
+                let taskservers = if batch.batch_size.is_some() {
+                    vec![Taskserver::single(client.clone())]
+                } else {
+                    taskservers(client.clone(), commander_config, options.require_all).await?
+                };
+                let clients: Vec<_> = taskservers.iter().map(|t| t.client.clone()).collect();
+                let policies = applicable_policies(&clients, commander_config, &query).await?;
+                let unsafe_commands = UnsafeCommands::new(&commander_config.unsafe_commands())?;
+                match &script {
+                    Some(script) => safeguard_script(
+                        &script.name,
+                        &script.content,
+                        &unsafe_commands,
+                        &policies,
+                        options.yes,
+                    )?,
+                    None => {
+                        safeguard_command(&command, &unsafe_commands, &policies, None, options.yes)?
+                    }
+                }
+                if let (Some(confirm_above), false) =
+                    (commander_config.confirm_above, options.force)
+                {
+                    // resolved first: nothing is sent before the confirmation
+                    let mut client_ids = vec![];
+                    for client in &clients {
+                        client_ids.extend(
+                            resolve_query(&mut client.clone(), commander_config, &query)
+                                .await?
+                                .into_iter()
+                                .map(|executor| executor.client_id),
+                        );
+                    }
+                    confirm_matching(&client_ids, confirm_above, tty_prompt)?;
+                }
+                let execute_command = ExecuteCommand {
+                    program: Some(match script {
+                        Some(script) => Program::Script(script),
+                        None => Program::Command(command),
+                    }),
+                    stdin: if stdin {
+                        read_stdin(std::io::stdin(), commander_config.max_stdin_bytes())?
+                    } else {
+                        Vec::new()
+                    },
+                    with_become,
+                };
 
-                safeguard_command(&command)?;
+                if let Some(batch_size) = batch.batch_size {
+                    return handle_batched_cmd(
+                        client,
+                        commander_config,
+                        &query,
+                        execute_command,
+                        options,
+                        batch_size,
+                        batch,
+                    )
+                    .await;
+                }
 
                 let request = tonic::Request::new(LaunchTaskRequest {
                     payload: Some(encode_and_sign(
                         LaunchTaskRequestPayload {
-                            task: Some(Task::ExecuteCommand(ExecuteCommand { command })),
+                            task: Some(Task::ExecuteCommand(execute_command)),
                         },
                         &commander_config.ed25519_key,
-                        Duration::from_secs(60),
+                        commander_config.signature_validity(),
                     )?),
 
                     predicate: query,
+                    capabilities: Capabilities::local().into(),
+                    delegation: commander_config.delegation_certificate()?,
                 });
-                (request, options)
+                (request, options, taskservers)
             }
 
             Cmd::Keys {
                 options,
+                query_options,
                 query,
                 key_cmd,
             } => {
-                //check the query is parsable
-                parse(&query)?;
-                (
-                    match key_cmd {
-                        KeyCmd::Authorize { key_id, public_key } => {
-                            tonic::Request::new(LaunchTaskRequest {
-                                payload: Some(encode_and_sign(
-                                    LaunchTaskRequestPayload {
-                                        task: Some(Task::AuthorizeKey(PublicKey {
-                                            key_id,
-                                            key_bytes: data_encoding::BASE64
-                                                .decode(public_key.as_bytes())
-                                                .context("Unable to decode base64 encoded key")?,
-                                        })),
-                                    },
-                                    &commander_config.ed25519_key,
-                                    Duration::from_secs(60),
-                                )?),
-
-                                predicate: query,
-                            })
-                        }
-                        KeyCmd::Revoke { key_id } => tonic::Request::new(LaunchTaskRequest {
-                            payload: Some(encode_and_sign(
-                                LaunchTaskRequestPayload {
-                                    task: Some(Task::RevokeKey(key_id)),
-                                },
-                                &commander_config.ed25519_key,
-                                Duration::from_secs(60),
-                            )?),
-
-                            predicate: query,
-                        }),
-                    },
-                    options,
-                )
+                let query = query_options.resolve(query, &commander_config.saved_queries)?;
+                let request = match key_cmd {
+                    KeyCmd::Authorize {
+                        key_id,
+                        public_key,
+                        expires_in,
+                    } => tonic::Request::new(LaunchTaskRequest {
+                        payload: Some(encode_and_sign(
+                            LaunchTaskRequestPayload {
+                                task: Some(Task::AuthorizeKey(PublicKey {
+                                    key_id,
+                                    key_bytes: data_encoding::BASE64
+                                        .decode(public_key.as_bytes())
+                                        .context("Unable to decode base64 encoded key")?,
+                                    expires_at_secs: expires_at_secs(expires_in)?,
+                                })),
+                            },
+                            &commander_config.ed25519_key,
+                            commander_config.signature_validity(),
+                        )?),
+
+                        predicate: query,
+                        capabilities: Capabilities::local().into(),
+                        delegation: None,
+                    }),
+                    KeyCmd::Revoke { key_id } => tonic::Request::new(LaunchTaskRequest {
+                        payload: Some(encode_and_sign(
+                            LaunchTaskRequestPayload {
+                                task: Some(Task::RevokeKey(key_id)),
+                            },
+                            &commander_config.ed25519_key,
+                            commander_config.signature_validity(),
+                        )?),
+
+                        predicate: query,
+                        capabilities: Capabilities::local().into(),
+                        delegation: None,
+                    }),
+                    KeyCmd::Rotate {
+                        new_key_name,
+                        revoke_old,
+                        out,
+                    } => {
+                        return rotate_key(
+                            client,
+                            commander_config,
+                            &query,
+                            &new_key_name,
+                            revoke_old,
+                            out,
+                            options,
+                        )
+                        .await
+                    }
+                };
+                let taskservers =
+                    taskservers(client, commander_config, options.require_all).await?;
+                (request, options, taskservers)
             }
-            Cmd::Int {
-                options: _,
-                query: _,
-            } => panic!("You should never reach this code"),
+            Cmd::Ping {
+                options,
+                measure,
+                query_options,
+                query,
+            } => {
+                let query = query_options.resolve(query, &commander_config.saved_queries)?;
+                return handle_ping(client, commander_config, &query, options, measure).await;
+            }
+            Cmd::Int { .. } => panic!("You should never reach this code"),
         };
-        do_handle_cmd(client, request, options).await
+        do_handle_taskservers_cmd(
+            &taskservers,
+            commander_config,
+            request.into_inner(),
+            options,
+            Interrupts::ctrl_c(),
+        )
+        .await
     }
 }
 
-pub async fn do_handle_cmd(
+/// The command run, either given as is or the expansion of `alias`
+fn expanded_command(
+    alias: Option<&str>,
+    command: Vec<String>,
+    commander_config: &CommanderConfig,
+    options: &CommandOptions,
+) -> anyhow::Result<String> {
+    Ok(match alias {
+        Some(alias) => {
+            let expanded = expand_alias(alias, &commander_config.aliases, &command)?;
+            if !options.raw && !options.json && !options.quiet {
+                println!("{}: {}", alias.bold(), expanded);
+            }
+            expanded
+        }
+        None => command.join(" "),
+    })
+}
+
+/// `run --local`: run the command on this machine and render it as the output of the executor
+/// `localhost`, through the same output modes as the commands sent to the taskserver
+pub async fn handle_local_cmd(
+    commander_config: &CommanderConfig,
+    cmd: Cmd,
+) -> Result<CommanderSyntheticOutput, Box<dyn Error>> {
+    let Cmd::Run {
+        options,
+        stdin,
+        alias,
+        query,
+        mut command,
+        ..
+    } = cmd
+    else {
+        return Err(anyhow!("Only run commands can be run locally").into());
+    };
+    // there is no query, the first positional argument is the command
+    command.splice(0..0, query);
+    let command = expanded_command(alias.as_deref(), command, commander_config, &options)?;
+    let unsafe_commands = UnsafeCommands::new(&commander_config.unsafe_commands())?;
+    safeguard_command(&command, &unsafe_commands, &[], None, options.yes)?;
+    let stdin = if stdin {
+        read_stdin(std::io::stdin(), commander_config.max_stdin_bytes())?
+    } else {
+        Vec::new()
+    };
+
+    let mut state = RunState::new(&options)?;
+    let mut responses = Box::pin(local_responses(&command, stdin)?);
+    while let Some(task_response) = responses.next().await {
+        state.record_and_handle(task_response, &options).await?;
+    }
+    state.finish(&options)
+}
+
+/// A taskserver a command is sent to
+pub(crate) struct Taskserver {
+    /// prefix of the client_ids of its executors, only set when the command is sent to several
+    /// taskservers
+    name: Option<String>,
+    client: CommanderServiceClient<Channel>,
+}
+
+impl Taskserver {
+    pub(crate) fn single(client: CommanderServiceClient<Channel>) -> Self {
+        Self { name: None, client }
+    }
+
+    fn describe(&self) -> String {
+        match &self.name {
+            Some(name) => format!("taskserver {}", name),
+            None => "taskserver".to_string(),
+        }
+    }
+}
+
+/// `servers` of the configuration if any, the taskserver of `client` otherwise.
+///
+/// The taskservers which cannot be reached are reported then skipped, unless all of them are
+/// required.
+async fn taskservers(
+    client: CommanderServiceClient<Channel>,
+    commander_config: &CommanderConfig,
+    require_all: bool,
+) -> anyhow::Result<Vec<Taskserver>> {
+    let servers = &commander_config.servers;
+    if servers.is_empty() {
+        return Ok(vec![Taskserver::single(client)]);
+    }
+    let mut taskservers = vec![];
+    for (name, url) in servers {
+        match connect(url, commander_config).await {
+            Ok(client) => taskservers.push(Taskserver {
+                name: (servers.len() > 1).then(|| name.clone()),
+                client,
+            }),
+            Err(e) if require_all => {
+                return Err(e.context(format!("Taskserver {} is required", name)))
+            }
+            Err(e) => eprintln!(
+                "{}: taskserver {} skipped: {:#}",
+                "Warning".yellow(),
+                name,
+                e
+            ),
+        }
+    }
+    if taskservers.is_empty() {
+        return Err(anyhow!(
+            "None of the configured taskservers could be reached"
+        ));
+    }
+    Ok(taskservers)
+}
+
+/// Distinguish the executors of a taskserver from the ones of the other taskservers
+fn prefix_client_ids(taskserver: &str, response: &mut TaskResponse) {
+    match response {
+        TaskResponse::MatchingExecutors(matching) => {
+            for client_id in &mut matching.client_id {
+                *client_id = format!("{}/{}", taskserver, client_id);
+            }
+        }
+        TaskResponse::TaskExecutionResult(result) => {
+            result.client_id = format!("{}/{}", taskserver, result.client_id);
+        }
+    }
+}
+
+/// A batch of output lines as the results reporting each line, other responses are kept as is
+fn unbatched(response: TaskResponse) -> Vec<TaskResponse> {
+    match response {
+        TaskResponse::TaskExecutionResult(TaskExecutionResult {
+            task_id,
+            client_id,
+            execution_result: Some(ExecutionResult::TaskOutputBatch(batch)),
+            seq,
+        }) => batch
+            .outputs
+            .into_iter()
+            .map(|output| {
+                TaskResponse::TaskExecutionResult(TaskExecutionResult {
+                    task_id: task_id.clone(),
+                    client_id: client_id.clone(),
+                    execution_result: Some(ExecutionResult::TaskOutput(output)),
+                    seq,
+                })
+            })
+            .collect(),
+        response => vec![response],
+    }
+}
+
+/// Run an empty command, which executors complete as soon as started, with a latency probe
+async fn handle_ping(
+    client: CommanderServiceClient<Channel>,
+    commander_config: &CommanderConfig,
+    query: &str,
+    mut options: CommandOptions,
+    measure: bool,
+) -> Result<CommanderSyntheticOutput, Box<dyn Error>> {
+    let connecting = Instant::now();
+    let client = if commander_config.servers.is_empty() {
+        // the connection to `server_url` is established again to be timed
+        connect(&commander_config.server_url, commander_config).await?
+    } else {
+        client
+    };
+    let taskservers = taskservers(client, commander_config, options.require_all).await?;
+    let connect_duration = connecting.elapsed();
+
+    let signing = Instant::now();
+    let payload = encode_and_sign(
+        LaunchTaskRequestPayload {
+            task: Some(Task::ExecuteCommand(ExecuteCommand {
+                program: Some(Program::Command("".into())),
+                ..Default::default()
+            })),
+        },
+        &commander_config.ed25519_key,
+        commander_config.signature_validity(),
+    )?;
+    let signing_duration = signing.elapsed();
+    let request = LaunchTaskRequest {
+        payload: Some(payload),
+        predicate: query.to_string(),
+        capabilities: Capabilities::local().into(),
+        delegation: commander_config.delegation_certificate()?,
+    };
+
+    // the report replaces the executors output
+    options.group = true;
+    options.no_progress = true;
+    let mut state = RunState::new(&options)?;
+    state.latency = Some(LatencyProbe::new(
+        measure,
+        connect_duration,
+        signing_duration,
+    ));
+    stream_task_responses(
+        &taskservers,
+        commander_config,
+        request,
+        &options,
+        &mut state,
+        &mut Interrupts::ctrl_c(),
+    )
+    .await?;
+    state.finish(&options)
+}
+
+/// Resolve the executors matching `query` without running anything on them.
+///
+/// Executors are sorted by client_id.
+pub async fn resolve_query(
+    client: &mut CommanderServiceClient<Channel>,
+    commander_config: &CommanderConfig,
+    query: &str,
+) -> Result<Vec<ResolvedExecutor>, Box<dyn Error>> {
+    let mut executors = client
+        .resolve_query(encode_and_sign(
+            ResolveQueryRequest {
+                predicate: query.to_string(),
+            },
+            &commander_config.ed25519_key,
+            commander_config.signature_validity(),
+        )?)
+        .await?
+        .into_inner()
+        .executors;
+    executors.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+    Ok(executors)
+}
+
+#[derive(Serialize)]
+struct DryRunExecutor {
+    client_id: String,
+    connected: bool,
+    tags: BTreeMap<String, Tag>,
+}
+
+/// Print the executors matching the query, using the same matching code as task launches
+async fn handle_dry_run(
     mut client: CommanderServiceClient<Channel>,
-    request: Request<LaunchTaskRequest>,
+    commander_config: &CommanderConfig,
+    query: &str,
+    command: &str,
+    json: bool,
+) -> Result<CommanderSyntheticOutput, Box<dyn Error>> {
+    let executors = resolve_query(&mut client, commander_config, query).await?;
+    if json {
+        let executors: Vec<DryRunExecutor> = executors
+            .iter()
+            .map(|executor| DryRunExecutor {
+                client_id: executor.client_id.clone(),
+                connected: executor.connected,
+                tags: executor
+                    .tags
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.into()))
+                    .collect(),
+            })
+            .collect();
+        println!("{}", serde_json::to_string(&executors)?);
+    } else {
+        println!(
+            "Dry run: `{}` would be sent to {} executors",
+            command,
+            executors.len()
+        );
+        let (connected, disconnected): (Vec<_>, Vec<_>) =
+            executors.iter().partition(|executor| executor.connected);
+        if !connected.is_empty() {
+            println!(
+                "{}: {}",
+                "Connected".green(),
+                colorize(connected.iter().map(|e| &e.client_id), Color::Green)
+            );
+        }
+        if !disconnected.is_empty() {
+            println!(
+                "{}: {}",
+                "Disconnected".red(),
+                colorize(disconnected.iter().map(|e| &e.client_id), Color::Red)
+            );
+        }
+    }
+    Ok(CommanderSyntheticOutput::DryRun(
+        executors
+            .into_iter()
+            .map(|executor| executor.client_id)
+            .collect(),
+    ))
+}
+
+/// Expiration date (unix timestamp in seconds) of a key expiring in `expires_in` days, 0 if it
+/// never expires
+fn expires_at_secs(expires_in: Option<u64>) -> anyhow::Result<u64> {
+    let days = match expires_in {
+        Some(days) => days,
+        None => return Ok(0),
+    };
+    days.checked_mul(24 * 3600)
+        .and_then(|secs| SystemTime::now().checked_add(Duration::from_secs(secs)))
+        .map(|expires_at| {
+            expires_at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        })
+        .ok_or_else(|| anyhow!("Key expiry too far away: {} days", days))
+}
+
+/// Query matching the given executors, and only them
+pub(crate) fn client_ids_predicate(client_ids: &[String]) -> String {
+    client_ids
+        .iter()
+        .map(|client_id| format!("\"{}\"", client_id))
+        .collect::<Vec<_>>()
+        .join(" or ")
+}
+
+/// Run the command on matching executors, `batch_size` executors at a time.
+///
+/// Matching executors are resolved once, then each batch is launched with a query restricted to
+/// its client_ids. A single progress bar & synthetic output cover all the batches.
+async fn handle_batched_cmd(
+    mut client: CommanderServiceClient<Channel>,
+    commander_config: &CommanderConfig,
+    query: &str,
+    execute_command: ExecuteCommand,
     options: CommandOptions,
+    batch_size: usize,
+    batch: BatchOptions,
 ) -> Result<CommanderSyntheticOutput, Box<dyn Error>> {
-    let CommandOptions {
-        raw,
-        group,
-        no_progress,
-        no_std_process_return,
-    } = options;
+    let client_ids: Vec<String> = resolve_query(&mut client, commander_config, query)
+        .await?
+        .into_iter()
+        .map(|executor| executor.client_id)
+        .collect();
+    let batches: Vec<&[String]> = client_ids.chunks(batch_size.max(1)).collect();
 
-    let mut response = client.launch_task(request).await?.into_inner();
+    let mut state = RunState::new(&options)?;
+    let mut interrupts = Interrupts::ctrl_c();
+    if !options.raw
+        && !options.no_progress
+        && !options.json
+        && !options.quiet
+        && atty::is(Stream::Stdout)
+    {
+        state
+            .set_progress_bar(ProgressBar::new(client_ids.len() as u64))
+            .await;
+    }
 
-    let mut executors = HashMap::new();
+    for (index, batch_client_ids) in batches.iter().enumerate() {
+        if index > 0 && batch.batch_delay > 0 {
+            tokio::time::sleep(Duration::from_secs(batch.batch_delay)).await;
+        }
+        if !options.raw && !options.json {
+            state
+                .renderer
+                .message(format!("Batch {}/{}", index + 1, batches.len()))
+                .await;
+        }
+        let request = LaunchTaskRequest {
+            payload: Some(encode_and_sign(
+                LaunchTaskRequestPayload {
+                    task: Some(Task::ExecuteCommand(execute_command.clone())),
+                },
+                &commander_config.ed25519_key,
+                commander_config.signature_validity(),
+            )?),
+            predicate: client_ids_predicate(batch_client_ids),
+            capabilities: Capabilities::local().into(),
+            delegation: commander_config.delegation_certificate()?,
+        };
+        stream_task_responses(
+            &[Taskserver::single(client.clone())],
+            commander_config,
+            request,
+            &options,
+            &mut state,
+            &mut interrupts,
+        )
+        .await?;
+        if state.cancelling {
+            break;
+        }
+
+        if batch.fail_fast
+            && batch_client_ids.iter().any(|client_id| {
+                state.tracker.executors.get(client_id) != Some(&ExecutorState::Success)
+            })
+        {
+            let skipped: usize = batches[index + 1..].iter().map(|b| b.len()).sum();
+            if skipped > 0 {
+                state
+                    .renderer
+                    .message(format!(
+                        "{}: command failed, {} executors skipped",
+                        "Aborting".red(),
+                        skipped
+                    ))
+                    .await;
+            }
+            break;
+        }
+    }
+    state.finish(&options)
+}
+
+/// `--json` output of a command
+#[derive(Serialize)]
+struct RunJsonSummary<'a> {
+    states: &'a BTreeMap<ExecutorState, BTreeSet<String>>,
+    output: BTreeMap<&'a String, &'a Vec<String>>,
+    /// by executor, only reported by recent executors
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    durations_ms: &'a BTreeMap<String, u64>,
+    /// reason of the rejection by executor
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    rejections: &'a BTreeMap<String, String>,
+}
 
+/// Executors states & outputs of a command, possibly gathered over several launch requests
+struct RunState {
+    tracker: RunTracker,
+    prefix: OutputPrefix,
     // output by executor
-    let mut executors_output = HashMap::new();
+    executors_output: HashMap<String, Vec<String>>,
+    /// duration of the completed tasks by executor, in milliseconds
+    durations: BTreeMap<String, u64>,
+    /// reason of the rejected tasks by executor
+    rejections: BTreeMap<String, String>,
+    /// task id by executor, while the task is running
+    running_tasks: HashMap<String, String>,
+    /// the user asked to cancel the command
+    cancelling: bool,
+    renderer: Renderer,
+    /// where the executors states summary is printed, once the renderer is closed
+    summary: Box<dyn Write + Send>,
+    recorder: Option<TranscriptWriter>,
+    /// `ping`: the latency report replaces the executors states summary
+    latency: Option<LatencyProbe>,
+}
 
-    let mut pb: Option<ProgressBar> = None;
+impl RunState {
+    fn new(options: &CommandOptions) -> anyhow::Result<Self> {
+        Self::with_writers(
+            options,
+            std::io::stdout(),
+            std::io::stderr(),
+            std::io::stdout(),
+        )
+    }
+
+    fn with_writers<O, E, S>(
+        options: &CommandOptions,
+        stdout: O,
+        stderr: E,
+        summary: S,
+    ) -> anyhow::Result<Self>
+    where
+        O: Write + Send + 'static,
+        E: Write + Send + 'static,
+        S: Write + Send + 'static,
+    {
+        let (renderer, summary): (_, Box<dyn Write + Send>) = if options.json || options.quiet {
+            // only the final summary is printed, quiet keeps stdout empty for cron jobs
+            let renderer = Renderer::with_writers(
+                options.render_buffer_lines,
+                true,
+                std::io::sink(),
+                std::io::sink(),
+            );
+            if options.quiet {
+                (renderer, Box::new(stderr))
+            } else {
+                (renderer, Box::new(summary))
+            }
+        } else {
+            // raw output is not worth delaying completions: drop lines rather than wait
+            (
+                Renderer::with_writers(options.render_buffer_lines, options.raw, stdout, stderr),
+                Box::new(summary),
+            )
+        };
+        Ok(Self {
+            tracker: RunTracker::default(),
+            prefix: OutputPrefix::new(options.prefix_format.as_deref(), options.align),
+            executors_output: HashMap::new(),
+            durations: BTreeMap::new(),
+            rejections: BTreeMap::new(),
+            running_tasks: HashMap::new(),
+            cancelling: false,
+            renderer,
+            summary,
+            recorder: options
+                .record
+                .as_deref()
+                .map(TranscriptWriter::create)
+                .transpose()?,
+            latency: None,
+        })
+    }
+
+    async fn set_progress_bar(&mut self, pb: ProgressBar) {
+        self.renderer
+            .send(RenderEvent::ProgressBar(pb.clone()))
+            .await;
+        self.tracker.set_progress_bar(pb);
+    }
+
+    /// Save the response to the `--record` transcript if any, then handle it
+    async fn record_and_handle(
+        &mut self,
+        task_response: TaskResponse,
+        options: &CommandOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(&LaunchTaskResponse {
+                task_response: Some(task_response.clone()),
+            })?;
+        }
+        self.handle_response(task_response, options).await;
+        Ok(())
+    }
+
+    /// Update the executors states & render a response of the taskserver, either live, replayed
+    /// from a transcript or of a local execution
+    async fn handle_response(&mut self, task_response: TaskResponse, options: &CommandOptions) {
+        for task_response in unbatched(task_response) {
+            self.handle_unbatched_response(task_response, options).await;
+        }
+    }
+
+    async fn handle_unbatched_response(
+        &mut self,
+        task_response: TaskResponse,
+        options: &CommandOptions,
+    ) {
+        let &CommandOptions {
+            raw,
+            group,
+            no_progress,
+            json,
+            quiet,
+            ..
+        } = options;
+        // the json summary carries the output of each executor, which is also buffered when quiet
+        // for the synthetic output
+        let (raw, group, no_progress) = (
+            raw && !json,
+            group || json || quiet,
+            no_progress || json || quiet,
+        );
 
-    while let Some(task_execution_result) = response.message().await? {
-        debug!("Received {:?}", task_execution_result);
-        // by convention this field is always here, so we can "safely" unwrap
-        let task_response = task_execution_result.task_response.unwrap();
         match task_response {
             TaskResponse::MatchingExecutors(mut e) => {
                 e.client_id.sort();
                 if !raw {
-                    let executors_string = e.client_id.join(", ");
-                    if no_progress || !atty::is(Stream::Stdout) {
-                        println!("Matching executors: {}", executors_string);
-                    } else {
-                        let progress = ProgressBar::new(e.client_id.len() as u64);
-                        progress.println(format!("Matching executors: {}", executors_string));
-                        pb = Some(progress);
+                    // a progress bar may be shared by several requests
+                    if self.tracker.progress_bar().is_none()
+                        && !no_progress
+                        && atty::is(Stream::Stdout)
+                    {
+                        self.set_progress_bar(ProgressBar::new(e.client_id.len() as u64))
+                            .await;
                     }
+                    self.renderer
+                        .message(format!("Matching executors: {}", e.client_id.join(", ")))
+                        .await;
+                    self.prefix.matching(&e.client_id);
                     for id in e.client_id {
-                        executors.insert(id, ExecutorState::Matching);
+                        self.tracker.matching(id);
                     }
                 }
             }
             TaskResponse::TaskExecutionResult(task_execution_result) => {
+                let RunState {
+                    tracker,
+                    prefix,
+                    executors_output,
+                    durations,
+                    rejections,
+                    running_tasks,
+                    renderer,
+                    ..
+                } = self;
                 let client_id = &task_execution_result.client_id;
-                match task_execution_result.execution_result.unwrap() {
+                let execution_result = task_execution_result.execution_result.unwrap();
+                match &execution_result {
+                    ExecutionResult::Ping(_) | ExecutionResult::TaskQueued(_) => {
+                        // executors report the actual task id once the task is started or queued
+                        running_tasks
+                            .insert(client_id.clone(), task_execution_result.task_id.clone());
+                    }
+                    ExecutionResult::TaskRejected(_)
+                    | ExecutionResult::TaskAborted(_)
+                    | ExecutionResult::TaskCompleted(_)
+                    | ExecutionResult::TaskCancelled(_) => {
+                        running_tasks.remove(client_id);
+                    }
+                    _ => (),
+                }
+                match execution_result {
+                    ExecutionResult::TaskCancelled(_) => {
+                        debug!("Task cancelled on {}", client_id);
+                        tracker.transition(client_id, ExecutorState::Cancelled);
+                        if group && !raw {
+                            if let Some(lines) = executors_output.remove(client_id) {
+                                print_group(renderer, client_id, lines).await;
+                            }
+                        }
+                    }
                     ExecutionResult::TaskRejected(reason) => {
                         debug!("Tasks completed on {} (REJECTED: {})", client_id, reason);
-                        *executors
-                            .entry(client_id.clone())
-                            .or_insert(ExecutorState::Matching) = ExecutorState::Error;
-                        if let Some(pb) = &pb {
-                            pb.inc(1);
-                        }
+                        tracker.transition(client_id, ExecutorState::Rejected);
+                        rejections.insert(client_id.clone(), reason.clone());
                         if group && !raw {
-                            match &pb {
-                                None => {
-                                    println!("{} {}:", "########".green(), client_id);
-                                    println!("{}: {}", "Task rejected".red(), reason);
-                                }
-                                Some(pb) => {
-                                    pb.println(format!("{} {}:", "########".green(), client_id));
-                                    pb.println(format!("{}: {}", "Task rejected".red(), reason));
-                                }
-                            }
+                            renderer
+                                .message(format!("{} {}:", "########".green(), client_id))
+                                .await;
+                            renderer
+                                .message(format!("{}: {}", "Task rejected".red(), reason))
+                                .await;
                         } else {
-                            match &pb {
-                                None => eprintln!(
+                            renderer
+                                .error(format!(
                                     "{}: {}: {}",
                                     client_id.red(),
                                     "Task rejected".red(),
                                     reason
-                                ),
-                                Some(pb) => pb.println(format!(
-                                    "{}: {}: {}",
-                                    client_id.red(),
-                                    "Task rejected".red(),
-                                    reason
-                                )),
-                            }
+                                ))
+                                .await;
                         }
                     }
 
-                    ExecutionResult::TaskAborted(_) => {
+                    ExecutionResult::TaskAborted(aborted) => {
                         debug!("Tasks completed on {} (KILLED)", client_id);
-                        *executors
-                            .entry(client_id.clone())
-                            .or_insert(ExecutorState::Matching) = ExecutorState::Error;
-                        if let Some(pb) = &pb {
-                            pb.inc(1);
-                        }
+                        tracker.transition(client_id, ExecutorState::Error);
                         if group && !raw {
                             if let Some(lines) = executors_output.remove(client_id) {
-                                match &pb {
-                                    None => {
-                                        println!("{} {}:", "########".green(), client_id);
-                                        for line in lines {
-                                            println!("{}", line);
-                                        }
-                                    }
-                                    Some(pb) => {
-                                        pb.println(format!(
-                                            "{} {}:",
-                                            "########".green(),
-                                            client_id
-                                        ));
-                                        for line in lines {
-                                            pb.println(line);
-                                        }
-                                    }
-                                }
+                                print_group(renderer, client_id, lines).await;
                             }
                         }
+                        // empty when reported by older executors
+                        if !aborted.reason.is_empty() {
+                            renderer
+                                .error(format!(
+                                    "{}: {}: {}",
+                                    client_id.red(),
+                                    "Task aborted".red(),
+                                    aborted.reason
+                                ))
+                                .await;
+                        }
                     }
                     ExecutionResult::TaskCompleted(completion) => {
                         debug!(
-                            "Tasks completed on {} with exit code: {}",
-                            client_id, completion.return_code
+                            "Tasks completed on {} with exit code: {} in {}ms",
+                            client_id, completion.return_code, completion.duration_ms
                         );
+                        // not measured by older executors
+                        if completion.duration_ms > 0 {
+                            durations.insert(client_id.clone(), completion.duration_ms);
+                        }
                         if completion.return_code == 0 {
-                            *executors
-                                .entry(client_id.clone())
-                                .or_insert(ExecutorState::Matching) = ExecutorState::Success;
+                            tracker.transition(client_id, ExecutorState::Success);
                         } else {
-                            *executors
-                                .entry(client_id.clone())
-                                .or_insert(ExecutorState::Matching) = ExecutorState::Error;
+                            tracker.transition(client_id, ExecutorState::Error);
                         }
-                        if !raw {
-                            if let Some(pb) = &pb {
-                                pb.inc(1);
-                            }
-                            if group {
-                                if let Some(lines) = executors_output.get(client_id) {
-                                    match &pb {
-                                        None => {
-                                            println!("{} {}:", "########".green(), client_id);
-                                            for line in lines {
-                                                println!("{}", line);
-                                            }
-                                        }
-                                        Some(pb) => {
-                                            pb.println(format!(
-                                                "{} {}:",
-                                                "########".green(),
-                                                client_id
-                                            ));
-                                            for line in lines {
-                                                pb.println(line);
-                                            }
-                                        }
-                                    }
-                                }
+                        if group && !raw {
+                            if let Some(lines) = executors_output.get(client_id) {
+                                print_group(renderer, client_id, lines.clone()).await;
                             }
                         }
                     }
                     ExecutionResult::TaskOutput(output) => {
-                        if let Some(output) = output.output.as_ref() {
+                        if let Some(output) = output.output {
                             if raw {
                                 match output {
-                                    Output::Stdout(o) => println!("{}", o),
-                                    Output::Stderr(e) => eprintln!("{}", e),
+                                    Output::Stdout(o) => renderer.output(o, false).await,
+                                    Output::Stderr(e) => renderer.output(e, true).await,
                                 }
                             } else if group {
                                 (*executors_output
                                     .entry(client_id.clone())
                                     .or_insert(Vec::new()))
                                 .push(match output {
-                                    Output::Stdout(o) => o.clone(),
+                                    Output::Stdout(o) => o,
+                                    Output::Stderr(e) if json => e,
                                     Output::Stderr(e) => format!("{}", e.trim_end().red()),
                                 });
                             } else {
+                                let prefix =
+                                    prefix.format(client_id, tracker.executors.get(client_id));
                                 let out = match output {
                                     Output::Stdout(o) => {
-                                        format!("{}: {}", client_id.green(), o.trim_end())
+                                        format!("{}: {}", prefix.green(), o.trim_end())
                                     }
                                     Output::Stderr(e) => {
-                                        format!("{}: {}", client_id.red(), e.trim_end())
+                                        format!("{}: {}", prefix.red(), e.trim_end())
                                     }
                                 };
-                                match &pb {
-                                    None => println!("{}", out),
-                                    Some(pb) => pb.println(out),
-                                }
+                                renderer.output(out, false).await;
                             }
                         }
                     }
+                    ExecutionResult::TaskQueued(_) => {
+                        debug!("Task queued on {}", client_id);
+                        tracker.transition(client_id, ExecutorState::Alive);
+                    }
                     ExecutionResult::Ping(_) => {
                         debug!("Pinged!");
-                        *executors
-                            .entry(client_id.clone())
-                            .or_insert(ExecutorState::Matching) = ExecutorState::Alive;
+                        tracker.transition(client_id, ExecutorState::Alive);
                     }
                     ExecutionResult::Disconnected(_) => {
                         debug!("{} disconnected!", client_id);
-                        pb.iter().for_each(|pb| {
-                            pb.println(format!("{} disconnected!", client_id.red()))
-                        });
-                        *executors
-                            .entry(client_id.clone())
-                            .or_insert(ExecutorState::Matching) = ExecutorState::Disconnected;
+                        if tracker.progress_bar().is_some() {
+                            renderer
+                                .message(format!("{} disconnected!", client_id.red()))
+                                .await;
+                        }
+                        tracker.transition(client_id, ExecutorState::Disconnected);
                     }
                     ExecutionResult::TaskSubmitted(_) => {
                         debug!("{} task submitted", client_id);
-                        *executors
-                            .entry(client_id.clone())
-                            .or_insert(ExecutorState::Matching) = ExecutorState::Submitted;
+                        tracker.transition(client_id, ExecutorState::Submitted);
                     }
+                    // unpacked by handle_response
+                    ExecutionResult::TaskOutputBatch(_) => (),
                 }
             }
         }
     }
-    pb.iter().for_each(|pb| pb.finish_and_clear());
 
-    let mut success = true;
-    if executors.len() == 0 {
-        success = false;
+    /// Print the executors states summary, then return the synthetic output or exit the process
+    fn finish(self, options: &CommandOptions) -> Result<CommanderSyntheticOutput, Box<dyn Error>> {
+        let RunState {
+            tracker,
+            executors_output,
+            durations,
+            rejections,
+            renderer,
+            mut summary,
+            latency,
+            ..
+        } = self;
+        let dropped_lines = renderer.close();
+        let executors = tracker.finish();
+
+        let mut states = BTreeMap::new();
+        for (client_id, state) in executors {
+            (*states.entry(state).or_insert(BTreeSet::new())).insert(client_id);
+        }
+        let latency = latency.map(|latency| latency.report());
+        if let Some(report) = &latency {
+            if options.json {
+                writeln!(summary, "{}", serde_json::to_string(report)?)?;
+            } else {
+                report.print(&mut summary)?;
+            }
+        } else if options.json {
+            writeln!(
+                summary,
+                "{}",
+                serde_json::to_string(&RunJsonSummary {
+                    states: &states,
+                    output: executors_output.iter().collect(),
+                    durations_ms: &durations,
+                    rejections: &rejections,
+                })?
+            )?;
+        } else if !options.raw {
+            for (state, client_ids) in &states {
+                writeln!(
+                    summary,
+                    "{}: {}",
+                    state,
+                    colorize(client_ids.iter(), state.color())
+                )?;
+            }
+            if let Some(durations) = durations_summary(&durations) {
+                writeln!(summary, "Duration: {}", durations)?;
+            }
+        }
+        summary.flush()?;
+        if dropped_lines > 0 {
+            // stderr so it does not end up mixed with a raw output
+            eprintln!(
+                "{}: {} output lines dropped, the terminal could not keep up (see --render-buffer-lines)",
+                "Warning".yellow(),
+                dropped_lines
+            );
+        }
+        if let (true, Some(report)) = (options.no_std_process_return, latency) {
+            Ok(CommanderSyntheticOutput::Latency(report))
+        } else if options.no_std_process_return {
+            Ok(CommanderSyntheticOutput::Executor {
+                states,
+                output: executors_output,
+                durations_ms: durations,
+                rejections,
+            })
+        } else {
+            std::process::exit(exit_code(&states));
+        }
     }
-    let mut states = BTreeMap::new();
-    for (client_id, state) in executors {
-        if state != ExecutorState::Success {
-            success = false;
+}
+
+/// Exit code of a command, from the final executors states:
+/// - 0: success on all executors
+/// - 2: the command failed on at least one executor (non zero exit code, aborted)
+/// - 3: at least one executor did not complete the command (disconnected, cancelled)
+/// - 4: no executor matched the query
+/// - 6: at least one executor refused the command, none failed or did not complete it
+///
+/// 5 is used when the taskserver cannot be reached or the request cannot be signed, see
+/// [`is_transport_error`].
+pub fn exit_code(states: &BTreeMap<ExecutorState, BTreeSet<String>>) -> i32 {
+    let any = |matching: fn(&ExecutorState) -> bool| {
+        states
+            .iter()
+            .any(|(state, client_ids)| matching(state) && !client_ids.is_empty())
+    };
+    if !any(|_| true) {
+        4
+    } else if any(|state| {
+        !matches!(
+            state,
+            ExecutorState::Success | ExecutorState::Error | ExecutorState::Rejected
+        )
+    }) {
+        3
+    } else if any(|state| *state == ExecutorState::Error) {
+        2
+    } else if any(|state| *state == ExecutorState::Rejected) {
+        REJECTED_EXIT_CODE
+    } else {
+        0
+    }
+}
+
+pub const TRANSPORT_ERROR_EXIT_CODE: i32 = 5;
+pub const REJECTED_EXIT_CODE: i32 = 6;
+
+/// The taskserver could not be reached, refused the request, or the request could not be signed
+pub fn is_transport_error(error: &(dyn Error + 'static)) -> bool {
+    std::iter::successors(Some(error), |error| (*error).source()).any(|error| {
+        error.is::<tonic::Status>()
+            || error.is::<tonic::transport::Error>()
+            || error.is::<EncodePayloadError>()
+    })
+}
+
+/// Ctrl-C presses while a command is running
+pub struct Interrupts {
+    receiver: mpsc::UnboundedReceiver<()>,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl Interrupts {
+    /// Interrupts raised by SIGINT
+    pub fn ctrl_c() -> Self {
+        let (sender, mut interrupts) = Self::channel();
+        interrupts.listener = Some(tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if sender.send(()).is_err() {
+                    break;
+                }
+            }
+        }));
+        interrupts
+    }
+
+    /// Interrupts raised through the returned sender
+    pub fn channel() -> (mpsc::UnboundedSender<()>, Self) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (
+            sender,
+            Self {
+                receiver,
+                listener: None,
+            },
+        )
+    }
+
+    /// Wait for the next interrupt, forever if none can be raised anymore
+    async fn next(&mut self) {
+        if self.receiver.recv().await.is_none() {
+            futures::future::pending::<()>().await;
         }
-        (*states.entry(state).or_insert(BTreeSet::new())).insert(client_id);
     }
-    if !raw {
-        for (state, client_ids) in &states {
-            println!("{}: {}", state, colorize(client_ids.iter(), state.color()));
+}
+
+impl Drop for Interrupts {
+    fn drop(&mut self) {
+        if let Some(listener) = &self.listener {
+            listener.abort();
         }
     }
-    if no_std_process_return {
-        Ok(CommanderSyntheticOutput::Executor {
-            states,
-            output: executors_output,
-        })
+}
+
+/// Run the command, the first interrupt cancels it on all executors, the second one aborts
+/// without waiting for the executors
+pub async fn do_handle_cmd(
+    client: CommanderServiceClient<Channel>,
+    commander_config: &CommanderConfig,
+    request: Request<LaunchTaskRequest>,
+    options: CommandOptions,
+    interrupts: Interrupts,
+) -> Result<CommanderSyntheticOutput, Box<dyn Error>> {
+    do_handle_taskservers_cmd(
+        &[Taskserver::single(client)],
+        commander_config,
+        request.into_inner(),
+        options,
+        interrupts,
+    )
+    .await
+}
+
+/// Run the command on several taskservers at once, states & outputs are merged
+async fn do_handle_taskservers_cmd(
+    taskservers: &[Taskserver],
+    commander_config: &CommanderConfig,
+    request: LaunchTaskRequest,
+    options: CommandOptions,
+    mut interrupts: Interrupts,
+) -> Result<CommanderSyntheticOutput, Box<dyn Error>> {
+    let mut state = RunState::new(&options)?;
+    stream_task_responses(
+        taskservers,
+        commander_config,
+        request,
+        &options,
+        &mut state,
+        &mut interrupts,
+    )
+    .await?;
+    state.finish(&options)
+}
+
+/// Ask the taskserver to cancel tasks, failures are reported but do not stop the command
+async fn cancel_tasks(
+    client: &mut CommanderServiceClient<Channel>,
+    commander_config: &CommanderConfig,
+    renderer: &Renderer,
+    task_ids: Vec<String>,
+) {
+    if task_ids.is_empty() {
+        return;
+    }
+    let result = match encode_and_sign(
+        CancelTasksRequest { task_ids },
+        &commander_config.ed25519_key,
+        commander_config.signature_validity(),
+    ) {
+        Ok(payload) => client
+            .cancel_tasks(payload)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = result {
+        renderer
+            .error(format!("{}: {}", "Unable to cancel tasks".red(), e))
+            .await;
+    }
+}
+
+/// Send the request to all the taskservers and handle their responses as they come.
+///
+/// A taskserver failure stops the command, unless other taskservers can go on without it.
+async fn stream_task_responses(
+    taskservers: &[Taskserver],
+    commander_config: &CommanderConfig,
+    request: LaunchTaskRequest,
+    options: &CommandOptions,
+    state: &mut RunState,
+    interrupts: &mut Interrupts,
+) -> Result<(), Box<dyn Error>> {
+    let fatal_failures = taskservers.len() == 1 || options.require_all;
+    if let Some(latency) = &mut state.latency {
+        latency.request_sent();
+    }
+    let mut responses = vec![];
+    for (index, taskserver) in taskservers.iter().enumerate() {
+        match taskserver.client.clone().launch_task(request.clone()).await {
+            Ok(response) => {
+                responses.push(response.into_inner().map(move |message| (index, message)))
+            }
+            Err(status) if fatal_failures => return Err(status.into()),
+            Err(status) => {
+                state
+                    .renderer
+                    .error(format!(
+                        "{}: {}",
+                        format!("Unable to run the command on {}", taskserver.describe()).red(),
+                        status.message()
+                    ))
+                    .await
+            }
+        }
+    }
+    if responses.is_empty() {
+        return Err(anyhow!("The command could not be sent to any taskserver").into());
+    }
+    let mut responses = futures::stream::select_all(responses);
+    // the progress bar is sized by the first matching executors received
+    let mut matching_responses = 0;
+
+    loop {
+        let (index, task_execution_result) = tokio::select! {
+            // interrupts first: a second Ctrl-C must not be lost among buffered responses
+            biased;
+            _ = interrupts.next() => {
+                if state.cancelling {
+                    return Err(anyhow!("Force quit, tasks may still be running").into());
+                }
+                state.cancelling = true;
+                state
+                    .renderer
+                    .error(format!(
+                        "{} (press again to force quit)",
+                        "Cancelling…".yellow()
+                    ))
+                    .await;
+                // task ids are random: unknown ones are ignored by the other taskservers
+                let task_ids: Vec<String> = state.running_tasks.values().cloned().collect();
+                for taskserver in taskservers {
+                    cancel_tasks(
+                        &mut taskserver.client.clone(),
+                        commander_config,
+                        &state.renderer,
+                        task_ids.clone(),
+                    )
+                    .await;
+                }
+                continue;
+            }
+            message = responses.next() => match message {
+                Some((index, Ok(task_execution_result))) => (index, task_execution_result),
+                Some((_, Err(status))) if fatal_failures => return Err(status.into()),
+                Some((index, Err(status))) => {
+                    state
+                        .renderer
+                        .error(format!(
+                            "{}: {}",
+                            format!("Lost {}", taskservers[index].describe()).red(),
+                            status.message()
+                        ))
+                        .await;
+                    continue;
+                }
+                None => {
+                    if let Some(latency) = &mut state.latency {
+                        latency.stream_ended();
+                    }
+                    break;
+                }
+            },
+        };
+        let taskserver = &taskservers[index];
+        debug!("Received {:?}", task_execution_result);
+        // by convention this field is always here, so we can "safely" unwrap
+        let mut task_response = task_execution_result.task_response.unwrap();
+        if let Some(name) = &taskserver.name {
+            prefix_client_ids(name, &mut task_response);
+        }
+        if let Some(latency) = &mut state.latency {
+            latency.response(&task_response);
+        }
+        if let TaskResponse::MatchingExecutors(matching) = &task_response {
+            if let (true, Some(pb)) = (matching_responses > 0, state.tracker.progress_bar()) {
+                pb.inc_length(matching.client_id.len() as u64);
+            }
+            matching_responses += 1;
+        }
+        if let TaskResponse::TaskExecutionResult(TaskExecutionResult {
+            task_id,
+            execution_result: Some(ExecutionResult::Ping(_) | ExecutionResult::TaskQueued(_)),
+            ..
+        }) = &task_response
+        {
+            if state.cancelling {
+                // started or queued after the cancellation request
+                cancel_tasks(
+                    &mut taskserver.client.clone(),
+                    commander_config,
+                    &state.renderer,
+                    vec![task_id.clone()],
+                )
+                .await;
+            }
+        }
+        state.record_and_handle(task_response, options).await?;
+    }
+    Ok(())
+}
+
+/// Render a transcript saved with `--record`, without any taskserver
+pub async fn replay(
+    path: &Path,
+    options: CommandOptions,
+    realtime: bool,
+) -> Result<CommanderSyntheticOutput, Box<dyn Error>> {
+    let state = RunState::new(&options)?;
+    replay_responses(path, &options, realtime, state).await
+}
+
+async fn replay_responses(
+    path: &Path,
+    options: &CommandOptions,
+    realtime: bool,
+    mut state: RunState,
+) -> Result<CommanderSyntheticOutput, Box<dyn Error>> {
+    let started = Instant::now();
+    for recorded in read_transcript(path)? {
+        if realtime {
+            tokio::time::sleep_until(
+                (started + Duration::from_millis(recorded.elapsed_millis)).into(),
+            )
+            .await;
+        }
+        let Some(response) = recorded.response else {
+            continue;
+        };
+        if let Some(recorder) = &mut state.recorder {
+            recorder.record(&response)?;
+        }
+        if let Some(task_response) = response.task_response {
+            state.handle_response(task_response, options).await;
+        }
+    }
+    state.finish(options)
+}
+
+async fn print_group(renderer: &Renderer, client_id: &str, lines: Vec<String>) {
+    renderer
+        .message(format!("{} {}:", "########".green(), client_id))
+        .await;
+    for line in lines {
+        renderer.message(line).await;
+    }
+}
+
+/// min/avg/max of the task durations, None if no executor reported them
+fn durations_summary(durations_ms: &BTreeMap<String, u64>) -> Option<String> {
+    let min = durations_ms.values().min()?;
+    let max = durations_ms.values().max()?;
+    let avg = durations_ms.values().sum::<u64>() / durations_ms.len() as u64;
+    Some(format!(
+        "min {}, avg {}, max {}",
+        humanize_millis(*min),
+        humanize_millis(avg),
+        humanize_millis(*max)
+    ))
+}
+
+fn humanize_millis(millis: u64) -> String {
+    if millis < 1000 {
+        format!("{}ms", millis)
     } else {
-        std::process::exit(if success { 0 } else { 1 });
+        format!("{:.1}s", millis as f64 / 1000.0)
     }
 }
 
@@ -509,40 +1952,372 @@ fn colorize<'a, T: Iterator<Item = &'a String>>(collection: T, color: Color) ->
     ret
 }
 
-/// This will prompt something if an unsafe command is run from a terminal with a tty input
-///
-/// Unsafe means commands like 'reboot', 'rm'.
-///
-/// It will return an error if the user do not agree to run the command
-fn safeguard_command(command: &str) -> anyhow::Result<()> {
-    let Ok(parsed_commands) = shellish_parse::multiparse(
-        command,
-        ParseOptions::default(),
-        &["&&", "||", "&", "|", ";"],
-    ) else {
-        return Ok(());
+#[cfg(test)]
+mod test {
+    use super::{
+        durations_summary, exit_code, expires_at_secs, load_query_file, read_script, read_stdin,
+        replay_responses, unbatched, Cmd, CommandOptions, RunState,
     };
-    for command in parsed_commands {
-        let Some(command) = command.0.get(0) else {
-            return Ok(());
+    use crate::{Command, CommanderSyntheticOutput, ExecutorState, Opt};
+    use clap::Parser;
+    use funtonic::tokio;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn testdata(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    /// `run.transcript` rendered in each display mode must match `run.<mode>.txt`, set
+    /// `UPDATE_GOLDEN` to write the current rendering instead
+    #[tokio::test]
+    async fn replay_golden_files() {
+        let _colors = crate::without_colors().await;
+        let base = CommandOptions {
+            no_progress: true,
+            no_std_process_return: true,
+            ..Default::default()
         };
-        if command.ends_with("reboot") || command.ends_with("rm") || command.ends_with("halt") {
-            if atty::isnt(Stream::Stdin) {
-                eprintln!("stdin not a tty, running unsafe command {command} anyway!");
-                return Ok(());
+        let modes = [
+            ("stream", base.clone()),
+            (
+                "group",
+                CommandOptions {
+                    group: true,
+                    ..base.clone()
+                },
+            ),
+            (
+                "raw",
+                CommandOptions {
+                    raw: true,
+                    ..base.clone()
+                },
+            ),
+            (
+                "json",
+                CommandOptions {
+                    json: true,
+                    ..base.clone()
+                },
+            ),
+        ];
+        for (mode, options) in modes {
+            let (stdout, stderr) = (SharedBuffer::default(), SharedBuffer::default());
+            let state =
+                RunState::with_writers(&options, stdout.clone(), stderr.clone(), stdout.clone())
+                    .unwrap();
+            replay_responses(&testdata("run.transcript"), &options, false, state)
+                .await
+                .unwrap();
+            let rendered = format!("{}--- stderr ---\n{}", stdout.contents(), stderr.contents());
+
+            let golden = testdata(&format!("run.{}.txt", mode));
+            if std::env::var_os("UPDATE_GOLDEN").is_some() {
+                std::fs::write(&golden, &rendered).unwrap();
             }
-            // unsafe command
-            let mut rl = DefaultEditor::new()?;
-            let prompt = format!("Do you really want to run unsafe command `{command}` (y/N)? ");
-            loop {
-                let line = rl.readline(&prompt)?;
-                if line.eq_ignore_ascii_case("y") || line.eq_ignore_ascii_case("yes") {
-                    return Ok(());
-                } else {
-                    return Err(anyhow!("Cancelled!"));
+            assert_eq!(
+                std::fs::read_to_string(&golden).unwrap(),
+                rendered,
+                "{} rendering changed",
+                mode
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn quiet() {
+        let _colors = crate::without_colors().await;
+        let options = CommandOptions {
+            quiet: true,
+            no_std_process_return: true,
+            ..Default::default()
+        };
+        let (stdout, stderr) = (SharedBuffer::default(), SharedBuffer::default());
+        let state =
+            RunState::with_writers(&options, stdout.clone(), stderr.clone(), stdout.clone())
+                .unwrap();
+        let output = replay_responses(&testdata("run.transcript"), &options, false, state)
+            .await
+            .unwrap();
+
+        assert_eq!("", stdout.contents());
+        assert_eq!(
+            "Disconnected: cache-1\nRejected: db-1\nError: web-2\nSuccess: web-1\n",
+            stderr.contents()
+        );
+        match output {
+            CommanderSyntheticOutput::Executor {
+                states,
+                output,
+                rejections,
+                ..
+            } => {
+                assert_eq!(4, states.len());
+                assert!(states[&ExecutorState::Success].contains("web-1"));
+                assert_eq!("command not allowed on this executor", rejections["db-1"]);
+                assert_eq!(
+                    vec!["nginx is running\n", "warning: disk 91% full"],
+                    output["web-1"]
+                );
+            }
+            _ => panic!("Not an executor output"),
+        }
+
+        for conflicting in ["--raw", "--json"] {
+            assert!(
+                Opt::try_parse_from(["commander", "run", "-q", conflicting, "*", "uptime"])
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn exit_codes() {
+        let states = |states: Vec<(ExecutorState, &str)>| {
+            let mut map: BTreeMap<ExecutorState, BTreeSet<String>> = BTreeMap::new();
+            for (state, client_id) in states {
+                map.entry(state).or_default().insert(client_id.to_string());
+            }
+            map
+        };
+        assert_eq!(4, exit_code(&states(vec![])));
+        assert_eq!(0, exit_code(&states(vec![(ExecutorState::Success, "a")])));
+        assert_eq!(
+            2,
+            exit_code(&states(vec![
+                (ExecutorState::Success, "a"),
+                (ExecutorState::Error, "b")
+            ]))
+        );
+        assert_eq!(
+            3,
+            exit_code(&states(vec![
+                (ExecutorState::Error, "a"),
+                (ExecutorState::Disconnected, "b")
+            ]))
+        );
+        assert_eq!(3, exit_code(&states(vec![(ExecutorState::Cancelled, "a")])));
+        assert_eq!(
+            6,
+            exit_code(&states(vec![
+                (ExecutorState::Success, "a"),
+                (ExecutorState::Rejected, "b")
+            ]))
+        );
+        assert_eq!(
+            2,
+            exit_code(&states(vec![
+                (ExecutorState::Rejected, "a"),
+                (ExecutorState::Error, "b")
+            ]))
+        );
+        assert_eq!(3, exit_code(&states(vec![(ExecutorState::Alive, "a")])));
+    }
+
+    #[test]
+    fn query_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("query");
+
+        std::fs::write(&path, "env:prod and\r\n(role:web or role:api)\r\n").unwrap();
+        assert_eq!(
+            "env:prod and  (role:web or role:api)",
+            load_query_file(&path).unwrap()
+        );
+
+        std::fs::write(&path, " \r\n\n").unwrap();
+        let error = load_query_file(&path).unwrap_err().to_string();
+        assert!(error.contains("No query found in"), "{}", error);
+
+        assert!(load_query_file(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn query_options() {
+        let opt = Opt::try_parse_from(["commander", "run", "--query-file", "q", "uptime", "now"])
+            .unwrap();
+        match opt.command {
+            Command::Cmd(Cmd::Run {
+                query_options,
+                query,
+                command,
+                ..
+            }) => {
+                assert_eq!(Some("q".into()), query_options.query_file);
+                // resolved as the first word of the command
+                assert_eq!(Some("uptime".to_string()), query);
+                assert_eq!(vec!["now"], command);
+            }
+            other => panic!("Not a run command: {:?}", other),
+        }
+
+        assert!(Opt::try_parse_from(["commander", "keys", "--query", "-", "revoke", "k"]).is_ok());
+        assert!(
+            Opt::try_parse_from(["commander", "keys", "--query", "-", "*", "revoke", "k"]).is_err()
+        );
+        assert!(Opt::try_parse_from(["commander", "int", "--query-file", "q", "*"]).is_err());
+        assert!(
+            Opt::try_parse_from(["commander", "int", "--query", "*", "--query-file", "q"]).is_err()
+        );
+        assert!(Opt::try_parse_from(["commander", "run", "uptime"]).is_ok());
+        assert!(Opt::try_parse_from(["commander", "int"]).is_err());
+
+        // the positional query is not spliced into the command
+        let checked = |args: &[&str]| {
+            let mut command_line = vec!["commander", "run"];
+            command_line.extend_from_slice(args);
+            Opt::try_parse_checked_from(command_line)
+        };
+        assert!(checked(&["--query", "*", "uptime"]).is_ok());
+        assert!(checked(&["--query-file", "q", "uptime", "now"]).is_ok());
+        assert!(checked(&["*", "uptime"]).is_ok());
+        for args in [
+            &["*", "--query", "*", "uptime"],
+            &["*", "--query-file", "q", "uptime"],
+        ] {
+            let error = checked(args).unwrap_err();
+            assert_eq!(
+                clap::error::ErrorKind::ArgumentConflict,
+                error.kind(),
+                "{:?}",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn local_option() {
+        let opt = Opt::try_parse_from(["commander", "run", "--local", "echo", "hi"]).unwrap();
+        match opt.command {
+            Command::Cmd(cmd) => {
+                assert!(cmd.is_local());
+                match cmd {
+                    Cmd::Run { query, command, .. } => {
+                        assert_eq!(Some("echo".to_string()), query);
+                        assert_eq!(vec!["hi"], command);
+                    }
+                    other => panic!("Not a run command: {:?}", other),
                 }
             }
+            other => panic!("Not a command: {:?}", other),
+        }
+        // nothing is sent to the taskserver
+        for conflicting in [&["--dry-run"][..], &["--become"], &["--batch-size", "2"]] {
+            let mut args = vec!["commander", "run", "--local"];
+            args.extend_from_slice(conflicting);
+            args.extend_from_slice(&["echo", "hi"]);
+            assert!(Opt::try_parse_from(args).is_err(), "{:?}", conflicting);
         }
     }
-    Ok(())
+
+    #[test]
+    fn durations() {
+        assert_eq!(None, durations_summary(&BTreeMap::new()));
+        let durations = [("a", 250), ("b", 1600), ("c", 62_000)]
+            .iter()
+            .map(|(client_id, ms)| (client_id.to_string(), *ms))
+            .collect();
+        assert_eq!(
+            Some("min 250ms, avg 21.3s, max 62.0s".to_string()),
+            durations_summary(&durations)
+        );
+    }
+
+    #[test]
+    fn key_expiry() {
+        assert_eq!(0, expires_at_secs(None).unwrap());
+        assert!(expires_at_secs(Some(1)).unwrap() > 24 * 3600);
+        assert!(expires_at_secs(Some(u64::MAX / 3600)).is_err());
+    }
+
+    #[test]
+    fn stdin() {
+        assert_eq!(b"abc".to_vec(), read_stdin(&b"abc"[..], 3).unwrap());
+        assert!(read_stdin(&b"abcd"[..], 3).is_err());
+    }
+
+    #[test]
+    fn script() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("maintenance.sh");
+        std::fs::write(&path, "#!/bin/sh\nuptime\n").unwrap();
+        let script = read_script(&path, 64).unwrap();
+        assert_eq!("maintenance.sh", script.name);
+        assert_eq!("#!/bin/sh\nuptime\n", script.content);
+
+        assert!(read_script(&path, 8)
+            .unwrap_err()
+            .to_string()
+            .contains("max_script_bytes"));
+        std::fs::write(&path, b"\x7fELF\x02\x01\x01\x00").unwrap();
+        assert!(read_script(&path, 64)
+            .unwrap_err()
+            .to_string()
+            .ends_with("is not a text file"));
+        assert!(read_script(&dir.path().join("missing.sh"), 64).is_err());
+    }
+
+    #[test]
+    fn unbatched_output() {
+        use grpc_service::grpc_protocol::launch_task_response::TaskResponse;
+        use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+        use grpc_service::grpc_protocol::task_output::Output;
+        use grpc_service::grpc_protocol::{TaskExecutionResult, TaskOutput, TaskOutputBatch};
+
+        let result = |execution_result| {
+            TaskResponse::TaskExecutionResult(TaskExecutionResult {
+                task_id: "task".to_string(),
+                client_id: "exec".to_string(),
+                execution_result: Some(execution_result),
+                seq: 3,
+            })
+        };
+        let outputs = vec![
+            TaskOutput {
+                output: Some(Output::Stdout("a".to_string())),
+            },
+            TaskOutput {
+                output: Some(Output::Stderr("b".to_string())),
+            },
+            TaskOutput {
+                output: Some(Output::Stdout("c".to_string())),
+            },
+        ];
+        assert_eq!(
+            outputs
+                .iter()
+                .cloned()
+                .map(|output| result(ExecutionResult::TaskOutput(output)))
+                .collect::<Vec<_>>(),
+            unbatched(result(ExecutionResult::TaskOutputBatch(TaskOutputBatch {
+                outputs
+            })))
+        );
+        let ping = result(ExecutionResult::TaskQueued(Default::default()));
+        assert_eq!(vec![ping.clone()], unbatched(ping));
+    }
 }
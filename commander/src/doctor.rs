@@ -0,0 +1,538 @@
+//! `admin doctor`: the usual suspects of a fresh deployment checked one after the other, from the
+//! connection to the taskserver to the clocks of the executors.
+//!
+//! The checks needing a previous one to pass (a connection, an authorized key) are skipped when it
+//! fails.
+use crate::admin::send_admin_request;
+use crate::cmd::resolve_query;
+use crate::saved_queries::expand_saved_query;
+use crate::{commander_client, connect_channel, AdminCommandOuputMode, CommanderSyntheticOutput};
+use colored::Colorize;
+use funtonic::capabilities::Capabilities;
+use funtonic::config::CommanderConfig;
+use funtonic::crypto::signed_payload::encode_and_sign;
+use funtonic::diagnostic::DiagnosticReport;
+use funtonic::task_server::{AdminRunningTaskJsonResponse, AdminWhoAmIJsonResponse};
+use funtonic::tokio;
+use funtonic::tonic::transport::Channel;
+use funtonic::PROTOCOL_VERSION;
+use grpc_service::grpc_protocol::admin_request::RequestType;
+use grpc_service::grpc_protocol::commander_service_client::CommanderServiceClient;
+use grpc_service::grpc_protocol::launch_task_request_payload::Task;
+use grpc_service::grpc_protocol::launch_task_response::TaskResponse;
+use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+use grpc_service::grpc_protocol::task_output::Output;
+use grpc_service::grpc_protocol::{
+    AdminRequest, Diagnostic, Empty, LaunchTaskRequest, LaunchTaskRequestPayload, TaskOutput,
+};
+use prettytable::format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR;
+use prettytable::{row, Table};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+
+/// Clock skews up to this are not worth a warning
+const CLOCK_SKEW_WARNING_SECS: f64 = 1.0;
+/// Client ids listed in the details of a check, the others are only counted
+const LISTED_EXECUTORS: usize = 10;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DoctorCheck {
+    pub check: String,
+    pub status: CheckStatus,
+    pub details: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct DoctorReport {
+    pub checks: Vec<DoctorCheck>,
+}
+
+#[derive(Error, Debug)]
+#[error("{0} doctor check(s) failed")]
+pub struct DoctorCheckFailed(pub usize);
+
+impl DoctorReport {
+    fn add(&mut self, check: impl Into<String>, status: CheckStatus, details: impl Into<String>) {
+        self.checks.push(DoctorCheck {
+            check: check.into(),
+            status,
+            details: details.into(),
+        });
+    }
+
+    pub fn count(&self, status: CheckStatus) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| check.status == status)
+            .count()
+    }
+
+    pub fn print(&self) {
+        let mut table = Table::new();
+        table.set_format(*FORMAT_NO_BORDER_LINE_SEPARATOR);
+        table.set_titles(row!["check", "result", "details"]);
+        for check in &self.checks {
+            let result = match check.status {
+                CheckStatus::Pass => "ok".green(),
+                CheckStatus::Warn => "WARNING".yellow(),
+                CheckStatus::Fail => "FAILED".red(),
+            };
+            table.add_row(row![check.check, result, check.details]);
+        }
+        table.printstd();
+        println!(
+            "{} checks: {} passed, {} warnings, {} failed",
+            self.checks.len(),
+            self.count(CheckStatus::Pass).to_string().green(),
+            self.count(CheckStatus::Warn).to_string().yellow(),
+            self.count(CheckStatus::Fail).to_string().red()
+        );
+    }
+}
+
+/// Run the checks against the executors matching `query` and print the report, fails if any
+/// check fails
+pub async fn handle_doctor(
+    commander_config: &CommanderConfig,
+    query: &str,
+    timeout: Duration,
+    output_mode: AdminCommandOuputMode,
+) -> Result<CommanderSyntheticOutput, Box<dyn Error>> {
+    let query = expand_saved_query(query, &commander_config.saved_queries)?;
+    let report = diagnose(commander_config, &query, timeout).await;
+    match output_mode {
+        AdminCommandOuputMode::Json => println!("{}", serde_json::to_string(&report)?),
+        AdminCommandOuputMode::PrettyJson => {
+            println!("{}", serde_json::to_string_pretty(&report)?)
+        }
+        _ => report.print(),
+    }
+    let failed = report.count(CheckStatus::Fail);
+    if failed > 0 {
+        return Err(DoctorCheckFailed(failed).into());
+    }
+    Ok(CommanderSyntheticOutput::Doctor(report))
+}
+
+async fn diagnose(
+    commander_config: &CommanderConfig,
+    query: &str,
+    timeout: Duration,
+) -> DoctorReport {
+    let mut report = DoctorReport::default();
+    let key_id = &commander_config.ed25519_key.id;
+
+    let server_url = commander_config.server_url.as_str();
+    let connecting = Instant::now();
+    let connected = match connect_channel(server_url, commander_config).await {
+        Ok(channel) => {
+            commander_client(channel.clone(), commander_config).map(|client| (channel, client))
+        }
+        Err(e) => Err(e),
+    };
+    let (channel, mut client) = match connected {
+        Ok(connected) => {
+            report.add(
+                "taskserver reachable",
+                CheckStatus::Pass,
+                format!(
+                    "{} connected in {}",
+                    server_url,
+                    millis(connecting.elapsed())
+                ),
+            );
+            connected
+        }
+        Err(e) => {
+            report.add(
+                "taskserver reachable",
+                CheckStatus::Fail,
+                format!("{}: {:#}", server_url, e),
+            );
+            return report;
+        }
+    };
+
+    let admin_url = commander_config.admin_server_url();
+    let admin_channel = if admin_url == server_url {
+        Some(channel)
+    } else {
+        let connecting = Instant::now();
+        match connect_channel(admin_url, commander_config).await {
+            Ok(channel) => {
+                report.add(
+                    "admin listener reachable",
+                    CheckStatus::Pass,
+                    format!(
+                        "{} connected in {}",
+                        admin_url,
+                        millis(connecting.elapsed())
+                    ),
+                );
+                Some(channel)
+            }
+            Err(e) => {
+                report.add(
+                    "admin listener reachable",
+                    CheckStatus::Fail,
+                    format!("{}: {:#}", admin_url, e),
+                );
+                None
+            }
+        }
+    };
+
+    let mut taskserver_version = None;
+    if let Some(admin_channel) = &admin_channel {
+        let running_tasks = admin_json::<BTreeMap<String, AdminRunningTaskJsonResponse>>(
+            admin_channel,
+            commander_config,
+            RequestType::ListRunningTasks(Empty {}),
+        )
+        .await;
+        match running_tasks {
+            Ok(tasks) => report.add(
+                "admin key accepted",
+                CheckStatus::Pass,
+                format!("{}, {} running tasks", key_id, tasks.len()),
+            ),
+            Err(e) => report.add(
+                "admin key accepted",
+                CheckStatus::Fail,
+                format!("{}: {}", key_id, e),
+            ),
+        }
+        // whoami is answered to regular keys too
+        taskserver_version = admin_json::<AdminWhoAmIJsonResponse>(
+            admin_channel,
+            commander_config,
+            RequestType::WhoAmI(Empty {}),
+        )
+        .await
+        .ok()
+        .and_then(|whoami| whoami.version.zip(whoami.protocol_version));
+    }
+
+    let mut executor_protocols: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    match resolve_query(&mut client, commander_config, query).await {
+        Ok(executors) => {
+            report.add(
+                "authorized key accepted",
+                CheckStatus::Pass,
+                format!("{} resolved {}", key_id, query),
+            );
+            let (connected, disconnected): (Vec<_>, Vec<_>) = executors
+                .into_iter()
+                .partition(|executor| executor.connected);
+            let connected: Vec<String> = connected.into_iter().map(|e| e.client_id).collect();
+            let disconnected: Vec<String> = disconnected.into_iter().map(|e| e.client_id).collect();
+            let (status, details) = matching_check(&connected, &disconnected);
+            report.add("matching executors", status, details);
+
+            if !connected.is_empty() {
+                match diagnostic_reports(&mut client, commander_config, query, timeout).await {
+                    Ok(mut outcomes) => {
+                        for client_id in connected {
+                            let (status, details) = match outcomes.remove(&client_id) {
+                                Some(DiagnosticOutcome::Reported {
+                                    report,
+                                    skew_secs,
+                                    uncertainty_secs,
+                                }) => {
+                                    executor_protocols
+                                        .entry(report.protocol_version)
+                                        .or_default()
+                                        .push(client_id.clone());
+                                    clock_check(
+                                        skew_secs,
+                                        uncertainty_secs,
+                                        commander_config.signature_validity(),
+                                    )
+                                }
+                                Some(DiagnosticOutcome::Rejected(reason)) => {
+                                    (CheckStatus::Fail, format!("task rejected: {}", reason))
+                                }
+                                Some(DiagnosticOutcome::Disconnected) => {
+                                    (CheckStatus::Warn, "disconnected".to_string())
+                                }
+                                None => (
+                                    CheckStatus::Warn,
+                                    format!(
+                                        "no report within {}s, executors older than the \
+                                         diagnostic task do not answer it",
+                                        timeout.as_secs()
+                                    ),
+                                ),
+                            };
+                            report.add(format!("clock skew of {}", client_id), status, details);
+                        }
+                    }
+                    Err(e) => report.add("diagnostic task", CheckStatus::Fail, e.to_string()),
+                }
+            }
+        }
+        Err(e) => report.add(
+            "authorized key accepted",
+            CheckStatus::Fail,
+            format!("{}: {}", key_id, e),
+        ),
+    }
+
+    let (status, details) = protocol_check(taskserver_version, &executor_protocols);
+    report.add("protocol versions", status, details);
+    report
+}
+
+/// Send an admin request, its errors are reported by the checks rather than displayed
+async fn admin_json<T: DeserializeOwned>(
+    channel: &Channel,
+    commander_config: &CommanderConfig,
+    request_type: RequestType,
+) -> Result<T, Box<dyn Error>> {
+    let request = AdminRequest {
+        request_type: Some(request_type),
+    };
+    let json = send_admin_request(channel, commander_config, request, None).await?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+enum DiagnosticOutcome {
+    Reported {
+        report: DiagnosticReport,
+        skew_secs: f64,
+        uncertainty_secs: f64,
+    },
+    Rejected(String),
+    Disconnected,
+}
+
+/// Run the diagnostic task, the executors which did not answer within `timeout` are missing
+async fn diagnostic_reports(
+    client: &mut CommanderServiceClient<Channel>,
+    commander_config: &CommanderConfig,
+    query: &str,
+    timeout: Duration,
+) -> Result<BTreeMap<String, DiagnosticOutcome>, Box<dyn Error>> {
+    let request = LaunchTaskRequest {
+        payload: Some(encode_and_sign(
+            LaunchTaskRequestPayload {
+                task: Some(Task::Diagnostic(Diagnostic {})),
+            },
+            &commander_config.ed25519_key,
+            commander_config.signature_validity(),
+        )?),
+        predicate: query.to_string(),
+        capabilities: Capabilities::local().into(),
+        delegation: commander_config.delegation_certificate()?,
+    };
+    let deadline = tokio::time::Instant::now() + timeout;
+    let sent = SystemTime::now();
+    let mut responses = client.launch_task(request).await?.into_inner();
+    let mut outcomes = BTreeMap::new();
+    // older executors never complete the task: the stream is read until the deadline at most
+    while let Ok(response) = tokio::time::timeout_at(deadline, responses.message()).await {
+        let Some(response) = response? else {
+            break;
+        };
+        let Some(TaskResponse::TaskExecutionResult(result)) = response.task_response else {
+            continue;
+        };
+        let outcome = match result.execution_result {
+            Some(ExecutionResult::TaskOutput(TaskOutput {
+                output: Some(Output::Stdout(line)),
+            })) => match DiagnosticReport::decode(&line) {
+                Some(report) => {
+                    let received = SystemTime::now();
+                    DiagnosticOutcome::Reported {
+                        skew_secs: report.clock_skew_secs(sent, received),
+                        uncertainty_secs: received
+                            .duration_since(sent)
+                            .unwrap_or_default()
+                            .as_secs_f64()
+                            / 2.0,
+                        report,
+                    }
+                }
+                None => continue,
+            },
+            Some(ExecutionResult::TaskRejected(reason)) => DiagnosticOutcome::Rejected(reason),
+            Some(ExecutionResult::Disconnected(_)) => DiagnosticOutcome::Disconnected,
+            _ => continue,
+        };
+        outcomes.insert(result.client_id, outcome);
+    }
+    Ok(outcomes)
+}
+
+fn matching_check(connected: &[String], disconnected: &[String]) -> (CheckStatus, String) {
+    match (connected.len(), disconnected.len()) {
+        (0, 0) => (
+            CheckStatus::Warn,
+            "no executor matches the query".to_string(),
+        ),
+        (connected, 0) => (CheckStatus::Pass, format!("{} connected", connected)),
+        (connected, _) => (
+            CheckStatus::Warn,
+            format!(
+                "{} connected, {} disconnected: {}",
+                connected,
+                disconnected.len(),
+                listed(disconnected)
+            ),
+        ),
+    }
+}
+
+/// A skew larger than the validity of the signatures makes the signed payloads expired (or not
+/// yet valid) for the other end
+fn clock_check(
+    skew_secs: f64,
+    uncertainty_secs: f64,
+    signature_validity: Duration,
+) -> (CheckStatus, String) {
+    let details = format!("{:+.3}s (±{:.3}s)", skew_secs, uncertainty_secs);
+    let min_skew = skew_secs.abs() - uncertainty_secs;
+    if min_skew >= signature_validity.as_secs_f64() {
+        (
+            CheckStatus::Fail,
+            format!(
+                "{}, more than the {}s validity of the signatures: signed payloads are rejected",
+                details,
+                signature_validity.as_secs()
+            ),
+        )
+    } else if min_skew > CLOCK_SKEW_WARNING_SECS {
+        (CheckStatus::Warn, details)
+    } else {
+        (CheckStatus::Pass, details)
+    }
+}
+
+/// The protocol version of the commander against the ones of the taskserver (`(version,
+/// protocol_version)`, if it reported it) and of the executors
+fn protocol_check(
+    taskserver: Option<(String, String)>,
+    executors: &BTreeMap<String, Vec<String>>,
+) -> (CheckStatus, String) {
+    let mut status = CheckStatus::Pass;
+    let mut details = vec![format!("commander {}", PROTOCOL_VERSION)];
+    match taskserver {
+        Some((version, protocol_version)) => {
+            if protocol_version != PROTOCOL_VERSION {
+                status = CheckStatus::Warn;
+            }
+            details.push(format!(
+                "taskserver {} (core {})",
+                protocol_version, version
+            ));
+        }
+        None => {
+            status = CheckStatus::Warn;
+            details.push("taskserver unknown (not reported)".to_string());
+        }
+    }
+    for (protocol_version, client_ids) in executors {
+        if protocol_version != PROTOCOL_VERSION {
+            status = CheckStatus::Warn;
+            details.push(format!(
+                "executors {}: {}",
+                protocol_version,
+                listed(client_ids)
+            ));
+        } else {
+            details.push(format!(
+                "{} executors {}",
+                client_ids.len(),
+                protocol_version
+            ));
+        }
+    }
+    (status, details.join(", "))
+}
+
+fn listed(client_ids: &[String]) -> String {
+    let mut listed = client_ids
+        .iter()
+        .take(LISTED_EXECUTORS)
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ");
+    if client_ids.len() > LISTED_EXECUTORS {
+        listed.push_str(&format!(
+            " and {} more",
+            client_ids.len() - LISTED_EXECUTORS
+        ));
+    }
+    listed
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.2}ms", duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{clock_check, listed, matching_check, protocol_check, CheckStatus};
+    use funtonic::PROTOCOL_VERSION;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    #[test]
+    fn clock_checks() {
+        let validity = Duration::from_secs(30);
+        assert_eq!(
+            (CheckStatus::Pass, "+0.200s (±0.050s)".to_string()),
+            clock_check(0.2, 0.05, validity)
+        );
+        // within the uncertainty of the measure
+        assert_eq!(CheckStatus::Pass, clock_check(-1.5, 0.6, validity).0);
+        assert_eq!(CheckStatus::Warn, clock_check(-1.5, 0.1, validity).0);
+        let (status, details) = clock_check(45.0, 0.1, validity);
+        assert_eq!(CheckStatus::Fail, status);
+        assert!(details.contains("30s validity"), "{}", details);
+    }
+
+    #[test]
+    fn matching_and_protocol_checks() {
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        assert_eq!(CheckStatus::Warn, matching_check(&[], &[]).0);
+        assert_eq!(
+            (CheckStatus::Pass, "2 connected".to_string()),
+            matching_check(&ids(&["a", "b"]), &[])
+        );
+        assert_eq!(
+            (
+                CheckStatus::Warn,
+                "1 connected, 1 disconnected: b".to_string()
+            ),
+            matching_check(&ids(&["a"]), &ids(&["b"]))
+        );
+        let many: Vec<String> = (0..12).map(|i| format!("e{}", i)).collect();
+        assert!(listed(&many).ends_with("e9 and 2 more"));
+
+        let mut executors = BTreeMap::new();
+        executors.insert(PROTOCOL_VERSION.to_string(), ids(&["a", "b"]));
+        let taskserver = Some(("1.0.0".to_string(), PROTOCOL_VERSION.to_string()));
+        assert_eq!(
+            CheckStatus::Pass,
+            protocol_check(taskserver.clone(), &executors).0
+        );
+        assert_eq!(CheckStatus::Warn, protocol_check(None, &executors).0);
+        executors.insert("0.0.1".to_string(), ids(&["c"]));
+        let (status, details) = protocol_check(taskserver, &executors);
+        assert_eq!(CheckStatus::Warn, status);
+        assert!(details.contains("executors 0.0.1: c"), "{}", details);
+    }
+}
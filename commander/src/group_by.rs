@@ -0,0 +1,178 @@
+//! Executors counts by tag value (`--group-by`)
+use funtonic::executor_meta::{ExecutorMeta, Tag};
+use prettytable::format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR;
+use prettytable::{Row, Table};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Value counted for the executors without the tag
+const MISSING: &str = "<missing>";
+
+/// Counts by value of the first key, nested by value of the next keys
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub(crate) enum Groups {
+    Count(usize),
+    Nested(BTreeMap<String, Groups>),
+}
+
+impl Groups {
+    /// `keys` are `:` separated tag paths, like in queries (`os:type`)
+    pub(crate) fn of<'a, S: AsRef<str>>(
+        executors: impl IntoIterator<Item = &'a ExecutorMeta>,
+        keys: &[S],
+    ) -> Self {
+        let paths: Vec<Vec<&str>> = keys
+            .iter()
+            .map(|key| key.as_ref().split(':').collect())
+            .collect();
+        let mut groups = Self::new(&paths);
+        for meta in executors {
+            groups.add(meta, &paths);
+        }
+        groups
+    }
+
+    fn new(paths: &[Vec<&str>]) -> Self {
+        if paths.is_empty() {
+            Groups::Count(0)
+        } else {
+            Groups::Nested(BTreeMap::new())
+        }
+    }
+
+    fn add(&mut self, meta: &ExecutorMeta, paths: &[Vec<&str>]) {
+        match (self, paths) {
+            (Groups::Nested(groups), [path, paths @ ..]) => {
+                for value in tag_values(meta.tag(path)) {
+                    groups
+                        .entry(value)
+                        .or_insert_with(|| Self::new(paths))
+                        .add(meta, paths);
+                }
+            }
+            (Groups::Count(count), _) => *count += 1,
+            (Groups::Nested(_), []) => unreachable!("as many nesting levels as paths"),
+        }
+    }
+
+    /// One row per combination of values, ending with its count
+    pub(crate) fn rows(&self) -> Vec<Vec<String>> {
+        match self {
+            Groups::Count(count) => vec![vec![count.to_string()]],
+            Groups::Nested(groups) => groups
+                .iter()
+                .flat_map(|(value, groups)| {
+                    groups.rows().into_iter().map(move |mut row| {
+                        row.insert(0, value.clone());
+                        row
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn print<S: AsRef<str>>(&self, keys: &[S]) {
+        let mut table = Table::new();
+        table.set_format(*FORMAT_NO_BORDER_LINE_SEPARATOR);
+        table.set_titles(Row::from(
+            keys.iter()
+                .map(AsRef::as_ref)
+                .chain(std::iter::once("count")),
+        ));
+        for row in self.rows() {
+            table.add_row(Row::from(row));
+        }
+        table.printstd();
+    }
+}
+
+/// An executor is counted once per distinct value of a list
+fn tag_values(tag: Option<&Tag>) -> BTreeSet<String> {
+    let mut values = BTreeSet::new();
+    match tag {
+        Some(Tag::Value(value)) => {
+            values.insert(value.clone());
+        }
+        Some(Tag::List(tags)) => values.extend(tags.iter().map(tag_value)),
+        Some(tag @ Tag::Map(_)) => {
+            values.insert(tag_value(tag));
+        }
+        None => (),
+    }
+    if values.is_empty() {
+        values.insert(MISSING.to_string());
+    }
+    values
+}
+
+/// Maps & nested lists are counted by their json serialization
+fn tag_value(tag: &Tag) -> String {
+    match tag {
+        Tag::Value(value) => value.clone(),
+        tag => serde_json::to_string(tag).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Groups;
+    use funtonic::executor_meta::ExecutorMeta;
+    use std::collections::BTreeMap;
+
+    fn executors() -> Vec<ExecutorMeta> {
+        serde_yaml::from_str(
+            r#"
+- client_id: web-1
+  version: 0.21.5
+  tags: {os: {type: Linux}, roles: [web, api, web]}
+- client_id: web-2
+  version: 0.21.5
+  tags: {os: {type: Linux}, roles: [web]}
+- client_id: win-1
+  version: 0.21.5
+  tags: {os: {type: Windows}, roles: web}
+- client_id: bare
+  version: 0.21.5
+  tags: {}
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn group_by() {
+        let executors = executors();
+        let groups = Groups::of(&executors, &["os:type"]);
+        assert_eq!(
+            r#"{"<missing>":1,"Linux":2,"Windows":1}"#,
+            serde_json::to_string(&groups).unwrap()
+        );
+
+        // lists count once per distinct value
+        let groups = Groups::of(&executors, &["roles"]);
+        assert_eq!(
+            r#"{"<missing>":1,"api":1,"web":3}"#,
+            serde_json::to_string(&groups).unwrap()
+        );
+
+        let groups = Groups::of(&executors, &["os:type", "roles"]);
+        assert_eq!(
+            vec![
+                vec!["<missing>", "<missing>", "1"],
+                vec!["Linux", "api", "1"],
+                vec!["Linux", "web", "2"],
+                vec!["Windows", "web", "1"],
+            ],
+            groups.rows()
+        );
+        let nested: BTreeMap<String, BTreeMap<String, usize>> =
+            serde_json::from_value(serde_json::to_value(&groups).unwrap()).unwrap();
+        assert_eq!(2, nested["Linux"]["web"]);
+
+        assert_eq!(
+            Groups::Nested(BTreeMap::new()),
+            Groups::of(&Vec::<ExecutorMeta>::new(), &["os"])
+        );
+    }
+}
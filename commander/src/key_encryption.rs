@@ -0,0 +1,130 @@
+//! `utils encrypt-key` & `utils decrypt-key`: passphrase protection of the commander key, and the
+//! passphrase of an encrypted key when it is used
+use anyhow::{anyhow, Context};
+use funtonic::config::ED25519Key;
+use funtonic::crypto::passphrase::{decrypt_key, encrypt_key, PassphraseError, KEY_PASSPHRASE_ENV};
+use funtonic::file_utils::replace_file;
+use std::path::Path;
+
+/// Passphrase of an encrypted key: `FUNTONIC_KEY_PASSPHRASE`, asked on the terminal otherwise
+pub(crate) fn key_passphrase(key_id: &str) -> Result<String, PassphraseError> {
+    if let Ok(passphrase) = std::env::var(KEY_PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    rpassword::prompt_password(format!("Passphrase of key {}: ", key_id)).map_err(|e| {
+        PassphraseError::NoPassphrase {
+            key_id: key_id.to_string(),
+            reason: format!("{} (set {} without a terminal)", e, KEY_PASSPHRASE_ENV),
+        }
+    })
+}
+
+/// Passphrase encrypting a key, typed twice when asked on the terminal
+fn new_key_passphrase(key_id: &str) -> anyhow::Result<String> {
+    if let Ok(passphrase) = std::env::var(KEY_PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::prompt_password(format!("New passphrase of key {}: ", key_id))?;
+    if passphrase.is_empty() {
+        return Err(anyhow!("The passphrase must not be empty"));
+    }
+    if rpassword::prompt_password("Confirm the passphrase: ")? != passphrase {
+        return Err(anyhow!("The passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+/// Encrypt the key of the file, printed unless `in_place`
+pub(crate) fn encrypt_key_file(path: &Path, in_place: bool) -> anyhow::Result<()> {
+    convert_file(path, in_place, |key| {
+        Ok(encrypt_key(&key, &new_key_passphrase(&key.id)?)?)
+    })
+}
+
+/// Decrypt the key of the file, printed unless `in_place`
+pub(crate) fn decrypt_key_file(path: &Path, in_place: bool) -> anyhow::Result<()> {
+    convert_file(path, in_place, |key| {
+        Ok(decrypt_key(&key, &key_passphrase(&key.id)?)?)
+    })
+}
+
+fn convert_file(
+    path: &Path,
+    in_place: bool,
+    convert: impl FnOnce(ED25519Key) -> anyhow::Result<ED25519Key>,
+) -> anyhow::Result<()> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read {}", path.display()))?;
+    let converted = convert_key(&content, convert)?;
+    if in_place {
+        // the key is never left half-written, the permissions of the file are kept
+        replace_file(path, converted)
+            .with_context(|| format!("Unable to write {}", path.display()))?;
+    } else {
+        print!("{}", converted);
+    }
+    Ok(())
+}
+
+/// Convert the `ed25519_key` of a commander configuration or of a `utils genkey` output, or a
+/// bare key: the rest of the document is kept
+fn convert_key(
+    content: &str,
+    convert: impl FnOnce(ED25519Key) -> anyhow::Result<ED25519Key>,
+) -> anyhow::Result<String> {
+    let mut document: serde_yaml::Value = serde_yaml::from_str(content)?;
+    let key_value = if document.get("ed25519_key").is_some() {
+        &mut document["ed25519_key"]
+    } else {
+        &mut document
+    };
+    let key: ED25519Key =
+        serde_yaml::from_value(key_value.clone()).context("No ed25519 key found")?;
+    *key_value = serde_yaml::to_value(convert(key)?)?;
+    Ok(serde_yaml::to_string(&document)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::convert_key;
+    use funtonic::config::{CommanderConfig, ED25519Key};
+    use funtonic::crypto::keygen::generate_base64_encoded_keys;
+    use funtonic::crypto::passphrase::{decrypt_key, encrypt_key_with_iterations};
+
+    #[test]
+    fn config_key_conversion() {
+        let (key, _) = generate_base64_encoded_keys("ops");
+        let config = format!(
+            "server_url: http://127.0.0.1:54010\nconfirm_above: 10\n\
+             ed25519_key:\n  id: ops\n  pkcs8: {}\n  public_key: {}\n",
+            key.pkcs8,
+            key.public_key.as_deref().unwrap()
+        );
+        let encrypted = convert_key(&config, |key| {
+            Ok(encrypt_key_with_iterations(&key, "passphrase", 1000)?)
+        })
+        .unwrap();
+        assert!(!encrypted.contains(&key.pkcs8), "{}", encrypted);
+        let parsed: CommanderConfig = serde_yaml::from_str(&encrypted).unwrap();
+        assert!(parsed.ed25519_key.is_encrypted());
+        // the other fields are kept
+        assert_eq!(Some(10), parsed.confirm_above);
+
+        let decrypted =
+            convert_key(&encrypted, |key| Ok(decrypt_key(&key, "passphrase")?)).unwrap();
+        let parsed: CommanderConfig = serde_yaml::from_str(&decrypted).unwrap();
+        assert_eq!(key.pkcs8, parsed.ed25519_key.pkcs8);
+        assert!(!decrypted.contains("pkcs8_encrypted"), "{}", decrypted);
+
+        // bare keys
+        let bare = serde_yaml::to_string(&key).unwrap();
+        let encrypted = convert_key(&bare, |key| {
+            Ok(encrypt_key_with_iterations(&key, "passphrase", 1000)?)
+        })
+        .unwrap();
+        assert!(serde_yaml::from_str::<ED25519Key>(&encrypted)
+            .unwrap()
+            .is_encrypted());
+        assert!(convert_key("server_url: http://127.0.0.1:54010\n", Ok).is_err());
+    }
+}
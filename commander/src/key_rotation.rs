@@ -0,0 +1,173 @@
+//! `keys rotate`: replace the commander key on executors
+use crate::cmd::{client_ids_predicate, do_handle_cmd, CommandOptions, Interrupts};
+use crate::{CommanderSyntheticOutput, ExecutorState, GenerateKeyPairOutput};
+use anyhow::{anyhow, Context};
+use funtonic::capabilities::Capabilities;
+use funtonic::config::CommanderConfig;
+use funtonic::crypto::signed_payload::encode_and_sign;
+use funtonic::data_encoding;
+use funtonic::file_utils::write_private_file;
+use funtonic::tokio;
+use funtonic::tonic::transport::Channel;
+use funtonic::tonic::Request;
+use grpc_service::grpc_protocol::commander_service_client::CommanderServiceClient;
+use grpc_service::grpc_protocol::launch_task_request_payload::Task;
+use grpc_service::grpc_protocol::{LaunchTaskRequest, LaunchTaskRequestPayload, PublicKey};
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Executors reconnect after each key modification: revocations sent meanwhile are retried
+const REVOKE_ATTEMPTS: usize = 10;
+const REVOKE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Generate a new key & authorize it on the executors matching `query`; once all of them
+/// succeeded, the current key is revoked on the same executors if `revoke_old` is set.
+pub async fn rotate_key(
+    client: CommanderServiceClient<Channel>,
+    commander_config: &CommanderConfig,
+    query: &str,
+    new_key_name: &str,
+    revoke_old: bool,
+    out: Option<PathBuf>,
+    mut options: CommandOptions,
+) -> Result<CommanderSyntheticOutput, Box<dyn Error>> {
+    let old_key_id = &commander_config.ed25519_key.id;
+    if new_key_name == old_key_id {
+        return Err(anyhow!(
+            "The new key must not be named after the current key {}",
+            old_key_id
+        )
+        .into());
+    }
+    // each step outcome is needed to go on
+    options.no_std_process_return = true;
+
+    let new_key = GenerateKeyPairOutput::generate(new_key_name);
+    eprintln!("Authorizing key {} on {}", new_key_name, query);
+    let authorize = do_handle_cmd(
+        client.clone(),
+        commander_config,
+        key_request(
+            commander_config,
+            query.to_string(),
+            Task::AuthorizeKey(PublicKey {
+                key_id: new_key_name.to_string(),
+                key_bytes: data_encoding::BASE64
+                    .decode(new_key.authorized_keys[new_key_name].as_bytes())?,
+                expires_at_secs: 0,
+            }),
+        )?,
+        options.clone(),
+        Interrupts::ctrl_c(),
+    )
+    .await?;
+
+    let (authorized, failed) = split_success(&authorize);
+    if authorized.is_empty() && failed.is_empty() {
+        return Err(anyhow!("No executor matching {}", query).into());
+    }
+    // the key may be authorized on some executors even if the step failed: never lose it
+    let yaml = serde_yaml::to_string(&new_key)?;
+    match &out {
+        Some(path) => write_private_file(path, yaml)
+            .with_context(|| format!("Unable to write the new key to {}", path.display()))?,
+        None => println!("{}", yaml),
+    }
+
+    if !failed.is_empty() {
+        return Err(anyhow!(
+            "Key {} could not be authorized on all executors ({}), {} has not been revoked",
+            new_key_name,
+            failed.join(", "),
+            old_key_id
+        )
+        .into());
+    }
+    if !revoke_old {
+        return Ok(authorize);
+    }
+
+    let mut pending = authorized;
+    let mut revoke = authorize;
+    let mut failed = Vec::new();
+    for attempt in 1..=REVOKE_ATTEMPTS {
+        eprintln!("Revoking key {} on {} executors", old_key_id, pending.len());
+        revoke = do_handle_cmd(
+            client.clone(),
+            commander_config,
+            key_request(
+                commander_config,
+                client_ids_predicate(&pending),
+                Task::RevokeKey(old_key_id.clone()),
+            )?,
+            options.clone(),
+            Interrupts::ctrl_c(),
+        )
+        .await?;
+        pending = disconnected(&revoke);
+        let retry = attempt < REVOKE_ATTEMPTS;
+        let (_, attempt_failed) = split_success(&revoke);
+        failed.extend(
+            attempt_failed
+                .into_iter()
+                .filter(|client_id| !(retry && pending.contains(client_id))),
+        );
+        if pending.is_empty() || !retry {
+            break;
+        }
+        tokio::time::sleep(REVOKE_RETRY_DELAY).await;
+    }
+    if !failed.is_empty() {
+        return Err(anyhow!(
+            "Key {} could not be revoked on {}",
+            old_key_id,
+            failed.join(", ")
+        )
+        .into());
+    }
+    Ok(revoke)
+}
+
+fn key_request(
+    commander_config: &CommanderConfig,
+    predicate: String,
+    task: Task,
+) -> anyhow::Result<Request<LaunchTaskRequest>> {
+    Ok(Request::new(LaunchTaskRequest {
+        payload: Some(encode_and_sign(
+            LaunchTaskRequestPayload { task: Some(task) },
+            &commander_config.ed25519_key,
+            commander_config.signature_validity(),
+        )?),
+        predicate,
+        capabilities: Capabilities::local().into(),
+        delegation: None,
+    }))
+}
+
+/// Client ids of the executors that succeeded & of the others
+fn split_success(output: &CommanderSyntheticOutput) -> (Vec<String>, Vec<String>) {
+    let mut succeeded = Vec::new();
+    let mut failed = Vec::new();
+    if let CommanderSyntheticOutput::Executor { states, .. } = output {
+        for (state, client_ids) in states {
+            if *state == ExecutorState::Success {
+                succeeded.extend(client_ids.iter().cloned());
+            } else {
+                failed.extend(client_ids.iter().cloned());
+            }
+        }
+    }
+    (succeeded, failed)
+}
+
+fn disconnected(output: &CommanderSyntheticOutput) -> Vec<String> {
+    match output {
+        CommanderSyntheticOutput::Executor { states, .. } => states
+            .get(&ExecutorState::Disconnected)
+            .map(|client_ids| client_ids.iter().cloned().collect())
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,322 @@
+//! Latency breakdown of a no-op command (`ping --measure`).
+//!
+//! Most durations are measured on the commander clock, from the launch request being sent. The
+//! taskserver matching & dispatch durations and the executor start latency are measured by the
+//! taskserver & the executors themselves: no duration mixes the clocks of two hosts, so the
+//! breakdown does not depend on clock synchronization.
+use grpc_service::grpc_protocol::launch_task_response::TaskResponse;
+use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+use prettytable::format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR;
+use prettytable::{Row, Table};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Timing capture points of a command
+pub(crate) struct LatencyProbe {
+    measure: bool,
+    connect: Duration,
+    signing: Duration,
+    sent: Option<Instant>,
+    first_response: Option<Duration>,
+    server_matching: Option<Duration>,
+    hosts: BTreeMap<String, HostTimes>,
+    last_completion: Option<Duration>,
+    stream_end: Option<Duration>,
+}
+
+#[derive(Default)]
+struct HostTimes {
+    submitted: Option<Duration>,
+    dispatch: Option<Duration>,
+    started: Option<Duration>,
+    start_latency: Option<Duration>,
+    completed: Option<Duration>,
+}
+
+impl LatencyProbe {
+    /// Without `measure` only the round trip of each executor is reported
+    pub(crate) fn new(measure: bool, connect: Duration, signing: Duration) -> Self {
+        Self {
+            measure,
+            connect,
+            signing,
+            sent: None,
+            first_response: None,
+            server_matching: None,
+            hosts: BTreeMap::new(),
+            last_completion: None,
+            stream_end: None,
+        }
+    }
+
+    pub(crate) fn request_sent(&mut self) {
+        self.sent.get_or_insert_with(Instant::now);
+    }
+
+    pub(crate) fn response(&mut self, response: &TaskResponse) {
+        let Some(sent) = self.sent else {
+            return;
+        };
+        let elapsed = sent.elapsed();
+        self.first_response.get_or_insert(elapsed);
+        match response {
+            TaskResponse::MatchingExecutors(matching) => {
+                // the slowest taskserver when the command is sent to several of them
+                if let Some(matching) = reported(matching.matching_micros) {
+                    self.server_matching = self.server_matching.max(Some(matching));
+                }
+            }
+            TaskResponse::TaskExecutionResult(result) => {
+                let host = self.hosts.entry(result.client_id.clone()).or_default();
+                match &result.execution_result {
+                    Some(ExecutionResult::TaskSubmitted(submitted)) => {
+                        host.submitted = Some(elapsed);
+                        host.dispatch = reported(submitted.dispatch_micros);
+                    }
+                    Some(ExecutionResult::Ping(started)) => {
+                        host.started = Some(elapsed);
+                        host.start_latency = reported(started.start_latency_micros);
+                    }
+                    Some(
+                        ExecutionResult::TaskCompleted(_)
+                        | ExecutionResult::TaskAborted(_)
+                        | ExecutionResult::TaskRejected(_)
+                        | ExecutionResult::TaskCancelled(_),
+                    ) => {
+                        host.completed = Some(elapsed);
+                        self.last_completion = Some(elapsed);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    pub(crate) fn stream_ended(&mut self) {
+        if let Some(sent) = self.sent {
+            self.stream_end = Some(sent.elapsed());
+        }
+    }
+
+    pub(crate) fn report(&self) -> LatencyReport {
+        let hosts: BTreeMap<String, HostLatency> = self
+            .hosts
+            .iter()
+            .map(|(client_id, times)| {
+                let latency = if self.measure {
+                    HostLatency {
+                        submitted_ms: times.submitted.map(millis),
+                        server_dispatch_ms: times.dispatch.map(millis),
+                        started_ms: times.started.map(millis),
+                        executor_start_ms: times.start_latency.map(millis),
+                        round_trip_ms: times.completed.map(millis),
+                    }
+                } else {
+                    HostLatency {
+                        round_trip_ms: times.completed.map(millis),
+                        ..Default::default()
+                    }
+                };
+                (client_id.clone(), latency)
+            })
+            .collect();
+        let mut aggregates = BTreeMap::new();
+        for (index, column) in HostLatency::COLUMNS.iter().enumerate() {
+            let values: Vec<f64> = hosts
+                .values()
+                .filter_map(|host| host.columns()[index])
+                .collect();
+            if let Some(aggregate) = Aggregate::of(values) {
+                aggregates.insert(*column, aggregate);
+            }
+        }
+        LatencyReport {
+            breakdown: self.measure.then(|| Breakdown {
+                connect_ms: millis(self.connect),
+                signing_ms: millis(self.signing),
+                first_response_ms: self.first_response.map(millis),
+                server_matching_ms: self.server_matching.map(millis),
+                teardown_ms: self
+                    .stream_end
+                    .zip(self.last_completion)
+                    .map(|(end, completion)| millis(end.saturating_sub(completion))),
+            }),
+            hosts,
+            aggregates,
+        }
+    }
+}
+
+/// Durations are reported as 0 by older taskservers & executors
+fn reported(micros: u64) -> Option<Duration> {
+    (micros > 0).then(|| Duration::from_micros(micros))
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[derive(Serialize, Debug)]
+pub struct LatencyReport {
+    /// Durations of the steps which are not specific to an executor, only with `--measure`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub breakdown: Option<Breakdown>,
+    pub hosts: BTreeMap<String, HostLatency>,
+    /// By column of `hosts`
+    pub aggregates: BTreeMap<&'static str, Aggregate>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Breakdown {
+    pub connect_ms: f64,
+    pub signing_ms: f64,
+    pub first_response_ms: Option<f64>,
+    /// measured by the taskserver
+    pub server_matching_ms: Option<f64>,
+    /// from the last executor completion to the end of the response stream
+    pub teardown_ms: Option<f64>,
+}
+
+/// Durations since the launch request was sent, unless measured by the taskserver or the executor
+#[derive(Serialize, Debug, Default)]
+pub struct HostLatency {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub submitted_ms: Option<f64>,
+    /// measured by the taskserver
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_dispatch_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_ms: Option<f64>,
+    /// measured by the executor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub executor_start_ms: Option<f64>,
+    pub round_trip_ms: Option<f64>,
+}
+
+impl HostLatency {
+    const COLUMNS: [&'static str; 5] = [
+        "submitted",
+        "dispatch (server)",
+        "started",
+        "start (executor)",
+        "round trip",
+    ];
+
+    fn columns(&self) -> [Option<f64>; 5] {
+        [
+            self.submitted_ms,
+            self.server_dispatch_ms,
+            self.started_ms,
+            self.executor_start_ms,
+            self.round_trip_ms,
+        ]
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Aggregate {
+    pub min: f64,
+    pub median: f64,
+    pub max: f64,
+}
+
+impl Aggregate {
+    fn of(mut values: Vec<f64>) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(f64::total_cmp);
+        let len = values.len();
+        Some(Self {
+            min: values[0],
+            // both are the middle value when there is an odd number of values
+            median: (values[(len - 1) / 2] + values[len / 2]) / 2.0,
+            max: values[len - 1],
+        })
+    }
+}
+
+impl LatencyReport {
+    pub(crate) fn print(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let columns: Vec<usize> = if self.breakdown.is_some() {
+            (0..HostLatency::COLUMNS.len()).collect()
+        } else {
+            vec![HostLatency::COLUMNS.len() - 1]
+        };
+        let mut table = Table::new();
+        table.set_format(*FORMAT_NO_BORDER_LINE_SEPARATOR);
+        table.set_titles(Row::from(
+            std::iter::once("client_id").chain(columns.iter().map(|c| HostLatency::COLUMNS[*c])),
+        ));
+        for (client_id, host) in &self.hosts {
+            let values = host.columns();
+            table.add_row(Row::from(
+                std::iter::once(client_id.clone())
+                    .chain(columns.iter().map(|c| format_millis(values[*c]))),
+            ));
+        }
+        for (name, value) in [
+            ("min", (|a: &Aggregate| a.min) as fn(&Aggregate) -> f64),
+            ("median", |a| a.median),
+            ("max", |a| a.max),
+        ] {
+            table.add_row(Row::from(std::iter::once(name.to_string()).chain(
+                columns.iter().map(|c| {
+                    format_millis(self.aggregates.get(HostLatency::COLUMNS[*c]).map(value))
+                }),
+            )));
+        }
+        table.print(out)?;
+
+        if let Some(breakdown) = &self.breakdown {
+            writeln!(out)?;
+            for (name, value) in [
+                ("connect", Some(breakdown.connect_ms)),
+                ("signing", Some(breakdown.signing_ms)),
+                ("first response", breakdown.first_response_ms),
+                ("matching (server)", breakdown.server_matching_ms),
+                ("stream teardown", breakdown.teardown_ms),
+            ] {
+                writeln!(out, "{:>18}: {}", name, format_millis(value))?;
+            }
+            writeln!(
+                out,
+                "\nDurations since the request was sent, on the commander clock, except the \
+                 (server) & (executor) ones which are measured on that host: clocks do not need \
+                 to be synchronized. `-` when not reported (older versions)."
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn format_millis(value: Option<f64>) -> String {
+    match value {
+        Some(value) => format!("{:.2}ms", value),
+        None => "-".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Aggregate;
+
+    #[test]
+    fn aggregate() {
+        assert_eq!(None, Aggregate::of(vec![]));
+        assert_eq!(
+            Some(Aggregate {
+                min: 1.0,
+                median: 3.0,
+                max: 8.0
+            }),
+            Aggregate::of(vec![8.0, 1.0, 3.0])
+        );
+        assert_eq!(
+            Some(3.5),
+            Aggregate::of(vec![4.0, 1.0, 3.0, 9.0]).map(|a| a.median)
+        );
+    }
+}
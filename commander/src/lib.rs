@@ -1,12 +1,17 @@
 #[macro_use]
 extern crate log;
 
-pub use crate::admin::{AdminCommand, AdminCommandOuputMode};
+pub use crate::admin::{AdminCommand, AdminCommandError, AdminCommandOuputMode};
+pub use crate::check_config::{ConfigCheckFailed, ConfigRole};
+pub use crate::doctor::{CheckStatus, DoctorCheckFailed, DoctorReport};
+pub use crate::latency::LatencyReport;
+pub use crate::local::LOCAL_CLIENT_ID;
 use anyhow::Context;
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use colored::{Color, Colorize};
-use funtonic::config::{CommanderConfig, ED25519Key};
+use funtonic::config::{self, compression_encoding, CommanderConfig, ED25519Key};
 use funtonic::crypto::keygen::generate_ed25519_key_pair;
+use funtonic::crypto::passphrase::PassphraseError;
 use funtonic::{data_encoding, tonic};
 use grpc_service::grpc_protocol::commander_service_client::CommanderServiceClient;
 use http::Uri;
@@ -17,17 +22,38 @@ use std::path::PathBuf;
 use std::str::FromStr;
 use std::time::Duration;
 use thiserror::Error;
-use tonic::transport::Channel;
+use tonic::codec::CompressionEncoding;
+use tonic::transport::{Channel, Endpoint};
 
 mod admin;
+mod aliases;
+mod check_config;
 pub mod cmd;
+mod doctor;
+mod group_by;
+mod key_encryption;
+mod key_rotation;
+mod latency;
+mod local;
+mod prefix;
+mod progress;
+pub mod render;
+mod safeguard;
+mod saved_queries;
+mod signing;
+mod transcript;
+mod watch;
 
-#[derive(Eq, Ord, PartialOrd, PartialEq, Hash, Debug)]
+#[derive(Eq, Ord, PartialOrd, PartialEq, Hash, Debug, Serialize, Deserialize)]
 pub enum ExecutorState {
     Matching,
     Submitted,
     Alive,
     Disconnected,
+    /// the task has been cancelled by the user (Ctrl-C)
+    Cancelled,
+    /// the executor refused the task (unknown key, policy, clock skew...), nothing was run
+    Rejected,
     Error,
     Success,
 }
@@ -38,6 +64,8 @@ impl Display for ExecutorState {
             ExecutorState::Submitted => write!(f, "{}", "Submitted".color(self.color())),
             ExecutorState::Alive => write!(f, "{}", "Alive".color(self.color())),
             ExecutorState::Disconnected => write!(f, "{}", "Disconnected".color(self.color())),
+            ExecutorState::Cancelled => write!(f, "{}", "Cancelled".color(self.color())),
+            ExecutorState::Rejected => write!(f, "{}", "Rejected".color(self.color())),
             ExecutorState::Error => write!(f, "{}", "Error".color(self.color())),
             ExecutorState::Success => write!(f, "{}", "Success".color(self.color())),
         }
@@ -50,6 +78,8 @@ impl ExecutorState {
             ExecutorState::Submitted => Color::Yellow,
             ExecutorState::Alive => Color::Yellow,
             ExecutorState::Disconnected => Color::Red,
+            ExecutorState::Cancelled => Color::Magenta,
+            ExecutorState::Rejected => Color::BrightRed,
             ExecutorState::Error => Color::Red,
             ExecutorState::Success => Color::Green,
         }
@@ -66,11 +96,36 @@ pub struct Opt {
     pub command: Command,
 }
 
+impl Opt {
+    /// Parse the command line, exiting on error like [`Parser::parse`]
+    pub fn parse_checked() -> Self {
+        Self::try_parse_checked_from(std::env::args_os()).unwrap_or_else(|e| e.exit())
+    }
+
+    /// Parse the arguments, a positional query given along `--query` or `--query-file` is a
+    /// conflict: `run` would otherwise take it for the first word of the command
+    pub fn try_parse_checked_from<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let mut command = Self::command();
+        let matches = command.try_get_matches_from_mut(args)?;
+        if let Some(("run", run)) = matches.subcommand() {
+            cmd::check_run_query(run).map_err(|message| {
+                command.error(clap::error::ErrorKind::ArgumentConflict, message)
+            })?;
+        }
+        Self::from_arg_matches(&matches)
+    }
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Command {
     /// Admin commands
     Admin {
-        /// json (js), pretty-json (pjs) or human-readable (hr) or human-readable-long (hrl)
+        /// json (js), pretty-json (pjs), human-readable (hr), human-readable-long (hrl), plain
+        /// (client_ids only) or csv
         #[arg(short = 'o', long = "output-mode", default_value = "human-readable")]
         output_mode: AdminCommandOuputMode,
         #[command(subcommand)]
@@ -78,6 +133,16 @@ pub enum Command {
     },
     #[command(flatten)]
     Cmd(cmd::Cmd),
+    /// Render again a command recorded with `--record`, without connecting to the taskserver
+    #[command(name = "replay")]
+    Replay {
+        #[command(flatten)]
+        options: cmd::CommandOptions,
+        /// Wait between the responses as long as the recorded command did
+        #[arg(long = "realtime")]
+        realtime: bool,
+        file: PathBuf,
+    },
     /// Utilities
     #[command(name = "utils", subcommand)]
     Utils(Utils),
@@ -91,6 +156,85 @@ pub enum Utils {
         /// name of the key.
         name: String,
     },
+    /// Check a configuration file: keys encoding, TLS files... Fails if any check fails
+    CheckConfig {
+        /// Kind of configuration file, guessed from the file name if not set
+        #[arg(long, value_enum)]
+        role: Option<ConfigRole>,
+        /// Output the report as json
+        #[arg(long)]
+        json: bool,
+        path: PathBuf,
+    },
+    /// Sign a payload file, prints the base64 encoded SignedPayload
+    Sign {
+        /// Key file (commander configuration, genkey output or a bare ed25519 key), `config` to
+        /// use the key of the commander configuration
+        #[arg(long)]
+        key: String,
+        /// Already encoded payload to sign
+        #[arg(long = "payload-file")]
+        payload_file: PathBuf,
+        /// Validity of the signature, in seconds
+        #[arg(long, default_value_t = 60)]
+        validity: u64,
+    },
+    /// Print the public key & its fingerprint, to compare with `admin list-executor-keys`
+    ShowPublicKey {
+        /// Key file (commander configuration, genkey output or an executor key file), `config` to
+        /// use the key of the commander configuration
+        #[arg(long, default_value = "config")]
+        key: String,
+    },
+    /// Delegate the signing rights of a key to a short-lived key, eg: the key of a CI pipeline.
+    /// Prints the base64 encoded delegation certificate, to be set as `delegation` in the
+    /// configuration of the delegated commander
+    Delegate {
+        /// Key file of the delegating key (commander configuration, genkey output or a bare
+        /// ed25519 key), `config` to use the key of the commander configuration
+        #[arg(long, default_value = "config")]
+        key: String,
+        /// Id of the delegated key
+        #[arg(long = "key-id")]
+        key_id: String,
+        /// Base64 encoded public key of the delegated key
+        #[arg(long = "public-key")]
+        public_key: String,
+        /// The delegated key can only launch tasks on the executors matching this query
+        #[arg(long)]
+        query: String,
+        /// Validity of the delegation, in seconds
+        #[arg(long, default_value_t = 3600)]
+        ttl: u64,
+    },
+    /// Verify the signature & the validity date of a base64 encoded SignedPayload
+    Verify {
+        /// Base64 encoded public key of the signing key
+        #[arg(long = "public-key")]
+        public_key: String,
+        /// Base64 encoded SignedPayload
+        #[arg(long)]
+        signed: String,
+    },
+    /// List the saved queries of the commander configuration, usable as `@name` instead of a
+    /// query
+    ListQueries,
+    /// Encrypt the private key of a commander configuration (or a genkey output) with a
+    /// passphrase, asked on the terminal or read from `FUNTONIC_KEY_PASSPHRASE`. The commander
+    /// then asks for it on each run. Prints the converted file.
+    EncryptKey {
+        /// Overwrite the file instead of printing it
+        #[arg(long = "in-place")]
+        in_place: bool,
+        file: PathBuf,
+    },
+    /// Decrypt the private key encrypted by `encrypt-key`. Prints the converted file.
+    DecryptKey {
+        /// Overwrite the file instead of printing it
+        #[arg(long = "in-place")]
+        in_place: bool,
+        file: PathBuf,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -102,54 +246,205 @@ pub async fn commander_main(
     commander_config: CommanderConfig,
 ) -> Result<CommanderSyntheticOutput, Box<dyn std::error::Error>> {
     debug!("Commander starting with config {:#?}", commander_config);
-    let mut channel = Channel::builder(Uri::from_str(&commander_config.server_url)?)
-        .tcp_keepalive(Some(Duration::from_secs(60)));
-    if let Some(tls_config) = &commander_config.tls {
-        info!("TLS configuration found");
-        channel = channel.tls_config(tls_config.get_client_config()?)?;
-    }
-    let channel = channel
-        .connect()
-        .await
-        .context("Unable to connect to taskserver")?;
-
-    let client = CommanderServiceClient::new(channel);
-
-    info!("Connected");
-
+    let commander_config = match &opt.command {
+        Command::Admin { .. } => unlocked(commander_config)?,
+        Command::Cmd(cmd) if !cmd.is_local() => unlocked(commander_config)?,
+        _ => commander_config,
+    };
     match opt.command {
+        Command::Admin {
+            output_mode,
+            command: AdminCommand::Doctor { query, timeout },
+        } => {
+            // the connection to the taskserver is one of the checks
+            doctor::handle_doctor(
+                &commander_config,
+                &query,
+                Duration::from_secs(timeout),
+                output_mode,
+            )
+            .await
+        }
         Command::Admin {
             output_mode,
             command,
-        } => admin::handle_admin_command(client, &commander_config, command, output_mode).await,
-        Command::Cmd(cmd) => cmd::handle_cmd(client, &commander_config, cmd).await,
+        } => {
+            let channel =
+                connect_channel(commander_config.admin_server_url(), &commander_config).await?;
+            admin::handle_admin_command(channel, &commander_config, command, output_mode).await
+        }
+        Command::Cmd(cmd) if cmd.is_local() => cmd::handle_local_cmd(&commander_config, cmd).await,
+        Command::Cmd(cmd) => {
+            // with `servers`, commands fanned out never use `server_url`: an unreachable one must
+            // not abort them
+            let client = if commander_config.servers.is_empty() {
+                connect(&commander_config.server_url, &commander_config).await?
+            } else {
+                connect_lazily(&commander_config.server_url, &commander_config)?
+            };
+            cmd::handle_cmd(client, &commander_config, cmd).await
+        }
+        Command::Replay {
+            options,
+            realtime,
+            file,
+        } => cmd::replay(&file, options, realtime).await,
 
-        Command::Utils(cmd) => handle_utils_cmd(cmd),
+        Command::Utils(cmd) => handle_utils_cmd(&cmd, &opt.config),
     }
 }
 
+/// The configuration with the commander key decrypted, if it is protected by a passphrase
+fn unlocked(mut commander_config: CommanderConfig) -> Result<CommanderConfig, PassphraseError> {
+    let key_id = commander_config.ed25519_key.id.clone();
+    commander_config.ed25519_key = commander_config
+        .ed25519_key
+        .resolve(|| key_encryption::key_passphrase(&key_id))?;
+    Ok(commander_config)
+}
+
+pub(crate) async fn connect(
+    server_url: &str,
+    commander_config: &CommanderConfig,
+) -> anyhow::Result<CommanderServiceClient<Channel>> {
+    commander_client(
+        connect_channel(server_url, commander_config).await?,
+        commander_config,
+    )
+}
+
+/// Client connecting to the taskserver on its first request
+fn connect_lazily(
+    server_url: &str,
+    commander_config: &CommanderConfig,
+) -> anyhow::Result<CommanderServiceClient<Channel>> {
+    commander_client(
+        endpoint(server_url, commander_config)?.connect_lazy(),
+        commander_config,
+    )
+}
+
+/// Client of the commander service over a connected channel
+fn commander_client(
+    channel: Channel,
+    commander_config: &CommanderConfig,
+) -> anyhow::Result<CommanderServiceClient<Channel>> {
+    let client = CommanderServiceClient::new(channel).accept_compressed(CompressionEncoding::Gzip);
+    Ok(
+        match compression_encoding(commander_config.compression.as_deref())? {
+            Some(encoding) => client.send_compressed(encoding),
+            None => client,
+        },
+    )
+}
+
+fn endpoint(server_url: &str, commander_config: &CommanderConfig) -> anyhow::Result<Endpoint> {
+    let mut endpoint =
+        Channel::builder(Uri::from_str(server_url)?).tcp_keepalive(Some(Duration::from_secs(60)));
+    if let Some(tls_config) = &commander_config.tls {
+        info!("TLS configuration found");
+        tls_config.warn_on_expiry();
+        endpoint = endpoint.tls_config(tls_config.get_client_config()?)?;
+    }
+    Ok(endpoint)
+}
+
+async fn connect_channel(
+    server_url: &str,
+    commander_config: &CommanderConfig,
+) -> anyhow::Result<Channel> {
+    let channel = endpoint(server_url, commander_config)?
+        .connect()
+        .await
+        .context("Unable to connect to taskserver")?;
+    info!("Connected to {}", server_url);
+    Ok(channel)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct GenerateKeyPairOutput {
     ed25519_key: ED25519Key,
     authorized_keys: BTreeMap<String, String>,
 }
 
-fn handle_utils_cmd(cmd: Utils) -> Result<CommanderSyntheticOutput, Box<dyn std::error::Error>> {
+impl GenerateKeyPairOutput {
+    fn generate(name: &str) -> Self {
+        let (priv_key, pub_key) = generate_ed25519_key_pair().unwrap();
+        GenerateKeyPairOutput {
+            ed25519_key: ED25519Key {
+                id: name.to_string(),
+                pkcs8: data_encoding::BASE64.encode(&priv_key),
+                public_key: Some(data_encoding::BASE64.encode(&pub_key)),
+                pkcs8_encrypted: None,
+            },
+            authorized_keys: vec![(name.to_string(), data_encoding::BASE64.encode(&pub_key))]
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+/// Utils do not need a configuration nor a taskserver
+pub fn handle_utils_cmd(
+    cmd: &Utils,
+    config: &Option<PathBuf>,
+) -> Result<CommanderSyntheticOutput, Box<dyn std::error::Error>> {
     match cmd {
         Utils::GenerateED25519KeyPair { name } => {
-            let (priv_key, pub_key) = generate_ed25519_key_pair().unwrap();
-            let out = GenerateKeyPairOutput {
-                ed25519_key: ED25519Key {
-                    id: name.clone(),
-                    pkcs8: data_encoding::BASE64.encode(&priv_key),
-                    public_key: Some(data_encoding::BASE64.encode(&pub_key)),
-                },
-                authorized_keys: vec![(name, data_encoding::BASE64.encode(&pub_key))]
-                    .into_iter()
-                    .collect(),
-            };
+            let out = GenerateKeyPairOutput::generate(name);
             println!("Generated Keys:\n{}", serde_yaml::to_string(&out)?);
         }
+        Utils::CheckConfig { role, json, path } => {
+            let role = role
+                .or_else(|| ConfigRole::from_path(path))
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Unable to guess the role of {}, use --role", path.display())
+                })?;
+            let report = check_config::check_config(path, role);
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                report.print();
+            }
+            let failed = report.failed();
+            if failed > 0 {
+                return Err(ConfigCheckFailed(failed).into());
+            }
+        }
+        Utils::Sign {
+            key,
+            payload_file,
+            validity,
+        } => println!(
+            "{}",
+            signing::sign(key, payload_file, Duration::from_secs(*validity), config)?
+        ),
+        Utils::ShowPublicKey { key } => signing::show_public_key(key, config)?,
+        Utils::Delegate {
+            key,
+            key_id,
+            public_key,
+            query,
+            ttl,
+        } => println!(
+            "{}",
+            signing::delegate(
+                key,
+                key_id,
+                public_key,
+                query,
+                Duration::from_secs(*ttl),
+                config
+            )?
+        ),
+        Utils::Verify { public_key, signed } => signing::verify(public_key, signed)?,
+        Utils::ListQueries => {
+            let (commander_config, _) =
+                config::parse::<_, _, CommanderConfig>(config, "commander.yml")?;
+            saved_queries::print_saved_queries(&commander_config.saved_queries);
+        }
+        Utils::EncryptKey { in_place, file } => key_encryption::encrypt_key_file(file, *in_place)?,
+        Utils::DecryptKey { in_place, file } => key_encryption::decrypt_key_file(file, *in_place)?,
     }
     Ok(CommanderSyntheticOutput::Cmd)
 }
@@ -158,7 +453,28 @@ pub enum CommanderSyntheticOutput {
     Executor {
         states: BTreeMap<ExecutorState, BTreeSet<String>>,
         output: HashMap<String, Vec<String>>,
+        /// by executor, only reported by recent executors
+        durations_ms: BTreeMap<String, u64>,
+        /// reason of the rejection by executor
+        rejections: BTreeMap<String, String>,
     },
     Admin(String),
+    /// client_ids of the executors that would have received the command
+    DryRun(Vec<String>),
+    /// `ping` report
+    Latency(LatencyReport),
+    /// `admin doctor` report, when no check failed
+    Doctor(DoctorReport),
     Cmd,
 }
+
+/// Colors are disabled by the tests asserting on the rendered text: a global setting, changed by
+/// one test at a time
+#[cfg(test)]
+pub(crate) async fn without_colors() -> funtonic::tokio::sync::MutexGuard<'static, ()> {
+    use funtonic::tokio::sync::Mutex;
+    static COLORS: Mutex<()> = Mutex::const_new(());
+    let guard = COLORS.lock().await;
+    colored::control::set_override(false);
+    guard
+}
@@ -0,0 +1,160 @@
+//! `commander run --local`: the command is run on this machine by the engine of the executors,
+//! without any taskserver, and rendered as the output of a single executor
+use exec::a_sync::{self, Execution};
+use exec::{signal_name, ExecEvent, Line, Type};
+use futures::{Stream, StreamExt};
+use grpc_service::grpc_protocol::launch_task_response::TaskResponse;
+use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+use grpc_service::grpc_protocol::task_output::Output;
+use grpc_service::grpc_protocol::{
+    MatchingExecutors, TaskAborted, TaskCompleted, TaskExecutionResult, TaskOutput, TaskStarted,
+    TaskSubmitted,
+};
+use std::error::Error;
+use std::time::Instant;
+
+/// client_id of the machine running the commander
+pub const LOCAL_CLIENT_ID: &str = "localhost";
+const LOCAL_TASK_ID: &str = "local";
+
+/// Responses a taskserver would send for the command run by a single executor: the matching
+/// executors, the submission, then the results of the execution
+pub(crate) fn local_responses(
+    command: &str,
+    stdin: Vec<u8>,
+) -> Result<impl Stream<Item = TaskResponse>, Box<dyn Error>> {
+    let received = Instant::now();
+    let Execution {
+        events,
+        kill_sender,
+        ..
+    } = a_sync::exec_command_with_limits(
+        command,
+        stdin,
+        a_sync::DEFAULT_EVENT_BUFFER,
+        a_sync::OutputLimits::default(),
+    )?;
+    let mut started = received;
+    // dropping the kill sender kills the command: it is owned by the stream
+    let results = futures::stream::unfold(
+        (events, kill_sender),
+        |(mut events, kill_sender)| async move {
+            events
+                .recv()
+                .await
+                .map(|event| (event, (events, kill_sender)))
+        },
+    )
+    .map(move |event| match event {
+        ExecEvent::Started => {
+            started = Instant::now();
+            ExecutionResult::Ping(TaskStarted {
+                start_latency_micros: received.elapsed().as_micros() as u64,
+            })
+        }
+        ExecEvent::Finished(status) => match status.code {
+            Some(return_code) => ExecutionResult::TaskCompleted(TaskCompleted {
+                return_code,
+                duration_ms: started.elapsed().as_millis() as u64,
+            }),
+            None => ExecutionResult::TaskAborted(TaskAborted {
+                reason: match status.signal {
+                    Some(signal) => format!("killed by {}", signal_name(signal)),
+                    None => "exited without status".to_string(),
+                },
+            }),
+        },
+        ExecEvent::SpawnFailed(e) => {
+            ExecutionResult::TaskRejected(format!("failed to spawn: {}", e))
+        }
+        ExecEvent::LineEmitted(line) => ExecutionResult::TaskOutput(task_output(line)),
+    });
+    let submitted = ExecutionResult::TaskSubmitted(TaskSubmitted {
+        dispatch_micros: received.elapsed().as_micros() as u64,
+    });
+    Ok(
+        futures::stream::iter([TaskResponse::MatchingExecutors(MatchingExecutors {
+            client_id: vec![LOCAL_CLIENT_ID.to_string()],
+            matching_micros: 0,
+        })])
+        .chain(
+            futures::stream::once(async { submitted })
+                .chain(results)
+                .map(|execution_result| {
+                    TaskResponse::TaskExecutionResult(TaskExecutionResult {
+                        task_id: LOCAL_TASK_ID.to_string(),
+                        client_id: LOCAL_CLIENT_ID.to_string(),
+                        execution_result: Some(execution_result),
+                        seq: 0,
+                    })
+                }),
+        ),
+    )
+}
+
+/// Same conversion as the executors: the protocol only carries text
+fn task_output(line: Line) -> TaskOutput {
+    TaskOutput {
+        output: Some(match &line.line_type {
+            Type::Out => Output::Stdout(String::from_utf8_lossy(&line.line).into_owned()),
+            Type::Err => Output::Stderr(String::from_utf8_lossy(&line.line).into_owned()),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{local_responses, LOCAL_CLIENT_ID};
+    use funtonic::tokio;
+    use futures::StreamExt;
+    use grpc_service::grpc_protocol::launch_task_response::TaskResponse;
+    use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+    use grpc_service::grpc_protocol::task_output::Output;
+
+    #[tokio::test]
+    async fn local_execution() {
+        let responses: Vec<TaskResponse> =
+            local_responses("echo hi; echo oops >&2; exit 3", vec![])
+                .unwrap()
+                .collect()
+                .await;
+        match &responses[0] {
+            TaskResponse::MatchingExecutors(matching) => {
+                assert_eq!(vec![LOCAL_CLIENT_ID.to_string()], matching.client_id)
+            }
+            other => panic!("Not the matching executors: {:?}", other),
+        }
+        let results: Vec<ExecutionResult> = responses[1..]
+            .iter()
+            .map(|response| match response {
+                TaskResponse::TaskExecutionResult(result) => {
+                    assert_eq!(LOCAL_CLIENT_ID, result.client_id);
+                    result.execution_result.clone().unwrap()
+                }
+                other => panic!("Not an execution result: {:?}", other),
+            })
+            .collect();
+        assert!(matches!(results[0], ExecutionResult::TaskSubmitted(_)));
+        assert!(matches!(results[1], ExecutionResult::Ping(_)));
+        let mut outputs: Vec<Output> = results[2..results.len() - 1]
+            .iter()
+            .map(|result| match result {
+                ExecutionResult::TaskOutput(output) => output.output.clone().unwrap(),
+                other => panic!("Not an output: {:?}", other),
+            })
+            .collect();
+        // stdout & stderr are read concurrently
+        outputs.sort_by_key(|output| matches!(output, Output::Stderr(_)));
+        assert_eq!(
+            vec![
+                Output::Stdout("hi".to_string()),
+                Output::Stderr("oops".to_string())
+            ],
+            outputs
+        );
+        match &results[results.len() - 1] {
+            ExecutionResult::TaskCompleted(completed) => assert_eq!(3, completed.return_code),
+            other => panic!("Not completed: {:?}", other),
+        }
+    }
+}
@@ -1,5 +1,7 @@
-use clap::Parser;
-use commander::{commander_main, Opt};
+use commander::cmd::{is_transport_error, TRANSPORT_ERROR_EXIT_CODE};
+use commander::{
+    commander_main, handle_utils_cmd, AdminCommandError, Command, DoctorCheckFailed, Opt,
+};
 use funtonic::config;
 use funtonic::tokio;
 use tracing_subscriber::EnvFilter;
@@ -15,7 +17,40 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     )
     .expect("setting tracing default failed");
     tracing_log::LogTracer::init().unwrap();
-    let opt: Opt = Opt::parse();
+    let opt = Opt::parse_checked();
+    if let Command::Utils(cmd) = &opt.command {
+        if let Err(e) = handle_utils_cmd(cmd, &opt.config) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Command::Replay {
+        options,
+        realtime,
+        file,
+    } = opt.command
+    {
+        commander::cmd::replay(&file, options, realtime).await?;
+        return Ok(());
+    }
     let (config, _) = config::parse(&opt.config, "commander.yml")?;
-    commander_main(opt, config).await.map(|_| ())
+    let is_cmd = matches!(opt.command, Command::Cmd(_));
+    if let Err(e) = commander_main(opt, config).await {
+        if let Some(admin_error) = e.downcast_ref::<AdminCommandError>() {
+            // the error has already been displayed according to the output mode
+            std::process::exit(admin_error.exit_code());
+        }
+        if let Some(failed) = e.downcast_ref::<DoctorCheckFailed>() {
+            // the report has already been displayed
+            eprintln!("{}", failed);
+            std::process::exit(1);
+        }
+        if is_cmd && is_transport_error(e.as_ref()) {
+            eprintln!("Error: {}", e);
+            std::process::exit(TRANSPORT_ERROR_EXIT_CODE);
+        }
+        return Err(e);
+    }
+    Ok(())
 }
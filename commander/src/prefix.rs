@@ -0,0 +1,121 @@
+//! Prefix of the live output lines (`--prefix-format` & `--align`)
+use crate::ExecutorState;
+use unicode_width::UnicodeWidthStr;
+
+pub(crate) const DEFAULT_PREFIX_FORMAT: &str = "{host}";
+
+pub(crate) struct OutputPrefix {
+    format: String,
+    align: bool,
+    /// display width of the longest client_id & short hostname matched so far
+    host_width: usize,
+    short_width: usize,
+}
+
+impl OutputPrefix {
+    /// `format` supports the `{host}`, `{short}` (hostname up to the first dot) and `{state}`
+    /// tokens
+    pub(crate) fn new(format: Option<&str>, align: bool) -> Self {
+        Self {
+            format: format.unwrap_or(DEFAULT_PREFIX_FORMAT).to_string(),
+            align,
+            host_width: 0,
+            short_width: 0,
+        }
+    }
+
+    /// Widen the aligned tokens to fit the matching executors
+    pub(crate) fn matching(&mut self, client_ids: &[String]) {
+        for client_id in client_ids {
+            self.host_width = self.host_width.max(client_id.width());
+            self.short_width = self.short_width.max(short(client_id).width());
+        }
+    }
+
+    pub(crate) fn format(&self, client_id: &str, state: Option<&ExecutorState>) -> String {
+        let state = state.map(state_name).unwrap_or_default();
+        let (host_width, short_width, state_width) = if self.align {
+            (self.host_width, self.short_width, STATE_WIDTH)
+        } else {
+            (0, 0, 0)
+        };
+        self.format
+            .replace("{host}", &left_pad(client_id, host_width))
+            .replace("{short}", &left_pad(short(client_id), short_width))
+            .replace("{state}", &left_pad(state, state_width))
+    }
+}
+
+/// Width of the longest state name, `Disconnected`
+const STATE_WIDTH: usize = 12;
+
+fn state_name(state: &ExecutorState) -> &'static str {
+    match state {
+        ExecutorState::Matching => "Matching",
+        ExecutorState::Submitted => "Submitted",
+        ExecutorState::Alive => "Alive",
+        ExecutorState::Disconnected => "Disconnected",
+        ExecutorState::Cancelled => "Cancelled",
+        ExecutorState::Rejected => "Rejected",
+        ExecutorState::Error => "Error",
+        ExecutorState::Success => "Success",
+    }
+}
+
+fn short(client_id: &str) -> &str {
+    client_id.split('.').next().unwrap_or(client_id)
+}
+
+/// Padding by display width: wide characters take two columns of the terminal
+fn left_pad(value: &str, width: usize) -> String {
+    let padding = width.saturating_sub(value.width());
+    format!("{}{}", " ".repeat(padding), value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::OutputPrefix;
+    use crate::ExecutorState;
+
+    #[test]
+    fn prefix_format() {
+        let client_ids = [
+            "web1".to_string(),
+            "bücher.example.com".to_string(),
+            "東京.example.jp".to_string(),
+        ];
+        let mut prefix = OutputPrefix::new(None, false);
+        prefix.matching(&client_ids);
+        assert_eq!("web1", prefix.format("web1", None));
+
+        let mut prefix = OutputPrefix::new(None, true);
+        prefix.matching(&client_ids);
+        assert_eq!("              web1", prefix.format("web1", None));
+        assert_eq!(
+            "bücher.example.com",
+            prefix.format("bücher.example.com", None)
+        );
+        assert_eq!("   東京.example.jp", prefix.format("東京.example.jp", None));
+
+        let mut prefix = OutputPrefix::new(Some("{short} [{state}]"), true);
+        prefix.matching(&client_ids);
+        assert_eq!(
+            "  web1 [       Alive]",
+            prefix.format("web1", Some(&ExecutorState::Alive))
+        );
+        assert_eq!(
+            "bücher [     Success]",
+            prefix.format("bücher.example.com", Some(&ExecutorState::Success))
+        );
+        assert_eq!(
+            "  東京 [            ]",
+            prefix.format("東京.example.jp", None)
+        );
+
+        let prefix = OutputPrefix::new(Some("{host}/{short}/{state}/{other}"), false);
+        assert_eq!(
+            "web1.lan/web1/Disconnected/{other}",
+            prefix.format("web1.lan", Some(&ExecutorState::Disconnected))
+        );
+    }
+}
@@ -0,0 +1,131 @@
+//! Executors states of a command, with their live counts on the progress bar
+use crate::ExecutorState;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::HashMap;
+
+const TEMPLATE: &str = "{elapsed_precise} [{wide_bar}] {pos}/{len} {msg}";
+
+/// The states counted in the progress bar message
+const COUNTED: [ExecutorState; 5] = [
+    ExecutorState::Success,
+    ExecutorState::Error,
+    ExecutorState::Rejected,
+    ExecutorState::Alive,
+    ExecutorState::Disconnected,
+];
+
+#[derive(Default)]
+pub(crate) struct RunTracker {
+    pub(crate) executors: HashMap<String, ExecutorState>,
+    pb: Option<ProgressBar>,
+}
+
+impl RunTracker {
+    pub(crate) fn progress_bar(&self) -> Option<&ProgressBar> {
+        self.pb.as_ref()
+    }
+
+    pub(crate) fn set_progress_bar(&mut self, pb: ProgressBar) {
+        pb.set_style(
+            ProgressStyle::with_template(TEMPLATE)
+                .expect("valid progress bar template")
+                .progress_chars("=> "),
+        );
+        self.pb = Some(pb);
+        self.refresh();
+    }
+
+    pub(crate) fn matching(&mut self, client_id: String) {
+        self.executors.insert(client_id, ExecutorState::Matching);
+        self.refresh();
+    }
+
+    /// The progress bar advances once per executor, when its task is over
+    pub(crate) fn transition(&mut self, client_id: &str, state: ExecutorState) {
+        let finished = is_finished(&state);
+        let previous = self.executors.insert(client_id.to_string(), state);
+        if let (true, false, Some(pb)) = (
+            finished,
+            previous.as_ref().is_some_and(is_finished),
+            &self.pb,
+        ) {
+            pb.inc(1);
+        }
+        self.refresh();
+    }
+
+    /// Clear the progress bar and return the final states
+    pub(crate) fn finish(self) -> HashMap<String, ExecutorState> {
+        if let Some(pb) = &self.pb {
+            pb.finish_and_clear();
+        }
+        self.executors
+    }
+
+    fn message(&self) -> String {
+        COUNTED
+            .iter()
+            .map(|counted| {
+                let count = self.executors.values().filter(|s| *s == counted).count();
+                format!("{}: {}", counted, count)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn refresh(&self) {
+        if let Some(pb) = &self.pb {
+            pb.set_message(self.message());
+        }
+    }
+}
+
+fn is_finished(state: &ExecutorState) -> bool {
+    matches!(
+        state,
+        ExecutorState::Success
+            | ExecutorState::Error
+            | ExecutorState::Rejected
+            | ExecutorState::Cancelled
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::RunTracker;
+    use crate::ExecutorState;
+    use funtonic::tokio;
+    use indicatif::ProgressBar;
+
+    #[tokio::test]
+    async fn counts() {
+        let _colors = crate::without_colors().await;
+        let mut tracker = RunTracker::default();
+        tracker.set_progress_bar(ProgressBar::hidden());
+        tracker.progress_bar().unwrap().set_length(3);
+        for client_id in ["a", "b", "c"] {
+            tracker.matching(client_id.to_string());
+        }
+        tracker.transition("a", ExecutorState::Alive);
+        tracker.transition("b", ExecutorState::Alive);
+        assert_eq!(
+            "Success: 0 Error: 0 Rejected: 0 Alive: 2 Disconnected: 0",
+            tracker.message()
+        );
+
+        tracker.transition("a", ExecutorState::Success);
+        tracker.transition("b", ExecutorState::Error);
+        tracker.transition("c", ExecutorState::Rejected);
+        let pb = tracker.progress_bar().unwrap();
+        assert_eq!(3, pb.position());
+        assert_eq!(
+            "Success: 1 Error: 1 Rejected: 1 Alive: 0 Disconnected: 0",
+            pb.message()
+        );
+
+        // a task is counted once
+        tracker.transition("b", ExecutorState::Error);
+        assert_eq!(3, tracker.progress_bar().unwrap().position());
+        assert_eq!(3, tracker.finish().len());
+    }
+}
@@ -0,0 +1,302 @@
+//! Terminal rendering of the command outputs.
+//!
+//! The gRPC event loop never writes to the terminal itself: it pushes [`RenderEvent`]s into a
+//! bounded queue drained by a dedicated writer thread. A slow terminal thus cannot delay the
+//! processing of the executors completions.
+use funtonic::tokio::sync::Notify;
+use indicatif::ProgressBar;
+use std::collections::VecDeque;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_RENDER_BUFFER_LINES: usize = 10_000;
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+pub enum RenderEvent {
+    /// Line of a remote executor output, may be dropped when the queue is full in raw mode
+    Output { line: String, stderr: bool },
+    /// Anything else (matching executors, rejections, grouped outputs...), never dropped
+    Message { line: String, stderr: bool },
+    /// Print the following lines above this progress bar
+    ProgressBar(ProgressBar),
+}
+
+impl RenderEvent {
+    fn is_output(&self) -> bool {
+        matches!(self, RenderEvent::Output { .. })
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    events: VecDeque<RenderEvent>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    /// signaled to the writer thread when events are pushed or the queue is closed
+    readable: Condvar,
+    /// notified to the producer when the writer thread drained the queue
+    writable: Notify,
+    dropped: AtomicU64,
+}
+
+impl Shared {
+    // a panic while holding the lock cannot leave the queue half updated: ignore poisoning
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub struct Renderer {
+    shared: Arc<Shared>,
+    capacity: usize,
+    drop_oldest: bool,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Renderer {
+    /// Renderer writing to the process stdout & stderr
+    pub fn new(capacity: usize, drop_oldest: bool) -> Self {
+        Self::with_writers(capacity, drop_oldest, std::io::stdout(), std::io::stderr())
+    }
+
+    /// `capacity` is the number of events the queue holds before output lines get dropped
+    /// (`drop_oldest`) or the producer waits for the writer to catch up
+    pub fn with_writers<O, E>(capacity: usize, drop_oldest: bool, stdout: O, stderr: E) -> Self
+    where
+        O: Write + Send + 'static,
+        E: Write + Send + 'static,
+    {
+        let shared = Arc::new(Shared::default());
+        let writer = {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                write_events(&shared, BufWriter::new(stdout), BufWriter::new(stderr))
+            })
+        };
+        Self {
+            shared,
+            capacity: capacity.max(1),
+            drop_oldest,
+            writer: Some(writer),
+        }
+    }
+
+    pub async fn send(&self, event: RenderEvent) {
+        loop {
+            // registered before checking the queue so a drain happening in between is not missed
+            let writable = self.shared.writable.notified();
+            {
+                let mut queue = self.shared.lock();
+                if !event.is_output() || queue.events.len() < self.capacity {
+                    return self.push(queue, event);
+                }
+                if self.drop_oldest {
+                    // state events are kept: drop the oldest output line if any
+                    if let Some(index) = queue.events.iter().position(RenderEvent::is_output) {
+                        queue.events.remove(index);
+                        self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    return self.push(queue, event);
+                }
+            }
+            writable.await;
+        }
+    }
+
+    fn push(&self, mut queue: MutexGuard<'_, Queue>, event: RenderEvent) {
+        queue.events.push_back(event);
+        self.shared.readable.notify_one();
+    }
+
+    pub async fn output(&self, line: String, stderr: bool) {
+        self.send(RenderEvent::Output { line, stderr }).await
+    }
+
+    pub async fn message(&self, line: String) {
+        self.send(RenderEvent::Message {
+            line,
+            stderr: false,
+        })
+        .await
+    }
+
+    pub async fn error(&self, line: String) {
+        self.send(RenderEvent::Message { line, stderr: true }).await
+    }
+
+    /// Number of output lines dropped so far
+    pub fn dropped_lines(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Wait for all the queued events to be written, returns the number of dropped output lines
+    pub fn close(mut self) -> u64 {
+        self.shutdown();
+        self.dropped_lines()
+    }
+
+    fn shutdown(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.readable.notify_one();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn write_events<O: Write, E: Write>(
+    shared: &Shared,
+    mut stdout: BufWriter<O>,
+    mut stderr: BufWriter<E>,
+) {
+    // write errors (closed pipe...) are ignored, there is nowhere else to report them
+    fn flush<O: Write, E: Write>(stdout: &mut BufWriter<O>, stderr: &mut BufWriter<E>) -> Instant {
+        let _ = stdout.flush();
+        let _ = stderr.flush();
+        Instant::now()
+    }
+
+    let mut pb: Option<ProgressBar> = None;
+    let mut last_flush = Instant::now();
+    loop {
+        let (events, closed) = {
+            let mut queue = shared.lock();
+            while queue.events.is_empty() && !queue.closed {
+                let (guard, timeout) = shared
+                    .readable
+                    .wait_timeout(queue, FLUSH_INTERVAL)
+                    .unwrap_or_else(|e| e.into_inner());
+                queue = guard;
+                if timeout.timed_out() {
+                    last_flush = flush(&mut stdout, &mut stderr);
+                }
+            }
+            (queue.events.drain(..).collect::<Vec<_>>(), queue.closed)
+        };
+        shared.writable.notify_waiters();
+
+        for event in events {
+            match event {
+                RenderEvent::ProgressBar(progress) => {
+                    last_flush = flush(&mut stdout, &mut stderr);
+                    pb = Some(progress);
+                }
+                RenderEvent::Output { line, stderr: err }
+                | RenderEvent::Message { line, stderr: err } => match &pb {
+                    Some(pb) => pb.println(line),
+                    None if err => {
+                        let _ = writeln!(stderr, "{}", line);
+                    }
+                    None => {
+                        let _ = writeln!(stdout, "{}", line);
+                    }
+                },
+            }
+        }
+        if closed {
+            flush(&mut stdout, &mut stderr);
+            return;
+        }
+        if last_flush.elapsed() >= FLUSH_INTERVAL {
+            last_flush = flush(&mut stdout, &mut stderr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Renderer;
+    use futures::FutureExt;
+    use std::io::Write;
+    use std::sync::{Arc, Barrier, Mutex};
+
+    /// Terminal blocked until the barrier is reached, then counting written lines
+    struct SlowTerminal {
+        barrier: Option<Arc<Barrier>>,
+        lines: Arc<Mutex<usize>>,
+    }
+
+    impl Write for SlowTerminal {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if let Some(barrier) = self.barrier.take() {
+                barrier.wait();
+            }
+            *self.lines.lock().unwrap() += buf.iter().filter(|b| **b == b'\n').count();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            if let Some(barrier) = self.barrier.take() {
+                barrier.wait();
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn raw_flood_never_awaits_terminal() {
+        let barrier = Arc::new(Barrier::new(2));
+        let lines = Arc::new(Mutex::new(0));
+        let renderer = Renderer::with_writers(
+            1000,
+            true,
+            SlowTerminal {
+                barrier: Some(barrier.clone()),
+                lines: lines.clone(),
+            },
+            std::io::sink(),
+        );
+
+        // the terminal is stuck until all the lines are sent
+        for i in 0..1_000_000 {
+            assert!(
+                renderer
+                    .output(format!("line {}", i), false)
+                    .now_or_never()
+                    .is_some(),
+                "event loop awaited the terminal"
+            );
+        }
+        // state events are never dropped
+        assert!(renderer.message("done".into()).now_or_never().is_some());
+        barrier.wait();
+
+        let dropped = renderer.close();
+        assert!(dropped > 0);
+        assert_eq!(*lines.lock().unwrap() as u64 + dropped, 1_000_001);
+    }
+
+    #[test]
+    fn lossless_when_not_raw() {
+        let lines = Arc::new(Mutex::new(0));
+        let renderer = Renderer::with_writers(
+            10,
+            false,
+            SlowTerminal {
+                barrier: None,
+                lines: lines.clone(),
+            },
+            std::io::sink(),
+        );
+        futures::executor::block_on(async {
+            for i in 0..10_000 {
+                renderer.output(format!("line {}", i), false).await;
+            }
+        });
+        assert_eq!(renderer.close(), 0);
+        assert_eq!(*lines.lock().unwrap(), 10_000);
+    }
+}
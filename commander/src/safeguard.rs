@@ -0,0 +1,782 @@
+//! Confirmation prompts & refusals of dangerous commands.
+//!
+//! The `unsafe_commands` of the commander configuration (`reboot`, `rm`... by default) always ask
+//! for a confirmation. The safeguard policies add prompts & refusals for the executors matching
+//! their query (eg: `env:prod`).
+use crate::cmd::resolve_query;
+use anyhow::{anyhow, Context};
+use atty::Stream;
+use funtonic::config::{CommanderConfig, SafeguardPolicy};
+use funtonic::executor_meta::Tag;
+use funtonic::tonic::transport::Channel;
+use grpc_service::grpc_protocol::commander_service_client::CommanderServiceClient;
+use grpc_service::grpc_protocol::ResolvedExecutor;
+use query_parser::{parse, QueryMatcher};
+use regex::Regex;
+use rustyline::DefaultEditor;
+use shellish_parse::ParseOptions;
+
+/// Policies applying to the executors targeted by `query` on any of the taskservers of `clients`.
+///
+/// The targeted executors are resolved by the taskservers. If they cannot be, a policy is applied
+/// whenever the query mentions the policy query or targets all the executors.
+pub async fn applicable_policies<'a>(
+    clients: &[CommanderServiceClient<Channel>],
+    commander_config: &'a CommanderConfig,
+    query: &str,
+) -> anyhow::Result<Vec<&'a SafeguardPolicy>> {
+    let policies = &commander_config.safeguard_policies;
+    if policies.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut executors = vec![];
+    let mut unresolved = None;
+    for client in clients {
+        match resolve_query(&mut client.clone(), commander_config, query).await {
+            Ok(resolved) => executors.extend(resolved),
+            Err(e) => {
+                unresolved = Some(e.to_string());
+                break;
+            }
+        }
+    }
+    match unresolved {
+        None => policies_for_executors(policies, &executors),
+        Some(e) => {
+            warn!(
+                "Unable to resolve {}, safeguard policies are applied from the query: {}",
+                query, e
+            );
+            Ok(policies_for_query(policies, query))
+        }
+    }
+}
+
+/// Policies whose query matches at least one of the executors
+fn policies_for_executors<'a>(
+    policies: &'a [SafeguardPolicy],
+    executors: &[ResolvedExecutor],
+) -> anyhow::Result<Vec<&'a SafeguardPolicy>> {
+    let executors: Vec<_> = executors
+        .iter()
+        .map(|executor| {
+            vec![
+                Tag::Value(executor.client_id.clone()),
+                Tag::Map(
+                    executor
+                        .tags
+                        .iter()
+                        .map(|(name, value)| (name.clone(), value.into()))
+                        .collect(),
+                ),
+            ]
+        })
+        .collect();
+    let mut applicable = vec![];
+    for policy in policies {
+        let query = parse(&policy.query)
+            .map_err(|e| anyhow!("Invalid safeguard policy {}: {}", policy.name(), e))?;
+        if executors
+            .iter()
+            .any(|executor| executor.qmatches(&query).matches())
+        {
+            applicable.push(policy);
+        }
+    }
+    Ok(applicable)
+}
+
+/// Conservative guess made from the text of the query only
+fn policies_for_query<'a>(
+    policies: &'a [SafeguardPolicy],
+    query: &str,
+) -> Vec<&'a SafeguardPolicy> {
+    let query = without_whitespaces(query);
+    policies
+        .iter()
+        .filter(|policy| query == "*" || query.contains(&without_whitespaces(&policy.query)))
+        .collect()
+}
+
+fn without_whitespaces(query: &str) -> String {
+    query.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+/// Commands asking for a confirmation whatever the targeted executors
+pub struct UnsafeCommands(Vec<Regex>);
+
+impl UnsafeCommands {
+    pub fn new(patterns: &[String]) -> anyhow::Result<Self> {
+        patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .with_context(|| format!("Invalid unsafe_commands regex {}", pattern))
+            })
+            .collect::<anyhow::Result<_>>()
+            .map(Self)
+    }
+
+    fn matches(&self, command: &str) -> bool {
+        self.0.iter().any(|regex| regex.is_match(command))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Safeguard {
+    Run,
+    /// the user must confirm, with this prompt
+    Confirm(String),
+    /// the user must confirm, with this prompt: the command is refused if the user cannot be asked
+    ConfirmRequired(String),
+}
+
+impl Safeguard {
+    fn is_stricter_than(&self, other: &Safeguard) -> bool {
+        matches!(
+            (self, other),
+            (Safeguard::Confirm(_), Safeguard::Run)
+                | (
+                    Safeguard::ConfirmRequired(_),
+                    Safeguard::Run | Safeguard::Confirm(_)
+                )
+        )
+    }
+}
+
+/// Forbidden commands are refused whatever the other commands of the line. `matching` executors
+/// are mentioned in the prompt when known.
+///
+/// A line that cannot be parsed cannot be checked: it is refused if a policy forbids commands,
+/// it must be confirmed otherwise.
+fn check_command(
+    command: &str,
+    unsafe_commands: &UnsafeCommands,
+    policies: &[&SafeguardPolicy],
+    matching: Option<usize>,
+) -> anyhow::Result<Safeguard> {
+    let matching = match matching {
+        Some(matching) => format!(" ({} executors matching)", matching),
+        None => String::new(),
+    };
+    let parsed_commands = match shellish_parse::multiparse(
+        command,
+        ParseOptions::default(),
+        &["&&", "||", "&", "|", ";"],
+    ) {
+        Ok(parsed_commands) => parsed_commands,
+        Err(_) if unsafe_commands.is_empty() && policies.is_empty() => return Ok(Safeguard::Run),
+        Err(e) => {
+            if let Some(policy) = policies
+                .iter()
+                .find(|policy| !policy.forbid_commands.is_empty())
+            {
+                return Err(anyhow!(
+                    "`{}` cannot be checked against safeguard policy {}: {}",
+                    command,
+                    policy.name(),
+                    e
+                ));
+            }
+            return Ok(Safeguard::ConfirmRequired(format!(
+                "`{command}` cannot be checked ({e}), do you really want to run it{matching} (y/N)? "
+            )));
+        }
+    };
+    // (program, command with its arguments), run through `sudo`, `env`... or not; the empty
+    // commands (`;;`...) are skipped
+    let commands: Vec<(&String, String)> = parsed_commands
+        .iter()
+        .filter_map(|command| {
+            let args = match unwrap_command(&command.0) {
+                // eg: `sudo -v`
+                [] => &command.0[..],
+                args => args,
+            };
+            args.first().map(|program| (program, args.join(" ")))
+        })
+        .collect();
+    for (program, _) in &commands {
+        if let Some(policy) = policies
+            .iter()
+            .find(|policy| is_listed(program, &policy.forbid_commands))
+        {
+            return Err(anyhow!(
+                "`{}` is forbidden by safeguard policy {}",
+                program,
+                policy.name()
+            ));
+        }
+    }
+    // the commands prompted by a policy prevail over the unsafe ones
+    let mut safeguard = Safeguard::Run;
+    for (program, command) in commands {
+        let checked = if let Some(policy) = policies
+            .iter()
+            .find(|policy| is_listed(program, &policy.prompt_commands))
+        {
+            Safeguard::ConfirmRequired(format!(
+                "Do you really want to run `{program}` on {} executors{matching} (y/N)? ",
+                policy.name()
+            ))
+        } else if unsafe_commands.matches(&command) {
+            Safeguard::Confirm(format!(
+                "Do you really want to run unsafe command `{command}`{matching} (y/N)? "
+            ))
+        } else {
+            Safeguard::Run
+        };
+        if checked.is_stricter_than(&safeguard) {
+            safeguard = checked;
+        }
+    }
+    Ok(safeguard)
+}
+
+/// Each line of the script is checked as a command, heredoc bodies included: the script is
+/// refused if any line is forbidden, or prompted with its first line requiring the strictest
+/// confirmation
+fn check_script(
+    script: &str,
+    unsafe_commands: &UnsafeCommands,
+    policies: &[&SafeguardPolicy],
+    matching: Option<usize>,
+) -> anyhow::Result<Safeguard> {
+    let mut safeguard = Safeguard::Run;
+    for line in script
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        let checked = check_command(line, unsafe_commands, policies, matching)?;
+        if checked.is_stricter_than(&safeguard) {
+            safeguard = checked;
+        }
+    }
+    Ok(safeguard)
+}
+
+/// `program` may be a path
+fn is_listed(program: &str, commands: &[String]) -> bool {
+    let name = program_name(program);
+    commands.iter().any(|command| command == name)
+}
+
+fn program_name(program: &str) -> &str {
+    program.rsplit('/').next().unwrap_or(program)
+}
+
+/// Programs running the command given as their arguments
+const WRAPPERS: [&str; 8] = [
+    "sudo", "doas", "env", "nohup", "nice", "time", "exec", "command",
+];
+
+/// The command run by `args` once the wrappers, their options & the variable assignments are
+/// stripped, eg: `rm -rf /tmp/x` for `sudo -u app env LANG=C rm -rf /tmp/x`
+fn unwrap_command(mut args: &[String]) -> &[String] {
+    loop {
+        match args {
+            [assignment, rest @ ..] if is_assignment(assignment) => args = rest,
+            [wrapper, rest @ ..] if WRAPPERS.contains(&program_name(wrapper)) => {
+                args = without_options(program_name(wrapper), rest)
+            }
+            _ => return args,
+        }
+    }
+}
+
+/// `NAME=value`, as set before a command or given to `env`
+fn is_assignment(arg: &str) -> bool {
+    match arg.split_once('=') {
+        Some((name, _)) => {
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    }
+}
+
+/// Skip the leading options of `wrapper`, along with their values
+fn without_options<'a>(wrapper: &str, mut args: &'a [String]) -> &'a [String] {
+    while let [option, rest @ ..] = args {
+        if option == "--" {
+            return rest;
+        }
+        if !option.starts_with('-') {
+            break;
+        }
+        args = match rest {
+            [_, rest @ ..] if takes_value(wrapper, option) => rest,
+            rest => rest,
+        };
+    }
+    args
+}
+
+/// Options of the wrappers whose value is the next argument
+fn takes_value(wrapper: &str, option: &str) -> bool {
+    match wrapper {
+        "sudo" => matches!(
+            option,
+            "-u" | "-g" | "-h" | "-p" | "-C" | "-D" | "-r" | "-t" | "-T" | "-U"
+        ),
+        "doas" => matches!(option, "-u" | "-C"),
+        "env" => matches!(option, "-u" | "-C" | "-S"),
+        "nice" => option == "-n",
+        _ => false,
+    }
+}
+
+/// This will prompt something if an unsafe command is run from a terminal with a tty input,
+/// unless `yes`
+///
+/// Unsafe means the `unsafe_commands` or the commands prompted by the given policies. Without a
+/// tty the `unsafe_commands` are run anyway, the commands prompted by a policy are refused.
+///
+/// It will return an error if the user do not agree to run the command, or if a policy forbids
+/// it
+pub fn safeguard_command(
+    command: &str,
+    unsafe_commands: &UnsafeCommands,
+    policies: &[&SafeguardPolicy],
+    matching: Option<usize>,
+    yes: bool,
+) -> anyhow::Result<()> {
+    confirm(
+        check_command(command, unsafe_commands, policies, matching)?,
+        command,
+        yes,
+        tty_prompt,
+    )
+}
+
+/// [safeguard_command] for each line of a script (`name` is its file name)
+pub fn safeguard_script(
+    name: &str,
+    script: &str,
+    unsafe_commands: &UnsafeCommands,
+    policies: &[&SafeguardPolicy],
+    yes: bool,
+) -> anyhow::Result<()> {
+    confirm(
+        check_script(script, unsafe_commands, policies, None)?,
+        name,
+        yes,
+        tty_prompt,
+    )
+}
+
+/// `prompt` returns the answer of the user, None if the user cannot be asked
+fn confirm(
+    safeguard: Safeguard,
+    command: &str,
+    yes: bool,
+    prompt: impl FnOnce(&str) -> anyhow::Result<Option<String>>,
+) -> anyhow::Result<()> {
+    let (question, required) = match safeguard {
+        Safeguard::Run => return Ok(()),
+        Safeguard::Confirm(question) => (question, false),
+        Safeguard::ConfirmRequired(question) => (question, true),
+    };
+    if yes {
+        return Ok(());
+    }
+    match prompt(&question)? {
+        Some(line) if line.eq_ignore_ascii_case("y") || line.eq_ignore_ascii_case("yes") => Ok(()),
+        Some(_) => Err(anyhow!("Cancelled!")),
+        None if required => Err(anyhow!(
+            "stdin not a tty, {} must be confirmed: use --yes to run it anyway",
+            command
+        )),
+        None => {
+            warn!("stdin not a tty, unsafe command {} not confirmed", command);
+            eprintln!("stdin not a tty, running unsafe command {command} anyway!");
+            Ok(())
+        }
+    }
+}
+
+/// Executors named when asking to confirm a large target
+const LISTED_EXECUTORS: usize = 5;
+
+/// Ask for a confirmation when more than `confirm_above` executors are targeted.
+///
+/// `prompt` returns the answer of the user, None if the user cannot be asked: the command is
+/// then refused.
+pub fn confirm_matching(
+    client_ids: &[String],
+    confirm_above: usize,
+    prompt: impl FnOnce(&str) -> anyhow::Result<Option<String>>,
+) -> anyhow::Result<()> {
+    if client_ids.len() <= confirm_above {
+        return Ok(());
+    }
+    let mut listed = client_ids[..client_ids.len().min(LISTED_EXECUTORS)].join(", ");
+    if client_ids.len() > LISTED_EXECUTORS {
+        listed.push_str(", ...");
+    }
+    let question = format!(
+        "The query matches {} executors ({}), run the command on all of them (y/N)? ",
+        client_ids.len(),
+        listed
+    );
+    match prompt(&question)? {
+        Some(line) if line.eq_ignore_ascii_case("y") || line.eq_ignore_ascii_case("yes") => Ok(()),
+        Some(_) => Err(anyhow!("Cancelled!")),
+        None => Err(anyhow!(
+            "The query matches {} executors (confirm_above: {}) and stdin is not a tty, use \
+             --force to run the command anyway",
+            client_ids.len(),
+            confirm_above
+        )),
+    }
+}
+
+/// Answer read from the terminal, None if stdin is not a tty
+pub fn tty_prompt(prompt: &str) -> anyhow::Result<Option<String>> {
+    if atty::isnt(Stream::Stdin) {
+        return Ok(None);
+    }
+    Ok(Some(DefaultEditor::new()?.readline(prompt)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_command, check_script, confirm, confirm_matching, policies_for_executors,
+        policies_for_query, Safeguard, UnsafeCommands,
+    };
+    use funtonic::config::{SafeguardPolicy, DEFAULT_UNSAFE_COMMANDS};
+    use grpc_service::grpc_protocol::tag::Tag;
+    use grpc_service::grpc_protocol::{ResolvedExecutor, Tag as TagMessage};
+
+    fn policies() -> Vec<SafeguardPolicy> {
+        vec![
+            SafeguardPolicy {
+                name: Some("production".to_string()),
+                query: "env:prod".to_string(),
+                prompt_commands: vec!["systemctl".to_string()],
+                forbid_commands: vec!["mkfs".to_string(), "dd".to_string()],
+            },
+            SafeguardPolicy {
+                query: "env:lab".to_string(),
+                prompt_commands: vec!["dd".to_string()],
+                ..Default::default()
+            },
+        ]
+    }
+
+    fn executor(client_id: &str, env: &str) -> ResolvedExecutor {
+        ResolvedExecutor {
+            client_id: client_id.to_string(),
+            connected: true,
+            tags: [(
+                "env".to_string(),
+                TagMessage {
+                    tag: Some(Tag::Value(env.to_string())),
+                },
+            )]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    fn defaults() -> UnsafeCommands {
+        let patterns: Vec<String> = DEFAULT_UNSAFE_COMMANDS
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        UnsafeCommands::new(&patterns).unwrap()
+    }
+
+    fn names(policies: Vec<&SafeguardPolicy>) -> Vec<&str> {
+        policies.into_iter().map(SafeguardPolicy::name).collect()
+    }
+
+    #[test]
+    fn overlapping_executors() {
+        let policies = policies();
+        let executors = vec![executor("web-1", "prod"), executor("lab-1", "lab")];
+        assert_eq!(
+            vec!["production", "env:lab"],
+            names(policies_for_executors(&policies, &executors).unwrap())
+        );
+        // a query that does not mention env:prod but matches a production executor
+        assert_eq!(
+            vec!["production"],
+            names(policies_for_executors(&policies, &executors[..1]).unwrap())
+        );
+        assert!(policies_for_executors(&policies, &[]).unwrap().is_empty());
+        assert!(
+            policies_for_executors(&policies, &[executor("dev-1", "dev")])
+                .unwrap()
+                .is_empty()
+        );
+
+        let invalid = vec![SafeguardPolicy {
+            query: "env:(".to_string(),
+            ..Default::default()
+        }];
+        assert!(policies_for_executors(&invalid, &executors).is_err());
+    }
+
+    #[test]
+    fn query_fallback() {
+        let policies = policies();
+        assert_eq!(
+            vec!["production"],
+            names(policies_for_query(&policies, "env: prod and os:linux"))
+        );
+        assert_eq!(
+            vec!["production", "env:lab"],
+            names(policies_for_query(&policies, "*"))
+        );
+        assert!(policies_for_query(&policies, "web-1").is_empty());
+    }
+
+    #[test]
+    fn commands() {
+        let policies = policies();
+        let production = vec![&policies[0]];
+        let lab = vec![&policies[1]];
+
+        assert_eq!(
+            Safeguard::Run,
+            check_command("uptime", &defaults(), &production, None).unwrap()
+        );
+        let forbidden = check_command(
+            "uptime && /sbin/mkfs /dev/sdb",
+            &defaults(),
+            &production,
+            None,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(forbidden.contains("production"), "{}", forbidden);
+        assert!(matches!(
+            check_command("systemctl restart nginx", &defaults(), &production, None).unwrap(),
+            Safeguard::ConfirmRequired(prompt) if prompt.contains("production")
+        ));
+        assert!(matches!(
+            check_command(
+                "dd if=/dev/zero of=/tmp/zero count=1",
+                &defaults(),
+                &lab,
+                None
+            )
+            .unwrap(),
+            Safeguard::ConfirmRequired(_)
+        ));
+        // without policy only the built-in commands are prompted
+        assert_eq!(
+            Safeguard::Run,
+            check_command("systemctl restart nginx", &defaults(), &[], None).unwrap()
+        );
+        assert!(matches!(
+            check_command("ls | rm", &defaults(), &[], None).unwrap(),
+            Safeguard::Confirm(_)
+        ));
+        // the policy prompt prevails, whatever the order of the commands
+        assert!(matches!(
+            check_command(
+                "rm /tmp/x; systemctl restart nginx",
+                &defaults(),
+                &production,
+                None
+            )
+            .unwrap(),
+            Safeguard::ConfirmRequired(_)
+        ));
+        // empty commands do not end the checks
+        assert!(check_command("uptime ; ; mkfs /dev/sdb", &defaults(), &production, None).is_err());
+    }
+
+    #[test]
+    fn unparsable_commands() {
+        let policies = policies();
+        let command = "echo 'unterminated && mkfs /dev/sdb";
+        let refused = check_command(command, &defaults(), &[&policies[0]], None).unwrap_err();
+        assert!(refused.to_string().contains("production"), "{}", refused);
+        assert!(matches!(
+            check_command(command, &defaults(), &[&policies[1]], None).unwrap(),
+            Safeguard::ConfirmRequired(_)
+        ));
+        assert!(matches!(
+            check_command(command, &defaults(), &[], None).unwrap(),
+            Safeguard::ConfirmRequired(_)
+        ));
+        // nothing to check
+        assert_eq!(
+            Safeguard::Run,
+            check_command(command, &UnsafeCommands::new(&[]).unwrap(), &[], None).unwrap()
+        );
+    }
+
+    #[test]
+    fn confirmations_without_tty() {
+        let no_tty = |_: &str| Ok(None);
+        let unsafe_command = || Safeguard::Confirm("rm?".to_string());
+        let prompted = || Safeguard::ConfirmRequired("systemctl?".to_string());
+
+        assert!(confirm(Safeguard::Run, "uptime", false, no_tty).is_ok());
+        assert!(confirm(unsafe_command(), "rm /tmp/x", false, no_tty).is_ok());
+        let refused = confirm(prompted(), "systemctl stop nginx", false, no_tty).unwrap_err();
+        assert!(refused.to_string().contains("--yes"), "{}", refused);
+        assert!(
+            confirm(prompted(), "systemctl stop nginx", true, |_: &str| panic!(
+                "prompted"
+            ))
+            .is_ok()
+        );
+        assert!(
+            confirm(prompted(), "systemctl stop nginx", false, |_: &str| Ok(
+                Some("y".to_string())
+            ))
+            .is_ok()
+        );
+        assert!(
+            confirm(unsafe_command(), "rm /tmp/x", false, |_: &str| Ok(Some(
+                "n".to_string()
+            )))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn wrapped_commands() {
+        let policies = policies();
+        let production = vec![&policies[0]];
+        for command in [
+            "sudo mkfs /dev/sdb",
+            "sudo -u root -E /sbin/mkfs /dev/sdb",
+            "env LANG=C mkfs /dev/sdb",
+            "sudo -- env -u HOME nice -n 10 mkfs /dev/sdb",
+            "LANG=C mkfs /dev/sdb",
+            "sudo -v; mkfs /dev/sdb",
+        ] {
+            assert!(
+                check_command(command, &defaults(), &production, None).is_err(),
+                "{}",
+                command
+            );
+        }
+        assert!(matches!(
+            check_command("sudo reboot", &defaults(), &[], None).unwrap(),
+            Safeguard::Confirm(prompt) if prompt.contains("`reboot`")
+        ));
+        assert_eq!(
+            Safeguard::Run,
+            check_command(
+                "sudo -u app env LANG=C uptime",
+                &defaults(),
+                &production,
+                None
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            Safeguard::Run,
+            check_command("sudo -v", &defaults(), &production, None).unwrap()
+        );
+    }
+
+    #[test]
+    fn scripts() {
+        let policies = policies();
+        let production = vec![&policies[0]];
+        let script = "#!/bin/sh\n# rm is mentioned in a comment\nuptime\n\
+                      cat > /tmp/motd <<EOF\nhello\nEOF\n";
+        assert_eq!(
+            Safeguard::Run,
+            check_script(script, &defaults(), &production, None).unwrap()
+        );
+        assert!(matches!(
+            check_script(
+                "uptime\nsystemctl restart nginx\nrm -rf /tmp/cache\n",
+                &defaults(),
+                &production,
+                None
+            )
+            .unwrap(),
+            Safeguard::ConfirmRequired(prompt) if prompt.contains("systemctl")
+        ));
+        // refused even after a prompted line
+        assert!(check_script(
+            "systemctl stop nginx\n  dd if=/dev/zero of=/dev/sdb\n",
+            &defaults(),
+            &production,
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn unsafe_commands() {
+        let unsafe_commands = UnsafeCommands::new(&[
+            r"^(\S*/)?(shutdown|mkfs(\.\w+)?)(\s|$)".to_string(),
+            r"^dd .*of=/dev/".to_string(),
+        ])
+        .unwrap();
+        let check = |command| check_command(command, &unsafe_commands, &[], Some(3)).unwrap();
+
+        for command in [
+            "uptime && shutdown -h now",
+            "echo y | /sbin/mkfs.ext4 /dev/sdb",
+            "sync ; dd if=/dev/zero of=/dev/sda bs=1M",
+        ] {
+            assert!(
+                matches!(
+                    check(command),
+                    Safeguard::Confirm(prompt) if prompt.contains("3 executors")
+                ),
+                "{} not prompted",
+                command
+            );
+        }
+        for command in [
+            "dd if=/dev/sda of=/tmp/disk.img",
+            "systemctl status shutdown.target",
+            "grep mkfs /var/log/syslog | wc -l",
+            // the defaults are replaced
+            "reboot",
+        ] {
+            assert_eq!(Safeguard::Run, check(command), "{} prompted", command);
+        }
+
+        // the defaults do not match on substrings anymore
+        assert_eq!(
+            Safeguard::Run,
+            check_command("uptime ; perm", &defaults(), &[], None).unwrap()
+        );
+        assert!(matches!(
+            check_command("cd /tmp && /bin/rm -rf foo", &defaults(), &[], None).unwrap(),
+            Safeguard::Confirm(_)
+        ));
+
+        assert!(UnsafeCommands::new(&["(".to_string()]).is_err());
+    }
+
+    #[test]
+    fn matching_confirmation() {
+        let client_ids: Vec<String> = (1..=8).map(|i| format!("web-{}", i)).collect();
+        let answer = |answer: Option<&'static str>| {
+            move |prompt: &str| {
+                assert!(prompt.contains("matches 8 executors"), "{}", prompt);
+                assert!(prompt.contains("web-5, ..."), "{}", prompt);
+                assert!(!prompt.contains("web-6"), "{}", prompt);
+                Ok(answer.map(str::to_string))
+            }
+        };
+
+        assert!(confirm_matching(&client_ids, 8, |_| panic!("prompted")).is_ok());
+        assert!(confirm_matching(&client_ids, 7, answer(Some("y"))).is_ok());
+        assert!(confirm_matching(&client_ids, 7, answer(Some("n"))).is_err());
+        assert!(confirm_matching(&client_ids, 7, answer(Some(""))).is_err());
+        // no tty
+        let refused = confirm_matching(&client_ids, 0, answer(None)).unwrap_err();
+        assert!(refused.to_string().contains("--force"), "{}", refused);
+    }
+}
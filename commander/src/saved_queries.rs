@@ -0,0 +1,88 @@
+//! Queries of the commander configuration referenced by name, eg: `commander run @web-prod uptime`
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+
+/// The saved query named by `query` if it is a `@name` reference, `query` itself otherwise.
+///
+/// Saved queries cannot reference other saved queries.
+pub fn expand_saved_query(
+    query: &str,
+    saved_queries: &BTreeMap<String, String>,
+) -> anyhow::Result<String> {
+    let Some(name) = query.trim().strip_prefix('@') else {
+        return Ok(query.to_string());
+    };
+    match saved_queries.get(name) {
+        Some(saved) if saved.trim().starts_with('@') => Err(anyhow!(
+            "Saved query @{} references another saved query ({}), this is not supported",
+            name,
+            saved.trim()
+        )),
+        Some(saved) => Ok(saved.clone()),
+        None if saved_queries.is_empty() => Err(anyhow!(
+            "Unknown saved query @{}: no saved_queries in the commander configuration",
+            name
+        )),
+        None => Err(anyhow!(
+            "Unknown saved query @{}, available: {}",
+            name,
+            saved_queries
+                .keys()
+                .map(|name| format!("@{}", name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// `utils list-queries`
+pub fn print_saved_queries(saved_queries: &BTreeMap<String, String>) {
+    let width = saved_queries
+        .keys()
+        .map(|name| name.len())
+        .max()
+        .unwrap_or(0);
+    for (name, query) in saved_queries {
+        println!("@{:width$}  {}", name, query, width = width);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::expand_saved_query;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn expansion() {
+        let saved: BTreeMap<String, String> = [
+            ("web-prod", "env:prod and role:web and not location:dr"),
+            ("alias", "@web-prod"),
+        ]
+        .into_iter()
+        .map(|(name, query)| (name.to_string(), query.to_string()))
+        .collect();
+
+        assert_eq!(
+            "env:prod and role:web and not location:dr",
+            expand_saved_query("@web-prod", &saved).unwrap()
+        );
+        assert_eq!(
+            "env:prod and role:web and not location:dr",
+            expand_saved_query(" @web-prod ", &saved).unwrap()
+        );
+        // only whole queries are references
+        assert_eq!(
+            "role:web and @web-prod",
+            expand_saved_query("role:web and @web-prod", &saved).unwrap()
+        );
+        assert_eq!("user:a@b", expand_saved_query("user:a@b", &saved).unwrap());
+
+        let error = expand_saved_query("@web", &saved).unwrap_err().to_string();
+        assert!(error.contains("@alias, @web-prod"), "{}", error);
+        let error = expand_saved_query("@alias", &saved)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("another saved query"), "{}", error);
+        assert!(expand_saved_query("@web-prod", &BTreeMap::new()).is_err());
+    }
+}
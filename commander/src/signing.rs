@@ -0,0 +1,134 @@
+//! `utils sign`, `utils verify` & `utils show-public-key`: debug signature issues between
+//! commanders, taskservers and executors. `utils delegate`: grant short-lived keys
+use crate::key_encryption::key_passphrase;
+use anyhow::Context;
+use chrono::{DateTime, Local};
+use colored::Colorize;
+use funtonic::config::{self, CommanderConfig, ED25519Key};
+use funtonic::crypto::keygen::{fingerprint, public_key_of};
+use funtonic::crypto::keystore::{check_expiry, memory_keystore, KeyStoreError};
+use funtonic::crypto::sealing::{read_machine_id, unseal, SealedED25519Key};
+use funtonic::crypto::signed_payload::{self, sign_raw_payload};
+use funtonic::data_encoding;
+use funtonic::file_utils::{parse_yaml_from_file, read};
+use funtonic::prost::Message;
+use grpc_service::payload::SignedPayload;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Files a signing key can be read from
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyFile {
+    /// commander configuration, `utils genkey` output
+    Wrapped {
+        ed25519_key: ED25519Key,
+    },
+    /// executor key file sealed on this host, tried before the plain keys whose pkcs8 is
+    /// optional when encrypted
+    Sealed(SealedED25519Key),
+    Key(ED25519Key),
+}
+
+/// The key, decrypted if it is protected by a passphrase
+fn load_key(key: &str, config: &Option<PathBuf>) -> anyhow::Result<ED25519Key> {
+    let key = if key == "config" {
+        let (config, _) = config::parse::<_, _, CommanderConfig>(config, "commander.yml")?;
+        config.ed25519_key
+    } else {
+        match parse_yaml_from_file(Path::new(key))? {
+            KeyFile::Wrapped { ed25519_key } | KeyFile::Key(ed25519_key) => ed25519_key,
+            KeyFile::Sealed(sealed) => unseal(&sealed, &read_machine_id()?)?,
+        }
+    };
+    let key_id = key.id.clone();
+    Ok(key.resolve(|| key_passphrase(&key_id))?)
+}
+
+/// Print the public key of a key file & its fingerprint
+pub fn show_public_key(key: &str, config: &Option<PathBuf>) -> anyhow::Result<()> {
+    let key = load_key(key, config)?;
+    let public_key = public_key_of(&key)?;
+    println!("key_id:      {}", key.id);
+    println!("public_key:  {}", data_encoding::BASE64.encode(&public_key));
+    println!("fingerprint: {}", fingerprint(&public_key));
+    Ok(())
+}
+
+/// Sign the content of `payload_file`, returns the base64 encoded SignedPayload
+pub fn sign(
+    key: &str,
+    payload_file: &Path,
+    validity: Duration,
+    config: &Option<PathBuf>,
+) -> anyhow::Result<String> {
+    let key = load_key(key, config)?;
+    let signed = sign_raw_payload(read(payload_file)?, &key, validity)?;
+    Ok(data_encoding::BASE64.encode(&signed.encode_to_vec()))
+}
+
+/// Delegate the signing rights of `key` to the `key_id` public key, returns the base64 encoded
+/// delegation certificate
+pub fn delegate(
+    key: &str,
+    key_id: &str,
+    public_key: &str,
+    query: &str,
+    ttl: Duration,
+    config: &Option<PathBuf>,
+) -> anyhow::Result<String> {
+    let key = load_key(key, config)?;
+    let public_key = data_encoding::BASE64
+        .decode(public_key.as_bytes())
+        .context("Unable to decode base64 encoded public key")?;
+    // the same checks as the taskservers & executors: an invalid delegation is useless
+    memory_keystore().register_key(key_id, public_key.clone())?;
+    query_parser::parse(query).map_err(|e| anyhow::anyhow!("Invalid query: {}", e))?;
+    let certificate = signed_payload::delegate(&key, key_id, public_key, query, ttl)?;
+    Ok(data_encoding::BASE64.encode(&certificate.encode_to_vec()))
+}
+
+fn check_result(result: Result<(), KeyStoreError>) -> String {
+    match result {
+        Ok(()) => "ok".green().to_string(),
+        Err(e) => format!("{} ({})", "FAILED".red(), e),
+    }
+}
+
+/// Print the content of a base64 encoded SignedPayload and whether it can be trusted
+pub fn verify(public_key: &str, signed: &str) -> anyhow::Result<()> {
+    let public_key = data_encoding::BASE64
+        .decode(public_key.as_bytes())
+        .context("Unable to decode base64 encoded public key")?;
+    let signed = SignedPayload::decode(
+        data_encoding::BASE64
+            .decode(signed.as_bytes())
+            .context("Unable to decode base64 encoded signed payload")?
+            .as_slice(),
+    )
+    .context("Not a SignedPayload")?;
+
+    let key_store = memory_keystore();
+    key_store.register_key(signed.key_id.as_str(), public_key)?;
+
+    let valid_until = SystemTime::UNIX_EPOCH + Duration::from_secs(signed.valid_until_secs);
+    println!("key_id:      {}", signed.key_id);
+    println!("nonce:       {}", signed.nonce);
+    println!(
+        "valid_until: {} ({})",
+        DateTime::<Local>::from(valid_until),
+        signed.valid_until_secs
+    );
+    println!("payload:     {} bytes", signed.payload.len());
+    println!(
+        "signature:   {}",
+        check_result(key_store.verify_signature(&signed))
+    );
+    println!("expiry:      {}", check_result(check_expiry(&signed)));
+
+    // signed by `sign`, whatever its validity
+    key_store.verify_signature(&signed)?;
+    check_expiry(&signed)?;
+    Ok(())
+}
@@ -0,0 +1,101 @@
+//! Transcripts of the responses received while running a command (`--record`), rendered again
+//! offline by `replay`.
+//!
+//! A transcript is a header followed by length delimited [`RecordedResponse`]s.
+use anyhow::{anyhow, Context};
+use grpc_service::grpc_protocol::{LaunchTaskResponse, RecordedResponse};
+use grpc_service::prost::Message;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+const HEADER: &[u8] = b"funtonic-transcript-v1\n";
+
+pub struct TranscriptWriter {
+    file: BufWriter<File>,
+    started: Instant,
+}
+
+impl TranscriptWriter {
+    /// Create (or truncate) the transcript file
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let mut file = BufWriter::new(
+            File::create(path)
+                .with_context(|| format!("Unable to create transcript {}", path.display()))?,
+        );
+        file.write_all(HEADER)?;
+        Ok(Self {
+            file,
+            started: Instant::now(),
+        })
+    }
+
+    /// Append a response, flushed right away so the transcript survives a force quit
+    pub fn record(&mut self, response: &LaunchTaskResponse) -> anyhow::Result<()> {
+        let entry = RecordedResponse {
+            elapsed_millis: self.started.elapsed().as_millis() as u64,
+            response: Some(response.clone()),
+        };
+        self.file
+            .write_all(&entry.encode_length_delimited_to_vec())
+            .and_then(|_| self.file.flush())
+            .context("Unable to write transcript")
+    }
+}
+
+pub fn read_transcript(path: &Path) -> anyhow::Result<Vec<RecordedResponse>> {
+    let content = std::fs::read(path)
+        .with_context(|| format!("Unable to read transcript {}", path.display()))?;
+    let mut entries = content
+        .strip_prefix(HEADER)
+        .ok_or_else(|| anyhow!("{} is not a funtonic transcript", path.display()))?;
+    let mut responses = Vec::new();
+    while !entries.is_empty() {
+        responses.push(
+            RecordedResponse::decode_length_delimited(&mut entries)
+                .with_context(|| format!("Corrupted transcript {}", path.display()))?,
+        );
+    }
+    Ok(responses)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_transcript, TranscriptWriter};
+    use grpc_service::grpc_protocol::launch_task_response::TaskResponse;
+    use grpc_service::grpc_protocol::{LaunchTaskResponse, MatchingExecutors};
+
+    #[test]
+    fn record_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transcript");
+        let responses: Vec<_> = [vec!["a".to_string(), "b".to_string()], vec![]]
+            .into_iter()
+            .map(|client_id| LaunchTaskResponse {
+                task_response: Some(TaskResponse::MatchingExecutors(MatchingExecutors {
+                    client_id,
+                    matching_micros: 0,
+                })),
+            })
+            .collect();
+
+        let mut writer = TranscriptWriter::create(&path).unwrap();
+        for response in &responses {
+            writer.record(response).unwrap();
+        }
+        // flushed without dropping the writer
+        let recorded = read_transcript(&path).unwrap();
+        assert_eq!(
+            responses,
+            recorded
+                .iter()
+                .map(|entry| entry.response.clone().unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert!(recorded[0].elapsed_millis <= recorded[1].elapsed_millis);
+
+        std::fs::write(&path, "not a transcript").unwrap();
+        assert!(read_transcript(&path).is_err());
+    }
+}
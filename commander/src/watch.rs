@@ -0,0 +1,133 @@
+//! `admin list-connected-executors --watch`: the listing is refreshed on an interval and the
+//! executors which appeared or disappeared since the previous refresh are highlighted
+use crate::admin::humanized_elapsed_time;
+use chrono::Local;
+use colored::Colorize;
+use funtonic::executor_meta::ExecutorMeta;
+use prettytable::format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR;
+use prettytable::{row, Table};
+use std::collections::BTreeMap;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum Change {
+    Appeared,
+    Disappeared,
+    Unchanged,
+}
+
+/// Executors of both listings by client_id; nothing has changed on the first refresh
+pub(crate) fn diff_executors<'a, V>(
+    previous: Option<&'a BTreeMap<String, V>>,
+    current: &'a BTreeMap<String, V>,
+) -> BTreeMap<&'a str, Change> {
+    let mut changes: BTreeMap<&str, Change> = current
+        .keys()
+        .map(|client_id| match previous {
+            Some(previous) if !previous.contains_key(client_id) => {
+                (client_id.as_str(), Change::Appeared)
+            }
+            _ => (client_id.as_str(), Change::Unchanged),
+        })
+        .collect();
+    for client_id in previous.into_iter().flat_map(BTreeMap::keys) {
+        if !current.contains_key(client_id) {
+            changes.insert(client_id, Change::Disappeared);
+        }
+    }
+    changes
+}
+
+/// Clear the screen & print the listing
+pub(crate) fn print_refresh(
+    query: &str,
+    interval_secs: u64,
+    previous: Option<&BTreeMap<String, ExecutorMeta>>,
+    current: &BTreeMap<String, ExecutorMeta>,
+) -> Result<(), serde_json::Error> {
+    print!("\x1B[2J\x1B[1;1H");
+    println!(
+        "Executors matching query: {} (every {}s, refreshed at {})",
+        query,
+        interval_secs,
+        Local::now().format("%H:%M:%S")
+    );
+    let mut table = Table::new();
+    table.set_format(*FORMAT_NO_BORDER_LINE_SEPARATOR);
+    table.set_titles(row!["client_id", "version", "connected", "uptime", "meta"]);
+    let (mut appeared, mut disappeared) = (0, 0);
+    for (client_id, change) in diff_executors(previous, current) {
+        let (client_id, meta) = match change {
+            Change::Appeared => {
+                appeared += 1;
+                (
+                    format!("+ {}", client_id).green().bold(),
+                    &current[client_id],
+                )
+            }
+            Change::Disappeared => {
+                disappeared += 1;
+                let previous = previous.expect("only previous executors can disappear");
+                (
+                    format!("- {}", client_id).red().bold(),
+                    &previous[client_id],
+                )
+            }
+            Change::Unchanged => (format!("  {}", client_id).normal(), &current[client_id]),
+        };
+        table.add_row(row![
+            client_id,
+            meta.version(),
+            humanized_elapsed_time(meta.connected_at()),
+            humanized_elapsed_time(meta.started_at()),
+            serde_json::to_string(meta.tags())?
+        ]);
+    }
+    table.printstd();
+    println!(
+        "{} executors connected, {} appeared, {} disappeared (Ctrl-C to exit)",
+        current.len().to_string().green(),
+        appeared.to_string().green(),
+        disappeared.to_string().red()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff_executors, Change};
+    use std::collections::BTreeMap;
+
+    fn executors(client_ids: &[&str]) -> BTreeMap<String, ()> {
+        client_ids.iter().map(|id| (id.to_string(), ())).collect()
+    }
+
+    #[test]
+    fn diff() {
+        let previous = executors(&["db-1", "web-1", "web-2"]);
+        let current = executors(&["db-1", "web-2", "web-3"]);
+        assert_eq!(
+            vec![
+                ("db-1", Change::Unchanged),
+                ("web-1", Change::Disappeared),
+                ("web-2", Change::Unchanged),
+                ("web-3", Change::Appeared),
+            ],
+            diff_executors(Some(&previous), &current)
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+
+        // first refresh
+        assert!(diff_executors(None, &current)
+            .values()
+            .all(|change| *change == Change::Unchanged));
+
+        let empty = executors(&[]);
+        assert!(diff_executors(Some(&previous), &empty)
+            .values()
+            .all(|change| *change == Change::Disappeared));
+        assert!(diff_executors(Some(&empty), &current)
+            .values()
+            .all(|change| *change == Change::Appeared));
+    }
+}
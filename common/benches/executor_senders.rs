@@ -0,0 +1,86 @@
+//! Compare the sharded executor senders map with a single `Mutex<HashMap>` under concurrent
+//! executor registrations and task dispatch.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use funtonic::task_server::{ExecutorSender, ExecutorSenders};
+use futures::channel::mpsc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+const EXECUTORS: usize = 5000;
+const DISPATCHES_PER_THREAD: usize = 2000;
+
+trait SenderMap: Send + Sync + 'static {
+    fn register(&self, client_id: String, sender: ExecutorSender);
+    fn dispatch(&self, client_id: &str) -> Option<ExecutorSender>;
+}
+
+impl SenderMap for Mutex<HashMap<String, ExecutorSender>> {
+    fn register(&self, client_id: String, sender: ExecutorSender) {
+        self.lock().unwrap().insert(client_id, sender);
+    }
+
+    fn dispatch(&self, client_id: &str) -> Option<ExecutorSender> {
+        self.lock().unwrap().get(client_id).cloned()
+    }
+}
+
+impl SenderMap for ExecutorSenders {
+    fn register(&self, client_id: String, sender: ExecutorSender) {
+        self.insert(client_id, sender);
+    }
+
+    fn dispatch(&self, client_id: &str) -> Option<ExecutorSender> {
+        self.get(client_id)
+    }
+}
+
+/// half of the threads (re)register executors while the other half dispatch tasks to them
+fn registrations_and_dispatch<M: SenderMap>(map: Arc<M>, threads: usize) {
+    let (sender, _receiver) = mpsc::channel(1);
+    let handles = (0..threads)
+        .map(|thread| {
+            let map = map.clone();
+            let sender = sender.clone();
+            std::thread::spawn(move || {
+                for i in 0..DISPATCHES_PER_THREAD {
+                    let client_id = format!("executor-{}", (thread * 7919 + i) % EXECUTORS);
+                    if thread % 2 == 0 {
+                        map.register(client_id, sender.clone());
+                    } else {
+                        map.dispatch(&client_id);
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+fn bench(c: &mut Criterion) {
+    let (sender, _receiver) = mpsc::channel(1);
+    let mut group = c.benchmark_group("executor_senders");
+    for threads in [2, 8, 32] {
+        let mutex = Arc::new(Mutex::new(HashMap::new()));
+        let sharded = Arc::new(ExecutorSenders::default());
+        for i in 0..EXECUTORS {
+            mutex.register(format!("executor-{}", i), sender.clone());
+            sharded.register(format!("executor-{}", i), sender.clone());
+        }
+        group.bench_with_input(
+            BenchmarkId::new("mutex", threads),
+            &threads,
+            |b, threads| b.iter(|| registrations_and_dispatch(mutex.clone(), *threads)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("sharded", threads),
+            &threads,
+            |b, threads| b.iter(|| registrations_and_dispatch(sharded.clone(), *threads)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
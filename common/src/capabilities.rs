@@ -0,0 +1,121 @@
+//! Optional protocol features negotiated between commanders, taskservers & executors.
+//!
+//! Each end advertises the capabilities it implements, a feature is used for a task only if the
+//! commander, the taskserver and the executor running the task all support it. Older peers
+//! advertise nothing: new features are never used with them.
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use std::iter::FromIterator;
+
+/// Output lines are sent by batches (`TaskOutputBatch`) rather than one by one
+pub const OUTPUT_BATCHES: &str = "output-batches";
+
+/// Scripts (`Program::Script`) are run, older executors see an empty command
+pub const SCRIPTS: &str = "scripts";
+
+/// Commands `withBecome` are run through the become command, older executors run them as is
+pub const BECOME: &str = "become";
+
+/// Capabilities implemented by this build
+pub const SUPPORTED: &[&str] = &[OUTPUT_BATCHES, SCRIPTS, BECOME];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities(BTreeSet<String>);
+
+impl Capabilities {
+    /// Capabilities of this build, to be advertised to peers
+    pub fn local() -> Self {
+        SUPPORTED.iter().copied().collect()
+    }
+
+    /// Capabilities advertised by a peer, the ones unknown to this build are ignored
+    pub fn from_peer(capabilities: &[String]) -> Self {
+        capabilities
+            .iter()
+            .filter(|capability| SUPPORTED.contains(&capability.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.0.contains(capability)
+    }
+
+    /// Capabilities supported by both ends
+    pub fn intersection(&self, other: &Capabilities) -> Capabilities {
+        self.0.intersection(&other.0).cloned().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<S: Into<String>> FromIterator<S> for Capabilities {
+    fn from_iter<T: IntoIterator<Item = S>>(iter: T) -> Self {
+        Self(iter.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<Capabilities> for Vec<String> {
+    fn from(capabilities: Capabilities) -> Self {
+        capabilities.0.into_iter().collect()
+    }
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(
+                f,
+                "{}",
+                self.0.iter().cloned().collect::<Vec<_>>().join(", ")
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Capabilities;
+
+    #[test]
+    fn intersection() {
+        let commander: Capabilities = ["compression", "pty", "keepalive"]
+            .iter()
+            .copied()
+            .collect();
+        let executor: Capabilities = ["keepalive", "compression", "detached"]
+            .iter()
+            .copied()
+            .collect();
+        let effective = commander.intersection(&executor);
+        assert!(effective.supports("compression"));
+        assert!(effective.supports("keepalive"));
+        assert!(!effective.supports("pty"));
+        assert!(!effective.supports("detached"));
+        assert_eq!(
+            vec!["compression".to_string(), "keepalive".to_string()],
+            Vec::from(effective.clone())
+        );
+        assert_eq!("compression, keepalive", effective.to_string());
+    }
+
+    #[test]
+    fn old_peers() {
+        // peers predating capabilities send an empty list
+        let old_peer = Capabilities::from_peer(&[]);
+        assert!(old_peer.is_empty());
+        assert!(Capabilities::local().intersection(&old_peer).is_empty());
+        assert_eq!("none", old_peer.to_string());
+
+        // unknown capabilities are ignored
+        assert!(!Capabilities::from_peer(&["teleportation".to_string()]).supports("teleportation"));
+        assert_eq!(
+            Capabilities::local(),
+            Capabilities::from_peer(&Vec::from(Capabilities::local()))
+        );
+    }
+}
@@ -1,7 +1,10 @@
+use crate::crypto::passphrase::{decrypt_key, EncryptedPkcs8, PassphraseError};
 use crate::executor_meta::{ExecutorMeta, Tag};
 use crate::file_utils::{parse_yaml_from_file, path_concat2, read};
+use crate::prost::Message;
 use crate::tonic;
-use anyhow::Error;
+use anyhow::{Context, Error};
+use grpc_service::payload::SignedPayload;
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
@@ -9,10 +12,17 @@ use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::File;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
 use thiserror::Error;
+use tonic::codec::CompressionEncoding;
 use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
 
-#[derive(Serialize, Deserialize, Debug)]
+mod env_overrides;
+mod tls_expiry;
+pub use env_overrides::{EnvOverrides, EnvVars, ENV_PREFIX};
+pub use tls_expiry::{CertificateExpiry, ExpiryStatus, DEFAULT_EXPIRY_WARNING_DAYS};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TlsConfig {
     /// CA PEM encoded certificate file path
     pub ca_cert: String,
@@ -27,6 +37,10 @@ pub struct TlsConfig {
     ///
     /// Specifying the server_domain overrides the server url domain.
     pub server_domain: Option<String>,
+    /// Warn about the certificate & the CA certificate expiring within this many days, defaults
+    /// to 30
+    #[serde(default)]
+    pub expiry_warning_days: Option<u64>,
 }
 
 impl TlsConfig {
@@ -58,43 +72,530 @@ impl TlsConfig {
     }
 }
 
+/// Compression of the gRPC messages sent, `compression` being `gzip` or not set. Compressed
+/// messages are accepted whatever the setting so peers with compression disabled keep working.
+pub fn compression_encoding(
+    compression: Option<&str>,
+) -> Result<Option<CompressionEncoding>, anyhow::Error> {
+    match compression {
+        None => Ok(None),
+        Some("gzip") => Ok(Some(CompressionEncoding::Gzip)),
+        Some("zstd") => Err(anyhow::anyhow!(
+            "zstd compression is not available in this build, use gzip"
+        )),
+        Some(other) => Err(anyhow::anyhow!(
+            "Unknown compression {}, expected gzip",
+            other
+        )),
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ServerConfig {
     /// TLS configuration. If not present, plain unencrypted socket communication will be used
     pub tls: Option<TlsConfig>,
     /// bind address
     pub bind_address: String,
+    /// Serve the admin requests on this address only, eg: on a management network. By default
+    /// they are served on `bind_address`.
+    #[serde(default)]
+    pub admin_bind_address: Option<String>,
     /// Where the server stores its data
     pub data_directory: String,
-    /// List of "authorized" public keys
-    pub authorized_keys: BTreeMap<String, String>,
+    /// List of "authorized" public keys, either base64 encoded or with the queries the executors
+    /// they target must match: `{ key: <base64>, allowed_queries: ["env:staging"] }`
+    ///
+    /// Used to create `authorized_keys.yml` in the data directory on first run, the keys are then
+    /// managed with `admin add-authorized-key` & `admin remove-authorized-key`. Keys added here
+    /// later on are added on start unless removed by an admin; a key differing from the stored
+    /// one is ignored with a warning. The allowed queries are read on each start, they apply to
+    /// the stored key of the same id.
+    pub authorized_keys: BTreeMap<String, AuthorizedKey>,
     /// List of admin related keys
+    ///
+    /// Used to create `admin_authorized_keys.yml` in the data directory on first run, keys added
+    /// here later on are added on start; remove this file to replace the stored keys
     pub admin_authorized_keys: BTreeMap<String, String>,
+    /// Keep the signed terminal results sent by executors in the task history, so they can be
+    /// verified afterwards with `admin verify-task`
+    #[serde(default)]
+    pub retain_signatures: bool,
+    /// At most this many tasks are kept in the task history, the oldest are dropped. Defaults to
+    /// 100000.
+    #[serde(default)]
+    pub task_history_max_entries: Option<usize>,
+    /// Signed payloads are still accepted this long after their expiry, for commanders &
+    /// executors whose clock is behind the taskserver one, and valid this much longer than
+    /// [MAX_SIGNATURE_VALIDITY_SECS] for those whose clock is ahead. Defaults to 0.
+    #[serde(default)]
+    pub allowed_clock_skew_secs: Option<u64>,
+    /// Sanity checks of the system clock & entropy source
+    #[serde(default)]
+    pub preflight: PreflightConfig,
+    /// Larger admin responses are kept by the taskserver & fetched by chunks, defaults to 3MiB
+    #[serde(default)]
+    pub max_admin_response_bytes: Option<u64>,
+    /// Header carrying the client certificate CN set by a TLS terminating reverse proxy, eg:
+    /// `x-forwarded-client-cn`. It replaces the CN of the peer certificate.
+    #[serde(default)]
+    pub trusted_proxy_header: Option<String>,
+    /// `trusted_proxy_header` is ignored unless the connection comes from these networks, eg:
+    /// `10.0.0.0/8` or `192.168.1.10`
+    #[serde(default)]
+    pub trusted_proxy_cidrs: Vec<String>,
+    /// The CN or a SAN of the executors client certificates (or the identity forwarded by a
+    /// trusted proxy) must be their client_id, the others are denied (PermissionDenied) without
+    /// waiting for an admin approval. Requires TLS or a trusted proxy.
+    #[serde(default)]
+    pub verify_peer_identity: bool,
+    /// The CN or a SAN of the commanders client certificates (or the identity forwarded by a
+    /// trusted proxy) must be one of these names. Any commander is accepted if empty.
+    #[serde(default)]
+    pub allowed_commander_cns: Vec<String>,
+    /// Tasks dispatched this long ago whose results are not reported are considered lost (eg: the
+    /// executor crashed before reporting them), defaults to 1 hour
+    #[serde(default)]
+    pub task_sink_ttl_secs: Option<u64>,
+    /// Messages buffered by the taskserver for each task & response stream: a slow commander
+    /// slows the executors down rather than having their output buffered, defaults to 1024
+    #[serde(default)]
+    pub stream_buffer_size: Option<usize>,
+    /// Compression of the responses sent to the executors & commanders accepting it: `gzip`.
+    /// Disabled by default.
+    #[serde(default)]
+    pub compression: Option<String>,
+    /// Append the signed requests of the commanders to this file, accepted or not, as JSON lines.
+    /// The file is reopened on SIGHUP, eg: after logrotate moved it.
+    #[serde(default)]
+    pub access_log: Option<String>,
+    /// The access log is renamed to `<access_log>.1` once it reaches this size, defaults to 100MiB.
+    /// Only this last rotated file is kept, rotate it with logrotate to keep more.
+    #[serde(default)]
+    pub access_log_max_bytes: Option<u64>,
+    /// An executor registering while another one is connected with the same client_id replaces
+    /// it instead of being rejected (eg: an executor moved to another host)
+    #[serde(default)]
+    pub allow_takeover: bool,
+    /// Trust on first use: the key of an unknown executor is approved when it first registers,
+    /// without `commander admin approve-executor-key`. A known executor presenting another key
+    /// still waits for an approval. Meant for small setups, disabled by default.
+    #[serde(default)]
+    pub auto_approve_executor_keys: bool,
+}
+
+impl ServerConfig {
+    /// Base64 encoded authorized keys by key id
+    pub fn authorized_public_keys(&self) -> BTreeMap<String, String> {
+        self.authorized_keys
+            .iter()
+            .map(|(key_id, key)| (key_id.clone(), key.key.clone()))
+            .collect()
+    }
+
+    /// Allowed queries of the scoped authorized keys by key id
+    pub fn authorized_key_scopes(&self) -> BTreeMap<String, Vec<String>> {
+        self.authorized_keys
+            .iter()
+            .filter_map(|(key_id, key)| {
+                key.allowed_queries
+                    .as_ref()
+                    .map(|allowed_queries| (key_id.clone(), allowed_queries.clone()))
+            })
+            .collect()
+    }
+}
+
+/// Authorized commander key, restricted to the executors matching one of the `allowed_queries`
+/// if set
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(from = "AuthorizedKeyRepr", into = "AuthorizedKeyRepr")]
+pub struct AuthorizedKey {
+    /// base64 encoded public key
+    pub key: String,
+    pub allowed_queries: Option<Vec<String>>,
+}
+
+/// Keys without scope are plain base64 strings, as in configurations written before keys could
+/// be scoped
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum AuthorizedKeyRepr {
+    Key(String),
+    Scoped {
+        key: String,
+        allowed_queries: Option<Vec<String>>,
+    },
+}
+
+impl From<AuthorizedKeyRepr> for AuthorizedKey {
+    fn from(repr: AuthorizedKeyRepr) -> Self {
+        match repr {
+            AuthorizedKeyRepr::Key(key) => AuthorizedKey {
+                key,
+                allowed_queries: None,
+            },
+            AuthorizedKeyRepr::Scoped {
+                key,
+                allowed_queries,
+            } => AuthorizedKey {
+                key,
+                allowed_queries,
+            },
+        }
+    }
+}
+
+impl From<AuthorizedKey> for AuthorizedKeyRepr {
+    fn from(key: AuthorizedKey) -> Self {
+        match key.allowed_queries {
+            None => AuthorizedKeyRepr::Key(key.key),
+            allowed_queries => AuthorizedKeyRepr::Scoped {
+                key: key.key,
+                allowed_queries,
+            },
+        }
+    }
+}
+
+impl From<String> for AuthorizedKey {
+    fn from(key: String) -> Self {
+        AuthorizedKey {
+            key,
+            allowed_queries: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PreflightConfig {
+    /// Refuse to start if a startup check fails, the failures are only logged otherwise
+    #[serde(default)]
+    pub strict: bool,
+    /// The system clock must be after this rfc3339 date at startup, eg: the deployment date
+    #[serde(default)]
+    pub earliest_time: Option<String>,
+    /// Log when the system clock goes backwards by more than this while running, defaults to 5s
+    #[serde(default)]
+    pub time_jump_threshold_secs: Option<u64>,
 }
+
+/// Validity of the signed payloads when not configured
+pub const DEFAULT_SIGNATURE_VALIDITY_SECS: u64 = 60;
+
+/// Longest validity of the signed payloads: the payloads valid for longer (plus the allowed clock
+/// skew) are rejected
+pub const MAX_SIGNATURE_VALIDITY_SECS: u64 = 3600;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CommanderConfig {
     /// TLS configuration. If not present, plain unencrypted socket communication will be used
     pub tls: Option<TlsConfig>,
     pub server_url: String,
+    /// Admin listener of the taskserver (`admin_bind_address`), defaults to `server_url`
+    #[serde(default)]
+    pub admin_server_url: Option<String>,
+    /// Taskservers by name, eg: one per region. When set, `run` & `keys` commands are sent to
+    /// all of them instead of `server_url`, except batched runs & dry runs. With several
+    /// taskservers the executors client_ids are prefixed by the taskserver name, eg: `eu/web-1`.
+    #[serde(default)]
+    pub servers: BTreeMap<String, String>,
     pub ed25519_key: ED25519Key,
+    /// Validity of the requests signed by the commander, defaults to 60s, at most 1h
+    #[serde(default)]
+    pub signature_validity_secs: Option<u64>,
+    /// Regexes of the commands asking for a confirmation whatever the targeted executors,
+    /// matched against each command of a command line (`a && b | c`). Defaults to `reboot`, `rm`
+    /// & `halt`.
+    #[serde(default)]
+    pub unsafe_commands: Option<Vec<String>>,
+    #[serde(default)]
+    pub safeguard_policies: Vec<SafeguardPolicy>,
+    /// Commands targeting more executors ask for a confirmation (`--force` skips it), they are
+    /// refused when stdin is not a tty
+    #[serde(default)]
+    pub confirm_above: Option<usize>,
+    /// Queries referenced as `@name` instead of a query on the command line, eg:
+    /// `web-prod: env:prod and role:web`
+    #[serde(default)]
+    pub saved_queries: BTreeMap<String, String>,
+    /// Commands run with `run --alias name`, `{1}`, `{2}`... are replaced by the arguments, eg:
+    /// `restart-service: sudo systemctl restart {1} && systemctl is-active {1}`
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+    /// Largest standard input sent with `run --stdin`, defaults to 1MiB
+    #[serde(default)]
+    pub max_stdin_bytes: Option<usize>,
+    /// Largest script sent with `run --script`, defaults to 1MiB
+    #[serde(default)]
+    pub max_script_bytes: Option<usize>,
+    /// Compression of the requests sent to the taskserver: `gzip`. Disabled by default, the
+    /// taskserver must be recent enough to accept compressed requests.
+    #[serde(default)]
+    pub compression: Option<String>,
+    /// Base64 encoded delegation certificate of `ed25519_key` (`utils delegate` output), sent
+    /// along with the tasks when the key is a short-lived delegated key, eg: in a CI pipeline
+    #[serde(default)]
+    pub delegation: Option<String>,
+}
+
+impl CommanderConfig {
+    pub fn admin_server_url(&self) -> &str {
+        self.admin_server_url.as_deref().unwrap_or(&self.server_url)
+    }
+
+    pub fn signature_validity(&self) -> Duration {
+        Duration::from_secs(
+            self.signature_validity_secs
+                .unwrap_or(DEFAULT_SIGNATURE_VALIDITY_SECS)
+                .min(MAX_SIGNATURE_VALIDITY_SECS),
+        )
+    }
+
+    pub fn max_stdin_bytes(&self) -> usize {
+        self.max_stdin_bytes.unwrap_or(DEFAULT_MAX_STDIN_BYTES)
+    }
+
+    pub fn max_script_bytes(&self) -> usize {
+        self.max_script_bytes.unwrap_or(DEFAULT_MAX_SCRIPT_BYTES)
+    }
+
+    /// Decoded delegation certificate, if any
+    pub fn delegation_certificate(&self) -> Result<Option<SignedPayload>, anyhow::Error> {
+        self.delegation
+            .as_ref()
+            .map(|delegation| {
+                let bytes = data_encoding::BASE64
+                    .decode(delegation.trim().as_bytes())
+                    .context("Unable to decode base64 encoded delegation")?;
+                SignedPayload::decode(bytes.as_slice()).context("Invalid delegation certificate")
+            })
+            .transpose()
+    }
+
+    pub fn unsafe_commands(&self) -> Vec<String> {
+        match &self.unsafe_commands {
+            Some(unsafe_commands) => unsafe_commands.clone(),
+            None => DEFAULT_UNSAFE_COMMANDS
+                .iter()
+                .map(|command| command.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// `reboot`, `rm` & `halt`, including their paths (`/sbin/reboot`)
+pub const DEFAULT_UNSAFE_COMMANDS: [&str; 3] = [
+    r"^(\S*/)?reboot(\s|$)",
+    r"^(\S*/)?rm(\s|$)",
+    r"^(\S*/)?halt(\s|$)",
+];
+
+/// The standard input is embedded in the signed launch request, it must stay small
+pub const DEFAULT_MAX_STDIN_BYTES: usize = 1024 * 1024;
+
+/// Scripts are embedded in the signed launch request as well
+pub const DEFAULT_MAX_SCRIPT_BYTES: usize = 1024 * 1024;
+
+/// Prompts & refusals of the commands run on the executors matching `query`, on top of the
+/// built-in prompt of commands like `reboot` or `rm`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SafeguardPolicy {
+    /// shown when a command is prompted or refused, defaults to the query
+    #[serde(default)]
+    pub name: Option<String>,
+    pub query: String,
+    /// commands (program names) asking for a confirmation
+    #[serde(default)]
+    pub prompt_commands: Vec<String>,
+    /// commands (program names) that are never sent
+    #[serde(default)]
+    pub forbid_commands: Vec<String>,
+}
+
+impl SafeguardPolicy {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.query)
+    }
 }
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ED25519Key {
     pub id: String,
+    /// empty when the key is encrypted
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub pkcs8: String,
     // useful for retrieving the public key from the config ;)
     pub public_key: Option<String>,
+    /// pkcs8 encrypted with a passphrase, see `commander utils encrypt-key`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pkcs8_encrypted: Option<EncryptedPkcs8>,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ExecutorConfig {
     /// TLS configuration. If not present, plain unencrypted socket communication will be used
     pub tls: Option<TlsConfig>,
     pub client_id: String,
     pub tags: HashMap<String, Tag>,
+    /// Single taskserver url, kept for compatibility with `server_urls`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub server_url: String,
+    /// Taskserver urls (eg: active & standby), tried in turn when the connection fails
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub server_urls: Vec<String>,
+    /// Url of the last taskserver the executor connected to, tried first on reconnection
+    #[serde(skip)]
+    pub last_server_url: Option<String>,
     pub authorized_keys: BTreeMap<String, String>,
+    /// Expiration dates (unix timestamp in seconds) of the authorized keys that expire, by key id
+    #[serde(default)]
+    pub authorized_keys_expiry: BTreeMap<String, u64>,
+    /// If set, only these keys can authorize or revoke keys on this executor. The key operations
+    /// must also be signed by a key of `authorized_keys`.
+    #[serde(default)]
+    pub admin_authorized_keys: Option<BTreeMap<String, String>>,
+    /// At-rest protection of the executor signing key file
+    #[serde(default)]
+    pub key_protection: KeyProtection,
+    /// If set, system tags (os info, network interfaces, grains) are recomputed and sent to the
+    /// taskserver at this interval
+    #[serde(default)]
+    pub tag_refresh_interval_secs: Option<u64>,
+    /// Saltstack grains file merged into the executor tags, defaults to /etc/salt/grains
+    #[serde(default)]
+    pub grains_file: Option<PathBuf>,
+    /// Delay before registering again while the executor key is waiting for an admin approval,
+    /// defaults to 60s
+    #[serde(default)]
+    pub pending_approval_retry_secs: Option<u64>,
+    /// Execution results (output lines...) of a task buffered while they cannot be sent to the
+    /// taskserver fast enough, the task output is not read anymore once the buffer is full.
+    /// Defaults to 1024.
+    #[serde(default)]
+    pub result_buffer_messages: Option<usize>,
+    /// When the taskserver becomes unavailable while a task is running (restart...), how long
+    /// the results of the task are sent again once it is finished, in seconds. Defaults to 60s, 0
+    /// disables the retries.
+    #[serde(default)]
+    pub result_retry_window_secs: Option<u64>,
+    /// Results of a task kept to be sent again, in bytes: the oldest output lines are dropped
+    /// beyond, the end of the task is always kept. Defaults to 1MiB.
+    #[serde(default)]
+    pub result_retry_buffer_bytes: Option<usize>,
+    /// Output (stdout & stderr) bytes forwarded per task. The rest of the output is dropped once
+    /// exceeded, the task keeps running. Unlimited by default.
+    #[serde(default)]
+    pub max_output_bytes: Option<usize>,
+    /// Longer output lines are truncated, in bytes. Unlimited by default.
+    #[serde(default)]
+    pub max_line_length: Option<usize>,
+    /// Output lines are sent by batches collected for up to this delay, in milliseconds. Defaults
+    /// to 20ms, 0 only groups the lines already read.
+    #[serde(default)]
+    pub output_batch_millis: Option<u64>,
+    /// A batch of output lines is sent once this size is reached, in bytes. Defaults to 16KiB.
+    #[serde(default)]
+    pub output_batch_bytes: Option<usize>,
+    /// Program & arguments running the commands, the command is appended to them, eg:
+    /// `["powershell", "-Command"]`. Defaults to `["sh", "-c"]`, `["cmd", "/C"]` on Windows.
+    #[serde(default)]
+    pub shell: Option<Vec<String>>,
+    /// Program & arguments wrapping the shell of the commands run with `--become`, split on
+    /// whitespaces. Defaults to `sudo -n`, an empty command rejects these commands.
+    #[serde(default)]
+    pub become_command: Option<String>,
+    /// On shutdown (SIGTERM), how long running tasks may take to finish before being aborted,
+    /// defaults to 30s
+    #[serde(default)]
+    pub drain_timeout_secs: Option<u64>,
+    /// Serves `GET /healthz` & `GET /metrics` (prometheus) over plain http on this address,
+    /// `127.0.0.1:9100` for instance. Disabled by default.
+    #[serde(default)]
+    pub monitoring_bind_address: Option<String>,
+    /// Execute the commands one at a time, in the order they are received, instead of running
+    /// them in parallel (eg: package installs). Key operations are not queued.
+    #[serde(default)]
+    pub serialize_tasks: bool,
+    /// Validity of the registration requests & task results signed by the executor, defaults to
+    /// 60s, at most 1h
+    #[serde(default)]
+    pub signature_validity_secs: Option<u64>,
+    /// Compression of the results sent to the taskserver: `gzip`. Disabled by default, the
+    /// taskserver must be recent enough to accept compressed requests.
+    #[serde(default)]
+    pub compression: Option<String>,
+    /// Keep a record of each task executed in this directory (YAML files), even when its results
+    /// could not be sent to the taskserver. Disabled by default.
+    #[serde(default)]
+    pub task_log_directory: Option<String>,
+    /// Records kept in `task_log_directory`, the oldest ones are removed. Defaults to 1000.
+    #[serde(default)]
+    pub task_log_max_files: Option<usize>,
+    /// `key=value` tags given on the command line (`--tag`), merged over all the other tags.
+    /// Never written back to the configuration file.
+    #[serde(skip)]
+    pub cli_tags: Vec<(String, String)>,
+    /// Random id of the executor installation, read from the `executor_instance_id` file next to
+    /// the signing key. Generated for each run if None.
+    #[serde(skip)]
+    pub instance_id: Option<String>,
+    /// `authorized_keys` or `authorized_keys_expiry` are set by environment variables: the key
+    /// operations are rejected, they would not outlive the next configuration parsing
+    #[serde(skip)]
+    pub authorized_keys_overridden: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyProtection {
+    /// the signing key is stored in plain text
+    #[default]
+    None,
+    /// the signing key is sealed with a key derived from the host machine-id
+    MachineId,
+}
+
+impl ExecutorConfig {
+    /// `server_urls` followed by `server_url`, without duplicates
+    pub fn server_urls(&self) -> Vec<&str> {
+        let mut urls: Vec<&str> = vec![];
+        for url in self.server_urls.iter().chain(Some(&self.server_url)) {
+            if !url.is_empty() && !urls.contains(&url.as_str()) {
+                urls.push(url);
+            }
+        }
+        urls
+    }
+
+    pub fn signature_validity(&self) -> Duration {
+        Duration::from_secs(
+            self.signature_validity_secs
+                .unwrap_or(DEFAULT_SIGNATURE_VALIDITY_SECS)
+                .min(MAX_SIGNATURE_VALIDITY_SECS),
+        )
+    }
+
+    /// Authorized keys changes of `changed` (the configuration overridden by the environment
+    /// variables) applied to this configuration, as read from its file: the only values the
+    /// executor saves back to the file
+    pub fn apply_key_changes(&mut self, changed: &ExecutorConfig) {
+        if !changed.authorized_keys_overridden {
+            self.authorized_keys = changed.authorized_keys.clone();
+            self.authorized_keys_expiry = changed.authorized_keys_expiry.clone();
+        }
+    }
+
+    /// None if `--become` commands are rejected
+    pub fn become_command(&self) -> Option<&str> {
+        match self.become_command.as_deref() {
+            None => Some(DEFAULT_BECOME_COMMAND),
+            Some(command) if command.trim().is_empty() => None,
+            Some(command) => Some(command),
+        }
+    }
 }
 
+pub const DEFAULT_BECOME_COMMAND: &str = "sudo -n";
+
 const DEFAULT_CONFIG_LOCATION: &[&str] = &["~/.funtonic/", "/etc/funtonic/"];
 
 #[derive(Error, Debug)]
@@ -128,11 +629,31 @@ fn resolve_config_path<P: AsRef<Path>, T: AsRef<Path>>(
         .ok_or(NoConfigFileError(name.as_ref().to_string_lossy().into()))
 }
 
-pub fn parse<P: AsRef<Path>, T: AsRef<Path>, C: DeserializeOwned>(
+/// Parse the configuration file, its values are then overridden by the `FUNTONIC_*` environment
+/// variables (see [EnvOverrides])
+pub fn parse<P: AsRef<Path>, T: AsRef<Path>, C: DeserializeOwned + EnvOverrides>(
     provided_config: &Option<P>,
     name: T,
 ) -> Result<(C, PathBuf), Error> {
-    resolve_config(provided_config, name)
+    let (mut config, path) = resolve_config(provided_config, name)?;
+    env_overrides::override_from_process_env(&mut config)?;
+    Ok((config, path))
+}
+
+/// [parse], also returning the configuration as written in the file: the one to save back, the
+/// overridden values must not end up in the file
+pub fn parse_with_file<
+    P: AsRef<Path>,
+    T: AsRef<Path>,
+    C: DeserializeOwned + EnvOverrides + Clone,
+>(
+    provided_config: &Option<P>,
+    name: T,
+) -> Result<(C, C, PathBuf), Error> {
+    let (file_config, path): (C, PathBuf) = resolve_config(provided_config, name)?;
+    let mut config = file_config.clone();
+    env_overrides::override_from_process_env(&mut config)?;
+    Ok((config, file_config, path))
 }
 
 pub fn get_config_directory<P: AsRef<Path>, T: AsRef<Path>>(
@@ -150,6 +671,7 @@ impl From<(&str, &[u8])> for ED25519Key {
             id: id.to_string(),
             pkcs8: data_encoding::BASE64.encode(bytes),
             public_key: None,
+            pkcs8_encrypted: None,
         }
     }
 }
@@ -162,4 +684,22 @@ impl ED25519Key {
     pub fn id(&self) -> &str {
         &self.id
     }
+
+    /// The private key is protected by a passphrase: it must be resolved before being used
+    pub fn is_encrypted(&self) -> bool {
+        self.pkcs8_encrypted.is_some()
+    }
+
+    /// The key usable by [ED25519Key::to_bytes]: decrypted with the passphrase given by
+    /// `passphrase` if it is encrypted, which is only called then
+    pub fn resolve<F>(self, passphrase: F) -> Result<ED25519Key, PassphraseError>
+    where
+        F: FnOnce() -> Result<String, PassphraseError>,
+    {
+        if self.is_encrypted() {
+            decrypt_key(&self, &passphrase()?)
+        } else {
+            Ok(self)
+        }
+    }
 }
@@ -0,0 +1,491 @@
+//! Configuration values overridden by environment variables, eg: in containers where templating
+//! the YAML configuration is not convenient.
+//!
+//! The variable of a field is `FUNTONIC_` followed by its name in upper snake case, the fields of
+//! nested sections are joined by `__`: `FUNTONIC_SERVER_URL`, `FUNTONIC_TLS__CA_CERT`. Strings are
+//! taken as is, the other values (numbers, lists, maps, tags, whole sections) are JSON encoded:
+//! `FUNTONIC_TAGS='{"env": "prod", "roles": ["web"]}'`.
+//!
+//! The executor only saves the authorized keys changes to its configuration file, never the
+//! overridden values. The key operations are rejected while `FUNTONIC_AUTHORIZED_KEYS` or
+//! `FUNTONIC_AUTHORIZED_KEYS_EXPIRY` is set.
+use super::{
+    CommanderConfig, ED25519Key, ExecutorConfig, PreflightConfig, ServerConfig, TlsConfig,
+};
+use anyhow::{Context, Error};
+use serde::de::DeserializeOwned;
+
+/// Prefix of the environment variables overriding configuration values
+pub const ENV_PREFIX: &str = "FUNTONIC_";
+
+/// Configuration sections whose fields can be overridden by environment variables
+pub trait EnvOverrides {
+    fn override_from(&mut self, env: &EnvVars) -> Result<(), Error>;
+}
+
+/// Environment variables of a configuration section
+pub struct EnvVars<'a> {
+    prefix: String,
+    lookup: &'a dyn Fn(&str) -> Option<String>,
+}
+
+impl<'a> EnvVars<'a> {
+    /// Variables read with `lookup`, eg: `|name| std::env::var(name).ok()`
+    pub fn new(lookup: &'a dyn Fn(&str) -> Option<String>) -> Self {
+        Self {
+            prefix: ENV_PREFIX.to_string(),
+            lookup,
+        }
+    }
+
+    /// Variables of the fields of the `name` section
+    pub fn section(&self, name: &str) -> EnvVars<'a> {
+        EnvVars {
+            prefix: format!("{}{}__", self.prefix, name.to_uppercase()),
+            lookup: self.lookup,
+        }
+    }
+
+    /// Whether the variable of the `name` field is set
+    pub fn is_set(&self, name: &str) -> bool {
+        self.var(name).is_some()
+    }
+
+    fn var(&self, name: &str) -> Option<(String, String)> {
+        let var = format!("{}{}", self.prefix, name.to_uppercase());
+        (self.lookup)(&var).map(|value| (var, value))
+    }
+
+    pub fn string(&self, name: &str, field: &mut String) {
+        if let Some((_, value)) = self.var(name) {
+            *field = value;
+        }
+    }
+
+    pub fn optional_string(&self, name: &str, field: &mut Option<String>) {
+        if let Some((_, value)) = self.var(name) {
+            *field = Some(value);
+        }
+    }
+
+    /// JSON encoded value, or a plain string for the fields deserialized from a string (paths,
+    /// enums): `FUNTONIC_KEY_PROTECTION=machine_id`
+    pub fn value<T: DeserializeOwned>(&self, name: &str, field: &mut T) -> Result<(), Error> {
+        if let Some((var, value)) = self.var(name) {
+            *field = serde_json::from_str(&value)
+                .or_else(|e| {
+                    serde_json::from_value(serde_json::Value::String(value)).map_err(|_| e)
+                })
+                .with_context(|| format!("Invalid value of {}, expected JSON", var))?;
+        }
+        Ok(())
+    }
+}
+
+/// The variables of the process
+pub(super) fn override_from_process_env<C: EnvOverrides>(config: &mut C) -> Result<(), Error> {
+    config.override_from(&EnvVars::new(&|name| std::env::var(name).ok()))
+}
+
+impl EnvOverrides for TlsConfig {
+    fn override_from(&mut self, env: &EnvVars) -> Result<(), Error> {
+        env.string("ca_cert", &mut self.ca_cert);
+        env.string("key", &mut self.key);
+        env.string("cert", &mut self.cert);
+        env.optional_string("server_domain", &mut self.server_domain);
+        env.value("expiry_warning_days", &mut self.expiry_warning_days)?;
+        Ok(())
+    }
+}
+
+/// `tls` as a whole (JSON), then its fields if it is set
+fn override_tls(tls: &mut Option<TlsConfig>, env: &EnvVars) -> Result<(), Error> {
+    env.value("tls", tls)?;
+    if let Some(tls) = tls {
+        tls.override_from(&env.section("tls"))?;
+    }
+    Ok(())
+}
+
+impl EnvOverrides for PreflightConfig {
+    fn override_from(&mut self, env: &EnvVars) -> Result<(), Error> {
+        env.value("strict", &mut self.strict)?;
+        env.optional_string("earliest_time", &mut self.earliest_time);
+        env.value(
+            "time_jump_threshold_secs",
+            &mut self.time_jump_threshold_secs,
+        )?;
+        Ok(())
+    }
+}
+
+/// A plain key replaces the encrypted key of the file, and the other way around
+impl EnvOverrides for ED25519Key {
+    fn override_from(&mut self, env: &EnvVars) -> Result<(), Error> {
+        env.string("id", &mut self.id);
+        if let (Some((plain, _)), Some((encrypted, _))) =
+            (env.var("pkcs8"), env.var("pkcs8_encrypted"))
+        {
+            anyhow::bail!("{} and {} cannot both be set", plain, encrypted);
+        }
+        if env.is_set("pkcs8") {
+            env.string("pkcs8", &mut self.pkcs8);
+            self.pkcs8_encrypted = None;
+        }
+        if env.is_set("pkcs8_encrypted") {
+            env.value("pkcs8_encrypted", &mut self.pkcs8_encrypted)?;
+            self.pkcs8.clear();
+        }
+        env.optional_string("public_key", &mut self.public_key);
+        Ok(())
+    }
+}
+
+impl EnvOverrides for ServerConfig {
+    fn override_from(&mut self, env: &EnvVars) -> Result<(), Error> {
+        override_tls(&mut self.tls, env)?;
+        env.string("bind_address", &mut self.bind_address);
+        env.optional_string("admin_bind_address", &mut self.admin_bind_address);
+        env.string("data_directory", &mut self.data_directory);
+        env.value("authorized_keys", &mut self.authorized_keys)?;
+        env.value("admin_authorized_keys", &mut self.admin_authorized_keys)?;
+        env.value("retain_signatures", &mut self.retain_signatures)?;
+        env.value(
+            "task_history_max_entries",
+            &mut self.task_history_max_entries,
+        )?;
+        env.value("allowed_clock_skew_secs", &mut self.allowed_clock_skew_secs)?;
+        self.preflight.override_from(&env.section("preflight"))?;
+        env.value(
+            "max_admin_response_bytes",
+            &mut self.max_admin_response_bytes,
+        )?;
+        env.optional_string("trusted_proxy_header", &mut self.trusted_proxy_header);
+        env.value("trusted_proxy_cidrs", &mut self.trusted_proxy_cidrs)?;
+        env.value("verify_peer_identity", &mut self.verify_peer_identity)?;
+        env.value("allowed_commander_cns", &mut self.allowed_commander_cns)?;
+        env.value("task_sink_ttl_secs", &mut self.task_sink_ttl_secs)?;
+        env.value("stream_buffer_size", &mut self.stream_buffer_size)?;
+        env.optional_string("compression", &mut self.compression);
+        env.optional_string("access_log", &mut self.access_log);
+        env.value("access_log_max_bytes", &mut self.access_log_max_bytes)?;
+        env.value("allow_takeover", &mut self.allow_takeover)?;
+        env.value(
+            "auto_approve_executor_keys",
+            &mut self.auto_approve_executor_keys,
+        )?;
+        Ok(())
+    }
+}
+
+impl EnvOverrides for ExecutorConfig {
+    fn override_from(&mut self, env: &EnvVars) -> Result<(), Error> {
+        override_tls(&mut self.tls, env)?;
+        env.string("client_id", &mut self.client_id);
+        env.value("tags", &mut self.tags)?;
+        env.string("server_url", &mut self.server_url);
+        env.value("server_urls", &mut self.server_urls)?;
+        env.value("authorized_keys", &mut self.authorized_keys)?;
+        env.value("authorized_keys_expiry", &mut self.authorized_keys_expiry)?;
+        self.authorized_keys_overridden =
+            env.is_set("authorized_keys") || env.is_set("authorized_keys_expiry");
+        env.value("admin_authorized_keys", &mut self.admin_authorized_keys)?;
+        env.value("key_protection", &mut self.key_protection)?;
+        env.value(
+            "tag_refresh_interval_secs",
+            &mut self.tag_refresh_interval_secs,
+        )?;
+        env.value("grains_file", &mut self.grains_file)?;
+        env.value(
+            "pending_approval_retry_secs",
+            &mut self.pending_approval_retry_secs,
+        )?;
+        env.value("result_buffer_messages", &mut self.result_buffer_messages)?;
+        env.value(
+            "result_retry_window_secs",
+            &mut self.result_retry_window_secs,
+        )?;
+        env.value(
+            "result_retry_buffer_bytes",
+            &mut self.result_retry_buffer_bytes,
+        )?;
+        env.value("max_output_bytes", &mut self.max_output_bytes)?;
+        env.value("max_line_length", &mut self.max_line_length)?;
+        env.value("output_batch_millis", &mut self.output_batch_millis)?;
+        env.value("output_batch_bytes", &mut self.output_batch_bytes)?;
+        env.value("shell", &mut self.shell)?;
+        env.optional_string("become_command", &mut self.become_command);
+        env.value("drain_timeout_secs", &mut self.drain_timeout_secs)?;
+        env.optional_string("monitoring_bind_address", &mut self.monitoring_bind_address);
+        env.value("serialize_tasks", &mut self.serialize_tasks)?;
+        env.value("signature_validity_secs", &mut self.signature_validity_secs)?;
+        env.optional_string("compression", &mut self.compression);
+        env.optional_string("task_log_directory", &mut self.task_log_directory);
+        env.value("task_log_max_files", &mut self.task_log_max_files)?;
+        Ok(())
+    }
+}
+
+impl EnvOverrides for CommanderConfig {
+    fn override_from(&mut self, env: &EnvVars) -> Result<(), Error> {
+        override_tls(&mut self.tls, env)?;
+        env.string("server_url", &mut self.server_url);
+        env.optional_string("admin_server_url", &mut self.admin_server_url);
+        env.value("servers", &mut self.servers)?;
+        self.ed25519_key
+            .override_from(&env.section("ed25519_key"))?;
+        env.value("signature_validity_secs", &mut self.signature_validity_secs)?;
+        env.value("unsafe_commands", &mut self.unsafe_commands)?;
+        env.value("safeguard_policies", &mut self.safeguard_policies)?;
+        env.value("confirm_above", &mut self.confirm_above)?;
+        env.value("saved_queries", &mut self.saved_queries)?;
+        env.value("aliases", &mut self.aliases)?;
+        env.value("max_stdin_bytes", &mut self.max_stdin_bytes)?;
+        env.value("max_script_bytes", &mut self.max_script_bytes)?;
+        env.optional_string("compression", &mut self.compression);
+        env.optional_string("delegation", &mut self.delegation);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EnvOverrides, EnvVars};
+    use crate::config::{
+        AuthorizedKey, CommanderConfig, ExecutorConfig, KeyProtection, ServerConfig,
+    };
+    use crate::executor_meta::Tag;
+    use std::collections::HashMap;
+
+    fn overridden<C: EnvOverrides>(mut config: C, vars: &[(&str, &str)]) -> anyhow::Result<C> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        config.override_from(&EnvVars::new(&|name| vars.get(name).cloned()))?;
+        Ok(config)
+    }
+
+    #[test]
+    fn executor_overrides() {
+        let config: ExecutorConfig = serde_yaml::from_str(
+            "client_id: exec\nserver_url: http://127.0.0.1:54010\n\
+             tags:\n  env: dev\nauthorized_keys: {}\n",
+        )
+        .unwrap();
+        let config = overridden(
+            config,
+            &[
+                ("FUNTONIC_CLIENT_ID", "web-1"),
+                ("FUNTONIC_SERVER_URL", "http://taskserver:54010"),
+                (
+                    "FUNTONIC_TAGS",
+                    r#"{"env": "prod", "roles": ["web", "db"]}"#,
+                ),
+                ("FUNTONIC_AUTHORIZED_KEYS", r#"{"ops": "c29tZSBrZXk="}"#),
+                ("FUNTONIC_MAX_OUTPUT_BYTES", "4096"),
+                ("FUNTONIC_SERIALIZE_TASKS", "true"),
+                ("FUNTONIC_SHELL", r#"["bash", "-c"]"#),
+                ("FUNTONIC_KEY_PROTECTION", "machine_id"),
+                // no tls section to override
+                ("FUNTONIC_TLS__CA_CERT", "/etc/funtonic/ca.pem"),
+                ("OTHER_CLIENT_ID", "ignored"),
+            ],
+        )
+        .unwrap();
+        assert_eq!("web-1", config.client_id);
+        assert_eq!("http://taskserver:54010", config.server_url);
+        assert!(matches!(config.tags.get("env"), Some(Tag::Value(env)) if env == "prod"));
+        assert!(matches!(config.tags.get("roles"), Some(Tag::List(roles)) if roles.len() == 2));
+        assert_eq!("c29tZSBrZXk=", config.authorized_keys["ops"]);
+        assert_eq!(Some(4096), config.max_output_bytes);
+        assert!(config.serialize_tasks);
+        assert_eq!(
+            Some(vec!["bash".to_string(), "-c".to_string()]),
+            config.shell
+        );
+        assert_eq!(KeyProtection::MachineId, config.key_protection);
+        assert!(config.tls.is_none());
+        assert!(config.authorized_keys_overridden);
+    }
+
+    #[test]
+    fn executor_saved_key_changes() {
+        let yaml = "client_id: exec
+server_url: http://127.0.0.1:54010
+tags: {}
+authorized_keys:
+  ops: b3Bz
+  old: b2xk
+";
+        let env = [("FUNTONIC_CLIENT_ID", "web-1")];
+        let file_config: ExecutorConfig = serde_yaml::from_str(yaml).unwrap();
+        let mut config = overridden(file_config.clone(), &env).unwrap();
+        assert!(!config.authorized_keys_overridden);
+
+        // revoke, reconnect: the file is saved, then parsed again
+        config.authorized_keys.remove("old");
+        let mut saved = file_config;
+        saved.apply_key_changes(&config);
+        let yaml = serde_yaml::to_string(&saved).unwrap();
+        assert!(!yaml.contains("web-1"), "{}", yaml);
+        let config =
+            overridden::<ExecutorConfig>(serde_yaml::from_str(&yaml).unwrap(), &env).unwrap();
+        assert_eq!("web-1", config.client_id);
+        assert_eq!(
+            vec!["ops"],
+            config.authorized_keys.keys().collect::<Vec<_>>()
+        );
+
+        // the keys of the environment are never written to the file
+        let env = [("FUNTONIC_AUTHORIZED_KEYS", r#"{"env": "ZW52"}"#)];
+        let file_config: ExecutorConfig = serde_yaml::from_str(&yaml).unwrap();
+        let config = overridden(file_config.clone(), &env).unwrap();
+        assert!(config.authorized_keys_overridden);
+        let mut saved = file_config;
+        saved.apply_key_changes(&config);
+        assert_eq!(
+            vec!["ops"],
+            saved.authorized_keys.keys().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn server_overrides() {
+        let config: ServerConfig = serde_yaml::from_str(
+            "bind_address: 127.0.0.1:54010\ndata_directory: /tmp\nauthorized_keys: {}\n\
+             admin_authorized_keys: {}\n\
+             tls:\n  ca_cert: ca.pem\n  key: key.pem\n  cert: cert.pem\n",
+        )
+        .unwrap();
+        let config = overridden(
+            config,
+            &[
+                ("FUNTONIC_BIND_ADDRESS", "0.0.0.0:54010"),
+                ("FUNTONIC_TLS__CERT", "/run/secrets/cert.pem"),
+                ("FUNTONIC_PREFLIGHT__STRICT", "true"),
+                (
+                    "FUNTONIC_AUTHORIZED_KEYS",
+                    r#"{"ci": {"key": "c29tZSBrZXk=", "allowed_queries": ["env:staging"]}}"#,
+                ),
+            ],
+        )
+        .unwrap();
+        assert_eq!("0.0.0.0:54010", config.bind_address);
+        let tls = config.tls.as_ref().unwrap();
+        assert_eq!("/run/secrets/cert.pem", tls.cert);
+        assert_eq!("ca.pem", tls.ca_cert);
+        assert!(config.preflight.strict);
+        assert_eq!(
+            AuthorizedKey {
+                key: "c29tZSBrZXk=".into(),
+                allowed_queries: Some(vec!["env:staging".into()]),
+            },
+            config.authorized_keys["ci"]
+        );
+    }
+
+    #[test]
+    fn commander_overrides() {
+        let config: CommanderConfig = serde_yaml::from_str(
+            "server_url: http://127.0.0.1:54010\ned25519_key:\n  id: ops\n  pkcs8: a2V5\n",
+        )
+        .unwrap();
+        let config = overridden(
+            config,
+            &[
+                ("FUNTONIC_SERVER_URL", "https://taskserver:54010"),
+                ("FUNTONIC_ED25519_KEY__ID", "ci"),
+                ("FUNTONIC_ED25519_KEY__PKCS8", "Y2kga2V5"),
+                (
+                    "FUNTONIC_TLS",
+                    r#"{"ca_cert": "ca.pem", "key": "key.pem", "cert": "cert.pem"}"#,
+                ),
+                ("FUNTONIC_TLS__SERVER_DOMAIN", "taskserver.example.com"),
+            ],
+        )
+        .unwrap();
+        assert_eq!("https://taskserver:54010", config.server_url);
+        assert_eq!("ci", config.ed25519_key.id);
+        assert_eq!("Y2kga2V5", config.ed25519_key.pkcs8);
+        let tls = config.tls.as_ref().unwrap();
+        assert_eq!("ca.pem", tls.ca_cert);
+        assert_eq!(Some("taskserver.example.com"), tls.server_domain.as_deref());
+
+        let error = overridden(config, &[("FUNTONIC_CONFIRM_ABOVE", "ten")]).unwrap_err();
+        assert!(
+            error.to_string().contains("FUNTONIC_CONFIRM_ABOVE"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn encrypted_key_overrides() {
+        let yaml = "server_url: http://127.0.0.1:54010\ned25519_key:\n  id: ops\n  \
+                    pkcs8_encrypted:\n    iterations: 10\n    salt: c2FsdA==\n    \
+                    encrypted: ZW5jcnlwdGVk\n";
+
+        // a plain key is not shadowed by the encrypted key of the file
+        let config: CommanderConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(config.ed25519_key.is_encrypted());
+        let config = overridden(config, &[("FUNTONIC_ED25519_KEY__PKCS8", "Y2kga2V5")]).unwrap();
+        assert!(!config.ed25519_key.is_encrypted());
+        assert_eq!("Y2kga2V5", config.ed25519_key.pkcs8);
+
+        // and the other way around
+        let config = overridden(
+            config,
+            &[(
+                "FUNTONIC_ED25519_KEY__PKCS8_ENCRYPTED",
+                r#"{"iterations": 20, "salt": "c2Vs", "encrypted": "c2VjcmV0"}"#,
+            )],
+        )
+        .unwrap();
+        assert!(config.ed25519_key.pkcs8.is_empty());
+        let encrypted = config.ed25519_key.pkcs8_encrypted.as_ref().unwrap();
+        assert_eq!(20, encrypted.iterations);
+        assert_eq!("c2VjcmV0", encrypted.encrypted);
+
+        let config: CommanderConfig = serde_yaml::from_str(yaml).unwrap();
+        let error = overridden(
+            config,
+            &[
+                ("FUNTONIC_ED25519_KEY__PKCS8", "Y2kga2V5"),
+                (
+                    "FUNTONIC_ED25519_KEY__PKCS8_ENCRYPTED",
+                    r#"{"iterations": 20, "salt": "c2Vs", "encrypted": "c2VjcmV0"}"#,
+                ),
+            ],
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("FUNTONIC_ED25519_KEY__PKCS8_ENCRYPTED"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn process_env() {
+        // only read by this test
+        std::env::set_var(
+            "FUNTONIC_ENV_OVERRIDES_TEST__DATA_DIRECTORY",
+            "/var/lib/funtonic",
+        );
+        let mut config: ServerConfig = serde_yaml::from_str(
+            "bind_address: 127.0.0.1:54010\ndata_directory: /tmp\nauthorized_keys: {}\n\
+             admin_authorized_keys: {}\n",
+        )
+        .unwrap();
+        config
+            .override_from(
+                &EnvVars::new(&|name| std::env::var(name).ok()).section("env_overrides_test"),
+            )
+            .unwrap();
+        std::env::remove_var("FUNTONIC_ENV_OVERRIDES_TEST__DATA_DIRECTORY");
+        assert_eq!("/var/lib/funtonic", config.data_directory);
+    }
+}
@@ -0,0 +1,241 @@
+//! Expiry of the TLS certificates: all the connections fail at once when a certificate or the CA
+//! expires, it is better known in advance.
+use super::TlsConfig;
+use crate::file_utils::read;
+use anyhow::{anyhow, Context};
+use chrono::{TimeZone, Utc};
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+use x509_parser::pem::Pem;
+
+/// Certificates expiring within this many days are reported, unless `expiry_warning_days` is set
+pub const DEFAULT_EXPIRY_WARNING_DAYS: u64 = 30;
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// End of validity of a PEM certificate file, the first certificate to expire for a chain or a
+/// bundle
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CertificateExpiry {
+    /// `tls.cert` or `tls.ca_cert`
+    pub name: String,
+    pub path: String,
+    /// unix timestamp of the `notAfter` of the certificate
+    pub not_after_secs: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpiryStatus {
+    Valid,
+    /// expires within the warning delay, in this many whole days
+    ExpiresSoon(i64),
+    Expired,
+}
+
+impl CertificateExpiry {
+    /// `name` identifies the certificate in the messages
+    pub fn read(name: &str, path: &str) -> anyhow::Result<Self> {
+        let content = read(path)?;
+        let mut not_after_secs = None;
+        for pem in Pem::iter_from_buffer(&content) {
+            let pem = pem.with_context(|| format!("{} is not PEM encoded", path))?;
+            if pem.label != "CERTIFICATE" {
+                continue;
+            }
+            let certificate = pem
+                .parse_x509()
+                .with_context(|| format!("Invalid certificate in {}", path))?;
+            let expiry = certificate.validity().not_after.timestamp();
+            not_after_secs = Some(not_after_secs.map_or(expiry, |other: i64| other.min(expiry)));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            path: path.to_string(),
+            not_after_secs: not_after_secs
+                .ok_or_else(|| anyhow!("No certificate found in {}", path))?,
+        })
+    }
+
+    /// rfc3339 date
+    pub fn not_after(&self) -> String {
+        match Utc.timestamp_opt(self.not_after_secs, 0).single() {
+            Some(not_after) => not_after.to_rfc3339(),
+            None => self.not_after_secs.to_string(),
+        }
+    }
+
+    pub fn status_at(&self, now: SystemTime, warning_days: u64) -> ExpiryStatus {
+        let now_secs = match now.duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => since_epoch.as_secs() as i64,
+            Err(_) => 0,
+        };
+        let left_secs = self.not_after_secs - now_secs;
+        if left_secs <= 0 {
+            ExpiryStatus::Expired
+        } else if left_secs <= warning_days as i64 * SECS_PER_DAY {
+            ExpiryStatus::ExpiresSoon(left_secs / SECS_PER_DAY)
+        } else {
+            ExpiryStatus::Valid
+        }
+    }
+
+    pub fn status(&self, warning_days: u64) -> ExpiryStatus {
+        self.status_at(SystemTime::now(), warning_days)
+    }
+}
+
+impl TlsConfig {
+    pub fn expiry_warning_days(&self) -> u64 {
+        self.expiry_warning_days
+            .unwrap_or(DEFAULT_EXPIRY_WARNING_DAYS)
+    }
+
+    /// Expiry of the certificate & of the CA certificate
+    pub fn certificate_expiries(&self) -> anyhow::Result<Vec<CertificateExpiry>> {
+        Ok(vec![
+            CertificateExpiry::read("tls.cert", &self.cert)?,
+            CertificateExpiry::read("tls.ca_cert", &self.ca_cert)?,
+        ])
+    }
+
+    /// Warn about the certificates expired or expiring soon, meant to be called on startup. The
+    /// expiries are returned, none if a certificate cannot be read: the TLS configuration reports
+    /// it better.
+    pub fn warn_on_expiry(&self) -> Vec<CertificateExpiry> {
+        let expiries = match self.certificate_expiries() {
+            Ok(expiries) => expiries,
+            Err(e) => {
+                warn!(
+                    "Unable to check the expiry of the TLS certificates: {:#}",
+                    e
+                );
+                return vec![];
+            }
+        };
+        for expiry in &expiries {
+            match expiry.status(self.expiry_warning_days()) {
+                ExpiryStatus::Valid => debug!(
+                    "{} ({}) expires on {}",
+                    expiry.name,
+                    expiry.path,
+                    expiry.not_after()
+                ),
+                ExpiryStatus::ExpiresSoon(days) => warn!(
+                    "{} ({}) expires in {} days, on {}: renew it!",
+                    expiry.name,
+                    expiry.path,
+                    days,
+                    expiry.not_after()
+                ),
+                ExpiryStatus::Expired => warn!(
+                    "{} ({}) expired on {}: the TLS connections will fail",
+                    expiry.name,
+                    expiry.path,
+                    expiry.not_after()
+                ),
+            }
+        }
+        expiries
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CertificateExpiry, ExpiryStatus};
+    use crate::config::TlsConfig;
+    use rcgen::{date_time_ymd, Certificate, CertificateParams};
+    use std::path::Path;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn certificate(not_after: (i32, u8, u8)) -> Certificate {
+        let mut params = CertificateParams::new(vec!["test.funtonic.io".to_string()]);
+        params.not_before = date_time_ymd(2020, 1, 1);
+        params.not_after = date_time_ymd(not_after.0, not_after.1, not_after.2);
+        Certificate::from_params(params).unwrap()
+    }
+
+    fn write(dir: &Path, name: &str, certificates: &[&Certificate]) -> String {
+        let path = dir.join(name);
+        let pem: String = certificates
+            .iter()
+            .map(|certificate| certificate.serialize_pem().unwrap())
+            .collect();
+        std::fs::write(&path, pem).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// 2024-01-01T00:00:00Z
+    fn now() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_704_067_200)
+    }
+
+    #[test]
+    fn expiry_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let expired = certificate((2023, 12, 1));
+        let near_expiry = certificate((2024, 1, 20));
+        let fine = certificate((2030, 1, 1));
+
+        let status = |certificates: &[&Certificate]| {
+            CertificateExpiry::read("tls.cert", &write(dir.path(), "cert.pem", certificates))
+                .unwrap()
+                .status_at(now(), 30)
+        };
+        assert_eq!(ExpiryStatus::Expired, status(&[&expired]));
+        assert_eq!(ExpiryStatus::ExpiresSoon(19), status(&[&near_expiry]));
+        assert_eq!(ExpiryStatus::Valid, status(&[&fine]));
+        // the first certificate of a chain to expire
+        assert_eq!(
+            ExpiryStatus::ExpiresSoon(19),
+            status(&[&fine, &near_expiry])
+        );
+
+        let expiry =
+            CertificateExpiry::read("tls.cert", &write(dir.path(), "cert.pem", &[&near_expiry]))
+                .unwrap();
+        assert_eq!("2024-01-20T00:00:00+00:00", expiry.not_after());
+        // the warning delay is configurable
+        assert_eq!(ExpiryStatus::ExpiresSoon(19), expiry.status_at(now(), 19));
+        assert_eq!(ExpiryStatus::Valid, expiry.status_at(now(), 18));
+    }
+
+    #[test]
+    fn tls_config_expiries() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = certificate((2030, 1, 1));
+        let cert = certificate((2024, 1, 20));
+        let tls = TlsConfig {
+            ca_cert: write(dir.path(), "ca.pem", &[&ca]),
+            key: "unused".to_string(),
+            cert: write(dir.path(), "cert.pem", &[&cert]),
+            server_domain: None,
+            expiry_warning_days: None,
+        };
+        let expiries = tls.certificate_expiries().unwrap();
+        assert_eq!(
+            vec!["tls.cert", "tls.ca_cert"],
+            expiries
+                .iter()
+                .map(|expiry| expiry.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            ExpiryStatus::ExpiresSoon(19),
+            expiries[0].status_at(now(), tls.expiry_warning_days())
+        );
+        assert_eq!(tls.warn_on_expiry(), expiries);
+
+        // a key is not a certificate
+        std::fs::write(dir.path().join("key.pem"), ca.serialize_private_key_pem()).unwrap();
+        let error =
+            CertificateExpiry::read("tls.cert", &dir.path().join("key.pem").to_string_lossy())
+                .unwrap_err();
+        assert!(format!("{:#}", error).contains("No certificate found"));
+        assert!(CertificateExpiry::read("tls.cert", "missing.pem").is_err());
+        let tls = TlsConfig {
+            cert: "missing.pem".to_string(),
+            ..tls
+        };
+        assert!(tls.warn_on_expiry().is_empty());
+    }
+}
@@ -1,4 +1,6 @@
 use crate::config::ED25519Key;
+use anyhow::Context;
+use ring::digest;
 use ring::signature;
 use ring::signature::KeyPair;
 use std::collections::BTreeMap;
@@ -14,8 +16,85 @@ pub fn generate_ed25519_key_pair() -> Result<(Vec<u8>, Vec<u8>), ring::error::Un
     Ok((pkcs8_bytes.as_ref().to_vec(), public_key))
 }
 
+/// Derive an ed25519 key pair from a seed, for tests which need the same keys on each run.
+///
+/// ring does not encode seeded keys, the pkcs8 v2 document is built the way
+/// `Ed25519KeyPair::generate_pkcs8` builds it. Returns (private_key pkcs8 encoded , public_key)
+#[cfg(any(test, feature = "test-utils"))]
+pub fn generate_ed25519_key_pair_from_seed(
+    seed: &[u8; 32],
+) -> Result<(Vec<u8>, Vec<u8>), ring::error::Unspecified> {
+    const PKCS8_PREFIX: &[u8] = &[
+        0x30, 0x53, 0x02, 0x01, 0x01, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04,
+        0x20,
+    ];
+    const PUBLIC_KEY_PREFIX: &[u8] = &[0xa1, 0x23, 0x03, 0x21, 0x00];
+    let public_key = signature::Ed25519KeyPair::from_seed_unchecked(seed)
+        .map_err(|_| ring::error::Unspecified)?
+        .public_key()
+        .as_ref()
+        .to_vec();
+    let pkcs8 = [PKCS8_PREFIX, seed, PUBLIC_KEY_PREFIX, &public_key].concat();
+    // checks the encoding
+    signature::Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| ring::error::Unspecified)?;
+    Ok((pkcs8, public_key))
+}
+
+/// Load a pkcs8 encoded ed25519 private key, returns its public key
+pub fn public_key_from_pkcs8(pkcs8: &[u8]) -> Result<Vec<u8>, ring::error::KeyRejected> {
+    let key_pair = signature::Ed25519KeyPair::from_pkcs8(pkcs8)?;
+    Ok(key_pair.public_key().as_ref().to_vec())
+}
+
+/// Public key of a key pair, derived from its private key when not set (older configurations)
+pub fn public_key_of(key: &ED25519Key) -> anyhow::Result<Vec<u8>> {
+    match &key.public_key {
+        Some(public_key) => data_encoding::BASE64
+            .decode(public_key.as_bytes())
+            .with_context(|| format!("Invalid base64 encoded public key of {}", key.id)),
+        None => {
+            let pkcs8 = data_encoding::BASE64
+                .decode(key.pkcs8.as_bytes())
+                .with_context(|| format!("Invalid base64 encoded private key of {}", key.id))?;
+            public_key_from_pkcs8(&pkcs8)
+                .map_err(|e| anyhow::anyhow!("Invalid private key of {}: {}", key.id, e))
+        }
+    }
+}
+
+/// Short form of a public key to compare keys at a glance, the OpenSSH way: `SHA256:` followed by
+/// the unpadded base64 encoded SHA-256 digest of the key
+pub fn fingerprint(public_key: &[u8]) -> String {
+    format!(
+        "SHA256:{}",
+        data_encoding::BASE64_NOPAD.encode(digest::digest(&digest::SHA256, public_key).as_ref())
+    )
+}
+
+/// Fingerprint of a base64 encoded public key, as found in the configurations & keystores
+pub fn base64_fingerprint(public_key: &str) -> Result<String, data_encoding::DecodeError> {
+    Ok(fingerprint(
+        &data_encoding::BASE64.decode(public_key.as_bytes())?,
+    ))
+}
+
 pub fn generate_base64_encoded_keys(key_name: &str) -> (ED25519Key, BTreeMap<String, String>) {
-    let (priv_key, pub_key) = generate_ed25519_key_pair().unwrap();
+    base64_encoded_keys(key_name, generate_ed25519_key_pair().unwrap())
+}
+
+/// Same keys for the same seed, see [`generate_ed25519_key_pair_from_seed`]
+#[cfg(any(test, feature = "test-utils"))]
+pub fn generate_base64_encoded_keys_from_seed(
+    key_name: &str,
+    seed: &[u8; 32],
+) -> (ED25519Key, BTreeMap<String, String>) {
+    base64_encoded_keys(key_name, generate_ed25519_key_pair_from_seed(seed).unwrap())
+}
+
+fn base64_encoded_keys(
+    key_name: &str,
+    (priv_key, pub_key): (Vec<u8>, Vec<u8>),
+) -> (ED25519Key, BTreeMap<String, String>) {
     let authorized_keys = vec![(key_name.to_string(), data_encoding::BASE64.encode(&pub_key))]
         .into_iter()
         .collect();
@@ -24,6 +103,7 @@ pub fn generate_base64_encoded_keys(key_name: &str) -> (ED25519Key, BTreeMap<Str
             id: key_name.to_string(),
             pkcs8: data_encoding::BASE64.encode(&priv_key),
             public_key: Some(data_encoding::BASE64.encode(&pub_key)),
+            pkcs8_encrypted: None,
         },
         authorized_keys,
     )
@@ -1,25 +1,30 @@
-use crate::config::ED25519Key;
-use crate::crypto::signed_payload::payload_bytes_to_sign;
+use crate::config::{ED25519Key, MAX_SIGNATURE_VALIDITY_SECS};
+use crate::crypto::signed_payload::{payload_bytes_to_sign, DELEGATION_PURPOSE};
+use crate::file_utils::{set_private_permissions, warn_if_not_private, write_private_file};
 use crate::prost;
 use crate::tonic;
 use chrono::{DateTime, Local};
 use grpc_service::grpc_protocol::streaming_payload::Payload;
+use grpc_service::grpc_protocol::Delegation;
 use grpc_service::payload::SignedPayload;
 use prost::bytes;
+use prost::Message;
 use rand::random;
 use ring::signature;
 use ring::signature::KeyPair;
 use rustbreak::deser::Yaml;
 use rustbreak::FileDatabase;
+use serde::{Deserialize, Serialize};
 use std::borrow::Borrow;
-use std::collections::hash_map::RandomState;
+use std::collections::hash_map::{Entry, RandomState};
 use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
 use std::fs::File;
 use std::io;
 use std::io::Write;
-use std::path::Path;
-use std::sync::RwLock;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
 use tonic::Status;
@@ -32,6 +37,8 @@ pub enum KeyStoreError {
     WrongSignature(String),
     #[error("Signature expired on {0}, system time: {1}")]
     ExpiredSignature(String, String),
+    #[error("Signature valid until {0}, too far ahead of system time: {1}")]
+    SignatureTooFarAhead(String, String),
     #[error("Cannot decode payload: {0}")]
     PayloadDecodeError(String),
     #[error("Wrong key encoding {0}")]
@@ -42,57 +49,260 @@ pub enum KeyStoreError {
     InternalStorage(#[from] rustbreak::RustbreakError),
     #[error("Poisonned lock (not possible AFAIK)")]
     Poison,
+    #[error("Payload signed by {0} with nonce {1} has already been received")]
+    ReplayDetected(String, u64),
+    #[error("Key {0} expired on {1}")]
+    KeyExpired(String, String),
+    #[error("Key {0} is {1} bytes long, expected a 32 bytes ED25519 public key")]
+    InvalidKeyMaterial(String, usize),
+    #[error("Key ids {0:?} and {1:?} only differ by whitespace")]
+    DuplicateKeyId(String, String),
+    #[error("Invalid delegation: {0}")]
+    InvalidDelegation(String),
 }
 
 impl From<KeyStoreError> for Status {
     fn from(e: KeyStoreError) -> Self {
-        Status::internal(e.to_string())
+        match e {
+            // most likely a wrong clock on the signing host
+            KeyStoreError::ExpiredSignature(valid_until, now) => Status::internal(format!(
+                "Signature expired: payload valid until {}, taskserver time {}, check the clock of the signing host",
+                valid_until, now
+            )),
+            KeyStoreError::SignatureTooFarAhead(valid_until, now) => Status::internal(format!(
+                "Signature valid for too long: payload valid until {}, taskserver time {}, check the clock of the signing host",
+                valid_until, now
+            )),
+            e @ KeyStoreError::InvalidDelegation(_) => Status::permission_denied(e.to_string()),
+            e => Status::internal(e.to_string()),
+        }
+    }
+}
+
+/// A public key and its optional expiration date
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "StoredKeyRepr", into = "StoredKeyRepr")]
+pub struct StoredKey {
+    pub bytes: Vec<u8>,
+    pub expires_at: Option<SystemTime>,
+}
+
+impl StoredKey {
+    pub fn is_expired_at(&self, now: SystemTime) -> bool {
+        self.expires_at
+            .map(|expires_at| expires_at <= now)
+            .unwrap_or(false)
+    }
+
+    /// Expiration date as unix timestamp in seconds, 0 if the key never expires (protobuf
+    /// `PublicKey` encoding)
+    pub fn expires_at_secs(&self) -> u64 {
+        self.expires_at
+            .and_then(|expires_at| expires_at.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|expires_at| expires_at.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// Keys without expiration date are stored as plain bytes: keystore files written before keys
+/// could expire are still readable, and the other way around
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredKeyRepr {
+    Bytes(Vec<u8>),
+    Key {
+        bytes: Vec<u8>,
+        expires_at: Option<SystemTime>,
+    },
+}
+
+impl From<StoredKeyRepr> for StoredKey {
+    fn from(repr: StoredKeyRepr) -> Self {
+        match repr {
+            StoredKeyRepr::Bytes(bytes) => StoredKey {
+                bytes,
+                expires_at: None,
+            },
+            StoredKeyRepr::Key { bytes, expires_at } => StoredKey { bytes, expires_at },
+        }
+    }
+}
+
+impl From<StoredKey> for StoredKeyRepr {
+    fn from(key: StoredKey) -> Self {
+        match key.expires_at {
+            None => StoredKeyRepr::Bytes(key.bytes),
+            expires_at => StoredKeyRepr::Key {
+                bytes: key.bytes,
+                expires_at,
+            },
+        }
+    }
+}
+
+/// Expiration date from its protobuf encoding (unix timestamp in seconds, 0 if the key never
+/// expires)
+pub fn expires_at_from_secs(expires_at_secs: u64) -> Option<SystemTime> {
+    if expires_at_secs == 0 {
+        None
+    } else {
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(expires_at_secs))
     }
 }
 
 pub trait KeyStoreBackend: Sized {
-    fn insert_key<S: Into<String>>(
-        &self,
-        key_id: S,
-        key_bytes: Vec<u8>,
-    ) -> Result<(), KeyStoreError>;
+    fn insert_key<S: Into<String>>(&self, key_id: S, key: StoredKey) -> Result<(), KeyStoreError>;
 
     fn verify(&self, key_id: &str, payload: &[u8], signature: &[u8]) -> Result<(), KeyStoreError>;
 
-    fn list_all(&self) -> Result<HashMap<String, Vec<u8>>, KeyStoreError>;
+    fn list_all(&self) -> Result<HashMap<String, StoredKey>, KeyStoreError>;
 
     fn remove_key(&self, key_id: &str) -> Result<Vec<u8>, KeyStoreError>;
 
     fn has_key(&self, key_id: &str, key_bytes: &[u8]) -> Result<bool, KeyStoreError>;
+
+    fn get_key(&self, key_id: &str) -> Result<Option<StoredKey>, KeyStoreError>;
+}
+
+pub type MemoryKeyStoreBackend = RwLock<HashMap<String, StoredKey>>;
+
+/// Keys stored in a yaml file.
+///
+/// In write-behind mode, inserted keys are only persisted by [KeyStore::flush] (or when the
+/// backend is dropped): registering thousands of keys does not rewrite the whole file each time.
+/// Removals are always persisted right away.
+pub struct FileKeyStoreBackend {
+    db: FileDatabase<HashMap<String, StoredKey>, Yaml>,
+    path: PathBuf,
+    write_behind: bool,
+    /// keys inserted since the last save
+    dirty: AtomicBool,
+    saves: AtomicU64,
+}
+
+impl FileKeyStoreBackend {
+    fn save(&self) -> Result<(), KeyStoreError> {
+        // cleared before saving: keys inserted while saving are saved by the next flush
+        self.dirty.store(false, Ordering::SeqCst);
+        if let Err(e) = self.db.save() {
+            self.dirty.store(true, Ordering::SeqCst);
+            return Err(e.into());
+        }
+        // the file may have been replaced by the save
+        set_private_permissions(&self.path)?;
+        self.saves.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), KeyStoreError> {
+        if self.dirty.load(Ordering::SeqCst) {
+            self.save()
+        } else {
+            Ok(())
+        }
+    }
 }
 
-pub type FileKeyStoreBackend = FileDatabase<HashMap<String, Vec<u8>>, Yaml>;
-pub type MemoryKeyStoreBackend = RwLock<HashMap<String, Vec<u8>>>;
+impl Drop for FileKeyStoreBackend {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("Unable to save keystore: {}", e);
+        }
+    }
+}
 
 fn verify_signature(
-    db: &HashMap<String, Vec<u8>>,
+    db: &HashMap<String, StoredKey>,
     key_id: &str,
     payload: &[u8],
     signature: &[u8],
 ) -> Result<(), KeyStoreError> {
-    db.get(key_id)
-        .ok_or(KeyStoreError::KeyNotFound(key_id.to_string()))
-        .and_then(|key_bytes| {
-            signature::UnparsedPublicKey::new(&signature::ED25519, key_bytes)
-                .verify(&payload, &signature)
-                .map_err(|_| KeyStoreError::WrongSignature(key_id.to_string()))
-        })
+    let key = db
+        .get(key_id)
+        .ok_or(KeyStoreError::KeyNotFound(key_id.to_string()))?;
+    match key.expires_at {
+        Some(expires_at) if key.is_expired_at(SystemTime::now()) => Err(KeyStoreError::KeyExpired(
+            key_id.to_string(),
+            DateTime::<Local>::from(expires_at).to_rfc3339(),
+        )),
+        _ => verify_with_key(key_id, &key.bytes, payload, signature),
+    }
+}
+
+fn verify_with_key(
+    key_id: &str,
+    key_bytes: &[u8],
+    payload: &[u8],
+    signature: &[u8],
+) -> Result<(), KeyStoreError> {
+    signature::UnparsedPublicKey::new(&signature::ED25519, key_bytes)
+        .verify(payload, signature)
+        .map_err(|_| KeyStoreError::WrongSignature(key_id.to_string()))
+}
+
+/// Check the payload validity date is not over
+pub fn check_expiry(payload: &SignedPayload) -> Result<(), KeyStoreError> {
+    check_expiry_at(payload, Duration::ZERO, None, SystemTime::now())
+}
+
+/// Check the payload validity date is not over by more than `allowed_clock_skew`: a payload
+/// signed on a host whose clock is behind ours looks like it was signed in the past. The other
+/// way around, the payload must not be valid for more than [MAX_SIGNATURE_VALIDITY_SECS] plus
+/// `allowed_clock_skew`.
+pub fn check_expiry_with_skew(
+    payload: &SignedPayload,
+    allowed_clock_skew: Duration,
+) -> Result<(), KeyStoreError> {
+    check_expiry_at(
+        payload,
+        allowed_clock_skew,
+        Some(Duration::from_secs(MAX_SIGNATURE_VALIDITY_SECS)),
+        SystemTime::now(),
+    )
+}
+
+pub(crate) fn check_expiry_at(
+    payload: &SignedPayload,
+    allowed_clock_skew: Duration,
+    max_validity: Option<Duration>,
+    now: SystemTime,
+) -> Result<(), KeyStoreError> {
+    let valid_until = SystemTime::UNIX_EPOCH + Duration::from_secs(payload.valid_until_secs);
+    if valid_until + allowed_clock_skew < now {
+        return Err(KeyStoreError::ExpiredSignature(
+            DateTime::<Local>::from(valid_until).to_rfc3339(),
+            DateTime::<Local>::from(now).to_rfc3339(),
+        ));
+    }
+    if let Some(max_validity) = max_validity {
+        if valid_until > now + max_validity + allowed_clock_skew {
+            return Err(KeyStoreError::SignatureTooFarAhead(
+                DateTime::<Local>::from(valid_until).to_rfc3339(),
+                DateTime::<Local>::from(now).to_rfc3339(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check the signature of a payload against the given public key, whatever its validity date
+pub fn verify_payload_signature(
+    payload: &SignedPayload,
+    key_bytes: &[u8],
+) -> Result<(), KeyStoreError> {
+    verify_with_key(
+        &payload.key_id,
+        key_bytes,
+        &payload_bytes_to_sign(payload),
+        &payload.signature,
+    )
 }
 
 impl KeyStoreBackend for MemoryKeyStoreBackend {
-    fn insert_key<S: Into<String>>(
-        &self,
-        key_id: S,
-        key_bytes: Vec<u8>,
-    ) -> Result<(), KeyStoreError> {
+    fn insert_key<S: Into<String>>(&self, key_id: S, key: StoredKey) -> Result<(), KeyStoreError> {
         self.write()
             .map_err(|_| KeyStoreError::Poison)?
-            .insert(key_id.into(), key_bytes);
+            .insert(key_id.into(), key);
         Ok(())
     }
 
@@ -105,7 +315,7 @@ impl KeyStoreBackend for MemoryKeyStoreBackend {
         )
     }
 
-    fn list_all(&self) -> Result<HashMap<String, Vec<u8>, RandomState>, KeyStoreError> {
+    fn list_all(&self) -> Result<HashMap<String, StoredKey, RandomState>, KeyStoreError> {
         Ok(self.read().map_err(|_| KeyStoreError::Poison)?.clone())
     }
 
@@ -113,6 +323,7 @@ impl KeyStoreBackend for MemoryKeyStoreBackend {
         self.write()
             .map_err(|_| KeyStoreError::Poison)?
             .remove(key_id)
+            .map(|key| key.bytes)
             .ok_or(KeyStoreError::KeyNotFound(key_id.to_string()))
     }
 
@@ -121,84 +332,233 @@ impl KeyStoreBackend for MemoryKeyStoreBackend {
             .read()
             .map_err(|_| KeyStoreError::Poison)?
             .get(key_id)
-            .filter(|bytes| bytes.as_slice() == key_bytes)
+            .filter(|key| key.bytes.as_slice() == key_bytes)
             .is_some())
     }
+
+    fn get_key(&self, key_id: &str) -> Result<Option<StoredKey>, KeyStoreError> {
+        Ok(self
+            .read()
+            .map_err(|_| KeyStoreError::Poison)?
+            .get(key_id)
+            .cloned())
+    }
 }
 
 impl KeyStoreBackend for FileKeyStoreBackend {
-    fn insert_key<S: Into<String>>(
-        &self,
-        key_id: S,
-        key_bytes: Vec<u8>,
-    ) -> Result<(), KeyStoreError> {
-        self.write(|db| {
-            db.insert(key_id.into(), key_bytes);
+    fn insert_key<S: Into<String>>(&self, key_id: S, key: StoredKey) -> Result<(), KeyStoreError> {
+        self.db.write(|db| {
+            db.insert(key_id.into(), key);
         })?;
-        Ok(self.save()?)
+        self.dirty.store(true, Ordering::SeqCst);
+        if self.write_behind {
+            Ok(())
+        } else {
+            self.save()
+        }
     }
 
     fn verify(&self, key_id: &str, payload: &[u8], signature: &[u8]) -> Result<(), KeyStoreError> {
-        self.read(|db| verify_signature(db, key_id, payload, signature))?
+        self.db
+            .read(|db| verify_signature(db, key_id, payload, signature))?
     }
 
-    fn list_all(&self) -> Result<HashMap<String, Vec<u8>>, KeyStoreError> {
-        Ok(self.read(|db| db.clone())?)
+    fn list_all(&self) -> Result<HashMap<String, StoredKey>, KeyStoreError> {
+        Ok(self.db.read(|db| db.clone())?)
     }
 
     fn remove_key(&self, key_id: &str) -> Result<Vec<u8>, KeyStoreError> {
-        self.write(|db| {
-            db.remove(key_id)
-                .ok_or(KeyStoreError::KeyNotFound(key_id.to_string()))
-        })?
-        .and_then(|removed| {
-            self.save()?;
-            Ok(removed)
-        })
+        self.db
+            .write(|db| {
+                db.remove(key_id)
+                    .map(|key| key.bytes)
+                    .ok_or(KeyStoreError::KeyNotFound(key_id.to_string()))
+            })?
+            .and_then(|removed| {
+                self.save()?;
+                Ok(removed)
+            })
     }
 
     fn has_key(&self, key_id: &str, key_bytes: &[u8]) -> Result<bool, KeyStoreError> {
-        Ok(self.read(|db| {
+        Ok(self.db.read(|db| {
             db.get(key_id)
-                .filter(|bytes| bytes.as_slice() == key_bytes)
+                .filter(|key| key.bytes.as_slice() == key_bytes)
                 .is_some()
         })?)
     }
+
+    fn get_key(&self, key_id: &str) -> Result<Option<StoredKey>, KeyStoreError> {
+        Ok(self.db.read(|db| db.get(key_id).cloned())?)
+    }
+}
+
+pub(crate) const NONCE_PURGE_INTERVAL_SECS: u64 = 60;
+
+/// Nonces of the decoded payloads, kept until the payloads expire: a captured payload cannot be
+/// replayed while it is still valid. A cache may be shared by several keystores.
+#[derive(Default)]
+pub struct NonceCache {
+    inner: Mutex<NonceCacheInner>,
+    /// nonces are kept longer when expired payloads are tolerated
+    allowed_clock_skew_secs: u64,
+}
+
+#[derive(Default)]
+struct NonceCacheInner {
+    /// validity date by key id & nonce
+    nonces: HashMap<(String, u64), u64>,
+    last_purge_secs: u64,
+}
+
+impl NonceCache {
+    /// Cache for keystores accepting payloads up to `allowed_clock_skew` after their expiry
+    pub fn with_allowed_clock_skew(allowed_clock_skew: Duration) -> Self {
+        Self {
+            inner: Default::default(),
+            allowed_clock_skew_secs: allowed_clock_skew.as_secs(),
+        }
+    }
+
+    /// Record the nonce of a payload, fails if it has already been recorded
+    pub fn check(&self, payload: &SignedPayload) -> Result<(), KeyStoreError> {
+        let now_secs = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.check_at(payload, now_secs)
+    }
+
+    pub(crate) fn check_at(
+        &self,
+        payload: &SignedPayload,
+        now_secs: u64,
+    ) -> Result<(), KeyStoreError> {
+        let mut inner = self.inner.lock().map_err(|_| KeyStoreError::Poison)?;
+        if now_secs >= inner.last_purge_secs + NONCE_PURGE_INTERVAL_SECS {
+            inner
+                .nonces
+                .retain(|_, valid_until_secs| *valid_until_secs >= now_secs);
+            inner.last_purge_secs = now_secs;
+        }
+        match inner.nonces.entry((payload.key_id.clone(), payload.nonce)) {
+            Entry::Occupied(_) => Err(KeyStoreError::ReplayDetected(
+                payload.key_id.clone(),
+                payload.nonce,
+            )),
+            Entry::Vacant(entry) => {
+                entry.insert(payload.valid_until_secs + self.allowed_clock_skew_secs);
+                Ok(())
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.inner
+            .lock()
+            .map(|inner| inner.nonces.len())
+            .unwrap_or_default()
+    }
 }
 
 /// Store ED25519 public key
 pub struct KeyStore<B: KeyStoreBackend> {
     keys: B,
+    nonces: Option<Arc<NonceCache>>,
+    allowed_clock_skew: Duration,
 }
 
 pub fn memory_keystore() -> KeyStore<MemoryKeyStoreBackend> {
     KeyStore {
         keys: Default::default(),
+        nonces: None,
+        allowed_clock_skew: Duration::ZERO,
     }
 }
 
+/// Check the base64 encoded public keys of a configuration, without registering them anywhere
+pub fn validate_keys<'a, T: IntoIterator<Item = (&'a String, &'a String)>>(
+    keys: T,
+) -> Result<(), KeyStoreError> {
+    memory_keystore().init_from_map(keys).map(|_| ())
+}
+
 pub fn file_keystore<P: AsRef<Path>>(
     path: P,
 ) -> Result<KeyStore<FileKeyStoreBackend>, KeyStoreError> {
     let path: &Path = path.as_ref();
     let initialize_db = !path.exists();
+    if initialize_db {
+        // created private before any key is written in it
+        write_private_file(path, "")?;
+    } else {
+        warn_if_not_private(path);
+    }
     let db = FileDatabase::<_, Yaml>::from_path(path, Default::default())?;
     if initialize_db {
         db.save()?;
     } else {
         db.load()?;
     }
-    Ok(KeyStore { keys: db })
+    Ok(KeyStore {
+        keys: FileKeyStoreBackend {
+            db,
+            path: path.to_path_buf(),
+            write_behind: false,
+            dirty: AtomicBool::new(false),
+            saves: AtomicU64::new(0),
+        },
+        nonces: None,
+        allowed_clock_skew: Duration::ZERO,
+    })
+}
+
+impl KeyStore<FileKeyStoreBackend> {
+    /// Only persist inserted keys on [KeyStore::flush], see [FileKeyStoreBackend]
+    pub fn with_write_behind(mut self) -> Self {
+        self.keys.write_behind = true;
+        self
+    }
+
+    /// Persist the keys inserted since the last save, if any
+    pub fn flush(&self) -> Result<(), KeyStoreError> {
+        self.keys.flush()
+    }
+
+    /// Number of times the keystore file has been written
+    pub fn save_count(&self) -> u64 {
+        self.keys.saves.load(Ordering::Relaxed)
+    }
 }
 
 impl<B: KeyStoreBackend> KeyStore<B> {
+    /// Reject the payloads whose nonce has already been seen by [KeyStore::decode_payload]
+    pub fn with_nonce_cache(mut self, nonces: Arc<NonceCache>) -> Self {
+        self.nonces = Some(nonces);
+        self
+    }
+
+    /// Accept the payloads up to `allowed_clock_skew` after their expiry, the nonce cache (if any)
+    /// must tolerate the same skew
+    pub fn with_allowed_clock_skew(mut self, allowed_clock_skew: Duration) -> Self {
+        self.allowed_clock_skew = allowed_clock_skew;
+        self
+    }
+
+    /// Register base64 encoded public keys by key id, eg: from a configuration file
     pub fn init_from_map<'a, T: IntoIterator<Item = (&'a String, &'a String)>>(
         self,
         map: T,
     ) -> Result<Self, KeyStoreError> {
+        let mut key_ids: HashMap<String, &String> = HashMap::new();
         map.into_iter().try_fold(
             self,
             |store, (key, base64_encoded_bytes): (&String, &String)| {
+                let normalized = key.chars().filter(|c| !c.is_whitespace()).collect();
+                if let Some(other) = key_ids.insert(normalized, key) {
+                    return Err(KeyStoreError::DuplicateKeyId(other.clone(), key.clone()));
+                }
                 store.register_key(
                     key,
                     data_encoding::BASE64.decode(base64_encoded_bytes.as_bytes())?,
@@ -213,7 +573,28 @@ impl<B: KeyStoreBackend> KeyStore<B> {
         key_id: S,
         key_bytes: Vec<u8>,
     ) -> Result<(), KeyStoreError> {
-        self.keys.insert_key(key_id.into(), key_bytes)
+        self.register_key_with_expiry(key_id, key_bytes, None)
+    }
+
+    /// Register a key the signatures of which are rejected from `expires_at`
+    pub fn register_key_with_expiry<S: Into<String>>(
+        &self,
+        key_id: S,
+        key_bytes: Vec<u8>,
+        expires_at: Option<SystemTime>,
+    ) -> Result<(), KeyStoreError> {
+        let key_id = key_id.into();
+        // eg: a private key, only noticed on the first signature check otherwise
+        if key_bytes.len() != signature::ED25519_PUBLIC_KEY_LEN {
+            return Err(KeyStoreError::InvalidKeyMaterial(key_id, key_bytes.len()));
+        }
+        self.keys.insert_key(
+            key_id,
+            StoredKey {
+                bytes: key_bytes,
+                expires_at,
+            },
+        )
     }
 
     pub fn remove_key(&self, key_id: &str) -> Result<Vec<u8>, KeyStoreError> {
@@ -224,36 +605,107 @@ impl<B: KeyStoreBackend> KeyStore<B> {
         self.keys.has_key(key_id, key_bytes)
     }
 
+    pub fn get_key(&self, key_id: &str) -> Result<Option<Vec<u8>>, KeyStoreError> {
+        Ok(self.keys.get_key(key_id)?.map(|key| key.bytes))
+    }
+
+    pub fn get_stored_key(&self, key_id: &str) -> Result<Option<StoredKey>, KeyStoreError> {
+        self.keys.get_key(key_id)
+    }
+
+    /// Check the payload has been signed by the stored key, ignoring its validity date
+    pub fn verify_signature(&self, payload: &SignedPayload) -> Result<(), KeyStoreError> {
+        self.keys.verify(
+            &payload.key_id,
+            &payload_bytes_to_sign(payload),
+            &payload.signature,
+        )
+    }
+
+    /// Check the validity date & the signature of the payload, returns the still encoded payload
+    pub fn verify_payload<'a>(
+        &self,
+        payload: &'a SignedPayload,
+    ) -> Result<&'a [u8], KeyStoreError> {
+        check_expiry_with_skew(payload, self.allowed_clock_skew)?;
+        self.verify_signature(payload)?;
+        Ok(payload.payload.as_slice())
+    }
+
+    /// Check & decode the payload, each payload can only be decoded once if the keystore has a
+    /// nonce cache
     pub fn decode_payload<P: prost::Message + Default>(
         &self,
         payload: &SignedPayload,
     ) -> Result<P, KeyStoreError> {
-        // validate time limit
-        let valid_until = SystemTime::UNIX_EPOCH + Duration::from_secs(payload.valid_until_secs);
-        if valid_until < SystemTime::now() {
-            Err(KeyStoreError::ExpiredSignature(
-                DateTime::<Local>::from(valid_until).to_string(),
-                DateTime::<Local>::from(SystemTime::now()).to_string(),
-            ))?;
+        let encoded = self.verify_payload(payload)?;
+        if let Some(nonces) = &self.nonces {
+            nonces.check(payload)?;
         }
+        P::decode(encoded)
+            .map_err(|decode_err| KeyStoreError::PayloadDecodeError(decode_err.to_string()))
+    }
 
-        // check signature
-        self.keys.verify(
-            &payload.key_id,
-            &payload_bytes_to_sign(&payload),
-            &payload.signature,
+    /// Check & decode a payload signed by a delegated key: the delegation certificate must be
+    /// signed by a key of this keystore & still be valid. The certificate can be used for several
+    /// payloads, each payload can only be decoded once if the keystore has a nonce cache.
+    ///
+    /// The executors the payload is sent to are not checked against the allowed predicate of the
+    /// returned delegation.
+    pub fn decode_delegated_payload<P: prost::Message + Default>(
+        &self,
+        certificate: &SignedPayload,
+        payload: &SignedPayload,
+    ) -> Result<(P, Delegation), KeyStoreError> {
+        // valid for as long as its signer decided
+        check_expiry_at(
+            certificate,
+            self.allowed_clock_skew,
+            None,
+            SystemTime::now(),
         )?;
-
-        // decode payload
-        P::decode(payload.payload.as_slice())
-            .map_err(|decode_err| KeyStoreError::PayloadDecodeError(decode_err.to_string()))
+        self.verify_signature(certificate)?;
+        let delegation = Delegation::decode(certificate.payload.as_slice())
+            .map_err(|decode_err| KeyStoreError::PayloadDecodeError(decode_err.to_string()))?;
+        if delegation.purpose != DELEGATION_PURPOSE {
+            return Err(KeyStoreError::InvalidDelegation(format!(
+                "the payload signed by {} is not a delegation",
+                certificate.key_id
+            )));
+        }
+        let delegate = delegation
+            .delegate
+            .as_ref()
+            .ok_or_else(|| KeyStoreError::InvalidDelegation("missing delegate key".to_string()))?;
+        if delegate.key_id != payload.key_id {
+            return Err(KeyStoreError::InvalidDelegation(format!(
+                "{} delegated to {}, not to {}",
+                certificate.key_id, delegate.key_id, payload.key_id
+            )));
+        }
+        let delegate_store = KeyStore {
+            keys: MemoryKeyStoreBackend::default(),
+            nonces: self.nonces.clone(),
+            allowed_clock_skew: self.allowed_clock_skew,
+        };
+        delegate_store.register_key_with_expiry(
+            delegate.key_id.as_str(),
+            delegate.key_bytes.clone(),
+            expires_at_from_secs(delegate.expires_at_secs),
+        )?;
+        let decoded = delegate_store.decode_payload(payload)?;
+        Ok((decoded, delegation))
     }
 
     pub fn list_all(&self) -> Result<BTreeMap<String, String>, KeyStoreError> {
         self.keys.list_all().map(|keys| {
             keys.into_iter()
-                .map(|(id, bytes)| (id, data_encoding::BASE64.encode(&bytes)))
+                .map(|(id, key)| (id, data_encoding::BASE64.encode(&key.bytes)))
                 .collect()
         })
     }
+
+    pub fn list_all_with_expiry(&self) -> Result<BTreeMap<String, StoredKey>, KeyStoreError> {
+        self.keys.list_all().map(|keys| keys.into_iter().collect())
+    }
 }
@@ -1,5 +1,7 @@
 pub mod keygen;
 pub mod keystore;
+pub mod passphrase;
+pub mod sealing;
 pub mod signed_payload;
 
 #[cfg(test)]
@@ -12,6 +14,7 @@ mod test {
     use ring::signature;
     use ring::signature::KeyPair;
     use std::fs::{read_to_string, File};
+    use std::os::unix::fs::MetadataExt;
     use std::path::PathBuf;
     use tokio::time::Duration;
 
@@ -66,6 +69,8 @@ mod test {
             let ks = file_keystore(&file).unwrap();
             assert!(file.exists());
             ks.register_key("abcd", public_key.to_vec()).unwrap();
+            // only readable by its owner
+            assert_eq!(0o600, file.metadata().unwrap().mode() & 0o777);
 
             let decoded = ks.decode_payload::<TestPayload>(&signed_payload).unwrap();
             assert_eq!(&decoded.some_stuff, "foo // bar");
@@ -79,4 +84,654 @@ mod test {
             assert_eq!(&decoded.some_stuff, "foo // bar");
         }
     }
+    #[test]
+    fn write_behind_keystore() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = (0..5000)
+            .map(|i: u32| (format!("executor-{}", i), i.to_be_bytes().repeat(8)))
+            .collect::<Vec<_>>();
+
+        // fleet wide registration: one save per key (sampled, rewriting the whole file each
+        // time is far too slow)...
+        let sample = 200;
+        let file = PathBuilder::from_path(&dir).push("immediate.yaml").build();
+        let ks = file_keystore(&file).unwrap();
+        for (key_id, key) in &keys[..sample] {
+            ks.register_key(key_id, key.clone()).unwrap();
+        }
+        assert_eq!(sample as u64, ks.save_count());
+
+        // ...or one save per flush
+        let file = PathBuilder::from_path(&dir)
+            .push("write_behind.yaml")
+            .build();
+        {
+            let ks = file_keystore(&file).unwrap().with_write_behind();
+            for (i, (key_id, key)) in keys.iter().enumerate() {
+                ks.register_key(key_id, key.clone()).unwrap();
+                if i % 1000 == 999 {
+                    ks.flush().unwrap();
+                }
+            }
+            assert_eq!(5, ks.save_count());
+            // nothing left to save
+            ks.flush().unwrap();
+            assert_eq!(5, ks.save_count());
+
+            // removals are saved right away
+            ks.register_key("removed", vec![0; 32]).unwrap();
+            ks.remove_key("executor-0").unwrap();
+            assert_eq!(6, ks.save_count());
+            let saved = file_keystore(&file).unwrap();
+            assert_eq!(None, saved.get_key("executor-0").unwrap());
+            assert!(saved.get_key("removed").unwrap().is_some());
+
+            ks.register_key("unsaved", vec![0; 32]).unwrap();
+        }
+        // pending keys are saved when the keystore is dropped
+        let saved = file_keystore(&file).unwrap();
+        assert!(saved.get_key("unsaved").unwrap().is_some());
+        assert_eq!(keys.len() + 1, saved.list_all().unwrap().len());
+    }
+
+    #[test]
+    fn verify_raw_payload() {
+        use crate::crypto::keystore::KeyStoreError;
+        use crate::crypto::signed_payload::{sign_raw_payload, to_sign_from_exploded_payload};
+
+        let (private_key, public_key) = generate_ed25519_key_pair().unwrap();
+        let (_, other_public_key) = generate_ed25519_key_pair().unwrap();
+        let key_store = memory_keystore();
+        key_store.register_key("abcd", public_key.to_vec()).unwrap();
+        let key = ("abcd", private_key.as_slice()).into();
+
+        let signed = sign_raw_payload(b"raw bytes".to_vec(), &key, Duration::from_secs(5)).unwrap();
+        assert_eq!(b"raw bytes", key_store.verify_payload(&signed).unwrap());
+
+        // tampered payload
+        let mut tampered = signed.clone();
+        tampered.payload[0] ^= 1;
+        assert!(matches!(
+            key_store.verify_payload(&tampered),
+            Err(KeyStoreError::WrongSignature(_))
+        ));
+
+        // wrong key
+        let other_store = memory_keystore();
+        other_store
+            .register_key("abcd", other_public_key.to_vec())
+            .unwrap();
+        assert!(matches!(
+            other_store.verify_payload(&signed),
+            Err(KeyStoreError::WrongSignature(_))
+        ));
+        let unknown_key_store = memory_keystore();
+        assert!(matches!(
+            unknown_key_store.verify_payload(&signed),
+            Err(KeyStoreError::KeyNotFound(_))
+        ));
+
+        // expired, yet properly signed, payload
+        let mut expired = signed;
+        expired.valid_until_secs -= 3600;
+        expired.signature = signature::Ed25519KeyPair::from_pkcs8(&private_key)
+            .unwrap()
+            .sign(&to_sign_from_exploded_payload(
+                &expired.payload,
+                expired.nonce,
+                expired.valid_until_secs,
+            ))
+            .as_ref()
+            .to_vec();
+        key_store.verify_signature(&expired).unwrap();
+        assert!(matches!(
+            key_store.verify_payload(&expired),
+            Err(KeyStoreError::ExpiredSignature(_, _))
+        ));
+    }
+
+    #[test]
+    fn replayed_payload() {
+        use crate::crypto::keystore::{KeyStoreError, NonceCache, NONCE_PURGE_INTERVAL_SECS};
+        use std::sync::Arc;
+
+        let (private_key, public_key) = generate_ed25519_key_pair().unwrap();
+        let nonces = Arc::new(NonceCache::default());
+        let key_store = memory_keystore().with_nonce_cache(nonces.clone());
+        key_store.register_key("abcd", public_key.to_vec()).unwrap();
+        // another keystore sharing the cache, eg: admin keys
+        let other_store = memory_keystore().with_nonce_cache(nonces.clone());
+        other_store
+            .register_key("abcd", public_key.to_vec())
+            .unwrap();
+
+        let key = ("abcd", private_key.as_slice()).into();
+        let signed_payload = encode_and_sign(
+            TestPayload {
+                some_stuff: "foo".into(),
+            },
+            &key,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+
+        key_store
+            .decode_payload::<TestPayload>(&signed_payload)
+            .unwrap();
+        for store in [&key_store, &other_store] {
+            assert!(matches!(
+                store.decode_payload::<TestPayload>(&signed_payload),
+                Err(KeyStoreError::ReplayDetected(key_id, nonce))
+                    if key_id == "abcd" && nonce == signed_payload.nonce
+            ));
+        }
+        // the same content signed again is another payload
+        let signed_again = encode_and_sign(
+            TestPayload {
+                some_stuff: "foo".into(),
+            },
+            &key,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        key_store
+            .decode_payload::<TestPayload>(&signed_again)
+            .unwrap();
+        assert_eq!(2, nonces.len());
+
+        // expired payloads are rejected by the validity date check: their nonces are purged
+        let purge_at = signed_again.valid_until_secs + NONCE_PURGE_INTERVAL_SECS;
+        let mut later = signed_again;
+        later.nonce = later.nonce.wrapping_add(1);
+        later.valid_until_secs = purge_at + 5;
+        nonces.check_at(&later, purge_at).unwrap();
+        assert_eq!(1, nonces.len());
+        assert!(matches!(
+            nonces.check_at(&later, purge_at),
+            Err(KeyStoreError::ReplayDetected(_, _))
+        ));
+    }
+
+    #[test]
+    fn clock_skew() {
+        use crate::config::MAX_SIGNATURE_VALIDITY_SECS;
+        use crate::crypto::keystore::{KeyStoreError, NonceCache, NONCE_PURGE_INTERVAL_SECS};
+        use crate::crypto::signed_payload::to_sign_from_exploded_payload;
+        use grpc_service::payload::SignedPayload;
+        use std::sync::Arc;
+        use std::time::SystemTime;
+
+        let (private_key, public_key) = generate_ed25519_key_pair().unwrap();
+        let skew = Duration::from_secs(NONCE_PURGE_INTERVAL_SECS);
+        let nonces = Arc::new(NonceCache::with_allowed_clock_skew(skew));
+        let tolerant_store = memory_keystore()
+            .with_nonce_cache(nonces.clone())
+            .with_allowed_clock_skew(skew);
+        tolerant_store
+            .register_key("abcd", public_key.to_vec())
+            .unwrap();
+        let strict_store = memory_keystore();
+        strict_store
+            .register_key("abcd", public_key.to_vec())
+            .unwrap();
+
+        // signed with a 30s validity on a host whose clock is `offset_secs` ahead of ours
+        let now_secs = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let key_pair = signature::Ed25519KeyPair::from_pkcs8(&private_key).unwrap();
+        let sign_with_offset = |offset_secs: i64| {
+            let valid_until_secs = (now_secs as i64 + offset_secs + 30) as u64;
+            let nonce = rand::random();
+            SignedPayload {
+                payload: b"payload".to_vec(),
+                nonce,
+                valid_until_secs,
+                signature: key_pair
+                    .sign(&to_sign_from_exploded_payload(
+                        b"payload",
+                        nonce,
+                        valid_until_secs,
+                    ))
+                    .as_ref()
+                    .to_vec(),
+                key_id: "abcd".to_string(),
+            }
+        };
+
+        // clock ahead within the tolerance: valid up to the longest validity plus the tolerance
+        let ahead = sign_with_offset(MAX_SIGNATURE_VALIDITY_SECS as i64);
+        assert!(matches!(
+            strict_store.verify_payload(&ahead),
+            Err(KeyStoreError::SignatureTooFarAhead(_, _))
+        ));
+        tolerant_store.verify_payload(&ahead).unwrap();
+
+        // clock ahead outside the tolerance
+        assert!(matches!(
+            tolerant_store
+                .verify_payload(&sign_with_offset(MAX_SIGNATURE_VALIDITY_SECS as i64 + 60)),
+            Err(KeyStoreError::SignatureTooFarAhead(_, _))
+        ));
+
+        // clock behind within the tolerance
+        let behind = sign_with_offset(-80);
+        assert!(matches!(
+            strict_store.verify_payload(&behind),
+            Err(KeyStoreError::ExpiredSignature(_, _))
+        ));
+        tolerant_store.verify_payload(&behind).unwrap();
+        // accepted after its expiry: its nonce is not purged meanwhile
+        let received_at = behind.valid_until_secs - 40;
+        let purge_at = behind.valid_until_secs + 20;
+        nonces.check_at(&behind, received_at).unwrap();
+        assert!(matches!(
+            nonces.check_at(&behind, purge_at),
+            Err(KeyStoreError::ReplayDetected(_, _))
+        ));
+        let strict_nonces = NonceCache::default();
+        strict_nonces.check_at(&behind, received_at).unwrap();
+        strict_nonces.check_at(&behind, purge_at).unwrap();
+
+        // clock behind outside the tolerance
+        assert!(matches!(
+            tolerant_store.verify_payload(&sign_with_offset(-150)),
+            Err(KeyStoreError::ExpiredSignature(_, _))
+        ));
+    }
+
+    #[test]
+    fn wrong_clock() {
+        use crate::crypto::keystore::{check_expiry_at, KeyStoreError};
+        use crate::crypto::signed_payload::{clock_skew_secs, sign_raw_payload};
+        use crate::tonic::Status;
+        use std::time::SystemTime;
+
+        let (private_key, _) = generate_ed25519_key_pair().unwrap();
+        let key = ("abcd", private_key.as_slice()).into();
+        let signed =
+            sign_raw_payload(b"raw bytes".to_vec(), &key, Duration::from_secs(60)).unwrap();
+
+        // checked on a host whose clock is one hour ahead
+        let now = SystemTime::now() + Duration::from_secs(3600);
+        check_expiry_at(&signed, Duration::ZERO, None, SystemTime::now()).unwrap();
+        let error = check_expiry_at(&signed, Duration::ZERO, None, now).unwrap_err();
+        let (valid_until, host_time) = match &error {
+            KeyStoreError::ExpiredSignature(valid_until, host_time) => {
+                (valid_until.clone(), host_time.clone())
+            }
+            other => panic!("Unexpected error {}", other),
+        };
+        assert_eq!(
+            signed.valid_until_secs as i64,
+            chrono::DateTime::parse_from_rfc3339(&valid_until)
+                .unwrap()
+                .timestamp()
+        );
+        let message = Status::from(error).message().to_string();
+        assert!(message.contains(&valid_until), "{}", message);
+        assert!(message.contains(&host_time), "{}", message);
+
+        // `date` header of a peer whose clock is 90s behind
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777 + 90);
+        assert_eq!(
+            Some(90),
+            clock_skew_secs("Sun, 06 Nov 1994 08:49:37 GMT", now)
+        );
+        assert_eq!(None, clock_skew_secs("yesterday", now));
+    }
+
+    #[test]
+    fn key_expiration() {
+        use crate::crypto::keystore::{KeyStoreError, StoredKey};
+        use std::time::SystemTime;
+
+        let (private_key, public_key) = generate_ed25519_key_pair().unwrap();
+        let key = ("contractor", private_key.as_slice()).into();
+        let signed_payload = encode_and_sign(
+            TestPayload {
+                some_stuff: "foo".into(),
+            },
+            &key,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = PathBuilder::from_path(&dir).push("keystore.yaml").build();
+        // keystore written before keys could expire
+        std::fs::write(
+            &file,
+            serde_yaml::to_string(&std::collections::HashMap::from([(
+                "contractor",
+                public_key.to_vec(),
+            )]))
+            .unwrap(),
+        )
+        .unwrap();
+        let key_store = file_keystore(&file).unwrap();
+        key_store
+            .decode_payload::<TestPayload>(&signed_payload)
+            .unwrap();
+
+        let expires_at = SystemTime::now() + Duration::from_secs(3600);
+        key_store
+            .register_key_with_expiry("contractor", public_key.to_vec(), Some(expires_at))
+            .unwrap();
+        key_store.verify_payload(&signed_payload).unwrap();
+        assert!(key_store
+            .has_key("contractor", public_key.as_slice())
+            .unwrap());
+
+        // the expiration date is kept across restarts
+        let expired_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        key_store
+            .register_key_with_expiry("contractor", public_key.to_vec(), Some(expired_at))
+            .unwrap();
+        drop(key_store);
+        let key_store = file_keystore(&file).unwrap();
+        assert_eq!(
+            Some(StoredKey {
+                bytes: public_key.to_vec(),
+                expires_at: Some(expired_at),
+            }),
+            key_store.get_stored_key("contractor").unwrap()
+        );
+        assert!(matches!(
+            key_store.verify_payload(&signed_payload),
+            Err(KeyStoreError::KeyExpired(key_id, _)) if key_id == "contractor"
+        ));
+
+        // back to a key that never expires, stored as before
+        key_store
+            .register_key("contractor", public_key.to_vec())
+            .unwrap();
+        key_store.verify_payload(&signed_payload).unwrap();
+        assert!(!read_to_string(&file).unwrap().contains("expires_at"));
+    }
+
+    #[test]
+    fn remove_then_register_again() {
+        use crate::crypto::keystore::KeyStoreError;
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = PathBuilder::from_path(&dir).push("keystore.yaml").build();
+        let (_, public_key) = generate_ed25519_key_pair().unwrap();
+        let key_store = file_keystore(&file).unwrap();
+        key_store.register_key("exec", public_key.to_vec()).unwrap();
+
+        assert_eq!(public_key.to_vec(), key_store.remove_key("exec").unwrap());
+        assert!(!key_store.has_key("exec", public_key.as_slice()).unwrap());
+        assert!(matches!(
+            key_store.remove_key("exec"),
+            Err(KeyStoreError::KeyNotFound(_))
+        ));
+        // removals are saved right away
+        assert!(!file_keystore(&file)
+            .unwrap()
+            .has_key("exec", public_key.as_slice())
+            .unwrap());
+
+        // nothing prevents the key from being registered again
+        key_store.register_key("exec", public_key.to_vec()).unwrap();
+        assert!(key_store.has_key("exec", public_key.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn fingerprints() {
+        use crate::config::ED25519Key;
+        use crate::crypto::keygen::{
+            base64_fingerprint, fingerprint, generate_ed25519_key_pair_from_seed, public_key_of,
+        };
+
+        // RFC 8032 test vector 1
+        let public_key = data_encoding::HEXLOWER
+            .decode(b"d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a")
+            .unwrap();
+        let expected = "SHA256:If4x36FUomFia/hUBG/SJxt77UtqvkWqWId+9H+XIbk";
+        assert_eq!(expected, fingerprint(&public_key));
+        assert_eq!(
+            expected,
+            base64_fingerprint("11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=").unwrap()
+        );
+        assert!(base64_fingerprint("not base64!").is_err());
+
+        // configurations written before the public key was saved along the private one
+        let (private_key, generated_public_key) =
+            generate_ed25519_key_pair_from_seed(&[7; 32]).unwrap();
+        let mut key = ED25519Key {
+            id: "ops".to_string(),
+            pkcs8: data_encoding::BASE64.encode(&private_key),
+            public_key: None,
+            pkcs8_encrypted: None,
+        };
+        assert_eq!(generated_public_key, public_key_of(&key).unwrap());
+        key.public_key = Some(data_encoding::BASE64.encode(&public_key));
+        assert_eq!(public_key, public_key_of(&key).unwrap());
+
+        key.public_key = None;
+        key.pkcs8 = data_encoding::BASE64.encode(&public_key);
+        assert!(public_key_of(&key)
+            .unwrap_err()
+            .to_string()
+            .starts_with("Invalid private key of ops"));
+    }
+
+    #[test]
+    fn invalid_key_material() {
+        use crate::crypto::keystore::{validate_keys, KeyStoreError};
+        use std::collections::BTreeMap;
+
+        fn keys<K: AsRef<[u8]>>(keys: &[(&str, K)]) -> BTreeMap<String, String> {
+            keys.iter()
+                .map(|(key_id, key)| {
+                    (
+                        key_id.to_string(),
+                        data_encoding::BASE64.encode(key.as_ref()),
+                    )
+                })
+                .collect()
+        }
+
+        let (private_key, public_key) = generate_ed25519_key_pair().unwrap();
+        validate_keys(&keys(&[("ops", &public_key)])).unwrap();
+
+        // the private key pasted instead of the public one
+        assert!(matches!(
+            validate_keys(&keys(&[("ops", &public_key), ("admin", &private_key)])),
+            Err(KeyStoreError::InvalidKeyMaterial(key_id, len))
+                if key_id == "admin" && len == private_key.len()
+        ));
+        // truncated, or too long
+        let invalid_keys = vec![
+            public_key[..31].to_vec(),
+            [public_key.clone(), vec![0]].concat(),
+            vec![],
+        ];
+        for invalid in &invalid_keys {
+            assert!(matches!(
+                validate_keys(&keys(&[("ops", invalid)])),
+                Err(KeyStoreError::InvalidKeyMaterial(key_id, len))
+                    if key_id == "ops" && len == invalid.len()
+            ));
+        }
+        // not base64 at all
+        let mut not_base64 = BTreeMap::new();
+        not_base64.insert("ops".to_string(), "not base64!".to_string());
+        assert!(matches!(
+            validate_keys(&not_base64),
+            Err(KeyStoreError::KeyEncodingError(_))
+        ));
+
+        // the same key id twice, once with a trailing space or a tab
+        for duplicate in ["ops ", "o\tps"] {
+            assert!(matches!(
+                validate_keys(&keys(&[("ops", &public_key), (duplicate, &public_key)])),
+                Err(KeyStoreError::DuplicateKeyId(..))
+            ));
+        }
+        validate_keys(&keys(&[("ops", &public_key), ("ops-2", &public_key)])).unwrap();
+
+        // keys registered at runtime are checked as well
+        let key_store = memory_keystore();
+        assert!(matches!(
+            key_store.register_key("exec", private_key.clone()),
+            Err(KeyStoreError::InvalidKeyMaterial(key_id, _)) if key_id == "exec"
+        ));
+        assert!(!key_store.has_key("exec", &private_key).unwrap());
+        assert_eq!(
+            "Key exec is 5 bytes long, expected a 32 bytes ED25519 public key",
+            key_store
+                .register_key("exec", vec![0; 5])
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn seeded_key_pair() {
+        use crate::crypto::keygen::{generate_ed25519_key_pair_from_seed, public_key_from_pkcs8};
+        use std::convert::TryInto;
+
+        // RFC 8032 test vector 1
+        let seed: [u8; 32] = data_encoding::HEXLOWER
+            .decode(b"9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let (private_key, public_key) = generate_ed25519_key_pair_from_seed(&seed).unwrap();
+        assert_eq!(
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            data_encoding::HEXLOWER.encode(&public_key)
+        );
+        assert_eq!(public_key, public_key_from_pkcs8(&private_key).unwrap());
+        assert_eq!(
+            (private_key, public_key),
+            generate_ed25519_key_pair_from_seed(&seed).unwrap()
+        );
+    }
+
+    #[test]
+    fn delegated_payload() {
+        use crate::config::ED25519Key;
+        use crate::crypto::keystore::{KeyStoreError, NonceCache};
+        use crate::crypto::signed_payload::{delegate, to_sign_from_exploded_payload};
+        use grpc_service::grpc_protocol::execute_command::Program;
+        use grpc_service::grpc_protocol::launch_task_request_payload::Task;
+        use grpc_service::grpc_protocol::{ExecuteCommand, LaunchTaskRequestPayload};
+        use std::sync::Arc;
+
+        let (ops_private, ops_public) = generate_ed25519_key_pair().unwrap();
+        let (ci_private, ci_public) = generate_ed25519_key_pair().unwrap();
+        let ops: ED25519Key = ("ops", ops_private.as_slice()).into();
+        let ci: ED25519Key = ("ci", ci_private.as_slice()).into();
+        let key_store = memory_keystore().with_nonce_cache(Arc::new(NonceCache::default()));
+        key_store.register_key("ops", ops_public.to_vec()).unwrap();
+        let sign = |key: &ED25519Key| {
+            encode_and_sign(
+                TestPayload {
+                    some_stuff: "deploy".into(),
+                },
+                key,
+                Duration::from_secs(5),
+            )
+            .unwrap()
+        };
+
+        let certificate = delegate(
+            &ops,
+            "ci",
+            ci_public.to_vec(),
+            "env:staging",
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+        let signed = sign(&ci);
+        let (decoded, delegation) = key_store
+            .decode_delegated_payload::<TestPayload>(&certificate, &signed)
+            .unwrap();
+        assert_eq!("deploy", decoded.some_stuff);
+        assert_eq!("env:staging", delegation.allowed_predicate);
+        assert_eq!(
+            certificate.valid_until_secs,
+            delegation.delegate.unwrap().expires_at_secs
+        );
+        // the certificate serves several payloads, each payload only once
+        key_store
+            .decode_delegated_payload::<TestPayload>(&certificate, &sign(&ci))
+            .unwrap();
+        assert!(matches!(
+            key_store.decode_delegated_payload::<TestPayload>(&certificate, &signed),
+            Err(KeyStoreError::ReplayDetected(key_id, _)) if key_id == "ci"
+        ));
+        // the delegated key is not trusted on its own
+        assert!(matches!(
+            key_store.decode_payload::<TestPayload>(&sign(&ci)),
+            Err(KeyStoreError::KeyNotFound(key_id)) if key_id == "ci"
+        ));
+
+        // payload signed by another key than the delegated one
+        assert!(matches!(
+            key_store.decode_delegated_payload::<TestPayload>(&certificate, &sign(&ops)),
+            Err(KeyStoreError::InvalidDelegation(_))
+        ));
+        // payload claiming to be signed by the delegated key
+        let mut forged = sign(&ops);
+        forged.key_id = "ci".to_string();
+        assert!(matches!(
+            key_store.decode_delegated_payload::<TestPayload>(&certificate, &forged),
+            Err(KeyStoreError::WrongSignature(key_id)) if key_id == "ci"
+        ));
+
+        // delegation signed by an unknown key
+        let (rogue_private, _) = generate_ed25519_key_pair().unwrap();
+        let rogue_certificate = delegate(
+            &("rogue", rogue_private.as_slice()).into(),
+            "ci",
+            ci_public.to_vec(),
+            "*",
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+        assert!(matches!(
+            key_store.decode_delegated_payload::<TestPayload>(&rogue_certificate, &sign(&ci)),
+            Err(KeyStoreError::KeyNotFound(key_id)) if key_id == "rogue"
+        ));
+
+        // expired, yet properly signed, delegation
+        let mut expired = certificate;
+        expired.valid_until_secs -= 7200;
+        expired.signature = signature::Ed25519KeyPair::from_pkcs8(&ops_private)
+            .unwrap()
+            .sign(&to_sign_from_exploded_payload(
+                &expired.payload,
+                expired.nonce,
+                expired.valid_until_secs,
+            ))
+            .as_ref()
+            .to_vec();
+        key_store.verify_signature(&expired).unwrap();
+        assert!(matches!(
+            key_store.decode_delegated_payload::<TestPayload>(&expired, &sign(&ci)),
+            Err(KeyStoreError::ExpiredSignature(_, _))
+        ));
+
+        // any other payload signed by the delegator is not a delegation
+        let task = encode_and_sign(
+            LaunchTaskRequestPayload {
+                task: Some(Task::ExecuteCommand(ExecuteCommand {
+                    program: Some(Program::Command("ci".to_string())),
+                    ..Default::default()
+                })),
+            },
+            &ops,
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+        assert!(matches!(
+            key_store.decode_delegated_payload::<TestPayload>(&task, &sign(&ci)),
+            Err(KeyStoreError::InvalidDelegation(_))
+        ));
+    }
 }
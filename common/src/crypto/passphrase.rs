@@ -0,0 +1,237 @@
+//! Passphrase protection of the commander private key, for configuration files left on shared
+//! hosts.
+//!
+//! The pkcs8 document is encrypted with ChaCha20-Poly1305 using a key derived (PBKDF2-HMAC-SHA256)
+//! from the passphrase and a random salt stored along the encrypted key.
+use crate::config::ED25519Key;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use thiserror::Error;
+
+/// Environment variable holding the passphrase of the key, asked on the terminal otherwise
+pub const KEY_PASSPHRASE_ENV: &str = "FUNTONIC_KEY_PASSPHRASE";
+pub const DEFAULT_PBKDF2_ITERATIONS: u32 = 600_000;
+const SALT_LEN: usize = 32;
+const KEY_LEN: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum PassphraseError {
+    #[error("Unable to decrypt key {0}: wrong passphrase or corrupted key")]
+    WrongPassphrase(String),
+    #[error("Invalid encrypted key {key_id}: {reason}")]
+    InvalidEncryptedKey { key_id: String, reason: String },
+    #[error("Key {0} is not encrypted")]
+    NotEncrypted(String),
+    #[error("Key {0} is already encrypted")]
+    AlreadyEncrypted(String),
+    #[error("No passphrase for key {key_id}: {reason}")]
+    NoPassphrase { key_id: String, reason: String },
+    #[error("Cryptographic error while encrypting key")]
+    Crypto,
+}
+
+impl From<ring::error::Unspecified> for PassphraseError {
+    fn from(_: ring::error::Unspecified) -> Self {
+        PassphraseError::Crypto
+    }
+}
+
+/// `pkcs8_encrypted` of an [`ED25519Key`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EncryptedPkcs8 {
+    /// PBKDF2 iterations deriving the encryption key
+    pub iterations: u32,
+    /// base64 encoded salt used with the passphrase to derive the encryption key
+    pub salt: String,
+    /// base64 encoded nonce followed by the encrypted pkcs8 document
+    pub encrypted: String,
+}
+
+impl EncryptedPkcs8 {
+    /// Check the encoding of the encrypted key, without the passphrase
+    pub fn check(&self, key_id: &str) -> Result<(), PassphraseError> {
+        self.decode(key_id).map(|_| ())
+    }
+
+    /// Iterations, salt & nonce followed by the ciphertext
+    fn decode(&self, key_id: &str) -> Result<(NonZeroU32, Vec<u8>, Vec<u8>), PassphraseError> {
+        let invalid = |reason: &str| PassphraseError::InvalidEncryptedKey {
+            key_id: key_id.to_string(),
+            reason: reason.to_string(),
+        };
+        let iterations =
+            NonZeroU32::new(self.iterations).ok_or_else(|| invalid("iterations must not be 0"))?;
+        let salt = data_encoding::BASE64
+            .decode(self.salt.as_bytes())
+            .map_err(|_| invalid("salt is not valid base64"))?;
+        let encrypted = data_encoding::BASE64
+            .decode(self.encrypted.as_bytes())
+            .map_err(|_| invalid("encrypted is not valid base64"))?;
+        if encrypted.len() < NONCE_LEN {
+            return Err(invalid("encrypted is too short"));
+        }
+        Ok((iterations, salt, encrypted))
+    }
+}
+
+fn encryption_key(passphrase: &str, salt: &[u8], iterations: NonZeroU32) -> LessSafeKey {
+    let mut key = [0u8; KEY_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    LessSafeKey::new(
+        UnboundKey::new(&CHACHA20_POLY1305, &key)
+            // the derived key is the ChaCha20 key length, this cannot fail
+            .expect("ChaCha20 key length is valid"),
+    )
+}
+
+/// Encrypt the private key with the passphrase, the returned key has no plain pkcs8 anymore
+pub fn encrypt_key(key: &ED25519Key, passphrase: &str) -> Result<ED25519Key, PassphraseError> {
+    encrypt_key_with_iterations(key, passphrase, DEFAULT_PBKDF2_ITERATIONS)
+}
+
+/// [`encrypt_key`] with a given PBKDF2 cost
+pub fn encrypt_key_with_iterations(
+    key: &ED25519Key,
+    passphrase: &str,
+    iterations: u32,
+) -> Result<ED25519Key, PassphraseError> {
+    if key.is_encrypted() {
+        return Err(PassphraseError::AlreadyEncrypted(key.id.clone()));
+    }
+    let invalid = |reason: String| PassphraseError::InvalidEncryptedKey {
+        key_id: key.id.clone(),
+        reason,
+    };
+    let non_zero_iterations =
+        NonZeroU32::new(iterations).ok_or_else(|| invalid("iterations must not be 0".into()))?;
+    let rng = SystemRandom::new();
+    let mut salt = [0u8; SALT_LEN];
+    rng.fill(&mut salt)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut nonce)?;
+
+    let mut in_out = key
+        .to_bytes()
+        .map_err(|e| invalid(format!("pkcs8 is not valid base64: {}", e)))?;
+    encryption_key(passphrase, &salt, non_zero_iterations).seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(key.id.as_bytes()),
+        &mut in_out,
+    )?;
+
+    let mut encrypted = nonce.to_vec();
+    encrypted.extend_from_slice(&in_out);
+    Ok(ED25519Key {
+        id: key.id.clone(),
+        pkcs8: String::new(),
+        public_key: key.public_key.clone(),
+        pkcs8_encrypted: Some(EncryptedPkcs8 {
+            iterations,
+            salt: data_encoding::BASE64.encode(&salt),
+            encrypted: data_encoding::BASE64.encode(&encrypted),
+        }),
+    })
+}
+
+/// Decrypt a private key encrypted by [`encrypt_key`] with the same passphrase
+pub fn decrypt_key(key: &ED25519Key, passphrase: &str) -> Result<ED25519Key, PassphraseError> {
+    let encrypted = key
+        .pkcs8_encrypted
+        .as_ref()
+        .ok_or_else(|| PassphraseError::NotEncrypted(key.id.clone()))?;
+    let (iterations, salt, encrypted) = encrypted.decode(&key.id)?;
+    let (nonce, ciphertext) = encrypted.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce)?;
+
+    let mut in_out = ciphertext.to_vec();
+    let pkcs8 = encryption_key(passphrase, &salt, iterations)
+        .open_in_place(nonce, Aad::from(key.id.as_bytes()), &mut in_out)
+        .map_err(|_| PassphraseError::WrongPassphrase(key.id.clone()))?;
+
+    Ok(ED25519Key {
+        id: key.id.clone(),
+        pkcs8: data_encoding::BASE64.encode(pkcs8),
+        public_key: key.public_key.clone(),
+        pkcs8_encrypted: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decrypt_key, encrypt_key_with_iterations, PassphraseError};
+    use crate::crypto::keygen::generate_base64_encoded_keys;
+
+    // fast enough for the tests, the default cost is meant to slow down brute force attacks
+    const ITERATIONS: u32 = 1000;
+
+    #[test]
+    fn encrypt_decrypt_round_trip() {
+        let (key, _) = generate_base64_encoded_keys("ops");
+        let encrypted = encrypt_key_with_iterations(&key, "correct horse", ITERATIONS).unwrap();
+        assert!(encrypted.is_encrypted());
+        assert!(encrypted.pkcs8.is_empty());
+        assert_eq!(key.public_key, encrypted.public_key);
+
+        let decrypted = decrypt_key(&encrypted, "correct horse").unwrap();
+        assert!(!decrypted.is_encrypted());
+        assert_eq!(key.pkcs8, decrypted.pkcs8);
+        assert_eq!(key.id, decrypted.id);
+
+        // the key is resolved only when encrypted
+        let resolved = encrypted
+            .clone()
+            .resolve(|| Ok("correct horse".to_string()))
+            .unwrap();
+        assert_eq!(key.pkcs8, resolved.pkcs8);
+        let resolved = key
+            .clone()
+            .resolve(|| panic!("the passphrase of a plain key is not needed"))
+            .unwrap();
+        assert_eq!(key.pkcs8, resolved.pkcs8);
+
+        assert!(matches!(
+            encrypt_key_with_iterations(&encrypted, "correct horse", ITERATIONS),
+            Err(PassphraseError::AlreadyEncrypted(_))
+        ));
+        assert!(matches!(
+            decrypt_key(&key, "correct horse"),
+            Err(PassphraseError::NotEncrypted(_))
+        ));
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let (key, _) = generate_base64_encoded_keys("ops");
+        let encrypted = encrypt_key_with_iterations(&key, "correct horse", ITERATIONS).unwrap();
+        let error = decrypt_key(&encrypted, "battery staple").unwrap_err();
+        assert!(matches!(error, PassphraseError::WrongPassphrase(_)));
+        assert_eq!(
+            "Unable to decrypt key ops: wrong passphrase or corrupted key",
+            error.to_string()
+        );
+
+        // the key id is authenticated
+        let mut renamed = encrypted.clone();
+        renamed.id = "admin".into();
+        assert!(matches!(
+            decrypt_key(&renamed, "correct horse"),
+            Err(PassphraseError::WrongPassphrase(_))
+        ));
+
+        let mut truncated = encrypted;
+        truncated.pkcs8_encrypted.as_mut().unwrap().encrypted = "AAAA".into();
+        assert!(matches!(
+            decrypt_key(&truncated, "correct horse"),
+            Err(PassphraseError::InvalidEncryptedKey { .. })
+        ));
+    }
+}
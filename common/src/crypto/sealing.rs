@@ -0,0 +1,238 @@
+//! At-rest protection of the executor signing key.
+//!
+//! A sealed key is encrypted with AES-256-GCM using a key derived (HKDF-SHA256) from the host
+//! machine-id and a random salt stored in the key file: copied to another host, the file is
+//! useless.
+use crate::config::ED25519Key;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+const MACHINE_ID_PATHS: &[&str] = &["/etc/machine-id", "/var/lib/dbus/machine-id"];
+const SALT_LEN: usize = 32;
+const HKDF_INFO: &[u8] = b"funtonic executor signing key";
+
+#[derive(Error, Debug)]
+pub enum SealingError {
+    #[error("Unable to read machine id from {path}: {source}")]
+    MachineIdUnreadable {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Empty machine id in {0}")]
+    EmptyMachineId(PathBuf),
+    #[error("Unable to unseal signing key {0}: it has been sealed on another host, the machine-id has changed or the key file is corrupted")]
+    WrongHost(String),
+    #[error("Invalid sealed key {key_id}: {reason}")]
+    InvalidSealedKey { key_id: String, reason: String },
+    #[error("Cryptographic error while sealing signing key")]
+    Crypto,
+}
+
+impl From<ring::error::Unspecified> for SealingError {
+    fn from(_: ring::error::Unspecified) -> Self {
+        SealingError::Crypto
+    }
+}
+
+/// Sealed representation of an [`ED25519Key`]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SealedED25519Key {
+    pub id: String,
+    pub public_key: Option<String>,
+    /// base64 encoded salt used with the machine-id to derive the sealing key
+    pub salt: String,
+    /// base64 encoded nonce followed by the encrypted pkcs8 document
+    pub sealed_pkcs8: String,
+}
+
+/// Content of the executor signing key file, either plain or sealed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum SigningKeyFile {
+    Sealed(SealedED25519Key),
+    Plain(ED25519Key),
+}
+
+/// Read the host machine id (systemd or dbus location)
+pub fn read_machine_id() -> Result<Vec<u8>, SealingError> {
+    let path = MACHINE_ID_PATHS
+        .iter()
+        .map(Path::new)
+        .find(|path| path.exists())
+        .unwrap_or_else(|| Path::new(MACHINE_ID_PATHS[0]));
+    read_machine_id_from(path)
+}
+
+pub fn read_machine_id_from<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, SealingError> {
+    let path = path.as_ref();
+    let machine_id =
+        std::fs::read_to_string(path).map_err(|source| SealingError::MachineIdUnreadable {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    let machine_id = machine_id.trim();
+    if machine_id.is_empty() {
+        return Err(SealingError::EmptyMachineId(path.to_path_buf()));
+    }
+    Ok(machine_id.as_bytes().to_vec())
+}
+
+fn sealing_key(machine_id: &[u8], salt: &[u8]) -> LessSafeKey {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(machine_id);
+    let okm = prk
+        .expand(&[HKDF_INFO], &AES_256_GCM)
+        // the requested length is the AES-256 key length, this cannot fail
+        .expect("HKDF output length is valid");
+    LessSafeKey::new(UnboundKey::from(okm))
+}
+
+/// Seal the signing key with the given machine id
+pub fn seal(key: &ED25519Key, machine_id: &[u8]) -> Result<SealedED25519Key, SealingError> {
+    let rng = SystemRandom::new();
+    let mut salt = [0u8; SALT_LEN];
+    rng.fill(&mut salt)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut nonce)?;
+
+    let mut in_out = data_encoding::BASE64
+        .decode(key.pkcs8.as_bytes())
+        .map_err(|e| SealingError::InvalidSealedKey {
+            key_id: key.id.clone(),
+            reason: format!("pkcs8 is not valid base64: {}", e),
+        })?;
+    sealing_key(machine_id, &salt).seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(key.id.as_bytes()),
+        &mut in_out,
+    )?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&in_out);
+    Ok(SealedED25519Key {
+        id: key.id.clone(),
+        public_key: key.public_key.clone(),
+        salt: data_encoding::BASE64.encode(&salt),
+        sealed_pkcs8: data_encoding::BASE64.encode(&sealed),
+    })
+}
+
+/// Unseal a signing key sealed by [`seal`] with the same machine id
+pub fn unseal(sealed: &SealedED25519Key, machine_id: &[u8]) -> Result<ED25519Key, SealingError> {
+    let invalid = |reason: &str| SealingError::InvalidSealedKey {
+        key_id: sealed.id.clone(),
+        reason: reason.to_string(),
+    };
+    let salt = data_encoding::BASE64
+        .decode(sealed.salt.as_bytes())
+        .map_err(|_| invalid("salt is not valid base64"))?;
+    let sealed_pkcs8 = data_encoding::BASE64
+        .decode(sealed.sealed_pkcs8.as_bytes())
+        .map_err(|_| invalid("sealed_pkcs8 is not valid base64"))?;
+    if sealed_pkcs8.len() < NONCE_LEN {
+        return Err(invalid("sealed_pkcs8 is too short"));
+    }
+    let (nonce, ciphertext) = sealed_pkcs8.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce)?;
+
+    let mut in_out = ciphertext.to_vec();
+    let pkcs8 = sealing_key(machine_id, &salt)
+        .open_in_place(nonce, Aad::from(sealed.id.as_bytes()), &mut in_out)
+        .map_err(|_| SealingError::WrongHost(sealed.id.clone()))?;
+
+    Ok(ED25519Key {
+        id: sealed.id.clone(),
+        pkcs8: data_encoding::BASE64.encode(pkcs8),
+        public_key: sealed.public_key.clone(),
+        pkcs8_encrypted: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_machine_id_from, seal, unseal, SealingError, SigningKeyFile};
+    use crate::crypto::keygen::generate_base64_encoded_keys;
+
+    #[test]
+    fn seal_unseal_round_trip() {
+        let (key, _) = generate_base64_encoded_keys("exec");
+        let sealed = seal(&key, b"0123456789abcdef0123456789abcdef").unwrap();
+        assert_ne!(sealed.sealed_pkcs8, key.pkcs8);
+
+        let unsealed = unseal(&sealed, b"0123456789abcdef0123456789abcdef").unwrap();
+        assert_eq!(unsealed.id, key.id);
+        assert_eq!(unsealed.pkcs8, key.pkcs8);
+        assert_eq!(unsealed.public_key, key.public_key);
+
+        // salt & nonce are random: sealing twice does not give the same file
+        let resealed = seal(&key, b"0123456789abcdef0123456789abcdef").unwrap();
+        assert_ne!(resealed.salt, sealed.salt);
+        assert_ne!(resealed.sealed_pkcs8, sealed.sealed_pkcs8);
+    }
+
+    #[test]
+    fn wrong_host() {
+        let (key, _) = generate_base64_encoded_keys("exec");
+        let sealed = seal(&key, b"host-a").unwrap();
+        assert!(matches!(
+            unseal(&sealed, b"host-b"),
+            Err(SealingError::WrongHost(_))
+        ));
+
+        // the key id is authenticated
+        let mut renamed = sealed.clone();
+        renamed.id = "other".into();
+        assert!(matches!(
+            unseal(&renamed, b"host-a"),
+            Err(SealingError::WrongHost(_))
+        ));
+
+        let mut truncated = sealed;
+        truncated.sealed_pkcs8 = "AAAA".into();
+        assert!(matches!(
+            unseal(&truncated, b"host-a"),
+            Err(SealingError::InvalidSealedKey { .. })
+        ));
+    }
+
+    #[test]
+    fn key_file() {
+        let (key, _) = generate_base64_encoded_keys("exec");
+        let plain = serde_yaml::to_string(&SigningKeyFile::Plain(key.clone())).unwrap();
+        assert!(matches!(
+            serde_yaml::from_str::<SigningKeyFile>(&plain).unwrap(),
+            SigningKeyFile::Plain(_)
+        ));
+        let sealed =
+            serde_yaml::to_string(&SigningKeyFile::Sealed(seal(&key, b"host").unwrap())).unwrap();
+        match serde_yaml::from_str::<SigningKeyFile>(&sealed).unwrap() {
+            SigningKeyFile::Sealed(sealed) => {
+                assert_eq!(unseal(&sealed, b"host").unwrap().pkcs8, key.pkcs8)
+            }
+            SigningKeyFile::Plain(_) => panic!("sealed key parsed as a plain one"),
+        }
+    }
+
+    #[test]
+    fn machine_id_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("machine-id");
+        assert!(matches!(
+            read_machine_id_from(&path),
+            Err(SealingError::MachineIdUnreadable { .. })
+        ));
+        std::fs::write(&path, "\n").unwrap();
+        assert!(matches!(
+            read_machine_id_from(&path),
+            Err(SealingError::EmptyMachineId(_))
+        ));
+        std::fs::write(&path, "4c4c4544004d3510804bb7c04f325232\n").unwrap();
+        assert_eq!(
+            read_machine_id_from(&path).unwrap(),
+            b"4c4c4544004d3510804bb7c04f325232"
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use crate::config::ED25519Key;
 use crate::prost;
 use bytes::BytesMut;
+use chrono::{DateTime, Utc};
+use grpc_service::grpc_protocol::{Delegation, PublicKey};
 use grpc_service::payload::SignedPayload;
 use rand::random;
 use ring::signature;
@@ -22,6 +24,20 @@ pub fn to_sign_from_exploded_payload(payload: &[u8], nonce: u64, valid_until_sec
 
 const BUFFER_SIZE: usize = 8 * 1024;
 
+/// Purpose of every delegation certificate, checked when the certificate is decoded
+pub const DELEGATION_PURPOSE: &str = "funtonic-delegation";
+
+/// Seconds the local clock is ahead (positive) or behind (negative) a peer one, `http_date` being
+/// the `date` header of a response the peer sent at `now`
+pub fn clock_skew_secs(http_date: &str, now: SystemTime) -> Option<i64> {
+    let peer_time = DateTime::parse_from_rfc2822(http_date).ok()?;
+    Some(
+        DateTime::<Utc>::from(now)
+            .signed_duration_since(peer_time)
+            .num_seconds(),
+    )
+}
+
 #[derive(Error, Debug)]
 pub enum EncodePayloadError {
     #[error("Invalid key provided: {0}")]
@@ -36,6 +52,47 @@ pub fn encode_and_sign<P: prost::Message>(
     payload: P,
     key: &ED25519Key,
     validity: Duration,
+) -> Result<SignedPayload, EncodePayloadError> {
+    let mut buf = BytesMut::with_capacity(BUFFER_SIZE);
+    payload
+        .encode(&mut buf)
+        .map_err(|e| EncodePayloadError::EncodeError(e.to_string()))?;
+    sign_raw_payload(buf.to_vec(), key, validity)
+}
+
+/// Grant the signing rights of `key` to the `delegate_key_id` key for `ttl`, on the executors
+/// matching `allowed_predicate`. Returns the delegation certificate.
+pub fn delegate(
+    key: &ED25519Key,
+    delegate_key_id: &str,
+    delegate_key_bytes: Vec<u8>,
+    allowed_predicate: &str,
+    ttl: Duration,
+) -> Result<SignedPayload, EncodePayloadError> {
+    let expires_at_secs = (SystemTime::now() + ttl)
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| EncodePayloadError::SystemClockIsBeforeUnixEpoch)?
+        .as_secs();
+    encode_and_sign(
+        Delegation {
+            delegate: Some(PublicKey {
+                key_id: delegate_key_id.to_string(),
+                key_bytes: delegate_key_bytes,
+                expires_at_secs,
+            }),
+            allowed_predicate: allowed_predicate.to_string(),
+            purpose: DELEGATION_PURPOSE.to_string(),
+        },
+        key,
+        ttl,
+    )
+}
+
+/// Sign already encoded bytes, `encode_and_sign` should be preferred
+pub fn sign_raw_payload(
+    payload: Vec<u8>,
+    key: &ED25519Key,
+    validity: Duration,
 ) -> Result<SignedPayload, EncodePayloadError> {
     let valid_until_secs = (SystemTime::now() + validity)
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -49,12 +106,6 @@ pub fn encode_and_sign<P: prost::Message>(
     )
     .map_err(|e| EncodePayloadError::KeyRejected(e.to_string()))?;
 
-    let mut buf = BytesMut::with_capacity(BUFFER_SIZE);
-    payload
-        .encode(&mut buf)
-        .map_err(|e| EncodePayloadError::EncodeError(e.to_string()))?;
-    let payload = buf.to_vec();
-
     let signature = Vec::from(
         key_pair
             .sign(&to_sign_from_exploded_payload(
@@ -0,0 +1,81 @@
+//! Diagnostic task (`admin doctor`): instead of running a command, executors report their clock &
+//! versions as a json line on stdout.
+use crate::{PROTOCOL_VERSION, VERSION};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DiagnosticReport {
+    /// executor clock when the task was received, unix timestamp in microseconds
+    pub time_micros: u64,
+    pub version: String,
+    pub protocol_version: String,
+}
+
+impl DiagnosticReport {
+    pub fn now() -> Self {
+        Self {
+            time_micros: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64,
+            version: VERSION.to_string(),
+            protocol_version: PROTOCOL_VERSION.to_string(),
+        }
+    }
+
+    /// The stdout line of the diagnostic task
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("a diagnostic report is always serializable")
+    }
+
+    /// None if the line is not a diagnostic report
+    pub fn decode(line: &str) -> Option<Self> {
+        serde_json::from_str(line).ok()
+    }
+
+    pub fn time(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_micros(self.time_micros)
+    }
+
+    /// Executor clock minus the clock of the requester, in seconds, estimated at the middle of
+    /// the round trip from `sent` to `received`: accurate within half of the round trip
+    pub fn clock_skew_secs(&self, sent: SystemTime, received: SystemTime) -> f64 {
+        let round_trip = received.duration_since(sent).unwrap_or_default();
+        let middle = sent + round_trip / 2;
+        match self.time().duration_since(middle) {
+            Ok(ahead) => ahead.as_secs_f64(),
+            Err(behind) => -behind.duration().as_secs_f64(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DiagnosticReport;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn clock_skew() {
+        let sent = SystemTime::now();
+        let received = sent + Duration::from_millis(200);
+        let at = |time: SystemTime| DiagnosticReport {
+            time_micros: time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_micros() as u64,
+            ..DiagnosticReport::now()
+        };
+        let skew = |time| at(time).clock_skew_secs(sent, received);
+        assert!(skew(sent + Duration::from_millis(100)).abs() < 0.001);
+        assert!((skew(sent + Duration::from_secs(30)) - 29.9).abs() < 0.001);
+        assert!((skew(sent - Duration::from_secs(30)) + 30.1).abs() < 0.001);
+
+        let report = at(sent);
+        assert_eq!(
+            Some(report.clone()),
+            DiagnosticReport::decode(&report.encode())
+        );
+        assert_eq!(None, DiagnosticReport::decode("hello"));
+    }
+}
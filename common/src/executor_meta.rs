@@ -1,6 +1,9 @@
+use crate::capabilities::Capabilities;
 use crate::config::ExecutorConfig;
+use crate::system_info::SystemInfo;
 use crate::{PROTOCOL_VERSION, VERSION};
 use anyhow::Context;
+use chrono::{Local, TimeZone};
 use get_if_addrs::{IfAddr, Interface};
 use grpc_service::grpc_protocol::{GetTasksRequest, PublicKey, ValueList, ValueMap};
 use os_info::Info;
@@ -10,6 +13,11 @@ use serde::{Deserialize, Serialize};
 use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_GRAINS_FILE: &str = "/etc/salt/grains";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
@@ -24,6 +32,42 @@ pub struct ExecutorMeta {
     client_id: String,
     version: String,
     tags: HashMap<String, Tag>,
+    /// executor process start time (rfc3339)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    started_at: Option<String>,
+    /// last connection of the executor to the taskserver (rfc3339)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    connected_at: Option<String>,
+    /// random id of the executor installation (uuid)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    instance_id: Option<String>,
+    /// some tags have been overridden by an admin on the taskserver
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    overridden: bool,
+    /// registrations of other processes with this client_id rejected while the executor was
+    /// connected, only set in the listings of the connected executors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duplicate_registrations: Option<u64>,
+    /// protocol features advertised by the executor, see [Capabilities]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    capabilities: Vec<String>,
+}
+
+static PROCESS_STARTED_AT: OnceLock<u64> = OnceLock::new();
+
+/// Record the start time of the executor process, to be called first thing in main. Otherwise
+/// the start time is approximated by the first registration attempt.
+pub fn init_process_started_at() {
+    process_started_at_secs();
+}
+
+fn process_started_at_secs() -> u64 {
+    *PROCESS_STARTED_AT.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or(0)
+    })
 }
 
 impl From<&ExecutorConfig> for ExecutorMeta {
@@ -32,6 +76,12 @@ impl From<&ExecutorConfig> for ExecutorMeta {
             client_id: config.client_id.clone(),
             version: VERSION.into(),
             tags: config.tags.clone(),
+            started_at: None,
+            connected_at: None,
+            instance_id: config.instance_id.clone(),
+            overridden: false,
+            duplicate_registrations: None,
+            capabilities: vec![],
         }
     }
 }
@@ -107,7 +157,11 @@ impl TryFrom<&ExecutorConfig> for GetTasksRequest {
     fn try_from(config: &ExecutorConfig) -> Result<Self, Self::Error> {
         let mut m: ExecutorMeta = config.into();
         // add Saltstack grains if found.
-        if let Ok(grains_file) = std::fs::File::open("/etc/salt/grains") {
+        let grains_file = config
+            .grains_file
+            .as_deref()
+            .unwrap_or_else(|| Path::new(DEFAULT_GRAINS_FILE));
+        if let Ok(grains_file) = std::fs::File::open(grains_file) {
             if let Ok(grains) = serde_yaml::from_reader::<_, HashMap<String, Tag>>(grains_file) {
                 info!("Found Saltstack grains, extending executor tags with it!");
                 m.tags.extend(grains);
@@ -115,10 +169,12 @@ impl TryFrom<&ExecutorConfig> for GetTasksRequest {
         }
         // add os info to executor metas
         m.tags.insert("os_info".into(), os_info::get().into());
+        m.tags.insert("system".into(), SystemInfo::probe().into());
         m.tags.insert(
             "network_interfaces".into(),
             get_if_addrs::get_if_addrs()?.into(),
         );
+        merge_cli_tags(&mut m.tags, &config.cli_tags);
         Ok(Self {
             client_id: m.client_id.clone(),
             client_version: m.version.clone(),
@@ -133,6 +189,7 @@ impl TryFrom<&ExecutorConfig> for GetTasksRequest {
                 })
                 .collect(),
             client_protocol_version: PROTOCOL_VERSION.into(),
+            started_at_secs: process_started_at_secs(),
             authorized_keys: config
                 .authorized_keys
                 .iter()
@@ -142,9 +199,16 @@ impl TryFrom<&ExecutorConfig> for GetTasksRequest {
                         key_bytes: data_encoding::BASE64
                             .decode(key.as_bytes())
                             .with_context(|| format!("Unable to decode key {}", id))?,
+                        expires_at_secs: config
+                            .authorized_keys_expiry
+                            .get(id)
+                            .copied()
+                            .unwrap_or(0),
                     });
                     Ok(keys)
                 })?,
+            capabilities: Capabilities::local().into(),
+            instance_id: m.instance_id.clone().unwrap_or_default(),
         })
     }
 }
@@ -159,6 +223,18 @@ impl From<&GetTasksRequest> for ExecutorMeta {
                 .iter()
                 .map(|(tag_name, tag_value)| (tag_name.clone(), tag_value.into()))
                 .collect(),
+            started_at: match r.started_at_secs {
+                0 => None,
+                secs => Local
+                    .timestamp_opt(secs as i64, 0)
+                    .single()
+                    .map(|started_at| started_at.to_rfc3339()),
+            },
+            connected_at: None,
+            instance_id: Some(r.instance_id.clone()).filter(|instance_id| !instance_id.is_empty()),
+            overridden: false,
+            duplicate_registrations: None,
+            capabilities: r.capabilities.clone(),
         }
     }
 }
@@ -269,11 +345,159 @@ impl ExecutorMeta {
     pub fn tags_mut(&mut self) -> &mut HashMap<String, Tag> {
         &mut self.tags
     }
+
+    pub fn started_at(&self) -> Option<&str> {
+        self.started_at.as_deref()
+    }
+
+    pub fn connected_at(&self) -> Option<&str> {
+        self.connected_at.as_deref()
+    }
+
+    pub fn set_connected_at(&mut self, connected_at: Option<String>) {
+        self.connected_at = connected_at;
+    }
+
+    pub fn instance_id(&self) -> Option<&str> {
+        self.instance_id.as_deref()
+    }
+
+    /// First group of the instance id, enough to tell apart the executors sharing a client_id
+    pub fn short_instance_id(&self) -> Option<&str> {
+        self.instance_id
+            .as_deref()
+            .map(|instance_id| instance_id.split('-').next().unwrap_or(instance_id))
+    }
+
+    pub fn overridden(&self) -> bool {
+        self.overridden
+    }
+
+    /// Capabilities of the executor known to this build
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::from_peer(&self.capabilities)
+    }
+
+    pub fn duplicate_registrations(&self) -> u64 {
+        self.duplicate_registrations.unwrap_or(0)
+    }
+
+    pub fn set_duplicate_registrations(&mut self, count: u64) {
+        self.duplicate_registrations = (count > 0).then_some(count);
+    }
+
+    /// Set (or remove if `value` is `None`) the tag at `path` & mark the metas as overridden.
+    ///
+    /// `path` is `:` separated like queries (`os_info:type`), missing maps are created and
+    /// non map tags found along the path are replaced.
+    pub fn override_tag(&mut self, path: &str, value: Option<&str>) {
+        set_tag_at(
+            &mut self.tags,
+            &path.split(':').collect::<Vec<_>>(),
+            value.map(|value| Tag::Value(value.to_string())),
+        );
+        self.overridden = true;
+    }
+
+    /// The tag found by following `path` through nested maps
+    pub fn tag(&self, path: &[&str]) -> Option<&Tag> {
+        tag_at(&self.tags, path)
+    }
+
+    /// Only keep the tags at the `:` separated `paths` (like in queries), missing paths are
+    /// ignored
+    pub fn project_tags<S: AsRef<str>>(&mut self, paths: &[S]) {
+        let mut projected = HashMap::new();
+        for path in paths {
+            let path: Vec<&str> = path.as_ref().split(':').collect();
+            if let Some(tag) = self.tag(&path) {
+                set_tag_at(&mut projected, &path, Some(tag.clone()));
+            }
+        }
+        self.tags = projected;
+    }
+
+    /// Remove the tags at the `:` separated `paths`
+    pub fn hide_tags<S: AsRef<str>>(&mut self, paths: &[S]) {
+        for path in paths {
+            set_tag_at(
+                &mut self.tags,
+                &path.as_ref().split(':').collect::<Vec<_>>(),
+                None,
+            );
+        }
+    }
+}
+
+impl Tag {
+    /// The tag found by following `path` through nested maps, `self` if `path` is empty
+    pub fn get_path(&self, path: &[&str]) -> Option<&Tag> {
+        match (self, path) {
+            (_, []) => Some(self),
+            (Tag::Map(tags), path) => tag_at(tags, path),
+            _ => None,
+        }
+    }
+}
+
+fn tag_at<'a>(tags: &'a HashMap<String, Tag>, path: &[&str]) -> Option<&'a Tag> {
+    match path {
+        [] => None,
+        [name, path @ ..] => tags.get(*name)?.get_path(path),
+    }
+}
+
+/// Set (or remove) the tag at `path`, creating missing maps & replacing non map tags found
+/// along the path. Returns the replaced tag if any.
+fn set_tag_at(tags: &mut HashMap<String, Tag>, path: &[&str], value: Option<Tag>) -> Option<Tag> {
+    match (path, value) {
+        ([], _) => None,
+        ([name], Some(value)) => tags.insert(name.to_string(), value),
+        ([name], None) => tags.remove(*name),
+        ([name, path @ ..], Some(value)) => {
+            let tag = tags
+                .entry(name.to_string())
+                .or_insert_with(|| Tag::Map(HashMap::new()));
+            let replaced = match tag {
+                Tag::Map(_) => None,
+                _ => Some(std::mem::replace(tag, Tag::Map(HashMap::new()))),
+            };
+            match tag {
+                Tag::Map(tags) => set_tag_at(tags, path, Some(value)).or(replaced),
+                _ => replaced,
+            }
+        }
+        ([name, path @ ..], None) => match tags.get_mut(*name) {
+            Some(Tag::Map(tags)) => set_tag_at(tags, path, None),
+            _ => None,
+        },
+    }
+}
+
+/// Merge `key=value` tags given on the command line over `tags`.
+///
+/// Keys are `.` separated paths (`os.flavor=custom` sets `flavor` in the `os` map), the command
+/// line wins on conflicts.
+pub fn merge_cli_tags(tags: &mut HashMap<String, Tag>, cli_tags: &[(String, String)]) {
+    for (key, value) in cli_tags {
+        if let Some(replaced) = set_tag_at(
+            tags,
+            &key.split('.').collect::<Vec<_>>(),
+            Some(Tag::Value(value.clone())),
+        ) {
+            warn!(
+                "Tag {} overridden by the command line: {} replaced by {}",
+                key,
+                serde_json::to_string(&replaced).unwrap_or("???".to_string()),
+                value
+            );
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::executor_meta::{ExecutorMeta, Tag};
+    use crate::executor_meta::{merge_cli_tags, ExecutorMeta, Tag};
     use query_parser::{parse, QueryMatcher};
     use std::collections::HashMap;
 
@@ -371,4 +595,144 @@ mod test {
         assert!(!meta.matches("env:prod and !siderant"));
         // this is a TODO
     }
+
+    #[test]
+    fn override_tag() {
+        let metas = r#"
+        client_id: siderant
+        version: 0.0.1
+        tags:
+          env: prod
+          roles:
+            - foo
+          os:
+            type: Linux
+            version: "18.04"
+        "#;
+        let mut meta: ExecutorMeta = serde_yaml::from_str(metas).unwrap();
+        assert!(!meta.overridden());
+
+        meta.override_tag("env", Some("dev"));
+        assert!(meta.overridden());
+        assert!(meta.matches("env:dev"));
+        assert!(!meta.matches("env:prod"));
+
+        // missing maps are created, non map tags are replaced
+        meta.override_tag("team:name", Some("ops"));
+        assert!(meta.matches("team:name:ops"));
+        meta.override_tag("roles:main", Some("bar"));
+        assert!(meta.matches("roles:main:bar"));
+        assert!(!meta.matches("roles:foo"));
+
+        meta.override_tag("os:version", None);
+        assert!(meta.matches("os:type:Linux"));
+        assert!(!meta.matches("os:version:*"));
+        // removing a missing tag is a no-op
+        meta.override_tag("os:version:major", None);
+        meta.override_tag("missing", None);
+        assert!(meta.matches("os:type:Linux"));
+
+        let serialized = serde_yaml::to_string(&meta).unwrap();
+        assert!(serialized.contains("overridden: true"));
+    }
+
+    #[test]
+    fn tag_path() {
+        let tags: Tag = serde_yaml::from_str(
+            "env: prod
+roles:
+  - foo
+os:
+  type: Linux
+  release:
+    major: \"18\"",
+        )
+        .unwrap();
+        let value = |path: &[&str]| match tags.get_path(path) {
+            Some(Tag::Value(value)) => Some(value.as_str()),
+            _ => None,
+        };
+        assert_eq!(Some("prod"), value(&["env"]));
+        assert_eq!(Some("Linux"), value(&["os", "type"]));
+        assert_eq!(Some("18"), value(&["os", "release", "major"]));
+        assert!(matches!(
+            tags.get_path(&["os", "release"]),
+            Some(Tag::Map(_))
+        ));
+        assert!(matches!(tags.get_path(&["roles"]), Some(Tag::List(_))));
+        assert!(matches!(tags.get_path(&[]), Some(Tag::Map(_))));
+        assert!(tags.get_path(&["os", "missing"]).is_none());
+        // lists & values have no children
+        assert!(tags.get_path(&["roles", "foo"]).is_none());
+        assert!(tags.get_path(&["env", "prod"]).is_none());
+    }
+
+    #[test]
+    fn project_tags() {
+        let metas = r#"
+        client_id: siderant
+        version: 0.0.1
+        tags:
+          env: prod
+          roles:
+            - foo
+          os:
+            type: Linux
+            version: "18.04"
+        "#;
+        let mut meta: ExecutorMeta = serde_yaml::from_str(metas).unwrap();
+        meta.project_tags(&["os:type", "roles", "missing", "env:missing"]);
+        assert!(meta.matches("os:type:Linux"));
+        assert!(meta.matches("roles:foo"));
+        assert!(!meta.matches("os:version:*"));
+        assert!(!meta.matches("env:*"));
+        assert_eq!(2, meta.tags().len());
+
+        let mut meta: ExecutorMeta = serde_yaml::from_str(metas).unwrap();
+        meta.hide_tags(&["os:version", "roles", "missing"]);
+        assert!(meta.matches("env:prod"));
+        assert!(meta.matches("os:type:Linux"));
+        assert!(!meta.matches("os:version:*"));
+        assert!(!meta.matches("roles:*"));
+    }
+    #[test]
+    fn cli_tags() {
+        let mut tags: HashMap<String, Tag> = serde_yaml::from_str(
+            "env: prod
+roles:
+  - foo
+os:
+  type: Linux",
+        )
+        .unwrap();
+        let cli_tags = [
+            ("env", "staging"),
+            ("role", "ci"),
+            ("os.flavor", "custom"),
+            ("roles.main", "bar"),
+            ("team.members.lead", "alice"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        merge_cli_tags(&mut tags, &cli_tags);
+
+        let tags = Tag::Map(tags);
+        assert!(tags.matches("env:staging"));
+        assert!(!tags.matches("env:prod"));
+        assert!(tags.matches("role:ci"));
+        // dotted keys are merged into existing maps...
+        assert!(tags.matches("os:type:Linux"));
+        assert!(tags.matches("os:flavor:custom"));
+        // ...replace non map tags...
+        assert!(tags.matches("roles:main:bar"));
+        assert!(!tags.matches("roles:foo"));
+        // ... and create missing maps
+        assert!(tags.matches("team:members:lead:alice"));
+        match &tags {
+            Tag::Map(tags) => match &tags["team"] {
+                Tag::Map(team) => assert!(matches!(team["members"], Tag::Map(_))),
+                _ => panic!("team must be a map"),
+            },
+            _ => unreachable!(),
+        }
+    }
 }
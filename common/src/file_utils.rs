@@ -2,11 +2,22 @@ use crate::path_builder::PathBuilder;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::borrow::Borrow;
-use std::fs::File;
-use std::io::{ErrorKind, Read, Write};
+#[cfg(unix)]
+use std::fs::Permissions;
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 
+/// Directories holding key material: only accessible by their owner
+pub const PRIVATE_DIR_MODE: u32 = 0o700;
+/// Key material files: only readable by their owner
+pub const PRIVATE_FILE_MODE: u32 = 0o600;
+
 pub fn path_concat2<T: AsRef<Path>, U: AsRef<Path>>(p1: T, p2: U) -> PathBuf {
     PathBuilder::from_path(p1).push(p2).build()
 }
@@ -59,8 +70,22 @@ pub enum DirCreationError {
 }
 
 pub fn mkdirs<P: AsRef<Path>>(dir: P) -> Result<String, DirCreationError> {
+    mkdirs_with(dir, DirBuilder::new().recursive(true))
+}
+
+/// [mkdirs] creating the missing directories with [PRIVATE_DIR_MODE], existing ones are kept as is.
+/// Modes only exist on unix: elsewhere this is [mkdirs].
+pub fn mkdirs_private<P: AsRef<Path>>(dir: P) -> Result<String, DirCreationError> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    builder.mode(PRIVATE_DIR_MODE);
+    mkdirs_with(dir, &builder)
+}
+
+fn mkdirs_with<P: AsRef<Path>>(dir: P, builder: &DirBuilder) -> Result<String, DirCreationError> {
     let dir: String = shellexpand::tilde(&dir.as_ref().to_string_lossy().into_owned()).into_owned();
-    if let Err(e) = std::fs::create_dir_all(&dir) {
+    if let Err(e) = builder.create(&dir) {
         match e.kind() {
             ErrorKind::AlreadyExists => {
                 // dir or file exists
@@ -75,3 +100,434 @@ pub fn mkdirs<P: AsRef<Path>>(dir: P) -> Result<String, DirCreationError> {
     }
     Ok(dir)
 }
+
+/// Write a file only readable by its owner ([PRIVATE_FILE_MODE]), an existing file is truncated and
+/// its permissions restricted. Modes only exist on unix: elsewhere the file is plainly written.
+pub fn write_private_file<P: AsRef<Path>, C: AsRef<[u8]>>(
+    path: P,
+    contents: C,
+) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(PRIVATE_FILE_MODE);
+    let mut file = options.open(&path)?;
+    // the mode is only applied on creation (and masked by the umask)
+    #[cfg(unix)]
+    file.set_permissions(Permissions::from_mode(PRIVATE_FILE_MODE))?;
+    file.write_all(contents.as_ref())?;
+    file.sync_all()
+}
+
+/// Replace the contents of an existing file at once: they are written to a temporary file next to
+/// it, with the same permissions, which is then renamed over it
+pub fn replace_file<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let permissions = std::fs::metadata(path)?.permissions();
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let written = (|| -> std::io::Result<()> {
+        let mut file = File::create(&temp_path)?;
+        // restricted before anything is written
+        file.set_permissions(permissions)?;
+        file.write_all(contents.as_ref())?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)
+    })();
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    written
+}
+
+#[cfg(unix)]
+pub fn set_private_permissions<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    std::fs::set_permissions(path, Permissions::from_mode(PRIVATE_FILE_MODE))
+}
+
+/// Modes only exist on unix: the permissions are left as is
+#[cfg(not(unix))]
+pub fn set_private_permissions<P: AsRef<Path>>(_path: P) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Why the key material file is not private: readable by the group or others, or owned by
+/// another user than the current one
+#[cfg(unix)]
+pub fn private_file_issue<P: AsRef<Path>>(path: P) -> std::io::Result<Option<String>> {
+    let metadata = std::fs::metadata(path)?;
+    let mode = metadata.mode() & 0o777;
+    // safe: geteuid cannot fail
+    let uid = unsafe { libc::geteuid() };
+    Ok(if mode & 0o077 != 0 {
+        Some(format!(
+            "mode {:o} gives access to the group or others",
+            mode
+        ))
+    } else if metadata.uid() != uid {
+        Some(format!(
+            "owned by uid {}, running as uid {}",
+            metadata.uid(),
+            uid
+        ))
+    } else {
+        None
+    })
+}
+
+/// Modes & owners are only checked on unix: only the existence of the file is checked
+#[cfg(not(unix))]
+pub fn private_file_issue<P: AsRef<Path>>(path: P) -> std::io::Result<Option<String>> {
+    std::fs::metadata(path)?;
+    Ok(None)
+}
+
+/// Log a warning if an existing key material file is not private, see [private_file_issue]
+pub fn warn_if_not_private<P: AsRef<Path>>(path: P) {
+    let path = path.as_ref();
+    match private_file_issue(path) {
+        Ok(Some(issue)) => log::warn!(
+            "Key file {} is not private: {}, restrict it with chmod 600",
+            path.to_string_lossy(),
+            issue
+        ),
+        Ok(None) => {}
+        Err(e) => log::warn!(
+            "Unable to check the permissions of {}: {}",
+            path.to_string_lossy(),
+            e
+        ),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error(
+        "{path} is held by another process{}",
+        .pid.map(|pid| format!(" (pid {})", pid)).unwrap_or_default()
+    )]
+    Held { path: String, pid: Option<u32> },
+    #[error("Unable to lock {path}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+}
+
+/// Exclusive advisory lock (flock) on a file holding the pid of its owner, released & removed on
+/// drop. Without flock (non unix platforms), the lock is the file itself: a file left behind by a
+/// crashed process must be removed by hand.
+#[derive(Debug)]
+pub struct PidLock {
+    file: File,
+    path: PathBuf,
+}
+
+/// The pid written in a locked file, along with this file
+struct Holder {
+    pid: Option<u32>,
+    file: File,
+}
+
+impl PidLock {
+    /// Lock `path`, created if missing. A lock held by a dead process (its pid is not running
+    /// anymore) is stolen.
+    pub fn acquire<P: AsRef<Path>>(path: P) -> Result<PidLock, LockError> {
+        let path = path.as_ref();
+        let io_error = |source| LockError::Io {
+            path: path.to_string_lossy().into(),
+            source,
+        };
+        let held = |holder: Holder| LockError::Held {
+            path: path.to_string_lossy().into(),
+            pid: holder.pid,
+        };
+        match PidLock::try_acquire(path).map_err(io_error)? {
+            Ok(lock) => Ok(lock),
+            Err(Holder {
+                pid: Some(pid),
+                file,
+            }) if !is_running(pid) => {
+                // the lock outlives its owner: a child process inherited the descriptor, or the
+                // filesystem does not release the locks of dead processes.
+                // The file is replaced by a new one, locked on its own.
+                log::warn!(
+                    "Stealing the lock {} of dead process {}",
+                    path.to_string_lossy(),
+                    pid
+                );
+                // unless another process already did: its own file is not removed
+                if is_same_file(path, &file) {
+                    match std::fs::remove_file(path) {
+                        Err(e) if e.kind() != ErrorKind::NotFound => return Err(io_error(e)),
+                        _ => {}
+                    }
+                }
+                PidLock::try_acquire(path).map_err(io_error)?.map_err(held)
+            }
+            Err(holder) => Err(held(holder)),
+        }
+    }
+
+    /// The lock, or the pid written in the file when it is held
+    #[cfg(unix)]
+    fn try_acquire(path: &Path) -> std::io::Result<Result<PidLock, Holder>> {
+        loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            // safe: the descriptor is owned by file
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+                let e = std::io::Error::last_os_error();
+                return if e.kind() == ErrorKind::WouldBlock {
+                    let mut content = String::new();
+                    file.read_to_string(&mut content)?;
+                    Ok(Err(Holder {
+                        pid: content.trim().parse().ok(),
+                        file,
+                    }))
+                } else {
+                    Err(e)
+                };
+            }
+            let mut lock = PidLock {
+                file,
+                path: path.to_path_buf(),
+            };
+            // the opened file was removed before being locked (released or stolen by another
+            // process): the lock is taken on the current file instead
+            if !lock.is_current_file() {
+                continue;
+            }
+            lock.file.set_len(0)?;
+            lock.file.seek(SeekFrom::Start(0))?;
+            lock.file
+                .write_all(format!("{}\n", std::process::id()).as_bytes())?;
+            lock.file.sync_all()?;
+            return Ok(Ok(lock));
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn try_acquire(path: &Path) -> std::io::Result<Result<PidLock, Holder>> {
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let mut file = File::open(path)?;
+                let mut content = String::new();
+                file.read_to_string(&mut content)?;
+                return Ok(Err(Holder {
+                    pid: content.trim().parse().ok(),
+                    file,
+                }));
+            }
+            Err(e) => return Err(e),
+        };
+        file.write_all(format!("{}\n", std::process::id()).as_bytes())?;
+        file.sync_all()?;
+        Ok(Ok(PidLock {
+            file,
+            path: path.to_path_buf(),
+        }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[cfg(unix)]
+    fn is_current_file(&self) -> bool {
+        is_same_file(&self.path, &self.file)
+    }
+
+    /// Never stolen: the file is ours as long as it holds our pid
+    #[cfg(not(unix))]
+    fn is_current_file(&self) -> bool {
+        std::fs::read_to_string(&self.path)
+            .map(|content| content.trim() == std::process::id().to_string())
+            .unwrap_or(false)
+    }
+}
+
+/// `path` is the opened `file`, not another file created at the same path since
+#[cfg(unix)]
+fn is_same_file(path: &Path, file: &File) -> bool {
+    match (std::fs::metadata(path), file.metadata()) {
+        (Ok(current), Ok(opened)) => current.dev() == opened.dev() && current.ino() == opened.ino(),
+        _ => false,
+    }
+}
+
+/// Files cannot be told apart: never the same, so that nothing is removed
+#[cfg(not(unix))]
+fn is_same_file(_path: &Path, _file: &File) -> bool {
+    false
+}
+
+impl Drop for PidLock {
+    fn drop(&mut self) {
+        // removed while still locked, unless it was stolen & replaced: nobody else can own it
+        if self.is_current_file() {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                log::warn!("Unable to remove {}: {}", self.path.to_string_lossy(), e);
+            }
+        }
+        // safe: the descriptor is owned by self.file
+        #[cfg(unix)]
+        unsafe {
+            libc::flock(self.file.as_raw_fd(), libc::LOCK_UN)
+        };
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // signal 0 only checks the process exists; EPERM: it exists but belongs to another user
+    let alive = unsafe { libc::kill(pid as libc::pid_t, 0) == 0 };
+    alive || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// The process cannot be checked: the lock is never stolen
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(unix)]
+    use super::{
+        is_same_file, mkdirs_private, private_file_issue, replace_file, write_private_file,
+        PRIVATE_DIR_MODE, PRIVATE_FILE_MODE,
+    };
+    use super::{LockError, PidLock};
+    #[cfg(unix)]
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    #[cfg(unix)]
+    fn mode(path: &std::path::Path) -> u32 {
+        std::fs::metadata(path).unwrap().mode() & 0o777
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("funtonic/data");
+        assert_eq!(
+            data.to_string_lossy(),
+            mkdirs_private(&data).unwrap().as_str()
+        );
+        assert_eq!(PRIVATE_DIR_MODE, mode(&data));
+        assert_eq!(PRIVATE_DIR_MODE, mode(&dir.path().join("funtonic")));
+        // existing directories are kept as is
+        std::fs::set_permissions(&data, std::fs::Permissions::from_mode(0o755)).unwrap();
+        mkdirs_private(&data).unwrap();
+        assert_eq!(0o755, mode(&data));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("executor_ed25519_key.yml");
+        write_private_file(&key, "id: foo\n").unwrap();
+        assert_eq!(PRIVATE_FILE_MODE, mode(&key));
+        assert_eq!("id: foo\n", std::fs::read_to_string(&key).unwrap());
+        assert!(private_file_issue(&key).unwrap().is_none());
+
+        std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(
+            Some("mode 644 gives access to the group or others".to_string()),
+            private_file_issue(&key).unwrap()
+        );
+        write_private_file(&key, "id: bar\n").unwrap();
+        assert_eq!(PRIVATE_FILE_MODE, mode(&key));
+        assert_eq!("id: bar\n", std::fs::read_to_string(&key).unwrap());
+        assert!(private_file_issue(dir.path().join("missing")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replaced_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("commander.yml");
+        write_private_file(&key, "id: foo\n").unwrap();
+        replace_file(&key, "id: bar\n").unwrap();
+        assert_eq!("id: bar\n", std::fs::read_to_string(&key).unwrap());
+        assert_eq!(PRIVATE_FILE_MODE, mode(&key));
+        assert_eq!(1, std::fs::read_dir(dir.path()).unwrap().count());
+        assert!(replace_file(dir.path().join("missing"), "id: foo\n").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pid_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("executor.lock");
+        let lock = PidLock::acquire(&path).unwrap();
+        assert_eq!(
+            format!("{}\n", std::process::id()),
+            std::fs::read_to_string(&path).unwrap()
+        );
+
+        // flock locks are per open file: held even in the same process
+        match PidLock::acquire(&path).unwrap_err() {
+            LockError::Held { pid, .. } => assert_eq!(Some(std::process::id()), pid),
+            e => panic!("Not held: {:?}", e),
+        }
+
+        drop(lock);
+        assert!(!path.exists());
+        let _lock = PidLock::acquire(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_pid_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("executor.lock");
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+
+        // a descriptor of the dead process survived it, still locking the file
+        let stale = PidLock::acquire(&path).unwrap();
+        std::fs::write(&path, format!("{}\n", dead_pid)).unwrap();
+
+        let lock = PidLock::acquire(&path).unwrap();
+        assert_eq!(
+            format!("{}\n", std::process::id()),
+            std::fs::read_to_string(&path).unwrap()
+        );
+        drop(lock);
+        assert!(!path.exists());
+        let lock = PidLock::acquire(&path).unwrap();
+        // the stolen lock does not remove the new file
+        drop(stale);
+        assert!(path.exists());
+        drop(lock);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replaced_pid_lock_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("executor.lock");
+        let replaced = PidLock::acquire(&path).unwrap();
+        assert!(is_same_file(&path, &replaced.file));
+
+        // removed by another process stealing it
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "").unwrap();
+        assert!(!is_same_file(&path, &replaced.file));
+        let lock = PidLock::acquire(&path).unwrap();
+        assert!(is_same_file(&path, &lock.file));
+        drop(replaced);
+        assert!(path.exists());
+    }
+}
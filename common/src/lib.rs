@@ -6,12 +6,17 @@ extern crate async_stream;
 #[macro_use]
 extern crate log;
 
+pub mod capabilities;
 pub mod config;
 pub mod crypto;
+pub mod diagnostic;
 pub mod executor_meta;
 pub mod file_utils;
+pub mod log_bridge;
 pub mod path_builder;
+pub mod system_info;
 pub mod task_server;
+pub mod version;
 
 pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
@@ -0,0 +1,152 @@
+//! Tracing events logged through the `log` logger (log4rs) along with the fields of their spans:
+//! without a tracing subscriber, events are logged as is and the task or client ids recorded in
+//! their spans are lost.
+use std::io::Write;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Format the tracing events with the fields of their spans, eg:
+/// `execute_task{task_id=.. client_id=..}: Task completed`, and log them at their level.
+///
+/// To be called once the `log` logger is set.
+pub fn init() -> Result<(), tracing::subscriber::SetGlobalDefaultError> {
+    let max_level = match log::max_level() {
+        log::LevelFilter::Off => tracing::level_filters::LevelFilter::OFF,
+        log::LevelFilter::Error => tracing::level_filters::LevelFilter::ERROR,
+        log::LevelFilter::Warn => tracing::level_filters::LevelFilter::WARN,
+        log::LevelFilter::Info => tracing::level_filters::LevelFilter::INFO,
+        log::LevelFilter::Debug => tracing::level_filters::LevelFilter::DEBUG,
+        log::LevelFilter::Trace => tracing::level_filters::LevelFilter::TRACE,
+    };
+    // the log logger is already set: not installed by `init` of the fmt subscriber
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::fmt()
+            .with_writer(LogWriters(log::logger()))
+            .with_max_level(max_level)
+            .without_time()
+            .with_level(false)
+            .with_target(false)
+            .with_ansi(false)
+            .finish(),
+    )
+}
+
+/// One writer per formatted event
+struct LogWriters(&'static dyn log::Log);
+
+impl<'a> MakeWriter<'a> for LogWriters {
+    type Writer = LogWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogWriter::new(self.0, log::Level::Info, "")
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let level = match *meta.level() {
+            Level::ERROR => log::Level::Error,
+            Level::WARN => log::Level::Warn,
+            Level::INFO => log::Level::Info,
+            Level::DEBUG => log::Level::Debug,
+            Level::TRACE => log::Level::Trace,
+        };
+        LogWriter::new(self.0, level, meta.target())
+    }
+}
+
+/// Collects a formatted event, logged when dropped
+struct LogWriter {
+    logger: &'static dyn log::Log,
+    level: log::Level,
+    target: String,
+    line: Vec<u8>,
+}
+
+impl LogWriter {
+    fn new(logger: &'static dyn log::Log, level: log::Level, target: &str) -> Self {
+        Self {
+            logger,
+            level,
+            target: target.to_string(),
+            line: vec![],
+        }
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+        let line = String::from_utf8_lossy(&self.line);
+        self.logger.log(
+            &log::Record::builder()
+                .level(self.level)
+                .target(&self.target)
+                .args(format_args!("{}", line.trim_end()))
+                .build(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LogWriters;
+    use std::sync::Mutex;
+
+    /// (level, target, message) of the logged records
+    struct Records(Mutex<Vec<(log::Level, String, String)>>);
+
+    impl log::Log for Records {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push((
+                record.level(),
+                record.target().to_string(),
+                record.args().to_string(),
+            ));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn span_fields() {
+        let records: &'static Records = Box::leak(Box::new(Records(Mutex::new(vec![]))));
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(LogWriters(records))
+            .without_time()
+            .with_level(false)
+            .with_target(false)
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let (task_id, client_id) = ("42", "exec");
+            let span = tracing::info_span!("task_execution", %task_id, %client_id);
+            let _entered = span.enter();
+            tracing::warn!(return_code = 1, "Task completed");
+        });
+        assert_eq!(
+            vec![(
+                log::Level::Warn,
+                "funtonic::log_bridge::test".to_string(),
+                "task_execution{task_id=42 client_id=exec}: Task completed return_code=1"
+                    .to_string()
+            )],
+            *records.0.lock().unwrap()
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Hardware & host metas reported by executors in the `system` tag.
+use crate::executor_meta::Tag;
+use std::collections::HashMap;
+
+const MEMINFO: &str = "/proc/meminfo";
+const HOSTNAME: &str = "/proc/sys/kernel/hostname";
+
+/// Values gathered from the host, a failed probe leaves its value empty
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SystemInfo {
+    pub arch: Option<String>,
+    pub cpus: Option<usize>,
+    pub memory_total_mb: Option<u64>,
+    pub hostname: Option<String>,
+}
+
+impl SystemInfo {
+    pub fn probe() -> Self {
+        Self {
+            arch: Some(std::env::consts::ARCH.to_string()),
+            cpus: std::thread::available_parallelism()
+                .map(|cpus| cpus.get())
+                .map_err(|e| warn!("Unable to get the number of CPUs: {}", e))
+                .ok(),
+            memory_total_mb: std::fs::read_to_string(MEMINFO)
+                .map_err(|e| warn!("Unable to read {}: {}", MEMINFO, e))
+                .ok()
+                .and_then(|meminfo| parse_memory_total_mb(&meminfo)),
+            hostname: std::fs::read_to_string(HOSTNAME)
+                .map_err(|e| warn!("Unable to read {}: {}", HOSTNAME, e))
+                .ok()
+                .map(|hostname| hostname.trim().to_string())
+                .filter(|hostname| !hostname.is_empty()),
+        }
+    }
+}
+
+/// `MemTotal` of /proc/meminfo, in MB
+fn parse_memory_total_mb(meminfo: &str) -> Option<u64> {
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .and_then(|value| value.trim().strip_suffix("kB"))
+        .and_then(|kb| kb.trim().parse::<u64>().ok())
+        .map(|kb| kb / 1024)
+}
+
+impl From<SystemInfo> for Tag {
+    fn from(info: SystemInfo) -> Self {
+        let mut tags = HashMap::new();
+        if let Some(arch) = info.arch {
+            tags.insert("arch".to_string(), Tag::Value(arch));
+        }
+        if let Some(cpus) = info.cpus {
+            tags.insert("cpus".to_string(), Tag::Value(cpus.to_string()));
+        }
+        if let Some(memory_total_mb) = info.memory_total_mb {
+            tags.insert(
+                "memory_total_mb".to_string(),
+                Tag::Value(memory_total_mb.to_string()),
+            );
+        }
+        if let Some(hostname) = info.hostname {
+            tags.insert("hostname".to_string(), Tag::Value(hostname));
+        }
+        Tag::Map(tags)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_memory_total_mb, SystemInfo};
+    use crate::executor_meta::Tag;
+    use query_parser::{parse, QueryMatcher};
+    use std::collections::HashMap;
+
+    fn matches(tag: &Tag, query: &str) -> bool {
+        let mut tags = HashMap::new();
+        tags.insert("system".to_string(), tag.clone());
+        tags.qmatches(&parse(query).unwrap()).matches()
+    }
+
+    #[test]
+    fn system_tag() {
+        let tag: Tag = SystemInfo {
+            arch: Some("x86_64".into()),
+            cpus: Some(8),
+            memory_total_mb: Some(15923),
+            hostname: Some("web-1".into()),
+        }
+        .into();
+        assert!(matches(&tag, "system:arch:x86_64"));
+        assert!(matches(&tag, "system:cpus:8"));
+        assert!(matches(&tag, "system:memory_total_mb:15923"));
+        assert!(matches(&tag, "system:hostname:web-1"));
+        assert!(!matches(&tag, "system:arch:aarch64"));
+
+        // failed probes are omitted
+        let tag: Tag = SystemInfo {
+            arch: Some("aarch64".into()),
+            ..Default::default()
+        }
+        .into();
+        match &tag {
+            Tag::Map(map) => assert_eq!(vec!["arch"], map.keys().collect::<Vec<_>>()),
+            _ => panic!("system tag must be a map"),
+        }
+        assert!(matches(&tag, "system:arch:aarch64"));
+        assert!(!matches(&tag, "system:cpus:*"));
+    }
+
+    #[test]
+    fn meminfo() {
+        assert_eq!(
+            Some(15923),
+            parse_memory_total_mb("MemTotal:       16305372 kB\nMemFree:         1024000 kB\n")
+        );
+        assert_eq!(None, parse_memory_total_mb("MemFree:         1024000 kB\n"));
+        assert_eq!(None, parse_memory_total_mb("MemTotal:       lots\n"));
+    }
+}
@@ -1,10 +1,13 @@
+use crate::capabilities::Capabilities;
 use crate::executor_meta::ExecutorMeta;
 use crate::tonic;
 use crate::PROTOCOL_VERSION;
-use futures::channel::mpsc;
+use anyhow::Context;
+use futures::channel::{mpsc, oneshot};
 use futures::{SinkExt, StreamExt};
 use grpc_service::grpc_protocol::launch_task_response::TaskResponse;
-use grpc_service::grpc_protocol::{ExecuteCommand, GetTasksRequest};
+use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+use grpc_service::grpc_protocol::{Empty, ExecuteCommand, GetTasksRequest, TaskExecutionResult};
 use query_parser::{parse, Query, QueryMatcher};
 use rand::Rng;
 use rustbreak::deser::Yaml;
@@ -13,28 +16,59 @@ use serde::Deserialize;
 use serde::Serialize;
 use serde_json::json;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
-use std::fs::File;
-use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
 use thiserror::Error;
+use tokio::task::JoinHandle;
 use tokio::time::Duration;
 use tonic::metadata::{Ascii, MetadataValue};
 use tonic::{Code, Request, Response, Status, Streaming};
+use tracing::{debug, error, info, warn};
 
+mod access_log;
+mod admin_results;
 mod commander_service_impl;
+mod executor_metas;
+mod executor_senders;
 mod executor_service_impl;
+#[cfg(feature = "failpoints")]
+mod failpoints;
+pub mod key_scopes;
+pub mod peer_identity;
+pub mod preflight;
+mod result_tracker;
+mod task_history;
+pub mod task_ids;
 
+use crate::crypto::keygen::fingerprint;
 use crate::crypto::keystore::{
-    file_keystore, memory_keystore, FileKeyStoreBackend, KeyStore, KeyStoreError,
-    MemoryKeyStoreBackend,
+    expires_at_from_secs, file_keystore, validate_keys, FileKeyStoreBackend, KeyStore,
+    KeyStoreError, NonceCache,
 };
 use crate::file_utils::path_concat2;
+use crate::version::Version;
+pub use access_log::{AccessLog, AccessLogEntry, Outcome, DEFAULT_ACCESS_LOG_MAX_BYTES};
+use admin_results::AdminResults;
+pub use admin_results::{result_checksum, AdminResultError};
 pub use commander_service_impl::{
-    AdminDroppedExecutorJsonResponse, AdminListExecutorKeysJsonResponse,
+    AdminAuthorizedKeyJsonResponse, AdminDroppedExecutorJsonResponse, AdminErrorJsonResponse,
+    AdminListExecutorKeysJsonResponse, AdminRejectedExecutorKeysJsonResponse, AdminRequestError,
+    AdminRevokedExecutorKeyJsonResponse, AdminRunningTaskJsonResponse, AdminVerifyTaskJsonResponse,
+    AdminWhoAmIJsonResponse,
 };
+use executor_metas::{ExecutorMetaDatabase, ExecutorMetas};
+pub use executor_senders::{ExecutorSender, ExecutorSenders};
 use grpc_service::payload::SignedPayload;
+use key_scopes::KeyScopes;
+use peer_identity::PeerIdentity;
+use result_tracker::ResultTracker;
+use task_history::{ArchivedKey, ExecutorKeyArchive, ExecutorKeyConflicts, TaskHistory};
+pub use task_history::{
+    KeyConflict, PayloadVerificationReport, VerifiedWith, DEFAULT_TASK_HISTORY_MAX_ENTRIES,
+};
+use task_ids::{RandomTaskIds, TaskIdGenerator};
 
 #[derive(Debug, Error)]
 pub enum TaskServerError {
@@ -44,41 +78,127 @@ pub enum TaskServerError {
     DatabaseError(#[from] rustbreak::RustbreakError),
     #[error("Internal key store error {0}")]
     KeyStoreError(#[from] KeyStoreError),
+    #[error("{0} already connected from elsewhere")]
+    AlreadyConnected(String),
 }
 
 impl From<TaskServerError> for Status {
     fn from(e: TaskServerError) -> Self {
-        Status::internal(e.to_string())
+        match e {
+            TaskServerError::AlreadyConnected(_) => {
+                Status::already_exists("client_id already connected from elsewhere")
+            }
+            e => Status::internal(e.to_string()),
+        }
     }
 }
 
-type ExecutorMetaDatabase = HashMap<String, ExecutorMeta>;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+pub const DEFAULT_MAX_ADMIN_RESPONSE_BYTES: u64 = 3 * 1024 * 1024;
+pub const DEFAULT_TASK_SINK_TTL_SECS: u64 = 3600;
+pub const DEFAULT_STREAM_BUFFER_SIZE: usize = 1024;
+/// Message of the PermissionDenied status returned to the executors whose key awaits an admin
+/// approval, told apart from the other PermissionDenied statuses by the executors
+pub const PENDING_APPROVAL_MESSAGE: &str = "executor key pending approval";
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Sink where the execution of a task is reported, along with the executor it was dispatched to
+struct TaskSink {
+    client_id: String,
+    sender: mpsc::Sender<TaskResponse>,
+    registered_at: Instant,
+}
+
+type TaskSinks = Mutex<HashMap<String, TaskSink>>;
+
+/// Tags overridden by admins, by client_id then tag path; a `None` value removes the tag.
+///
+/// Overrides are applied in path order, a tag before the tags nested in it: overriding a tag
+/// forgets the overrides of its nested tags, which are replaced along with it.
+type TagOverridesDatabase = BTreeMap<String, BTreeMap<String, Option<String>>>;
 
 #[derive(Clone)]
 pub struct TaskServer {
     /// executors by id: when a task must be submited to an executor,
     /// a Sender is sent to each matching executor
-    executors: Arc<
-        Mutex<
-            HashMap<
-                String,
-                mpsc::UnboundedSender<(SignedPayload, mpsc::UnboundedSender<TaskResponse>)>,
-            >,
-        >,
-    >,
+    executors: Arc<ExecutorSenders>,
 
     /// by task id, sinks where executors reports task execution
-    tasks_sinks: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<TaskResponse>>>>,
+    tasks_sinks: Arc<TaskSinks>,
+
+    /// by task id, cancellation triggers of the tasks whose execution is being reported
+    task_cancellations: Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>,
+
+    /// results already forwarded to the commanders, to drop the ones sent again by executors
+    task_results: Arc<ResultTracker>,
+
+    /// sinks of the tasks whose results are not reported are dropped after this delay
+    task_sink_ttl: Duration,
 
-    executor_meta_database: Arc<FileDatabase<ExecutorMetaDatabase, Yaml>>,
+    /// capacity of the channels streaming tasks to executors & responses to commanders: slow
+    /// commanders apply backpressure up to the executors instead of having responses buffered
+    stream_buffer_size: usize,
 
-    authorized_keys: Arc<KeyStore<MemoryKeyStoreBackend>>,
+    executor_meta_database: Arc<ExecutorMetas>,
 
-    authorized_admin_keys: Arc<KeyStore<MemoryKeyStoreBackend>>,
+    /// registrations rejected while another executor was connected with the same client_id, by
+    /// client_id
+    duplicate_registrations: Arc<Mutex<HashMap<String, u64>>>,
+    /// such registrations replace the connected executor instead
+    allow_takeover: bool,
+
+    /// keys of unknown executors are trusted on first use
+    auto_approve_executor_keys: bool,
+
+    /// stored apart from the executor metas which are replaced on each registration
+    tag_overrides: Arc<FileDatabase<TagOverridesDatabase, Yaml>>,
+
+    /// seeded from the configuration, then completed by executors & admin requests
+    authorized_keys: Arc<KeyStore<FileKeyStoreBackend>>,
+
+    /// ids of the authorized keys removed by admins: neither the executors reporting them nor the
+    /// configuration add them again
+    removed_authorized_keys: Arc<FileDatabase<BTreeSet<String>, Yaml>>,
+
+    authorized_admin_keys: Arc<KeyStore<FileKeyStoreBackend>>,
 
     trusted_executor_keystore: Arc<KeyStore<FileKeyStoreBackend>>,
 
     unapproved_executor_keystore: Arc<KeyStore<FileKeyStoreBackend>>,
+
+    /// executor keys replaced by a newly approved one, kept to verify old signatures
+    executor_key_archive: Arc<FileDatabase<ExecutorKeyArchive, Yaml>>,
+
+    /// client_ids of the executors whose key was revoked, kept once they are dropped: another key
+    /// is never trusted on first use for them
+    revoked_executor_keys: Arc<FileDatabase<BTreeSet<String>, Yaml>>,
+
+    /// keys presented by executors whose trusted key is another one
+    executor_key_conflicts: Arc<FileDatabase<ExecutorKeyConflicts, Yaml>>,
+
+    /// signed terminal task results, only present if signatures are retained
+    task_history: Option<Arc<TaskHistory>>,
+
+    /// admin responses larger than `max_admin_response_bytes`, fetched by chunks
+    admin_results: Arc<AdminResults>,
+    max_admin_response_bytes: u64,
+    /// the admin requests are only served by the admin listener, not through the deprecated
+    /// CommanderService alias
+    separate_admin_listener: bool,
+
+    task_ids: Arc<dyn TaskIdGenerator>,
+
+    /// identity of the connected commanders & executors, as reported in the logs
+    peer_identity: Arc<PeerIdentity>,
+
+    /// signed requests received from the commanders, if configured
+    access_log: Option<Arc<AccessLog>>,
+
+    /// executors the scoped commander keys are allowed to target
+    key_scopes: Arc<KeyScopes>,
+
+    #[cfg(feature = "failpoints")]
+    failpoints: Arc<failpoints::Failpoints>,
 }
 
 impl TaskServer {
@@ -86,65 +206,329 @@ impl TaskServer {
         database_dir: P,
         authorized_keys: &BTreeMap<String, String>,
         admin_authorized_keys: &BTreeMap<String, String>,
+        retain_signatures: bool,
+        allowed_clock_skew: Duration,
     ) -> Result<Self, anyhow::Error> {
-        let database_path = path_concat2(&database_dir, "known_executors.yml");
-        if !database_path.exists() {
-            let mut empty = File::create(&database_path)?;
-            empty.write("---\n{}".as_bytes())?;
-        }
-
-        let db = FileDatabase::from_path(database_path, Default::default())?;
-        db.load()?;
+        // the configured keys are only added when missing from the databases, but a mistake is
+        // reported on each start
+        validate_keys(authorized_keys).context("Invalid authorized_keys")?;
+        validate_keys(admin_authorized_keys).context("Invalid admin_authorized_keys")?;
+        // shared by all the keystores: a payload is accepted once, whatever the service
+        let nonces = Arc::new(NonceCache::with_allowed_clock_skew(allowed_clock_skew));
+        let removed_authorized_keys: FileDatabase<BTreeSet<String>, Yaml> =
+            open_database(path_concat2(&database_dir, "removed_authorized_keys.yml"))?;
+        let removed_key_ids = removed_authorized_keys.read(|removed| removed.clone())?;
+        let admin_keys_path = path_concat2(&database_dir, "admin_authorized_keys.yml");
+        let authorized_admin_keys = seeded_file_keystore(
+            admin_keys_path.clone(),
+            admin_authorized_keys,
+            &BTreeSet::new(),
+        )?;
+        warn_unconfigured_keys(
+            &authorized_admin_keys,
+            admin_authorized_keys,
+            &admin_keys_path,
+        )?;
         Ok(TaskServer {
-            executors: Arc::new(Mutex::new(HashMap::new())),
+            executors: Arc::new(ExecutorSenders::default()),
             tasks_sinks: Arc::new(Mutex::new(HashMap::new())),
-            executor_meta_database: Arc::new(db),
-            authorized_keys: Arc::new(memory_keystore().init_from_map(authorized_keys)?),
+            task_cancellations: Arc::new(Mutex::new(HashMap::new())),
+            task_results: Arc::new(ResultTracker::default()),
+            task_sink_ttl: Duration::from_secs(DEFAULT_TASK_SINK_TTL_SECS),
+            stream_buffer_size: DEFAULT_STREAM_BUFFER_SIZE,
+            executor_meta_database: Arc::new(ExecutorMetas::open(path_concat2(
+                &database_dir,
+                "known_executors.yml",
+            ))?),
+            duplicate_registrations: Default::default(),
+            allow_takeover: false,
+            auto_approve_executor_keys: false,
+            tag_overrides: Arc::new(open_database(path_concat2(
+                &database_dir,
+                "executor_tag_overrides.yml",
+            ))?),
+            authorized_keys: Arc::new(
+                seeded_file_keystore(
+                    path_concat2(&database_dir, "authorized_keys.yml"),
+                    authorized_keys,
+                    &removed_key_ids,
+                )?
+                .with_write_behind()
+                .with_nonce_cache(nonces.clone())
+                .with_allowed_clock_skew(allowed_clock_skew),
+            ),
+            removed_authorized_keys: Arc::new(removed_authorized_keys),
             authorized_admin_keys: Arc::new(
-                memory_keystore().init_from_map(admin_authorized_keys)?,
+                authorized_admin_keys
+                    .with_nonce_cache(nonces.clone())
+                    .with_allowed_clock_skew(allowed_clock_skew),
+            ),
+            trusted_executor_keystore: Arc::new(
+                file_keystore(path_concat2(&database_dir, "trusted_executors_keys.yml"))?
+                    .with_write_behind()
+                    .with_nonce_cache(nonces)
+                    .with_allowed_clock_skew(allowed_clock_skew),
+            ),
+            unapproved_executor_keystore: Arc::new(
+                file_keystore(path_concat2(&database_dir, "unapproved_executors_keys.yml"))?
+                    .with_write_behind(),
             ),
-            trusted_executor_keystore: Arc::new(file_keystore(path_concat2(
+            executor_key_archive: Arc::new(open_database(path_concat2(
                 &database_dir,
-                "trusted_executors_keys.yml",
+                "archived_executors_keys.yml",
             ))?),
-            unapproved_executor_keystore: Arc::new(file_keystore(path_concat2(
+            revoked_executor_keys: Arc::new(open_database(path_concat2(
                 &database_dir,
-                "unapproved_executors_keys.yml",
+                "revoked_executors_keys.yml",
             ))?),
+            executor_key_conflicts: Arc::new(open_database(path_concat2(
+                &database_dir,
+                "conflicting_executors_keys.yml",
+            ))?),
+            task_history: if retain_signatures {
+                Some(Arc::new(TaskHistory::open(path_concat2(
+                    &database_dir,
+                    "task_history.yml",
+                ))?))
+            } else {
+                None
+            },
+            admin_results: Arc::new(AdminResults::new(path_concat2(
+                &database_dir,
+                "admin_results",
+            ))?),
+            max_admin_response_bytes: DEFAULT_MAX_ADMIN_RESPONSE_BYTES,
+            separate_admin_listener: false,
+            task_ids: Arc::new(RandomTaskIds),
+            peer_identity: Default::default(),
+            access_log: None,
+            key_scopes: Default::default(),
+            #[cfg(feature = "failpoints")]
+            failpoints: Default::default(),
         })
     }
 
-    pub fn start_heartbeat(&self) {
-        tokio::spawn(heartbeat(self.executors.clone()));
+    /// Larger admin responses are kept & fetched by chunks of at most this size
+    pub fn with_max_admin_response_bytes(mut self, max_admin_response_bytes: u64) -> Self {
+        self.max_admin_response_bytes = max_admin_response_bytes;
+        self
     }
 
-    fn get_channels_to_matching_executors(
+    /// Admin requests sent through the deprecated CommanderService alias are refused: the
+    /// AdminService is served on its own listener
+    pub fn with_separate_admin_listener(mut self) -> Self {
+        self.separate_admin_listener = true;
+        self
+    }
+
+    /// An executor registering while another one is connected with the same client_id replaces
+    /// it instead of being rejected
+    pub fn with_takeover(mut self) -> Self {
+        self.allow_takeover = true;
+        self
+    }
+
+    /// The key of an executor registering for the first time is trusted without approval
+    pub fn with_auto_approved_executor_keys(mut self) -> Self {
+        self.auto_approve_executor_keys = true;
+        self
+    }
+
+    /// Random task ids by default, tests may want predictable ones
+    pub fn with_task_id_generator(mut self, task_ids: impl TaskIdGenerator + 'static) -> Self {
+        self.task_ids = Arc::new(task_ids);
+        self
+    }
+
+    /// How peers are identified, eg: from a header set by a trusted reverse proxy
+    pub fn with_peer_identity(mut self, peer_identity: PeerIdentity) -> Self {
+        self.peer_identity = Arc::new(peer_identity);
+        self
+    }
+
+    /// Record the signed requests of the commanders, accepted or not
+    pub fn with_access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = Some(Arc::new(access_log));
+        self
+    }
+
+    /// Restrict the executors the scoped commander keys may target
+    pub fn with_key_scopes(mut self, key_scopes: KeyScopes) -> Self {
+        self.key_scopes = Arc::new(key_scopes);
+        self
+    }
+
+    /// At most this many entries are kept in the task history if signatures are retained, the
+    /// oldest are dropped
+    pub fn with_task_history_max_entries(self, max_entries: usize) -> Self {
+        if let Some(task_history) = &self.task_history {
+            task_history.set_max_entries(max_entries);
+        }
+        self
+    }
+
+    /// Tasks dispatched longer ago whose results are not reported are considered lost
+    pub fn with_task_sink_ttl(mut self, task_sink_ttl: Duration) -> Self {
+        self.task_sink_ttl = task_sink_ttl;
+        self
+    }
+
+    /// Messages buffered by each task & response stream before the sender waits
+    pub fn with_stream_buffer_size(mut self, stream_buffer_size: usize) -> Self {
+        self.stream_buffer_size = stream_buffer_size;
+        self
+    }
+
+    pub fn start_heartbeat(&self) -> JoinHandle<()> {
+        let task_server = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+                debug!("Checking connected executor health");
+                task_server.reap_stale_task_sinks();
+            }
+        })
+    }
+
+    /// Drop the sinks of the tasks dispatched more than `task_sink_ttl` ago whose results are not
+    /// being reported, returns the count of dropped sinks
+    fn reap_stale_task_sinks(&self) -> usize {
+        let stale = remove_task_sinks(&self.tasks_sinks, |task_id, sink| {
+            sink.registered_at.elapsed() > self.task_sink_ttl
+                && !self.task_results.is_reported(task_id)
+        });
+        let count = stale.len();
+        for (task_id, sink) in stale {
+            warn!(
+                %task_id, client_id = %sink.client_id,
+                "Results of the task not reported after {:?}, dropping it", self.task_sink_ttl
+            );
+            self.report_task_disconnected(task_id, sink);
+        }
+        count
+    }
+
+    /// Tell the commander a task will never be reported, unless it is already completed
+    fn report_task_disconnected(&self, task_id: String, sink: TaskSink) {
+        if self.task_results.is_completed(&task_id) {
+            return;
+        }
+        // results still coming from a dying stream are dropped
+        self.task_results.complete(&task_id);
+        self.task_results.forget(&task_id);
+        // the sink has never been used to send: its slot in the channel is free
+        let mut sender = sink.sender;
+        let _ = sender.try_send(TaskResponse::TaskExecutionResult(TaskExecutionResult {
+            task_id,
+            client_id: sink.client_id,
+            execution_result: Some(ExecutionResult::Disconnected(Empty {})),
+            seq: 0,
+        }));
+    }
+
+    /// Periodically persist the executor keys, metas & task results recorded since the last save
+    pub fn start_flush(&self) -> JoinHandle<()> {
+        let task_server = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(FLUSH_INTERVAL).await;
+                let task_server = task_server.clone();
+                // the files are written & the access log writer waited for: off the tokio workers
+                if let Ok(Err(e)) = tokio::task::spawn_blocking(move || task_server.flush()).await {
+                    error!("Unable to save executors: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Persist the executor keys & metas registered since the last save, the recorded task results
+    /// & the pending access log entries, must be called on shutdown. Blocking.
+    pub fn flush(&self) -> Result<(), TaskServerError> {
+        if let Some(access_log) = &self.access_log {
+            access_log.sync();
+        }
+        self.executor_meta_database.flush()?;
+        if let Some(task_history) = &self.task_history {
+            task_history.flush()?;
+        }
+        self.unapproved_executor_keystore.flush()?;
+        self.trusted_executor_keystore.flush()?;
+        Ok(self.authorized_keys.flush()?)
+    }
+
+    /// End the task streams of the connected executors: they never end otherwise, a graceful
+    /// shutdown would wait for them forever
+    pub fn close_executor_streams(&self) {
+        info!(
+            "Closing the task streams of {} executor(s)",
+            self.executors.len()
+        );
+        self.executors.close_all();
+    }
+
+    /// Reopen the access log file, eg: after it has been rotated
+    pub fn reopen_access_log(&self) {
+        if let Some(access_log) = &self.access_log {
+            access_log.reopen();
+        }
+    }
+
+    /// The entry is only built if the access log is enabled
+    fn log_access(&self, entry: impl FnOnce() -> AccessLogEntry) {
+        if let Some(access_log) = &self.access_log {
+            access_log.record(entry());
+        }
+    }
+
+    /// Register a running task, the returned receiver completes when the task is cancelled
+    fn register_task_cancellation(&self, task_id: &str) -> oneshot::Receiver<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.task_cancellations
+            .lock()
+            .unwrap()
+            .insert(task_id.to_string(), sender);
+        receiver
+    }
+
+    fn unregister_task_cancellation(&self, task_id: &str) {
+        self.task_cancellations.lock().unwrap().remove(task_id);
+    }
+
+    /// Returns false if the task is not running (unknown or already completed)
+    fn cancel_task(&self, task_id: &str) -> bool {
+        match self.task_cancellations.lock().unwrap().remove(task_id) {
+            Some(cancellation) => cancellation.send(()).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Apply `f` to the metas of all known executors matching the query
+    fn map_matching_executors<T>(
         &self,
         query: &Query,
-    ) -> Result<
-        Vec<(
-            String,
-            Option<mpsc::UnboundedSender<(SignedPayload, mpsc::UnboundedSender<TaskResponse>)>>,
-        )>,
-        TaskServerError,
-    > {
-        let client_ids: Vec<String> = self.executor_meta_database.read(|executors| {
+        f: impl Fn(&ExecutorMeta) -> T,
+    ) -> Result<Vec<T>, TaskServerError> {
+        Ok(self.executor_meta_database.read(|executors| {
             executors
-                .iter()
-                .filter(|(_client_id, meta)| meta.qmatches(query).matches())
-                .map(|(client_id, _meta)| client_id.clone())
+                .values()
+                .filter(|meta| meta.qmatches(query).matches())
+                .map(f)
                 .collect()
-        })?;
+        })?)
+    }
 
-        let executor_senders = self.executors.lock().unwrap();
+    /// Senders of the matching executors, along with their metas
+    fn get_channels_to_matching_executors(
+        &self,
+        query: &Query,
+    ) -> Result<Vec<(ExecutorMeta, Option<ExecutorSender>)>, TaskServerError> {
         // find matching senders, clone them
-        Ok(client_ids
+        Ok(self
+            .map_matching_executors(query, ExecutorMeta::clone)?
             .into_iter()
-            .map(|client_id| {
-                let executor_sender = executor_senders
-                    .get(&client_id)
-                    .map(|executor_sender| executor_sender.clone());
-                (client_id, executor_sender)
+            .map(|meta| {
+                let executor_sender = self.executors.get(meta.client_id());
+                (meta, executor_sender)
             })
             .collect())
     }
@@ -152,19 +536,113 @@ impl TaskServer {
     fn register_executor(
         &self,
         request: &GetTasksRequest,
-        sender_to_get_task_response: mpsc::UnboundedSender<(
-            SignedPayload,
-            mpsc::UnboundedSender<TaskResponse>,
-        )>,
+        sender_to_get_task_response: ExecutorSender,
     ) -> Result<(), TaskServerError> {
-        let executor_meta: ExecutorMeta = request.into();
+        if self.connected_elsewhere(request) {
+            if !self.allow_takeover {
+                let count = {
+                    let mut duplicate_registrations = self.duplicate_registrations.lock().unwrap();
+                    let count = duplicate_registrations
+                        .entry(request.client_id.clone())
+                        .or_default();
+                    *count += 1;
+                    *count
+                };
+                warn!(
+                    client_id = %request.client_id, instance_id = %request.instance_id,
+                    "Registration rejected, another executor is connected with this client_id \
+                     ({} duplicate registrations)",
+                    count
+                );
+                return Err(TaskServerError::AlreadyConnected(request.client_id.clone()));
+            }
+            warn!(
+                client_id = %request.client_id, instance_id = %request.instance_id,
+                "Executor taken over by another one registering with this client_id"
+            );
+        }
+        if let Some(mut previous) = self
+            .executors
+            .insert(request.client_id.clone(), sender_to_get_task_response)
+        {
+            // end the task stream of the previous connection
+            previous.close_channel();
+        }
+        // the outdated executors are listed by `admin list-connected-executors --outdated`
+        if is_outdated(&request.client_version) {
+            warn!(
+                client_id = %request.client_id, version = %request.client_version,
+                "Executor older than the taskserver v{}",
+                crate::VERSION
+            );
+        }
 
-        self.executors.lock().unwrap().insert(
-            executor_meta.client_id().to_string(),
-            sender_to_get_task_response,
-        );
+        self.store_executor_meta(request, true)
+    }
+
+    /// Whether another executor is connected with this client_id: its task stream is still open.
+    ///
+    /// The executor installation registering again (same instance id) is not another executor:
+    /// its previous connection is half-open (host crash, NAT drop...) and is replaced.
+    fn connected_elsewhere(&self, request: &GetTasksRequest) -> bool {
+        let open = self
+            .executors
+            .get(&request.client_id)
+            .map(|sender| !sender.is_closed())
+            .unwrap_or(false);
+        if !open || request.instance_id.is_empty() {
+            return open;
+        }
+        let same_instance = self
+            .executor_meta_database
+            .read(|executors| {
+                executors
+                    .get(&request.client_id)
+                    .and_then(|meta| meta.instance_id())
+                    == Some(request.instance_id.as_str())
+            })
+            .unwrap_or(false);
+        !same_instance
+    }
+
+    /// Registrations rejected while another executor was connected with this client_id, since
+    /// the taskserver started
+    pub fn duplicate_registrations(&self, client_id: &str) -> u64 {
+        self.duplicate_registrations
+            .lock()
+            .unwrap()
+            .get(client_id)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Store the executor metas & the keys it authorizes, replacing previously stored ones.
+    ///
+    /// The connection time is set to now on `connection`, kept from the stored metas otherwise.
+    /// Tags overridden by admins take precedence over the reported ones.
+    fn store_executor_meta(
+        &self,
+        request: &GetTasksRequest,
+        connection: bool,
+    ) -> Result<(), TaskServerError> {
+        let mut executor_meta: ExecutorMeta = request.into();
+        if let Some(overrides) = self
+            .tag_overrides
+            .read(|overrides| overrides.get(&request.client_id).cloned())?
+        {
+            for (path, value) in &overrides {
+                executor_meta.override_tag(path, value.as_deref());
+            }
+        }
 
         self.executor_meta_database.write(move |executors| {
+            executor_meta.set_connected_at(if connection {
+                Some(chrono::Local::now().to_rfc3339())
+            } else {
+                executors
+                    .get(executor_meta.client_id())
+                    .and_then(|previous| previous.connected_at().map(str::to_string))
+            });
             info!(
                 "Registered {}",
                 serde_yaml::to_string(&executor_meta).unwrap_or("???".to_string())
@@ -173,20 +651,56 @@ impl TaskServer {
         })?;
 
         for public_key in &request.authorized_keys {
-            self.authorized_keys
-                .register_key(&public_key.key_id, public_key.key_bytes.clone())?;
+            // executors report their keys on each registration, most of them are already known:
+            // the stored keys are never replaced by the reported ones
+            let key_id = &public_key.key_id;
+            if self
+                .removed_authorized_keys
+                .read(|removed| removed.contains(key_id))?
+            {
+                debug!(client_id = %request.client_id, "Ignoring reported key {}: removed", key_id);
+                continue;
+            }
+            match self.authorized_keys.get_key(key_id)? {
+                Some(stored) if stored != public_key.key_bytes => warn!(
+                    client_id = %request.client_id,
+                    "Ignoring reported key {}: another key is stored with this id", key_id
+                ),
+                Some(_) => {}
+                None => match self.authorized_keys.register_key_with_expiry(
+                    key_id,
+                    public_key.key_bytes.clone(),
+                    expires_at_from_secs(public_key.expires_at_secs),
+                ) {
+                    // executors not validating their configuration do not prevent registration
+                    Err(e @ KeyStoreError::InvalidKeyMaterial(..)) => {
+                        warn!(client_id = %request.client_id, "Ignoring reported key: {}", e)
+                    }
+                    result => result?,
+                },
+            }
         }
 
-        Ok(self.executor_meta_database.save()?)
+        Ok(())
     }
 
-    fn get_running_tasks(&self) -> Result<Vec<String>, TaskServerError> {
+    fn get_running_tasks(
+        &self,
+    ) -> Result<BTreeMap<String, AdminRunningTaskJsonResponse>, TaskServerError> {
         Ok(self
             .tasks_sinks
             .lock()
             .map_err(|_e| TaskServerError::LockError)?
             .iter()
-            .map(|(task_id, _)| task_id.clone())
+            .map(|(task_id, sink)| {
+                (
+                    task_id.clone(),
+                    AdminRunningTaskJsonResponse {
+                        client_id: sink.client_id.clone(),
+                        age_secs: sink.registered_at.elapsed().as_secs(),
+                    },
+                )
+            })
             .collect())
     }
 
@@ -204,81 +718,863 @@ impl TaskServer {
         Ok(self.executor_meta_database.write(write_function)?)
     }
 
-    /// Handle executor public key.
-    ///
-    /// If the key is known and authorized, does nothing.
+    /// Trust the commander key, even if it has been removed before. Admin modifications are never
+    /// left to the write-behind.
+    fn add_authorized_key(
+        &self,
+        key_id: &str,
+        key_bytes: Vec<u8>,
+        expires_at: Option<SystemTime>,
+    ) -> Result<(), KeyStoreError> {
+        self.authorized_keys
+            .register_key_with_expiry(key_id, key_bytes, expires_at)?;
+        self.authorized_keys.flush()?;
+        if self
+            .removed_authorized_keys
+            .write(|removed| removed.remove(key_id))?
+        {
+            self.removed_authorized_keys.save()?;
+        }
+        Ok(())
+    }
+
+    /// Stop trusting the commander key, recorded first so that an executor registering
+    /// concurrently does not add it again
+    fn remove_authorized_key(&self, key_id: &str) -> Result<(), KeyStoreError> {
+        if self
+            .removed_authorized_keys
+            .write(|removed| removed.insert(key_id.to_string()))?
+        {
+            self.removed_authorized_keys.save()?;
+        }
+        self.authorized_keys.remove_key(key_id)?;
+        self.authorized_keys.flush()
+    }
+
+    /// Handle executor public key, returns true if the key is approved.
     ///
-    /// If the key is not known for this client_id, register it in unapproved_executor_keystore
-    fn handle_executor_key(&self, client_id: &str, key_bytes: &[u8]) -> Result<(), KeyStoreError> {
-        if !self
+    /// If the key is not known for this client_id, register it in unapproved_executor_keystore,
+    /// unless the executor itself is unknown & keys are trusted on first use. A key other than
+    /// the trusted one is recorded as a conflict.
+    fn handle_executor_key(
+        &self,
+        client_id: &str,
+        key_bytes: &[u8],
+    ) -> Result<bool, KeyStoreError> {
+        if self
             .trusted_executor_keystore
             .has_key(client_id, key_bytes)?
-            && !self
-                .unapproved_executor_keystore
-                .has_key(client_id, key_bytes)?
+        {
+            return Ok(true);
+        }
+        if let Some(trusted_key) = self.trusted_executor_keystore.get_key(client_id)? {
+            self.record_key_conflict(client_id, key_bytes, &trusted_key)?;
+        }
+        if self.auto_approve_executor_keys && !self.is_known_executor(client_id)? {
+            warn!(
+                "Trusting key {} of new executor {} on first use (auto_approve_executor_keys)",
+                fingerprint(key_bytes),
+                client_id
+            );
+            self.trusted_executor_keystore
+                .register_key(client_id, key_bytes.to_vec())?;
+            self.trusted_executor_keystore.flush()?;
+            return Ok(true);
+        }
+        if !self
+            .unapproved_executor_keystore
+            .has_key(client_id, key_bytes)?
         {
             self.unapproved_executor_keystore
-                .register_key(client_id, key_bytes.to_vec())
-        } else {
-            Ok(())
+                .register_key(client_id, key_bytes.to_vec())?;
         }
+        Ok(false)
     }
 
-    fn approve_executor_key(&self, client_id: &str) -> Result<(), KeyStoreError> {
-        self.trusted_executor_keystore.register_key(
-            client_id,
-            self.unapproved_executor_keystore.remove_key(client_id)?,
+    /// An executor having a trusted, pending, archived or revoked key, or which registered once:
+    /// another key must not be trusted on first use. Dropping an executor does not make it unknown.
+    fn is_known_executor(&self, client_id: &str) -> Result<bool, KeyStoreError> {
+        Ok(self.trusted_executor_keystore.get_key(client_id)?.is_some()
+            || self
+                .unapproved_executor_keystore
+                .get_key(client_id)?
+                .is_some()
+            || self
+                .executor_meta_database
+                .read(|executors| executors.contains_key(client_id))?
+            || self
+                .executor_key_archive
+                .read(|archive| archive.contains_key(client_id))?
+            || self
+                .revoked_executor_keys
+                .read(|revoked| revoked.contains(client_id))?)
+    }
+
+    fn record_key_conflict(
+        &self,
+        client_id: &str,
+        key_bytes: &[u8],
+        trusted_key: &[u8],
+    ) -> Result<(), KeyStoreError> {
+        let trusted_fingerprint = fingerprint(trusted_key);
+        let key = data_encoding::BASE64.encode(key_bytes);
+        let trusted_key = data_encoding::BASE64.encode(trusted_key);
+        let recorded = self.executor_key_conflicts.write(|conflicts| {
+            match conflicts.get(client_id) {
+                // executors retry: the conflict is only reported once
+                Some(conflict) if conflict.key == key && conflict.trusted_key == trusted_key => {
+                    false
+                }
+                _ => {
+                    conflicts.insert(
+                        client_id.to_string(),
+                        KeyConflict {
+                            key: key.clone(),
+                            trusted_key: trusted_key.clone(),
+                            detected_at: chrono::Local::now().to_rfc3339(),
+                        },
+                    );
+                    true
+                }
+            }
+        })?;
+        if recorded {
+            error!(
+                "{} presented key {} but its trusted key is {}: reinstalled host or impersonation? \
+                 The new key must be approved with --force to replace the trusted one",
+                client_id,
+                fingerprint(key_bytes),
+                trusted_fingerprint
+            );
+            self.executor_key_conflicts.save()?;
+        }
+        Ok(())
+    }
+
+    /// The pending key of the executor would replace its trusted key
+    fn has_key_conflict(&self, client_id: &str) -> Result<bool, KeyStoreError> {
+        Ok(
+            match (
+                self.trusted_executor_keystore.get_key(client_id)?,
+                self.unapproved_executor_keystore.get_key(client_id)?,
+            ) {
+                (Some(trusted_key), Some(pending_key)) => trusted_key != pending_key,
+                _ => false,
+            },
         )
     }
 
+    fn forget_key_conflict(&self, client_id: &str) -> Result<(), KeyStoreError> {
+        if self
+            .executor_key_conflicts
+            .write(|conflicts| conflicts.remove(client_id))?
+            .is_some()
+        {
+            self.executor_key_conflicts.save()?;
+        }
+        Ok(())
+    }
+
+    fn list_executor_key_conflicts(&self) -> Result<ExecutorKeyConflicts, KeyStoreError> {
+        Ok(self
+            .executor_key_conflicts
+            .read(|conflicts| conflicts.clone())?)
+    }
+
+    /// Trust the pending key of the executor, replacing its trusted key if any: the callers check
+    /// [`Self::has_key_conflict`] unless the replacement is forced
+    fn approve_executor_key(&self, client_id: &str) -> Result<(), KeyStoreError> {
+        let key = self.unapproved_executor_keystore.remove_key(client_id)?;
+        if let Some(replaced_key) = self.trusted_executor_keystore.get_key(client_id)? {
+            if replaced_key != key {
+                info!("Archiving replaced key of {}", client_id);
+                self.executor_key_archive.write(|archive| {
+                    archive
+                        .entry(client_id.to_string())
+                        .or_default()
+                        .push(ArchivedKey {
+                            key: data_encoding::BASE64.encode(&replaced_key),
+                            replaced_at: chrono::Local::now().to_rfc3339(),
+                        })
+                })?;
+                self.executor_key_archive.save()?;
+            }
+        }
+        self.trusted_executor_keystore
+            .register_key(client_id, key)?;
+        // approvals are never left to the write-behind
+        self.trusted_executor_keystore.flush()?;
+        self.forget_key_conflict(client_id)
+    }
+
+    /// Forget a pending executor key, this is not a ban: the key is registered again as unapproved
+    /// the next time the executor tries to register
+    fn reject_executor_key(&self, client_id: &str) -> Result<(), KeyStoreError> {
+        self.unapproved_executor_keystore.remove_key(client_id)?;
+        self.forget_key_conflict(client_id)?;
+        info!("Pending key of {} rejected", client_id);
+        Ok(())
+    }
+
+    /// Untrust the key of an executor & close its channel: a reconnecting executor lands in the
+    /// unapproved keys. Returns true if the executor was connected.
+    fn revoke_executor_key(&self, client_id: &str) -> Result<bool, KeyStoreError> {
+        // recorded first so that the executor reconnecting meanwhile is not trusted on first use
+        if self
+            .revoked_executor_keys
+            .write(|revoked| revoked.insert(client_id.to_string()))?
+        {
+            self.revoked_executor_keys.save()?;
+        }
+        self.trusted_executor_keystore.remove_key(client_id)?;
+        self.forget_key_conflict(client_id)?;
+        warn!("Key of {} revoked", client_id);
+        Ok(match self.executors.remove(client_id) {
+            Some(mut sender) => {
+                sender.close_channel();
+                true
+            }
+            None => false,
+        })
+    }
+
+    /// Keep the signed payload of a terminal task event, if signatures are retained. A task has a
+    /// single terminal result: returns false if one is already recorded.
+    fn record_signed_result(
+        &self,
+        task_id: &str,
+        client_id: &str,
+        signed_payload: &SignedPayload,
+    ) -> Result<bool, TaskServerError> {
+        let task_history = match &self.task_history {
+            Some(task_history) => task_history,
+            None => return Ok(true),
+        };
+        Ok(task_history.record(task_id, client_id, signed_payload)?)
+    }
+
+    /// Executor the task history tells the task ran on, if signatures are retained
+    fn recorded_executor(&self, task_id: &str) -> Result<Option<String>, TaskServerError> {
+        Ok(match &self.task_history {
+            Some(task_history) => task_history
+                .read(|history| history.get(task_id).map(|entry| entry.client_id.clone()))?,
+            None => None,
+        })
+    }
+
     fn list_trusted_executor_keys(&self) -> Result<BTreeMap<String, String>, KeyStoreError> {
         self.trusted_executor_keystore.list_all()
     }
 
     fn list_unapproved_executor_keys(&self) -> Result<BTreeMap<String, String>, KeyStoreError> {
+        // what an admin sees pending approval is on disk
+        self.unapproved_executor_keystore.flush()?;
         self.unapproved_executor_keystore.list_all()
     }
 }
 
-async fn heartbeat(
-    _executors: Arc<
-        Mutex<
-            HashMap<
-                String,
-                mpsc::UnboundedSender<(SignedPayload, mpsc::UnboundedSender<TaskResponse>)>,
-            >,
-        >,
-    >,
-) {
-    loop {
-        tokio::time::sleep(Duration::from_secs(5)).await;
-        debug!("Checking connected executor health");
+/// Open a file keystore created from the keys of the configuration.
+///
+/// The file prevails afterwards: keys added to the configuration are added to it unless they have
+/// been `removed`, configured keys differing from the stored ones are ignored.
+fn seeded_file_keystore(
+    path: PathBuf,
+    keys: &BTreeMap<String, String>,
+    removed: &BTreeSet<String>,
+) -> Result<KeyStore<FileKeyStoreBackend>, KeyStoreError> {
+    if !path.exists() {
+        info!("Creating {} from the configuration", path.display());
+        return file_keystore(path)?.init_from_map(keys);
+    }
+    let keystore = file_keystore(&path)?;
+    for (key_id, base64_encoded_bytes) in keys {
+        let key_bytes = data_encoding::BASE64.decode(base64_encoded_bytes.as_bytes())?;
+        match keystore.get_key(key_id)? {
+            None if removed.contains(key_id) => {
+                info!(
+                    "Configured key {} has been removed by an admin, ignored",
+                    key_id
+                )
+            }
+            None => {
+                info!("Adding configured key {} to {}", key_id, path.display());
+                keystore.register_key(key_id, key_bytes)?;
+            }
+            Some(stored) if stored != key_bytes => warn!(
+                "Configured key {} differs from the one stored in {}, the stored key is used",
+                key_id,
+                path.display()
+            ),
+            Some(_) => {}
+        }
+    }
+    Ok(keystore)
+}
+
+/// Stored keys are kept when they are removed from the configuration: no admin command removes
+/// admin keys, so the ones still trusted are reported on each start
+fn warn_unconfigured_keys(
+    keystore: &KeyStore<FileKeyStoreBackend>,
+    keys: &BTreeMap<String, String>,
+    path: &Path,
+) -> Result<(), KeyStoreError> {
+    for key_id in keystore.list_all()?.keys() {
+        if !keys.contains_key(key_id) {
+            warn!(
+                "Key {} is no longer configured but is still authorized: remove it from {} to revoke it",
+                key_id,
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Older than the taskserver, executors reporting an unparsable version are not
+fn is_outdated(client_version: &str) -> bool {
+    match (
+        client_version.parse::<Version>(),
+        crate::VERSION.parse::<Version>(),
+    ) {
+        (Ok(client_version), Ok(version)) => client_version < version,
+        _ => false,
+    }
+}
+
+/// Open a yaml database, creating an empty one if the file does not exist
+fn open_database<T, P>(path: P) -> Result<FileDatabase<T, Yaml>, rustbreak::RustbreakError>
+where
+    T: Serialize + serde::de::DeserializeOwned + Clone + Send + Default,
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let initialize_db = !path.exists();
+    let db = FileDatabase::from_path(path, T::default())?;
+    if initialize_db {
+        db.save()?;
+    } else {
+        db.load()?;
     }
+    Ok(db)
 }
 
 fn register_new_task(
-    tasks_sinks: &Mutex<HashMap<String, mpsc::UnboundedSender<TaskResponse>>>,
-    sender_to_commander: mpsc::UnboundedSender<TaskResponse>,
+    tasks_sinks: &TaskSinks,
+    task_ids: &dyn TaskIdGenerator,
+    client_id: &str,
+    sender_to_commander: mpsc::Sender<TaskResponse>,
 ) -> String {
-    let task_id = random_task_id();
+    let task_id = task_ids.next_id();
+    tasks_sinks.lock().unwrap().insert(
+        task_id.clone(),
+        TaskSink {
+            client_id: client_id.to_string(),
+            sender: sender_to_commander,
+            registered_at: Instant::now(),
+        },
+    );
+    task_id
+}
+
+/// The sink is kept until the task completes: executors may report the results again on a new
+/// stream
+fn get_task_sink(tasks_sinks: &TaskSinks, task_id: &str) -> Option<mpsc::Sender<TaskResponse>> {
     tasks_sinks
         .lock()
         .unwrap()
-        .insert(task_id.clone(), sender_to_commander);
-    task_id
+        .get(task_id)
+        .map(|sink| sink.sender.clone())
 }
 
-fn get_task_sink(
-    tasks_sinks: &Mutex<HashMap<String, mpsc::UnboundedSender<TaskResponse>>>,
-    task_id: &str,
-) -> Option<mpsc::UnboundedSender<TaskResponse>> {
-    tasks_sinks.lock().unwrap().remove(task_id)
+/// The commander response stream ends once all the sinks of its tasks are dropped
+fn remove_task_sink(tasks_sinks: &TaskSinks, task_id: &str) {
+    tasks_sinks.lock().unwrap().remove(task_id);
+}
+
+/// Remove the sinks matching `predicate`, by task id
+fn remove_task_sinks(
+    tasks_sinks: &TaskSinks,
+    predicate: impl Fn(&str, &TaskSink) -> bool,
+) -> Vec<(String, TaskSink)> {
+    let mut tasks_sinks = tasks_sinks.lock().unwrap();
+    let task_ids: Vec<String> = tasks_sinks
+        .iter()
+        .filter(|(task_id, sink)| predicate(task_id, sink))
+        .map(|(task_id, _)| task_id.clone())
+        .collect();
+    task_ids
+        .into_iter()
+        .filter_map(|task_id| {
+            let sink = tasks_sinks.remove(&task_id)?;
+            Some((task_id, sink))
+        })
+        .collect()
+}
+
+/// Id of the responses sent before the executor reports the actual task id. Always random: the
+/// task id generator only numbers actual tasks.
+fn placeholder_task_id() -> String {
+    RandomTaskIds.next_id()
 }
 
 type Stream<T> =
     Pin<Box<dyn futures::Stream<Item = std::result::Result<T, Status>> + Send + Sync + 'static>>;
 
-fn random_task_id() -> String {
-    let id: u128 = rand::thread_rng().gen();
-    format!("{:x}", id)
+#[cfg(test)]
+mod test {
+    use super::{is_outdated, register_new_task, TaskServer, TaskServerError};
+    use crate::executor_meta::ExecutorMeta;
+    use futures::channel::mpsc;
+    use grpc_service::grpc_protocol::launch_task_response::TaskResponse;
+    use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+    use grpc_service::grpc_protocol::{GetTasksRequest, PublicKey};
+    use grpc_service::payload::SignedPayload;
+    use std::collections::{BTreeMap, HashMap};
+    use std::path::Path;
+    use std::time::Duration;
+
+    pub(super) fn task_server(dir: &Path) -> TaskServer {
+        TaskServer::new(
+            dir,
+            &BTreeMap::new(),
+            &BTreeMap::new(),
+            false,
+            Duration::from_secs(0),
+        )
+        .unwrap()
+    }
+
+    fn known_executors(task_server: &TaskServer) -> usize {
+        task_server
+            .read_executor_meta_database(|executors| executors.len())
+            .unwrap()
+    }
+
+    #[test]
+    fn stale_task_sinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let task_server = task_server(dir.path()).with_task_sink_ttl(Duration::from_millis(100));
+        let (sender, mut receiver) = mpsc::channel(1);
+        let register = |sender| {
+            register_new_task(
+                &task_server.tasks_sinks,
+                task_server.task_ids.as_ref(),
+                "exec",
+                sender,
+            )
+        };
+        let lost = register(sender.clone());
+        let reported = register(sender);
+        task_server.task_results.stream_started(&reported);
+
+        assert_eq!(0, task_server.reap_stale_task_sinks());
+        assert_eq!(2, task_server.get_running_tasks().unwrap().len());
+
+        std::thread::sleep(Duration::from_millis(150));
+        // the task whose results are being reported is kept
+        assert_eq!(1, task_server.reap_stale_task_sinks());
+        let running = task_server.get_running_tasks().unwrap();
+        assert_eq!(vec![&reported], running.keys().collect::<Vec<_>>());
+        assert_eq!("exec", running[&reported].client_id);
+        assert!(running[&reported].age_secs < 10);
+
+        match receiver.try_next() {
+            Ok(Some(TaskResponse::TaskExecutionResult(result))) => {
+                assert_eq!(lost, result.task_id);
+                assert_eq!("exec", result.client_id);
+                assert!(matches!(
+                    result.execution_result,
+                    Some(ExecutionResult::Disconnected(_))
+                ));
+            }
+            other => panic!("Not a task execution result: {:?}", other),
+        }
+        // the commander still waits for the reported task
+        assert!(receiver.try_next().is_err());
+    }
+
+    #[test]
+    fn single_terminal_result() {
+        let dir = tempfile::tempdir().unwrap();
+        let task_server = TaskServer::new(
+            dir.path(),
+            &BTreeMap::new(),
+            &BTreeMap::new(),
+            true,
+            Duration::from_secs(0),
+        )
+        .unwrap();
+        let signed_payload = |key_id: &str| SignedPayload {
+            key_id: key_id.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(None, task_server.recorded_executor("task").unwrap());
+        assert!(task_server
+            .record_signed_result("task", "exec-a", &signed_payload("exec-a"))
+            .unwrap());
+        assert!(!task_server
+            .record_signed_result("task", "exec-a", &signed_payload("exec-a"))
+            .unwrap());
+        // never appended to the entry of another executor
+        assert!(!task_server
+            .record_signed_result("task", "exec-b", &signed_payload("exec-b"))
+            .unwrap());
+        assert_eq!(
+            Some("exec-a".to_string()),
+            task_server.recorded_executor("task").unwrap()
+        );
+        let signed_results = task_server
+            .task_history
+            .as_ref()
+            .unwrap()
+            .read(|history| history["task"].signed_results.len())
+            .unwrap();
+        assert_eq!(1, signed_results);
+    }
+
+    #[test]
+    fn concurrent_registrations() {
+        let dir = tempfile::tempdir().unwrap();
+        let task_server = task_server(dir.path());
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let task_server = &task_server;
+                scope.spawn(move || {
+                    for i in 0..50 {
+                        let request = GetTasksRequest {
+                            client_id: format!("exec-{}-{}", thread, i),
+                            ..Default::default()
+                        };
+                        task_server.store_executor_meta(&request, true).unwrap();
+                    }
+                });
+            }
+        });
+        // the registrations are saved by the next flush only
+        assert_eq!(0, task_server.executor_meta_database.save_count());
+        assert_eq!(400, known_executors(&task_server));
+        task_server.flush().unwrap();
+        task_server.flush().unwrap();
+        assert_eq!(1, task_server.executor_meta_database.save_count());
+
+        drop(task_server);
+        assert_eq!(400, known_executors(&self::task_server(dir.path())));
+    }
+
+    #[test]
+    fn invalid_configured_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        // created on first run
+        task_server(dir.path());
+
+        // the keystore files already exist, the configuration is still checked
+        let mut keys = BTreeMap::new();
+        keys.insert("ops".to_string(), data_encoding::BASE64.encode(&[0; 64]));
+        let error = TaskServer::new(
+            dir.path(),
+            &BTreeMap::new(),
+            &keys,
+            false,
+            Duration::from_secs(0),
+        )
+        .err()
+        .expect("invalid admin key accepted");
+        assert_eq!(
+            "Invalid admin_authorized_keys: Key ops is 64 bytes long, expected a 32 bytes ED25519 public key",
+            format!("{:#}", error)
+        );
+    }
+
+    #[test]
+    fn reported_authorized_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let task_server = task_server(dir.path());
+        let reporting = |key_bytes: Vec<u8>| GetTasksRequest {
+            client_id: "exec".to_string(),
+            authorized_keys: vec![PublicKey {
+                key_id: "ops".to_string(),
+                key_bytes,
+                expires_at_secs: 0,
+            }],
+            ..Default::default()
+        };
+        task_server
+            .store_executor_meta(&reporting(vec![1; 32]), true)
+            .unwrap();
+        assert_eq!(
+            Some(vec![1; 32]),
+            task_server.authorized_keys.get_key("ops").unwrap()
+        );
+        // the stored key is not replaced by another one reported with the same id
+        task_server
+            .store_executor_meta(&reporting(vec![2; 32]), true)
+            .unwrap();
+        assert_eq!(
+            Some(vec![1; 32]),
+            task_server.authorized_keys.get_key("ops").unwrap()
+        );
+
+        // nor added again once removed, even after a restart
+        task_server.remove_authorized_key("ops").unwrap();
+        task_server
+            .store_executor_meta(&reporting(vec![1; 32]), true)
+            .unwrap();
+        assert_eq!(None, task_server.authorized_keys.get_key("ops").unwrap());
+        drop(task_server);
+        let task_server = self::task_server(dir.path());
+        task_server
+            .store_executor_meta(&reporting(vec![1; 32]), true)
+            .unwrap();
+        assert_eq!(None, task_server.authorized_keys.get_key("ops").unwrap());
+
+        // unless an admin adds it back
+        task_server
+            .add_authorized_key("ops", vec![3; 32], None)
+            .unwrap();
+        task_server
+            .store_executor_meta(&reporting(vec![1; 32]), true)
+            .unwrap();
+        assert_eq!(
+            Some(vec![3; 32]),
+            task_server.authorized_keys.get_key("ops").unwrap()
+        );
+    }
+
+    #[test]
+    fn configured_keys_merged() {
+        let dir = tempfile::tempdir().unwrap();
+        let configured = |keys: &[(&str, u8)]| {
+            let keys: BTreeMap<String, String> = keys
+                .iter()
+                .map(|(key_id, byte)| {
+                    (
+                        key_id.to_string(),
+                        data_encoding::BASE64.encode(&[*byte; 32]),
+                    )
+                })
+                .collect();
+            TaskServer::new(dir.path(), &keys, &BTreeMap::new(), false, Duration::ZERO).unwrap()
+        };
+        let task_server = configured(&[("ops", 1), ("ci", 1)]);
+        task_server.remove_authorized_key("ci").unwrap();
+        drop(task_server);
+
+        // keys added to the configuration are trusted, the removed ones & the stored ones prevail
+        let task_server = configured(&[("ops", 2), ("ci", 1), ("dev", 1)]);
+        let keys = task_server.authorized_keys.list_all().unwrap();
+        assert_eq!(vec!["dev", "ops"], keys.keys().collect::<Vec<_>>());
+        assert_eq!(data_encoding::BASE64.encode(&[1; 32]), keys["ops"]);
+    }
+
+    #[test]
+    fn known_executors_file() {
+        // written by a taskserver saving the database on each registration
+        let dir = tempfile::tempdir().unwrap();
+        let request = GetTasksRequest {
+            client_id: "exec".to_string(),
+            client_version: "1.0.0".to_string(),
+            ..Default::default()
+        };
+        let known: HashMap<String, ExecutorMeta> =
+            HashMap::from([("exec".to_string(), (&request).into())]);
+        std::fs::write(
+            dir.path().join("known_executors.yml"),
+            serde_yaml::to_string(&known).unwrap(),
+        )
+        .unwrap();
+
+        let task_server = task_server(dir.path());
+        assert_eq!(
+            Some("1.0.0".to_string()),
+            task_server
+                .read_executor_meta_database(|executors| executors
+                    .get("exec")
+                    .map(|meta| meta.version().to_string()))
+                .unwrap()
+        );
+        // the executors registered before a shutdown are known after a restart
+        let request = GetTasksRequest {
+            client_id: "other".to_string(),
+            ..Default::default()
+        };
+        task_server.store_executor_meta(&request, true).unwrap();
+        drop(task_server);
+        assert_eq!(2, known_executors(&self::task_server(dir.path())));
+    }
+
+    #[test]
+    fn outdated_executors() {
+        assert!(!is_outdated(crate::VERSION));
+        assert!(is_outdated("0.1.0"));
+        assert!(!is_outdated("unknown"));
+        assert!(!is_outdated("99.0.0"));
+    }
+
+    #[test]
+    fn duplicate_registrations() {
+        let dir = tempfile::tempdir().unwrap();
+        let task_server = task_server(dir.path());
+        let instance = |instance_id: &str| GetTasksRequest {
+            client_id: "exec".to_string(),
+            instance_id: instance_id.to_string(),
+            ..Default::default()
+        };
+        let (first, _first_receiver) = mpsc::channel(1);
+        task_server
+            .register_executor(&instance("host-a"), first.clone())
+            .unwrap();
+
+        // another installation, or one reporting no instance id
+        for instance_id in ["host-b", ""] {
+            let (second, _second_receiver) = mpsc::channel(1);
+            assert!(matches!(
+                task_server.register_executor(&instance(instance_id), second),
+                Err(TaskServerError::AlreadyConnected(_))
+            ));
+        }
+        assert_eq!(2, task_server.duplicate_registrations("exec"));
+        assert!(task_server
+            .executors
+            .get("exec")
+            .unwrap()
+            .same_receiver(&first));
+
+        // the same installation registering again replaces its half-open connection
+        let (restarted, restarted_receiver) = mpsc::channel(1);
+        task_server
+            .register_executor(&instance("host-a"), restarted)
+            .unwrap();
+        assert!(first.is_closed());
+
+        // accepted once the task stream of the connected one is dropped
+        drop(restarted_receiver);
+        let (second, _second_receiver) = mpsc::channel(1);
+        task_server
+            .register_executor(&instance("host-b"), second.clone())
+            .unwrap();
+
+        let task_server = task_server.with_takeover();
+        let (third, _third_receiver) = mpsc::channel(1);
+        task_server
+            .register_executor(&instance("host-c"), third)
+            .unwrap();
+        // the connection taken over is closed
+        assert!(second.is_closed());
+        assert_eq!(2, task_server.duplicate_registrations("exec"));
+    }
+
+    #[test]
+    fn auto_approved_executor_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let task_server = task_server(dir.path());
+        assert!(!task_server
+            .handle_executor_key("pending", &[1; 32])
+            .unwrap());
+
+        let task_server = task_server.with_auto_approved_executor_keys();
+        assert!(task_server.handle_executor_key("exec", &[2; 32]).unwrap());
+        assert!(task_server.handle_executor_key("exec", &[2; 32]).unwrap());
+        // a known executor presenting another key waits for an approval
+        assert!(!task_server.handle_executor_key("exec", &[3; 32]).unwrap());
+        assert_eq!(
+            Some(vec![3; 32]),
+            task_server
+                .unapproved_executor_keystore
+                .get_key("exec")
+                .unwrap()
+        );
+        assert!(!task_server
+            .handle_executor_key("pending", &[4; 32])
+            .unwrap());
+
+        // the key of a registered executor was revoked
+        let request = GetTasksRequest {
+            client_id: "revoked".to_string(),
+            ..Default::default()
+        };
+        task_server.store_executor_meta(&request, true).unwrap();
+        assert!(!task_server
+            .handle_executor_key("revoked", &[5; 32])
+            .unwrap());
+    }
+
+    #[test]
+    fn revoked_and_dropped_executor_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let task_server = task_server(dir.path()).with_auto_approved_executor_keys();
+        let request = GetTasksRequest {
+            client_id: "exec".to_string(),
+            ..Default::default()
+        };
+        assert!(task_server.handle_executor_key("exec", &[1; 32]).unwrap());
+        task_server.store_executor_meta(&request, true).unwrap();
+        task_server.revoke_executor_key("exec").unwrap();
+        // as done by `admin drop-executor`
+        task_server
+            .write_executor_meta_database(|executors| executors.remove("exec"))
+            .unwrap();
+
+        // another key registering with the client_id waits for an approval, even after a restart
+        assert!(!task_server.handle_executor_key("exec", &[2; 32]).unwrap());
+        task_server.reject_executor_key("exec").unwrap();
+        task_server.flush().unwrap();
+        drop(task_server);
+        let task_server = self::task_server(dir.path()).with_auto_approved_executor_keys();
+        assert!(!task_server.handle_executor_key("exec", &[3; 32]).unwrap());
+        assert_eq!(
+            None,
+            task_server
+                .trusted_executor_keystore
+                .get_key("exec")
+                .unwrap()
+        );
+        // new executors are still trusted on first use
+        assert!(task_server.handle_executor_key("other", &[4; 32]).unwrap());
+    }
+
+    #[test]
+    fn key_conflicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let task_server = task_server(dir.path());
+        assert!(!task_server.handle_executor_key("exec", &[1; 32]).unwrap());
+        assert!(!task_server.has_key_conflict("exec").unwrap());
+        task_server.approve_executor_key("exec").unwrap();
+        assert!(task_server
+            .list_executor_key_conflicts()
+            .unwrap()
+            .is_empty());
+
+        // reinstalled host or impersonation
+        assert!(!task_server.handle_executor_key("exec", &[2; 32]).unwrap());
+        assert!(task_server.has_key_conflict("exec").unwrap());
+        let conflicts = task_server.list_executor_key_conflicts().unwrap();
+        assert_eq!(
+            data_encoding::BASE64.encode(&[2; 32]),
+            conflicts["exec"].key
+        );
+        assert_eq!(
+            data_encoding::BASE64.encode(&[1; 32]),
+            conflicts["exec"].trusted_key
+        );
+        // the trusted key is still accepted
+        assert!(task_server.handle_executor_key("exec", &[1; 32]).unwrap());
+
+        // the conflict is kept across restarts
+        drop(task_server);
+        let task_server = self::task_server(dir.path());
+        assert_eq!(1, task_server.list_executor_key_conflicts().unwrap().len());
+        task_server.approve_executor_key("exec").unwrap();
+        assert!(task_server.handle_executor_key("exec", &[2; 32]).unwrap());
+        assert!(task_server
+            .list_executor_key_conflicts()
+            .unwrap()
+            .is_empty());
+
+        assert!(!task_server.handle_executor_key("exec", &[3; 32]).unwrap());
+        task_server.reject_executor_key("exec").unwrap();
+        assert!(task_server
+            .list_executor_key_conflicts()
+            .unwrap()
+            .is_empty());
+    }
 }
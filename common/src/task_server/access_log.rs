@@ -0,0 +1,277 @@
+//! Durable log of the signed requests received by the taskserver, one JSON object per line
+use crate::crypto::keystore::KeyStoreError;
+use crossbeam::channel::{Receiver, Sender};
+use grpc_service::grpc_protocol::admin_request::RequestType;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::error;
+
+pub const DEFAULT_ACCESS_LOG_MAX_BYTES: u64 = 100 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Accepted,
+    Rejected,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AccessLogEntry {
+    pub timestamp: String,
+    /// launch_task, admin, resolve_query or cancel_tasks
+    pub rpc: String,
+    /// as claimed by the request, even if it is not a known key
+    pub key_id: String,
+    /// signer of the delegation certificate of a delegated key, as claimed by the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delegated_by: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub predicate: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_type: Option<String>,
+    pub outcome: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl AccessLogEntry {
+    pub(crate) fn new(rpc: &str, key_id: &str) -> Self {
+        Self {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            rpc: rpc.to_string(),
+            key_id: key_id.to_string(),
+            delegated_by: None,
+            predicate: None,
+            request_type: None,
+            outcome: Outcome::Accepted,
+            reason: None,
+        }
+    }
+
+    pub(crate) fn with_predicate(mut self, predicate: &str) -> Self {
+        self.predicate = Some(predicate.to_string());
+        self
+    }
+
+    pub(crate) fn with_delegator(mut self, delegator: Option<&str>) -> Self {
+        self.delegated_by = delegator.map(str::to_string);
+        self
+    }
+
+    pub(crate) fn with_request_type(mut self, request_type: &RequestType) -> Self {
+        self.request_type = Some(request_type_name(request_type).to_string());
+        self
+    }
+
+    pub(crate) fn rejected(mut self, reason: impl ToString) -> Self {
+        self.outcome = Outcome::Rejected;
+        self.reason = Some(reason.to_string());
+        self
+    }
+
+    pub(crate) fn decoded<T>(self, decoded: &Result<T, KeyStoreError>) -> Self {
+        match decoded {
+            Ok(_) => self,
+            Err(e) => self.rejected(e),
+        }
+    }
+}
+
+fn request_type_name(request_type: &RequestType) -> &'static str {
+    match request_type {
+        RequestType::ListConnectedExecutors(_) => "ListConnectedExecutors",
+        RequestType::ListKnownExecutors(_) => "ListKnownExecutors",
+        RequestType::ListRunningTasks(_) => "ListRunningTasks",
+        RequestType::DropExecutor(_) => "DropExecutor",
+        RequestType::ListExecutorKeys(_) => "ListExecutorKeys",
+        RequestType::ApproveExecutorKey(_) => "ApproveExecutorKey",
+        RequestType::ForceApproveExecutorKey(_) => "ForceApproveExecutorKey",
+        RequestType::RejectExecutorKey(_) => "RejectExecutorKey",
+        RequestType::RevokeExecutorKey(_) => "RevokeExecutorKey",
+        RequestType::ListAuthorizedKeys(_) => "ListAuthorizedKeys",
+        RequestType::ListAdminAuthorizedKeys(_) => "ListAdminAuthorizedKeys",
+        RequestType::VerifyTask(_) => "VerifyTask",
+        RequestType::SetExecutorTag(_) => "SetExecutorTag",
+        RequestType::SetFailpoint(_) => "SetFailpoint",
+        RequestType::AddAuthorizedKey(_) => "AddAuthorizedKey",
+        RequestType::RemoveAuthorizedKey(_) => "RemoveAuthorizedKey",
+        RequestType::FetchResultChunk(_) => "FetchResultChunk",
+        RequestType::ReleaseResult(_) => "ReleaseResult",
+        RequestType::WhoAmI(_) => "WhoAmI",
+    }
+}
+
+enum Message {
+    Entry(AccessLogEntry),
+    /// the file has been moved away, eg: by logrotate
+    Reopen,
+    /// the entries sent before are written
+    Sync(Sender<()>),
+}
+
+/// Appends the entries to the access log file from a dedicated thread: the grpc handlers never
+/// wait for the disk.
+///
+/// The file is reopened on [AccessLog::reopen], and renamed to `<file>.1` once it holds
+/// `max_bytes`: the previous `<file>.1` is replaced, let logrotate move the file away to keep
+/// more of it.
+pub struct AccessLog {
+    sender: Sender<Message>,
+}
+
+impl AccessLog {
+    pub fn open<P: AsRef<Path>>(path: P, max_bytes: u64) -> io::Result<Self> {
+        let writer = Writer::open(path.as_ref().to_path_buf(), max_bytes)?;
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || writer.run(receiver))?;
+        Ok(Self { sender })
+    }
+
+    pub(crate) fn record(&self, entry: AccessLogEntry) {
+        // the writer thread only stops once the log is dropped
+        let _ = self.sender.send(Message::Entry(entry));
+    }
+
+    pub fn reopen(&self) {
+        let _ = self.sender.send(Message::Reopen);
+    }
+
+    /// Wait for the entries recorded so far to be written, blocking
+    pub fn sync(&self) {
+        let (done, written) = crossbeam::channel::bounded(1);
+        if self.sender.send(Message::Sync(done)).is_ok() {
+            let _ = written.recv();
+        }
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    max_bytes: u64,
+    file: File,
+    written: u64,
+}
+
+impl Writer {
+    fn open(path: PathBuf, max_bytes: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            file,
+            written,
+        })
+    }
+
+    fn run(mut self, receiver: Receiver<Message>) {
+        for message in receiver {
+            let result = match message {
+                Message::Entry(entry) => self.write(&entry),
+                Message::Reopen => self.reopen(),
+                Message::Sync(done) => {
+                    let _ = done.send(());
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                error!("Unable to write access log {}: {}", self.path.display(), e);
+            }
+        }
+    }
+
+    fn write(&mut self, entry: &AccessLogEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.written += line.len() as u64;
+        if self.written >= self.max_bytes {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            std::fs::rename(&self.path, rotated)?;
+            self.reopen()?;
+        }
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        *self = Self::open(self.path.clone(), self.max_bytes)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AccessLog, AccessLogEntry, Outcome};
+    use crate::crypto::keystore::KeyStoreError;
+
+    fn entries(path: &std::path::Path) -> Vec<AccessLogEntry> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn entries_appended() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let log = AccessLog::open(&path, 1024 * 1024).unwrap();
+        log.record(AccessLogEntry::new("launch_task", "ops").with_predicate("role:web"));
+        log.record(
+            AccessLogEntry::new("admin", "intruder")
+                .decoded::<()>(&Err(KeyStoreError::KeyNotFound("intruder".to_string()))),
+        );
+        log.sync();
+
+        let logged = entries(&path);
+        assert_eq!(2, logged.len());
+        assert_eq!(Some("role:web".to_string()), logged[0].predicate);
+        assert_eq!(Outcome::Accepted, logged[0].outcome);
+        assert_eq!("intruder", logged[1].key_id);
+        assert_eq!(Outcome::Rejected, logged[1].outcome);
+        assert_eq!(
+            Some("Key intruder does not exists".to_string()),
+            logged[1].reason
+        );
+
+        // appended to the existing file
+        drop(log);
+        let log = AccessLog::open(&path, 1024 * 1024).unwrap();
+        log.record(AccessLogEntry::new("cancel_tasks", "ops"));
+        log.sync();
+        assert_eq!(3, entries(&path).len());
+    }
+
+    #[test]
+    fn reopen_and_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let log = AccessLog::open(&path, 1024).unwrap();
+        log.record(AccessLogEntry::new("launch_task", "ops"));
+        log.sync();
+
+        // moved away by logrotate
+        std::fs::rename(&path, dir.path().join("access.log.old")).unwrap();
+        log.reopen();
+        log.record(AccessLogEntry::new("admin", "ops"));
+        log.sync();
+        assert_eq!(1, entries(&path).len());
+        assert_eq!(1, entries(&dir.path().join("access.log.old")).len());
+
+        for _ in 0..12 {
+            log.record(AccessLogEntry::new("launch_task", "ops"));
+        }
+        log.sync();
+        let rotated = entries(&dir.path().join("access.log.1"));
+        assert!(!rotated.is_empty());
+        assert_eq!(13, rotated.len() + entries(&path).len());
+        assert!(std::fs::metadata(&path).unwrap().len() < 1024);
+    }
+}
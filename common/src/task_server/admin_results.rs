@@ -0,0 +1,211 @@
+//! Admin json responses too large to be sent at once: they are written to a temporary file of the
+//! data directory, the commander fetches them by chunks then releases them.
+use grpc_service::grpc_protocol::ResultHandle;
+use rand::Rng;
+use ring::digest;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+/// Results are deleted this long after their last fetch
+const RESULT_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Error, Debug)]
+pub enum AdminResultError {
+    #[error("Result {0} not found, it may have expired")]
+    NotFound(String),
+    #[error("Offset {offset} is beyond the end of result {id} ({size} bytes)")]
+    InvalidOffset { id: String, offset: u64, size: u64 },
+    #[error("Result storage error {0}")]
+    IOError(#[from] io::Error),
+}
+
+struct StoredResult {
+    path: PathBuf,
+    /// only the key which sent the request can fetch the result
+    key_id: String,
+    size: u64,
+    expires_at: Instant,
+}
+
+pub struct AdminResults {
+    directory: PathBuf,
+    results: Mutex<HashMap<String, StoredResult>>,
+}
+
+impl AdminResults {
+    /// Results left by a previous run are deleted
+    pub fn new<P: AsRef<Path>>(directory: P) -> io::Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        if directory.exists() {
+            std::fs::remove_dir_all(&directory)?;
+        }
+        std::fs::create_dir_all(&directory)?;
+        Ok(Self {
+            directory,
+            results: Default::default(),
+        })
+    }
+
+    pub fn store(&self, key_id: &str, content: &[u8]) -> Result<ResultHandle, AdminResultError> {
+        self.purge_expired(Instant::now());
+        let id = format!("{:x}", rand::thread_rng().gen::<u128>());
+        let path = self.directory.join(&id);
+        std::fs::write(&path, content)?;
+        self.results.lock().unwrap().insert(
+            id.clone(),
+            StoredResult {
+                path,
+                key_id: key_id.to_string(),
+                size: content.len() as u64,
+                expires_at: Instant::now() + RESULT_TTL,
+            },
+        );
+        Ok(ResultHandle {
+            id,
+            size: content.len() as u64,
+            sha256: result_checksum(content),
+        })
+    }
+
+    /// Read at most `len` bytes from `offset`, an empty chunk is returned at the end of the result
+    pub fn read_chunk(
+        &self,
+        key_id: &str,
+        id: &str,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, AdminResultError> {
+        self.purge_expired(Instant::now());
+        let mut results = self.results.lock().unwrap();
+        let result = results
+            .get_mut(id)
+            .filter(|result| result.key_id == key_id)
+            .ok_or_else(|| AdminResultError::NotFound(id.to_string()))?;
+        if offset > result.size {
+            return Err(AdminResultError::InvalidOffset {
+                id: id.to_string(),
+                offset,
+                size: result.size,
+            });
+        }
+        result.expires_at = Instant::now() + RESULT_TTL;
+        let mut file = File::open(&result.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut chunk = Vec::new();
+        file.take(len).read_to_end(&mut chunk)?;
+        Ok(chunk)
+    }
+
+    pub fn release(&self, key_id: &str, id: &str) -> Result<(), AdminResultError> {
+        let mut results = self.results.lock().unwrap();
+        match results.get(id) {
+            Some(result) if result.key_id == key_id => {
+                let result = results.remove(id).unwrap();
+                Ok(std::fs::remove_file(result.path)?)
+            }
+            _ => Err(AdminResultError::NotFound(id.to_string())),
+        }
+    }
+
+    fn purge_expired(&self, now: Instant) {
+        self.results.lock().unwrap().retain(|id, result| {
+            let expired = result.expires_at <= now;
+            if expired {
+                debug!("Admin result {} expired", id);
+                if let Err(e) = std::fs::remove_file(&result.path) {
+                    warn!("Unable to remove admin result {}: {}", id, e);
+                }
+            }
+            !expired
+        });
+    }
+}
+
+/// Hex encoded sha256 of a result, as found in its [ResultHandle]
+pub fn result_checksum(content: &[u8]) -> String {
+    data_encoding::HEXLOWER.encode(digest::digest(&digest::SHA256, content).as_ref())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{result_checksum, AdminResultError, AdminResults, RESULT_TTL};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let results = AdminResults::new(dir.path().join("admin_results")).unwrap();
+        let content: Vec<u8> = (0..10_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let handle = results.store("admin", &content).unwrap();
+        assert_eq!(content.len() as u64, handle.size);
+        assert_eq!(result_checksum(&content), handle.sha256);
+
+        let mut fetched = vec![];
+        loop {
+            let chunk = results
+                .read_chunk("admin", &handle.id, fetched.len() as u64, 3000)
+                .unwrap();
+            if chunk.is_empty() {
+                break;
+            }
+            assert!(chunk.len() <= 3000);
+            fetched.extend(chunk);
+        }
+        assert_eq!(content, fetched);
+
+        // results are private to the key which requested them
+        assert!(matches!(
+            results.read_chunk("other", &handle.id, 0, 3000),
+            Err(AdminResultError::NotFound(_))
+        ));
+        assert!(matches!(
+            results.read_chunk("admin", &handle.id, handle.size + 1, 3000),
+            Err(AdminResultError::InvalidOffset { .. })
+        ));
+        assert!(results.release("other", &handle.id).is_err());
+        results.release("admin", &handle.id).unwrap();
+        assert!(matches!(
+            results.read_chunk("admin", &handle.id, 0, 3000),
+            Err(AdminResultError::NotFound(_))
+        ));
+        assert_eq!(
+            0,
+            std::fs::read_dir(dir.path().join("admin_results"))
+                .unwrap()
+                .count()
+        );
+    }
+
+    #[test]
+    fn expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let results = AdminResults::new(dir.path()).unwrap();
+        let handle = results.store("admin", b"{}").unwrap();
+
+        results.purge_expired(Instant::now() + RESULT_TTL / 2);
+        assert_eq!(
+            b"{}".to_vec(),
+            results.read_chunk("admin", &handle.id, 0, 10).unwrap()
+        );
+        results.store("admin", b"[]").unwrap();
+        results.purge_expired(Instant::now() + RESULT_TTL + Duration::from_secs(1));
+        assert!(matches!(
+            results.read_chunk("admin", &handle.id, 0, 10),
+            Err(AdminResultError::NotFound(_))
+        ));
+        assert_eq!(0, std::fs::read_dir(dir.path()).unwrap().count());
+
+        // leftovers of a previous run
+        results.store("admin", b"{}").unwrap();
+        drop(results);
+        AdminResults::new(dir.path()).unwrap();
+        assert_eq!(0, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+}
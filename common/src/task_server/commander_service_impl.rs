@@ -1,20 +1,32 @@
+use crate::capabilities::{Capabilities, BECOME, SCRIPTS};
+use crate::crypto::keystore::{expires_at_from_secs, FileKeyStoreBackend, KeyStore, KeyStoreError};
 use crate::executor_meta::ExecutorMeta;
-use crate::task_server::{random_task_id, Stream, TaskServer};
+use crate::task_server::key_scopes::KeyScopes;
+use crate::task_server::task_history::{verify_task, KeyConflict, PayloadVerificationReport};
+use crate::task_server::{
+    placeholder_task_id, AccessLogEntry, AdminResultError, ExecutorSender, Stream, TaskServer,
+    TaskServerError,
+};
 use crate::tonic;
-use crate::PROTOCOL_VERSION;
+use crate::{PROTOCOL_VERSION, VERSION};
 use anyhow::Context;
+use chrono::{DateTime, Local};
 use futures::channel::mpsc;
+use futures::stream::FuturesUnordered;
 use futures::{SinkExt, StreamExt};
 use grpc_service::grpc_protocol::admin_request::RequestType;
 use grpc_service::grpc_protocol::admin_request_response::ResponseKind;
+use grpc_service::grpc_protocol::admin_service_server::AdminService;
 use grpc_service::grpc_protocol::commander_service_server::*;
+use grpc_service::grpc_protocol::execute_command::Program;
 use grpc_service::grpc_protocol::executor_service_server::*;
 use grpc_service::grpc_protocol::launch_task_request_payload::Task;
 use grpc_service::grpc_protocol::launch_task_response::TaskResponse;
+use grpc_service::grpc_protocol::set_executor_tag::Operation;
 use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
 use grpc_service::grpc_protocol::*;
 use grpc_service::payload::SignedPayload;
-use query_parser::{parse, Query, QueryMatcher};
+use query_parser::{parse, Query, QueryMatcher, QueryParseError};
 use rand::Rng;
 use rustbreak::deser::Yaml;
 use rustbreak::{Database, FileDatabase};
@@ -28,18 +40,26 @@ use std::path::Path;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use thiserror::Error;
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
 use tonic::metadata::{Ascii, MetadataValue};
 use tonic::{Code, Request, Response, Status, Streaming};
+use tracing::{debug, error, field, info, warn, Instrument, Span};
 
 #[tonic::async_trait]
 impl CommanderService for TaskServer {
     type LaunchTaskStream = Stream<LaunchTaskResponse>;
 
+    #[tracing::instrument(
+        skip_all,
+        fields(key_id = field::Empty, predicate = %request.get_ref().predicate)
+    )]
     async fn launch_task(
         &self,
         request: tonic::Request<LaunchTaskRequest>,
     ) -> Result<tonic::Response<Self::LaunchTaskStream>, tonic::Status> {
+        let received = Instant::now();
+        self.peer_identity.check_commander(&request)?;
+        let identity = self.peer_identity.of(&request);
         let request = request.get_ref();
         let query = &request.predicate;
 
@@ -47,8 +67,30 @@ impl CommanderService for TaskServer {
             .payload
             .as_ref()
             .ok_or(Status::invalid_argument("Missing signed payload"))?;
-        let payload: LaunchTaskRequestPayload =
-            self.authorized_keys.decode_payload(signed_payload)?;
+        Span::current().record("key_id", signed_payload.key_id.as_str());
+        let delegator = request
+            .delegation
+            .as_ref()
+            .map(|certificate| certificate.key_id.as_str());
+        let access = || {
+            AccessLogEntry::new("launch_task", &signed_payload.key_id)
+                .with_predicate(query)
+                .with_delegator(delegator)
+        };
+        let decoded = match &request.delegation {
+            Some(certificate) => self
+                .authorized_keys
+                .decode_delegated_payload::<LaunchTaskRequestPayload>(certificate, signed_payload)
+                .map(|(payload, delegation)| (payload, Some(delegation))),
+            None => self
+                .authorized_keys
+                .decode_payload::<LaunchTaskRequestPayload>(signed_payload)
+                .map(|payload| (payload, None)),
+        };
+        if decoded.is_err() {
+            self.log_access(|| access().decoded(&decoded));
+        }
+        let (payload, delegation) = decoded?;
 
         let task = payload
             .task
@@ -59,13 +101,14 @@ impl CommanderService for TaskServer {
         // send keys to executors
         match task {
             Task::AuthorizeKey(_) | Task::RevokeKey(_) => {
+                // already decoded (thus not expired): decoding it again would be seen as a replay
                 self.authorized_admin_keys
-                    .decode_payload(signed_payload)
+                    .verify_signature(signed_payload)
                     .map_err(|e| {
-                        error!(
-                            "Tried to manipulate keys on executor with an non admin key: {}. {e}",
-                            signed_payload.key_id
-                        );
+                        error!("Tried to manipulate keys on executor with an non admin key. {e}");
+                        self.log_access(|| {
+                            access().rejected(format!("key operation with a non admin key: {e}"))
+                        });
                         Status::failed_precondition(format!(
                             "Key manipulation must be done with an admin key. {e}"
                         ))
@@ -73,108 +116,96 @@ impl CommanderService for TaskServer {
             }
             _ => (),
         }
+        let rejected = |status: Status| {
+            warn!("{} rejected: {}", signed_payload.key_id, status.message());
+            self.log_access(|| access().rejected(status.message()));
+            status
+        };
+        let query = parse(query)
+            .map_err(|parse_error| {
+                Status::invalid_argument(format!("Invalid query: {}", parse_error))
+            })
+            .map_err(rejected)?;
+        debug!("Parsed query: {:#?}", query);
+        // the scope is checked against the very executors the task is dispatched to
+        let matching = self
+            .get_channels_to_matching_executors(&query)
+            .map_err(|e| rejected(e.into()))?;
+        // a delegated key is restricted by the scope of its delegator
+        let scoped_key_id = delegator.unwrap_or(signed_payload.key_id.as_str());
+        self.check_allowed_executors(scoped_key_id, &matching, delegation.as_ref())
+            .map_err(rejected)?;
+        self.log_access(access);
 
         let command = match task {
-            Task::ExecuteCommand(command) => format!("ExecuteCommand: {}", command.command),
+            Task::ExecuteCommand(command) => match &command.program {
+                Some(Program::Script(script)) => format!("ExecuteScript: {}", script.name),
+                Some(Program::Command(command)) => format!("ExecuteCommand: {}", command),
+                None => "ExecuteCommand: ".to_string(),
+            },
             Task::AuthorizeKey(key) => format!(
                 "AuthorizeKey: {} - {}",
                 key.key_id,
                 data_encoding::BASE64.encode(&key.key_bytes)
             ),
             Task::RevokeKey(key_id) => format!("RevokeKey: {}", key_id),
+            Task::Diagnostic(_) => "Diagnostic".to_string(),
             Task::StreamingPayload(_) => {
                 return Err(Status::new(Code::Internal, "not implemented"))
             }
         };
+        #[cfg(feature = "failpoints")]
+        if self.failpoints.launch_task_failure() {
+            return Err(Status::internal("Failpoint launch_task_internal"));
+        }
+
         // this channel will be sent to the matching executors. the executors will then register it so
         // further task progression reporting could be sent o
-        let (mut sender, receiver) = mpsc::unbounded::<TaskResponse>();
+        let (sender, receiver) = mpsc::channel::<TaskResponse>(self.stream_buffer_size);
 
         info!(
-            "Command received {:?} for {} signed by  {}",
-            command, query, signed_payload.key_id
+            peer = identity.as_deref().unwrap_or("unidentified peer"),
+            "Command received {:?}", command
         );
 
-        let query = parse(query).map_err(|parse_error| {
-            Status::invalid_argument(format!("Invalid query: {}", parse_error))
-        })?;
-        debug!("Parsed query: {:#?}", query);
-
-        let mut senders = self.get_channels_to_matching_executors(&query)?;
-
-        let matching_clients: Vec<String> = senders
-            .iter()
-            .map(|(client_id, _)| client_id.clone())
+        let senders = matching
+            .into_iter()
+            .map(|(meta, executor_sender)| {
+                (
+                    meta.client_id().to_string(),
+                    executor_sender,
+                    meta.capabilities(),
+                )
+            })
             .collect();
+        let capabilities = Capabilities::from_peer(&request.capabilities);
+        let required = required_capabilities(task);
 
-        sender
-            .send(TaskResponse::MatchingExecutors(MatchingExecutors {
-                client_id: matching_clients,
-            }))
-            .await
-            .map_err(|e| {
-                error!("Commander disconnected!");
-                tonic::Status::new(Code::Internal, format!("Unexpected Error {}", e))
-            })?;
-
-        for (client_id, executor_sender) in senders.iter_mut() {
-            debug!("client {} matches query!", client_id);
-            if let Some(executor_sender) = executor_sender {
-                match executor_sender
-                    .send((signed_payload.clone(), sender.clone()))
+        // the channel is bounded: the task is dispatched while the commander reads the responses
+        let task_server = self.clone();
+        let signed_payload = signed_payload.clone();
+        let delegation = request.delegation.clone();
+        tokio::spawn(
+            async move {
+                if task_server
+                    .dispatch_task(
+                        signed_payload,
+                        delegation,
+                        senders,
+                        capabilities,
+                        required,
+                        sender,
+                        received,
+                    )
                     .await
+                    .is_err()
                 {
-                    Err(_) => {
-                        // disconnected executor: task sink has been found
-                        error!("Executor {} disconnected!", client_id);
-                        sender
-                            .send(TaskResponse::TaskExecutionResult(TaskExecutionResult {
-                                task_id: random_task_id(),
-                                client_id: client_id.clone(),
-                                execution_result: Some(ExecutionResult::Disconnected(Empty {})),
-                            }))
-                            .await
-                            .map_err(|e| {
-                                error!("Commander disconnected!");
-                                tonic::Status::new(
-                                    Code::Internal,
-                                    format!("Unexpected Error {}", e),
-                                )
-                            })?;
-                    }
-                    Ok(..) => {
-                        info!("Command {:?} sent to {}", command, client_id);
-                        sender
-                            .send(TaskResponse::TaskExecutionResult(TaskExecutionResult {
-                                task_id: random_task_id(),
-                                client_id: client_id.clone(),
-                                execution_result: Some(ExecutionResult::TaskSubmitted(Empty {})),
-                            }))
-                            .await
-                            .map_err(|e| {
-                                error!("Commander disconnected!");
-                                tonic::Status::new(
-                                    Code::Internal,
-                                    format!("Unexpected Error {}", e),
-                                )
-                            })?;
-                    }
+                    error!("Commander disconnected!");
                 }
-            } else {
-                // executor is knowm but no commication channel has been found
-                sender
-                    .send(TaskResponse::TaskExecutionResult(TaskExecutionResult {
-                        task_id: random_task_id(),
-                        client_id: client_id.clone(),
-                        execution_result: Some(ExecutionResult::Disconnected(Empty {})),
-                    }))
-                    .await
-                    .map_err(|e| {
-                        error!("Commander disconnected!");
-                        tonic::Status::new(Code::Internal, format!("Unexpected Error {}", e))
-                    })?;
             }
-        }
+            .instrument(Span::current()),
+        );
+
         let response_stream = receiver.map(|task_response| {
             Ok(LaunchTaskResponse {
                 task_response: Some(task_response),
@@ -189,170 +220,787 @@ impl CommanderService for TaskServer {
         &self,
         request: Request<SignedPayload>,
     ) -> Result<Response<AdminRequestResponse>, Status> {
-        let signed_payload = request.into_inner();
-        let request: AdminRequest = self.authorized_admin_keys.decode_payload(&signed_payload)?;
+        if self.separate_admin_listener {
+            return Err(Status::permission_denied(
+                "Admin requests are only served on the admin listener of the taskserver",
+            ));
+        }
+        self.peer_identity.check_commander(&request)?;
+        Ok(Response::new(self.admin_response(request.into_inner())))
+    }
+
+    async fn resolve_query(
+        &self,
+        request: Request<SignedPayload>,
+    ) -> Result<Response<ResolveQueryResponse>, Status> {
+        self.peer_identity.check_commander(&request)?;
+        let signed_payload = request.get_ref();
+        let request: Result<ResolveQueryRequest, _> =
+            self.authorized_keys.decode_payload(signed_payload);
+        self.log_access(|| {
+            let entry = AccessLogEntry::new("resolve_query", &signed_payload.key_id);
+            match &request {
+                Ok(request) => entry.with_predicate(&request.predicate),
+                Err(e) => entry.rejected(e),
+            }
+        });
+        let request = request?;
+
+        debug!(
+            "Resolve query {} signed by {}",
+            request.predicate, signed_payload.key_id
+        );
+
+        let query = parse(&request.predicate).map_err(|parse_error| {
+            Status::invalid_argument(format!("Invalid query: {}", parse_error))
+        })?;
+
+        let executors = self.map_matching_executors(&query, |meta| ResolvedExecutor {
+            client_id: meta.client_id().to_string(),
+            connected: self.executors.contains(meta.client_id()),
+            tags: meta
+                .tags()
+                .iter()
+                .map(|(name, value)| (name.clone(), value.into()))
+                .collect(),
+        })?;
+
+        Ok(Response::new(ResolveQueryResponse { executors }))
+    }
+
+    async fn cancel_tasks(
+        &self,
+        request: Request<SignedPayload>,
+    ) -> Result<Response<Empty>, Status> {
+        self.peer_identity.check_commander(&request)?;
+        let signed_payload = request.get_ref();
+        // task ids are random & only known by the commander which launched the task
+        let request: Result<CancelTasksRequest, _> =
+            self.authorized_keys.decode_payload(signed_payload);
+        self.log_access(|| {
+            AccessLogEntry::new("cancel_tasks", &signed_payload.key_id).decoded(&request)
+        });
+        let request = request?;
+        for task_id in &request.task_ids {
+            if self.cancel_task(task_id) {
+                info!(%task_id, key_id = %signed_payload.key_id, "Cancelling task");
+            } else {
+                debug!(%task_id, "Task is not running, not cancelling it");
+            }
+        }
+        Ok(Response::new(Empty {}))
+    }
+}
+
+#[tonic::async_trait]
+impl AdminService for TaskServer {
+    async fn admin(
+        &self,
+        request: Request<SignedPayload>,
+    ) -> Result<Response<AdminRequestResponse>, Status> {
+        self.peer_identity.check_commander(&request)?;
+        Ok(Response::new(self.admin_response(request.into_inner())))
+    }
+}
+
+/// Executors listed when a request targets executors its key is not allowed to
+const MAX_REPORTED_FORBIDDEN_EXECUTORS: usize = 10;
+
+fn describe_forbidden(mut forbidden: Vec<String>) -> String {
+    let count = forbidden.len();
+    forbidden.truncate(MAX_REPORTED_FORBIDDEN_EXECUTORS);
+    format!(
+        "{} matching executor(s) are not: {}{}",
+        count,
+        forbidden.join(", "),
+        if count > MAX_REPORTED_FORBIDDEN_EXECUTORS {
+            ", ..."
+        } else {
+            ""
+        }
+    )
+}
+
+/// Client ids of the matching executors matching none of the allowed queries, sorted
+fn forbidden_executors(
+    matching: &[(ExecutorMeta, Option<ExecutorSender>)],
+    allowed: &[Query],
+) -> Vec<String> {
+    let mut forbidden: Vec<String> = matching
+        .iter()
+        .map(|(meta, _)| meta)
+        .filter(|meta| {
+            !allowed
+                .iter()
+                .any(|allowed| meta.qmatches(allowed).matches())
+        })
+        .map(|meta| meta.client_id().to_string())
+        .collect();
+    forbidden.sort();
+    forbidden
+}
+
+/// Capabilities an executor needs to run the task as intended
+fn required_capabilities(task: &Task) -> Vec<&'static str> {
+    let mut required = vec![];
+    if let Task::ExecuteCommand(command) = task {
+        if matches!(command.program, Some(Program::Script(_))) {
+            required.push(SCRIPTS);
+        }
+        if command.with_become {
+            required.push(BECOME);
+        }
+    }
+    required
+}
+
+impl TaskServer {
+    /// Check the executors matching the query are allowed by the scope of the key & by the
+    /// delegation of the request, if any
+    fn check_allowed_executors(
+        &self,
+        key_id: &str,
+        matching: &[(ExecutorMeta, Option<ExecutorSender>)],
+        delegation: Option<&Delegation>,
+    ) -> Result<(), Status> {
+        if let Some(allowed) = self.key_scopes.parsed_allowed_queries(key_id) {
+            let forbidden = forbidden_executors(matching, &allowed);
+            if !forbidden.is_empty() {
+                return Err(Status::permission_denied(format!(
+                    "Key {} can only target executors matching {}, {}",
+                    key_id,
+                    self.key_scopes
+                        .allowed_queries(key_id)
+                        .unwrap_or_default()
+                        .join(" or "),
+                    describe_forbidden(forbidden)
+                )));
+            }
+        }
+        if let Some(delegation) = delegation {
+            let allowed = parse(&delegation.allowed_predicate).map_err(|parse_error| {
+                KeyStoreError::InvalidDelegation(format!(
+                    "invalid allowed predicate {}: {}",
+                    delegation.allowed_predicate, parse_error
+                ))
+            })?;
+            let forbidden = forbidden_executors(matching, std::slice::from_ref(&allowed));
+            if !forbidden.is_empty() {
+                return Err(KeyStoreError::InvalidDelegation(format!(
+                    "only executors matching {} are allowed, {}",
+                    delegation.allowed_predicate,
+                    describe_forbidden(forbidden)
+                ))
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the task to the matching executors, reporting the dispatch to the commander.
+    ///
+    /// The task is sent to all the executors at once: an executor whose channel is full does not
+    /// delay the others, each dispatch is reported as soon as it completes. The executors missing
+    /// a `required` capability would run the task wrongly: it is rejected for them.
+    #[allow(clippy::too_many_arguments)]
+    async fn dispatch_task(
+        &self,
+        signed_payload: SignedPayload,
+        delegation: Option<SignedPayload>,
+        senders: Vec<(String, Option<ExecutorSender>, Capabilities)>,
+        capabilities: Capabilities,
+        required: Vec<&'static str>,
+        mut sender: mpsc::Sender<TaskResponse>,
+        received: Instant,
+    ) -> Result<(), mpsc::SendError> {
+        let matching_clients: Vec<String> = senders
+            .iter()
+            .map(|(client_id, _, _)| client_id.clone())
+            .collect();
+
+        sender
+            .send(TaskResponse::MatchingExecutors(MatchingExecutors {
+                client_id: matching_clients,
+                matching_micros: received.elapsed().as_micros() as u64,
+            }))
+            .await?;
+
+        let mut dispatches: FuturesUnordered<_> = senders
+            .into_iter()
+            .map(|(client_id, executor_sender, executor_capabilities)| {
+                debug!(%client_id, "Executor matches the query");
+                let task = (
+                    signed_payload.clone(),
+                    delegation.clone(),
+                    sender.clone(),
+                    capabilities.clone(),
+                );
+                let unsupported = required
+                    .iter()
+                    .find(|capability| !executor_capabilities.supports(capability))
+                    .copied();
+                async move {
+                    if let Some(unsupported) = unsupported {
+                        warn!(
+                            %client_id,
+                            "Task rejected: the executor does not support {}", unsupported
+                        );
+                        return (
+                            client_id,
+                            ExecutionResult::TaskRejected(format!(
+                                "the executor does not support {}, upgrade it",
+                                unsupported
+                            )),
+                        );
+                    }
+                    let submitted = match executor_sender {
+                        Some(mut executor_sender) => {
+                            #[cfg(feature = "failpoints")]
+                            if self.failpoints.drop_executor_channel() {
+                                warn!(
+                                    %client_id,
+                                    "Failpoint drop_executor_channel: dropping the channel"
+                                );
+                                self.executors.remove(&client_id);
+                                executor_sender.close_channel();
+                            }
+                            let submitted = executor_sender.send(task).await.is_ok();
+                            if !submitted {
+                                // disconnected executor: task sink has been found
+                                error!(%client_id, "Executor disconnected!");
+                            }
+                            submitted
+                        }
+                        // executor is knowm but no commication channel has been found
+                        None => false,
+                    };
+                    let execution_result = if submitted {
+                        info!(%client_id, "Command sent");
+                        ExecutionResult::TaskSubmitted(TaskSubmitted {
+                            dispatch_micros: received.elapsed().as_micros() as u64,
+                        })
+                    } else {
+                        ExecutionResult::Disconnected(Empty {})
+                    };
+                    (client_id, execution_result)
+                }
+            })
+            .collect();
+
+        while let Some((client_id, execution_result)) = dispatches.next().await {
+            sender
+                .send(TaskResponse::TaskExecutionResult(TaskExecutionResult {
+                    task_id: placeholder_task_id(),
+                    client_id,
+                    execution_result: Some(execution_result),
+                    seq: 0,
+                }))
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn admin_response(&self, signed_payload: SignedPayload) -> AdminRequestResponse {
+        match self.handle_admin_request(&signed_payload) {
+            Ok(response_kind) => AdminRequestResponse {
+                response_kind: Some(response_kind),
+                structured_error: None,
+            },
+            Err(e) => {
+                warn!("{}: admin request failed: {}", signed_payload.key_id, e);
+                e.into()
+            }
+        }
+    }
+
+    fn handle_admin_request(
+        &self,
+        signed_payload: &SignedPayload,
+    ) -> Result<ResponseKind, AdminRequestError> {
+        let access = || AccessLogEntry::new("admin", &signed_payload.key_id);
+        let request: AdminRequest = match self.authorized_admin_keys.decode_payload(signed_payload)
+        {
+            Ok(request) => request,
+            Err(e @ KeyStoreError::PayloadDecodeError(_)) => {
+                self.log_access(|| access().rejected(&e));
+                return Err(AdminRequestError::InvalidRequest(e.to_string()));
+            }
+            Err(e) => {
+                // regular keys are only allowed to ask who they are
+                return match self
+                    .authorized_keys
+                    .decode_payload::<AdminRequest>(signed_payload)
+                {
+                    Ok(AdminRequest {
+                        request_type: Some(request_type @ RequestType::WhoAmI(_)),
+                    }) => {
+                        info!("{}: WhoAmI", signed_payload.key_id);
+                        self.log_access(|| access().with_request_type(&request_type));
+                        Ok(ResponseKind::JsonResponse(serde_json::to_string(
+                            &self.who_am_i(&signed_payload.key_id, false)?,
+                        )?))
+                    }
+                    _ => {
+                        self.log_access(|| access().rejected(&e));
+                        Err(AdminRequestError::PermissionDenied {
+                            key_id: signed_payload.key_id.clone(),
+                            source: e,
+                        })
+                    }
+                };
+            }
+        };
+        self.log_access(|| match &request.request_type {
+            Some(request_type) => access().with_request_type(request_type),
+            None => access().rejected("missing request type"),
+        });
 
         info!("{}: {:?}", signed_payload.key_id, request);
+        let key_id = &signed_payload.key_id;
 
         match request
             .request_type
-            .ok_or(Status::invalid_argument("Missing request type"))?
-        {
+            .ok_or(AdminRequestError::InvalidRequest(
+                "Missing request type".to_string(),
+            ))? {
+            RequestType::FetchResultChunk(fetch) => {
+                Ok(ResponseKind::ResultChunk(self.admin_results.read_chunk(
+                    key_id,
+                    &fetch.id,
+                    fetch.offset,
+                    fetch.len.min(self.max_admin_response_bytes),
+                )?))
+            }
+            RequestType::ReleaseResult(release) => {
+                self.admin_results.release(key_id, &release.id)?;
+                Ok(ResponseKind::JsonResponse("{}".to_string()))
+            }
+            request_type => {
+                let json_response = self.admin_json_response(signed_payload, request_type)?;
+                if json_response.len() as u64 > self.max_admin_response_bytes {
+                    let handle = self.admin_results.store(key_id, json_response.as_bytes())?;
+                    info!(
+                        "{}: {} bytes response kept as result {}",
+                        key_id, handle.size, handle.id
+                    );
+                    Ok(ResponseKind::ResultHandle(handle))
+                } else {
+                    Ok(ResponseKind::JsonResponse(json_response))
+                }
+            }
+        }
+    }
+
+    fn admin_json_response(
+        &self,
+        signed_payload: &SignedPayload,
+        request_type: RequestType,
+    ) -> Result<String, AdminRequestError> {
+        match request_type {
+            RequestType::FetchResultChunk(_) | RequestType::ReleaseResult(_) => Err(
+                AdminRequestError::InvalidRequest("Not a json request".to_string()),
+            ),
             RequestType::ListConnectedExecutors(query) => {
-                let connected_executors = self
-                    .executors
-                    .lock()
-                    .map_err(|_| Status::internal("Unable to lock"))?
-                    .iter()
-                    .map(|(client_id, _)| client_id.clone())
-                    .collect::<HashSet<_>>();
-
-                Ok(Response::new(AdminRequestResponse {
-                    response_kind: Some(ResponseKind::JsonResponse(
-                        parse(&query)
-                            .map_err(|parse_error| {
-                                Status::invalid_argument(format!("Invalid query: {}", parse_error))
+                let query = parse_admin_query(&query)?;
+                let connected_executors = self.executors.client_ids();
+
+                Ok(self.read_executor_meta_database(|data| {
+                    serde_json::to_string(
+                        &data
+                            .iter()
+                            .filter(|(client_id, meta)| {
+                                connected_executors.contains(*client_id)
+                                    && meta.qmatches(&query).matches()
                             })
-                            .and_then(|query| {
-                                self.read_executor_meta_database(|data| {
-                                    serde_json::to_string(
-                                        &data
-                                            .iter()
-                                            .filter(|(client_id, meta)| {
-                                                connected_executors.contains(*client_id)
-                                                    && meta.qmatches(&query).matches()
-                                            })
-                                            .collect::<BTreeMap<_, _>>(),
-                                    )
-                                })?
-                                .map_err(|deser| {
-                                    Status::internal(format!("An error occured: {}", deser))
-                                })
-                            })?,
-                    )),
-                }))
-            }
-            RequestType::ListKnownExecutors(query) => Ok(Response::new(AdminRequestResponse {
-                response_kind: Some(ResponseKind::JsonResponse(
-                    parse(&query)
-                        .map_err(|parse_error| {
-                            Status::invalid_argument(format!("Invalid query: {}", parse_error))
-                        })
-                        .and_then(|query| {
-                            self.read_executor_meta_database(|data| {
-                                serde_json::to_string(
-                                    &data
-                                        .iter()
-                                        .filter(|(_, meta)| meta.qmatches(&query).matches())
-                                        .collect::<BTreeMap<_, _>>(),
-                                )
-                            })?
-                            .map_err(|deser| {
-                                Status::internal(format!("An error occured: {}", deser))
+                            .map(|(client_id, meta)| {
+                                let mut meta = meta.clone();
+                                meta.set_duplicate_registrations(
+                                    self.duplicate_registrations(client_id),
+                                );
+                                (client_id, meta)
                             })
-                        })?,
-                )),
-            })),
-            RequestType::ListRunningTasks(_) => Ok(Response::new(AdminRequestResponse {
-                response_kind: Some(ResponseKind::JsonResponse(
+                            .collect::<BTreeMap<_, _>>(),
+                    )
+                })??)
+            }
+            RequestType::ListKnownExecutors(query) => {
+                let query = parse_admin_query(&query)?;
+                Ok(self.read_executor_meta_database(|data| {
                     serde_json::to_string(
-                        &self
-                            .get_running_tasks()
-                            .map_err(|e| Status::internal(e.to_string()))?,
+                        &data
+                            .iter()
+                            .filter(|(_, meta)| meta.qmatches(&query).matches())
+                            .collect::<BTreeMap<_, _>>(),
                     )
-                    .map_err(|deser| Status::internal(format!("An error occured: {}", deser)))?,
-                )),
-            })),
+                })??)
+            }
+            RequestType::ListRunningTasks(_) => {
+                Ok(serde_json::to_string(&self.get_running_tasks()?)?)
+            }
             RequestType::DropExecutor(query) => {
-                Ok(Response::new(AdminRequestResponse {
-                    response_kind: Some(ResponseKind::JsonResponse(
-                        serde_json::to_string(
-                            &parse(&query)
-                                .map_err(|parse_error| {
-                                    Status::invalid_argument(format!(
-                                        "Invalid query: {}",
-                                        parse_error
-                                    ))
-                                })
-                                .and_then(|query| {
-                                    Ok(self.read_executor_meta_database(|data| {
-                                        data.iter()
-                                            .filter(|(_, meta)| meta.qmatches(&query).matches())
-                                            .map(|(client_id, _)| client_id.clone())
-                                            .collect::<Vec<_>>()
-                                    })?)
-                                })
-                                .and_then(|client_ids| {
-                                    client_ids.into_iter().try_fold(
-                                        BTreeMap::new(),
-                                        |mut acc, client_id| {
-                                            // remove from database
-                                            let removed_from_known = self
-                                                .write_executor_meta_database(|data| {
-                                                    data.remove(&client_id).is_some()
-                                                })?;
-                                            // remove from connected executors
-                                            let removed_from_connected = self
-                                                .executors
-                                                .lock()
-                                                .map_err(|_| Status::internal("Unable to lock"))?
-                                                .remove(&client_id)
-                                                .is_some();
-                                            acc.insert(
-                                                client_id,
-                                                AdminDroppedExecutorJsonResponse {
-                                                    removed_from_connected,
-                                                    removed_from_known,
-                                                },
-                                            );
-                                            Ok(acc)
-                                        },
-                                    )
-                                })?,
-                        )
-                        .map_err(|deser| {
-                            Status::internal(format!("An error occured: {}", deser))
-                        })?,
-                    )),
-                }))
+                let query = parse_admin_query(&query)?;
+                let client_ids = self.read_executor_meta_database(|data| {
+                    data.iter()
+                        .filter(|(_, meta)| meta.qmatches(&query).matches())
+                        .map(|(client_id, _)| client_id.clone())
+                        .collect::<Vec<_>>()
+                })?;
+                let dropped = client_ids.into_iter().try_fold(
+                    BTreeMap::new(),
+                    |mut acc, client_id| -> Result<_, AdminRequestError> {
+                        // remove from database
+                        let removed_from_known = self.write_executor_meta_database(|data| {
+                            data.remove(&client_id).is_some()
+                        })?;
+                        // a new executor may register with this client_id
+                        self.tag_overrides
+                            .write(|overrides| overrides.remove(&client_id))
+                            .map_err(TaskServerError::from)?;
+                        // remove from connected executors
+                        let removed_from_connected = match self.executors.remove(&client_id) {
+                            Some(mut sender) => {
+                                sender.close_channel();
+                                true
+                            }
+                            None => false,
+                        };
+                        acc.insert(
+                            client_id,
+                            AdminDroppedExecutorJsonResponse {
+                                removed_from_connected,
+                                removed_from_known,
+                            },
+                        );
+                        Ok(acc)
+                    },
+                )?;
+                self.tag_overrides.save().map_err(TaskServerError::from)?;
+                Ok(serde_json::to_string(&dropped)?)
+            }
+            RequestType::ListExecutorKeys(_) => {
+                Ok(serde_json::to_string(&AdminListExecutorKeysJsonResponse {
+                    trusted_executor_keys: self.list_trusted_executor_keys()?,
+                    unapproved_executor_keys: self.list_unapproved_executor_keys()?,
+                    conflicting_executor_keys: self.list_executor_key_conflicts()?,
+                })?)
             }
-            RequestType::ListExecutorKeys(_) => Ok(Response::new(AdminRequestResponse {
-                response_kind: Some(ResponseKind::JsonResponse(
-                    serde_json::to_string(&AdminListExecutorKeysJsonResponse {
-                        trusted_executor_keys: self.list_trusted_executor_keys()?,
-                        unapproved_executor_keys: self.list_unapproved_executor_keys()?,
-                    })
-                    .map_err(|deser| Status::internal(format!("An error occured: {}", deser)))?,
-                )),
-            })),
             RequestType::ApproveExecutorKey(client_id) => {
                 if &client_id == "*" {
-                    // batch approve all
+                    // batch approve all, but the keys which would replace a trusted one
                     for (client_id, _) in self.list_unapproved_executor_keys()?.iter() {
-                        self.approve_executor_key(client_id)?;
+                        if self.has_key_conflict(client_id)? {
+                            warn!("Conflicting key of {} left pending", client_id);
+                        } else {
+                            self.approve_executor_key(client_id)?;
+                        }
                     }
+                } else if self.has_key_conflict(&client_id)? {
+                    return Err(AdminRequestError::KeyConflict(client_id));
                 } else {
                     self.approve_executor_key(&client_id)?;
                 }
-                Ok(Response::new(AdminRequestResponse {
-                    response_kind: Some(ResponseKind::JsonResponse("{}".to_string())),
-                }))
+                Ok("{}".to_string())
+            }
+            RequestType::ForceApproveExecutorKey(client_id) => {
+                if &client_id == "*" {
+                    return Err(AdminRequestError::InvalidRequest(
+                        "Conflicting keys must be approved one by one".to_string(),
+                    ));
+                }
+                warn!("Replacing the trusted key of {}", client_id);
+                self.approve_executor_key(&client_id)?;
+                Ok("{}".to_string())
+            }
+            RequestType::RejectExecutorKey(client_id) => {
+                let rejected = if &client_id == "*" {
+                    self.list_unapproved_executor_keys()?
+                        .into_keys()
+                        .map(|client_id| {
+                            self.reject_executor_key(&client_id)?;
+                            Ok(client_id)
+                        })
+                        .collect::<Result<_, KeyStoreError>>()?
+                } else {
+                    self.reject_executor_key(&client_id)?;
+                    vec![client_id]
+                };
+                Ok(serde_json::to_string(
+                    &AdminRejectedExecutorKeysJsonResponse { rejected },
+                )?)
+            }
+            RequestType::RevokeExecutorKey(client_id) => Ok(serde_json::to_string(
+                &AdminRevokedExecutorKeyJsonResponse {
+                    removed_from_connected: self.revoke_executor_key(&client_id)?,
+                },
+            )?),
+
+            RequestType::ListAuthorizedKeys(_) => Ok(serde_json::to_string(
+                &list_authorized_keys(&self.authorized_keys, &self.key_scopes)?,
+            )?),
+            RequestType::ListAdminAuthorizedKeys(_) => Ok(serde_json::to_string(
+                &list_authorized_keys(&self.authorized_admin_keys, &KeyScopes::default())?,
+            )?),
+            RequestType::VerifyTask(task_id) => {
+                Ok(serde_json::to_string(&self.verify_task(&task_id)?)?)
+            }
+            RequestType::SetExecutorTag(set_tag) => {
+                Ok(serde_json::to_string(&self.set_executor_tag(set_tag)?)?)
             }
+            #[cfg(feature = "failpoints")]
+            RequestType::SetFailpoint(failpoint) => {
+                self.failpoints
+                    .set(&failpoint.name, &failpoint.config)
+                    .map_err(AdminRequestError::InvalidRequest)?;
+                Ok("{}".to_string())
+            }
+            #[cfg(not(feature = "failpoints"))]
+            RequestType::SetFailpoint(_) => Err(AdminRequestError::InvalidRequest(
+                "This taskserver is not built with failpoints".to_string(),
+            )),
+            RequestType::AddAuthorizedKey(public_key) => {
+                if public_key.key_id.is_empty() || public_key.key_bytes.len() != 32 {
+                    return Err(AdminRequestError::InvalidRequest(format!(
+                        "Invalid ed25519 public key {}",
+                        public_key.key_id
+                    )));
+                }
+                self.add_authorized_key(
+                    &public_key.key_id,
+                    public_key.key_bytes,
+                    expires_at_from_secs(public_key.expires_at_secs),
+                )?;
+                Ok("{}".to_string())
+            }
+            RequestType::RemoveAuthorizedKey(key_id) => {
+                self.remove_authorized_key(&key_id)?;
+                Ok("{}".to_string())
+            }
+            RequestType::WhoAmI(_) => Ok(serde_json::to_string(
+                &self.who_am_i(&signed_payload.key_id, true)?,
+            )?),
+        }
+    }
+
+    /// What the signing key is allowed to do, `admin` if the request has been verified with an
+    /// admin key
+    fn who_am_i(
+        &self,
+        key_id: &str,
+        admin: bool,
+    ) -> Result<AdminWhoAmIJsonResponse, KeyStoreError> {
+        let authorized = if admin {
+            // the same key id may be bound to another key in the regular keystore
+            match self.authorized_admin_keys.get_key(key_id)? {
+                Some(key_bytes) => self.authorized_keys.has_key(key_id, &key_bytes)?,
+                None => false,
+            }
+        } else {
+            true
+        };
+        let mut allowed = Vec::new();
+        if authorized {
+            allowed.extend(["cmd", "resolve", "cancel"]);
+        }
+        if admin {
+            allowed.push("admin");
+        }
+        allowed.push("whoami");
+        Ok(AdminWhoAmIJsonResponse {
+            key_id: key_id.to_string(),
+            authorized,
+            admin,
+            allowed: allowed.into_iter().map(String::from).collect(),
+            allowed_queries: self
+                .key_scopes
+                .allowed_queries(key_id)
+                .map(<[String]>::to_vec),
+            version: Some(VERSION.to_string()),
+            protocol_version: Some(PROTOCOL_VERSION.to_string()),
+        })
+    }
 
-            RequestType::ListAuthorizedKeys(_) => Ok(Response::new(AdminRequestResponse {
-                response_kind: Some(ResponseKind::JsonResponse(
-                    serde_json::to_string(&self.authorized_keys.list_all()?).map_err(|deser| {
-                        Status::internal(format!("An error occured: {}", deser))
-                    })?,
-                )),
-            })),
-            RequestType::ListAdminAuthorizedKeys(_) => Ok(Response::new(AdminRequestResponse {
-                response_kind: Some(ResponseKind::JsonResponse(
-                    serde_json::to_string(&self.authorized_admin_keys.list_all()?).map_err(
-                        |deser| Status::internal(format!("An error occured: {}", deser)),
-                    )?,
-                )),
-            })),
+    /// Override a tag of the matching known executors, returns their client ids
+    fn set_executor_tag(&self, set_tag: SetExecutorTag) -> Result<Vec<String>, AdminRequestError> {
+        let SetExecutorTag {
+            query,
+            tag_path,
+            operation,
+        } = set_tag;
+        let query = parse_admin_query(&query)?;
+        if tag_path.split(':').any(str::is_empty) {
+            return Err(AdminRequestError::InvalidRequest(format!(
+                "Invalid tag path {}",
+                tag_path
+            )));
+        }
+        let value = match operation.ok_or(AdminRequestError::InvalidRequest(
+            "Missing tag operation".to_string(),
+        ))? {
+            Operation::Value(value) => Some(value),
+            Operation::Remove(_) => None,
+        };
+
+        let client_ids = self.read_executor_meta_database(|data| {
+            data.iter()
+                .filter(|(_, meta)| meta.qmatches(&query).matches())
+                .map(|(client_id, _)| client_id.clone())
+                .collect::<Vec<_>>()
+        })?;
+        self.tag_overrides
+            .write(|overrides| {
+                let nested = format!("{}:", tag_path);
+                for client_id in &client_ids {
+                    let client_overrides = overrides.entry(client_id.clone()).or_default();
+                    // replaced along with the tag
+                    client_overrides.retain(|path, _| !path.starts_with(&nested));
+                    client_overrides.insert(tag_path.clone(), value.clone());
+                }
+            })
+            .map_err(TaskServerError::from)?;
+        self.tag_overrides.save().map_err(TaskServerError::from)?;
+
+        self.write_executor_meta_database(|data| {
+            for client_id in &client_ids {
+                if let Some(meta) = data.get_mut(client_id) {
+                    meta.override_tag(&tag_path, value.as_deref());
+                }
+            }
+        })?;
+        self.executor_meta_database
+            .save()
+            .map_err(TaskServerError::from)?;
+        Ok(client_ids)
+    }
+
+    fn verify_task(&self, task_id: &str) -> Result<AdminVerifyTaskJsonResponse, AdminRequestError> {
+        let task_history = self.task_history.as_ref().ok_or_else(|| {
+            AdminRequestError::InvalidRequest(
+                "Signatures are not retained by this taskserver (retain_signatures)".to_string(),
+            )
+        })?;
+        let entry = task_history
+            .read(|history| history.get(task_id).cloned())
+            .map_err(TaskServerError::from)?
+            .ok_or_else(|| AdminRequestError::TaskNotFound(task_id.to_string()))?;
+        let archived_keys = self
+            .executor_key_archive
+            .read(|archive| archive.get(&entry.client_id).cloned())
+            .map_err(TaskServerError::from)?
+            .unwrap_or_default();
+
+        let payloads = verify_task(
+            task_id,
+            &entry,
+            &self.trusted_executor_keystore,
+            &archived_keys,
+        );
+        Ok(AdminVerifyTaskJsonResponse {
+            task_id: task_id.to_string(),
+            client_id: entry.client_id,
+            verified: !payloads.is_empty() && payloads.iter().all(|p| p.verified()),
+            payloads,
+        })
+    }
+}
+
+fn parse_admin_query(query: &str) -> Result<Query<'_>, AdminRequestError> {
+    parse(query).map_err(|source| AdminRequestError::InvalidQuery {
+        query: query.to_string(),
+        source,
+    })
+}
+
+/// Error raised while handling an admin request.
+///
+/// It is sent back to the commander both as a plain message (for older commanders) and as a
+/// structured [AdminError].
+#[derive(Debug, Error)]
+pub enum AdminRequestError {
+    #[error("Admin request signed by {key_id} rejected: {source}")]
+    PermissionDenied {
+        key_id: String,
+        source: KeyStoreError,
+    },
+    #[error("Invalid query {query}: {source}")]
+    InvalidQuery {
+        query: String,
+        source: QueryParseError,
+    },
+    #[error("Invalid admin request: {0}")]
+    InvalidRequest(String),
+    #[error("Task {0} not found in task history")]
+    TaskNotFound(String),
+    #[error("Pending key of {0} conflicts with its trusted key, use --force to replace it")]
+    KeyConflict(String),
+    #[error("{0}")]
+    KeyStore(#[from] KeyStoreError),
+    #[error("{0}")]
+    TaskServer(#[from] TaskServerError),
+    #[error("{0}")]
+    AdminResult(#[from] AdminResultError),
+    #[error("Unable to serialize response: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+impl AdminRequestError {
+    pub fn code(&self) -> AdminErrorCode {
+        match self {
+            AdminRequestError::PermissionDenied { .. } => AdminErrorCode::PermissionDenied,
+            AdminRequestError::InvalidQuery { .. } => AdminErrorCode::InvalidQuery,
+            AdminRequestError::InvalidRequest(_)
+            | AdminRequestError::KeyConflict(_)
+            | AdminRequestError::KeyStore(KeyStoreError::InvalidKeyMaterial(..))
+            | AdminRequestError::AdminResult(AdminResultError::InvalidOffset { .. }) => {
+                AdminErrorCode::InvalidRequest
+            }
+            AdminRequestError::KeyStore(KeyStoreError::KeyNotFound(_))
+            | AdminRequestError::TaskNotFound(_)
+            | AdminRequestError::AdminResult(AdminResultError::NotFound(_)) => {
+                AdminErrorCode::NotFound
+            }
+            AdminRequestError::KeyStore(_)
+            | AdminRequestError::AdminResult(_)
+            | AdminRequestError::TaskServer(_)
+            | AdminRequestError::Serialization(_) => AdminErrorCode::Internal,
+        }
+    }
+
+    pub fn details(&self) -> HashMap<String, String> {
+        let mut details = HashMap::new();
+        match self {
+            AdminRequestError::PermissionDenied { key_id, .. } => {
+                details.insert("key_id".to_string(), key_id.clone());
+            }
+            AdminRequestError::InvalidQuery { query, .. } => {
+                details.insert("query".to_string(), query.clone());
+            }
+            AdminRequestError::KeyStore(
+                KeyStoreError::KeyNotFound(key_id) | KeyStoreError::InvalidKeyMaterial(key_id, _),
+            ) => {
+                details.insert("key_id".to_string(), key_id.clone());
+            }
+            AdminRequestError::TaskNotFound(task_id) => {
+                details.insert("task_id".to_string(), task_id.clone());
+            }
+            AdminRequestError::KeyConflict(client_id) => {
+                details.insert("client_id".to_string(), client_id.clone());
+            }
+            _ => (),
+        }
+        details
+    }
+}
+
+impl From<AdminRequestError> for AdminRequestResponse {
+    fn from(e: AdminRequestError) -> Self {
+        let message = e.to_string();
+        AdminRequestResponse {
+            response_kind: Some(ResponseKind::Error(message.clone())),
+            structured_error: Some(AdminError {
+                code: e.code() as i32,
+                message,
+                details: e.details(),
+            }),
         }
     }
 }
@@ -363,8 +1011,277 @@ pub struct AdminDroppedExecutorJsonResponse {
     pub removed_from_known: bool,
 }
 
+/// Task whose execution is being reported to a commander
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminRunningTaskJsonResponse {
+    /// executor the task has been dispatched to
+    pub client_id: String,
+    /// seconds elapsed since the task has been dispatched
+    pub age_secs: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminRejectedExecutorKeysJsonResponse {
+    /// client ids of the executors whose pending key has been removed
+    pub rejected: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminRevokedExecutorKeyJsonResponse {
+    pub removed_from_connected: bool,
+}
+
+fn list_authorized_keys(
+    keystore: &KeyStore<FileKeyStoreBackend>,
+    key_scopes: &KeyScopes,
+) -> Result<BTreeMap<String, AdminAuthorizedKeyJsonResponse>, KeyStoreError> {
+    Ok(keystore
+        .list_all_with_expiry()?
+        .into_iter()
+        .map(|(key_id, key)| {
+            let encoded = data_encoding::BASE64.encode(&key.bytes);
+            let allowed_queries = key_scopes.allowed_queries(&key_id).map(<[String]>::to_vec);
+            let key = match (key.expires_at, allowed_queries) {
+                (None, None) => AdminAuthorizedKeyJsonResponse::Key(encoded),
+                (expires_at, allowed_queries) => AdminAuthorizedKeyJsonResponse::Detailed {
+                    key: encoded,
+                    expires_at: expires_at
+                        .map(|expires_at| DateTime::<Local>::from(expires_at).to_rfc3339()),
+                    allowed_queries,
+                },
+            };
+            (key_id, key)
+        })
+        .collect())
+}
+
+/// Keys that never expire & can target any executor are rendered as before expiration dates
+/// existed (base64 encoded key)
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum AdminAuthorizedKeyJsonResponse {
+    Key(String),
+    Detailed {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<String>,
+        /// the key can only target the executors matching one of these queries
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        allowed_queries: Option<Vec<String>>,
+    },
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct AdminListExecutorKeysJsonResponse {
     pub trusted_executor_keys: BTreeMap<String, String>,
     pub unapproved_executor_keys: BTreeMap<String, String>,
+    /// keys presented by executors whose trusted key is another one, by client_id (absent from
+    /// older taskservers)
+    #[serde(default)]
+    pub conflicting_executor_keys: BTreeMap<String, KeyConflict>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminVerifyTaskJsonResponse {
+    pub task_id: String,
+    pub client_id: String,
+    /// true if every stored payload has been verified
+    pub verified: bool,
+    pub payloads: Vec<PayloadVerificationReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminWhoAmIJsonResponse {
+    pub key_id: String,
+    /// the key is an authorized key (commands may be run with it)
+    pub authorized: bool,
+    /// the key is an admin authorized key
+    pub admin: bool,
+    /// commander commands the key can be used for
+    pub allowed: Vec<String>,
+    /// the key can only target the executors matching one of these queries, None if unscoped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_queries: Option<Vec<String>>,
+    /// core version of the taskserver, not reported by older taskservers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<String>,
+}
+
+/// Json rendering of a structured admin error
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminErrorJsonResponse {
+    pub code: String,
+    pub message: String,
+    pub details: BTreeMap<String, String>,
+}
+
+impl From<&AdminError> for AdminErrorJsonResponse {
+    fn from(e: &AdminError) -> Self {
+        Self {
+            code: e.code().as_str_name().to_lowercase(),
+            message: e.message.clone(),
+            details: e.details.clone().into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::capabilities::{Capabilities, SCRIPTS};
+    use crate::task_server::TaskServer;
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use grpc_service::grpc_protocol::launch_task_response::TaskResponse;
+    use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+    use grpc_service::payload::SignedPayload;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    /// client_id & whether the task was submitted, of a dispatch report
+    fn dispatched(response: Option<TaskResponse>) -> (String, bool) {
+        match response {
+            Some(TaskResponse::TaskExecutionResult(result)) => (
+                result.client_id,
+                matches!(
+                    result.execution_result,
+                    Some(ExecutionResult::TaskSubmitted(_))
+                ),
+            ),
+            other => panic!("Not a task execution result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn wedged_executor_channel() {
+        let dir = tempfile::tempdir().unwrap();
+        let task_server = TaskServer::new(
+            dir.path(),
+            &BTreeMap::new(),
+            &BTreeMap::new(),
+            false,
+            Duration::ZERO,
+        )
+        .unwrap();
+        let task = || {
+            (
+                SignedPayload::default(),
+                None,
+                mpsc::channel(1).0,
+                Capabilities::default(),
+            )
+        };
+        // this sender already used its slot: it waits for the executor to read its tasks
+        let (mut wedged, mut wedged_receiver) = mpsc::channel(0);
+        wedged.try_send(task()).unwrap();
+        // a free slot: sending completes without the executor reading the task
+        let (first, mut first_receiver) = mpsc::channel(1);
+        let (second, mut second_receiver) = mpsc::channel(1);
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        tokio::spawn(async move {
+            task_server
+                .dispatch_task(
+                    SignedPayload::default(),
+                    None,
+                    vec![
+                        ("wedged".to_string(), Some(wedged), Capabilities::default()),
+                        ("first".to_string(), Some(first), Capabilities::default()),
+                        ("second".to_string(), Some(second), Capabilities::default()),
+                        ("disconnected".to_string(), None, Capabilities::default()),
+                    ],
+                    Capabilities::default(),
+                    vec![],
+                    sender,
+                    Instant::now(),
+                )
+                .await
+        });
+
+        let timeout = Duration::from_secs(5);
+        assert!(matches!(
+            receiver.next().await,
+            Some(TaskResponse::MatchingExecutors(_))
+        ));
+        let mut reports = vec![];
+        for _ in 0..3 {
+            let response = tokio::time::timeout(timeout, receiver.next())
+                .await
+                .expect("dispatch delayed by the wedged executor");
+            reports.push(dispatched(response));
+        }
+        reports.sort();
+        assert_eq!(
+            vec![
+                ("disconnected".to_string(), false),
+                ("first".to_string(), true),
+                ("second".to_string(), true),
+            ],
+            reports
+        );
+        assert!(first_receiver.try_next().unwrap().is_some());
+        assert!(second_receiver.try_next().unwrap().is_some());
+
+        // reported once the executor reads its tasks
+        wedged_receiver.next().await.unwrap();
+        assert!(wedged_receiver.next().await.is_some());
+        let response = tokio::time::timeout(timeout, receiver.next())
+            .await
+            .unwrap();
+        assert_eq!(("wedged".to_string(), true), dispatched(response));
+    }
+
+    #[tokio::test]
+    async fn unsupported_by_executor() {
+        let dir = tempfile::tempdir().unwrap();
+        let task_server = TaskServer::new(
+            dir.path(),
+            &BTreeMap::new(),
+            &BTreeMap::new(),
+            false,
+            Duration::ZERO,
+        )
+        .unwrap();
+        let (recent, mut recent_receiver) = mpsc::channel(1);
+        let (old, mut old_receiver) = mpsc::channel(1);
+        let (sender, receiver) = mpsc::channel(16);
+        task_server
+            .dispatch_task(
+                SignedPayload::default(),
+                None,
+                vec![
+                    ("recent".to_string(), Some(recent), Capabilities::local()),
+                    // older executors advertise no capabilities
+                    ("old".to_string(), Some(old), Capabilities::default()),
+                ],
+                Capabilities::default(),
+                vec![SCRIPTS],
+                sender,
+                Instant::now(),
+            )
+            .await
+            .unwrap();
+        let mut reports: Vec<_> = receiver
+            .skip(1)
+            .take(2)
+            .map(|response| match response {
+                TaskResponse::TaskExecutionResult(result) => {
+                    (result.client_id, result.execution_result.unwrap())
+                }
+                other => panic!("Not a task execution result: {:?}", other),
+            })
+            .collect()
+            .await;
+        reports.sort_by(|a, b| a.0.cmp(&b.0));
+        assert!(matches!(
+            &reports[0],
+            (client_id, ExecutionResult::TaskRejected(reason))
+                if client_id == "old" && reason.contains("scripts")
+        ));
+        assert!(matches!(reports[1].1, ExecutionResult::TaskSubmitted(_)));
+        assert!(recent_receiver.try_next().unwrap().is_some());
+        assert!(!matches!(old_receiver.try_next(), Ok(Some(_))));
+    }
 }
@@ -0,0 +1,84 @@
+use crate::executor_meta::ExecutorMeta;
+use rustbreak::deser::Yaml;
+use rustbreak::{FileDatabase, RustbreakError};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tracing::error;
+
+pub(crate) type ExecutorMetaDatabase = HashMap<String, ExecutorMeta>;
+
+/// Metas of the known executors, saved in a YAML file.
+///
+/// Writes only mark the database as modified: with thousands of known executors, serializing the
+/// whole database on each registration stalls the executors reconnecting all at once. The
+/// modifications are saved by [ExecutorMetas::flush], periodically & on shutdown.
+pub(crate) struct ExecutorMetas {
+    db: FileDatabase<ExecutorMetaDatabase, Yaml>,
+    /// modified since the last save
+    dirty: AtomicBool,
+    saves: AtomicU64,
+}
+
+impl ExecutorMetas {
+    /// Load the database file written by any taskserver version, creating it if missing
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Self, RustbreakError> {
+        Ok(Self {
+            db: super::open_database(path)?,
+            dirty: AtomicBool::new(false),
+            saves: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) fn read<F: FnOnce(&ExecutorMetaDatabase) -> R, R>(
+        &self,
+        read_function: F,
+    ) -> Result<R, RustbreakError> {
+        self.db.read(read_function)
+    }
+
+    /// The modification is saved by the next flush
+    pub(crate) fn write<F: FnOnce(&mut ExecutorMetaDatabase) -> R, R>(
+        &self,
+        write_function: F,
+    ) -> Result<R, RustbreakError> {
+        let result = self.db.write(write_function)?;
+        self.dirty.store(true, Ordering::SeqCst);
+        Ok(result)
+    }
+
+    /// Save the database now, eg: after an admin modification
+    pub(crate) fn save(&self) -> Result<(), RustbreakError> {
+        // cleared before saving: modifications made while saving are saved by the next flush
+        self.dirty.store(false, Ordering::SeqCst);
+        if let Err(e) = self.db.save() {
+            self.dirty.store(true, Ordering::SeqCst);
+            return Err(e);
+        }
+        self.saves.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Save the modifications made since the last save, if any
+    pub(crate) fn flush(&self) -> Result<(), RustbreakError> {
+        if self.dirty.load(Ordering::SeqCst) {
+            self.save()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Number of times the database file has been written
+    #[cfg(test)]
+    pub(crate) fn save_count(&self) -> u64 {
+        self.saves.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for ExecutorMetas {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("Unable to save known executors: {}", e);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use crate::capabilities::Capabilities;
+use futures::channel::mpsc;
+use grpc_service::grpc_protocol::launch_task_response::TaskResponse;
+use grpc_service::payload::SignedPayload;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Channel used to submit a task (and its delegation certificate, if any) to a connected executor,
+/// along with the sink where the executor reports the task execution and the capabilities of the
+/// commander
+pub type ExecutorSender = mpsc::Sender<(
+    SignedPayload,
+    Option<SignedPayload>,
+    mpsc::Sender<TaskResponse>,
+    Capabilities,
+)>;
+
+const DEFAULT_SHARD_COUNT: usize = 32;
+
+/// Connected executors senders by client_id.
+///
+/// Senders are spread over several independently locked shards so registrations, task dispatch
+/// and admin listings do not all contend on a single lock.
+pub struct ExecutorSenders {
+    shards: Vec<RwLock<HashMap<String, ExecutorSender>>>,
+    hasher: RandomState,
+}
+
+impl Default for ExecutorSenders {
+    fn default() -> Self {
+        Self::with_shards(DEFAULT_SHARD_COUNT)
+    }
+}
+
+impl ExecutorSenders {
+    pub fn with_shards(shard_count: usize) -> Self {
+        Self {
+            shards: (0..shard_count.max(1))
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, client_id: &str) -> &RwLock<HashMap<String, ExecutorSender>> {
+        &self.shards[self.hasher.hash_one(client_id) as usize % self.shards.len()]
+    }
+
+    // a panic while holding a shard lock cannot leave a HashMap half updated: ignore poisoning
+    fn read(
+        shard: &RwLock<HashMap<String, ExecutorSender>>,
+    ) -> RwLockReadGuard<'_, HashMap<String, ExecutorSender>> {
+        shard.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(
+        shard: &RwLock<HashMap<String, ExecutorSender>>,
+    ) -> RwLockWriteGuard<'_, HashMap<String, ExecutorSender>> {
+        shard.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Register the sender of an executor, returns the previously registered one if any
+    pub fn insert(&self, client_id: String, sender: ExecutorSender) -> Option<ExecutorSender> {
+        Self::write(self.shard(&client_id)).insert(client_id, sender)
+    }
+
+    pub fn remove(&self, client_id: &str) -> Option<ExecutorSender> {
+        Self::write(self.shard(client_id)).remove(client_id)
+    }
+
+    pub fn get(&self, client_id: &str) -> Option<ExecutorSender> {
+        Self::read(self.shard(client_id)).get(client_id).cloned()
+    }
+
+    pub fn contains(&self, client_id: &str) -> bool {
+        Self::read(self.shard(client_id)).contains_key(client_id)
+    }
+
+    /// client_ids of all connected executors
+    pub fn client_ids(&self) -> HashSet<String> {
+        self.shards
+            .iter()
+            .flat_map(|shard| Self::read(shard).keys().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| Self::read(shard).len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// End the task streams of all the connected executors
+    pub fn close_all(&self) {
+        for shard in &self.shards {
+            for sender in Self::write(shard).values_mut() {
+                sender.close_channel();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ExecutorSenders;
+    use futures::channel::mpsc;
+    use std::sync::Arc;
+
+    #[test]
+    fn insert_get_remove() {
+        let senders = ExecutorSenders::default();
+        let (first, _first_receiver) = mpsc::channel(1);
+        let (second, _second_receiver) = mpsc::channel(1);
+
+        assert!(senders.insert("exec".into(), first.clone()).is_none());
+        assert!(senders.get("exec").unwrap().same_receiver(&first));
+        // a reconnecting executor replaces its previous sender
+        assert!(senders
+            .insert("exec".into(), second.clone())
+            .unwrap()
+            .same_receiver(&first));
+        assert!(senders.get("exec").unwrap().same_receiver(&second));
+        assert_eq!(senders.len(), 1);
+
+        assert!(senders.remove("exec").unwrap().same_receiver(&second));
+        assert!(senders.remove("exec").is_none());
+        assert!(senders.get("exec").is_none());
+        assert!(senders.is_empty());
+    }
+
+    #[test]
+    fn concurrent_insert_remove_same_client_id() {
+        let senders = Arc::new(ExecutorSenders::with_shards(4));
+        let threads = (0..8)
+            .map(|_| {
+                let senders = senders.clone();
+                std::thread::spawn(move || {
+                    let (sender, _receiver) = mpsc::channel(1);
+                    for _ in 0..1000 {
+                        senders.insert("exec".into(), sender.clone());
+                        senders.get("exec");
+                        senders.remove("exec");
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(senders.is_empty());
+        assert!(!senders.contains("exec"));
+
+        // last writer wins
+        let threads = (0..8)
+            .map(|_| {
+                let senders = senders.clone();
+                std::thread::spawn(move || {
+                    let (sender, receiver) = mpsc::channel(1);
+                    senders.insert("exec".into(), sender);
+                    receiver
+                })
+            })
+            .collect::<Vec<_>>();
+        let receivers = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(senders.len(), 1);
+        let sender = senders.get("exec").unwrap();
+        assert_eq!(
+            1,
+            receivers
+                .iter()
+                .filter(|receiver| sender.is_connected_to(receiver))
+                .count()
+        );
+    }
+
+    #[test]
+    fn concurrent_distinct_client_ids() {
+        let senders = Arc::new(ExecutorSenders::default());
+        let threads = (0..8)
+            .map(|thread| {
+                let senders = senders.clone();
+                std::thread::spawn(move || {
+                    let (sender, _receiver) = mpsc::channel(1);
+                    for i in 0..100 {
+                        senders.insert(format!("exec-{}-{}", thread, i), sender.clone());
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(senders.len(), 800);
+        assert_eq!(senders.client_ids().len(), 800);
+        assert!(senders.contains("exec-7-99"));
+    }
+}
@@ -1,9 +1,14 @@
 use super::Stream;
+use crate::capabilities::Capabilities;
 use crate::executor_meta::ExecutorMeta;
-use crate::task_server::{get_task_sink, register_new_task, TaskServer};
+use crate::task_server::peer_identity::PeerNames;
+use crate::task_server::{
+    get_task_sink, register_new_task, remove_task_sink, remove_task_sinks, ExecutorSender,
+    TaskServer, PENDING_APPROVAL_MESSAGE,
+};
 use crate::tonic;
 use crate::PROTOCOL_VERSION;
-use futures::channel::mpsc;
+use futures::channel::{mpsc, oneshot};
 use futures::{SinkExt, StreamExt};
 use grpc_service::grpc_protocol::admin_request::RequestType;
 use grpc_service::grpc_protocol::admin_request_response::ResponseKind;
@@ -26,10 +31,18 @@ use std::io::Write;
 use std::path::Path;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use thiserror::Error;
 use tokio::time::Duration;
 use tonic::metadata::{Ascii, MetadataValue};
 use tonic::{Code, Request, Response, Status, Streaming};
+use tracing::{debug, error, field, info, warn, Span};
+
+/// How long the results of a task may be reported again after its last stream ended
+const RESULT_STREAM_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// How long the previous connection of a reconnecting executor may take to be seen closed
+const PREVIOUS_CONNECTION_CLOSE_DELAY: Duration = Duration::from_secs(1);
 
 #[tonic::async_trait]
 impl ExecutorService for TaskServer {
@@ -38,11 +51,18 @@ impl ExecutorService for TaskServer {
         &self,
         request: tonic::Request<RegisterExecutorRequest>,
     ) -> Result<tonic::Response<Self::GetTasksStream>, tonic::Status> {
+        let identity = self.peer_identity.of(&request);
+        if let Some(names) = self.peer_identity.executor_names(&request) {
+            names.check(&request.get_ref().client_id)?;
+        }
         let metadata = request.metadata();
         let request = request.get_ref();
 
         // check the public key of the executor
-        self.handle_executor_key(&request.client_id, &request.public_key)?;
+        if !self.handle_executor_key(&request.client_id, &request.public_key)? {
+            warn!("Key of {} is pending approval", request.client_id);
+            return Err(Status::permission_denied(PENDING_APPROVAL_MESSAGE));
+        }
 
         // decode the payload
         let request: GetTasksRequest = self.trusted_executor_keystore.decode_payload(
@@ -68,31 +88,96 @@ impl ExecutorService for TaskServer {
         }
 
         let client_id = request.client_id.clone();
-        info!("{} connected with meta {:?}", client_id, metadata);
+        let instance_id = request.instance_id.clone();
+        info!(
+            %instance_id,
+            "{} connected as {} with meta {:?}",
+            client_id,
+            identity.as_deref().unwrap_or("unidentified peer"),
+            metadata
+        );
         // register the client and wait for new tasks to come, forward them
         // to the response
-        let (sender, receiver) = mpsc::unbounded();
-        if let Err(e) = self.register_executor(&request, sender) {
+        let (sender, receiver) = mpsc::channel(self.stream_buffer_size);
+        self.previous_connection_closed(&request).await;
+        if let Err(e) = self.register_executor(&request, sender.clone()) {
             error!("Unable to register executor {}", e);
             Err(e)?;
         }
 
         let tasks_sinks = self.tasks_sinks.clone();
+        let task_ids = self.task_ids.clone();
+        let executor_capabilities = Capabilities::from_peer(&request.capabilities);
+        // dropped along with the stream, when the executor is gone
+        let disconnection = ExecutorDisconnection {
+            task_server: self.clone(),
+            client_id: client_id.clone(),
+            sender,
+        };
 
-        let response_stream = receiver.map(move |(payload, sender_to_commander)| {
-            // for each new task, register the task and forward it to the executor stream
-            let task_id = register_new_task(&tasks_sinks, sender_to_commander);
-            info!("Sending task {} - {:?} to {}", task_id, payload, client_id);
-            Ok(GetTaskStreamReply {
-                task_id,
-                payload: Some(payload),
-            })
-        });
+        let response_stream = receiver.map(
+            move |(payload, delegation, sender_to_commander, commander_capabilities)| {
+                // for each new task, register the task and forward it to the executor stream
+                let task_id = register_new_task(
+                    &tasks_sinks,
+                    task_ids.as_ref(),
+                    &disconnection.client_id,
+                    sender_to_commander,
+                );
+                let capabilities = executor_capabilities.intersection(&commander_capabilities);
+                info!(
+                    %task_id, client_id = %disconnection.client_id, %instance_id, %capabilities,
+                    "Sending task {:?}", payload
+                );
+                Ok(GetTaskStreamReply {
+                    task_id,
+                    payload: Some(payload),
+                    capabilities: capabilities.into(),
+                    delegation,
+                })
+            },
+        );
 
         Ok(Response::new(
             Box::pin(response_stream) as Self::GetTasksStream
         ))
     }
+    async fn update_meta(
+        &self,
+        request: tonic::Request<SignedPayload>,
+    ) -> Result<tonic::Response<Empty>, tonic::Status> {
+        let signed_payload = request.get_ref();
+        let peer_names = self.peer_identity.executor_names(&request);
+        let request: GetTasksRequest = self
+            .trusted_executor_keystore
+            .decode_payload(signed_payload)?;
+        if let Some(names) = peer_names {
+            names.check(&request.client_id)?;
+        }
+
+        // an executor can only update its own metas
+        if signed_payload.key_id != request.client_id {
+            warn!(
+                "{} tried to update metas of {}",
+                signed_payload.key_id, request.client_id
+            );
+            return Err(Status::permission_denied(format!(
+                "Metas of {} cannot be updated with key {}",
+                request.client_id, signed_payload.key_id
+            )));
+        }
+        if !self.executors.contains(&request.client_id) {
+            return Err(Status::failed_precondition(format!(
+                "{} is not connected",
+                request.client_id
+            )));
+        }
+        debug!("{} updated its metas", request.client_id);
+        self.store_executor_meta(&request, false)?;
+        Ok(Response::new(Empty {}))
+    }
+
+    #[tracing::instrument(skip_all, fields(task_id = field::Empty, client_id = field::Empty))]
     async fn task_execution(
         &self,
         request: tonic::Request<tonic::Streaming<SignedPayload>>,
@@ -100,55 +185,320 @@ impl ExecutorService for TaskServer {
         let task_id =
             String::from_utf8_lossy(request.metadata().get("task_id").unwrap().as_bytes())
                 .into_owned();
+        Span::current().record("task_id", task_id.as_str());
 
-        let mut request_stream = request.into_inner();
+        let peer_names = self.peer_identity.executor_names(&request);
+        let request_stream = request.into_inner();
         if let Some(sender) = get_task_sink(&self.tasks_sinks, &task_id) {
-            let mut sender = sender;
-            while let Some(task_execution_stream) = request_stream.next().await {
-                let signed_payload = task_execution_stream?;
-                let task_execution_stream: TaskExecutionResult = self
-                    .trusted_executor_keystore
-                    .decode_payload(&signed_payload)?;
+            self.task_results.stream_started(&task_id);
+            let cancelled = self.register_task_cancellation(&task_id);
+            let result = self
+                .forward_task_execution(
+                    &task_id,
+                    request_stream,
+                    peer_names.as_ref(),
+                    sender,
+                    cancelled,
+                )
+                .await;
+            self.task_stream_ended(&task_id);
+            result
+        } else if self.task_results.is_completed(&task_id) {
+            debug!("Task results already forwarded, ignoring them");
+            Ok(Response::new(Empty {}))
+        } else {
+            info!("Task sink not found, recording the late task results");
+            self.task_results.stream_started(&task_id);
+            let result = self
+                .record_late_task_execution(&task_id, request_stream, peer_names.as_ref())
+                .await;
+            self.task_stream_ended(&task_id);
+            result
+        }
+    }
+}
 
-                debug!(
-                    "Received task_execution_report {} - {}",
-                    task_execution_stream.client_id, task_id
-                );
-                if let Some(execution_result) = &task_execution_stream.execution_result {
-                    if let ExecutionResult::TaskRejected(reason) = execution_result {
-                        info!(
-                            "Task {} rejected ({}) on {}",
-                            task_id, reason, task_execution_stream.client_id,
-                        );
-                    }
-                    if let ExecutionResult::TaskAborted(_) = execution_result {
-                        info!(
-                            "Task {} aborted (killed) on {}",
-                            task_id, task_execution_stream.client_id,
-                        );
-                    }
-                    if let ExecutionResult::TaskCompleted(completed) = execution_result {
-                        info!(
-                            "Task {} completed with code {} on {}",
-                            task_id, task_execution_stream.client_id, completed.return_code
-                        );
+/// Reports the tasks dispatched to an executor as disconnected once its task stream is dropped,
+/// unless the executor already registered again
+struct ExecutorDisconnection {
+    task_server: TaskServer,
+    client_id: String,
+    sender: ExecutorSender,
+}
+
+impl Drop for ExecutorDisconnection {
+    fn drop(&mut self) {
+        let reconnected = self
+            .task_server
+            .executors
+            .get(&self.client_id)
+            .map(|current| !current.same_receiver(&self.sender))
+            .unwrap_or(false);
+        if reconnected {
+            debug!(client_id = %self.client_id, "Previous task stream of the executor dropped");
+        } else {
+            info!(client_id = %self.client_id, "Executor disconnected");
+            self.task_server.executor_disconnected(&self.client_id);
+        }
+    }
+}
+
+impl TaskServer {
+    /// An executor reconnecting (reload...) may register before its previous task stream is
+    /// dropped: without an instance id, it is not told apart from another executor with the same
+    /// client_id
+    async fn previous_connection_closed(&self, request: &GetTasksRequest) {
+        let started = Instant::now();
+        while self.connected_elsewhere(request)
+            && started.elapsed() < PREVIOUS_CONNECTION_CLOSE_DELAY
+        {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// The tasks still running on a gone executor will never be reported: their commanders are
+    /// told the executor is disconnected. The results of a task still streamed by the executor
+    /// (reconnecting after a key change or a failover) are not lost: its sink is kept.
+    fn executor_disconnected(&self, client_id: &str) {
+        for (task_id, sink) in remove_task_sinks(&self.tasks_sinks, |task_id, sink| {
+            sink.client_id == client_id && !self.task_results.is_reported(task_id)
+        }) {
+            if !self.task_results.is_completed(&task_id) {
+                warn!(%task_id, %client_id, "Executor disconnected while running the task");
+            }
+            self.report_task_disconnected(task_id, sink);
+        }
+    }
+
+    /// Once no stream reports the task results anymore, its sink is dropped if the task is
+    /// completed or after a grace period during which the executor may report them again
+    fn task_stream_ended(&self, task_id: &str) {
+        if !self.task_results.stream_ended(task_id) {
+            return;
+        }
+        self.unregister_task_cancellation(task_id);
+        if self.task_results.is_completed(task_id) {
+            remove_task_sink(&self.tasks_sinks, task_id);
+        }
+        let task_server = self.clone();
+        let task_id = task_id.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(RESULT_STREAM_GRACE_PERIOD).await;
+            if task_server.task_results.forget(&task_id) {
+                remove_task_sink(&task_server.tasks_sinks, &task_id);
+            }
+        });
+    }
+
+    /// Forward the execution results reported by the executor to the commander, until the task
+    /// completes or is cancelled
+    async fn forward_task_execution(
+        &self,
+        task_id: &str,
+        mut request_stream: Streaming<SignedPayload>,
+        peer_names: Option<&PeerNames>,
+        mut sender: mpsc::Sender<TaskResponse>,
+        mut cancelled: oneshot::Receiver<()>,
+    ) -> Result<Response<Empty>, Status> {
+        let mut client_id = None;
+        loop {
+            let signed_payload = tokio::select! {
+                next = request_stream.next() => match next {
+                    Some(signed_payload) => signed_payload?,
+                    None => break,
+                },
+                Ok(()) = &mut cancelled => {
+                    info!("Task cancelled on commander request");
+                    self.task_results.complete(task_id);
+                    if let Some(client_id) = client_id {
+                        let _ = sender
+                            .send(TaskResponse::TaskExecutionResult(TaskExecutionResult {
+                                task_id: task_id.to_string(),
+                                client_id,
+                                execution_result: Some(ExecutionResult::TaskCancelled(Empty {})),
+                                seq: 0,
+                            }))
+                            .await;
                     }
+                    // ending the stream makes the executor kill the task
+                    return Err(Status::cancelled("Task cancelled on commander request"));
                 }
-                if let Err(_e) = sender
-                    .send(TaskResponse::TaskExecutionResult(task_execution_stream))
-                    .await
-                {
+            };
+            let task_execution_stream: TaskExecutionResult = self
+                .trusted_executor_keystore
+                .decode_payload(&signed_payload)?;
+            if let Some(names) = peer_names {
+                names.check(&task_execution_stream.client_id)?;
+            }
+            if !self.task_results.accept(task_id, &task_execution_stream) {
+                debug!(
+                    seq = task_execution_stream.seq,
+                    duplicates = self.task_results.duplicates(),
+                    "Dropping result already forwarded"
+                );
+                continue;
+            }
+            if client_id.is_none() {
+                Span::current().record("client_id", task_execution_stream.client_id.as_str());
+            }
+            client_id = Some(task_execution_stream.client_id.clone());
+
+            debug!(
+                seq = task_execution_stream.seq,
+                "Received task execution report"
+            );
+            self.record_terminal_result(task_id, &task_execution_stream, &signed_payload);
+            #[cfg(feature = "failpoints")]
+            if let Some(delay) = self.failpoints.task_execution_delay() {
+                tokio::time::sleep(delay).await;
+            }
+            if let Err(_e) = sender
+                .send(TaskResponse::TaskExecutionResult(task_execution_stream))
+                .await
+            {
+                warn!("Commander disconnected, the executor kills the task if still running");
+                self.task_results.complete(task_id);
+                break;
+            }
+        }
+        Ok(Response::new(Empty {}))
+    }
+
+    /// Results reported after the task sink is gone: the taskserver restarted or the executor
+    /// was disconnected while running the task, and reports its results again. Nobody waits for
+    /// them anymore, the end of the task is only recorded, unless the task history tells the task
+    /// ran on another executor or already ended.
+    async fn record_late_task_execution(
+        &self,
+        task_id: &str,
+        mut request_stream: Streaming<SignedPayload>,
+        peer_names: Option<&PeerNames>,
+    ) -> Result<Response<Empty>, Status> {
+        while let Some(signed_payload) = request_stream.next().await {
+            let signed_payload = signed_payload?;
+            let task_execution_stream: TaskExecutionResult = self
+                .trusted_executor_keystore
+                .decode_payload(&signed_payload)?;
+            // without task sink, nothing tells which executor ran the task but the signature
+            if task_execution_stream.task_id != task_id
+                || task_execution_stream.client_id != signed_payload.key_id
+            {
+                warn!(
+                    key_id = %signed_payload.key_id,
+                    "Late results of another task or executor"
+                );
+                return Err(Status::permission_denied(
+                    "Results of another task or executor",
+                ));
+            }
+            if let Some(names) = peer_names {
+                names.check(&task_execution_stream.client_id)?;
+            }
+            match self.recorded_executor(task_id)? {
+                Some(recorded) if recorded != task_execution_stream.client_id => {
                     warn!(
-                        "Commander disconnected for task {}, task will be killed by executor if not already done.",
-                        task_id
+                        key_id = %signed_payload.key_id,
+                        %recorded,
+                        "Late results of a task run by another executor"
                     );
-                    break;
+                    return Err(Status::permission_denied(
+                        "Results of a task run by another executor",
+                    ));
                 }
+                _ => {}
             }
-            Ok(Response::new(Empty {}))
-        } else {
-            error!("Task id not found {}", task_id);
-            Err(tonic::Status::new(Code::NotFound, "task_id not found"))
+            if !self.task_results.accept(task_id, &task_execution_stream) {
+                continue;
+            }
+            Span::current().record("client_id", task_execution_stream.client_id.as_str());
+            debug!(
+                seq = task_execution_stream.seq,
+                "Received late task execution report"
+            );
+            self.record_terminal_result(task_id, &task_execution_stream, &signed_payload);
+        }
+        Ok(Response::new(Empty {}))
+    }
+
+    /// Record & log the end of a task
+    fn record_terminal_result(
+        &self,
+        task_id: &str,
+        task_execution_stream: &TaskExecutionResult,
+        signed_payload: &SignedPayload,
+    ) {
+        let execution_result = match &task_execution_stream.execution_result {
+            Some(execution_result) => execution_result,
+            None => return,
+        };
+        if let ExecutionResult::TaskRejected(_)
+        | ExecutionResult::TaskAborted(_)
+        | ExecutionResult::TaskCompleted(_) = execution_result
+        {
+            match self.record_signed_result(
+                task_id,
+                &task_execution_stream.client_id,
+                signed_payload,
+            ) {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!("Task already ended, ignoring another terminal result");
+                    return;
+                }
+                Err(e) => error!("Unable to record the task result: {}", e),
+            }
+        }
+        if let ExecutionResult::TaskRejected(reason) = execution_result {
+            info!(%reason, "Task rejected");
+        }
+        if let ExecutionResult::TaskAborted(aborted) = execution_result {
+            info!(reason = %aborted.reason, "Task aborted");
+        }
+        if let ExecutionResult::TaskCompleted(completed) = execution_result {
+            info!(return_code = completed.return_code, "Task completed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::task_server::register_new_task;
+    use crate::task_server::test::task_server;
+    use futures::channel::mpsc;
+    use grpc_service::grpc_protocol::launch_task_response::TaskResponse;
+    use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+
+    #[test]
+    fn executor_disconnected() {
+        let dir = tempfile::tempdir().unwrap();
+        let task_server = task_server(dir.path());
+        let (sender, mut receiver) = mpsc::channel(1);
+        let register = |sender| {
+            register_new_task(
+                &task_server.tasks_sinks,
+                task_server.task_ids.as_ref(),
+                "exec",
+                sender,
+            )
+        };
+        let lost = register(sender.clone());
+        let streamed = register(sender);
+        task_server.task_results.stream_started(&streamed);
+
+        task_server.executor_disconnected("exec");
+        // the task whose results are being reported is kept
+        let running = task_server.get_running_tasks().unwrap();
+        assert_eq!(vec![&streamed], running.keys().collect::<Vec<_>>());
+        match receiver.try_next() {
+            Ok(Some(TaskResponse::TaskExecutionResult(result))) => {
+                assert_eq!(lost, result.task_id);
+                assert!(matches!(
+                    result.execution_result,
+                    Some(ExecutionResult::Disconnected(_))
+                ));
+            }
+            other => panic!("Not a task execution result: {:?}", other),
         }
+        assert!(receiver.try_next().is_err());
     }
 }
@@ -0,0 +1,134 @@
+//! Failures injected in the taskserver to test how commanders & executors cope with a
+//! misbehaving server. Only compiled with the `failpoints` feature.
+//!
+//! Failpoints are set with the `SetFailpoint` admin request, `off` disables a failpoint:
+//!
+//! - `drop_executor_channel`: `N`, drop the channel of the executor receiving the Nth task
+//!   dispatch from now (`1` is the next one)
+//! - `delay_task_execution`: `MILLIS`, delay the forwarding of each task execution result
+//! - `launch_task_internal`: `N`, the next N launch_task calls fail with an Internal status
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tracing::warn;
+
+#[derive(Default, Debug)]
+struct State {
+    /// dispatches left before dropping an executor channel
+    drop_executor_channel: Option<u64>,
+    delay_task_execution: Option<Duration>,
+    launch_task_failures: u64,
+}
+
+#[derive(Default)]
+pub struct Failpoints {
+    state: Mutex<State>,
+}
+
+impl Failpoints {
+    // a panic while holding the lock cannot leave the state half updated: ignore poisoning
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set(&self, name: &str, config: &str) -> Result<(), String> {
+        let off = config == "off";
+        let mut state = self.lock();
+        match name {
+            "drop_executor_channel" => {
+                state.drop_executor_channel = if off {
+                    None
+                } else {
+                    Some(parse_count(config)?.max(1))
+                }
+            }
+            "delay_task_execution" => {
+                state.delay_task_execution = if off {
+                    None
+                } else {
+                    Some(Duration::from_millis(parse_count(config)?))
+                }
+            }
+            "launch_task_internal" => {
+                state.launch_task_failures = if off { 0 } else { parse_count(config)? }
+            }
+            _ => return Err(format!("Unknown failpoint {}", name)),
+        }
+        warn!("Failpoint {} set to {}, now {:?}", name, config, *state);
+        Ok(())
+    }
+
+    /// Whether the channel of the executor the task is about to be dispatched to must be dropped
+    pub fn drop_executor_channel(&self) -> bool {
+        let mut state = self.lock();
+        match state.drop_executor_channel {
+            Some(1) => {
+                state.drop_executor_channel = None;
+                true
+            }
+            Some(left) => {
+                state.drop_executor_channel = Some(left - 1);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn task_execution_delay(&self) -> Option<Duration> {
+        self.lock().delay_task_execution
+    }
+
+    /// Whether this launch_task call must fail
+    pub fn launch_task_failure(&self) -> bool {
+        let mut state = self.lock();
+        if state.launch_task_failures > 0 {
+            state.launch_task_failures -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn parse_count(config: &str) -> Result<u64, String> {
+    config
+        .parse()
+        .map_err(|_| format!("Invalid failpoint configuration {}", config))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Failpoints;
+    use std::time::Duration;
+
+    #[test]
+    fn failpoints() {
+        let failpoints = Failpoints::default();
+        assert!(!failpoints.drop_executor_channel());
+        assert!(!failpoints.launch_task_failure());
+        assert_eq!(None, failpoints.task_execution_delay());
+
+        failpoints.set("drop_executor_channel", "3").unwrap();
+        assert_eq!(
+            vec![false, false, true, false],
+            (0..4)
+                .map(|_| failpoints.drop_executor_channel())
+                .collect::<Vec<_>>()
+        );
+
+        failpoints.set("launch_task_internal", "2").unwrap();
+        assert!(failpoints.launch_task_failure());
+        assert!(failpoints.launch_task_failure());
+        assert!(!failpoints.launch_task_failure());
+
+        failpoints.set("delay_task_execution", "250").unwrap();
+        assert_eq!(
+            Some(Duration::from_millis(250)),
+            failpoints.task_execution_delay()
+        );
+        failpoints.set("delay_task_execution", "off").unwrap();
+        assert_eq!(None, failpoints.task_execution_delay());
+
+        assert!(failpoints.set("unknown", "1").is_err());
+        assert!(failpoints.set("launch_task_internal", "often").is_err());
+    }
+}
@@ -0,0 +1,96 @@
+//! Executors the commander keys are allowed to target, from the `allowed_queries` of the
+//! `authorized_keys` of the taskserver configuration.
+use query_parser::{parse, Query, QueryParseError};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[error("Invalid allowed query {query:?} of key {key_id}: {source}")]
+pub struct KeyScopeError {
+    key_id: String,
+    query: String,
+    source: QueryParseError,
+}
+
+/// Allowed queries by key id: the executors targeted by a scoped key must each match one of its
+/// allowed queries. Keys without scope may target any executor.
+#[derive(Debug, Default)]
+pub struct KeyScopes(BTreeMap<String, Vec<String>>);
+
+impl KeyScopes {
+    pub fn new(scopes: BTreeMap<String, Vec<String>>) -> Result<Self, KeyScopeError> {
+        for (key_id, queries) in &scopes {
+            for query in queries {
+                parse(query).map_err(|source| KeyScopeError {
+                    key_id: key_id.clone(),
+                    query: query.clone(),
+                    source,
+                })?;
+            }
+        }
+        Ok(Self(scopes))
+    }
+
+    /// None if the key is not scoped
+    pub fn allowed_queries(&self, key_id: &str) -> Option<&[String]> {
+        self.0.get(key_id).map(Vec::as_slice)
+    }
+
+    /// Parsed allowed queries of the key, None if the key is not scoped
+    pub(crate) fn parsed_allowed_queries(&self, key_id: &str) -> Option<Vec<Query<'_>>> {
+        self.allowed_queries(key_id).map(|queries| {
+            queries
+                .iter()
+                // checked when loaded
+                .filter_map(|query| parse(query).ok())
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::KeyScopes;
+    use crate::config::AuthorizedKey;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn scoped_keys() {
+        let keys: BTreeMap<String, AuthorizedKey> = serde_yaml::from_str(
+            "ops: b3Bz\n\
+             ci:\n  key: Y2k=\n  allowed_queries: [\"env:staging\", \"role:web\"]\n\
+             unscoped:\n  key: dW5zY29wZWQ=\n",
+        )
+        .unwrap();
+        assert_eq!(AuthorizedKey::from("b3Bz".to_string()), keys["ops"]);
+        assert_eq!("Y2k=", keys["ci"].key);
+        assert_eq!(None, keys["unscoped"].allowed_queries);
+        // plain keys are written as before
+        assert_eq!(
+            "b3Bz\n",
+            serde_yaml::to_string(&keys["ops"])
+                .unwrap()
+                .trim_start_matches("---\n")
+        );
+
+        let scopes = KeyScopes::new(
+            keys.into_iter()
+                .filter_map(|(key_id, key)| key.allowed_queries.map(|queries| (key_id, queries)))
+                .collect(),
+        )
+        .unwrap();
+        assert_eq!(None, scopes.allowed_queries("ops"));
+        assert_eq!(
+            Some(&["env:staging".to_string(), "role:web".to_string()][..]),
+            scopes.allowed_queries("ci")
+        );
+        assert_eq!(2, scopes.parsed_allowed_queries("ci").unwrap().len());
+
+        let mut invalid = BTreeMap::new();
+        invalid.insert("ci".to_string(), vec!["env:staging and".to_string()]);
+        assert!(KeyScopes::new(invalid)
+            .unwrap_err()
+            .to_string()
+            .starts_with("Invalid allowed query \"env:staging and\" of key ci"));
+    }
+}
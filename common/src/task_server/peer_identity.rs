@@ -0,0 +1,334 @@
+//! Identity of the peers connected to the taskserver: the CN of their TLS client certificate, or
+//! the identity forwarded by a trusted TLS terminating reverse proxy (nginx, envoy...).
+//!
+//! The names of the certificates (CN & SANs) may also be checked: an executor certificate must
+//! name the client_id of the executor, a commander certificate one of the allowed commanders.
+use crate::tonic;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use thiserror::Error;
+use tonic::metadata::{Ascii, MetadataKey, MetadataMap};
+use tonic::{Request, Status};
+use tracing::warn;
+use x509_parser::extensions::GeneralName;
+
+#[derive(Error, Debug)]
+pub enum PeerIdentityError {
+    #[error("Invalid trusted proxy header {0}")]
+    InvalidHeader(String),
+    #[error("Invalid trusted proxy cidr {0}: {1}")]
+    InvalidCidr(String, ipnet::AddrParseError),
+    #[error("Peer identified as {names:?} is not executor {client_id}")]
+    ExecutorMismatch {
+        client_id: String,
+        names: Vec<String>,
+    },
+    #[error("Peer identified as {0:?} is not an allowed commander")]
+    CommanderNotAllowed(Vec<String>),
+}
+
+impl From<PeerIdentityError> for Status {
+    fn from(e: PeerIdentityError) -> Self {
+        match e {
+            PeerIdentityError::ExecutorMismatch { .. }
+            | PeerIdentityError::CommanderNotAllowed(_) => {
+                warn!("{}", e);
+                Status::permission_denied(e.to_string())
+            }
+            PeerIdentityError::InvalidHeader(_) | PeerIdentityError::InvalidCidr(..) => {
+                Status::internal(e.to_string())
+            }
+        }
+    }
+}
+
+struct TrustedProxies {
+    header: MetadataKey<Ascii>,
+    cidrs: Vec<IpNet>,
+}
+
+#[derive(Default)]
+pub struct PeerIdentity {
+    trusted_proxies: Option<TrustedProxies>,
+    /// executors must be named after their client_id
+    verify_executors: bool,
+    /// names of the commanders, any commander is accepted if empty
+    allowed_commanders: Vec<String>,
+}
+
+/// Names of a peer, see [`PeerIdentity::executor_names`]
+#[derive(Debug)]
+pub struct PeerNames(Vec<String>);
+
+impl PeerIdentity {
+    /// The `header` set by the proxies is honoured only for the connections coming from `cidrs`
+    pub fn new(header: Option<&str>, cidrs: &[String]) -> Result<Self, PeerIdentityError> {
+        let trusted_proxies = match header {
+            None => None,
+            Some(header) => Some(TrustedProxies {
+                header: MetadataKey::from_bytes(header.to_lowercase().as_bytes())
+                    .map_err(|_| PeerIdentityError::InvalidHeader(header.to_string()))?,
+                cidrs: cidrs
+                    .iter()
+                    .map(|cidr| parse_cidr(cidr))
+                    .collect::<Result<_, _>>()?,
+            }),
+        };
+        Ok(Self {
+            trusted_proxies,
+            ..Default::default()
+        })
+    }
+
+    /// The CN or a SAN of the executors certificates must be their client_id
+    pub fn with_verified_executors(mut self) -> Self {
+        self.verify_executors = true;
+        self
+    }
+
+    /// The CN or a SAN of the commanders certificates must be one of `names`
+    pub fn with_allowed_commanders(mut self, names: Vec<String>) -> Self {
+        self.allowed_commanders = names;
+        self
+    }
+
+    pub fn of<T>(&self, request: &Request<T>) -> Option<String> {
+        let peer_cert_cn = request.peer_certs().and_then(|certs| {
+            certs
+                .first()
+                .and_then(|cert| certificate_cn(cert.get_ref()))
+        });
+        self.resolve(request.remote_addr(), request.metadata(), peer_cert_cn)
+    }
+
+    /// Names to check the client_id claimed by an executor against, if executors are verified
+    pub fn executor_names<T>(&self, request: &Request<T>) -> Option<PeerNames> {
+        self.verify_executors
+            .then(|| PeerNames(self.names_of(request)))
+    }
+
+    pub fn check_commander<T>(&self, request: &Request<T>) -> Result<(), PeerIdentityError> {
+        if self.allowed_commanders.is_empty() {
+            return Ok(());
+        }
+        self.check_commander_names(self.names_of(request))
+    }
+
+    fn check_commander_names(&self, names: Vec<String>) -> Result<(), PeerIdentityError> {
+        if names
+            .iter()
+            .any(|name| self.allowed_commanders.contains(name))
+        {
+            Ok(())
+        } else {
+            Err(PeerIdentityError::CommanderNotAllowed(names))
+        }
+    }
+
+    /// The forwarded identity, or the CN & the SANs of the peer certificate
+    fn names_of<T>(&self, request: &Request<T>) -> Vec<String> {
+        match self.forwarded(request.remote_addr(), request.metadata()) {
+            Some(forwarded) => vec![forwarded],
+            None => request
+                .peer_certs()
+                .and_then(|certs| certs.first().map(|cert| certificate_names(cert.get_ref())))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The forwarded identity takes precedence: the direct peer is the proxy
+    fn resolve(
+        &self,
+        remote_addr: Option<SocketAddr>,
+        metadata: &MetadataMap,
+        peer_cert_cn: Option<String>,
+    ) -> Option<String> {
+        self.forwarded(remote_addr, metadata).or(peer_cert_cn)
+    }
+
+    /// The identity set by a trusted proxy
+    fn forwarded(&self, remote_addr: Option<SocketAddr>, metadata: &MetadataMap) -> Option<String> {
+        let trusted_proxies = self.trusted_proxies.as_ref()?;
+        let forwarded = metadata.get(&trusted_proxies.header)?;
+        match remote_addr {
+            Some(addr) if trusted_proxies.trusts(addr) => match forwarded.to_str() {
+                Ok(identity) => Some(identity.to_string()),
+                Err(_) => {
+                    warn!(
+                        "Ignoring non ascii {} header sent by {}",
+                        trusted_proxies.header, addr
+                    );
+                    None
+                }
+            },
+            _ => {
+                warn!(
+                    "Ignoring {} header sent by untrusted peer {:?}",
+                    trusted_proxies.header, remote_addr
+                );
+                None
+            }
+        }
+    }
+}
+
+impl PeerNames {
+    pub fn check(&self, client_id: &str) -> Result<(), PeerIdentityError> {
+        if self.0.iter().any(|name| name == client_id) {
+            Ok(())
+        } else {
+            Err(PeerIdentityError::ExecutorMismatch {
+                client_id: client_id.to_string(),
+                names: self.0.clone(),
+            })
+        }
+    }
+}
+
+impl TrustedProxies {
+    fn trusts(&self, addr: SocketAddr) -> bool {
+        let ip = addr.ip().to_canonical();
+        self.cidrs.iter().any(|cidr| cidr.contains(&ip))
+    }
+}
+
+/// A single address is accepted as a /32 (or /128) network
+fn parse_cidr(cidr: &str) -> Result<IpNet, PeerIdentityError> {
+    cidr.parse::<IpNet>()
+        .or_else(|e| cidr.parse::<IpAddr>().map(IpNet::from).map_err(|_| e))
+        .map_err(|e| PeerIdentityError::InvalidCidr(cidr.to_string(), e))
+}
+
+fn certificate_cn(der: &[u8]) -> Option<String> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(der).ok()?;
+    let cn = certificate.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(str::to_string)
+}
+
+/// The CNs followed by the DNS, email & URI SANs of the certificate
+fn certificate_names(der: &[u8]) -> Vec<String> {
+    let Ok((_, certificate)) = x509_parser::parse_x509_certificate(der) else {
+        return vec![];
+    };
+    let mut names: Vec<String> = certificate
+        .subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok().map(str::to_string))
+        .collect();
+    if let Ok(Some(san)) = certificate.subject_alternative_name() {
+        for name in &san.value.general_names {
+            if let GeneralName::DNSName(name)
+            | GeneralName::RFC822Name(name)
+            | GeneralName::URI(name) = name
+            {
+                // the CN is usually repeated as a SAN
+                if !names.iter().any(|known| known == name) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod test {
+    use super::{certificate_cn, certificate_names, PeerIdentity, PeerNames};
+    use crate::tonic::metadata::MetadataMap;
+
+    fn forwarded(identity: &str) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        metadata.insert("x-forwarded-client-cn", identity.parse().unwrap());
+        metadata
+    }
+
+    #[test]
+    fn cidrs() {
+        let identity = PeerIdentity::new(
+            Some("X-Forwarded-Client-CN"),
+            &["10.1.0.0/16".to_string(), "fd00::1".to_string()],
+        )
+        .unwrap();
+        let metadata = forwarded("executor-1");
+        let resolve = |addr: &str| identity.resolve(Some(addr.parse().unwrap()), &metadata, None);
+
+        assert_eq!(Some("executor-1".to_string()), resolve("10.1.2.3:443"));
+        assert_eq!(
+            Some("executor-1".to_string()),
+            resolve("[::ffff:10.1.2.3]:443")
+        );
+        assert_eq!(Some("executor-1".to_string()), resolve("[fd00::1]:443"));
+        assert_eq!(None, resolve("10.2.0.1:443"));
+        assert_eq!(None, resolve("[fd00::2]:443"));
+        assert_eq!(None, identity.resolve(None, &metadata, None));
+
+        assert!(PeerIdentity::new(Some("x-proxy"), &["10.1.0.0/33".to_string()]).is_err());
+        assert!(PeerIdentity::new(Some("x proxy"), &[]).is_err());
+    }
+
+    #[test]
+    fn precedence() {
+        let proxy = Some("10.0.0.1:443".parse().unwrap());
+        let spoofer = Some("192.168.0.1:443".parse().unwrap());
+        let cert_cn = || Some("direct".to_string());
+        let identity =
+            PeerIdentity::new(Some("x-forwarded-client-cn"), &["10.0.0.0/8".to_string()]).unwrap();
+
+        assert_eq!(
+            Some("forwarded".to_string()),
+            identity.resolve(proxy, &forwarded("forwarded"), cert_cn())
+        );
+        assert_eq!(
+            cert_cn(),
+            identity.resolve(spoofer, &forwarded("forwarded"), cert_cn())
+        );
+        assert_eq!(
+            cert_cn(),
+            identity.resolve(proxy, &MetadataMap::new(), cert_cn())
+        );
+        // headers are never trusted without configuration
+        assert_eq!(
+            cert_cn(),
+            PeerIdentity::default().resolve(proxy, &forwarded("forwarded"), cert_cn())
+        );
+    }
+
+    #[test]
+    fn certificate() {
+        let pem = std::fs::read("../integration/tls/funtonic-ca.pem").unwrap();
+        let (_, pem) = x509_parser::pem::parse_x509_pem(&pem).unwrap();
+        assert_eq!(
+            Some("example.net".to_string()),
+            certificate_cn(&pem.contents)
+        );
+        assert_eq!(None, certificate_cn(b"not a certificate"));
+    }
+
+    #[test]
+    fn certificate_names_check() {
+        let pem = std::fs::read("../integration/tls/exec.pem").unwrap();
+        let (_, pem) = x509_parser::pem::parse_x509_pem(&pem).unwrap();
+        let names = certificate_names(&pem.contents);
+        assert_eq!(vec!["exec".to_string()], names);
+        assert!(certificate_names(b"not a certificate").is_empty());
+
+        let names = PeerNames(names);
+        assert!(names.check("exec").is_ok());
+        assert_eq!(
+            "Peer identified as [\"exec\"] is not executor other",
+            names.check("other").unwrap_err().to_string()
+        );
+        // without certificate
+        assert!(PeerNames(vec![]).check("exec").is_err());
+
+        let identity = PeerIdentity::default()
+            .with_allowed_commanders(vec!["ops".to_string(), "ci".to_string()]);
+        assert!(identity
+            .check_commander_names(vec!["laptop".to_string(), "ci".to_string()])
+            .is_ok());
+        assert!(identity
+            .check_commander_names(vec!["exec".to_string()])
+            .is_err());
+        assert!(identity.check_commander_names(vec![]).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+//! Sanity checks of the taskserver host: with a wrong clock every signed payload looks expired,
+//! with a starved entropy source keys & nonces cannot be generated.
+use crate::config::PreflightConfig;
+use crate::tokio;
+use chrono::{DateTime, Local};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+use tracing::{error, warn};
+
+/// Data files written up to this long "in the future" are not reported
+const FILE_MTIME_TOLERANCE: Duration = Duration::from_secs(60);
+const ENTROPY_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_TIME_JUMP_THRESHOLD_SECS: u64 = 5;
+const CLOCK_MONITOR_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum PreflightError {
+    #[error("System time {now} is before the last modification of {path} ({modified}), the system clock is probably wrong")]
+    ClockBeforeDataFile {
+        now: String,
+        path: String,
+        modified: String,
+    },
+    #[error("System time {now} is before the configured earliest time {earliest}, the system clock is probably wrong")]
+    ClockBeforeEarliestTime { now: String, earliest: String },
+    #[error("Invalid preflight earliest_time {0}: {1}")]
+    InvalidEarliestTime(String, chrono::ParseError),
+    #[error("The system random generator did not produce any byte within {0:?}, entropy may be exhausted")]
+    EntropyTimeout(Duration),
+    #[error("The system random generator failed")]
+    EntropyFailure,
+}
+
+#[derive(Error, Debug)]
+#[error("{} preflight check(s) failed", .0.len())]
+pub struct PreflightFailed(pub Vec<PreflightError>);
+
+/// Check the system clock & the entropy source, the failures are logged and only prevent the
+/// taskserver to start if the preflight is `strict`
+pub fn preflight(config: &PreflightConfig, data_directory: &Path) -> Result<(), PreflightFailed> {
+    let failures: Vec<_> = vec![
+        check_clock(config, data_directory, SystemTime::now()),
+        check_entropy(
+            || {
+                let mut bytes = [0u8; 32];
+                SystemRandom::new().fill(&mut bytes).is_ok()
+            },
+            ENTROPY_TIMEOUT,
+        ),
+    ]
+    .into_iter()
+    .filter_map(Result::err)
+    .collect();
+    for failure in &failures {
+        if config.strict {
+            error!("Preflight: {}", failure);
+        } else {
+            warn!("Preflight: {}", failure);
+        }
+    }
+    if config.strict && !failures.is_empty() {
+        Err(PreflightFailed(failures))
+    } else {
+        Ok(())
+    }
+}
+
+/// The system clock must not be before the configured earliest time nor before the data files
+/// written by a previous run
+fn check_clock(
+    config: &PreflightConfig,
+    data_directory: &Path,
+    now: SystemTime,
+) -> Result<(), PreflightError> {
+    if let Some(earliest) = &config.earliest_time {
+        let earliest_time = DateTime::parse_from_rfc3339(earliest)
+            .map_err(|e| PreflightError::InvalidEarliestTime(earliest.clone(), e))?;
+        if DateTime::<Local>::from(now) < earliest_time {
+            return Err(PreflightError::ClockBeforeEarliestTime {
+                now: format_time(now),
+                earliest: earliest.clone(),
+            });
+        }
+    }
+
+    // unreadable entries are not this check business
+    let newest = std::fs::read_dir(data_directory)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .max_by_key(|(modified, _)| *modified);
+    match newest {
+        Some((modified, path)) if now + FILE_MTIME_TOLERANCE < modified => {
+            Err(PreflightError::ClockBeforeDataFile {
+                now: format_time(now),
+                path: path.display().to_string(),
+                modified: format_time(modified),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// `fill` is run in a dedicated thread: a blocked entropy source never returns
+fn check_entropy<F: FnOnce() -> bool + Send + 'static>(
+    fill: F,
+    timeout: Duration,
+) -> Result<(), PreflightError> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(fill());
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(PreflightError::EntropyFailure),
+        Err(_) => Err(PreflightError::EntropyTimeout(timeout)),
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    DateTime::<Local>::from(time).to_rfc3339()
+}
+
+/// Periodically log the backward jumps of the system clock larger than `threshold`: the
+/// signatures validity dates are checked against it
+pub fn start_clock_monitor(threshold: Duration) {
+    tokio::spawn(async move {
+        let mut monitor = ClockMonitor::new(SystemTime::now(), Instant::now());
+        loop {
+            tokio::time::sleep(CLOCK_MONITOR_INTERVAL).await;
+            if let Some(jump) = monitor.check(SystemTime::now(), Instant::now(), threshold) {
+                warn!(
+                    "System clock went backwards by {:?}, signed payloads may be rejected",
+                    jump
+                );
+            }
+        }
+    });
+}
+
+/// Compares the system clock with the monotonic one
+struct ClockMonitor {
+    system: SystemTime,
+    monotonic: Instant,
+}
+
+impl ClockMonitor {
+    fn new(system: SystemTime, monotonic: Instant) -> Self {
+        Self { system, monotonic }
+    }
+
+    /// Backward jump of the system clock since the previous check, if larger than `threshold`
+    fn check(
+        &mut self,
+        system: SystemTime,
+        monotonic: Instant,
+        threshold: Duration,
+    ) -> Option<Duration> {
+        let expected = self.system + monotonic.saturating_duration_since(self.monotonic);
+        self.system = system;
+        self.monotonic = monotonic;
+        expected
+            .duration_since(system)
+            .ok()
+            .filter(|jump| *jump > threshold)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_clock, check_entropy, ClockMonitor, PreflightError};
+    use crate::config::PreflightConfig;
+    use std::time::{Duration, Instant, SystemTime};
+
+    #[test]
+    fn clock() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::now();
+        let config = PreflightConfig::default();
+        // no data yet
+        check_clock(&config, dir.path(), now).unwrap();
+
+        std::fs::write(dir.path().join("known_executors.yml"), "---\n{}").unwrap();
+        check_clock(&config, dir.path(), now).unwrap();
+        // the clock went one hour back since the file was written
+        assert!(matches!(
+            check_clock(&config, dir.path(), now - Duration::from_secs(3600)),
+            Err(PreflightError::ClockBeforeDataFile { path, .. })
+                if path.ends_with("known_executors.yml")
+        ));
+
+        let config = PreflightConfig {
+            earliest_time: Some("2021-06-01T00:00:00Z".to_string()),
+            ..Default::default()
+        };
+        check_clock(&config, dir.path(), now).unwrap();
+        // the clock of a VM restored without RTC
+        assert!(matches!(
+            check_clock(&config, dir.path(), SystemTime::UNIX_EPOCH),
+            Err(PreflightError::ClockBeforeEarliestTime { .. })
+        ));
+        let config = PreflightConfig {
+            earliest_time: Some("June 2021".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            check_clock(&config, dir.path(), now),
+            Err(PreflightError::InvalidEarliestTime(_, _))
+        ));
+    }
+
+    #[test]
+    fn entropy() {
+        check_entropy(|| true, Duration::from_secs(1)).unwrap();
+        assert!(matches!(
+            check_entropy(|| false, Duration::from_secs(1)),
+            Err(PreflightError::EntropyFailure)
+        ));
+        assert!(matches!(
+            check_entropy(
+                || {
+                    std::thread::sleep(Duration::from_secs(2));
+                    true
+                },
+                Duration::from_millis(100)
+            ),
+            Err(PreflightError::EntropyTimeout(_))
+        ));
+    }
+
+    #[test]
+    fn time_jumps() {
+        let threshold = Duration::from_secs(5);
+        let system = SystemTime::now();
+        let monotonic = Instant::now();
+        let mut monitor = ClockMonitor::new(system, monotonic);
+        let tick = Duration::from_secs(10);
+
+        assert_eq!(
+            None,
+            monitor.check(system + tick, monotonic + tick, threshold)
+        );
+        // NTP adjustments are tolerated, whatever their direction
+        assert_eq!(
+            None,
+            monitor.check(
+                system + 2 * tick - Duration::from_secs(3),
+                monotonic + 2 * tick,
+                threshold
+            )
+        );
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            monitor.check(
+                system + 3 * tick - Duration::from_secs(63),
+                monotonic + 3 * tick,
+                threshold
+            )
+        );
+        // forward jumps do not expire anything
+        assert_eq!(
+            None,
+            monitor.check(
+                system + Duration::from_secs(3600),
+                monotonic + 4 * tick,
+                threshold
+            )
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+use grpc_service::grpc_protocol::TaskExecutionResult;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Delivery state of the results reported by executors, by task id (task ids are generated for
+/// each executor a task is sent to).
+///
+/// An executor may report the results of a task again on a new stream after a transient failure:
+/// the results are numbered (`seq`) so the ones already forwarded to the commander are dropped.
+#[derive(Default)]
+pub struct ResultTracker {
+    tasks: Mutex<HashMap<String, TrackedTask>>,
+    duplicates: AtomicU64,
+}
+
+#[derive(Default)]
+struct TrackedTask {
+    client_id: String,
+    /// highest `seq` forwarded
+    last_seq: u64,
+    /// a terminal result has been forwarded
+    completed: bool,
+    /// streams currently reporting the task results
+    streams: usize,
+}
+
+impl ResultTracker {
+    /// A stream reporting the results of the task starts
+    pub fn stream_started(&self, task_id: &str) {
+        self.tasks
+            .lock()
+            .unwrap()
+            .entry(task_id.to_string())
+            .or_default()
+            .streams += 1;
+    }
+
+    /// A stream reporting the results of the task ends, returns true if it was the last one
+    pub fn stream_ended(&self, task_id: &str) -> bool {
+        match self.tasks.lock().unwrap().get_mut(task_id) {
+            Some(task) => {
+                task.streams = task.streams.saturating_sub(1);
+                task.streams == 0
+            }
+            None => true,
+        }
+    }
+
+    /// Whether a stream currently reports the task results
+    pub fn is_reported(&self, task_id: &str) -> bool {
+        self.tasks
+            .lock()
+            .unwrap()
+            .get(task_id)
+            .map(|task| task.streams > 0)
+            .unwrap_or(false)
+    }
+
+    /// Whether the result must be forwarded to the commander: the first terminal result of a
+    /// task is forwarded once, results already forwarded are dropped
+    pub fn accept(&self, task_id: &str, result: &TaskExecutionResult) -> bool {
+        let mut tasks = self.tasks.lock().unwrap();
+        let task = tasks.entry(task_id.to_string()).or_default();
+        if task.client_id.is_empty() {
+            task.client_id = result.client_id.clone();
+        }
+        // results of executors not numbering them (seq 0) cannot be told apart
+        let duplicate = task.completed
+            || task.client_id != result.client_id
+            || (result.seq != 0 && result.seq <= task.last_seq);
+        if duplicate {
+            self.duplicates.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        task.last_seq = task.last_seq.max(result.seq);
+        task.completed = matches!(
+            result.execution_result,
+            Some(ExecutionResult::TaskCompleted(_))
+                | Some(ExecutionResult::TaskAborted(_))
+                | Some(ExecutionResult::TaskRejected(_))
+                | Some(ExecutionResult::TaskCancelled(_))
+        );
+        true
+    }
+
+    /// The task will not be reported anymore (cancelled, commander gone...)
+    pub fn complete(&self, task_id: &str) {
+        self.tasks
+            .lock()
+            .unwrap()
+            .entry(task_id.to_string())
+            .or_default()
+            .completed = true;
+    }
+
+    pub fn is_completed(&self, task_id: &str) -> bool {
+        self.tasks
+            .lock()
+            .unwrap()
+            .get(task_id)
+            .map(|task| task.completed)
+            .unwrap_or(false)
+    }
+
+    /// Forget the task unless a stream still reports its results, returns true if forgotten
+    pub fn forget(&self, task_id: &str) -> bool {
+        let mut tasks = self.tasks.lock().unwrap();
+        match tasks.get(task_id) {
+            Some(task) if task.streams > 0 => false,
+            _ => {
+                tasks.remove(task_id);
+                true
+            }
+        }
+    }
+
+    /// Results dropped since the taskserver started
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ResultTracker;
+    use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+    use grpc_service::grpc_protocol::task_output::Output;
+    use grpc_service::grpc_protocol::{
+        TaskCompleted, TaskExecutionResult, TaskOutput, TaskStarted,
+    };
+
+    fn results(client_id: &str, lines: u64) -> Vec<TaskExecutionResult> {
+        let result = |seq, execution_result| TaskExecutionResult {
+            task_id: "task".to_string(),
+            client_id: client_id.to_string(),
+            execution_result: Some(execution_result),
+            seq,
+        };
+        std::iter::once(result(1, ExecutionResult::Ping(TaskStarted::default())))
+            .chain((2..lines + 2).map(|seq| {
+                result(
+                    seq,
+                    ExecutionResult::TaskOutput(TaskOutput {
+                        output: Some(Output::Stdout(format!("line {}", seq))),
+                    }),
+                )
+            }))
+            .chain(std::iter::once(result(
+                lines + 2,
+                ExecutionResult::TaskCompleted(TaskCompleted {
+                    return_code: 0,
+                    duration_ms: 0,
+                }),
+            )))
+            .collect()
+    }
+
+    fn forwarded<'a>(
+        tracker: &ResultTracker,
+        stream: impl IntoIterator<Item = &'a TaskExecutionResult>,
+    ) -> Vec<u64> {
+        stream
+            .into_iter()
+            .filter(|result| tracker.accept("task", result))
+            .map(|result| result.seq)
+            .collect()
+    }
+
+    #[test]
+    fn retried_streams() {
+        let tracker = ResultTracker::default();
+        let results = results("exec", 5);
+
+        // the first stream breaks after 4 results, the retry sends them all again
+        tracker.stream_started("task");
+        assert_eq!(vec![1, 2, 3, 4], forwarded(&tracker, &results[..4]));
+        // the retry starts before the server notices the first stream is gone
+        tracker.stream_started("task");
+        assert!(!tracker.stream_ended("task"));
+        assert_eq!(vec![5, 6, 7], forwarded(&tracker, &results));
+        assert!(tracker.is_completed("task"));
+        assert_eq!(4, tracker.duplicates());
+
+        // late retry of a completed task: the completion is not forwarded twice
+        assert!(forwarded(&tracker, &results).is_empty());
+        assert_eq!(11, tracker.duplicates());
+        assert!(!tracker.forget("task"));
+        assert!(tracker.stream_ended("task"));
+        assert!(tracker.forget("task"));
+        assert!(!tracker.is_completed("task"));
+    }
+
+    #[test]
+    fn unnumbered_results() {
+        let tracker = ResultTracker::default();
+        let mut results = results("exec", 2);
+        for result in &mut results {
+            result.seq = 0;
+        }
+        assert_eq!(4, forwarded(&tracker, &results).len());
+        // ...but the completion is still forwarded once
+        assert!(forwarded(&tracker, &results).is_empty());
+
+        // results of another executor for the same task id are not trusted
+        let tracker = ResultTracker::default();
+        assert_eq!(1, forwarded(&tracker, &results[..1]).len());
+        assert!(forwarded(&tracker, &self::results("other", 2)).is_empty());
+    }
+}
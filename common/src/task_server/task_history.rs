@@ -0,0 +1,466 @@
+use crate::crypto::keystore::{verify_payload_signature, KeyStore, KeyStoreBackend};
+use crate::prost::Message;
+use grpc_service::grpc_protocol::TaskExecutionResult;
+use grpc_service::payload::SignedPayload;
+use rustbreak::deser::Yaml;
+use rustbreak::{FileDatabase, RustbreakError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::SystemTime;
+use tracing::{error, info};
+
+/// At most this many entries are kept in the task history by default, the oldest are dropped
+pub const DEFAULT_TASK_HISTORY_MAX_ENTRIES: usize = 100_000;
+
+/// Signed payload kept as received from the executor, so its signature can be checked later
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredSignedPayload {
+    pub key_id: String,
+    /// base64 encoded protobuf payload
+    pub payload: String,
+    pub nonce: u64,
+    pub valid_until_secs: u64,
+    /// base64 encoded signature
+    pub signature: String,
+}
+
+impl From<&SignedPayload> for StoredSignedPayload {
+    fn from(payload: &SignedPayload) -> Self {
+        Self {
+            key_id: payload.key_id.clone(),
+            payload: data_encoding::BASE64.encode(&payload.payload),
+            nonce: payload.nonce,
+            valid_until_secs: payload.valid_until_secs,
+            signature: data_encoding::BASE64.encode(&payload.signature),
+        }
+    }
+}
+
+impl TryFrom<&StoredSignedPayload> for SignedPayload {
+    type Error = data_encoding::DecodeError;
+
+    fn try_from(stored: &StoredSignedPayload) -> Result<Self, Self::Error> {
+        Ok(Self {
+            payload: data_encoding::BASE64.decode(stored.payload.as_bytes())?,
+            nonce: stored.nonce,
+            valid_until_secs: stored.valid_until_secs,
+            signature: data_encoding::BASE64.decode(stored.signature.as_bytes())?,
+            key_id: stored.key_id.clone(),
+        })
+    }
+}
+
+/// Signed terminal execution results of a task
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskHistoryEntry {
+    pub client_id: String,
+    pub signed_results: Vec<StoredSignedPayload>,
+    /// entries recorded by older versions are considered recorded when loaded
+    #[serde(default = "now_secs")]
+    pub recorded_at_secs: u64,
+}
+
+/// Task history by task_id
+pub type TaskHistoryDatabase = BTreeMap<String, TaskHistoryEntry>;
+
+/// Task history saved in a YAML file, bounded to its `max_entries` most recent entries.
+///
+/// Like the executor metas, recorded entries are saved by [TaskHistory::flush], periodically &
+/// on shutdown: the whole file is serialized on each save.
+pub(crate) struct TaskHistory {
+    db: FileDatabase<TaskHistoryDatabase, Yaml>,
+    max_entries: AtomicUsize,
+    /// modified since the last save
+    dirty: AtomicBool,
+}
+
+impl TaskHistory {
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Self, RustbreakError> {
+        Ok(Self {
+            db: super::open_database(path)?,
+            max_entries: AtomicUsize::new(DEFAULT_TASK_HISTORY_MAX_ENTRIES),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /// Applied by the next flush
+    pub(crate) fn set_max_entries(&self, max_entries: usize) {
+        self.max_entries.store(max_entries, Ordering::Relaxed);
+    }
+
+    pub(crate) fn read<F: FnOnce(&TaskHistoryDatabase) -> R, R>(
+        &self,
+        read_function: F,
+    ) -> Result<R, RustbreakError> {
+        self.db.read(read_function)
+    }
+
+    /// Record the first terminal result of a task, saved by the next flush. Returns false if the
+    /// task already has an entry.
+    pub(crate) fn record(
+        &self,
+        task_id: &str,
+        client_id: &str,
+        signed_payload: &SignedPayload,
+    ) -> Result<bool, RustbreakError> {
+        let recorded = self.db.write(|history| {
+            if history.contains_key(task_id) {
+                return false;
+            }
+            history.insert(
+                task_id.to_string(),
+                TaskHistoryEntry {
+                    client_id: client_id.to_string(),
+                    signed_results: vec![StoredSignedPayload::from(signed_payload)],
+                    recorded_at_secs: now_secs(),
+                },
+            );
+            true
+        })?;
+        if recorded {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        Ok(recorded)
+    }
+
+    /// Drop the oldest entries beyond `max_entries` & save the entries recorded since the last
+    /// save, if any
+    pub(crate) fn flush(&self) -> Result<(), RustbreakError> {
+        // cleared before saving: entries recorded while saving are saved by the next flush
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let dropped = self
+            .db
+            .write(|history| prune(history, self.max_entries.load(Ordering::Relaxed)))?;
+        if dropped > 0 {
+            info!("{} oldest entries dropped from the task history", dropped);
+        }
+        if let Err(e) = self.db.save() {
+            self.dirty.store(true, Ordering::SeqCst);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TaskHistory {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("Unable to save the task history: {}", e);
+        }
+    }
+}
+
+/// Remove the oldest entries beyond `max_entries`, returns the count of removed entries
+fn prune(history: &mut TaskHistoryDatabase, max_entries: usize) -> usize {
+    let excess = history.len().saturating_sub(max_entries);
+    if excess == 0 {
+        return 0;
+    }
+    let mut by_age: Vec<(u64, String)> = history
+        .iter()
+        .map(|(task_id, entry)| (entry.recorded_at_secs, task_id.clone()))
+        .collect();
+    by_age.select_nth_unstable(excess - 1);
+    for (_, task_id) in &by_age[..excess] {
+        history.remove(task_id);
+    }
+    excess
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Executor public key replaced by a newly approved one
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchivedKey {
+    /// base64 encoded public key
+    pub key: String,
+    /// rfc3339 date
+    pub replaced_at: String,
+}
+
+/// Replaced executor keys by client_id, oldest first
+pub type ExecutorKeyArchive = BTreeMap<String, Vec<ArchivedKey>>;
+
+/// Key presented by an executor whose trusted key is another one: a reinstalled host, or someone
+/// impersonating the executor
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyConflict {
+    /// base64 encoded key presented by the executor
+    pub key: String,
+    /// base64 encoded trusted key of the executor
+    pub trusted_key: String,
+    /// rfc3339 date
+    pub detected_at: String,
+}
+
+/// Last conflicting key by client_id, until it is approved, rejected or the trusted key revoked
+pub type ExecutorKeyConflicts = BTreeMap<String, KeyConflict>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifiedWith {
+    /// currently trusted key of the executor
+    CurrentKey,
+    /// key the executor used before being approved with a new one
+    ArchivedKey { replaced_at: String },
+}
+
+/// Verification result of a single stored payload
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PayloadVerificationReport {
+    pub key_id: String,
+    /// None if the payload cannot be trusted, `error` then tells why
+    pub verified_with: Option<VerifiedWith>,
+    pub execution_result: Option<String>,
+    pub error: Option<String>,
+}
+
+impl PayloadVerificationReport {
+    pub fn verified(&self) -> bool {
+        self.verified_with.is_some()
+    }
+}
+
+/// Check each stored payload of the task was signed by the executor the task ran on, with its
+/// current key or one of its archived keys
+pub fn verify_task<B: KeyStoreBackend>(
+    task_id: &str,
+    entry: &TaskHistoryEntry,
+    trusted_executor_keystore: &KeyStore<B>,
+    archived_keys: &[ArchivedKey],
+) -> Vec<PayloadVerificationReport> {
+    entry
+        .signed_results
+        .iter()
+        .map(|stored| {
+            let mut report = PayloadVerificationReport {
+                key_id: stored.key_id.clone(),
+                verified_with: None,
+                execution_result: None,
+                error: None,
+            };
+            match verify_payload(
+                task_id,
+                entry,
+                stored,
+                trusted_executor_keystore,
+                archived_keys,
+            ) {
+                Ok((verified_with, result)) => {
+                    report.verified_with = Some(verified_with);
+                    report.execution_result = result
+                        .execution_result
+                        .map(|execution_result| format!("{:?}", execution_result));
+                }
+                Err(e) => report.error = Some(e),
+            }
+            report
+        })
+        .collect()
+}
+
+fn verify_payload<B: KeyStoreBackend>(
+    task_id: &str,
+    entry: &TaskHistoryEntry,
+    stored: &StoredSignedPayload,
+    trusted_executor_keystore: &KeyStore<B>,
+    archived_keys: &[ArchivedKey],
+) -> Result<(VerifiedWith, TaskExecutionResult), String> {
+    let payload = SignedPayload::try_from(stored).map_err(|e| e.to_string())?;
+    if payload.key_id != entry.client_id {
+        return Err(format!(
+            "signed by {} but the task ran on {}",
+            payload.key_id, entry.client_id
+        ));
+    }
+
+    let verified_with = match trusted_executor_keystore.verify_signature(&payload) {
+        Ok(()) => VerifiedWith::CurrentKey,
+        Err(e) => archived_keys
+            .iter()
+            .rev()
+            .find(|archived| {
+                data_encoding::BASE64
+                    .decode(archived.key.as_bytes())
+                    .map(|key| verify_payload_signature(&payload, &key).is_ok())
+                    .unwrap_or(false)
+            })
+            .map(|archived| VerifiedWith::ArchivedKey {
+                replaced_at: archived.replaced_at.clone(),
+            })
+            .ok_or_else(|| e.to_string())?,
+    };
+
+    let result = TaskExecutionResult::decode(payload.payload.as_slice())
+        .map_err(|e| format!("Cannot decode payload: {}", e))?;
+    if result.task_id != task_id || result.client_id != entry.client_id {
+        return Err(format!(
+            "payload belongs to task {} on {}",
+            result.task_id, result.client_id
+        ));
+    }
+    Ok((verified_with, result))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        prune, verify_task, ArchivedKey, StoredSignedPayload, TaskHistory, TaskHistoryDatabase,
+        TaskHistoryEntry, VerifiedWith,
+    };
+    use crate::config::ED25519Key;
+    use crate::crypto::keygen::generate_base64_encoded_keys;
+    use crate::crypto::keystore::memory_keystore;
+    use crate::crypto::signed_payload::encode_and_sign;
+    use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+    use grpc_service::grpc_protocol::{TaskCompleted, TaskExecutionResult};
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    fn completed(task_id: &str, key: &ED25519Key) -> StoredSignedPayload {
+        (&encode_and_sign(
+            TaskExecutionResult {
+                task_id: task_id.into(),
+                client_id: "exec".into(),
+                execution_result: Some(ExecutionResult::TaskCompleted(TaskCompleted {
+                    return_code: 0,
+                    duration_ms: 0,
+                })),
+                seq: 1,
+            },
+            key,
+            Duration::from_secs(60),
+        )
+        .unwrap())
+            .into()
+    }
+
+    fn entry(signed_results: Vec<StoredSignedPayload>) -> TaskHistoryEntry {
+        TaskHistoryEntry {
+            client_id: "exec".into(),
+            signed_results,
+            recorded_at_secs: 0,
+        }
+    }
+
+    #[test]
+    fn verify_and_tamper() {
+        let (key, public_keys) = generate_base64_encoded_keys("exec");
+        let keystore = memory_keystore().init_from_map(&public_keys).unwrap();
+
+        let stored = completed("task", &key);
+        let reports = verify_task("task", &entry(vec![stored.clone()]), &keystore, &[]);
+        assert_eq!(reports[0].verified_with, Some(VerifiedWith::CurrentKey));
+        assert!(reports[0]
+            .execution_result
+            .as_ref()
+            .unwrap()
+            .contains("TaskCompleted"));
+
+        // altered payload
+        let mut tampered = stored.clone();
+        let mut payload = data_encoding::BASE64
+            .decode(tampered.payload.as_bytes())
+            .unwrap();
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+        tampered.payload = data_encoding::BASE64.encode(&payload);
+        let reports = verify_task("task", &entry(vec![tampered]), &keystore, &[]);
+        assert!(!reports[0].verified());
+        assert!(reports[0].error.is_some());
+
+        // payload of another task
+        let reports = verify_task("other_task", &entry(vec![stored.clone()]), &keystore, &[]);
+        assert!(!reports[0].verified());
+
+        // payload signed by another executor key
+        let (other_key, _) = generate_base64_encoded_keys("other");
+        let reports = verify_task(
+            "task",
+            &entry(vec![completed("task", &other_key)]),
+            &keystore,
+            &[],
+        );
+        assert!(!reports[0].verified());
+    }
+
+    #[test]
+    fn rotated_key() {
+        let (old_key, old_public_keys) = generate_base64_encoded_keys("exec");
+        let (_, new_public_keys) = generate_base64_encoded_keys("exec");
+        let keystore = memory_keystore().init_from_map(&new_public_keys).unwrap();
+
+        let stored = completed("task", &old_key);
+        let reports = verify_task("task", &entry(vec![stored.clone()]), &keystore, &[]);
+        assert!(!reports[0].verified());
+
+        let archived_keys = vec![ArchivedKey {
+            key: old_public_keys.get("exec").unwrap().clone(),
+            replaced_at: "2020-01-01T00:00:00+00:00".into(),
+        }];
+        let reports = verify_task("task", &entry(vec![stored]), &keystore, &archived_keys);
+        assert_eq!(
+            reports[0].verified_with,
+            Some(VerifiedWith::ArchivedKey {
+                replaced_at: "2020-01-01T00:00:00+00:00".into()
+            })
+        );
+    }
+
+    #[test]
+    fn oldest_entries_pruned() {
+        let mut history = TaskHistoryDatabase::new();
+        for (task_id, recorded_at_secs) in [("a", 30), ("b", 10), ("c", 40), ("d", 20)] {
+            history.insert(
+                task_id.to_string(),
+                TaskHistoryEntry {
+                    recorded_at_secs,
+                    ..entry(vec![])
+                },
+            );
+        }
+        assert_eq!(0, prune(&mut history, 4));
+        assert_eq!(2, prune(&mut history, 2));
+        assert_eq!(
+            vec!["a", "c"],
+            history.keys().map(String::as_str).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn saved_on_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("task_history.yml");
+        let (key, _) = generate_base64_encoded_keys("exec");
+        let payload = |task_id: &str| {
+            grpc_service::payload::SignedPayload::try_from(&completed(task_id, &key)).unwrap()
+        };
+        let task_history = TaskHistory::open(&path).unwrap();
+        task_history.set_max_entries(2);
+        for task_id in ["a", "b", "c"] {
+            assert!(task_history
+                .record(task_id, "exec", &payload(task_id))
+                .unwrap());
+        }
+        assert!(!task_history.record("a", "exec", &payload("a")).unwrap());
+        // not saved yet
+        assert_eq!(
+            0,
+            TaskHistory::open(&path).unwrap().read(|h| h.len()).unwrap()
+        );
+
+        task_history.flush().unwrap();
+        let reopened = TaskHistory::open(&path).unwrap();
+        assert_eq!(2, reopened.read(|history| history.len()).unwrap());
+    }
+}
@@ -0,0 +1,54 @@
+//! Ids of the tasks launched by the taskserver
+use rand::Rng;
+#[cfg(any(test, feature = "test-utils"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub trait TaskIdGenerator: Send + Sync {
+    fn next_id(&self) -> String;
+}
+
+/// Random 128 bits ids: only the commander which launched a task knows its id, so only this
+/// commander can cancel it
+#[derive(Default)]
+pub struct RandomTaskIds;
+
+impl TaskIdGenerator for RandomTaskIds {
+    fn next_id(&self) -> String {
+        let id: u128 = rand::thread_rng().gen();
+        format!("{:x}", id)
+    }
+}
+
+/// `task-1`, `task-2`... for tests expecting the same ids on each run. Easy to guess: never use
+/// it in production.
+#[cfg(any(test, feature = "test-utils"))]
+#[derive(Default)]
+pub struct SequentialTaskIds {
+    last: AtomicU64,
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl TaskIdGenerator for SequentialTaskIds {
+    fn next_id(&self) -> String {
+        format!("task-{}", self.last.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RandomTaskIds, SequentialTaskIds, TaskIdGenerator};
+    use std::collections::HashSet;
+
+    #[test]
+    fn random_ids_are_unique() {
+        let ids: HashSet<String> = (0..10000).map(|_| RandomTaskIds.next_id()).collect();
+        assert_eq!(10000, ids.len());
+    }
+
+    #[test]
+    fn sequential_ids() {
+        let ids = SequentialTaskIds::default();
+        assert_eq!("task-1", ids.next_id());
+        assert_eq!("task-2", ids.next_id());
+    }
+}
@@ -0,0 +1,293 @@
+//! Versions reported by the executors, eg: `0.21.3`, compared the semver way
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum VersionError {
+    #[error("Invalid version {0}, expected major.minor.patch")]
+    InvalidVersion(String),
+    #[error("Invalid version requirement {0}, expected eg: <0.5.0 or >=0.3, <0.5")]
+    InvalidRequirement(String),
+}
+
+/// `major.minor.patch` with an optional pre-release (`1.0.0-rc1`), build metadata is ignored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub pre: Option<String>,
+}
+
+impl Version {
+    /// Missing minor & patch numbers are 0, as in version requirements (`<0.5`)
+    fn parse(version: &str, allow_partial: bool) -> Result<Self, VersionError> {
+        let invalid = || VersionError::InvalidVersion(version.to_string());
+        let trimmed = version.trim();
+        let trimmed = trimmed.split('+').next().unwrap_or_default();
+        let (numbers, pre) = match trimmed.split_once('-') {
+            Some((numbers, pre)) if !pre.is_empty() => (numbers, Some(pre.to_string())),
+            Some(_) => return Err(invalid()),
+            None => (trimmed, None),
+        };
+        let numbers = numbers
+            .split('.')
+            .map(|number| number.parse::<u64>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        match (numbers.as_slice(), allow_partial) {
+            ([major, minor, patch], _) => Ok(Self {
+                major: *major,
+                minor: *minor,
+                patch: *patch,
+                pre,
+            }),
+            ([major], true) | ([major, _], true) => Ok(Self {
+                major: *major,
+                minor: numbers.get(1).copied().unwrap_or(0),
+                patch: 0,
+                pre,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = VersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s, false)
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if let Some(pre) = &self.pre {
+            write!(f, "-{}", pre)?;
+        }
+        Ok(())
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (&self.pre, &other.pre) {
+                // a pre-release precedes the release
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+                (Some(pre), Some(other)) => compare_pre_releases(pre, other),
+            })
+    }
+}
+
+/// Dot separated identifiers compared one by one, their numbers numerically: `rc2 < rc10`
+fn compare_pre_releases(pre: &str, other: &str) -> Ordering {
+    let mut identifiers = pre.split('.');
+    let mut other_identifiers = other.split('.');
+    loop {
+        match (identifiers.next(), other_identifiers.next()) {
+            (None, None) => return Ordering::Equal,
+            // more identifiers: a later pre-release
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(identifier), Some(other)) => match compare_identifiers(identifier, other) {
+                Ordering::Equal => {}
+                ordering => return ordering,
+            },
+        }
+    }
+}
+
+/// Runs of digits are compared as numbers, the rest as strings
+fn compare_identifiers(identifier: &str, other: &str) -> Ordering {
+    let mut left = chunks(identifier);
+    let mut right = chunks(other);
+    loop {
+        let ordering = match (left.next(), right.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(chunk), Some(other)) => match (chunk.parse::<u64>(), other.parse::<u64>()) {
+                (Ok(number), Ok(other)) => number.cmp(&other),
+                // numeric identifiers precede alphanumeric ones
+                (Ok(_), Err(_)) => Ordering::Less,
+                (Err(_), Ok(_)) => Ordering::Greater,
+                (Err(_), Err(_)) => chunk.cmp(other),
+            },
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// `rc10` as `rc`, `10`
+fn chunks(identifier: &str) -> impl Iterator<Item = &str> {
+    let mut rest = identifier;
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?;
+        let end = rest
+            .find(|c: char| c.is_ascii_digit() != first.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (chunk, remaining) = rest.split_at(end);
+        rest = remaining;
+        Some(chunk)
+    })
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Lower,
+    LowerOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+}
+
+/// Comma separated comparisons all satisfied by the matching versions, eg: `>=0.3, <0.5`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionReq(Vec<(Op, Version)>);
+
+impl VersionReq {
+    pub fn matches(&self, version: &Version) -> bool {
+        self.0.iter().all(|(op, required)| {
+            let ordering = version.cmp(required);
+            match op {
+                Op::Lower => ordering == Ordering::Less,
+                Op::LowerOrEqual => ordering != Ordering::Greater,
+                Op::Greater => ordering == Ordering::Greater,
+                Op::GreaterOrEqual => ordering != Ordering::Less,
+                Op::Equal => ordering == Ordering::Equal,
+            }
+        })
+    }
+
+    /// Unparsable versions never match
+    pub fn matches_str(&self, version: &str) -> bool {
+        version
+            .parse()
+            .map(|version| self.matches(&version))
+            .unwrap_or(false)
+    }
+}
+
+impl FromStr for VersionReq {
+    type Err = VersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || VersionError::InvalidRequirement(s.to_string());
+        s.split(',')
+            .map(|comparison| {
+                let comparison = comparison.trim();
+                let (op, version) = [
+                    (">=", Op::GreaterOrEqual),
+                    ("<=", Op::LowerOrEqual),
+                    (">", Op::Greater),
+                    ("<", Op::Lower),
+                    ("=", Op::Equal),
+                ]
+                .iter()
+                .find_map(|(prefix, op)| comparison.strip_prefix(prefix).map(|v| (*op, v)))
+                .unwrap_or((Op::Equal, comparison));
+                let version = Version::parse(version, true).map_err(|_| invalid())?;
+                Ok((op, version))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(VersionReq)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Version, VersionError, VersionReq};
+
+    fn version(version: &str) -> Version {
+        version.parse().unwrap()
+    }
+
+    fn requirement(requirement: &str) -> VersionReq {
+        requirement.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_version() {
+        assert_eq!(
+            Version {
+                major: 0,
+                minor: 21,
+                patch: 3,
+                pre: None
+            },
+            version("0.21.3")
+        );
+        assert_eq!(Some("rc1".to_string()), version("1.0.0-rc1+build.5").pre);
+        assert_eq!("1.0.0-rc1", version("1.0.0-rc1").to_string());
+        for invalid in ["", "1", "1.2", "1.2.x", "1.2.3.4", "1.2.3-", "v1.2.3"] {
+            assert_eq!(
+                Err(VersionError::InvalidVersion(invalid.to_string())),
+                invalid.parse::<Version>(),
+            );
+        }
+    }
+
+    #[test]
+    fn compare_versions() {
+        assert!(version("0.21.3") > version("0.21.2"));
+        assert!(version("0.21.3") < version("0.22.0"));
+        // numbers, not strings
+        assert!(version("0.10.0") > version("0.9.0"));
+        assert!(version("1.0.0-rc1") < version("1.0.0"));
+        assert!(version("1.0.0-rc1") > version("0.99.0"));
+        assert!(version("1.0.0-rc10") > version("1.0.0-rc2"));
+        assert!(version("1.0.0-beta") < version("1.0.0-rc1"));
+        assert!(version("1.0.0-rc.2") < version("1.0.0-rc.10"));
+        assert!(version("1.0.0-rc.1") < version("1.0.0-rc.1.1"));
+        assert_eq!(version("1.0.0-rc1"), version("1.0.0-rc1+build.5"));
+        assert_eq!(
+            Some(version("0.21.3")),
+            ["0.9.1", "0.21.3", "0.21.2"]
+                .iter()
+                .map(|v| version(v))
+                .max()
+        );
+    }
+
+    #[test]
+    fn version_requirements() {
+        let outdated = requirement("<0.5.0");
+        assert!(outdated.matches(&version("0.4.9")));
+        assert!(!outdated.matches(&version("0.5.0")));
+        assert!(outdated.matches(&version("0.5.0-rc1")));
+
+        let range = requirement(">=0.3, <0.5");
+        assert!(range.matches_str("0.3.0"));
+        assert!(range.matches_str("0.4.12"));
+        assert!(!range.matches_str("0.2.9"));
+        assert!(!range.matches_str("0.5.0"));
+        assert!(!range.matches_str("unknown"));
+
+        assert!(requirement("0.21.3").matches_str("0.21.3"));
+        assert!(requirement("=0.21.3").matches_str("0.21.3"));
+        assert!(!requirement("<=0.21.2").matches_str("0.21.3"));
+        assert!(requirement(">0.21.2").matches_str("0.21.3"));
+
+        for invalid in ["", "<", "~0.5", "<0.5,", "<0.x"] {
+            assert_eq!(
+                Err(VersionError::InvalidRequirement(invalid.to_string())),
+                invalid.parse::<VersionReq>()
+            );
+        }
+    }
+}
@@ -1,12 +1,27 @@
-use crate::{ExecEvent, Line, Type};
+use crate::{ExecEvent, ExitStatus, Line, Output, Type};
 use futures::future::join_all;
 use futures::{select, FutureExt};
+use std::ffi::OsStr;
 use std::process::Stdio;
-use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
 use tokio::process::{Child, Command};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tokio::sync::oneshot::{Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+/// Events buffered by [exec_command] when the consumer does not specify it
+pub const DEFAULT_EVENT_BUFFER: usize = 1024;
+
+/// Program & arguments running the commands, the command is appended to them
+#[cfg(not(windows))]
+pub const DEFAULT_SHELL: &[&str] = &["sh", "-c"];
+/// Program & arguments running the commands, the command is appended to them
+#[cfg(windows)]
+pub const DEFAULT_SHELL: &[&str] = &["cmd", "/C"];
 
 #[derive(thiserror::Error, Debug)]
 pub enum InternalError {
@@ -16,46 +31,214 @@ pub enum InternalError {
     NoStdErr,
 }
 
-pub fn exec_command(
+/// Limits enforced by [run_to_completion], none by default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExecLimits {
+    pub timeout: Option<Duration>,
+    /// stdout & stderr bytes, line endings excluded
+    pub max_output_bytes: Option<usize>,
+}
+
+/// Limits of the output forwarded by [exec_command_with_limits], none by default. The command
+/// is never killed because of them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OutputLimits {
+    /// stdout & stderr bytes, line endings excluded. The output lines are dropped once it is
+    /// exceeded, a single warning line is emitted instead.
+    pub max_output_bytes: Option<usize>,
+    /// bytes, longer lines are truncated and marked as such
+    pub max_line_length: Option<usize>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ExecError {
+    #[error("Unable to run command: {0}")]
+    Spawn(String),
+    #[error("Command timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("Command output exceeds {0} bytes")]
+    OutputTooLarge(usize),
+}
+
+/// Run a command & collect its output, for short commands whose output is not streamed.
+///
+/// The process is killed as soon as a limit is exceeded.
+pub async fn run_to_completion(command: &str, limits: ExecLimits) -> Result<Output, ExecError> {
+    let Execution {
+        events: mut receiver,
+        kill_sender,
+        ..
+    } = exec_command(command, DEFAULT_EVENT_BUFFER).map_err(|e| ExecError::Spawn(e.to_string()))?;
+    let collect = async {
+        let mut output_lines = Vec::new();
+        let mut output_bytes = 0;
+        while let Some(event) = receiver.recv().await {
+            match event {
+                ExecEvent::Started => (),
+                ExecEvent::SpawnFailed(e) => return Err(ExecError::Spawn(e)),
+                ExecEvent::LineEmitted(line) => {
+                    output_bytes += line.line.len();
+                    match limits.max_output_bytes {
+                        Some(max) if output_bytes > max => {
+                            return Err(ExecError::OutputTooLarge(max))
+                        }
+                        _ => output_lines.push(line),
+                    }
+                }
+                ExecEvent::Finished(status) => {
+                    return Ok(Output {
+                        exit_code: status.code,
+                        output_lines,
+                    })
+                }
+            }
+        }
+        Ok(Output {
+            exit_code: None,
+            output_lines,
+        })
+    };
+    let result = match limits.timeout {
+        Some(timeout) => tokio::time::timeout(timeout, collect)
+            .await
+            .unwrap_or(Err(ExecError::Timeout(timeout))),
+        None => collect.await,
+    };
+    if result.is_err() {
+        let _ = kill_sender.send(());
+    }
+    result
+}
+
+/// A running command
+pub struct Execution {
+    /// [ExecEvent::Started] first, [ExecEvent::Finished] last unless the command is killed. A
+    /// single [ExecEvent::SpawnFailed] if the command could not be started.
+    pub events: Receiver<ExecEvent>,
+    /// kill the command
+    pub kill_sender: oneshot::Sender<()>,
+    /// number of times the output readers had to wait for the consumer of `events`
+    pub backpressure: Arc<AtomicU64>,
+}
+
+/// Spawn `command`, at most `buffer` events are buffered: the output is not read anymore
+/// while the consumer is late, which in turn blocks the command once its pipes are full.
+pub fn exec_command(command: &str, buffer: usize) -> Result<Execution, Box<dyn std::error::Error>> {
+    exec_command_with_limits(command, Vec::new(), buffer, OutputLimits::default())
+}
+
+/// [exec_command] whose output is bounded by `limits`, `stdin` is written to the standard input
+/// of the command which is then closed
+pub fn exec_command_with_limits(
     command: &str,
-) -> Result<(UnboundedReceiver<ExecEvent>, Sender<()>), Box<dyn std::error::Error>> {
-    let mut child = Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .stdin(Stdio::null())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .kill_on_drop(true) // needed to allow the command to be killed on kill event
-        .spawn()?;
-
-    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
-    let (kill_sender, kill_receiver) = tokio::sync::oneshot::channel::<()>();
+    stdin: Vec<u8>,
+    buffer: usize,
+    limits: OutputLimits,
+) -> Result<Execution, Box<dyn std::error::Error>> {
+    exec_command_with_shell(DEFAULT_SHELL, command, stdin, buffer, limits)
+}
 
+/// [exec_command_with_limits] run by `shell`, eg: `["powershell", "-Command"]`
+pub fn exec_command_with_shell<S: AsRef<OsStr>>(
+    shell: &[S],
+    command: &str,
+    stdin: Vec<u8>,
+    buffer: usize,
+    limits: OutputLimits,
+) -> Result<Execution, Box<dyn std::error::Error>> {
+    let (sender, receiver) = mpsc::channel(buffer.max(1));
+    let (kill_sender, kill_receiver) = oneshot::channel::<()>();
+    let backpressure = Arc::new(AtomicU64::new(0));
+    let budget = Arc::new(OutputBudget::new(limits));
+
+    let spawned = match shell.split_first() {
+        Some((program, args)) => {
+            let mut shell_command = Command::new(program);
+            shell_command.args(args);
+            // cmd has its own quoting rules: the command is passed as is
+            #[cfg(windows)]
+            shell_command.raw_arg(command);
+            #[cfg(not(windows))]
+            shell_command.arg(command);
+            shell_command
+                .stdin(if stdin.is_empty() {
+                    Stdio::null()
+                } else {
+                    Stdio::piped()
+                })
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true) // needed to allow the command to be killed on kill event
+                .spawn()
+        }
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "empty shell",
+        )),
+    };
+    // the channel is empty: there is room for the first event, lines come after it
+    let mut child = match spawned {
+        Ok(child) => {
+            sender.try_send(ExecEvent::Started)?;
+            child
+        }
+        Err(e) => {
+            sender.try_send(ExecEvent::SpawnFailed(e.to_string()))?;
+            return Ok(Execution {
+                events: receiver,
+                kill_sender,
+                backpressure,
+            });
+        }
+    };
+
+    if let Some(mut child_stdin) = child.stdin.take() {
+        tokio::spawn(async move {
+            // the command may exit without reading all of it
+            if let Err(e) = child_stdin.write_all(&stdin).await {
+                debug!("Standard input not fully written: {}", e);
+            }
+            // dropped: the command reads the end of file
+        });
+    }
     let stdout = child.stdout.take().ok_or(InternalError::NoStdOut)?;
     let stderr = child.stderr.take().ok_or(InternalError::NoStdErr)?;
 
-    let stdout_join = tokio::spawn(read_output_stream(Type::Out, stdout, sender.clone()));
-    let stderr_join = tokio::spawn(read_output_stream(Type::Err, stderr, sender.clone()));
+    let stdout_join = tokio::spawn(read_output_stream(
+        Type::Out,
+        stdout,
+        sender.clone(),
+        backpressure.clone(),
+        budget.clone(),
+    ));
+    let stderr_join = tokio::spawn(read_output_stream(
+        Type::Err,
+        stderr,
+        sender.clone(),
+        backpressure.clone(),
+        budget,
+    ));
     tokio::spawn(wait_for_exit(
         child,
         kill_receiver,
-        sender.clone(),
+        sender,
         vec![stdout_join, stderr_join],
     ));
 
-    Ok((receiver, kill_sender))
+    Ok(Execution {
+        events: receiver,
+        kill_sender,
+        backpressure,
+    })
 }
 
+/// The exit code is reported as is on Windows, where processes are not terminated by signals
 async fn wait_for_exit(
     mut child: Child,
-    kill_recv: Receiver<()>,
-    sender: UnboundedSender<ExecEvent>,
+    kill_recv: oneshot::Receiver<()>,
+    sender: mpsc::Sender<ExecEvent>,
     streams_join: Vec<JoinHandle<()>>,
 ) {
-    if let Err(e) = sender.send(ExecEvent::Started) {
-        // this should not happen however
-        warn!("Unable to send started event {}", e)
-    }
     let mut kill_recv = kill_recv.fuse();
 
     select! {
@@ -67,8 +250,15 @@ async fn wait_for_exit(
 
     select! {
         status = child =>{
-            let status = status.expect("child process encountered an error");
-            if let Err(e) = sender.send(ExecEvent::Finished(status.code())) {
+            let status = match status {
+                Ok(status) => ExitStatus::from(status),
+                Err(e) => {
+                    error!("Unable to get the exit status of the command: {}", e);
+                    ExitStatus::default()
+                }
+            };
+            // waits for room in the channel, never dropped
+            if let Err(e) = sender.send(ExecEvent::Finished(status)).await {
                 // this should not happen however
                 warn!("Unable to send finished execution result {}", e)
             }
@@ -81,58 +271,235 @@ async fn wait_for_exit(
     }
 }
 
+/// Output bytes forwarded so far, shared by stdout & stderr
+struct OutputBudget {
+    limits: OutputLimits,
+    used: AtomicUsize,
+    exhausted: AtomicBool,
+}
+
+enum Budget {
+    Forward,
+    /// the line is the first one over the budget
+    ExhaustedNow,
+    Exhausted,
+}
+
+impl OutputBudget {
+    fn new(limits: OutputLimits) -> Self {
+        Self {
+            limits,
+            used: AtomicUsize::new(0),
+            exhausted: AtomicBool::new(false),
+        }
+    }
+
+    fn consume(&self, line_bytes: usize) -> Budget {
+        let max = match self.limits.max_output_bytes {
+            None => return Budget::Forward,
+            Some(max) => max,
+        };
+        if self.exhausted.load(Ordering::SeqCst) {
+            return Budget::Exhausted;
+        }
+        let used = self.used.fetch_add(line_bytes, Ordering::SeqCst) + line_bytes;
+        if used <= max {
+            Budget::Forward
+        } else if self.exhausted.swap(true, Ordering::SeqCst) {
+            Budget::Exhausted
+        } else {
+            Budget::ExhaustedNow
+        }
+    }
+}
+
 async fn read_output_stream<T: AsyncRead + Unpin>(
     stream_type: Type,
     stream: T,
-    sender: UnboundedSender<ExecEvent>,
+    sender: mpsc::Sender<ExecEvent>,
+    backpressure: Arc<AtomicU64>,
+    budget: Arc<OutputBudget>,
 ) {
-    let mut reader = BufReader::new(stream).lines();
+    let mut reader = LineReader::new(BufReader::new(stream), budget.limits.max_line_length);
     loop {
         match reader.next_line().await {
-            Ok(maybe_line) => {
-                match maybe_line {
-                    Some(line) => {
-                        if let Err(e) = sender.send(ExecEvent::LineEmitted(Line {
-                            line_type: stream_type,
-                            line,
-                        })) {
-                            // this should not happen however
-                            warn!("Unable to send finished execution result {}", e)
-                        }
+            Ok(Some((mut line, truncated_bytes))) => {
+                if truncated_bytes > 0 {
+                    line.extend_from_slice(
+                        format!(" [truncated {} bytes]", truncated_bytes).as_bytes(),
+                    );
+                }
+                let line = match budget.consume(line.len()) {
+                    Budget::Forward => Line {
+                        line_type: stream_type,
+                        line,
+                    },
+                    Budget::ExhaustedNow => Line {
+                        line_type: Type::Err,
+                        line: format!(
+                            "[output exceeds {} bytes, the rest of it is dropped]",
+                            budget.limits.max_output_bytes.unwrap_or_default()
+                        )
+                        .into_bytes(),
+                    },
+                    Budget::Exhausted => break,
+                };
+                let event = ExecEvent::LineEmitted(line);
+                let sent = match sender.try_send(event) {
+                    Err(TrySendError::Full(event)) => {
+                        backpressure.fetch_add(1, Ordering::Relaxed);
+                        sender.send(event).await.map_err(|e| e.to_string())
                     }
-                    None => break, // EOF
+                    result => result.map_err(|e| e.to_string()),
+                };
+                if let Err(e) = sent {
+                    // this should not happen however
+                    warn!("Unable to send finished execution result {}", e)
                 }
             }
+            Ok(None) => return, // EOF
             Err(e) => {
                 error!("Unable to read stream {}", e);
-                break;
+                return;
             }
         }
     }
+    // over budget: keep reading so that the command is not blocked on its pipes until it exits
+    if let Err(e) = tokio::io::copy_buf(&mut reader.reader, &mut tokio::io::sink()).await {
+        error!("Unable to read stream {}", e);
+    }
+}
+
+/// Lines longer than this are split in several lines, unless truncated first by a maximum line
+/// length: a line without line endings is never buffered whole
+pub const LINE_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Splits the output in lines of raw bytes: the output of a command is not necessarily UTF-8
+struct LineReader<R> {
+    reader: R,
+    max_length: Option<usize>,
+    /// bytes of the current line already returned as previous chunks
+    returned: usize,
 }
-#[cfg(test)]
+
+impl<R: AsyncBufRead + Unpin> LineReader<R> {
+    fn new(reader: R, max_length: Option<usize>) -> Self {
+        Self {
+            reader,
+            max_length,
+            returned: 0,
+        }
+    }
+
+    /// Next line without its line ending, at most `max_length` bytes of it are kept: returns the
+    /// line & the count of truncated bytes
+    async fn next_line(&mut self) -> std::io::Result<Option<(Vec<u8>, usize)>> {
+        loop {
+            let mut line = Vec::new();
+            let mut truncated = 0;
+            let mut last_byte = None;
+            loop {
+                let available = self.reader.fill_buf().await?;
+                if available.is_empty() {
+                    if last_byte.is_none() {
+                        return Ok(None);
+                    }
+                    break;
+                }
+                let newline = available.iter().position(|byte| *byte == b'\n');
+                let chunk = &available[..newline.unwrap_or(available.len())];
+                let kept = match self.max_length {
+                    Some(max) => chunk
+                        .len()
+                        .min(max.saturating_sub(self.returned + line.len())),
+                    None => chunk.len(),
+                };
+                if line.len() + kept > LINE_CHUNK_BYTES {
+                    // the rest of the line is returned by the next calls
+                    let fitting = LINE_CHUNK_BYTES - line.len();
+                    line.extend_from_slice(&chunk[..fitting]);
+                    // a character cut at the end of the chunk starts the next one
+                    let cut = incomplete_utf8_suffix(&line).min(fitting);
+                    line.truncate(line.len() - cut);
+                    self.reader.consume(fitting - cut);
+                    self.returned += line.len();
+                    return Ok(Some((line, 0)));
+                }
+                line.extend_from_slice(&chunk[..kept]);
+                truncated += chunk.len() - kept;
+                last_byte = chunk.last().copied().or(last_byte).or(Some(b'\n'));
+                let consumed = chunk.len() + newline.map_or(0, |_| 1);
+                self.reader.consume(consumed);
+                if newline.is_some() {
+                    break;
+                }
+            }
+            // \r\n line endings
+            if last_byte == Some(b'\r') {
+                if truncated > 0 {
+                    truncated -= 1;
+                } else {
+                    line.pop();
+                }
+            }
+            let continued = std::mem::take(&mut self.returned) > 0;
+            if continued && line.is_empty() && truncated == 0 {
+                // the previous chunk ended right before the line ending
+                continue;
+            }
+            if truncated > 0 {
+                // a multi-byte character cut by the truncation
+                let cut = incomplete_utf8_suffix(&line);
+                line.truncate(line.len() - cut);
+                truncated += cut;
+            }
+            return Ok(Some((line, truncated)));
+        }
+    }
+}
+
+/// Length of the UTF-8 character cut at the end of `bytes`, if any
+fn incomplete_utf8_suffix(bytes: &[u8]) -> usize {
+    for len in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - len];
+        // continuation bytes are 0b10xxxxxx
+        if byte & 0xC0 != 0x80 {
+            let char_len = match byte {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => 1,
+            };
+            return if char_len > len { len } else { 0 };
+        }
+    }
+    0
+}
+
+// the commands are run by sh
+#[cfg(all(test, unix))]
 mod test {
     use super::*;
     use crate::*;
     use futures::stream::StreamExt;
-    use tokio_stream::wrappers::UnboundedReceiverStream;
+    use tokio_stream::wrappers::ReceiverStream;
 
     /// Helper trait to ease test impl
     trait ToStream<T> {
-        fn to_stream(self) -> UnboundedReceiverStream<T>;
+        fn to_stream(self) -> ReceiverStream<T>;
     }
-    impl<T> ToStream<T> for UnboundedReceiver<T> {
-        fn to_stream(self) -> UnboundedReceiverStream<T> {
-            UnboundedReceiverStream::new(self)
+    impl<T> ToStream<T> for Receiver<T> {
+        fn to_stream(self) -> ReceiverStream<T> {
+            ReceiverStream::new(self)
         }
     }
 
     #[tokio::test]
     async fn test() {
         assert_eq!(
-            exec_command("echo foo ; echo bar")
+            exec_command("echo foo ; echo bar", DEFAULT_EVENT_BUFFER)
                 .unwrap()
-                .0
+                .events
                 .to_stream()
                 .collect::<Vec<ExecEvent>>()
                 .await,
@@ -140,36 +507,355 @@ mod test {
                 ExecEvent::Started,
                 ExecEvent::out("foo"),
                 ExecEvent::out("bar"),
-                ExecEvent::Finished(Some(0))
+                ExecEvent::exited(0)
             ],
         );
 
         assert_eq!(
-            exec_command("echo foo ; exit 123")
+            exec_command("echo foo ; exit 123", DEFAULT_EVENT_BUFFER)
                 .unwrap()
-                .0
+                .events
                 .to_stream()
                 .collect::<Vec<ExecEvent>>()
                 .await,
             vec![
                 ExecEvent::Started,
                 ExecEvent::out("foo"),
-                ExecEvent::Finished(Some(123))
+                ExecEvent::exited(123)
             ],
         );
 
         assert_eq!(
-            exec_command(">&2 echo bar ; exit 5")
+            exec_command(">&2 echo bar ; exit 5", DEFAULT_EVENT_BUFFER)
                 .unwrap()
-                .0
+                .events
                 .to_stream()
                 .collect::<Vec<ExecEvent>>()
                 .await,
             vec![
                 ExecEvent::Started,
                 ExecEvent::err("bar"),
-                ExecEvent::Finished(Some(5))
+                ExecEvent::exited(5)
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn stalled_consumer() {
+        let Execution {
+            mut events,
+            backpressure,
+            // dropping it kills the command
+            kill_sender: _kill_sender,
+        } = exec_command("seq 1 2000", 16).unwrap();
+        // the consumer is stalled: the readers wait for it instead of buffering the output
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let mut buffered = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            buffered.push(event);
+        }
+        assert!(buffered.len() <= 16, "{} events buffered", buffered.len());
+        assert!(backpressure.load(Ordering::Relaxed) > 0);
+
+        // nothing has been lost meanwhile
+        buffered.extend(events.to_stream().collect::<Vec<_>>().await);
+        let mut expected = vec![ExecEvent::Started];
+        expected.extend((1..=2000).map(|i| ExecEvent::out(&i.to_string())));
+        expected.push(ExecEvent::exited(0));
+        assert_eq!(expected, buffered);
+    }
+
+    #[tokio::test]
+    async fn run_to_completion_test() {
+        let output = run_to_completion("echo foo ; >&2 echo bar ; exit 3", ExecLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(Some(3), output.exit_code);
+        assert_eq!(
+            vec![
+                Line {
+                    line_type: Type::Out,
+                    line: "foo".into()
+                },
+                Line {
+                    line_type: Type::Err,
+                    line: "bar".into()
+                }
+            ],
+            output.output_lines
+        );
+
+        let started = std::time::Instant::now();
+        match run_to_completion(
+            "echo foo ; sleep 5",
+            ExecLimits {
+                timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+        )
+        .await
+        {
+            Err(ExecError::Timeout(timeout)) => assert_eq!(Duration::from_millis(200), timeout),
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        match run_to_completion(
+            "while true; do echo 0123456789; done",
+            ExecLimits {
+                max_output_bytes: Some(100),
+                ..Default::default()
+            },
+        )
+        .await
+        {
+            Err(ExecError::OutputTooLarge(max)) => assert_eq!(100, max),
+            other => panic!("Expected an output cap error, got {:?}", other),
+        }
+
+        // exactly at the cap
+        let output = run_to_completion(
+            "echo 0123456789",
+            ExecLimits {
+                max_output_bytes: Some(10),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some(0), output.exit_code);
+        assert_eq!(1, output.output_lines.len());
+    }
+
+    async fn limited_events(command: &str, limits: OutputLimits) -> Vec<ExecEvent> {
+        exec_command_with_limits(command, Vec::new(), DEFAULT_EVENT_BUFFER, limits)
+            .unwrap()
+            .events
+            .to_stream()
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn max_line_length() {
+        let limits = OutputLimits {
+            max_line_length: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(
+            vec![
+                ExecEvent::Started,
+                ExecEvent::out("01234"),
+                ExecEvent::out("01234 [truncated 1 bytes]"),
+                ExecEvent::out(""),
+                ExecEvent::err("abc"),
+                ExecEvent::err("01234 [truncated 5 bytes]"),
+                ExecEvent::exited(2)
+            ],
+            limited_events(
+                "printf '01234\\n012345\\n\\n' ; >&2 printf 'abc\\r\\n0123456789\\r\\n' ; exit 2",
+                limits
+            )
+            .await
+        );
+        // multi-byte characters are not cut
+        assert_eq!(
+            vec![
+                ExecEvent::Started,
+                ExecEvent::out("\u{e9}\u{e9} [truncated 2 bytes]"),
+                ExecEvent::exited(0)
+            ],
+            limited_events("printf '\\303\\251\\303\\251\\303\\251'", limits).await
+        );
+    }
+
+    #[tokio::test]
+    async fn max_output_bytes() {
+        let limits = OutputLimits {
+            max_output_bytes: Some(13),
+            ..Default::default()
+        };
+        // exactly at the limit
+        assert_eq!(
+            vec![
+                ExecEvent::Started,
+                ExecEvent::out("0123456789"),
+                ExecEvent::out("abc"),
+                ExecEvent::exited(0)
+            ],
+            limited_events("echo 0123456789 ; echo abc", limits).await
+        );
+        assert_eq!(
+            vec![
+                ExecEvent::Started,
+                ExecEvent::out("0123456789"),
+                ExecEvent::out("abc"),
+                ExecEvent::err("[output exceeds 13 bytes, the rest of it is dropped]"),
+                ExecEvent::exited(3)
+            ],
+            limited_events(
+                "echo 0123456789 ; echo abc ; echo d ; echo e ; exit 3",
+                limits
+            )
+            .await
+        );
+        // the command is not blocked by its pipes
+        let events = limited_events("seq 1 200000 ; >&2 seq 1 200000 ; exit 4", limits).await;
+        assert_eq!(Some(&ExecEvent::exited(4)), events.last());
+        assert!(events.len() < 20, "{} events", events.len());
+    }
+
+    #[tokio::test]
+    async fn binary_output() {
+        assert_eq!(
+            vec![
+                ExecEvent::Started,
+                ExecEvent::LineEmitted(Line {
+                    line_type: Type::Out,
+                    line: vec![0xff, 0xfe],
+                }),
+                ExecEvent::out("after"),
+                ExecEvent::exited(7)
+            ],
+            limited_events(
+                "printf '\\377\\376\\n' ; echo after ; exit 7",
+                OutputLimits::default()
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn long_lines_are_chunked() {
+        let events = limited_events(
+            "head -c 65536 /dev/zero | tr '\\0' a ; echo ; head -c 150000 /dev/zero | tr '\\0' b",
+            OutputLimits::default(),
+        )
+        .await;
+        let lines: Vec<(u8, usize)> = events
+            .iter()
+            .filter_map(|event| match event {
+                ExecEvent::LineEmitted(line) => Some((line.line[0], line.line.len())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (b'a', LINE_CHUNK_BYTES),
+                (b'b', LINE_CHUNK_BYTES),
+                (b'b', LINE_CHUNK_BYTES),
+                (b'b', 150000 - 2 * LINE_CHUNK_BYTES)
+            ],
+            lines
+        );
+        assert_eq!(Some(&ExecEvent::exited(0)), events.last());
+    }
+
+    #[test]
+    fn utf8_suffix() {
+        assert_eq!(0, incomplete_utf8_suffix(b""));
+        assert_eq!(0, incomplete_utf8_suffix("a\u{e9}".as_bytes()));
+        assert_eq!(1, incomplete_utf8_suffix(b"a\xc3"));
+        assert_eq!(2, incomplete_utf8_suffix(b"\xe2\x82"));
+        assert_eq!(0, incomplete_utf8_suffix("\u{20ac}".as_bytes()));
+        assert_eq!(0, incomplete_utf8_suffix(b"\xff\xfe"));
+    }
+
+    #[tokio::test]
+    async fn killed_by_signal() {
+        assert_eq!(
+            vec![
+                ExecEvent::Started,
+                ExecEvent::out("before"),
+                ExecEvent::Finished(ExitStatus {
+                    code: None,
+                    signal: Some(9)
+                })
+            ],
+            limited_events("echo before ; kill -9 $$", OutputLimits::default()).await
+        );
+        assert_eq!("SIGKILL", signal_name(9));
+        assert_eq!("signal 64", signal_name(64));
+    }
+
+    #[tokio::test]
+    async fn spawn_failure() {
+        let events: Vec<ExecEvent> = exec_command_with_shell(
+            &["/nonexistent/sh", "-c"],
+            "echo foo",
+            Vec::new(),
+            DEFAULT_EVENT_BUFFER,
+            OutputLimits::default(),
+        )
+        .unwrap()
+        .events
+        .to_stream()
+        .collect()
+        .await;
+        match events.as_slice() {
+            [ExecEvent::SpawnFailed(e)] => assert!(e.contains("No such file"), "{}", e),
+            other => panic!("Expected a spawn failure, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn stdin() {
+        async fn events(command: &str, stdin: Vec<u8>) -> Vec<ExecEvent> {
+            exec_command_with_limits(
+                command,
+                stdin,
+                DEFAULT_EVENT_BUFFER,
+                OutputLimits::default(),
+            )
+            .unwrap()
+            .events
+            .to_stream()
+            .collect()
+            .await
+        }
+        assert_eq!(
+            events("tr a-z A-Z", b"foo\nbar".to_vec()).await,
+            vec![
+                ExecEvent::Started,
+                ExecEvent::out("FOO"),
+                ExecEvent::out("BAR"),
+                ExecEvent::exited(0)
+            ],
+        );
+        // the command does not read everything
+        assert_eq!(
+            events("head -c 3", vec![b'a'; 1024 * 1024]).await,
+            vec![
+                ExecEvent::Started,
+                ExecEvent::out("aaa"),
+                ExecEvent::exited(0)
+            ],
+        );
+    }
+}
+
+#[cfg(all(test, windows))]
+mod windows_test {
+    use super::*;
+    use crate::*;
+
+    #[tokio::test]
+    async fn cmd() {
+        let mut events = exec_command("echo foo& exit /b 3", DEFAULT_EVENT_BUFFER)
+            .unwrap()
+            .events;
+        let mut received = Vec::new();
+        while let Some(event) = events.recv().await {
+            received.push(event);
+        }
+        // \r\n line endings are stripped, exit codes are reported without signal
+        assert_eq!(
+            vec![
+                ExecEvent::Started,
+                ExecEvent::out("foo"),
+                ExecEvent::exited(3)
             ],
+            received
         );
     }
 }
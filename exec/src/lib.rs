@@ -2,7 +2,6 @@
 extern crate log;
 
 use std::fmt::{Debug, Formatter};
-use std::process::ExitStatus;
 
 pub mod a_sync;
 
@@ -15,23 +14,75 @@ pub enum Type {
 #[derive(Eq, PartialEq)]
 pub struct Line {
     pub line_type: Type,
-    pub line: String,
+    /// without its line ending, not necessarily UTF-8
+    pub line: Vec<u8>,
 }
 #[derive(Eq, PartialEq, Debug)]
 pub enum ExecEvent {
     Started,
-    Finished(Option<i32>),
+    Finished(ExitStatus),
     LineEmitted(Line),
+    /// The command could not be started (missing shell, process limit...), sole event of the
+    /// execution
+    SpawnFailed(String),
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct ExitStatus {
+    /// None if the process has been killed by a signal
+    pub code: Option<i32>,
+    /// The signal which terminated the process, unix only
+    pub signal: Option<i32>,
+}
+
+impl From<std::process::ExitStatus> for ExitStatus {
+    fn from(status: std::process::ExitStatus) -> Self {
+        #[cfg(unix)]
+        let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+        #[cfg(not(unix))]
+        let signal = None;
+        Self {
+            code: status.code(),
+            signal,
+        }
+    }
+}
+
+/// `SIGKILL`, `SIGTERM`... or the number of the less common signals
+pub fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        1 => "SIGHUP",
+        2 => "SIGINT",
+        3 => "SIGQUIT",
+        4 => "SIGILL",
+        6 => "SIGABRT",
+        8 => "SIGFPE",
+        9 => "SIGKILL",
+        11 => "SIGSEGV",
+        13 => "SIGPIPE",
+        14 => "SIGALRM",
+        15 => "SIGTERM",
+        _ => return format!("signal {}", signal),
+    };
+    name.to_string()
 }
 
 impl Debug for Line {
     fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
-        write!(f, "{:?}({})", self.line_type, self.line)
+        write!(
+            f,
+            "{:?}({})",
+            self.line_type,
+            String::from_utf8_lossy(&self.line)
+        )
     }
 }
 
+/// Collected execution of a command, see [a_sync::run_to_completion]
+#[derive(Debug)]
 pub struct Output {
-    pub exit_status: ExitStatus,
+    /// None if the process has been killed by a signal
+    pub exit_code: Option<i32>,
     pub output_lines: Vec<Line>,
 }
 
@@ -40,13 +91,14 @@ trait ExecEventHelper {
     fn line(s: &str, line_type: Type) -> ExecEvent;
     fn out(s: &str) -> ExecEvent;
     fn err(s: &str) -> ExecEvent;
+    fn exited(code: i32) -> ExecEvent;
 }
 #[cfg(test)]
 impl ExecEventHelper for ExecEvent {
     fn line(s: &str, line_type: Type) -> ExecEvent {
         ExecEvent::LineEmitted(Line {
             line_type,
-            line: s.into(),
+            line: s.as_bytes().to_vec(),
         })
     }
     fn out(s: &str) -> ExecEvent {
@@ -55,4 +107,10 @@ impl ExecEventHelper for ExecEvent {
     fn err(s: &str) -> ExecEvent {
         Self::line(s, Type::Err)
     }
+    fn exited(code: i32) -> ExecEvent {
+        ExecEvent::Finished(ExitStatus {
+            code: Some(code),
+            signal: None,
+        })
+    }
 }
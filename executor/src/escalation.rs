@@ -0,0 +1,94 @@
+//! Commands run with `commander run --become`, wrapped by the `become_command` of the executor
+use exec::{Line, Type};
+
+/// The wrapped commands must never wait for a password: sudo is always run non-interactively
+const SUDO_NON_INTERACTIVE: &str = "-n";
+
+/// Program & arguments running the command through `become_command` (split on whitespaces),
+/// followed by the shell of the executor: the command itself is still the last argument, it does
+/// not need more quoting.
+pub(crate) fn become_shell(become_command: &str, shell: &[String]) -> Vec<String> {
+    let mut wrapped: Vec<String> = become_command
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    let is_sudo = wrapped
+        .first()
+        .map(|program| program.rsplit('/').next() == Some("sudo"))
+        .unwrap_or(false);
+    if is_sudo
+        && !wrapped
+            .iter()
+            .skip(1)
+            .any(|arg| arg == SUDO_NON_INTERACTIVE || arg == "--non-interactive")
+    {
+        wrapped.insert(1, SUDO_NON_INTERACTIVE.to_string());
+    }
+    wrapped.extend(shell.iter().cloned());
+    wrapped
+}
+
+/// Error printed by `sudo -n` when it would have asked for a password (it then exits with 1)
+pub(crate) fn is_password_prompt(line: &Line) -> bool {
+    if line.line_type != Type::Err {
+        return false;
+    }
+    let line = String::from_utf8_lossy(&line.line);
+    line.starts_with("sudo:")
+        && (line.contains("password is required") || line.contains("terminal is required"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{become_shell, is_password_prompt};
+    use exec::{Line, Type};
+
+    fn shell() -> Vec<String> {
+        vec!["sh".to_string(), "-c".to_string()]
+    }
+
+    #[test]
+    fn wrapping() {
+        assert_eq!(
+            vec!["sudo", "-n", "sh", "-c"],
+            become_shell("sudo -n", &shell())
+        );
+        // non-interactive mode is forced
+        assert_eq!(
+            vec!["/usr/bin/sudo", "-n", "-u", "postgres", "sh", "-c"],
+            become_shell("/usr/bin/sudo -u postgres", &shell())
+        );
+        assert_eq!(
+            vec!["sudo", "--non-interactive", "-E", "sh", "-c"],
+            become_shell(" sudo  --non-interactive -E ", &shell())
+        );
+        // other wrappers are run as configured
+        assert_eq!(vec!["doas", "sh", "-c"], become_shell("doas", &shell()));
+        assert_eq!(vec!["env", "sh", "-c"], become_shell("env", &shell()));
+    }
+
+    #[test]
+    fn password_prompts() {
+        let line = |line_type, line: &str| Line {
+            line_type,
+            line: line.as_bytes().to_vec(),
+        };
+        assert!(is_password_prompt(&line(
+            Type::Err,
+            "sudo: a password is required"
+        )));
+        assert!(is_password_prompt(&line(
+            Type::Err,
+            "sudo: a terminal is required to read the password; either use the -S option to read \
+             from standard input or configure an askpass helper"
+        )));
+        assert!(!is_password_prompt(&line(
+            Type::Out,
+            "sudo: a password is required"
+        )));
+        assert!(!is_password_prompt(&line(
+            Type::Err,
+            "rm: cannot remove 'sudo': a password is required"
+        )));
+    }
+}
@@ -0,0 +1,49 @@
+//! Random id of an executor installation, kept next to its signing key: tells apart the
+//! executors sharing a client_id in the listings & logs of the taskserver
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub fn generate() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+pub fn instance_id_path<P: AsRef<Path>>(config_dir: P) -> PathBuf {
+    config_dir.as_ref().join("executor_instance_id")
+}
+
+/// Read the instance id kept in `path`, a new one is generated & written if the file is missing
+pub fn read_or_create(path: &Path) -> io::Result<String> {
+    match std::fs::read_to_string(path) {
+        Ok(instance_id) if !instance_id.trim().is_empty() => Ok(instance_id.trim().to_string()),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Empty instance id file {}", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let instance_id = generate();
+            std::fs::write(path, format!("{}\n", instance_id))?;
+            Ok(instance_id)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{instance_id_path, read_or_create};
+
+    #[test]
+    fn kept_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = instance_id_path(dir.path());
+        let instance_id = read_or_create(&path).unwrap();
+        assert_eq!(36, instance_id.len());
+        assert_eq!(instance_id, read_or_create(&path).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+        assert_ne!(instance_id, read_or_create(&path).unwrap());
+
+        std::fs::write(&path, "\n").unwrap();
+        assert!(read_or_create(&path).is_err());
+    }
+}
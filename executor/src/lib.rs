@@ -1,39 +1,73 @@
 #[macro_use]
 extern crate log;
 
+mod escalation;
+pub mod instance_id;
+mod monitoring;
+mod output_batches;
+mod result_retries;
+mod scripts;
+mod task_log;
+mod task_queue;
+
+use anyhow::Context;
 use exec::a_sync;
 use exec::*;
-use funtonic::config::{ED25519Key, ExecutorConfig};
-use funtonic::crypto::keystore::{memory_keystore, KeyStore, KeyStoreBackend};
-use funtonic::crypto::signed_payload::encode_and_sign;
+use funtonic::capabilities::{Capabilities, OUTPUT_BATCHES};
+use funtonic::config::{compression_encoding, ED25519Key, ExecutorConfig};
+use funtonic::crypto::keystore::{
+    expires_at_from_secs, memory_keystore, KeyStore, KeyStoreBackend, KeyStoreError,
+};
+use funtonic::crypto::signed_payload::{clock_skew_secs, encode_and_sign};
+use funtonic::diagnostic::DiagnosticReport;
 use funtonic::error::format_error;
 use funtonic::executor_meta::{ExecutorMeta, Tag};
+use funtonic::task_server::PENDING_APPROVAL_MESSAGE;
 use funtonic::tonic;
 use funtonic::PROTOCOL_VERSION;
 use funtonic::{data_encoding, tokio};
-use futures::StreamExt;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{FutureExt, StreamExt};
 use grpc_service::grpc_protocol::executor_service_client::ExecutorServiceClient;
 use grpc_service::grpc_protocol::launch_task_request_payload::Task;
 use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
 use grpc_service::grpc_protocol::task_output::Output;
 use grpc_service::grpc_protocol::{
-    Empty, ExecuteCommand, GetTasksRequest, LaunchTaskRequestPayload, RegisterExecutorRequest,
-    TaskCompleted, TaskExecutionResult, TaskOutput,
+    Empty, GetTasksRequest, LaunchTaskRequestPayload, RegisterExecutorRequest, TaskAborted,
+    TaskCompleted, TaskExecutionResult, TaskOutput, TaskOutputBatch, TaskStarted,
 };
+use grpc_service::payload::SignedPayload;
 use http::Uri;
+use output_batches::{
+    Batched, OutputBatching, DEFAULT_OUTPUT_BATCH_BYTES, DEFAULT_OUTPUT_BATCH_MILLIS,
+};
+use query_parser::QueryMatcher;
+use result_retries::{
+    ResultRetries, SentResults, DEFAULT_RESULT_RETRY_BUFFER_BYTES, DEFAULT_RESULT_RETRY_WINDOW_SECS,
+};
+use scripts::PreparedCommand;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::error::Error;
+use std::future::Future;
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use structopt::StructOpt;
+use task_log::{TaskLog, TaskLogger, DEFAULT_TASK_LOG_MAX_FILES};
+use task_queue::{TaskQueue, Turn};
 use thiserror::Error;
 use tokio::sync::watch::Sender;
-use tokio_stream::wrappers::UnboundedReceiverStream;
-use tonic::metadata::AsciiMetadataValue;
+use tokio::sync::Notify;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::codec::CompressionEncoding;
+use tonic::metadata::{AsciiMetadataValue, MetadataMap};
 use tonic::transport::{Channel, Endpoint};
-use tonic::Request;
+use tonic::{Request, Response};
+use tracing::Instrument;
 
 pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
@@ -42,23 +76,170 @@ pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 pub struct Opt {
     #[structopt(short, long, parse(from_os_str))]
     pub config: Option<PathBuf>,
+    /// Rewrite the signing key file sealed with this host machine-id, then exit
+    #[structopt(long, conflicts_with = "unseal")]
+    pub reseal: bool,
+    /// Rewrite the signing key file in plain text, then exit
+    #[structopt(long)]
+    pub unseal: bool,
+    /// Add or override a tag, eg: `--tag env=staging --tag os.flavor=custom`
+    ///
+    /// Dotted keys set a tag nested in maps. Command line tags take precedence over the ones
+    /// from the configuration file or the system.
+    #[structopt(long = "tag", parse(try_from_str = parse_cli_tag))]
+    pub tags: Vec<(String, String)>,
+}
+
+fn parse_cli_tag(tag: &str) -> Result<(String, String), String> {
+    match tag.split_once('=') {
+        Some((key, value)) if !key.split('.').any(str::is_empty) => {
+            Ok((key.to_string(), value.to_string()))
+        }
+        _ => Err(format!("Invalid tag {}, expected key=value", tag)),
+    }
 }
 
 #[derive(Error, Debug)]
 #[error("Missing field for server config!")]
 struct InvalidConfig;
 
+#[derive(Error, Debug)]
+#[error("No taskserver url configured, set server_url or server_urls")]
+struct NoServerUrl;
+
+/// Delay before registering again while the executor key is not approved on the taskserver
+const DEFAULT_PENDING_APPROVAL_RETRY_SECS: u64 = 60;
+
+/// Running tasks are aborted on shutdown if they take longer to finish
+const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 30;
+
+/// Once aborted, how long the tasks may take to report it
+const ABORT_REPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Clock differences with the taskserver above this are reported when registering
+const CLOCK_SKEW_WARNING_SECS: i64 = 30;
+
+#[derive(Error, Debug)]
+#[error("Executor key not accepted by the taskserver: {0}")]
+struct PendingApproval(String);
+
 #[derive(Debug, Clone, Copy)]
 enum LastConnectionStatus {
     Connecting,
     Connected,
 }
-/// Launch the executor ; returns an updated version of its configuration. The caller should persist it
-/// and immediately reconnect the executor
+/// Why [`executor_main`] returned
+#[derive(Debug)]
+pub enum ExecutorExit {
+    /// Updated version of the configuration (authorized keys...). The caller should persist it
+    /// and immediately reconnect the executor
+    Reconnect(Box<ExecutorConfig>),
+    /// A configuration reload has been requested (SIGHUP) and running tasks are finished. The
+    /// caller should parse the configuration file again and reconnect the executor
+    Reload,
+    /// A shutdown has been requested (SIGTERM) and running tasks are finished or aborted. The
+    /// caller should exit.
+    Shutdown,
+}
+
+/// Tasks executed by the executor, they outlive the connection which received them
+///
+/// Created once and passed to every [`executor_main`] call: after a reconnection, the tasks
+/// still running are waited for on reload or shutdown and keep being counted by the monitoring,
+/// the tasks received afterwards are queued behind them (`serialize_tasks`).
+#[derive(Clone)]
+pub struct ExecutorTasks {
+    running: RunningTasks,
+    queue: TaskQueue,
+}
+
+impl ExecutorTasks {
+    /// Must be called from the tokio runtime: the task queue worker is spawned
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self {
+            running: RunningTasks::default(),
+            queue: TaskQueue::new(),
+        }
+    }
+}
+
+/// Launch the executor, a SIGHUP triggers a configuration reload, a SIGTERM a shutdown
 pub async fn executor_main(
+    executor_config: ExecutorConfig,
+    signing_key: ED25519Key,
+    tasks: ExecutorTasks,
+) -> anyhow::Result<ExecutorExit> {
+    executor_main_with_signals(
+        executor_config,
+        signing_key,
+        tasks,
+        reload_signal()?,
+        shutdown_signal()?,
+    )
+    .await
+}
+
+#[cfg(unix)]
+fn reload_signal() -> anyhow::Result<BoxFuture<'static, ()>> {
+    // registered right away: SIGHUP would otherwise kill the process until the future is polled
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    Ok(async move {
+        hangup.recv().await;
+    }
+    .boxed())
+}
+
+#[cfg(not(unix))]
+fn reload_signal() -> anyhow::Result<BoxFuture<'static, ()>> {
+    Ok(futures::future::pending().boxed())
+}
+
+#[cfg(unix)]
+fn shutdown_signal() -> anyhow::Result<BoxFuture<'static, ()>> {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    Ok(async move {
+        terminate.recv().await;
+    }
+    .boxed())
+}
+
+#[cfg(not(unix))]
+fn shutdown_signal() -> anyhow::Result<BoxFuture<'static, ()>> {
+    Ok(async {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+    .boxed())
+}
+
+/// Launch the executor, returning [`ExecutorExit::Reload`] once `reload` completes and all the
+/// running tasks are finished
+pub async fn executor_main_with_reload<R: Future<Output = ()>>(
+    executor_config: ExecutorConfig,
+    signing_key: ED25519Key,
+    tasks: ExecutorTasks,
+    reload: R,
+) -> anyhow::Result<ExecutorExit> {
+    executor_main_with_signals(
+        executor_config,
+        signing_key,
+        tasks,
+        reload,
+        futures::future::pending(),
+    )
+    .await
+}
+
+/// Launch the executor, returning [`ExecutorExit::Shutdown`] once `shutdown` completes and the
+/// running tasks are drained: they are aborted if they are still running after
+/// `drain_timeout_secs`. New tasks are not accepted anymore while draining.
+pub async fn executor_main_with_signals<R: Future<Output = ()>, S: Future<Output = ()>>(
     mut executor_config: ExecutorConfig,
     mut signing_key: ED25519Key,
-) -> anyhow::Result<ExecutorConfig> {
+    tasks: ExecutorTasks,
+    reload: R,
+    shutdown: S,
+) -> anyhow::Result<ExecutorExit> {
     info!(
         "Executor v{}, core v{},  protocol v{}",
         VERSION,
@@ -66,20 +247,54 @@ pub async fn executor_main(
         PROTOCOL_VERSION
     );
     info!("{:#?}", executor_config);
+    let instance_id = executor_config
+        .instance_id
+        .get_or_insert_with(instance_id::generate);
+    info!("Instance {}", instance_id);
 
     // force the is of the key to match the executor client_id
     signing_key.id = executor_config.client_id.clone();
-    let mut endpoint = Channel::builder(Uri::from_str(&executor_config.server_url)?)
-        .tcp_keepalive(Some(Duration::from_secs(60)));
-
-    if let Some(tls_config) = &executor_config.tls {
-        endpoint = endpoint.tls_config(tls_config.get_client_config()?)?;
+    let mut endpoints = vec![];
+    for url in executor_config.server_urls() {
+        let mut endpoint =
+            Channel::builder(Uri::from_str(url)?).tcp_keepalive(Some(Duration::from_secs(60)));
+        if let Some(tls_config) = &executor_config.tls {
+            endpoint = endpoint.tls_config(tls_config.get_client_config()?)?;
+        }
+        endpoints.push((url.to_string(), endpoint));
+    }
+    if endpoints.is_empty() {
+        return Err(NoServerUrl.into());
     }
+    // the taskserver which worked last is preferred
+    let mut current_endpoint = endpoints
+        .iter()
+        .position(|(url, _)| Some(url) == executor_config.last_server_url.as_ref())
+        .unwrap_or(0);
+    // endpoints which could not be connected to since the last connection or backoff
+    let mut failed_endpoints = 0;
 
     let max_reconnect_time = Duration::from_secs(10);
     let mut reconnect_time = Duration::from_millis(100);
 
-    let key_store = memory_keystore().init_from_map(&executor_config.authorized_keys)?;
+    let key_store = memory_keystore()
+        .init_from_map(&executor_config.authorized_keys)
+        .context("Invalid authorized_keys")?;
+    for (key_id, expires_at_secs) in &executor_config.authorized_keys_expiry {
+        if let Some(key) = executor_config.authorized_keys.get(key_id) {
+            key_store.register_key_with_expiry(
+                key_id,
+                data_encoding::BASE64.decode(key.as_bytes())?,
+                expires_at_from_secs(*expires_at_secs),
+            )?;
+        }
+    }
+    let admin_key_store = executor_config
+        .admin_authorized_keys
+        .as_ref()
+        .map(|keys| memory_keystore().init_from_map(keys))
+        .transpose()
+        .context("Invalid admin_authorized_keys")?;
 
     let mut executor_meta = ExecutorMeta::from(&executor_config);
     // add some generic meta about system
@@ -93,77 +308,321 @@ pub async fn executor_main(
     let (mut connection_status_sender, connection_status_receiver) =
         tokio::sync::watch::channel(LastConnectionStatus::Connecting);
 
+    let mut lifecycle = Lifecycle {
+        reload: Box::pin(reload),
+        shutdown: Box::pin(shutdown),
+        drain_timeout: Duration::from_secs(
+            executor_config
+                .drain_timeout_secs
+                .unwrap_or(DEFAULT_DRAIN_TIMEOUT_SECS),
+        ),
+        running_tasks: tasks.running,
+        task_queue: executor_config.serialize_tasks.then_some(tasks.queue),
+        task_logger: task_logger(&executor_config),
+    };
+    let tls_expiries = executor_config
+        .tls
+        .as_ref()
+        .map(|tls_config| tls_config.warn_on_expiry())
+        .unwrap_or_default();
+    // serves across reconnections, until the executor returns
+    let monitoring = executor_config
+        .monitoring_bind_address
+        .as_deref()
+        .map(|bind_address| {
+            monitoring::Monitoring::spawn(
+                bind_address,
+                connection_status_receiver.clone(),
+                lifecycle.running_tasks.clone(),
+                tls_expiries,
+            )
+        })
+        .transpose()?;
+
     // executor execution never ends
-    'retryloop: loop {
+    let exit = 'retryloop: loop {
+        let (server_url, endpoint) = &endpoints[current_endpoint];
         match do_executor_main(
-            &endpoint,
+            server_url,
+            endpoint,
             &executor_meta,
             &executor_config,
             &mut connection_status_sender,
             &key_store,
+            admin_key_store.as_ref(),
             signing_key.clone(),
+            &mut lifecycle,
         )
         .await
         {
             Ok(config_modification) => {
+                executor_config.last_server_url = Some(server_url.clone());
                 match config_modification {
-                    ConfigurationModification::AddKey { key_id, key_bytes } => {
+                    ConfigurationModification::AddKey {
+                        key_id,
+                        key_bytes,
+                        expires_at_secs,
+                    } => {
+                        if expires_at_secs == 0 {
+                            executor_config.authorized_keys_expiry.remove(&key_id);
+                        } else {
+                            executor_config
+                                .authorized_keys_expiry
+                                .insert(key_id.clone(), expires_at_secs);
+                        }
                         executor_config
                             .authorized_keys
                             .insert(key_id, data_encoding::BASE64.encode(&key_bytes));
                     }
                     ConfigurationModification::RevokeKey(key_id) => {
                         executor_config.authorized_keys.remove(&key_id);
+                        executor_config.authorized_keys_expiry.remove(&key_id);
                     }
 
+                    ConfigurationModification::Reload => break 'retryloop ExecutorExit::Reload,
+                    ConfigurationModification::Shutdown => break 'retryloop ExecutorExit::Shutdown,
                     ConfigurationModification::None => {
                         // nothing to do here
                     }
                 }
-                break 'retryloop;
+                break 'retryloop ExecutorExit::Reconnect(Box::new(executor_config));
+            }
+            Err(e) if e.is::<PendingApproval>() => {
+                let retry_secs = executor_config
+                    .pending_approval_retry_secs
+                    .unwrap_or(DEFAULT_PENDING_APPROVAL_RETRY_SECS);
+                warn!(
+                    "{} ({}), registering again in {}s",
+                    e, executor_config.client_id, retry_secs
+                );
+                let sleep = tokio::time::sleep(Duration::from_secs(retry_secs));
+                if lifecycle.unless_shutdown(sleep).await.is_none() {
+                    break 'retryloop ExecutorExit::Shutdown;
+                }
             }
             Err(e) => {
-                error!("Error running executor: {}", format_error(e));
+                error!(
+                    "Error running executor on {}: {}",
+                    server_url,
+                    format_error(e)
+                );
                 // increase reconnect time if connecting, reset if connected
-                match *connection_status_receiver.borrow() {
+                let status = *connection_status_receiver.borrow();
+                match status {
                     LastConnectionStatus::Connecting => {
+                        // fail over right away, backing off once every taskserver failed
+                        current_endpoint = (current_endpoint + 1) % endpoints.len();
+                        failed_endpoints += 1;
+                        if failed_endpoints < endpoints.len() {
+                            info!("Failing over to {}", endpoints[current_endpoint].0);
+                            continue;
+                        }
+                        failed_endpoints = 0;
                         reconnect_time = reconnect_time + Duration::from_secs(1);
                         if reconnect_time > max_reconnect_time {
                             reconnect_time = max_reconnect_time;
                         }
                     }
-                    LastConnectionStatus::Connected => reconnect_time = Duration::from_secs(1),
+                    LastConnectionStatus::Connected => {
+                        failed_endpoints = 0;
+                        reconnect_time = Duration::from_secs(1);
+                    }
+                }
+                info!(
+                    "Reconnecting to {} in {}s",
+                    endpoints[current_endpoint].0,
+                    reconnect_time.as_secs()
+                );
+                let sleep = tokio::time::sleep(reconnect_time);
+                if lifecycle.unless_shutdown(sleep).await.is_none() {
+                    break 'retryloop ExecutorExit::Shutdown;
                 }
-                info!("Reconnecting in {}s", reconnect_time.as_secs());
-                tokio::time::sleep(reconnect_time).await;
             }
         }
-    }
+    };
 
-    Ok(executor_config)
+    if let Some(monitoring) = monitoring {
+        monitoring.stop().await;
+    }
+    Ok(exit)
 }
 
 enum ConfigurationModification {
-    AddKey { key_id: String, key_bytes: Vec<u8> },
+    AddKey {
+        key_id: String,
+        key_bytes: Vec<u8>,
+        expires_at_secs: u64,
+    },
     RevokeKey(String),
+    /// configuration must be read again from its file
+    Reload,
+    Shutdown,
     None,
 }
 
-async fn do_executor_main<B: KeyStoreBackend>(
+/// Reload & shutdown requests, honored once the running tasks are finished
+struct Lifecycle<R, S> {
+    reload: std::pin::Pin<Box<R>>,
+    shutdown: std::pin::Pin<Box<S>>,
+    drain_timeout: Duration,
+    /// queued tasks are counted as running
+    running_tasks: RunningTasks,
+    /// `serialize_tasks`
+    task_queue: Option<TaskQueue>,
+    /// `task_log_directory`
+    task_logger: Option<TaskLogger>,
+}
+
+impl<R: Future<Output = ()>, S: Future<Output = ()>> Lifecycle<R, S> {
+    /// Resolves to the output of `future`, or to None once a shutdown is requested first and the
+    /// running tasks are drained: the executor must not linger while waiting for a taskserver
+    async fn unless_shutdown<F: Future>(&mut self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            output = future => Some(output),
+            _ = self.shutdown.as_mut() => {
+                self.drain().await;
+                None
+            }
+        }
+    }
+
+    async fn drain(&mut self) {
+        info!(
+            "Shutdown requested, waiting up to {}s for {} running task(s) to finish",
+            self.drain_timeout.as_secs(),
+            self.running_tasks.count()
+        );
+        self.running_tasks.drain(self.drain_timeout).await;
+    }
+}
+
+/// Count of the tasks being executed
+#[derive(Default, Clone)]
+struct RunningTasks {
+    inner: Arc<RunningTasksInner>,
+}
+
+#[derive(Default)]
+struct RunningTasksInner {
+    count: AtomicUsize,
+    finished: Notify,
+    /// the running tasks must be aborted
+    cut_off: AtomicBool,
+    cut_off_notify: Notify,
+    /// finished tasks
+    executed: AtomicU64,
+    /// unix timestamp of the last started task, 0 if none
+    last_started_secs: AtomicU64,
+}
+
+/// Held by a running task, decrements the running tasks count when dropped
+struct RunningTask {
+    inner: Arc<RunningTasksInner>,
+}
+
+impl RunningTasks {
+    fn start(&self) -> RunningTask {
+        self.inner.count.fetch_add(1, Ordering::SeqCst);
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or_default();
+        self.inner.last_started_secs.store(now, Ordering::Relaxed);
+        RunningTask {
+            inner: self.inner.clone(),
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.inner.count.load(Ordering::SeqCst)
+    }
+
+    fn executed(&self) -> u64 {
+        self.inner.executed.load(Ordering::Relaxed)
+    }
+
+    fn last_started_secs(&self) -> u64 {
+        self.inner.last_started_secs.load(Ordering::Relaxed)
+    }
+
+    async fn wait_all(&self) {
+        loop {
+            let finished = self.inner.finished.notified();
+            if self.count() == 0 {
+                return;
+            }
+            finished.await;
+        }
+    }
+
+    /// Wait for the running tasks to finish, abort the ones still running after `timeout`
+    async fn drain(&self, timeout: Duration) {
+        if tokio::time::timeout(timeout, self.wait_all()).await.is_ok() {
+            return;
+        }
+        warn!(
+            "{} task(s) still running after {}s, aborting them",
+            self.count(),
+            timeout.as_secs()
+        );
+        self.inner.cut_off.store(true, Ordering::SeqCst);
+        self.inner.cut_off_notify.notify_waiters();
+        if tokio::time::timeout(ABORT_REPORT_TIMEOUT, self.wait_all())
+            .await
+            .is_err()
+        {
+            warn!("{} task(s) could not report their abortion", self.count());
+        }
+    }
+}
+
+impl RunningTask {
+    /// Completes once the task must be aborted
+    async fn cut_off(&self) {
+        loop {
+            let cut_off = self.inner.cut_off_notify.notified();
+            if self.inner.cut_off.load(Ordering::SeqCst) {
+                return;
+            }
+            cut_off.await;
+        }
+    }
+}
+
+impl Drop for RunningTask {
+    fn drop(&mut self) {
+        self.inner.executed.fetch_add(1, Ordering::Relaxed);
+        self.inner.count.fetch_sub(1, Ordering::SeqCst);
+        self.inner.finished.notify_waiters();
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn do_executor_main<B: KeyStoreBackend, R: Future<Output = ()>, S: Future<Output = ()>>(
+    server_url: &str,
     endpoint: &Endpoint,
     executor_metas: &ExecutorMeta,
     executor_config: &ExecutorConfig,
     last_connection_status_sender: &mut Sender<LastConnectionStatus>,
     key_store: &KeyStore<B>,
+    admin_key_store: Option<&KeyStore<B>>,
     signing_key: ED25519Key,
+    lifecycle: &mut Lifecycle<R, S>,
 ) -> anyhow::Result<ConfigurationModification> {
     last_connection_status_sender.send(LastConnectionStatus::Connecting)?;
-    let channel = endpoint.connect().await?;
+    let channel = match lifecycle.unless_shutdown(endpoint.connect()).await {
+        Some(channel) => channel?,
+        None => return Ok(ConfigurationModification::Shutdown),
+    };
     last_connection_status_sender.send(LastConnectionStatus::Connected)?;
 
-    let mut client = ExecutorServiceClient::new(channel);
+    let mut client =
+        ExecutorServiceClient::new(channel).accept_compressed(CompressionEncoding::Gzip);
+    if let Some(encoding) = compression_encoding(executor_config.compression.as_deref())? {
+        client = client.send_compressed(encoding);
+    }
 
-    info!("Connected");
+    info!("Connected to {}", server_url);
 
     let client_id = executor_metas.client_id().to_string();
 
@@ -175,33 +634,288 @@ async fn do_executor_main<B: KeyStoreBackend>(
             get_tasks_request: Some(encode_and_sign(
                 GetTasksRequest::try_from(executor_config)?,
                 &signing_key,
-                Duration::from_secs(60),
+                executor_config.signature_validity(),
             )?),
         }
         .into(),
     );
 
-    let mut response = client.get_tasks(request).await?.into_inner();
+    let mut response = match client.get_tasks(request).await {
+        Ok(response) => {
+            warn_on_clock_skew(response.metadata());
+            response.into_inner()
+        }
+        // retrying right away is pointless until an admin approves the key, unlike when the
+        // certificate does not name the executor
+        Err(status)
+            if status.code() == tonic::Code::PermissionDenied
+                && status.message() == PENDING_APPROVAL_MESSAGE =>
+        {
+            return Err(PendingApproval(status.message().to_string()).into())
+        }
+        Err(status) => return Err(status.into()),
+    };
+
+    let mut tag_refresh = executor_config.tag_refresh_interval_secs.map(|secs| {
+        let period = Duration::from_secs(secs.max(1));
+        tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+    });
+    let signature_validity = executor_config.signature_validity();
 
-    while let Some(task) = response.message().await? {
+    loop {
+        let task = tokio::select! {
+            task = response.message() => match task? {
+                Some(task) => task,
+                None => break,
+            },
+            _ = tick(&mut tag_refresh) => {
+                if let Err(e) = update_meta(&mut client, executor_config, &signing_key).await {
+                    match e.downcast_ref::<tonic::Status>() {
+                        Some(status) if status.code() == tonic::Code::Unimplemented => {
+                            warn!("Taskserver does not support tag refresh, disabling it");
+                            tag_refresh = None;
+                        }
+                        _ => warn!("Unable to refresh tags: {}", format_error(e)),
+                    }
+                }
+                continue;
+            }
+            _ = lifecycle.reload.as_mut() => {
+                info!(
+                    "Reload requested, waiting for {} running task(s) to finish",
+                    lifecycle.running_tasks.count()
+                );
+                // tasks sent from now on are rejected: their commanders would wait for them
+                let finished = lifecycle.running_tasks.wait_all();
+                tokio::pin!(finished);
+                loop {
+                    tokio::select! {
+                        _ = &mut finished => return Ok(ConfigurationModification::Reload),
+                        task = response.message() => match task {
+                            Ok(Some(task)) => {
+                                warn!("Task {} rejected: reload in progress", task.task_id);
+                                if let Err(e) = single_execution_result(
+                                    ExecutionResult::TaskRejected("executor reloading".into()),
+                                    &client_id,
+                                    &task.task_id,
+                                    &signing_key,
+                                    signature_validity,
+                                    &mut client,
+                                )
+                                .await
+                                {
+                                    warn!(
+                                        "Unable to reject task {}: {}",
+                                        task.task_id,
+                                        format_error(e)
+                                    );
+                                }
+                            }
+                            // no more tasks
+                            _ => {
+                                finished.await;
+                                return Ok(ConfigurationModification::Reload);
+                            }
+                        }
+                    }
+                }
+            }
+            _ = lifecycle.shutdown.as_mut() => {
+                // the taskserver sees the executor disconnected: no new task is sent to it
+                drop(response);
+                lifecycle.drain().await;
+                return Ok(ConfigurationModification::Shutdown);
+            }
+        };
+        let received = Instant::now();
         // by convention this field is always here, so we can "safely" unwrap
         let task_id = task.task_id;
+        let capabilities = Capabilities::from_peer(&task.capabilities);
+        debug!("Task {} capabilities: {}", task_id, capabilities);
+        let output_batching = if capabilities.supports(OUTPUT_BATCHES) {
+            OutputBatching {
+                max_delay: Duration::from_millis(
+                    executor_config
+                        .output_batch_millis
+                        .unwrap_or(DEFAULT_OUTPUT_BATCH_MILLIS),
+                ),
+                max_bytes: executor_config
+                    .output_batch_bytes
+                    .unwrap_or(DEFAULT_OUTPUT_BATCH_BYTES),
+            }
+        } else {
+            OutputBatching::disabled()
+        };
 
         let task_payload = task.payload;
+        let delegation = task.delegation;
         match task_payload {
             Some(signed_payload) => {
-                match key_store.decode_payload::<LaunchTaskRequestPayload>(&signed_payload) {
-                    Ok(task) => match task.task {
+                match decode_task(
+                    key_store,
+                    &signed_payload,
+                    delegation.as_ref(),
+                    executor_metas,
+                ) {
+                    Ok((task, delegated)) => match task.task {
+                        // delegated keys are never admin keys
+                        Some(Task::AuthorizeKey(_) | Task::RevokeKey(_))
+                            if delegated || !signed_by_admin(admin_key_store, &signed_payload) =>
+                        {
+                            warn!(
+                                "Key operation {} signed by non admin key {}, rejecting it",
+                                task_id, signed_payload.key_id
+                            );
+                            single_execution_result(
+                                ExecutionResult::TaskRejected(
+                                    "key operations require an admin key on this executor".into(),
+                                ),
+                                &client_id,
+                                &task_id,
+                                &signing_key,
+                                signature_validity,
+                                &mut client,
+                            )
+                            .await?;
+                        }
+                        Some(Task::AuthorizeKey(_) | Task::RevokeKey(_))
+                            if executor_config.authorized_keys_overridden =>
+                        {
+                            warn!(
+                                "Key operation {} rejected: the authorized keys are set by \
+                                 environment variables",
+                                task_id
+                            );
+                            single_execution_result(
+                                ExecutionResult::TaskRejected(
+                                    "the authorized keys of this executor are set by environment \
+                                     variables"
+                                        .into(),
+                                ),
+                                &client_id,
+                                &task_id,
+                                &signing_key,
+                                signature_validity,
+                                &mut client,
+                            )
+                            .await?;
+                        }
                         Some(task) => match task {
                             Task::ExecuteCommand(cmd) => {
-                                info!("Received task {} - {}", task_id, cmd.command);
-                                tokio::spawn(execute_task(
-                                    cmd,
-                                    task_id,
-                                    client_id.clone(),
-                                    client.clone(),
-                                    signing_key.clone(),
-                                ));
+                                let description = scripts::describe(&cmd.program);
+                                info!("Received task {} - {}", task_id, description);
+                                let task_shell =
+                                    match (cmd.with_become, executor_config.become_command()) {
+                                        (false, _) => shell(executor_config),
+                                        (true, Some(become_command)) => escalation::become_shell(
+                                            become_command,
+                                            &shell(executor_config),
+                                        ),
+                                        (true, None) => {
+                                            warn!("Task {} rejected: become is disabled", task_id);
+                                            single_execution_result(
+                                                ExecutionResult::TaskRejected(
+                                                    "become is disabled on this executor".into(),
+                                                ),
+                                                &client_id,
+                                                &task_id,
+                                                &signing_key,
+                                                signature_validity,
+                                                &mut client,
+                                            )
+                                            .await?;
+                                            continue;
+                                        }
+                                    };
+                                let prepared = match PreparedCommand::new(cmd.program) {
+                                    Ok(prepared) => prepared,
+                                    Err(e) => {
+                                        error!("Unable to write the script of {}: {}", task_id, e);
+                                        single_execution_result(
+                                            ExecutionResult::TaskRejected(format!(
+                                                "unable to write the script: {}",
+                                                e
+                                            )),
+                                            &client_id,
+                                            &task_id,
+                                            &signing_key,
+                                            signature_validity,
+                                            &mut client,
+                                        )
+                                        .await?;
+                                        continue;
+                                    }
+                                };
+                                let task_log = lifecycle.task_logger.as_ref().map(|logger| {
+                                    logger.start(&task_id, &signed_payload.key_id, &description)
+                                });
+                                let span = tracing::info_span!(
+                                    "execute_task",
+                                    %task_id,
+                                    %client_id,
+                                    key_id = %signed_payload.key_id
+                                );
+                                tokio::spawn(
+                                    execute_task(
+                                        prepared,
+                                        cmd.stdin,
+                                        received,
+                                        task_id,
+                                        client_id.clone(),
+                                        client.clone(),
+                                        signing_key.clone(),
+                                        signature_validity,
+                                        executor_config
+                                            .result_buffer_messages
+                                            .unwrap_or(a_sync::DEFAULT_EVENT_BUFFER),
+                                        a_sync::OutputLimits {
+                                            max_output_bytes: executor_config.max_output_bytes,
+                                            max_line_length: executor_config.max_line_length,
+                                        },
+                                        output_batching,
+                                        ResultRetries {
+                                            window: Duration::from_secs(
+                                                executor_config
+                                                    .result_retry_window_secs
+                                                    .unwrap_or(DEFAULT_RESULT_RETRY_WINDOW_SECS),
+                                            ),
+                                            max_bytes: executor_config
+                                                .result_retry_buffer_bytes
+                                                .unwrap_or(DEFAULT_RESULT_RETRY_BUFFER_BYTES),
+                                        },
+                                        task_shell,
+                                        cmd.with_become,
+                                        lifecycle.running_tasks.start(),
+                                        lifecycle.task_queue.clone(),
+                                        task_log,
+                                    )
+                                    .instrument(span),
+                                );
+                            }
+                            Task::Diagnostic(_) => {
+                                info!(
+                                    "Diagnostic {} requested by {}",
+                                    task_id, signed_payload.key_id
+                                );
+                                execution_results(
+                                    vec![
+                                        ExecutionResult::TaskOutput(TaskOutput {
+                                            output: Some(Output::Stdout(
+                                                DiagnosticReport::now().encode(),
+                                            )),
+                                        }),
+                                        ExecutionResult::TaskCompleted(TaskCompleted {
+                                            return_code: 0,
+                                            duration_ms: 0,
+                                        }),
+                                    ],
+                                    &client_id,
+                                    &task_id,
+                                    &signing_key,
+                                    signature_validity,
+                                    &mut client,
+                                )
+                                .await?;
                             }
                             Task::StreamingPayload(_) => {
                                 error!("Streaming not yet implemented!");
@@ -213,6 +927,7 @@ async fn do_executor_main<B: KeyStoreBackend>(
                                     &client_id,
                                     &task_id,
                                     &signing_key,
+                                    signature_validity,
                                     &mut client,
                                 )
                                 .await?;
@@ -221,16 +936,19 @@ async fn do_executor_main<B: KeyStoreBackend>(
                                 single_execution_result(
                                     ExecutionResult::TaskCompleted(TaskCompleted {
                                         return_code: 0,
+                                        duration_ms: 0,
                                     }),
                                     &client_id,
                                     &task_id,
                                     &signing_key,
+                                    signature_validity,
                                     &mut client,
                                 )
                                 .await?;
                                 return Ok(ConfigurationModification::AddKey {
                                     key_id: public_key.key_id,
                                     key_bytes: public_key.key_bytes,
+                                    expires_at_secs: public_key.expires_at_secs,
                                 });
                             }
                             Task::RevokeKey(key_id) => {
@@ -238,10 +956,12 @@ async fn do_executor_main<B: KeyStoreBackend>(
                                     single_execution_result(
                                         ExecutionResult::TaskCompleted(TaskCompleted {
                                             return_code: 0,
+                                            duration_ms: 0,
                                         }),
                                         &client_id,
                                         &task_id,
                                         &signing_key,
+                                        signature_validity,
                                         &mut client,
                                     )
                                     .await?;
@@ -255,6 +975,7 @@ async fn do_executor_main<B: KeyStoreBackend>(
                                         &client_id,
                                         &task_id,
                                         &signing_key,
+                                        signature_validity,
                                         &mut client,
                                     )
                                     .await?;
@@ -265,15 +986,24 @@ async fn do_executor_main<B: KeyStoreBackend>(
                     },
                     Err(e) => {
                         error!("Unable to decode received payload for {}: {}", task_id, e);
+                        let reason = match e {
+                            // the taskserver forwards tasks right away: the clocks disagree
+                            KeyStoreError::ExpiredSignature(valid_until, now)
+                            | KeyStoreError::SignatureTooFarAhead(valid_until, now) => format!(
+                                "clock skew detected: executor time {}, payload valid until {}",
+                                now, valid_until
+                            ),
+                            e => {
+                                format!("Unable to decode received payload for {}: {}", task_id, e)
+                            }
+                        };
                         // reject task
                         single_execution_result(
-                            ExecutionResult::TaskRejected(format!(
-                                "Unable to decode received payload for {}: {}",
-                                task_id, e
-                            )),
+                            ExecutionResult::TaskRejected(reason),
                             &client_id,
                             &task_id,
                             &signing_key,
+                            signature_validity,
                             &mut client,
                         )
                         .await?;
@@ -289,22 +1019,135 @@ async fn do_executor_main<B: KeyStoreBackend>(
     Ok(ConfigurationModification::None)
 }
 
+/// Check & decode a task, returns it along with whether it is signed by a delegated key.
+///
+/// A delegated task must be allowed on this executor: the delegation predicate is matched against
+/// the metas of the executor, not against the tags overridden on the taskserver.
+fn decode_task<B: KeyStoreBackend>(
+    key_store: &KeyStore<B>,
+    signed_payload: &SignedPayload,
+    delegation: Option<&SignedPayload>,
+    executor_metas: &ExecutorMeta,
+) -> Result<(LaunchTaskRequestPayload, bool), KeyStoreError> {
+    let certificate = match delegation {
+        Some(certificate) => certificate,
+        None => return Ok((key_store.decode_payload(signed_payload)?, false)),
+    };
+    let (task, delegation) = key_store.decode_delegated_payload(certificate, signed_payload)?;
+    let allowed = query_parser::parse(&delegation.allowed_predicate).map_err(|parse_error| {
+        KeyStoreError::InvalidDelegation(format!(
+            "invalid allowed predicate {}: {}",
+            delegation.allowed_predicate, parse_error
+        ))
+    })?;
+    if !executor_metas.qmatches(&allowed).matches() {
+        return Err(KeyStoreError::InvalidDelegation(format!(
+            "{} only allowed executors matching {}",
+            certificate.key_id, delegation.allowed_predicate
+        )));
+    }
+    Ok((task, true))
+}
+
+/// Any key is an admin key if the executor has no admin keys configured
+fn signed_by_admin<B: KeyStoreBackend>(
+    admin_key_store: Option<&KeyStore<B>>,
+    payload: &SignedPayload,
+) -> bool {
+    match admin_key_store {
+        Some(admin_key_store) => admin_key_store.verify_signature(payload).is_ok(),
+        None => true,
+    }
+}
+
+/// Wait for the next tick of the interval, forever if there is no interval
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+/// Signed payloads are rejected by the peers whose clock is too far from ours
+fn warn_on_clock_skew(metadata: &MetadataMap) {
+    let skew = metadata
+        .get("date")
+        .and_then(|date| date.to_str().ok())
+        .and_then(|date| clock_skew_secs(date, SystemTime::now()));
+    if let Some(skew) = skew {
+        if skew.abs() > CLOCK_SKEW_WARNING_SECS {
+            warn!(
+                "System clock is {}s {} the taskserver one, signed tasks & results may be rejected",
+                skew.abs(),
+                if skew > 0 { "ahead of" } else { "behind" }
+            );
+        }
+    }
+}
+
+/// Send freshly computed tags to the taskserver
+async fn update_meta(
+    client: &mut ExecutorServiceClient<Channel>,
+    executor_config: &ExecutorConfig,
+    signing_key: &ED25519Key,
+) -> anyhow::Result<()> {
+    client
+        .update_meta(encode_and_sign(
+            GetTasksRequest::try_from(executor_config)?,
+            signing_key,
+            executor_config.signature_validity(),
+        )?)
+        .await?;
+    Ok(())
+}
+
 async fn single_execution_result(
     result: ExecutionResult,
     client_id: &str,
     task_id: &str,
     signing_key: &ED25519Key,
+    signature_validity: Duration,
     client: &mut ExecutorServiceClient<Channel>,
 ) -> anyhow::Result<()> {
-    let stream = futures::stream::once(futures::future::ready(encode_and_sign(
-        TaskExecutionResult {
-            task_id: task_id.to_string(),
-            client_id: client_id.to_string(),
-            execution_result: Some(result),
-        },
-        &signing_key,
-        Duration::from_secs(60),
-    )?));
+    execution_results(
+        vec![result],
+        client_id,
+        task_id,
+        signing_key,
+        signature_validity,
+        client,
+    )
+    .await
+}
+
+/// All the results of a task which is not run, sent at once
+async fn execution_results(
+    results: Vec<ExecutionResult>,
+    client_id: &str,
+    task_id: &str,
+    signing_key: &ED25519Key,
+    signature_validity: Duration,
+    client: &mut ExecutorServiceClient<Channel>,
+) -> anyhow::Result<()> {
+    let signed = results
+        .into_iter()
+        .zip(1..)
+        .map(|(result, seq)| {
+            encode_and_sign(
+                TaskExecutionResult {
+                    task_id: task_id.to_string(),
+                    client_id: client_id.to_string(),
+                    execution_result: Some(result),
+                    seq,
+                },
+                signing_key,
+                signature_validity,
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let stream = futures::stream::iter(signed);
     let mut request = Request::new(stream);
     request
         .metadata_mut()
@@ -313,71 +1156,493 @@ async fn single_execution_result(
     Ok(())
 }
 
+/// Request stream reading the shared results: once the request failed, the results it did not
+/// read are still available
+fn shared_stream(
+    results: Arc<tokio::sync::Mutex<BoxStream<'static, SignedPayload>>>,
+) -> impl futures::Stream<Item = SignedPayload> {
+    futures::stream::unfold(results, |results| async move {
+        let next = results.lock().await.next().await;
+        next.map(|result| (result, results))
+    })
+}
+
+/// Send the results of the finished task again until the taskserver accepts them, for up to
+/// `window`. They are signed again: the signatures sent first may have expired meanwhile.
+async fn resend_results(
+    mut client: ExecutorServiceClient<Channel>,
+    task_id: &str,
+    sent_results: &SentResults,
+    signing_key: &ED25519Key,
+    signature_validity: Duration,
+    window: Duration,
+) -> Result<Response<Empty>, tonic::Status> {
+    if sent_results.dropped() > 0 {
+        warn!(
+            "{} results of the task will not be sent again, only the last ones are kept",
+            sent_results.dropped()
+        );
+    }
+    let task_id = AsciiMetadataValue::try_from(task_id.as_bytes())
+        .map_err(|e| tonic::Status::internal(e.to_string()))?;
+    let mut last_status = tonic::Status::unavailable("results not sent again");
+    for delay in result_retries::retry_delays(window) {
+        tokio::time::sleep(delay).await;
+        let results: Vec<SignedPayload> = sent_results
+            .results()
+            .into_iter()
+            .filter_map(
+                |result| match encode_and_sign(result, signing_key, signature_validity) {
+                    Ok(signed) => Some(signed),
+                    Err(e) => {
+                        error!("Unable to sign task execution result {}", e);
+                        None
+                    }
+                },
+            )
+            .collect();
+        let mut request = Request::new(futures::stream::iter(results));
+        request.metadata_mut().insert("task_id", task_id.clone());
+        match client.task_execution(request).await {
+            Ok(response) => {
+                info!("Task results sent again");
+                return Ok(response);
+            }
+            Err(status) if result_retries::is_transient(&status) => {
+                debug!(
+                    "Unable to send the task results again ({}), retrying",
+                    status.message()
+                );
+                last_status = status;
+            }
+            Err(status) => return Err(status),
+        }
+    }
+    Err(last_status)
+}
+
+/// Report the task as queued until the tasks received before it are finished. None if the task
+/// is cancelled or aborted by the executor shutdown meanwhile.
+async fn wait_turn(
+    task_queue: &TaskQueue,
+    client_id: &str,
+    task_id: &str,
+    signing_key: &ED25519Key,
+    signature_validity: Duration,
+    client: &mut ExecutorServiceClient<Channel>,
+    running_task: &RunningTask,
+) -> Result<Option<Turn>, Box<dyn Error>> {
+    let result = |execution_result, seq| {
+        encode_and_sign(
+            TaskExecutionResult {
+                task_id: task_id.to_string(),
+                client_id: client_id.to_string(),
+                execution_result: Some(execution_result),
+                seq,
+            },
+            signing_key,
+            signature_validity,
+        )
+    };
+    let queued = result(ExecutionResult::TaskQueued(Empty {}), 1)?;
+    let aborted = result(
+        ExecutionResult::TaskAborted(TaskAborted {
+            reason: "aborted by the executor shutdown".to_string(),
+        }),
+        2,
+    )?;
+
+    let waiting = task_queue.enqueue();
+    let (turn_sender, turn_receiver) = tokio::sync::oneshot::channel();
+    let (abort_sender, abort_receiver) = tokio::sync::oneshot::channel::<()>();
+    // the stream stays open while waiting, so that the commander can cancel the task
+    let stream = futures::stream::iter([queued]).chain(
+        futures::stream::once(async move {
+            tokio::select! {
+                Ok(turn) = waiting => {
+                    let _ = turn_sender.send(turn);
+                    None
+                }
+                _ = abort_receiver => Some(aborted),
+            }
+        })
+        .filter_map(futures::future::ready),
+    );
+    let mut request = Request::new(stream);
+    request
+        .metadata_mut()
+        .insert("task_id", AsciiMetadataValue::try_from(task_id.as_bytes())?);
+    let execution = client.task_execution(request);
+    tokio::pin!(execution);
+    let result = tokio::select! {
+        result = &mut execution => result,
+        _ = running_task.cut_off() => {
+            info!("Aborting queued task");
+            let _ = abort_sender.send(());
+            execution.await
+        }
+    };
+    match result {
+        Err(status) if status.code() == tonic::Code::Cancelled => {
+            info!("Queued task cancelled");
+            return Ok(None);
+        }
+        result => {
+            result?;
+        }
+    }
+    Ok(turn_receiver.await.ok())
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn execute_task(
-    task_payload: ExecuteCommand,
+    command: PreparedCommand,
+    stdin: Vec<u8>,
+    received: Instant,
     task_id: String,
     client_id: String,
     client: ExecutorServiceClient<Channel>,
     signing_key: ED25519Key,
+    signature_validity: Duration,
+    result_buffer: usize,
+    output_limits: a_sync::OutputLimits,
+    output_batching: OutputBatching,
+    result_retries: ResultRetries,
+    shell: Vec<String>,
+    with_become: bool,
+    running_task: RunningTask,
+    task_queue: Option<TaskQueue>,
+    task_log: Option<TaskLog>,
 ) {
-    match do_execute_task(task_payload, task_id, client_id, client, signing_key).await {
+    match do_execute_task(
+        command,
+        stdin,
+        received,
+        task_id.clone(),
+        client_id,
+        client,
+        signing_key,
+        signature_validity,
+        result_buffer,
+        output_limits,
+        output_batching,
+        result_retries,
+        &shell,
+        with_become,
+        &running_task,
+        task_queue,
+        task_log,
+    )
+    .await
+    {
         Ok(_) => (),
-        Err(e) => error!("Something wrong happened while executing task {}", e),
+        Err(e) => error!(
+            "Something wrong happened while executing the task {}: {}",
+            task_id, e
+        ),
+    }
+}
+
+/// Program & arguments running the commands
+fn shell(executor_config: &ExecutorConfig) -> Vec<String> {
+    match &executor_config.shell {
+        Some(shell) => shell.clone(),
+        None => a_sync::DEFAULT_SHELL
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect(),
+    }
+}
+
+/// Local record of the tasks, if `task_log_directory` is configured
+fn task_logger(executor_config: &ExecutorConfig) -> Option<TaskLogger> {
+    let directory = executor_config.task_log_directory.as_ref()?;
+    let max_files = executor_config
+        .task_log_max_files
+        .unwrap_or(DEFAULT_TASK_LOG_MAX_FILES);
+    Some(TaskLogger::new(directory, max_files))
+}
+
+fn task_output(line: Line) -> TaskOutput {
+    TaskOutput {
+        output: Some(match &line.line_type {
+            // the protocol only carries text
+            Type::Out => Output::Stdout(String::from_utf8_lossy(&line.line).into_owned()),
+            Type::Err => Output::Stderr(String::from_utf8_lossy(&line.line).into_owned()),
+        }),
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn do_execute_task(
-    execute_command: ExecuteCommand,
+    command: PreparedCommand,
+    stdin: Vec<u8>,
+    received: Instant,
     task_id: String,
     client_id: String,
     mut client: ExecutorServiceClient<Channel>,
     signing_key: ED25519Key,
+    signature_validity: Duration,
+    result_buffer: usize,
+    output_limits: a_sync::OutputLimits,
+    output_batching: OutputBatching,
+    result_retries: ResultRetries,
+    shell: &[String],
+    with_become: bool,
+    running_task: &RunningTask,
+    task_queue: Option<TaskQueue>,
+    task_log: Option<TaskLog>,
 ) -> Result<(), Box<dyn Error>> {
+    // held until the task is finished; the results are numbered after the queued report
+    let (_turn, first_seq) = match task_queue {
+        Some(task_queue) => {
+            match wait_turn(
+                &task_queue,
+                &client_id,
+                &task_id,
+                &signing_key,
+                signature_validity,
+                &mut client,
+                running_task,
+            )
+            .await?
+            {
+                Some(turn) => (Some(turn), 2),
+                None => return Ok(()),
+            }
+        }
+        None => (None, 1),
+    };
     let cloned_task_id = task_id.clone();
     let cloned_client_id = client_id.clone();
+    let sent_results = SentResults::new(result_retries.max_bytes);
+    // a killed command does not report its end, and its children may keep its output open
+    let aborted = Arc::new(AtomicBool::new(false));
+    let (abort_sender, abort_receiver) = tokio::sync::oneshot::channel::<()>();
+    let aborted_result = {
+        let aborted = aborted.clone();
+        futures::stream::once(async move {
+            aborted.load(Ordering::SeqCst).then(|| {
+                ExecutionResult::TaskAborted(TaskAborted {
+                    reason: "aborted by the executor shutdown".to_string(),
+                })
+            })
+        })
+        .filter_map(futures::future::ready)
+    };
 
-    let (exec_receiver, kill_sender) = a_sync::exec_command(&execute_command.command)?;
+    let a_sync::Execution {
+        mut events,
+        kill_sender,
+        backpressure,
+    } = a_sync::exec_command_with_shell(
+        shell,
+        &command.command,
+        stdin,
+        result_buffer,
+        output_limits,
+    )?;
 
-    let stream = UnboundedReceiverStream::new(exec_receiver)
-        .map(|exec_event| match exec_event {
-            ExecEvent::Started => ExecutionResult::Ping(Empty {}),
-            ExecEvent::Finished(return_code) => match return_code {
-                None => ExecutionResult::TaskAborted(Empty {}),
-                Some(return_code) => ExecutionResult::TaskCompleted(TaskCompleted { return_code }),
-            },
-            ExecEvent::LineEmitted(line) => ExecutionResult::TaskOutput(TaskOutput {
-                output: Some(match &line.line_type {
-                    Type::Out => Output::Stdout(line.line),
-                    Type::Err => Output::Stderr(line.line),
-                }),
+    // a command which could not be started is rejected, there is no output to stream
+    let first_event = events.recv().await;
+    // the process has been spawned
+    let started = Instant::now();
+    // queued tasks report it on their stream, see below
+    if let (1, Some(ExecEvent::SpawnFailed(e))) = (first_seq, &first_event) {
+        error!("Unable to spawn {}: {}", command.command, e);
+        let rejected = ExecutionResult::TaskRejected(format!("failed to spawn: {}", e));
+        if let Some(task_log) = &task_log {
+            task_log.observe(&rejected);
+        }
+        let reported = single_execution_result(
+            rejected,
+            &client_id,
+            &task_id,
+            &signing_key,
+            signature_validity,
+            &mut client,
+        )
+        .await;
+        if let Some(task_log) = &task_log {
+            task_log.write(reported.is_ok()).await;
+        }
+        reported?;
+        return Ok(());
+    }
+
+    // sudo asking for a password exits with 1, which is not the exit code of the command
+    let mut password_required = false;
+    // the lines are signed by batches, the other events end the batch being collected
+    let results: BoxStream<'static, SignedPayload> = output_batches::batched(
+        futures::stream::iter(first_event).chain(ReceiverStream::new(events)),
+        output_batching,
+    )
+    .map(move |batched| {
+        if with_become {
+            password_required |= match &batched {
+                Batched::Lines(lines) => lines.iter().any(escalation::is_password_prompt),
+                Batched::Event(ExecEvent::LineEmitted(line)) => {
+                    escalation::is_password_prompt(line)
+                }
+                Batched::Event(_) => false,
+            };
+        }
+        (batched, password_required)
+    })
+    .map(move |(batched, password_required)| match batched {
+        Batched::Lines(mut lines) if lines.len() == 1 => {
+            ExecutionResult::TaskOutput(task_output(lines.remove(0)))
+        }
+        Batched::Lines(lines) => ExecutionResult::TaskOutputBatch(TaskOutputBatch {
+            outputs: lines.into_iter().map(task_output).collect(),
+        }),
+        Batched::Event(exec_event) => match exec_event {
+            ExecEvent::Started => ExecutionResult::Ping(TaskStarted {
+                start_latency_micros: received.elapsed().as_micros() as u64,
             }),
-        })
-        .map(move |execution_result| TaskExecutionResult {
-            task_id: task_id.clone(),
-            client_id: cloned_client_id.clone(),
-            execution_result: Some(execution_result),
-        })
-        .map(move |execution_result| {
-            encode_and_sign(execution_result, &signing_key, Duration::from_secs(60))
-        })
-        .filter(|result| match result {
-            // filter out signing error
-            Ok(_) => futures::future::ready(true),
-            Err(e) => {
-                error!("Unable to sign task execution result {}", e);
-                futures::future::ready(false)
+            ExecEvent::Finished(status) => match status.code {
+                Some(1) if password_required => ExecutionResult::TaskRejected(
+                    "become failed: sudo requires a password".to_string(),
+                ),
+                None => ExecutionResult::TaskAborted(TaskAborted {
+                    reason: match status.signal {
+                        Some(signal) => format!("killed by {}", signal_name(signal)),
+                        None => "exited without status".to_string(),
+                    },
+                }),
+                Some(return_code) => ExecutionResult::TaskCompleted(TaskCompleted {
+                    return_code,
+                    duration_ms: started.elapsed().as_millis() as u64,
+                }),
+            },
+            ExecEvent::SpawnFailed(e) => {
+                ExecutionResult::TaskRejected(format!("failed to spawn: {}", e))
             }
-        })
-        .map(|result| result.unwrap());
+            ExecEvent::LineEmitted(line) => ExecutionResult::TaskOutput(task_output(line)),
+        },
+    })
+    .take_until(abort_receiver)
+    .chain(aborted_result)
+    .inspect({
+        let task_log = task_log.clone();
+        move |execution_result| {
+            if let Some(task_log) = &task_log {
+                task_log.observe(execution_result);
+            }
+        }
+    })
+    .zip(futures::stream::iter(first_seq..))
+    .map(move |(execution_result, seq)| TaskExecutionResult {
+        task_id: task_id.clone(),
+        client_id: cloned_client_id.clone(),
+        execution_result: Some(execution_result),
+        seq,
+    })
+    .inspect({
+        let sent_results = sent_results.clone();
+        move |execution_result| sent_results.push(execution_result)
+    })
+    .map({
+        let signing_key = signing_key.clone();
+        move |execution_result| encode_and_sign(execution_result, &signing_key, signature_validity)
+    })
+    .filter(|result| match result {
+        // filter out signing error
+        Ok(_) => futures::future::ready(true),
+        Err(e) => {
+            error!("Unable to sign task execution result {}", e);
+            futures::future::ready(false)
+        }
+    })
+    .map(|result| result.unwrap())
+    .boxed();
+    // the results not sent to a taskserver gone meanwhile are still read, see below
+    let results = Arc::new(tokio::sync::Mutex::new(results));
 
-    let mut request = Request::new(stream);
+    let mut request = Request::new(shared_stream(results.clone()));
     request.metadata_mut().insert(
         "task_id",
         AsciiMetadataValue::try_from(cloned_task_id.as_bytes())?,
     );
-    client.task_execution(request).await?;
+    let mut kill_sender = Some(kill_sender);
+    let mut abort_sender = Some(abort_sender);
+    let mut abort = || {
+        info!("Aborting task {}", cloned_task_id);
+        aborted.store(true, Ordering::SeqCst);
+        if let Some(kill_sender) = kill_sender.take() {
+            let _ = kill_sender.send(());
+        }
+        if let Some(abort_sender) = abort_sender.take() {
+            let _ = abort_sender.send(());
+        }
+    };
+    let resend_client = client.clone();
+    let execution = client.task_execution(request);
+    tokio::pin!(execution);
+    let mut result = tokio::select! {
+        result = &mut execution => result,
+        _ = running_task.cut_off() => {
+            abort();
+            execution.await
+        }
+    };
+    if let Err(status) = &result {
+        if result_retries::is_transient(status) && !result_retries.window.is_zero() {
+            warn!(
+                "Unable to send the task results ({}), sending them again once the task is \
+                 finished",
+                status.message()
+            );
+            let finished = async {
+                let mut results = results.lock().await;
+                while results.next().await.is_some() {}
+            };
+            tokio::pin!(finished);
+            tokio::select! {
+                _ = &mut finished => (),
+                _ = running_task.cut_off() => {
+                    abort();
+                    finished.await
+                }
+            }
+            // given up on shutdown
+            if let Some(resent) = tokio::select! {
+                resent = resend_results(
+                    resend_client,
+                    &cloned_task_id,
+                    &sent_results,
+                    &signing_key,
+                    signature_validity,
+                    result_retries.window,
+                ) => Some(resent),
+                _ = running_task.cut_off() => None,
+            } {
+                result = resent;
+            }
+        }
+    }
+    // whether its results could be sent or not
+    if let Some(task_log) = &task_log {
+        task_log.write(result.is_ok()).await;
+    }
+    match result {
+        Err(status) if status.code() == tonic::Code::Cancelled => {
+            info!("Task {} cancelled, killing it", cloned_task_id)
+        }
+        result => {
+            result?;
+        }
+    }
     // do not leave process behind
-    let _ = kill_sender.send(());
-    info!("Finished task {}", cloned_task_id);
+    if let Some(kill_sender) = kill_sender {
+        let _ = kill_sender.send(());
+    }
+    match backpressure.load(Ordering::Relaxed) {
+        0 => info!("Finished task {}", cloned_task_id),
+        waits => info!(
+            "Finished task {}, its output waited {} times for the taskserver",
+            cloned_task_id, waits
+        ),
+    }
     Ok(())
 }
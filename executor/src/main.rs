@@ -1,54 +1,115 @@
-use executor::{executor_main, Opt};
+use anyhow::Context;
+use executor::{executor_main, instance_id, ExecutorExit, ExecutorTasks, Opt};
 use funtonic::config;
-use funtonic::config::ExecutorConfig;
+use funtonic::config::{ED25519Key, ExecutorConfig, KeyProtection};
 use funtonic::crypto::keygen::generate_base64_encoded_keys;
+use funtonic::crypto::sealing::{read_machine_id, seal, unseal, SigningKeyFile};
+use funtonic::file_utils::{warn_if_not_private, write_private_file, PidLock};
 use funtonic::tokio;
 use log::{error, info, warn};
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 use tokio::time::Duration;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
 
 const LOG4RS_CONFIG: &'static str = "/etc/funtonic/executor-log4rs.yaml";
+const LOCK_FILE: &str = "executor.lock";
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    log4rs_gelf::init_file(LOG4RS_CONFIG, None).unwrap_or_else(|e| {
-        eprintln!("Cannot initialize logger from {} - {}", LOG4RS_CONFIG, e);
-        eprintln!("Trying with dev assets!");
-        log4rs_gelf::init_file("executor/assets/log4rs.yaml", None)
-            .expect("Cannot open executor/assets/log4rs.yaml");
-    });
+    funtonic::executor_meta::init_process_started_at();
+    if std::env::var_os("RUST_LOG").is_some() {
+        // spans of the tasks & their durations on close, eg: RUST_LOG=debug
+        tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::from_default_env())
+            .with_span_events(FmtSpan::CLOSE)
+            .init();
+    } else {
+        log4rs_gelf::init_file(LOG4RS_CONFIG, None).unwrap_or_else(|e| {
+            eprintln!("Cannot initialize logger from {} - {}", LOG4RS_CONFIG, e);
+            eprintln!("Trying with dev assets!");
+            log4rs_gelf::init_file("executor/assets/log4rs.yaml", None)
+                .expect("Cannot open executor/assets/log4rs.yaml");
+        });
+        // the task & client ids are fields of the tracing spans
+        funtonic::log_bridge::init().expect("Cannot forward the tracing events to log4rs");
+    }
     let opt = Opt::from_args();
+    let config_directory = config::get_config_directory(&opt.config, "executor.yml")?;
+    // released on return: two executors of the same configuration would share its client_id & key
+    let lock = PidLock::acquire(config_directory.join(LOCK_FILE))
+        .context("Another executor is running with this configuration")?;
+    info!("Lock {} acquired", lock.path().to_string_lossy());
+    if opt.reseal || opt.unseal {
+        let key_path = get_key_path(&config_directory);
+        let signing_key = read_signing_key(&key_path, KeyProtection::None)?;
+        let key_protection = if opt.reseal {
+            KeyProtection::MachineId
+        } else {
+            KeyProtection::None
+        };
+        write_signing_key(&key_path, &signing_key, key_protection)?;
+        info!(
+            "Signing key {} rewritten with {:?} protection",
+            key_path.to_string_lossy(),
+            key_protection
+        );
+        return Ok(());
+    }
+    // the tasks running on reconnection or reload are still waited for
+    let tasks = ExecutorTasks::new();
     loop {
-        let (config, config_path) =
-            config::parse::<_, _, ExecutorConfig>(&opt.config, "executor.yml")?;
-        let key_path = get_key_path(config::get_config_directory(&opt.config, "executor.yml")?);
+        // saved back on reconnection, without the values overridden by environment variables
+        let (mut config, mut file_config, config_path) =
+            config::parse_with_file::<_, _, ExecutorConfig>(&opt.config, "executor.yml")?;
+        config.cli_tags = opt.tags.clone();
+        let instance_id_path = instance_id::instance_id_path(&config_directory);
+        let instance_id = instance_id::read_or_create(&instance_id_path).with_context(|| {
+            format!(
+                "Unable to read the instance id {}",
+                instance_id_path.to_string_lossy()
+            )
+        })?;
+        config.instance_id = Some(instance_id);
+        let key_path = get_key_path(&config_directory);
         let signing_key = if key_path.exists() {
-            serde_yaml::from_reader(File::open(key_path)?)?
+            read_signing_key(&key_path, config.key_protection)?
         } else {
             let (signing_key, _) = generate_base64_encoded_keys(&config.client_id);
             warn!(
                 "Signing key not found, generated a new one, public_key: {}",
                 signing_key.public_key.as_ref().unwrap()
             );
-            serde_yaml::to_writer(File::create(key_path)?, &signing_key)?;
+            write_signing_key(&key_path, &signing_key, config.key_protection)?;
             signing_key
         };
-        match executor_main(config, signing_key).await {
+        match executor_main(config, signing_key, tasks.clone()).await {
             Err(e) => {
                 // this should only happen on TLS configuration parsing.
                 error!("Unknown error occured! {}", e);
                 tokio::time::sleep(Duration::from_secs(1)).await;
             }
-            Ok(config) => {
+            Ok(ExecutorExit::Shutdown) => {
+                info!("Executor shut down");
+                return Ok(());
+            }
+            Ok(ExecutorExit::Reload) => {
+                info!(
+                    "Reloading configuration from {}",
+                    config_path.to_string_lossy()
+                );
+            }
+            Ok(ExecutorExit::Reconnect(config)) => {
                 info!("Connection to task server ended gracefully, saving config & reconnecting.");
-                if let Err(e) = serde_yaml::to_writer(File::create(&config_path)?, &config) {
+                file_config.apply_key_changes(&config);
+                if let Err(e) = serde_yaml::to_writer(File::create(&config_path)?, &file_config) {
                     error!(
                         "Unable to write configuration file to {}: {}\n{:#?}",
                         config_path.to_string_lossy(),
                         e,
-                        config
+                        file_config
                     );
                 }
             }
@@ -56,6 +117,49 @@ async fn main() -> Result<(), anyhow::Error> {
     }
 }
 
+/// Read the signing key, unsealing it if needed
+fn read_signing_key(
+    key_path: &Path,
+    key_protection: KeyProtection,
+) -> Result<ED25519Key, anyhow::Error> {
+    warn_if_not_private(key_path);
+    let key_file: SigningKeyFile = serde_yaml::from_reader(File::open(key_path)?)
+        .with_context(|| format!("Invalid signing key file {}", key_path.to_string_lossy()))?;
+    Ok(match key_file {
+        SigningKeyFile::Plain(signing_key) => {
+            if key_protection == KeyProtection::MachineId {
+                warn!(
+                    "Signing key {} is not sealed, run the executor with --reseal to seal it",
+                    key_path.to_string_lossy()
+                );
+            }
+            signing_key
+        }
+        SigningKeyFile::Sealed(sealed) => {
+            unseal(&sealed, &read_machine_id()?).with_context(|| {
+                format!(
+                    "Cannot unseal signing key {}, if this host machine-id changed, \
+                 the executor key must be regenerated and approved again",
+                    key_path.to_string_lossy()
+                )
+            })?
+        }
+    })
+}
+
+fn write_signing_key(
+    key_path: &Path,
+    signing_key: &ED25519Key,
+    key_protection: KeyProtection,
+) -> Result<(), anyhow::Error> {
+    let key_file = match key_protection {
+        KeyProtection::None => SigningKeyFile::Plain(signing_key.clone()),
+        KeyProtection::MachineId => SigningKeyFile::Sealed(seal(signing_key, &read_machine_id()?)?),
+    };
+    write_private_file(key_path, serde_yaml::to_string(&key_file)?)?;
+    Ok(())
+}
+
 fn get_key_path<P: AsRef<Path>>(config_dir: P) -> PathBuf {
     let mut ret = PathBuf::from(config_dir.as_ref());
     ret.push("executor_ed25519_key.yml");
@@ -0,0 +1,174 @@
+//! `GET /healthz` & `GET /metrics` (prometheus text format) of the executor, served on
+//! `monitoring_bind_address`
+use crate::{LastConnectionStatus, RunningTasks};
+use funtonic::config::CertificateExpiry;
+use funtonic::tokio;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch::Receiver;
+use tokio::task::JoinHandle;
+
+/// `/healthz` fails once the executor has been connecting for longer than this
+const UNHEALTHY_CONNECTING: Duration = Duration::from_secs(60);
+
+struct State {
+    connection_status: Receiver<LastConnectionStatus>,
+    /// None while connected
+    connecting_since: Mutex<Option<Instant>>,
+    running_tasks: RunningTasks,
+    /// reported by `/healthz`, empty without TLS
+    tls_expiries: Vec<CertificateExpiry>,
+}
+
+/// The monitoring listener, serving until stopped
+pub(crate) struct Monitoring {
+    server: JoinHandle<()>,
+}
+
+impl Monitoring {
+    pub(crate) fn spawn(
+        bind_address: &str,
+        connection_status: Receiver<LastConnectionStatus>,
+        running_tasks: RunningTasks,
+        tls_expiries: Vec<CertificateExpiry>,
+    ) -> anyhow::Result<Self> {
+        let addr: SocketAddr = bind_address.parse()?;
+        let state = Arc::new(State {
+            connection_status,
+            connecting_since: Mutex::new(Some(Instant::now())),
+            running_tasks,
+            tls_expiries,
+        });
+        let server = Server::try_bind(&addr)?.serve(make_service_fn({
+            let state = state.clone();
+            move |_| {
+                let state = state.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request| {
+                        futures::future::ready(Ok::<_, Infallible>(state.respond(&request)))
+                    }))
+                }
+            }
+        }));
+        info!("Monitoring listening on {}", addr);
+        let server = tokio::spawn(async move {
+            let (served, _) = tokio::join!(server, track_connection(state));
+            if let Err(e) = served {
+                error!("Monitoring listener failed: {}", e);
+            }
+        });
+        Ok(Self { server })
+    }
+
+    /// Returns once the listening socket is closed, so that the address can be bound again
+    pub(crate) async fn stop(self) {
+        self.server.abort();
+        let _ = self.server.await;
+    }
+}
+
+/// Records since when the executor is connecting
+async fn track_connection(state: Arc<State>) {
+    let mut connection_status = state.connection_status.clone();
+    loop {
+        let connecting = matches!(
+            *connection_status.borrow_and_update(),
+            LastConnectionStatus::Connecting
+        );
+        {
+            let mut connecting_since = state.connecting_since.lock().unwrap();
+            if !connecting {
+                *connecting_since = None;
+            } else if connecting_since.is_none() {
+                *connecting_since = Some(Instant::now());
+            }
+        }
+        if connection_status.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+impl State {
+    fn respond(&self, request: &Request<Body>) -> Response<Body> {
+        let connecting_since = *self.connecting_since.lock().unwrap();
+        match (request.method(), request.uri().path()) {
+            (&Method::GET, "/healthz") => match connecting_since {
+                Some(since) if since.elapsed() > UNHEALTHY_CONNECTING => response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("connecting for {}s\n", since.elapsed().as_secs()),
+                ),
+                Some(_) => response(StatusCode::OK, self.health("connecting")),
+                None => response(StatusCode::OK, self.health("connected")),
+            },
+            (&Method::GET, "/metrics") => {
+                let mut response =
+                    response(StatusCode::OK, self.metrics(connecting_since.is_none()));
+                response.headers_mut().insert(
+                    hyper::header::CONTENT_TYPE,
+                    hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+                );
+                response
+            }
+            _ => response(StatusCode::NOT_FOUND, "not found\n".to_string()),
+        }
+    }
+
+    /// The status followed by the expiry of the TLS certificates
+    fn health(&self, status: &str) -> String {
+        let mut health = format!("{}\n", status);
+        for expiry in &self.tls_expiries {
+            health.push_str(&format!(
+                "{}.not_after: {}\n",
+                expiry.name,
+                expiry.not_after()
+            ));
+        }
+        health
+    }
+
+    fn metrics(&self, connected: bool) -> String {
+        let mut metrics = String::new();
+        for (name, kind, help, value) in [
+            (
+                "funtonic_executor_connected",
+                "gauge",
+                "1 when connected to a taskserver",
+                connected as u64,
+            ),
+            (
+                "funtonic_executor_tasks_executed_total",
+                "counter",
+                "Tasks finished since the configuration was loaded",
+                self.running_tasks.executed(),
+            ),
+            (
+                "funtonic_executor_tasks_running",
+                "gauge",
+                "Tasks being executed",
+                self.running_tasks.count() as u64,
+            ),
+            (
+                "funtonic_executor_last_task_timestamp_seconds",
+                "gauge",
+                "Start of the last task, 0 if none",
+                self.running_tasks.last_started_secs(),
+            ),
+        ] {
+            metrics.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            ));
+        }
+        metrics
+    }
+}
+
+fn response(status: StatusCode, body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,189 @@
+//! Output lines of a command grouped in batches: each batch is signed & sent as a single result
+use exec::{ExecEvent, Line};
+use funtonic::tokio;
+use futures::{Stream, StreamExt};
+use std::time::Duration;
+
+pub const DEFAULT_OUTPUT_BATCH_MILLIS: u64 = 20;
+pub const DEFAULT_OUTPUT_BATCH_BYTES: usize = 16 * 1024;
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct OutputBatching {
+    /// the batch is sent this long after its first line at most
+    pub max_delay: Duration,
+    /// the batch is sent once it holds this many bytes
+    pub max_bytes: usize,
+}
+
+impl OutputBatching {
+    /// One line per batch, for the commanders & taskservers not supporting batches
+    pub(crate) fn disabled() -> Self {
+        Self {
+            max_delay: Duration::ZERO,
+            max_bytes: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Batched {
+    Lines(Vec<Line>),
+    Event(ExecEvent),
+}
+
+/// Group the consecutive lines emitted by a command, the other events are not delayed: the batch
+/// being collected is sent right before them.
+pub(crate) fn batched<S>(events: S, batching: OutputBatching) -> impl Stream<Item = Batched>
+where
+    S: Stream<Item = ExecEvent> + Unpin,
+{
+    futures::stream::unfold(
+        (events, None),
+        move |(mut events, pending): (S, Option<ExecEvent>)| async move {
+            let line = match pending {
+                Some(event) => event,
+                None => events.next().await?,
+            };
+            let line = match line {
+                ExecEvent::LineEmitted(line) => line,
+                event => return Some((Batched::Event(event), (events, None))),
+            };
+            let deadline = tokio::time::Instant::now() + batching.max_delay;
+            let mut bytes = line.line.len();
+            let mut lines = vec![line];
+            let mut pending = None;
+            while bytes < batching.max_bytes {
+                match tokio::time::timeout_at(deadline, events.next()).await {
+                    Ok(Some(ExecEvent::LineEmitted(line))) => {
+                        bytes += line.line.len();
+                        lines.push(line);
+                    }
+                    Ok(Some(event)) => {
+                        pending = Some(event);
+                        break;
+                    }
+                    // the stream ended or the delay elapsed
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            Some((Batched::Lines(lines), (events, pending)))
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{batched, Batched, OutputBatching};
+    use exec::{ExecEvent, ExitStatus, Line, Type};
+    use funtonic::tokio;
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    fn line(line_type: Type, line: &str) -> Line {
+        Line {
+            line_type,
+            line: line.as_bytes().to_vec(),
+        }
+    }
+
+    /// the batches, lines rendered as `out:line` or `err:line`
+    async fn collect(
+        events: impl futures::Stream<Item = ExecEvent> + Unpin,
+        batching: OutputBatching,
+    ) -> Vec<Vec<String>> {
+        batched(events, batching)
+            .map(|batched| match batched {
+                Batched::Lines(lines) => lines
+                    .into_iter()
+                    .map(|line| {
+                        let kind = match line.line_type {
+                            Type::Out => "out",
+                            Type::Err => "err",
+                        };
+                        format!("{}:{}", kind, String::from_utf8_lossy(&line.line))
+                    })
+                    .collect(),
+                Batched::Event(event) => vec![format!("{:?}", event)],
+            })
+            .collect()
+            .await
+    }
+
+    fn interleaved(count: usize) -> Vec<ExecEvent> {
+        std::iter::once(ExecEvent::Started)
+            .chain((0..count).map(|i| {
+                let line_type = if i % 3 == 0 { Type::Err } else { Type::Out };
+                ExecEvent::LineEmitted(line(line_type, &i.to_string()))
+            }))
+            .chain(std::iter::once(ExecEvent::Finished(ExitStatus {
+                code: Some(0),
+                signal: None,
+            })))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn interleaved_output_order() {
+        let batching = OutputBatching {
+            max_delay: Duration::from_secs(10),
+            max_bytes: 8,
+        };
+        let batches = collect(futures::stream::iter(interleaved(20)), batching).await;
+        // started, the lines by batches of at most 8 bytes, then finished without waiting
+        assert_eq!(vec!["Started".to_string()], batches[0]);
+        assert_eq!(
+            vec!["err:0", "out:1", "out:2", "err:3", "out:4", "out:5", "err:6", "out:7"],
+            batches[1]
+        );
+        assert!(batches[1..batches.len() - 1]
+            .iter()
+            .all(|batch| batch.len() <= 8));
+        assert!(batches.last().unwrap()[0].starts_with("Finished"));
+        let lines: Vec<String> = batches[1..batches.len() - 1].concat();
+        let expected: Vec<String> = (0..20)
+            .map(|i| format!("{}:{}", if i % 3 == 0 { "err" } else { "out" }, i))
+            .collect();
+        assert_eq!(expected, lines);
+
+        // no batching: the same lines one by one
+        let batches = collect(
+            futures::stream::iter(interleaved(20)),
+            OutputBatching::disabled(),
+        )
+        .await;
+        assert_eq!(22, batches.len());
+        assert_eq!(expected, batches[1..21].concat());
+    }
+
+    #[tokio::test]
+    async fn delayed_lines() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(16);
+        let batching = OutputBatching {
+            max_delay: Duration::from_millis(50),
+            max_bytes: 1024,
+        };
+        let batches = tokio::spawn(collect(
+            tokio_stream::wrappers::ReceiverStream::new(receiver),
+            batching,
+        ));
+        sender
+            .send(ExecEvent::LineEmitted(line(Type::Out, "a")))
+            .await
+            .unwrap();
+        sender
+            .send(ExecEvent::LineEmitted(line(Type::Err, "b")))
+            .await
+            .unwrap();
+        // the batch is sent before the next line comes
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        sender
+            .send(ExecEvent::LineEmitted(line(Type::Out, "c")))
+            .await
+            .unwrap();
+        drop(sender);
+        assert_eq!(
+            vec![vec!["out:a", "err:b"], vec!["out:c"]],
+            batches.await.unwrap()
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Execution results of a task kept while they are sent: when the taskserver becomes unavailable
+//! (restarted, network failure...), the task keeps running and its results are sent again once it
+//! is finished
+use funtonic::prost::Message;
+use funtonic::tonic;
+use grpc_service::grpc_protocol::TaskExecutionResult;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const DEFAULT_RESULT_RETRY_WINDOW_SECS: u64 = 60;
+pub const DEFAULT_RESULT_RETRY_BUFFER_BYTES: usize = 1024 * 1024;
+
+/// Delay before the first retry, doubled after each failed one
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct ResultRetries {
+    /// how long the results are sent again once the task is finished, zero disables the retries
+    pub window: Duration,
+    /// results kept for the retries, in bytes
+    pub max_bytes: usize,
+}
+
+/// Results of a task sent to the taskserver, the oldest ones are dropped beyond `max_bytes`: the
+/// last result (the end of the task) is always kept
+#[derive(Clone)]
+pub(crate) struct SentResults {
+    inner: Arc<Mutex<SentResultsInner>>,
+}
+
+struct SentResultsInner {
+    results: VecDeque<TaskExecutionResult>,
+    bytes: usize,
+    max_bytes: usize,
+    dropped: usize,
+}
+
+impl SentResults {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(SentResultsInner {
+                results: VecDeque::new(),
+                bytes: 0,
+                max_bytes,
+                dropped: 0,
+            })),
+        }
+    }
+
+    pub(crate) fn push(&self, result: &TaskExecutionResult) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.bytes += result.encoded_len();
+        inner.results.push_back(result.clone());
+        while inner.bytes > inner.max_bytes && inner.results.len() > 1 {
+            if let Some(dropped) = inner.results.pop_front() {
+                inner.bytes -= dropped.encoded_len();
+                inner.dropped += 1;
+            }
+        }
+    }
+
+    /// Results to send again, in order: the taskserver drops the ones it already forwarded
+    pub(crate) fn results(&self) -> Vec<TaskExecutionResult> {
+        self.inner.lock().unwrap().results.iter().cloned().collect()
+    }
+
+    /// Results which will not be sent again
+    pub(crate) fn dropped(&self) -> usize {
+        self.inner.lock().unwrap().dropped
+    }
+}
+
+/// The taskserver could not be reached or went away while receiving the results, it may accept
+/// them later
+pub(crate) fn is_transient(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        tonic::Code::Unavailable | tonic::Code::Unknown
+    )
+}
+
+/// Delays before each retry, with an exponential backoff, their total does not exceed `window`
+pub(crate) fn retry_delays(window: Duration) -> impl Iterator<Item = Duration> {
+    let mut elapsed = Duration::ZERO;
+    std::iter::successors(Some(FIRST_RETRY_DELAY), |delay| {
+        Some((*delay * 2).min(MAX_RETRY_DELAY))
+    })
+    .take_while(move |delay| {
+        elapsed += *delay;
+        elapsed <= window
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{retry_delays, SentResults};
+    use funtonic::prost::Message;
+    use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+    use grpc_service::grpc_protocol::task_output::Output;
+    use grpc_service::grpc_protocol::{TaskCompleted, TaskExecutionResult, TaskOutput};
+    use std::time::Duration;
+
+    fn result(seq: u64, execution_result: ExecutionResult) -> TaskExecutionResult {
+        TaskExecutionResult {
+            task_id: "task-1".to_string(),
+            client_id: "exec".to_string(),
+            execution_result: Some(execution_result),
+            seq,
+        }
+    }
+
+    fn output(seq: u64) -> TaskExecutionResult {
+        result(
+            seq,
+            ExecutionResult::TaskOutput(TaskOutput {
+                output: Some(Output::Stdout("0123456789".to_string())),
+            }),
+        )
+    }
+
+    #[test]
+    fn bounded_results() {
+        let line_bytes = output(1).encoded_len();
+        let sent = SentResults::new(3 * line_bytes);
+        (1..=5).for_each(|seq| sent.push(&output(seq)));
+        // the oldest results are dropped
+        assert_eq!(
+            vec![3, 4, 5],
+            sent.results()
+                .iter()
+                .map(|result| result.seq)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(2, sent.dropped());
+
+        // the end of the task is kept, whatever its size
+        let sent = SentResults::new(0);
+        sent.push(&output(1));
+        sent.push(&result(
+            2,
+            ExecutionResult::TaskCompleted(TaskCompleted {
+                return_code: 0,
+                duration_ms: 1200,
+            }),
+        ));
+        let results = sent.results();
+        assert_eq!(1, results.len());
+        assert_eq!(2, results[0].seq);
+    }
+
+    #[test]
+    fn backoff() {
+        let secs = |window| {
+            retry_delays(Duration::from_secs(window))
+                .map(|delay| delay.as_secs())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec![1, 2, 4, 8, 10, 10, 10, 10], secs(60));
+        assert_eq!(vec![1, 2], secs(3));
+        assert!(secs(0).is_empty());
+    }
+}
@@ -0,0 +1,115 @@
+//! Scripts shipped whole by `commander run --script`, run from a temporary executable file
+use grpc_service::grpc_protocol::execute_command::Program;
+use grpc_service::grpc_protocol::Script;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use tempfile::TempPath;
+
+/// Command run by the executor shell, with the script file it runs if any
+pub(crate) struct PreparedCommand {
+    pub(crate) command: String,
+    /// removed when dropped: once the task is finished, even if it has been killed
+    _script_file: Option<TempPath>,
+}
+
+impl PreparedCommand {
+    /// Older commanders send empty commands (pings) without program
+    pub(crate) fn new(program: Option<Program>) -> io::Result<Self> {
+        Ok(match program {
+            Some(Program::Command(command)) => Self {
+                command,
+                _script_file: None,
+            },
+            Some(Program::Script(script)) => {
+                let script_file = write_script(&script)?;
+                Self {
+                    command: quote_path(&script_file),
+                    _script_file: Some(script_file),
+                }
+            }
+            None => Self {
+                command: String::new(),
+                _script_file: None,
+            },
+        })
+    }
+}
+
+/// Logged description of the task program
+pub(crate) fn describe(program: &Option<Program>) -> String {
+    match program {
+        Some(Program::Command(command)) => command.clone(),
+        Some(Program::Script(script)) => format!("script {}", script.name),
+        None => String::new(),
+    }
+}
+
+/// Executable file keeping the extension of the script (`.ps1`, `.bat`...): the shell runs it
+/// with the interpreter of its shebang, or as a shell script if it has none
+fn write_script(script: &Script) -> io::Result<TempPath> {
+    let suffix = Path::new(&script.name)
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    let mut file = tempfile::Builder::new()
+        .prefix("funtonic-script-")
+        .suffix(&suffix)
+        .tempfile()?;
+    file.write_all(script.content.as_bytes())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.as_file()
+            .set_permissions(std::fs::Permissions::from_mode(0o700))?;
+    }
+    // closed: a file still open for writing cannot be executed
+    Ok(file.into_temp_path())
+}
+
+fn quote_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    if cfg!(windows) {
+        format!("\"{}\"", path)
+    } else {
+        format!("'{}'", path.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PreparedCommand;
+    use grpc_service::grpc_protocol::execute_command::Program;
+    use grpc_service::grpc_protocol::Script;
+
+    #[test]
+    fn script_file() {
+        let prepared = PreparedCommand::new(Some(Program::Script(Script {
+            name: "maintenance.sh".to_string(),
+            content: "#!/bin/sh\necho hello\n".to_string(),
+        })))
+        .unwrap();
+        let path = prepared.command.trim_matches('\'').to_string();
+        assert!(path.ends_with(".sh"), "{}", path);
+        assert_eq!(
+            "#!/bin/sh\necho hello\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(0o700, mode & 0o777);
+        }
+        drop(prepared);
+        assert!(!std::path::Path::new(&path).exists());
+
+        assert_eq!("", PreparedCommand::new(None).unwrap().command);
+        assert_eq!(
+            "uptime",
+            PreparedCommand::new(Some(Program::Command("uptime".to_string())))
+                .unwrap()
+                .command
+        );
+    }
+}
@@ -0,0 +1,277 @@
+//! Local record of the tasks executed (`task_log_directory`), one YAML file per task, kept even
+//! if the results could not be reported to the taskserver
+use funtonic::tokio;
+use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+use grpc_service::grpc_protocol::task_output::Output;
+use grpc_service::grpc_protocol::TaskOutput;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_TASK_LOG_MAX_FILES: usize = 1000;
+
+/// Last output lines kept in the record of a task
+const OUTPUT_TAIL_LINES: usize = 20;
+/// Longer output lines are truncated in the record
+const OUTPUT_TAIL_LINE_LENGTH: usize = 512;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TaskRecord {
+    /// reception of the task by the executor
+    pub timestamp: String,
+    pub task_id: String,
+    /// signer of the task
+    pub key_id: String,
+    pub command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub return_code: Option<i32>,
+    /// why the task did not complete: aborted or rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<String>,
+    /// whether all the results have been sent to the taskserver
+    pub reported: bool,
+    /// stderr lines are prefixed by `! `
+    pub output_tail: VecDeque<String>,
+}
+
+impl TaskRecord {
+    fn new(task_id: &str, key_id: &str, command: &str) -> Self {
+        Self {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            task_id: task_id.to_string(),
+            key_id: key_id.to_string(),
+            command: command.to_string(),
+            return_code: None,
+            failure: None,
+            reported: false,
+            output_tail: VecDeque::new(),
+        }
+    }
+
+    fn observe(&mut self, result: &ExecutionResult) {
+        match result {
+            ExecutionResult::TaskOutput(output) => self.push_output(output),
+            ExecutionResult::TaskOutputBatch(batch) => batch
+                .outputs
+                .iter()
+                .for_each(|output| self.push_output(output)),
+            ExecutionResult::TaskCompleted(completed) => {
+                self.return_code = Some(completed.return_code)
+            }
+            ExecutionResult::TaskAborted(aborted) => {
+                self.failure = Some(format!("aborted: {}", aborted.reason))
+            }
+            ExecutionResult::TaskRejected(reason) => {
+                self.failure = Some(format!("rejected: {}", reason))
+            }
+            _ => (),
+        }
+    }
+
+    fn push_output(&mut self, output: &TaskOutput) {
+        let line = match &output.output {
+            Some(Output::Stdout(line)) => line.trim_end().to_string(),
+            Some(Output::Stderr(line)) => format!("! {}", line.trim_end()),
+            None => return,
+        };
+        if self.output_tail.len() == OUTPUT_TAIL_LINES {
+            self.output_tail.pop_front();
+        }
+        self.output_tail
+            .push_back(line.chars().take(OUTPUT_TAIL_LINE_LENGTH).collect());
+    }
+}
+
+/// Writes the task records to a directory, keeping the `max_files` most recent ones
+#[derive(Debug, Clone)]
+pub(crate) struct TaskLogger {
+    directory: PathBuf,
+    max_files: usize,
+    /// records in the directory, sorted by reception: listed by the first write only
+    files: Arc<Mutex<Option<BTreeSet<PathBuf>>>>,
+}
+
+impl TaskLogger {
+    pub(crate) fn new<P: AsRef<Path>>(directory: P, max_files: usize) -> Self {
+        Self {
+            directory: directory.as_ref().to_path_buf(),
+            max_files,
+            files: Default::default(),
+        }
+    }
+
+    /// Start recording a task, the record is written by [TaskLog::write]
+    pub(crate) fn start(&self, task_id: &str, key_id: &str, command: &str) -> TaskLog {
+        TaskLog {
+            logger: self.clone(),
+            record: Arc::new(Mutex::new(TaskRecord::new(task_id, key_id, command))),
+        }
+    }
+
+    fn write(&self, record: &TaskRecord) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let written = self.write_record(&mut files, record);
+        if written.is_err() {
+            // eg: the directory has been removed, listed again by the next write
+            *files = None;
+        }
+        written
+    }
+
+    fn write_record(
+        &self,
+        files: &mut Option<BTreeSet<PathBuf>>,
+        record: &TaskRecord,
+    ) -> io::Result<()> {
+        if files.is_none() {
+            std::fs::create_dir_all(&self.directory)?;
+            *files = Some(self.list()?);
+        }
+        let files = files.get_or_insert_with(BTreeSet::new);
+        // sorted by reception
+        let file_name = format!(
+            "{}-{}.yml",
+            chrono::DateTime::parse_from_rfc3339(&record.timestamp)
+                .map(|timestamp| timestamp.format("%Y%m%dT%H%M%S%.3f").to_string())
+                .unwrap_or_default(),
+            record.task_id
+        );
+        let yaml = serde_yaml::to_string(record)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let path = self.directory.join(file_name);
+        std::fs::write(&path, yaml)?;
+        files.insert(path);
+        // remove the oldest records beyond `max_files`
+        while files.len() > self.max_files {
+            if let Some(oldest) = files.pop_first() {
+                std::fs::remove_file(oldest)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> io::Result<BTreeSet<PathBuf>> {
+        Ok(std::fs::read_dir(&self.directory)?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().map(|ext| ext == "yml").unwrap_or(false))
+            .collect())
+    }
+}
+
+/// Record of a task being executed
+#[derive(Clone)]
+pub(crate) struct TaskLog {
+    logger: TaskLogger,
+    record: Arc<Mutex<TaskRecord>>,
+}
+
+impl TaskLog {
+    pub(crate) fn observe(&self, result: &ExecutionResult) {
+        self.record.lock().unwrap().observe(result);
+    }
+
+    /// The file system calls run off the tokio workers
+    pub(crate) async fn write(&self, reported: bool) {
+        let task_log = self.clone();
+        let _ = tokio::task::spawn_blocking(move || task_log.write_blocking(reported)).await;
+    }
+
+    fn write_blocking(&self, reported: bool) {
+        let mut record = self.record.lock().unwrap();
+        record.reported = reported;
+        if let Err(e) = self.logger.write(&record) {
+            error!(
+                "Unable to write the record of task {} to {}: {}",
+                record.task_id,
+                self.logger.directory.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TaskLogger, TaskRecord, OUTPUT_TAIL_LINES};
+    use funtonic::tokio;
+    use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+    use grpc_service::grpc_protocol::task_output::Output;
+    use grpc_service::grpc_protocol::{TaskCompleted, TaskOutput, TaskOutputBatch};
+
+    fn records(directory: &std::path::Path) -> Vec<TaskRecord> {
+        let mut paths: Vec<_> = std::fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort();
+        paths
+            .iter()
+            .map(|path| serde_yaml::from_str(&std::fs::read_to_string(path).unwrap()).unwrap())
+            .collect()
+    }
+
+    fn stdout(line: &str) -> TaskOutput {
+        TaskOutput {
+            output: Some(Output::Stdout(format!("{}\n", line))),
+        }
+    }
+
+    #[tokio::test]
+    async fn task_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger = TaskLogger::new(dir.path().join("tasks"), 2);
+
+        let log = logger.start("task-1", "ops", "uptime");
+        log.observe(&ExecutionResult::TaskOutput(stdout("first")));
+        log.observe(&ExecutionResult::TaskOutputBatch(TaskOutputBatch {
+            outputs: (0..OUTPUT_TAIL_LINES)
+                .map(|i| stdout(&i.to_string()))
+                .chain(Some(TaskOutput {
+                    output: Some(Output::Stderr("warning".to_string())),
+                }))
+                .collect(),
+        }));
+        log.observe(&ExecutionResult::TaskCompleted(TaskCompleted {
+            return_code: 3,
+            duration_ms: 12,
+        }));
+        log.write(true).await;
+
+        let logged = records(&dir.path().join("tasks"));
+        assert_eq!(1, logged.len());
+        assert_eq!("task-1", logged[0].task_id);
+        assert_eq!("ops", logged[0].key_id);
+        assert_eq!("uptime", logged[0].command);
+        assert_eq!(Some(3), logged[0].return_code);
+        assert!(logged[0].reported);
+        // the oldest lines are dropped
+        assert_eq!(OUTPUT_TAIL_LINES, logged[0].output_tail.len());
+        assert_eq!("1", logged[0].output_tail[0]);
+        assert_eq!("! warning", logged[0].output_tail[OUTPUT_TAIL_LINES - 1]);
+
+        let log = logger.start("task-2", "ops", "sleep 60");
+        log.observe(&ExecutionResult::TaskRejected(
+            "failed to spawn".to_string(),
+        ));
+        log.write(false).await;
+        // the oldest records are removed
+        logger.start("task-3", "ci", "true").write(false).await;
+        let logged = records(&dir.path().join("tasks"));
+        assert_eq!(
+            vec!["task-2", "task-3"],
+            logged
+                .iter()
+                .map(|record| record.task_id.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some("rejected: failed to spawn".to_string()),
+            logged[0].failure
+        );
+        assert_eq!(None, logged[1].return_code);
+        assert!(!logged[1].reported);
+    }
+}
@@ -0,0 +1,43 @@
+//! Commands executed one at a time, in the order they are received (`serialize_tasks`)
+use funtonic::tokio;
+use tokio::sync::{mpsc, oneshot};
+
+/// Held by the task being executed, the next task starts once it is dropped
+pub(crate) struct Turn {
+    _finished: oneshot::Sender<()>,
+}
+
+#[derive(Clone)]
+pub(crate) struct TaskQueue {
+    waiting: mpsc::UnboundedSender<oneshot::Sender<Turn>>,
+}
+
+impl TaskQueue {
+    /// Spawns the worker handing the turns out, it stops once the queue is dropped
+    pub(crate) fn new() -> Self {
+        let (waiting, mut receiver) = mpsc::unbounded_channel::<oneshot::Sender<Turn>>();
+        tokio::spawn(async move {
+            while let Some(task) = receiver.recv().await {
+                let (finished, finished_receiver) = oneshot::channel();
+                // a task cancelled while queued is gone
+                if task
+                    .send(Turn {
+                        _finished: finished,
+                    })
+                    .is_ok()
+                {
+                    let _ = finished_receiver.await;
+                }
+            }
+        });
+        Self { waiting }
+    }
+
+    /// Resolves once the tasks queued before are finished
+    pub(crate) fn enqueue(&self) -> oneshot::Receiver<Turn> {
+        let (sender, receiver) = oneshot::channel();
+        // the worker runs as long as the queue exists
+        let _ = self.waiting.send(sender);
+        receiver
+    }
+}
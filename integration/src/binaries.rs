@@ -0,0 +1,509 @@
+//! Integration scenarios run against external taskserver, executor & commander executables (eg: a
+//! packaged release) instead of in-process library calls.
+//!
+//! Enabled by setting `FUNTONIC_TEST_BINARIES` to the directory containing the executables, the
+//! tests are no-ops otherwise.
+use crate::test_utils::{commander_config, executor_config, taskserver_config};
+use commander::{CommanderSyntheticOutput, ExecutorState};
+use funtonic::config::{CommanderConfig, ED25519Key, ExecutorConfig, ServerConfig};
+use funtonic::crypto::sealing::SigningKeyFile;
+use funtonic::task_server::AdminErrorJsonResponse;
+use grpc_service::grpc_protocol::AdminErrorCode;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::time::Duration;
+
+pub const BINARIES_ENV_VAR: &str = "FUNTONIC_TEST_BINARIES";
+
+/// Directory of the executables
+pub struct Binaries {
+    directory: PathBuf,
+}
+
+/// Child process killed on drop
+pub struct ChildGuard(Child);
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Exit code & outputs of a commander invocation
+pub struct CommanderRun {
+    pub exit_code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// `commander run --json` summary
+#[derive(Deserialize)]
+struct RunJsonSummary {
+    states: BTreeMap<ExecutorState, BTreeSet<String>>,
+    output: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    durations_ms: BTreeMap<String, u64>,
+    #[serde(default)]
+    rejections: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct DryRunExecutor {
+    client_id: String,
+}
+
+impl Binaries {
+    pub fn from_env() -> Option<Self> {
+        std::env::var_os(BINARIES_ENV_VAR).map(|directory| Self {
+            directory: directory.into(),
+        })
+    }
+
+    fn command(&self, name: &str) -> Command {
+        let mut command = Command::new(self.directory.join(name));
+        // binaries fall back to their dev logging configuration, relative to the workspace root
+        command.current_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join(".."));
+        command.stdin(Stdio::null());
+        command
+    }
+
+    /// Spawn the taskserver and wait for its readiness signal, returns the address it listens on
+    pub fn taskserver(
+        &self,
+        config: &ServerConfig,
+        config_directory: &Path,
+    ) -> (ChildGuard, SocketAddr) {
+        let config_path = write_yaml(config_directory, "server.yml", config);
+        let mut child = ChildGuard(
+            self.command("taskserver")
+                .arg("-c")
+                .arg(config_path)
+                .stdout(Stdio::piped())
+                .spawn()
+                .expect("Cannot spawn taskserver"),
+        );
+
+        let stdout = child.0.stdout.take().unwrap();
+        let (ready_sender, ready_receiver) = mpsc::channel();
+        // keep reading stdout so the taskserver never blocks on a full pipe
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if let Some(addr) = line.strip_prefix("READY on ") {
+                    let _ = ready_sender.send(addr.parse::<SocketAddr>().unwrap());
+                }
+            }
+        });
+        let addr = ready_receiver
+            .recv_timeout(Duration::from_secs(30))
+            .expect("taskserver did not signal readiness");
+        (child, addr)
+    }
+
+    pub fn executor(
+        &self,
+        config: &ExecutorConfig,
+        signing_key: &ED25519Key,
+        config_directory: &Path,
+    ) -> ChildGuard {
+        let config_path = write_yaml(config_directory, "executor.yml", config);
+        write_yaml(
+            config_directory,
+            "executor_ed25519_key.yml",
+            &SigningKeyFile::Plain(signing_key.clone()),
+        );
+        ChildGuard(
+            self.command("executor")
+                .arg("-c")
+                .arg(config_path)
+                .stdout(Stdio::null())
+                .spawn()
+                .expect("Cannot spawn executor"),
+        )
+    }
+
+    pub fn commander(
+        &self,
+        config: &CommanderConfig,
+        config_directory: &Path,
+        args: &[&str],
+    ) -> CommanderRun {
+        let config_path = write_yaml(config_directory, "commander.yml", config);
+        let output = self
+            .command("commander")
+            .arg("-c")
+            .arg(config_path)
+            .args(args)
+            .output()
+            .expect("Cannot run commander");
+        CommanderRun {
+            exit_code: output.status.code().unwrap_or(-1),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+    }
+}
+
+impl CommanderRun {
+    fn last_json_line<'de, T: Deserialize<'de>>(&'de self) -> Option<T> {
+        self.stdout
+            .lines()
+            .rev()
+            .find_map(|line| serde_json::from_str(line).ok())
+    }
+
+    /// Synthetic output of `run --json` or `keys --json`, None if the command was not launched
+    pub fn executor_output(&self) -> Option<CommanderSyntheticOutput> {
+        self.last_json_line::<RunJsonSummary>()
+            .map(|summary| CommanderSyntheticOutput::Executor {
+                states: summary.states,
+                output: summary.output,
+                durations_ms: summary.durations_ms,
+                rejections: summary.rejections,
+            })
+    }
+
+    pub fn expect_executor_output(&self, message: &str) -> CommanderSyntheticOutput {
+        self.executor_output()
+            .unwrap_or_else(|| panic!("{}: {}{}", message, self.stdout, self.stderr))
+    }
+
+    pub fn dry_run_output(&self) -> CommanderSyntheticOutput {
+        CommanderSyntheticOutput::DryRun(
+            self.last_json_line::<Vec<DryRunExecutor>>()
+                .expect("Not a dry run result")
+                .into_iter()
+                .map(|executor| executor.client_id)
+                .collect(),
+        )
+    }
+
+    pub fn expect_admin_success(&self, message: &str) -> CommanderSyntheticOutput {
+        assert_eq!(0, self.exit_code, "{}: {}", message, self.stderr);
+        CommanderSyntheticOutput::Admin(self.stdout.trim().to_string())
+    }
+
+    pub fn assert_admin_error(&self, expected_code: AdminErrorCode) {
+        let error = self
+            .last_json_line::<AdminErrorJsonResponse>()
+            .unwrap_or_else(|| panic!("Not an admin error: {}", self.stdout));
+        assert_eq!(expected_code.as_str_name().to_lowercase(), error.code);
+        assert_ne!(0, self.exit_code);
+    }
+}
+
+fn write_yaml<T: serde::Serialize>(directory: &Path, name: &str, content: &T) -> PathBuf {
+    let path = directory.join(name);
+    std::fs::write(&path, serde_yaml::to_string(content).unwrap()).unwrap();
+    path
+}
+
+/// Taskserver & executor configuration directories along with the processes
+pub struct Deployment {
+    pub addr: SocketAddr,
+    pub commander_directory: tempfile::TempDir,
+    _taskserver_directory: tempfile::TempDir,
+    _executor_directory: tempfile::TempDir,
+    _taskserver: ChildGuard,
+    _executor: ChildGuard,
+}
+
+impl Deployment {
+    pub fn start(
+        binaries: &Binaries,
+        authorized_keys: BTreeMap<String, String>,
+        admin_authorized_keys: BTreeMap<String, String>,
+        executor_authorized_keys: BTreeMap<String, String>,
+        executor_key: &ED25519Key,
+    ) -> Self {
+        let taskserver_directory = tempfile::tempdir().unwrap();
+        let mut config = taskserver_config(
+            0,
+            false,
+            authorized_keys,
+            admin_authorized_keys,
+            taskserver_directory.path().join("data"),
+        );
+        config.bind_address = "127.0.0.1:0".into();
+        let (taskserver, addr) = binaries.taskserver(&config, taskserver_directory.path());
+
+        let executor_directory = tempfile::tempdir().unwrap();
+        let executor = binaries.executor(
+            &executor_config(addr.port(), false, executor_authorized_keys),
+            executor_key,
+            executor_directory.path(),
+        );
+        Self {
+            addr,
+            commander_directory: tempfile::tempdir().unwrap(),
+            _taskserver_directory: taskserver_directory,
+            _executor_directory: executor_directory,
+            _taskserver: taskserver,
+            _executor: executor,
+        }
+    }
+
+    pub fn commander(&self, binaries: &Binaries, key: &ED25519Key, args: &[&str]) -> CommanderRun {
+        binaries.commander(
+            &commander_config(self.addr.port(), false, key.clone()),
+            self.commander_directory.path(),
+            args,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Binaries, Deployment};
+    use crate::test_utils::{
+        assert_executor_error, assert_executor_rejected, assert_listed_executors,
+        assert_success_of_one_executor,
+    };
+    use commander::CommanderSyntheticOutput;
+    use funtonic::config::ED25519Key;
+    use funtonic::crypto::keygen::generate_base64_encoded_keys;
+    use grpc_service::grpc_protocol::AdminErrorCode;
+    use std::time::Duration;
+
+    /// Approve the executor key as soon as it is known, then wait for the executor to connect
+    fn approve_executor(binaries: &Binaries, deployment: &Deployment, admin_key: &ED25519Key) {
+        let approved = (0..60).any(|_| {
+            let approved = deployment
+                .commander(
+                    binaries,
+                    admin_key,
+                    &["admin", "-o", "json", "approve-executor-key", "exec"],
+                )
+                .exit_code
+                == 0;
+            if !approved {
+                std::thread::sleep(Duration::from_millis(500));
+            }
+            approved
+        });
+        assert!(approved, "Did not approve executor key");
+        let connected = (0..60).any(|_| {
+            let run = deployment.commander(
+                binaries,
+                admin_key,
+                &["admin", "-o", "json", "list-connected-executors"],
+            );
+            let connected = run.exit_code == 0 && run.stdout.contains("\"exec\"");
+            if !connected {
+                std::thread::sleep(Duration::from_millis(500));
+            }
+            connected
+        });
+        assert!(connected, "Executor did not connect");
+    }
+
+    #[test]
+    fn binaries_no_tls_test() {
+        let Some(binaries) = Binaries::from_env() else {
+            return;
+        };
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+        let deployment = Deployment::start(
+            &binaries,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            authorized_keys,
+            &executor_private_key,
+        );
+        approve_executor(&binaries, &deployment, &priv_key);
+
+        assert_success_of_one_executor(
+            deployment
+                .commander(
+                    &binaries,
+                    &priv_key,
+                    &["run", "--json", "*", "cat Cargo.toml"],
+                )
+                .expect_executor_output("cat Cargo.toml failed"),
+        );
+
+        match deployment
+            .commander(
+                &binaries,
+                &priv_key,
+                &["run", "--dry-run", "--json", "*", "false"],
+            )
+            .dry_run_output()
+        {
+            CommanderSyntheticOutput::DryRun(client_ids) => assert_eq!(vec!["exec"], client_ids),
+            other => panic!("Not a dry run result: {:?}", other),
+        }
+
+        assert_success_of_one_executor(
+            deployment
+                .commander(
+                    &binaries,
+                    &priv_key,
+                    &["run", "--json", "-b", "1", "*", "cat Cargo.toml"],
+                )
+                .expect_executor_output("batched cat Cargo.toml failed"),
+        );
+        let batched_false = deployment.commander(
+            &binaries,
+            &priv_key,
+            &["run", "--json", "-b", "1", "--fail-fast", "*", "false"],
+        );
+        assert_eq!(1, batched_false.exit_code);
+        assert_executor_error(batched_false.expect_executor_output("batched false failed"));
+    }
+
+    #[test]
+    fn binaries_keys_test() {
+        let Some(binaries) = Binaries::from_env() else {
+            return;
+        };
+        // valid keys
+        let (regular_key, mut authorized_keys) = generate_base64_encoded_keys("regular");
+        let (admin_key, mut admin_authorized_keys) = generate_base64_encoded_keys("admin");
+        // unknown or unauthorized keys
+        let (unauthorized_regular_key, _) = generate_base64_encoded_keys("regular");
+        let (unauthorized_unknown_key, _) = generate_base64_encoded_keys("unknown");
+        let (unauthorized_admin_key, _) = generate_base64_encoded_keys("admin");
+        let (ultimate_key, ultimate_authorized_key) = generate_base64_encoded_keys("ultimate");
+        let (not_in_executor_key, not_in_executor_authorized_key) =
+            generate_base64_encoded_keys("not_in_executor");
+        let (new_key, new_key_authorized_key) = generate_base64_encoded_keys("new_key");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        authorized_keys.insert(
+            "ultimate".into(),
+            ultimate_authorized_key["ultimate"].clone(),
+        );
+        admin_authorized_keys.insert(
+            "ultimate".into(),
+            ultimate_authorized_key["ultimate"].clone(),
+        );
+        let executor_authorized_keys = authorized_keys.clone();
+        authorized_keys.insert(
+            "not_in_executor".into(),
+            not_in_executor_authorized_key["not_in_executor"].clone(),
+        );
+
+        let deployment = Deployment::start(
+            &binaries,
+            authorized_keys,
+            admin_authorized_keys,
+            executor_authorized_keys,
+            &executor_private_key,
+        );
+        approve_executor(&binaries, &deployment, &admin_key);
+
+        let run = |key: &ED25519Key| {
+            deployment.commander(&binaries, key, &["run", "--json", "*", "cat Cargo.toml"])
+        };
+        let list_executors = |key: &ED25519Key| {
+            deployment.commander(
+                &binaries,
+                key,
+                &["admin", "-o", "json", "list-connected-executors"],
+            )
+        };
+
+        // =============  Regular command forwarded to executors
+        assert_success_of_one_executor(
+            run(&regular_key).expect_executor_output("cat Cargo.toml failed"),
+        );
+        let rejected = run(&not_in_executor_key);
+        assert_eq!(6, rejected.exit_code);
+        assert_executor_rejected(rejected.expect_executor_output("cat Cargo.toml failed"));
+        for key in [
+            &unauthorized_regular_key,
+            &unauthorized_unknown_key,
+            &admin_key,
+        ] {
+            assert!(
+                run(key).executor_output().is_none(),
+                "Execution with unauth key must fail"
+            );
+        }
+
+        // =============  ADMIN
+        assert_listed_executors(
+            list_executors(&admin_key).expect_admin_success("admin command with admin key"),
+            &["exec"],
+        );
+        deployment
+            .commander(
+                &binaries,
+                &admin_key,
+                &[
+                    "admin",
+                    "-o",
+                    "json",
+                    "list-connected-executors",
+                    "env:prod and",
+                ],
+            )
+            .assert_admin_error(AdminErrorCode::InvalidQuery);
+        list_executors(&regular_key).assert_admin_error(AdminErrorCode::PermissionDenied);
+        for key in [
+            &unauthorized_regular_key,
+            &unauthorized_admin_key,
+            &unauthorized_unknown_key,
+        ] {
+            assert_ne!(0, list_executors(key).exit_code, "unauthorized admin key");
+        }
+
+        // the ultimate key can do both regular cmd && admin cmd
+        list_executors(&ultimate_key).expect_admin_success("admin command with ultimate key");
+        assert_success_of_one_executor(
+            run(&ultimate_key).expect_executor_output("cat Cargo.toml failed"),
+        );
+
+        // ============= Dynamic key registration
+        assert!(run(&new_key).executor_output().is_none());
+        let authorize = |key: &ED25519Key| {
+            deployment.commander(
+                &binaries,
+                key,
+                &[
+                    "keys",
+                    "--json",
+                    "*",
+                    "authorize",
+                    "new_key",
+                    &new_key_authorized_key["new_key"],
+                ],
+            )
+        };
+        assert!(
+            authorize(&regular_key).executor_output().is_none(),
+            "authorize new_key must fail with a non admin key"
+        );
+        assert_success_of_one_executor(
+            authorize(&ultimate_key).expect_executor_output("authorize new_key with ultimate key"),
+        );
+        // let the executor reconnect with the new keyset
+        std::thread::sleep(Duration::from_secs(1));
+        assert_success_of_one_executor(
+            run(&new_key).expect_executor_output("Execution with new_key key must not fail"),
+        );
+
+        let revoke = |key: &ED25519Key| {
+            deployment.commander(
+                &binaries,
+                key,
+                &["keys", "--json", "*", "revoke", "new_key"],
+            )
+        };
+        assert!(revoke(&regular_key).executor_output().is_none());
+        assert_success_of_one_executor(
+            revoke(&ultimate_key).expect_executor_output("revoke new_key"),
+        );
+        std::thread::sleep(Duration::from_secs(1));
+        assert_executor_rejected(
+            run(&new_key).expect_executor_output("new_key is accepted by the taskserver only"),
+        );
+    }
+}
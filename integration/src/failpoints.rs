@@ -0,0 +1,177 @@
+//! Commander & executor behaviour against a misbehaving taskserver (`failpoints` feature)
+use crate::test_utils::{
+    approve_key_executor_cmd, assert_admin_error, assert_success_of_one_executor, commander_config,
+    executor_config, loop_executor_main, run_cmd_opt, taskserver_config,
+};
+use crate::tests::init_logger;
+use commander::{commander_main, AdminCommandOuputMode, CommanderSyntheticOutput, ExecutorState};
+use funtonic::config::ED25519Key;
+use funtonic::crypto::keygen::generate_base64_encoded_keys;
+use funtonic::tokio;
+use funtonic::tonic::{Code, Status};
+use grpc_service::grpc_protocol::AdminErrorCode;
+use std::time::{Duration, Instant};
+use taskserver::taskserver_main;
+use tempfile::{tempdir, TempDir};
+
+fn set_failpoint_cmd(name: &str, config: &str) -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Admin {
+            output_mode: AdminCommandOuputMode::Json,
+            command: commander::AdminCommand::SetFailpoint {
+                name: name.to_string(),
+                config: config.to_string(),
+            },
+        },
+    }
+}
+
+/// Start a taskserver & an approved executor, returns the commander key
+async fn start(port: u16) -> (ED25519Key, TempDir) {
+    init_logger();
+
+    let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+    let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+    let datadir = tempdir().unwrap();
+    tokio::spawn(taskserver_main(taskserver_config(
+        port,
+        false,
+        authorized_keys.clone(),
+        authorized_keys.clone(),
+        &datadir,
+    )));
+    tokio::spawn(loop_executor_main(
+        executor_config(port, false, authorized_keys),
+        executor_private_key,
+    ));
+
+    std::thread::sleep(Duration::from_secs(2));
+    commander_main(
+        approve_key_executor_cmd(),
+        commander_config(port, false, priv_key.clone()),
+    )
+    .await
+    .expect("Did not approve executor key");
+    std::thread::sleep(Duration::from_secs(2));
+    (priv_key, datadir)
+}
+
+async fn set_failpoint(port: u16, key: &ED25519Key, name: &str, config: &str) {
+    commander_main(
+        set_failpoint_cmd(name, config),
+        commander_config(port, false, key.clone()),
+    )
+    .await
+    .unwrap_or_else(|e| panic!("Unable to set failpoint {}: {}", name, e));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn launch_task_internal_test() {
+    let (key, _datadir) = start(54015).await;
+
+    assert_admin_error(
+        commander_main(
+            set_failpoint_cmd("unknown", "1"),
+            commander_config(54015, false, key.clone()),
+        )
+        .await
+        .expect_err("Unknown failpoint accepted"),
+        AdminErrorCode::InvalidRequest,
+    );
+
+    set_failpoint(54015, &key, "launch_task_internal", "1").await;
+    let error = commander_main(
+        run_cmd_opt("*", "echo hello"),
+        commander_config(54015, false, key.clone()),
+    )
+    .await
+    .expect_err("launch_task failure not reported");
+    assert_eq!(
+        Code::Internal,
+        error
+            .downcast_ref::<Status>()
+            .expect("Not a grpc status")
+            .code()
+    );
+
+    // the failure is transient: launching the command again succeeds
+    assert_success_of_one_executor(
+        commander_main(
+            run_cmd_opt("*", "echo hello"),
+            commander_config(54015, false, key),
+        )
+        .await
+        .expect("echo hello failed"),
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn drop_executor_channel_test() {
+    let (key, _datadir) = start(54016).await;
+
+    set_failpoint(54016, &key, "drop_executor_channel", "1").await;
+    match commander_main(
+        run_cmd_opt("*", "echo hello"),
+        commander_config(54016, false, key.clone()),
+    )
+    .await
+    .expect("echo hello failed")
+    {
+        CommanderSyntheticOutput::Executor { states, .. } => assert_eq!(
+            1,
+            states
+                .get(&ExecutorState::Disconnected)
+                .expect("Executor must be disconnected")
+                .len()
+        ),
+        other => panic!("Not an executor result: {:?}", other),
+    }
+
+    // the executor reconnects by itself
+    std::thread::sleep(Duration::from_secs(3));
+    assert_success_of_one_executor(
+        commander_main(
+            run_cmd_opt("*", "echo hello"),
+            commander_config(54016, false, key),
+        )
+        .await
+        .expect("echo hello failed"),
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delay_task_execution_test() {
+    let (key, _datadir) = start(54017).await;
+
+    // ping, the 2 lines sent in one batch, completion
+    set_failpoint(54017, &key, "delay_task_execution", "300").await;
+    let started = Instant::now();
+    match commander_main(
+        run_cmd_opt("*", "echo hello; echo world"),
+        commander_config(54017, false, key.clone()),
+    )
+    .await
+    .expect("echo failed")
+    {
+        CommanderSyntheticOutput::Executor { states, .. } => {
+            // every result has been delayed, none has been lost
+            assert!(started.elapsed() >= Duration::from_millis(900));
+            assert_eq!(1, states[&ExecutorState::Success].len());
+        }
+        other => panic!("Not an executor result: {:?}", other),
+    }
+
+    set_failpoint(54017, &key, "delay_task_execution", "off").await;
+    let started = Instant::now();
+    assert_success_of_one_executor(
+        commander_main(
+            run_cmd_opt("*", "echo hello"),
+            commander_config(54017, false, key),
+        )
+        .await
+        .expect("echo hello failed"),
+    );
+    assert!(started.elapsed() < Duration::from_millis(1200));
+}
@@ -1,25 +1,71 @@
 #[cfg(test)]
+mod binaries;
+#[cfg(all(test, feature = "failpoints"))]
+mod failpoints;
+#[cfg(test)]
 mod test_utils;
 
 #[cfg(test)]
 mod tests {
     use crate::test_utils::{
-        admin_cmd, approve_key_executor_cmd, assert_executor_error, assert_success_of_one_executor,
-        authorize_key_cmd_opt, commander_config, executor_config, list_executors_keys_cmd,
-        loop_executor_main, revoke_key_cmd_opt, run_cmd_opt, taskserver_config,
+        admin_add_authorized_key_cmd, admin_cmd, admin_doctor_cmd, admin_drop_executor_cmd,
+        admin_list_authorized_keys_cmd, admin_list_connected_executors_cmd,
+        admin_list_running_tasks_cmd, admin_remove_authorized_key_cmd, admin_set_tag_cmd,
+        admin_verify_task_cmd, admin_whoami_cmd, approve_key_cmd, approve_key_executor_cmd,
+        assert_admin_error, assert_executor_error, assert_executor_rejected, assert_exit_code,
+        assert_listed_executors, assert_success_of_one_executor, authorize_key_cmd_opt,
+        commander_config, counting_proxy, dry_run_cmd_opt, executor_config, force_approve_key_cmd,
+        http_get, launch_request, launch_request_with_stdin, list_executors_keys_cmd,
+        listed_executor_duplicates, listed_executor_field, listed_executor_overridden,
+        loop_executor_main, ping_cmd_opt, revoke_key_cmd_opt, revoke_key_executor_cmd,
+        rotate_key_cmd_opt, run_batched_cmd_opt, run_become_cmd_opt, run_cmd_opt,
+        run_grouped_cmd_opt, run_json_cmd_opt, run_local_json_cmd_opt, run_script_cmd_opt,
+        single_executor_json_summary, taskserver_config,
+    };
+    use commander::cmd::{do_handle_cmd, CommandOptions, Interrupts};
+    use commander::{commander_main, CheckStatus, CommanderSyntheticOutput, ExecutorState};
+    use executor::{
+        executor_main_with_reload, executor_main_with_signals, ExecutorExit, ExecutorTasks,
+    };
+    use funtonic::config::{ED25519Key, TlsConfig};
+    use funtonic::crypto::keygen::{
+        generate_base64_encoded_keys, generate_base64_encoded_keys_from_seed,
+    };
+    use funtonic::crypto::signed_payload::{delegate, encode_and_sign};
+    use funtonic::data_encoding;
+    use funtonic::prost::Message;
+    use funtonic::task_server::task_ids::{RandomTaskIds, SequentialTaskIds};
+    use funtonic::task_server::{
+        AccessLogEntry, AdminAuthorizedKeyJsonResponse, AdminListExecutorKeysJsonResponse,
+        AdminRevokedExecutorKeyJsonResponse, AdminRunningTaskJsonResponse,
+        AdminVerifyTaskJsonResponse, AdminWhoAmIJsonResponse, Outcome,
     };
-    use commander::commander_main;
-    use funtonic::crypto::keygen::generate_base64_encoded_keys;
     use funtonic::tokio;
+    use funtonic::tonic::transport::Channel;
+    use funtonic::tonic::Code;
+    use grpc_service::grpc_protocol::admin_request::RequestType;
+    use grpc_service::grpc_protocol::commander_service_client::CommanderServiceClient;
+    use grpc_service::grpc_protocol::executor_service_client::ExecutorServiceClient;
+    use grpc_service::grpc_protocol::launch_task_response::TaskResponse;
+    use grpc_service::grpc_protocol::task_execution_result::ExecutionResult;
+    use grpc_service::grpc_protocol::{
+        AdminErrorCode, AdminRequest, Empty, RegisterExecutorRequest,
+    };
     use log::LevelFilter;
+    use std::collections::BTreeMap;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::atomic::Ordering;
     use std::sync::Once;
     use std::time::Duration;
-    use taskserver::taskserver_main;
+    use std::time::Instant;
+    use taskserver::{
+        taskserver_main, taskserver_main_with_shutdown, taskserver_main_with_task_ids,
+    };
     use tempfile::tempdir;
 
     static INIT_LOGGER: Once = Once::new();
 
-    fn init_logger() {
+    pub(crate) fn init_logger() {
         INIT_LOGGER.call_once(|| env_logger::builder().filter_level(LevelFilter::Info).init())
     }
 
@@ -60,6 +106,176 @@ mod tests {
         );
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dry_run_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54059,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        tokio::spawn(loop_executor_main(
+            executor_config(54059, false, authorized_keys),
+            executor_private_key,
+        ));
+
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54059, false, priv_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        // nothing is executed, the matching executor is listed
+        match commander_main(
+            dry_run_cmd_opt("*", "false"),
+            commander_config(54059, false, priv_key),
+        )
+        .await
+        .expect("dry run failed")
+        {
+            CommanderSyntheticOutput::DryRun(client_ids) => assert_eq!(vec!["exec"], client_ids),
+            other => panic!("Not a dry run result: {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn batched_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54060,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        tokio::spawn(loop_executor_main(
+            executor_config(54060, false, authorized_keys),
+            executor_private_key,
+        ));
+
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54060, false, priv_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        assert_success_of_one_executor(
+            commander_main(
+                run_batched_cmd_opt("*", "cat Cargo.toml", 1),
+                commander_config(54060, false, priv_key.clone()),
+            )
+            .await
+            .expect("batched cat Cargo.toml failed"),
+        );
+        assert_executor_error(
+            commander_main(
+                run_batched_cmd_opt("*", "false", 1),
+                commander_config(54060, false, priv_key),
+            )
+            .await
+            .expect("batched false failed"),
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exit_code_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54061,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        tokio::spawn(loop_executor_main(
+            executor_config(54061, false, authorized_keys),
+            executor_private_key,
+        ));
+
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54061, false, priv_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        // no executor matched the query
+        assert_exit_code(
+            commander_main(
+                run_cmd_opt("role:nothing", "true"),
+                commander_config(54061, false, priv_key),
+            )
+            .await
+            .expect("unmatched query failed"),
+            4,
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn signal_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54063,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        tokio::spawn(loop_executor_main(
+            executor_config(54063, false, authorized_keys),
+            executor_private_key,
+        ));
+
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54063, false, priv_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        // killed by a signal
+        assert_executor_error(
+            commander_main(
+                run_cmd_opt("*", "kill -9 $$"),
+                commander_config(54063, false, priv_key),
+            )
+            .await
+            .expect("kill -9 failed"),
+        );
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn tls_test() {
         init_logger();
@@ -135,222 +351,2745 @@ mod tests {
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn keys_test() {
+    async fn peer_identity_test() {
         init_logger();
-        // valid keys
-        let (regular_key, mut authorized_keys) = generate_base64_encoded_keys("regular");
-        let (admin_key, mut admin_authorized_keys) = generate_base64_encoded_keys("admin");
-        // unknown or unauthorized keys
-        let (unauthorized_regular_key, _) = generate_base64_encoded_keys("regular");
-        let (unauthorized_unknown_key, _) = generate_base64_encoded_keys("unknown");
-        let (unauthorized_admin_key, _) = generate_base64_encoded_keys("admin");
-
-        // register an "ultimate" key both in normal & admin authorized key stores
-        let (ultimate_key, ultimate_authorired_key) = generate_base64_encoded_keys("ultimate");
-
-        // register an authorized key on the task server which is not in executor
-        let (not_in_executor_key, not_in_executor_authorized_key) =
-            generate_base64_encoded_keys("not_in_executor");
-
-        // a new key that will be dynamically registered in executor
-        let (new_key, new_key_authorized_key) = generate_base64_encoded_keys("new_key");
 
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
         let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
 
-        authorized_keys.insert(
-            "ultimate".into(),
-            ultimate_authorired_key.get("ultimate").unwrap().clone(),
-        );
-        admin_authorized_keys.insert(
-            "ultimate".into(),
-            ultimate_authorired_key.get("ultimate").unwrap().clone(),
-        );
-        let executor_authorized_keys = authorized_keys.clone();
-        authorized_keys.insert(
-            "not_in_executor".into(),
-            not_in_executor_authorized_key
-                .get("not_in_executor")
-                .unwrap()
-                .clone(),
-        );
-
         let datadir = tempdir().unwrap();
-        let taskserver_config = taskserver_config(
-            54012,
-            false,
+        let mut taskserver_config = taskserver_config(
+            54062,
+            true,
+            authorized_keys.clone(),
             authorized_keys.clone(),
-            admin_authorized_keys,
             &datadir,
         );
+        taskserver_config.verify_peer_identity = true;
         tokio::spawn(taskserver_main(taskserver_config));
-        let executor_config = executor_config(54012, false, executor_authorized_keys);
+
+        // the certificate names the client_id of the executor
+        let mut executor_config = executor_config(54062, true, authorized_keys);
+        executor_config.tls = Some(TlsConfig {
+            ca_cert: "tls/funtonic-ca.pem".to_string(),
+            key: "tls/exec-key.pem".to_string(),
+            cert: "tls/exec.pem".to_string(),
+            server_domain: Some("test.funtonic.io".into()),
+            expiry_warning_days: None,
+        });
         tokio::spawn(loop_executor_main(executor_config, executor_private_key));
 
         std::thread::sleep(Duration::from_secs(2));
-        // =============  Regular command forwarded to executors
-
         commander_main(
             approve_key_executor_cmd(),
-            commander_config(54012, false, admin_key.clone()),
+            commander_config(54062, true, priv_key.clone()),
         )
         .await
         .expect("Did not approve executor key");
         std::thread::sleep(Duration::from_secs(2));
-
-        // executing a command with regular key must succeed
         assert_success_of_one_executor(
             commander_main(
                 run_cmd_opt("*", "cat Cargo.toml"),
-                commander_config(54012, false, regular_key.clone()),
+                commander_config(54062, true, priv_key),
             )
             .await
             .expect("cat Cargo.toml failed"),
         );
 
-        // executing a command with a regular key not registered in executor must fail
-        assert_executor_error(
-            commander_main(
-                run_cmd_opt("*", "cat Cargo.toml"),
-                commander_config(54012, false, not_in_executor_key.clone()),
-            )
+        // a valid client certificate of another executor
+        let impostor_tls = TlsConfig {
+            ca_cert: "tls/funtonic-ca.pem".to_string(),
+            key: "tls/impostor-key.pem".to_string(),
+            cert: "tls/impostor.pem".to_string(),
+            server_domain: Some("test.funtonic.io".into()),
+            expiry_warning_days: None,
+        };
+        let channel = Channel::from_static("http://127.0.0.1:54062")
+            .tls_config(impostor_tls.get_client_config().unwrap())
+            .unwrap()
+            .connect()
             .await
-            .expect("cat Cargo.toml failed"),
+            .unwrap();
+        let status = ExecutorServiceClient::new(channel)
+            .get_tasks(RegisterExecutorRequest {
+                client_id: "exec".to_string(),
+                ..Default::default()
+            })
+            .await
+            .expect_err("An executor certificate must name the client_id");
+        assert_eq!(Code::PermissionDenied, status.code());
+        assert!(
+            status.message().contains("impostor"),
+            "{}",
+            status.message()
         );
+    }
 
-        // executing a command with unauthorized keys must fail
-        commander_main(
-            run_cmd_opt("*", "cat Cargo.toml"),
-            commander_config(54012, false, unauthorized_regular_key.clone()),
-        )
-        .await
-        .expect_err("Execution with unauth key must fail");
-        commander_main(
-            run_cmd_opt("*", "cat Cargo.toml"),
-            commander_config(54012, false, unauthorized_unknown_key.clone()),
-        )
-        .await
-        .expect_err("Execution with unauth key must fail");
-        // even with admin key, it must fail: the admin key is only registered on admin ops
-        commander_main(
-            run_cmd_opt("*", "cat Cargo.toml"),
-            commander_config(54012, false, admin_key.clone()),
-        )
-        .await
-        .expect_err("Execution with unauth key must fail");
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tags_refresh_test() {
+        init_logger();
 
-        // =============  ADMIN
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
 
-        commander_main(admin_cmd(), commander_config(54012, false, admin_key))
-            .await
-            .expect("This must not fail (admin command with admin key ;))");
+        let datadir = tempdir().unwrap();
+        let grains_file = datadir.path().join("grains");
+        std::fs::write(&grains_file, "role: blue\n").unwrap();
+
+        tokio::spawn(taskserver_main(taskserver_config(
+            54013,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        let mut executor_config = executor_config(54013, false, authorized_keys);
+        executor_config.tag_refresh_interval_secs = Some(1);
+        executor_config.grains_file = Some(grains_file.clone());
+        tokio::spawn(loop_executor_main(executor_config, executor_private_key));
 
+        std::thread::sleep(Duration::from_secs(2));
         commander_main(
-            admin_cmd(),
-            commander_config(54012, false, regular_key.clone()),
+            approve_key_executor_cmd(),
+            commander_config(54013, false, priv_key.clone()),
         )
         .await
-        .expect_err("Non admin keys are not authorized");
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
 
-        commander_main(
-            admin_cmd(),
-            commander_config(54012, false, unauthorized_regular_key),
+        let listing = commander_main(
+            admin_list_connected_executors_cmd("role:blue"),
+            commander_config(54013, false, priv_key.clone()),
         )
         .await
-        .expect_err("Invalid non admin keys are not authorized");
+        .unwrap();
+        let connected_at = listed_executor_field(&listing, "exec", "connected_at");
+        chrono::DateTime::parse_from_rfc3339(&connected_at).expect("connected_at is not rfc3339");
+        chrono::DateTime::parse_from_rfc3339(&listed_executor_field(
+            &listing,
+            "exec",
+            "started_at",
+        ))
+        .expect("started_at is not rfc3339");
+        assert_listed_executors(listing, &["exec"]);
 
-        commander_main(
-            admin_cmd(),
-            commander_config(54012, false, unauthorized_admin_key),
+        // the new tags are picked up without reconnecting the executor
+        std::fs::write(&grains_file, "role: green\n").unwrap();
+        std::thread::sleep(Duration::from_secs(3));
+        assert_listed_executors(
+            commander_main(
+                admin_list_connected_executors_cmd("role:blue"),
+                commander_config(54013, false, priv_key.clone()),
+            )
+            .await
+            .unwrap(),
+            &[],
+        );
+        let listing = commander_main(
+            admin_list_connected_executors_cmd("role:green"),
+            commander_config(54013, false, priv_key.clone()),
         )
         .await
-        .expect_err("Invalid admin keys are not authorized");
+        .unwrap();
+        // refreshing tags is not a new connection
+        assert_eq!(
+            connected_at,
+            listed_executor_field(&listing, "exec", "connected_at")
+        );
+        assert!(!listed_executor_overridden(&listing, "exec"));
+        assert_listed_executors(listing, &["exec"]);
 
+        // overridden tags survive tag refreshes...
+        let overridden = commander_main(
+            admin_set_tag_cmd("role:green", "role", Some("red")),
+            commander_config(54013, false, priv_key.clone()),
+        )
+        .await
+        .unwrap();
+        match overridden {
+            CommanderSyntheticOutput::Admin(json) => assert_eq!(r#"["exec"]"#, json),
+            other => panic!("Not an admin result: {:?}", other),
+        }
         commander_main(
-            admin_cmd(),
-            commander_config(54012, false, unauthorized_unknown_key),
+            admin_set_tag_cmd("exec", "os_info", None),
+            commander_config(54013, false, priv_key.clone()),
         )
         .await
-        .expect_err("unknown keys are not authorized");
+        .unwrap();
+        std::thread::sleep(Duration::from_secs(3));
+        let assert_overridden = |listing: CommanderSyntheticOutput| {
+            assert!(listed_executor_overridden(&listing, "exec"));
+            assert_listed_executors(listing, &["exec"]);
+        };
+        for query in ["role:red", "!os_info:*"] {
+            assert_overridden(
+                commander_main(
+                    admin_list_connected_executors_cmd(query),
+                    commander_config(54013, false, priv_key.clone()),
+                )
+                .await
+                .unwrap(),
+            );
+        }
 
-        // check the ultimate key can do both regular cmd && admin cmd
+        // ... but are forgotten along with the dropped executor
         commander_main(
-            admin_cmd(),
-            commander_config(54012, false, ultimate_key.clone()),
+            admin_drop_executor_cmd("exec"),
+            commander_config(54013, false, priv_key.clone()),
         )
         .await
-        .expect("This must not fail (admin command with admin key ;))");
-        commander_main(
-            run_cmd_opt("*", "cat Cargo.toml"),
-            commander_config(54012, false, ultimate_key.clone()),
+        .unwrap();
+        std::thread::sleep(Duration::from_secs(3));
+        let listing = commander_main(
+            admin_list_connected_executors_cmd("role:green"),
+            commander_config(54013, false, priv_key),
         )
         .await
-        .expect("cat Cargo.toml failed");
+        .unwrap();
+        assert_ne!(
+            connected_at,
+            listed_executor_field(&listing, "exec", "connected_at")
+        );
+        assert!(!listed_executor_overridden(&listing, "exec"));
+        assert_listed_executors(listing, &["exec"]);
+    }
 
-        // ============= Dynamic key registration
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reload_test() {
+        init_logger();
 
-        // before
-        commander_main(
-            run_cmd_opt("*", "cat Cargo.toml"),
-            commander_config(54012, false, new_key.clone()),
-        )
-        .await
-        .expect_err("Execution with new_key key must fail");
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54014,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        let (reload_sender, reload_receiver) = tokio::sync::oneshot::channel::<()>();
+        let executor = tokio::spawn(executor_main_with_reload(
+            executor_config(54014, false, authorized_keys),
+            executor_private_key,
+            ExecutorTasks::new(),
+            async {
+                let _ = reload_receiver.await;
+            },
+        ));
+
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54014, false, priv_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            reload_sender.send(()).unwrap();
+        });
+        // sent while the reload waits for the running task: answered right away
+        let during_reload = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            commander_main(
+                run_cmd_opt("*", "echo reloading"),
+                commander_config(54014, false, priv_key.clone()),
+            )
+            .await
+        });
+        let running = commander_main(
+            run_cmd_opt("*", "sleep 2"),
+            commander_config(54014, false, priv_key.clone()),
+        );
+        let (running, during_reload) = tokio::join!(running, during_reload);
+        // the running task is not interrupted by the reload
+        assert_success_of_one_executor(running.expect("sleep 2 failed"));
+        assert_executor_rejected(
+            during_reload
+                .expect("task sent during the reload was not answered")
+                .expect("rejected by the executor only"),
+        );
+
+        match tokio::time::timeout(Duration::from_secs(5), executor)
+            .await
+            .expect("executor did not reload")
+            .unwrap()
+            .unwrap()
+        {
+            ExecutorExit::Reload => {}
+            other => panic!("Unexpected executor exit: {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn monitoring_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54038,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        let mut config = executor_config(54038, false, authorized_keys);
+        config.monitoring_bind_address = Some("127.0.0.1:54039".to_string());
+        tokio::spawn(loop_executor_main(config, executor_private_key));
+
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54038, false, priv_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        assert_eq!(
+            (200, "connected\n".to_string()),
+            http_get(54039, "/healthz").await
+        );
+        let (status, metrics) = http_get(54039, "/metrics").await;
+        assert_eq!(200, status);
+        assert!(metrics.contains("\nfuntonic_executor_connected 1\n"));
+        assert!(metrics.contains("\nfuntonic_executor_tasks_executed_total 0\n"));
+        assert!(metrics.contains("\nfuntonic_executor_last_task_timestamp_seconds 0\n"));
+
+        assert_success_of_one_executor(
+            commander_main(
+                run_cmd_opt("*", "echo monitored"),
+                commander_config(54038, false, priv_key),
+            )
+            .await
+            .expect("echo failed"),
+        );
+        let (_, metrics) = http_get(54039, "/metrics").await;
+        assert!(metrics.contains("\nfuntonic_executor_tasks_executed_total 1\n"));
+        assert!(metrics.contains("\nfuntonic_executor_tasks_running 0\n"));
+        assert!(!metrics.contains("\nfuntonic_executor_last_task_timestamp_seconds 0\n"));
+        assert_eq!(404, http_get(54039, "/").await.0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drain_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54037,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        let spawn_executor = |drain_timeout_secs| {
+            let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+            let mut config = executor_config(54037, false, authorized_keys.clone());
+            config.drain_timeout_secs = Some(drain_timeout_secs);
+            let executor = tokio::spawn(executor_main_with_signals(
+                config,
+                executor_private_key.clone(),
+                ExecutorTasks::new(),
+                futures::future::pending(),
+                async {
+                    let _ = shutdown_receiver.await;
+                },
+            ));
+            (executor, shutdown_sender)
+        };
+        // shutdown while the task is running
+        let shutdown_later = |shutdown_sender: tokio::sync::oneshot::Sender<()>| {
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                shutdown_sender.send(()).unwrap();
+            })
+        };
+
+        // the running task finishes before the drain timeout
+        let (executor, shutdown_sender) = spawn_executor(10);
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54037, false, priv_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+        shutdown_later(shutdown_sender);
+        assert_success_of_one_executor(
+            commander_main(
+                run_cmd_opt("*", "sleep 2"),
+                commander_config(54037, false, priv_key.clone()),
+            )
+            .await
+            .expect("sleep 2 failed"),
+        );
+        match tokio::time::timeout(Duration::from_secs(5), executor)
+            .await
+            .expect("executor did not shut down")
+            .unwrap()
+            .unwrap()
+        {
+            ExecutorExit::Shutdown => {}
+            other => panic!("Unexpected executor exit: {:?}", other),
+        }
+
+        // the running task is aborted after the drain timeout
+        let (executor, shutdown_sender) = spawn_executor(1);
+        std::thread::sleep(Duration::from_secs(2));
+        shutdown_later(shutdown_sender);
+        let started = Instant::now();
+        assert_executor_error(
+            commander_main(
+                run_cmd_opt("*", "sleep 10"),
+                commander_config(54037, false, priv_key),
+            )
+            .await
+            .expect("sleep 10 failed"),
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        match tokio::time::timeout(Duration::from_secs(5), executor)
+            .await
+            .expect("executor did not shut down")
+            .unwrap()
+            .unwrap()
+        {
+            ExecutorExit::Shutdown => {}
+            other => panic!("Unexpected executor exit: {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shutdown_while_reconnecting_test() {
+        init_logger();
+
+        let (_, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+        // no taskserver listens: the executor keeps backing off
+        let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+        let executor = tokio::spawn(executor_main_with_signals(
+            executor_config(54058, false, authorized_keys),
+            executor_private_key,
+            ExecutorTasks::new(),
+            futures::future::pending(),
+            async {
+                let _ = shutdown_receiver.await;
+            },
+        ));
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        shutdown_sender.send(()).unwrap();
+        match tokio::time::timeout(Duration::from_secs(1), executor)
+            .await
+            .expect("executor did not shut down")
+            .unwrap()
+            .unwrap()
+        {
+            ExecutorExit::Shutdown => {}
+            other => panic!("Unexpected executor exit: {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn keys_test() {
+        init_logger();
+        // valid keys
+        let (regular_key, mut authorized_keys) = generate_base64_encoded_keys("regular");
+        let (admin_key, mut admin_authorized_keys) = generate_base64_encoded_keys("admin");
+        // unknown or unauthorized keys
+        let (unauthorized_regular_key, _) = generate_base64_encoded_keys("regular");
+        let (unauthorized_unknown_key, _) = generate_base64_encoded_keys("unknown");
+        let (unauthorized_admin_key, _) = generate_base64_encoded_keys("admin");
+
+        // register an "ultimate" key both in normal & admin authorized key stores
+        let (ultimate_key, ultimate_authorired_key) = generate_base64_encoded_keys("ultimate");
+
+        // register an authorized key on the task server which is not in executor
+        let (not_in_executor_key, not_in_executor_authorized_key) =
+            generate_base64_encoded_keys("not_in_executor");
+
+        // a new key that will be dynamically registered in executor
+        let (new_key, new_key_authorized_key) = generate_base64_encoded_keys("new_key");
+
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        authorized_keys.insert(
+            "ultimate".into(),
+            ultimate_authorired_key.get("ultimate").unwrap().clone(),
+        );
+        admin_authorized_keys.insert(
+            "ultimate".into(),
+            ultimate_authorired_key.get("ultimate").unwrap().clone(),
+        );
+        let executor_authorized_keys = authorized_keys.clone();
+        authorized_keys.insert(
+            "not_in_executor".into(),
+            not_in_executor_authorized_key
+                .get("not_in_executor")
+                .unwrap()
+                .clone(),
+        );
+
+        let datadir = tempdir().unwrap();
+        let taskserver_config = taskserver_config(
+            54012,
+            false,
+            authorized_keys.clone(),
+            admin_authorized_keys,
+            &datadir,
+        );
+        tokio::spawn(taskserver_main(taskserver_config));
+        let executor_config = executor_config(54012, false, executor_authorized_keys.clone());
+        tokio::spawn(loop_executor_main(executor_config, executor_private_key));
+
+        std::thread::sleep(Duration::from_secs(2));
+        // =============  Regular command forwarded to executors
+
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54012, false, admin_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        // executing a command with regular key must succeed
+        assert_success_of_one_executor(
+            commander_main(
+                run_cmd_opt("*", "cat Cargo.toml"),
+                commander_config(54012, false, regular_key.clone()),
+            )
+            .await
+            .expect("cat Cargo.toml failed"),
+        );
+
+        // executing a command with a regular key not registered in executor must fail
+        assert_executor_rejected(
+            commander_main(
+                run_cmd_opt("*", "cat Cargo.toml"),
+                commander_config(54012, false, not_in_executor_key.clone()),
+            )
+            .await
+            .expect("cat Cargo.toml failed"),
+        );
+
+        // executing a command with unauthorized keys must fail
+        commander_main(
+            run_cmd_opt("*", "cat Cargo.toml"),
+            commander_config(54012, false, unauthorized_regular_key.clone()),
+        )
+        .await
+        .expect_err("Execution with unauth key must fail");
+        commander_main(
+            run_cmd_opt("*", "cat Cargo.toml"),
+            commander_config(54012, false, unauthorized_unknown_key.clone()),
+        )
+        .await
+        .expect_err("Execution with unauth key must fail");
+        // even with admin key, it must fail: the admin key is only registered on admin ops
+        commander_main(
+            run_cmd_opt("*", "cat Cargo.toml"),
+            commander_config(54012, false, admin_key.clone()),
+        )
+        .await
+        .expect_err("Execution with unauth key must fail");
+
+        // =============  ADMIN
+
+        commander_main(
+            admin_cmd(),
+            commander_config(54012, false, admin_key.clone()),
+        )
+        .await
+        .expect("This must not fail (admin command with admin key ;))");
+
+        let invalid_query = commander_main(
+            admin_list_connected_executors_cmd("env:prod and"),
+            commander_config(54012, false, admin_key),
+        )
+        .await
+        .expect_err("Invalid queries must be rejected");
+        assert_admin_error(invalid_query, AdminErrorCode::InvalidQuery);
+
+        let not_admin = commander_main(
+            admin_cmd(),
+            commander_config(54012, false, regular_key.clone()),
+        )
+        .await
+        .expect_err("Non admin keys are not authorized");
+        assert_admin_error(not_admin, AdminErrorCode::PermissionDenied);
+
+        commander_main(
+            admin_cmd(),
+            commander_config(54012, false, unauthorized_regular_key),
+        )
+        .await
+        .expect_err("Invalid non admin keys are not authorized");
+
+        commander_main(
+            admin_cmd(),
+            commander_config(54012, false, unauthorized_admin_key),
+        )
+        .await
+        .expect_err("Invalid admin keys are not authorized");
+
+        commander_main(
+            admin_cmd(),
+            commander_config(54012, false, unauthorized_unknown_key),
+        )
+        .await
+        .expect_err("unknown keys are not authorized");
+
+        // check the ultimate key can do both regular cmd && admin cmd
+        commander_main(
+            admin_cmd(),
+            commander_config(54012, false, ultimate_key.clone()),
+        )
+        .await
+        .expect("This must not fail (admin command with admin key ;))");
+        commander_main(
+            run_cmd_opt("*", "cat Cargo.toml"),
+            commander_config(54012, false, ultimate_key.clone()),
+        )
+        .await
+        .expect("cat Cargo.toml failed");
+
+        // ============= Dynamic key registration
+
+        // before
+        commander_main(
+            run_cmd_opt("*", "cat Cargo.toml"),
+            commander_config(54012, false, new_key.clone()),
+        )
+        .await
+        .expect_err("Execution with new_key key must fail");
 
         // authorize new_key
         commander_main(
-            authorize_key_cmd_opt(
-                "*",
-                "new_key",
-                new_key_authorized_key.get("new_key").unwrap(),
+            authorize_key_cmd_opt(
+                "*",
+                "new_key",
+                new_key_authorized_key.get("new_key").unwrap(),
+            ),
+            commander_config(54012, false, regular_key.clone()),
+        )
+        .await
+        .expect_err(
+            "authorize new_key must failed (done with regular key that is not an admin key)",
+        );
+
+        commander_main(
+            authorize_key_cmd_opt(
+                "*",
+                "new_key",
+                new_key_authorized_key.get("new_key").unwrap(),
+            ),
+            commander_config(54012, false, ultimate_key.clone()),
+        )
+        .await
+        .expect("authorize new_key with ultimate admin key!");
+
+        // let the executor reconnect with the new keyset
+        std::thread::sleep(Duration::from_secs(1));
+        commander_main(
+            run_cmd_opt("*", "cat Cargo.toml"),
+            commander_config(54012, false, new_key.clone()),
+        )
+            .await
+            .expect("Execution with new_key key must not fail: it has been registed in executor which then reported in in the taskserver");
+
+        // revoke new_key
+        commander_main(
+            revoke_key_cmd_opt("*", "new_key"),
+            commander_config(54012, false, regular_key.clone()),
+        )
+        .await
+        .expect_err("revoke new_key with non admin key");
+        commander_main(
+            revoke_key_cmd_opt("*", "new_key"),
+            commander_config(54012, false, ultimate_key.clone()),
+        )
+        .await
+        .expect("revoke new_key");
+        std::thread::sleep(Duration::from_secs(1));
+        assert_executor_rejected(
+            commander_main(
+                run_cmd_opt("*", "cat Cargo.toml"),
+                commander_config(54012, false, new_key.clone()),
+            )
+            .await
+            .expect("Execution with new_key is accepted by the task server but rejected by the executor"),
+        );
+
+        // ============= Executor with its own admin keys
+
+        // admin on the taskserver but not on the executor
+        let (second_ultimate_key, second_ultimate_authorized_key) =
+            generate_base64_encoded_keys("second_ultimate");
+        // admin on both
+        let (executor_admin_key, executor_admin_authorized_key) =
+            generate_base64_encoded_keys("executor_admin");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+        let mut executor_authorized_keys = executor_authorized_keys;
+        executor_authorized_keys.extend(second_ultimate_authorized_key.clone());
+        executor_authorized_keys.extend(executor_admin_authorized_key.clone());
+        let mut taskserver_admin_keys = second_ultimate_authorized_key;
+        taskserver_admin_keys.extend(executor_admin_authorized_key.clone());
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(crate::test_utils::taskserver_config(
+            54027,
+            false,
+            executor_authorized_keys.clone(),
+            taskserver_admin_keys,
+            &datadir,
+        )));
+        let mut executor_config =
+            crate::test_utils::executor_config(54027, false, executor_authorized_keys);
+        executor_config.admin_authorized_keys = Some(executor_admin_authorized_key);
+        tokio::spawn(loop_executor_main(executor_config, executor_private_key));
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54027, false, executor_admin_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        assert_executor_rejected(
+            commander_main(
+                authorize_key_cmd_opt(
+                    "*",
+                    "new_key",
+                    new_key_authorized_key.get("new_key").unwrap(),
+                ),
+                commander_config(54027, false, second_ultimate_key),
+            )
+            .await
+            .expect("Accepted by the task server but rejected by the executor"),
+        );
+        assert_success_of_one_executor(
+            commander_main(
+                authorize_key_cmd_opt(
+                    "*",
+                    "new_key",
+                    new_key_authorized_key.get("new_key").unwrap(),
+                ),
+                commander_config(54027, false, executor_admin_key),
+            )
+            .await
+            .expect("authorize new_key with the executor admin key"),
+        );
+        std::thread::sleep(Duration::from_secs(1));
+        assert_success_of_one_executor(
+            commander_main(
+                run_cmd_opt("*", "cat Cargo.toml"),
+                commander_config(54027, false, new_key),
+            )
+            .await
+            .expect("cat Cargo.toml failed"),
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancel_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54018,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        tokio::spawn(loop_executor_main(
+            executor_config(54018, false, authorized_keys),
+            executor_private_key,
+        ));
+
+        std::thread::sleep(Duration::from_secs(2));
+        let config = commander_config(54018, false, priv_key.clone());
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54018, false, priv_key),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        let client = CommanderServiceClient::connect(config.server_url.clone())
+            .await
+            .unwrap();
+        let options = CommandOptions {
+            no_std_process_return: true,
+            ..Default::default()
+        };
+
+        // first interrupt: the task is killed, the executor reported as cancelled
+        let (interrupt, interrupts) = Interrupts::channel();
+        let started = Instant::now();
+        let (result, _) = tokio::join!(
+            do_handle_cmd(
+                client.clone(),
+                &config,
+                launch_request("*", "sleep 30", &config.ed25519_key),
+                options.clone(),
+                interrupts,
+            ),
+            async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                interrupt.send(()).unwrap();
+            }
+        );
+        match result.expect("cancelled command failed") {
+            CommanderSyntheticOutput::Executor { states, .. } => {
+                assert_eq!(1, states[&ExecutorState::Cancelled].len());
+            }
+            other => panic!("Not an executor result: {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // second interrupt: force quit
+        let (interrupt, interrupts) = Interrupts::channel();
+        let (result, _) = tokio::join!(
+            do_handle_cmd(
+                client.clone(),
+                &config,
+                launch_request("*", "sleep 30", &config.ed25519_key),
+                options.clone(),
+                interrupts,
+            ),
+            async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                interrupt.send(()).unwrap();
+                interrupt.send(()).unwrap();
+            }
+        );
+        assert!(result.is_err());
+
+        // the executor is still usable
+        assert_success_of_one_executor(
+            do_handle_cmd(
+                client,
+                &config,
+                launch_request("*", "echo hello", &config.ed25519_key),
+                options,
+                Interrupts::channel().1,
+            )
+            .await
+            .expect("echo hello failed"),
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn executor_disconnection_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54042,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        // the executor runs on its own runtime, killed while running a task
+        let (kill, killed) = std::sync::mpsc::channel::<()>();
+        let config = executor_config(54042, false, authorized_keys);
+        let executor = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.spawn(loop_executor_main(config, executor_private_key));
+            let _ = killed.recv();
+            runtime.shutdown_background();
+        });
+
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54042, false, priv_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        let started = Instant::now();
+        let (result, _) = tokio::join!(
+            commander_main(
+                run_cmd_opt("*", "sleep 30"),
+                commander_config(54042, false, priv_key),
+            ),
+            async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                kill.send(()).unwrap();
+            }
+        );
+        match result.expect("sleep 30 failed") {
+            CommanderSyntheticOutput::Executor { states, .. } => {
+                assert_eq!(1, states[&ExecutorState::Disconnected].len());
+            }
+            other => panic!("Not an executor result: {:?}", other),
+        }
+        // the commander does not wait for the task to end
+        assert!(started.elapsed() < Duration::from_secs(10));
+        executor.join().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "load test, run with --ignored"]
+    async fn backpressure_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        let mut server_config = taskserver_config(
+            54043,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        );
+        server_config.stream_buffer_size = Some(16);
+        tokio::spawn(taskserver_main(server_config));
+        let mut config = executor_config(54043, false, authorized_keys);
+        config.monitoring_bind_address = Some("127.0.0.1:54044".to_string());
+        config.result_buffer_messages = Some(16);
+        // small batches: the whole output cannot fit in the transport buffers
+        config.output_batch_bytes = Some(16);
+        tokio::spawn(loop_executor_main(config, executor_private_key));
+
+        std::thread::sleep(Duration::from_secs(2));
+        let config = commander_config(54043, false, priv_key.clone());
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54043, false, priv_key),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        let mut client = CommanderServiceClient::connect(config.server_url.clone())
+            .await
+            .unwrap();
+        let mut responses = client
+            .launch_task(launch_request(
+                "*",
+                "yes | head -n 1000000",
+                &config.ed25519_key,
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut lines = 0;
+        let mut return_code = None;
+        while let Some(response) = responses.message().await.unwrap() {
+            if let Some(TaskResponse::TaskExecutionResult(result)) = response.task_response {
+                let outputs = match result.execution_result {
+                    Some(ExecutionResult::TaskOutput(_)) => 1,
+                    Some(ExecutionResult::TaskOutputBatch(batch)) => batch.outputs.len(),
+                    Some(ExecutionResult::TaskCompleted(completed)) => {
+                        return_code = Some(completed.return_code);
+                        0
+                    }
+                    _ => 0,
+                };
+                if outputs > 0 {
+                    lines += outputs;
+                    if lines == outputs {
+                        // stalled commander: the output waits on the executor instead of
+                        // being buffered by the taskserver
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        let (_, metrics) = http_get(54044, "/metrics").await;
+                        assert!(metrics.contains("\nfuntonic_executor_tasks_running 1\n"));
+                    }
+                }
+            }
+        }
+        assert_eq!(1_000_000, lines);
+        assert_eq!(Some(0), return_code);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stdin_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54040,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        tokio::spawn(loop_executor_main(
+            executor_config(54040, false, authorized_keys),
+            executor_private_key,
+        ));
+
+        std::thread::sleep(Duration::from_secs(2));
+        let config = commander_config(54040, false, priv_key.clone());
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54040, false, priv_key),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        let client = CommanderServiceClient::connect(config.server_url.clone())
+            .await
+            .unwrap();
+        let options = CommandOptions {
+            no_std_process_return: true,
+            group: true,
+            ..Default::default()
+        };
+        match do_handle_cmd(
+            client,
+            &config,
+            launch_request_with_stdin("*", "wc -c", vec![b'x'; 100_000], &config.ed25519_key),
+            options,
+            Interrupts::channel().1,
+        )
+        .await
+        .expect("wc -c failed")
+        {
+            CommanderSyntheticOutput::Executor { states, output, .. } => {
+                assert_eq!(1, states[&ExecutorState::Success].len());
+                assert_eq!("100000", output["exec"].concat().trim());
+            }
+            other => panic!("Not an executor result: {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn compression_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        let mut server_config = taskserver_config(
+            54045,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        );
+        server_config.compression = Some("gzip".to_string());
+        tokio::spawn(taskserver_main(server_config));
+        let mut executor_config = executor_config(54045, false, authorized_keys);
+        executor_config.compression = Some("gzip".to_string());
+        tokio::spawn(loop_executor_main(executor_config, executor_private_key));
+
+        let mut compressed_config = commander_config(54045, false, priv_key.clone());
+        compressed_config.compression = Some("gzip".to_string());
+        std::thread::sleep(Duration::from_secs(2));
+        let mut approve_config = commander_config(54045, false, priv_key.clone());
+        approve_config.compression = Some("gzip".to_string());
+        commander_main(approve_key_executor_cmd(), approve_config)
+            .await
+            .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        // a commander without compression is still served by the compressing taskserver
+        let mut outputs = vec![];
+        for config in [compressed_config, commander_config(54045, false, priv_key)] {
+            match commander_main(run_grouped_cmd_opt("*", "cat src/lib.rs"), config)
+                .await
+                .expect("cat src/lib.rs failed")
+            {
+                CommanderSyntheticOutput::Executor { states, output, .. } => {
+                    assert_eq!(1, states[&ExecutorState::Success].len());
+                    outputs.push(output["exec"].join("\n"));
+                }
+                other => panic!("Not an executor result: {:?}", other),
+            }
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        let expected = std::fs::read_to_string("src/lib.rs").unwrap();
+        assert_eq!(
+            expected.lines().collect::<Vec<_>>(),
+            outputs[0].lines().collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn duration_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54046,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        tokio::spawn(loop_executor_main(
+            executor_config(54046, false, authorized_keys),
+            executor_private_key,
+        ));
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54046, false, priv_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        match commander_main(
+            run_grouped_cmd_opt("*", "sleep 1"),
+            commander_config(54046, false, priv_key),
+        )
+        .await
+        .expect("sleep 1 failed")
+        {
+            CommanderSyntheticOutput::Executor {
+                states,
+                durations_ms,
+                ..
+            } => {
+                assert_eq!(1, states[&ExecutorState::Success].len());
+                assert!(durations_ms["exec"] >= 1000, "{:?}", durations_ms);
+            }
+            other => panic!("Not an executor result: {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn access_log_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (unknown_key, _) = generate_base64_encoded_keys("unknown");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        let access_log = datadir.path().join("access.log");
+        let mut server_config = taskserver_config(
+            54047,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        );
+        server_config.access_log = Some(access_log.to_string_lossy().to_string());
+        tokio::spawn(taskserver_main(server_config));
+        tokio::spawn(loop_executor_main(
+            executor_config(54047, false, authorized_keys),
+            executor_private_key,
+        ));
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54047, false, priv_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        assert_success_of_one_executor(
+            commander_main(
+                run_cmd_opt("*", "true"),
+                commander_config(54047, false, priv_key),
+            )
+            .await
+            .expect("true failed"),
+        );
+        commander_main(
+            run_cmd_opt("*", "true"),
+            commander_config(54047, false, unknown_key),
+        )
+        .await
+        .expect_err("Execution with an unknown key must fail");
+        std::thread::sleep(Duration::from_secs(1));
+
+        let entries: Vec<AccessLogEntry> = std::fs::read_to_string(&access_log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let approval = entries
+            .iter()
+            .find(|entry| entry.request_type.as_deref() == Some("ApproveExecutorKey"))
+            .expect("approval not logged");
+        assert_eq!("admin", approval.rpc);
+        assert_eq!("tests", approval.key_id);
+        assert_eq!(Outcome::Accepted, approval.outcome);
+
+        let launched: Vec<_> = entries
+            .iter()
+            .filter(|entry| entry.rpc == "launch_task")
+            .collect();
+        assert_eq!(2, launched.len(), "{:?}", launched);
+        assert_eq!("tests", launched[0].key_id);
+        assert_eq!(Some("*".to_string()), launched[0].predicate);
+        assert_eq!(Outcome::Accepted, launched[0].outcome);
+        assert_eq!("unknown", launched[1].key_id);
+        assert_eq!(Outcome::Rejected, launched[1].outcome);
+        assert_eq!(
+            Some("Key unknown does not exists".to_string()),
+            launched[1].reason
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn delegation_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (ci_key, ci_public_keys) = generate_base64_encoded_keys("ci");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54048,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        let mut executor_config = executor_config(54048, false, authorized_keys);
+        executor_config
+            .tags
+            .insert("env".to_string(), "staging".into());
+        tokio::spawn(loop_executor_main(executor_config, executor_private_key));
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54048, false, priv_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        let ci_config = |allowed_predicate: &str, ttl: u64| {
+            let certificate = delegate(
+                &priv_key,
+                "ci",
+                data_encoding::BASE64
+                    .decode(ci_public_keys["ci"].as_bytes())
+                    .unwrap(),
+                allowed_predicate,
+                Duration::from_secs(ttl),
+            )
+            .unwrap();
+            let mut config = commander_config(54048, false, ci_key.clone());
+            config.delegation = Some(data_encoding::BASE64.encode(&certificate.encode_to_vec()));
+            config
+        };
+
+        assert_success_of_one_executor(
+            commander_main(
+                run_cmd_opt("env:staging", "true"),
+                ci_config("env:staging", 3600),
+            )
+            .await
+            .expect("delegated run failed"),
+        );
+        // the query matches executors the delegation does not allow
+        commander_main(run_cmd_opt("*", "true"), ci_config("env:prod", 3600))
+            .await
+            .expect_err("Executors not allowed by the delegation must be refused");
+        // without its delegation, the key is unknown
+        commander_main(
+            run_cmd_opt("env:staging", "true"),
+            commander_config(54048, false, ci_key.clone()),
+        )
+        .await
+        .expect_err("Execution with a delegated key but no delegation must fail");
+
+        let expiring = ci_config("env:staging", 1);
+        std::thread::sleep(Duration::from_secs(3));
+        commander_main(run_cmd_opt("env:staging", "true"), expiring)
+            .await
+            .expect_err("Execution with an expired delegation must fail");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn key_scopes_test() {
+        init_logger();
+
+        let (priv_key, mut authorized_keys) = generate_base64_encoded_keys("tests");
+        let (ci_key, ci_public_keys) = generate_base64_encoded_keys("ci");
+        authorized_keys.insert("ci".to_string(), ci_public_keys["ci"].clone());
+
+        let datadir = tempdir().unwrap();
+        let mut server_config = taskserver_config(
+            54049,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        );
+        server_config
+            .authorized_keys
+            .get_mut("ci")
+            .unwrap()
+            .allowed_queries = Some(vec!["env:staging".to_string()]);
+        tokio::spawn(taskserver_main(server_config));
+        for env in ["staging", "prod"] {
+            let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+            let mut executor_config = executor_config(54049, false, authorized_keys.clone());
+            executor_config.client_id = env.to_string();
+            executor_config.tags.insert("env".to_string(), env.into());
+            tokio::spawn(loop_executor_main(executor_config, executor_private_key));
+        }
+        std::thread::sleep(Duration::from_secs(2));
+        for env in ["staging", "prod"] {
+            commander_main(
+                approve_key_cmd(env),
+                commander_config(54049, false, priv_key.clone()),
+            )
+            .await
+            .expect("Did not approve executor key");
+        }
+        std::thread::sleep(Duration::from_secs(2));
+
+        assert_success_of_one_executor(
+            commander_main(
+                run_cmd_opt("env:staging", "true"),
+                commander_config(54049, false, ci_key.clone()),
+            )
+            .await
+            .expect("scoped run failed"),
+        );
+        commander_main(
+            run_cmd_opt("env:prod", "true"),
+            commander_config(54049, false, ci_key.clone()),
+        )
+        .await
+        .expect_err("Executors out of the key scope must be refused");
+        // the whole request is refused, even the allowed executors did not run anything
+        commander_main(
+            run_cmd_opt("*", "true"),
+            commander_config(54049, false, ci_key.clone()),
+        )
+        .await
+        .expect_err("Queries matching executors out of the key scope must be refused");
+        // unscoped keys may target any executor
+        commander_main(
+            run_cmd_opt("env:prod", "true"),
+            commander_config(54049, false, priv_key.clone()),
+        )
+        .await
+        .expect("unscoped run failed");
+
+        let json = match commander_main(
+            admin_list_authorized_keys_cmd(),
+            commander_config(54049, false, priv_key.clone()),
+        )
+        .await
+        {
+            Ok(CommanderSyntheticOutput::Admin(json)) => json,
+            other => panic!("Not an admin result: {:?}", other),
+        };
+        let keys: BTreeMap<String, AdminAuthorizedKeyJsonResponse> =
+            serde_json::from_str(&json).unwrap();
+        assert!(matches!(
+            keys["tests"],
+            AdminAuthorizedKeyJsonResponse::Key(_)
+        ));
+        match &keys["ci"] {
+            AdminAuthorizedKeyJsonResponse::Detailed {
+                allowed_queries, ..
+            } => assert_eq!(&Some(vec!["env:staging".to_string()]), allowed_queries),
+            other => panic!("Unscoped ci key {:?}", other),
+        }
+        // the scoped key is told which executors it may target
+        match commander_main(admin_whoami_cmd(), commander_config(54049, false, ci_key)).await {
+            Ok(CommanderSyntheticOutput::Admin(json)) => assert_eq!(
+                Some(vec!["env:staging".to_string()]),
+                serde_json::from_str::<AdminWhoAmIJsonResponse>(&json)
+                    .unwrap()
+                    .allowed_queries
+            ),
+            other => panic!("Not an admin result: {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn script_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54050,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        tokio::spawn(loop_executor_main(
+            executor_config(54050, false, authorized_keys),
+            executor_private_key,
+        ));
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54050, false, priv_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        let script = datadir.path().join("maintenance.sh");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\ncat <<'EOF' | tr a-z A-Z\n'quoted' \"twice\" $HOME `date`\nEOF\n",
+        )
+        .unwrap();
+        match commander_main(
+            run_script_cmd_opt("*", &script),
+            commander_config(54050, false, priv_key.clone()),
+        )
+        .await
+        .expect("script run failed")
+        {
+            CommanderSyntheticOutput::Executor { states, output, .. } => {
+                assert_eq!(1, states[&ExecutorState::Success].len());
+                assert_eq!(
+                    "'QUOTED' \"TWICE\" $HOME `DATE`",
+                    output["exec"].concat().trim()
+                );
+            }
+            other => panic!("Not an executor result: {:?}", other),
+        }
+
+        std::fs::write(&script, b"\x7fELF\x02\x01\x01\x00").unwrap();
+        commander_main(
+            run_script_cmd_opt("*", &script),
+            commander_config(54050, false, priv_key),
+        )
+        .await
+        .expect_err("Binary files must be refused");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn become_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54051,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        // stands in for a sudo which would ask for a password
+        let password_sudo = datadir.path().join("sudo");
+        std::fs::write(
+            &password_sudo,
+            "#!/bin/sh\necho 'sudo: a password is required' >&2\nexit 1\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&password_sudo, std::fs::Permissions::from_mode(0o755)).unwrap();
+        for (client_id, become_command) in [
+            // harmless stand-in for sudo
+            ("become-env", "env".to_string()),
+            ("become-password", password_sudo.display().to_string()),
+            ("become-disabled", "".to_string()),
+        ] {
+            let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+            let mut executor_config = executor_config(54051, false, authorized_keys.clone());
+            executor_config.client_id = client_id.to_string();
+            executor_config
+                .tags
+                .insert("become".to_string(), client_id.into());
+            executor_config.become_command = Some(become_command);
+            tokio::spawn(loop_executor_main(executor_config, executor_private_key));
+        }
+        std::thread::sleep(Duration::from_secs(2));
+        for client_id in ["become-env", "become-password", "become-disabled"] {
+            commander_main(
+                approve_key_cmd(client_id),
+                commander_config(54051, false, priv_key.clone()),
+            )
+            .await
+            .expect("Did not approve executor key");
+        }
+        std::thread::sleep(Duration::from_secs(2));
+
+        match commander_main(
+            run_become_cmd_opt("become:become-env", "echo 'wrapped as is'"),
+            commander_config(54051, false, priv_key.clone()),
+        )
+        .await
+        .expect("become run failed")
+        {
+            CommanderSyntheticOutput::Executor { states, output, .. } => {
+                assert_eq!(1, states[&ExecutorState::Success].len());
+                assert_eq!("wrapped as is", output["become-env"].concat().trim());
+            }
+            other => panic!("Not an executor result: {:?}", other),
+        }
+
+        for (client_id, reason) in [
+            ("become-password", "become failed: sudo requires a password"),
+            ("become-disabled", "become is disabled on this executor"),
+        ] {
+            let result = commander_main(
+                run_become_cmd_opt(&format!("become:{}", client_id), "true"),
+                commander_config(54051, false, priv_key.clone()),
+            )
+            .await
+            .expect("become run failed");
+            match &result {
+                CommanderSyntheticOutput::Executor { rejections, .. } => {
+                    assert_eq!(Some(&reason.to_string()), rejections.get(client_id))
+                }
+                other => panic!("Not an executor result: {:?}", other),
+            }
+            assert_executor_rejected(result);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn serialize_tasks_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54041,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        let mut executor_config = executor_config(54041, false, authorized_keys);
+        executor_config.serialize_tasks = true;
+        tokio::spawn(loop_executor_main(executor_config, executor_private_key));
+
+        std::thread::sleep(Duration::from_secs(2));
+        let config = commander_config(54041, false, priv_key.clone());
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54041, false, priv_key),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        let client = CommanderServiceClient::connect(config.server_url.clone())
+            .await
+            .unwrap();
+        let options = CommandOptions {
+            no_std_process_return: true,
+            group: true,
+            ..Default::default()
+        };
+        let run = || {
+            do_handle_cmd(
+                client.clone(),
+                &config,
+                launch_request("*", "date +%s%N; sleep 1; date +%s%N", &config.ed25519_key),
+                options.clone(),
+                Interrupts::channel().1,
+            )
+        };
+        // (start, end) of the command, in nanoseconds
+        let interval =
+            |result: Result<CommanderSyntheticOutput, _>| match result.expect("sleep failed") {
+                CommanderSyntheticOutput::Executor { states, output, .. } => {
+                    assert_eq!(1, states[&ExecutorState::Success].len());
+                    let timestamps: Vec<u128> = output["exec"]
+                        .iter()
+                        .map(|line| line.trim().parse().unwrap())
+                        .collect();
+                    (timestamps[0], timestamps[1])
+                }
+                other => panic!("Not an executor result: {:?}", other),
+            };
+        let (first, second) = tokio::join!(run(), run());
+        let (first, second) = (interval(first), interval(second));
+        assert!(
+            first.1 <= second.0 || second.1 <= first.0,
+            "{:?} and {:?} overlap",
+            first,
+            second
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rotate_test() {
+        init_logger();
+
+        let (old_key, authorized_keys) = generate_base64_encoded_keys("old_key");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54019,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        tokio::spawn(loop_executor_main(
+            executor_config(54019, false, authorized_keys),
+            executor_private_key,
+        ));
+
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54019, false, old_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        commander_main(
+            rotate_key_cmd_opt("*", "old_key", &datadir.path().join("unused.yml")),
+            commander_config(54019, false, old_key.clone()),
+        )
+        .await
+        .expect_err("The new key must have its own name");
+
+        let out = datadir.path().join("new_key.yml");
+        commander_main(
+            rotate_key_cmd_opt("*", "new_key", &out),
+            commander_config(54019, false, old_key.clone()),
+        )
+        .await
+        .expect("Key rotation failed");
+        let new_key: ED25519Key = serde_yaml::from_value(
+            serde_yaml::from_str::<serde_yaml::Value>(&std::fs::read_to_string(&out).unwrap())
+                .unwrap()["ed25519_key"]
+                .clone(),
+        )
+        .unwrap();
+        assert_eq!("new_key", new_key.id);
+
+        // let the executor reconnect & report its new keyset
+        std::thread::sleep(Duration::from_secs(1));
+        assert_success_of_one_executor(
+            commander_main(
+                run_cmd_opt("*", "echo hello"),
+                commander_config(54019, false, new_key),
+            )
+            .await
+            .expect("Execution with the new key failed"),
+        );
+        // still known by the taskserver, revoked on the executor
+        assert_executor_rejected(
+            commander_main(
+                run_cmd_opt("*", "echo hello"),
+                commander_config(54019, false, old_key),
+            )
+            .await
+            .expect("Execution with the old key is rejected by the executor only"),
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn authorized_keys_admin_test() {
+        init_logger();
+
+        // seeded: the listed keys are compared to a golden json
+        let (regular_key, authorized_keys) =
+            generate_base64_encoded_keys_from_seed("tests", &[1; 32]);
+        let (admin_key, admin_authorized_keys) =
+            generate_base64_encoded_keys_from_seed("admin", &[2; 32]);
+        let (operator_key, operator_authorized_keys) =
+            generate_base64_encoded_keys_from_seed("operator", &[3; 32]);
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        let (stop_sender, stop_receiver) = tokio::sync::oneshot::channel::<()>();
+        let taskserver = tokio::spawn(taskserver_main_with_shutdown(
+            taskserver_config(
+                54020,
+                false,
+                authorized_keys.clone(),
+                admin_authorized_keys.clone(),
+                &datadir,
+            ),
+            RandomTaskIds,
+            async move {
+                let _ = stop_receiver.await;
+            },
+        ));
+        tokio::spawn(loop_executor_main(
+            executor_config(54020, false, authorized_keys.clone()),
+            executor_private_key,
+        ));
+
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54020, false, admin_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        commander_main(
+            run_cmd_opt("*", "echo hello"),
+            commander_config(54020, false, operator_key.clone()),
+        )
+        .await
+        .expect_err("Unknown key accepted by the taskserver");
+
+        assert_admin_error(
+            commander_main(
+                admin_add_authorized_key_cmd("operator", &operator_authorized_keys["operator"]),
+                commander_config(54020, false, regular_key),
+            )
+            .await
+            .expect_err("Keys can only be added with an admin key"),
+            AdminErrorCode::PermissionDenied,
+        );
+        commander_main(
+            admin_add_authorized_key_cmd("operator", &operator_authorized_keys["operator"]),
+            commander_config(54020, false, admin_key.clone()),
+        )
+        .await
+        .expect("Unable to add the operator key");
+        // accepted by the taskserver, unknown to the executor
+        assert_executor_rejected(
+            commander_main(
+                run_cmd_opt("*", "echo hello"),
+                commander_config(54020, false, operator_key.clone()),
+            )
+            .await
+            .expect("Execution with the operator key is rejected by the executor only"),
+        );
+
+        // a restarted taskserver keeps the added key
+        stop_sender.send(()).unwrap();
+        taskserver
+            .await
+            .unwrap()
+            .expect("The taskserver did not stop");
+        tokio::spawn(taskserver_main(taskserver_config(
+            54021,
+            false,
+            authorized_keys,
+            admin_authorized_keys,
+            &datadir,
+        )));
+        std::thread::sleep(Duration::from_secs(1));
+        match commander_main(
+            admin_list_authorized_keys_cmd(),
+            commander_config(54021, false, admin_key.clone()),
+        )
+        .await
+        .expect("Unable to list authorized keys")
+        {
+            CommanderSyntheticOutput::Admin(json) => assert_eq!(
+                r#"{"operator":"7UkoxijRwsbq6QM4kFmVYSlZJzpcY/k2NsFGFKyHN9E=","tests":"iojj3XQJ8ZX9UtstPLpdcspnCb8dlBIb83SIAbQPb1w="}"#,
+                json
+            ),
+            other => panic!("Not an admin result: {:?}", other),
+        }
+
+        commander_main(
+            admin_remove_authorized_key_cmd("operator"),
+            commander_config(54021, false, admin_key.clone()),
+        )
+        .await
+        .expect("Unable to remove the operator key");
+        commander_main(
+            run_cmd_opt("*", "echo hello"),
+            commander_config(54021, false, operator_key),
+        )
+        .await
+        .expect_err("Removed key accepted by the taskserver");
+        assert_admin_error(
+            commander_main(
+                admin_remove_authorized_key_cmd("operator"),
+                commander_config(54021, false, admin_key),
+            )
+            .await
+            .expect_err("Removing an unknown key must fail"),
+            AdminErrorCode::NotFound,
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn revoke_executor_key_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54022,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        tokio::spawn(loop_executor_main(
+            executor_config(54022, false, authorized_keys),
+            executor_private_key,
+        ));
+        let config = || commander_config(54022, false, priv_key.clone());
+        let executor_keys = || async {
+            match commander_main(list_executors_keys_cmd(), config())
+                .await
+                .expect("Cannot list executor keys")
+            {
+                CommanderSyntheticOutput::Admin(json) => {
+                    serde_json::from_str::<AdminListExecutorKeysJsonResponse>(&json).unwrap()
+                }
+                other => panic!("Not an admin result: {:?}", other),
+            }
+        };
+
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(approve_key_executor_cmd(), config())
+            .await
+            .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+        assert_success_of_one_executor(
+            commander_main(run_cmd_opt("*", "echo hello"), config())
+                .await
+                .expect("echo hello failed"),
+        );
+
+        match commander_main(revoke_key_executor_cmd(), config())
+            .await
+            .expect("Unable to revoke the executor key")
+        {
+            CommanderSyntheticOutput::Admin(json) => assert!(
+                serde_json::from_str::<AdminRevokedExecutorKeyJsonResponse>(&json)
+                    .unwrap()
+                    .removed_from_connected
+            ),
+            other => panic!("Not an admin result: {:?}", other),
+        }
+        // the executor reconnects with an untrusted key
+        std::thread::sleep(Duration::from_secs(2));
+        match commander_main(run_cmd_opt("*", "echo hello"), config())
+            .await
+            .expect("echo hello failed")
+        {
+            CommanderSyntheticOutput::Executor { states, .. } => assert_eq!(
+                1,
+                states
+                    .get(&ExecutorState::Disconnected)
+                    .expect("Executor must be disconnected")
+                    .len()
+            ),
+            other => panic!("Not an executor result: {:?}", other),
+        }
+        let keys = executor_keys().await;
+        assert!(!keys.trusted_executor_keys.contains_key("exec"));
+        assert!(keys.unapproved_executor_keys.contains_key("exec"));
+
+        commander_main(approve_key_executor_cmd(), config())
+            .await
+            .expect("Did not approve executor key again");
+        std::thread::sleep(Duration::from_secs(3));
+        assert_success_of_one_executor(
+            commander_main(run_cmd_opt("*", "echo hello"), config())
+                .await
+                .expect("echo hello failed"),
+        );
+        assert!(executor_keys()
+            .await
+            .trusted_executor_keys
+            .contains_key("exec"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pending_approval_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54023,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        std::thread::sleep(Duration::from_secs(1));
+
+        // the executor reaches the taskserver through a proxy counting its connections
+        let connections = counting_proxy(54024, 54023).await;
+        let mut config = executor_config(54024, false, authorized_keys);
+        config.pending_approval_retry_secs = None;
+        tokio::spawn(loop_executor_main(config, executor_private_key));
+        tokio::time::sleep(Duration::from_secs(8)).await;
+
+        match commander_main(
+            list_executors_keys_cmd(),
+            commander_config(54023, false, priv_key),
+        )
+        .await
+        .expect("Cannot list executor keys")
+        {
+            CommanderSyntheticOutput::Admin(json) => assert!(serde_json::from_str::<
+                AdminListExecutorKeysJsonResponse,
+            >(&json)
+            .unwrap()
+            .unapproved_executor_keys
+            .contains_key("exec")),
+            other => panic!("Not an admin result: {:?}", other),
+        }
+        // the executor waits for the approval instead of reconnecting every second
+        let attempts = connections.load(Ordering::SeqCst);
+        assert!(
+            (1..=2).contains(&attempts),
+            "{} connections within 8s",
+            attempts
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn whoami_test() {
+        init_logger();
+
+        let (admin_key, mut authorized_keys) = generate_base64_encoded_keys("admin");
+        let admin_authorized_keys = authorized_keys.clone();
+        let (regular_key, regular_authorized_keys) = generate_base64_encoded_keys("regular");
+        authorized_keys.extend(regular_authorized_keys);
+        let (unknown_key, _) = generate_base64_encoded_keys("unknown");
+
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54025,
+            false,
+            authorized_keys,
+            admin_authorized_keys,
+            &datadir,
+        )));
+        std::thread::sleep(Duration::from_secs(1));
+
+        let whoami = |key: ED25519Key| async move {
+            match commander_main(admin_whoami_cmd(), commander_config(54025, false, key)).await {
+                Ok(CommanderSyntheticOutput::Admin(json)) => {
+                    Ok(serde_json::from_str::<AdminWhoAmIJsonResponse>(&json).unwrap())
+                }
+                Ok(other) => panic!("Not an admin result: {:?}", other),
+                Err(e) => Err(e),
+            }
+        };
+
+        let admin = whoami(admin_key).await.expect("whoami with admin key");
+        assert_eq!("admin", admin.key_id);
+        assert!(admin.authorized);
+        assert!(admin.admin);
+        assert_eq!(
+            vec!["cmd", "resolve", "cancel", "admin", "whoami"],
+            admin.allowed
+        );
+
+        // regular keys are not rejected but only told what they can do
+        let regular = whoami(regular_key.clone())
+            .await
+            .expect("whoami with regular key");
+        assert_eq!("regular", regular.key_id);
+        assert!(regular.authorized);
+        assert!(!regular.admin);
+        assert_eq!(vec!["cmd", "resolve", "cancel", "whoami"], regular.allowed);
+        assert_eq!(None, regular.allowed_queries);
+        // ... nor allowed to run other admin requests
+        assert_admin_error(
+            commander_main(admin_cmd(), commander_config(54025, false, regular_key))
+                .await
+                .expect_err("Non admin keys are not authorized"),
+            AdminErrorCode::PermissionDenied,
+        );
+
+        assert_admin_error(
+            whoami(unknown_key)
+                .await
+                .expect_err("Unknown keys are not authorized"),
+            AdminErrorCode::PermissionDenied,
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn chunked_admin_response_test() {
+        init_logger();
+
+        let (admin_key, admin_authorized_keys) = generate_base64_encoded_keys("admin");
+        let authorized_keys: BTreeMap<_, _> = (0..50)
+            .flat_map(|i| generate_base64_encoded_keys(&format!("key-{}", i)).1)
+            .collect();
+
+        let datadir = tempdir().unwrap();
+        let mut config = taskserver_config(
+            54026,
+            false,
+            authorized_keys.clone(),
+            admin_authorized_keys,
+            &datadir,
+        );
+        // ~3KB of keys fetched by 256 bytes chunks
+        config.max_admin_response_bytes = Some(256);
+        tokio::spawn(taskserver_main(config));
+        std::thread::sleep(Duration::from_secs(1));
+
+        let json = match commander_main(
+            admin_list_authorized_keys_cmd(),
+            commander_config(54026, false, admin_key),
+        )
+        .await
+        {
+            Ok(CommanderSyntheticOutput::Admin(json)) => json,
+            other => panic!("Not an admin result: {:?}", other),
+        };
+        let keys: BTreeMap<String, AdminAuthorizedKeyJsonResponse> =
+            serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            authorized_keys,
+            keys.into_iter()
+                .map(|(key_id, key)| match key {
+                    AdminAuthorizedKeyJsonResponse::Key(key) => (key_id, key),
+                    other => panic!("Unexpected expiring or scoped key {:?}", other),
+                })
+                .collect()
+        );
+        // released once fetched
+        assert_eq!(
+            0,
+            std::fs::read_dir(datadir.path().join("admin_results"))
+                .unwrap()
+                .count()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failover_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+
+        // only the standby taskserver is up
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54029,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        let mut executor_config = executor_config(54029, false, authorized_keys);
+        executor_config.server_url = String::new();
+        executor_config.server_urls = vec![
+            "http://127.0.0.1:54028".to_string(),
+            "http://127.0.0.1:54029".to_string(),
+        ];
+        tokio::spawn(loop_executor_main(executor_config, executor_private_key));
+
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54029, false, priv_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        assert_success_of_one_executor(
+            commander_main(
+                run_cmd_opt("*", "cat Cargo.toml"),
+                commander_config(54029, false, priv_key),
+            )
+            .await
+            .expect("cat Cargo.toml failed"),
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fan_out_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        // the executors of both taskservers have the same client_id
+        let mut datadirs = vec![];
+        for port in [54030, 54031] {
+            let datadir = tempdir().unwrap();
+            tokio::spawn(taskserver_main(taskserver_config(
+                port,
+                false,
+                authorized_keys.clone(),
+                authorized_keys.clone(),
+                &datadir,
+            )));
+            datadirs.push(datadir);
+            let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+            tokio::spawn(loop_executor_main(
+                executor_config(port, false, authorized_keys.clone()),
+                executor_private_key,
+            ));
+        }
+        std::thread::sleep(Duration::from_secs(2));
+        for port in [54030, 54031] {
+            commander_main(
+                approve_key_executor_cmd(),
+                commander_config(port, false, priv_key.clone()),
+            )
+            .await
+            .expect("Did not approve executor key");
+        }
+        std::thread::sleep(Duration::from_secs(2));
+
+        let mut config = commander_config(54030, false, priv_key.clone());
+        config.servers = [
+            ("eu", "http://127.0.0.1:54030"),
+            ("us", "http://127.0.0.1:54031"),
+            // nothing listens there
+            ("asia", "http://127.0.0.1:54032"),
+        ]
+        .into_iter()
+        .map(|(name, url)| (name.to_string(), url.to_string()))
+        .collect();
+
+        match commander_main(run_cmd_opt("*", "echo hello"), config)
+            .await
+            .expect("unreachable taskservers are skipped")
+        {
+            CommanderSyntheticOutput::Executor { states, .. } => assert_eq!(
+                vec!["eu/exec", "us/exec"],
+                states[&ExecutorState::Success].iter().collect::<Vec<_>>()
             ),
-            commander_config(54012, false, regular_key.clone()),
+            other => panic!("Not an executor result: {:?}", other),
+        }
+
+        // `server_url` is not targeted when `servers` is configured
+        let mut config = commander_config(54032, false, priv_key.clone());
+        config.servers = [("eu", 54030), ("us", 54031)]
+            .into_iter()
+            .map(|(name, port)| (name.to_string(), format!("http://127.0.0.1:{}", port)))
+            .collect();
+        match commander_main(run_cmd_opt("*", "echo hello"), config)
+            .await
+            .expect("server_url is not connected to")
+        {
+            CommanderSyntheticOutput::Executor { states, .. } => {
+                assert_eq!(2, states[&ExecutorState::Success].len())
+            }
+            other => panic!("Not an executor result: {:?}", other),
+        }
+
+        let mut config = commander_config(54030, false, priv_key);
+        config.servers = [("eu", 54030), ("asia", 54032)]
+            .into_iter()
+            .map(|(name, port)| (name.to_string(), format!("http://127.0.0.1:{}", port)))
+            .collect();
+        let mut require_all = run_cmd_opt("*", "echo hello");
+        if let commander::Command::Cmd(commander::cmd::Cmd::Run { options, .. }) =
+            &mut require_all.command
+        {
+            options.require_all = true;
+        }
+        commander_main(require_all, config)
+            .await
+            .expect_err("all the taskservers are required");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ping_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54033,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        tokio::spawn(loop_executor_main(
+            executor_config(54033, false, authorized_keys),
+            executor_private_key,
+        ));
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54033, false, priv_key.clone()),
         )
         .await
-        .expect_err(
-            "authorize new_key must failed (done with regular key that is not an admin key)",
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        match commander_main(
+            ping_cmd_opt("*", true),
+            commander_config(54033, false, priv_key.clone()),
+        )
+        .await
+        .expect("ping failed")
+        {
+            CommanderSyntheticOutput::Latency(report) => {
+                let host = &report.hosts["exec"];
+                assert!(host.round_trip_ms.is_some());
+                assert!(host.server_dispatch_ms.is_some());
+                assert!(host.executor_start_ms.is_some());
+                let breakdown = report.breakdown.expect("no breakdown with --measure");
+                assert!(breakdown.server_matching_ms.is_some());
+                assert!(report.aggregates.contains_key("round trip"));
+            }
+            other => panic!("Not a latency report: {:?}", other),
+        }
+
+        match commander_main(
+            ping_cmd_opt("*", false),
+            commander_config(54033, false, priv_key),
+        )
+        .await
+        .expect("ping failed")
+        {
+            CommanderSyntheticOutput::Latency(report) => {
+                assert!(report.breakdown.is_none());
+                assert!(report.hosts["exec"].executor_start_ms.is_none());
+                assert!(report.hosts["exec"].round_trip_ms.is_some());
+            }
+            other => panic!("Not a latency report: {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn admin_listener_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let datadir = tempdir().unwrap();
+        let mut config = taskserver_config(
+            54034,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
         );
+        config.admin_bind_address = Some("127.0.0.1:54035".to_string());
+        tokio::spawn(taskserver_main(config));
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+        tokio::spawn(loop_executor_main(
+            executor_config(54034, false, authorized_keys),
+            executor_private_key,
+        ));
+        std::thread::sleep(Duration::from_secs(2));
+
+        let mut admin_config = commander_config(54034, false, priv_key.clone());
+        admin_config.admin_server_url = Some("http://127.0.0.1:54035".to_string());
 
+        // neither the admin service nor the deprecated alias are served on the main port
         commander_main(
-            authorize_key_cmd_opt(
-                "*",
-                "new_key",
-                new_key_authorized_key.get("new_key").unwrap(),
+            approve_key_executor_cmd(),
+            commander_config(54034, false, priv_key.clone()),
+        )
+        .await
+        .expect_err("admin requests are only served on the admin listener");
+        let mut client = CommanderServiceClient::connect("http://127.0.0.1:54034")
+            .await
+            .unwrap();
+        let status = client
+            .admin(
+                encode_and_sign(
+                    AdminRequest {
+                        request_type: Some(RequestType::WhoAmI(Empty {})),
+                    },
+                    &priv_key,
+                    Duration::from_secs(60),
+                )
+                .unwrap(),
+            )
+            .await
+            .expect_err("the deprecated alias is disabled");
+        assert_eq!(Code::PermissionDenied, status.code());
+
+        commander_main(approve_key_executor_cmd(), admin_config)
+            .await
+            .expect("Did not approve executor key on the admin listener");
+        std::thread::sleep(Duration::from_secs(2));
+
+        assert_success_of_one_executor(
+            commander_main(
+                run_cmd_opt("*", "echo hello"),
+                commander_config(54034, false, priv_key),
+            )
+            .await
+            .expect("commands are still served on the main port"),
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sequential_task_ids_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main_with_task_ids(
+            taskserver_config(
+                54036,
+                false,
+                authorized_keys.clone(),
+                authorized_keys.clone(),
+                &datadir,
             ),
-            commander_config(54012, false, ultimate_key.clone()),
+            SequentialTaskIds::default(),
+        ));
+        tokio::spawn(loop_executor_main(
+            executor_config(54036, false, authorized_keys),
+            executor_private_key,
+        ));
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54036, false, priv_key.clone()),
         )
         .await
-        .expect("authorize new_key with ultimate admin key!");
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
 
-        // let the executor reconnect with the new keyset
-        std::thread::sleep(Duration::from_secs(1));
+        let (run, running_tasks) = tokio::join!(
+            commander_main(
+                run_cmd_opt("*", "sleep 2"),
+                commander_config(54036, false, priv_key.clone()),
+            ),
+            async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                commander_main(
+                    admin_list_running_tasks_cmd(),
+                    commander_config(54036, false, priv_key),
+                )
+                .await
+            }
+        );
+        match running_tasks.expect("Unable to list running tasks") {
+            CommanderSyntheticOutput::Admin(json) => {
+                let tasks: BTreeMap<String, AdminRunningTaskJsonResponse> =
+                    serde_json::from_str(&json).unwrap();
+                assert_eq!(vec!["task-1"], tasks.keys().collect::<Vec<_>>());
+                assert_eq!("exec", tasks["task-1"].client_id);
+            }
+            other => panic!("Not an admin result: {:?}", other),
+        }
+        assert_success_of_one_executor(run.expect("sleep failed"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn late_results_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+        let datadir = tempdir().unwrap();
+        let retaining_config = || {
+            let mut config = taskserver_config(
+                54052,
+                false,
+                authorized_keys.clone(),
+                authorized_keys.clone(),
+                &datadir,
+            );
+            config.retain_signatures = true;
+            config
+        };
+        // the first taskserver runs on its own runtime, killed while running a task
+        let (kill, killed) = std::sync::mpsc::channel::<()>();
+        let config = retaining_config();
+        let taskserver = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.spawn(taskserver_main_with_task_ids(
+                config,
+                SequentialTaskIds::default(),
+            ));
+            let _ = killed.recv();
+            runtime.shutdown_background();
+        });
+        tokio::spawn(loop_executor_main(
+            executor_config(54052, false, authorized_keys.clone()),
+            executor_private_key,
+        ));
+        std::thread::sleep(Duration::from_secs(2));
         commander_main(
-            run_cmd_opt("*", "cat Cargo.toml"),
-            commander_config(54012, false, new_key.clone()),
+            approve_key_executor_cmd(),
+            commander_config(54052, false, priv_key.clone()),
         )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        // the commander loses the task along with the taskserver
+        let _ = tokio::join!(
+            commander_main(
+                run_cmd_opt("*", "sleep 3; echo done"),
+                commander_config(54052, false, priv_key.clone()),
+            ),
+            async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                kill.send(()).unwrap();
+            }
+        );
+        taskserver.join().unwrap();
+        tokio::spawn(taskserver_main(retaining_config()));
+
+        // the executor sends the results again once the task is finished
+        let mut report = None;
+        for _ in 0..20 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            if let Ok(CommanderSyntheticOutput::Admin(json)) = commander_main(
+                admin_verify_task_cmd("task-1"),
+                commander_config(54052, false, priv_key.clone()),
+            )
             .await
-            .expect("Execution with new_key key must not fail: it has been registed in executor which then reported in in the taskserver");
+            {
+                report = Some(serde_json::from_str::<AdminVerifyTaskJsonResponse>(&json).unwrap());
+                break;
+            }
+        }
+        let report = report.expect("The task results did not land in the history");
+        assert_eq!("exec", report.client_id);
+        assert!(report.verified);
+        assert_eq!(1, report.payloads.len());
+        let execution_result = report.payloads[0].execution_result.as_deref().unwrap();
+        assert!(
+            execution_result.contains("TaskCompleted"),
+            "{}",
+            execution_result
+        );
+    }
 
-        // revoke new_key
+    #[tokio::test(flavor = "multi_thread")]
+    async fn duplicate_client_id_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54053,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        // the same executor accidentally started twice
+        let instance = |name: &str| {
+            let mut config = executor_config(54053, false, authorized_keys.clone());
+            config.cli_tags = vec![("instance".to_string(), name.to_string())];
+            config
+        };
+        tokio::spawn(loop_executor_main(
+            instance("first"),
+            executor_private_key.clone(),
+        ));
+        std::thread::sleep(Duration::from_secs(2));
         commander_main(
-            revoke_key_cmd_opt("*", "new_key"),
-            commander_config(54012, false, regular_key.clone()),
+            approve_key_executor_cmd(),
+            commander_config(54053, false, priv_key.clone()),
         )
         .await
-        .expect_err("revoke new_key with non admin key");
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        tokio::spawn(loop_executor_main(instance("second"), executor_private_key));
+        std::thread::sleep(Duration::from_secs(3));
+        let listing = commander_main(
+            admin_list_connected_executors_cmd("instance:first"),
+            commander_config(54053, false, priv_key.clone()),
+        )
+        .await
+        .expect("Unable to list connected executors");
+        assert!(listed_executor_duplicates(&listing, "exec") > 0);
+        assert_listed_executors(listing, &["exec"]);
+
+        // the tasks keep going to the executor connected first
+        match commander_main(
+            run_grouped_cmd_opt("*", "echo hello"),
+            commander_config(54053, false, priv_key),
+        )
+        .await
+        .expect("echo hello failed")
+        {
+            CommanderSyntheticOutput::Executor { output, .. } => {
+                assert_eq!("hello", output["exec"].concat().trim());
+            }
+            other => panic!("Not an execution result: {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn local_run_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54054,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        tokio::spawn(loop_executor_main(
+            executor_config(54054, false, authorized_keys),
+            executor_private_key,
+        ));
+        std::thread::sleep(Duration::from_secs(2));
         commander_main(
-            revoke_key_cmd_opt("*", "new_key"),
-            commander_config(54012, false, ultimate_key.clone()),
+            approve_key_executor_cmd(),
+            commander_config(54054, false, priv_key.clone()),
         )
         .await
-        .expect("revoke new_key");
-        std::thread::sleep(Duration::from_secs(1));
-        assert_executor_error(
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        let remote = commander_main(
+            run_json_cmd_opt("*", "echo hi"),
+            commander_config(54054, false, priv_key.clone()),
+        )
+        .await
+        .expect("echo hi failed");
+        // the taskserver is not needed
+        let local = commander_main(
+            run_local_json_cmd_opt("echo hi"),
+            commander_config(1, false, priv_key),
+        )
+        .await
+        .expect("local echo hi failed");
+        assert_eq!(
+            single_executor_json_summary(remote, "exec"),
+            single_executor_json_summary(local, commander::LOCAL_CLIENT_ID)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn doctor_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54055,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        tokio::spawn(loop_executor_main(
+            executor_config(54055, false, authorized_keys),
+            executor_private_key,
+        ));
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(
+            approve_key_executor_cmd(),
+            commander_config(54055, false, priv_key.clone()),
+        )
+        .await
+        .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+
+        match commander_main(
+            admin_doctor_cmd("*"),
+            commander_config(54055, false, priv_key.clone()),
+        )
+        .await
+        .expect("doctor failed")
+        {
+            CommanderSyntheticOutput::Doctor(report) => {
+                assert_eq!(0, report.count(CheckStatus::Fail), "{:?}", report);
+                assert!(report
+                    .checks
+                    .iter()
+                    .any(|check| check.check == "clock skew of exec"
+                        && check.status == CheckStatus::Pass));
+            }
+            other => panic!("Not a doctor report: {:?}", other),
+        }
+
+        // no executor matches: a warning, not a failure
+        match commander_main(
+            admin_doctor_cmd("nothing"),
+            commander_config(54055, false, priv_key),
+        )
+        .await
+        .expect("doctor failed")
+        {
+            CommanderSyntheticOutput::Doctor(report) => {
+                assert_eq!(0, report.count(CheckStatus::Fail), "{:?}", report);
+                assert!(report.count(CheckStatus::Warn) > 0, "{:?}", report);
+            }
+            other => panic!("Not a doctor report: {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn auto_approve_executor_keys_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+        let (impostor_private_key, _) = generate_base64_encoded_keys("impostor");
+        let datadir = tempdir().unwrap();
+        let mut config = taskserver_config(
+            54056,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        );
+        config.auto_approve_executor_keys = true;
+        tokio::spawn(taskserver_main(config));
+        tokio::spawn(loop_executor_main(
+            executor_config(54056, false, authorized_keys.clone()),
+            executor_private_key,
+        ));
+        std::thread::sleep(Duration::from_secs(2));
+
+        // no approval needed
+        assert_success_of_one_executor(
             commander_main(
                 run_cmd_opt("*", "cat Cargo.toml"),
-                commander_config(54012, false, new_key.clone()),
+                commander_config(54056, false, priv_key.clone()),
             )
             .await
-            .expect("Execution with new_key is accepted by the task server but rejected by the executor"),
+            .expect("cat Cargo.toml failed"),
+        );
+
+        // another key for the same client_id is not trusted on first use
+        tokio::spawn(loop_executor_main(
+            executor_config(54056, false, authorized_keys),
+            impostor_private_key,
+        ));
+        std::thread::sleep(Duration::from_secs(2));
+        match commander_main(
+            list_executors_keys_cmd(),
+            commander_config(54056, false, priv_key),
+        )
+        .await
+        .expect("Cannot list executor keys")
+        {
+            CommanderSyntheticOutput::Admin(json) => {
+                let keys =
+                    serde_json::from_str::<AdminListExecutorKeysJsonResponse>(&json).unwrap();
+                assert!(keys.trusted_executor_keys.contains_key("exec"));
+                assert!(keys.unapproved_executor_keys.contains_key("exec"));
+            }
+            other => panic!("Not an admin result: {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn key_conflict_test() {
+        init_logger();
+
+        let (priv_key, authorized_keys) = generate_base64_encoded_keys("tests");
+        let (executor_private_key, _) = generate_base64_encoded_keys("local_executor");
+        let (new_private_key, new_public_keys) = generate_base64_encoded_keys("reinstalled");
+        let datadir = tempdir().unwrap();
+        tokio::spawn(taskserver_main(taskserver_config(
+            54057,
+            false,
+            authorized_keys.clone(),
+            authorized_keys.clone(),
+            &datadir,
+        )));
+        tokio::spawn(loop_executor_main(
+            executor_config(54057, false, authorized_keys.clone()),
+            executor_private_key,
+        ));
+        let config = || commander_config(54057, false, priv_key.clone());
+        std::thread::sleep(Duration::from_secs(2));
+        commander_main(approve_key_executor_cmd(), config())
+            .await
+            .expect("Did not approve executor key");
+        std::thread::sleep(Duration::from_secs(2));
+        assert_success_of_one_executor(
+            commander_main(run_cmd_opt("*", "true"), config())
+                .await
+                .expect("true failed"),
+        );
+
+        // the same client_id with another key
+        tokio::spawn(loop_executor_main(
+            executor_config(54057, false, authorized_keys),
+            new_private_key,
+        ));
+        std::thread::sleep(Duration::from_secs(2));
+        let list_keys = || async {
+            match commander_main(list_executors_keys_cmd(), config())
+                .await
+                .expect("Cannot list executor keys")
+            {
+                CommanderSyntheticOutput::Admin(json) => {
+                    serde_json::from_str::<AdminListExecutorKeysJsonResponse>(&json).unwrap()
+                }
+                other => panic!("Not an admin result: {:?}", other),
+            }
+        };
+        let keys = list_keys().await;
+        let new_public_key = &new_public_keys["reinstalled"];
+        assert_eq!(new_public_key, &keys.conflicting_executor_keys["exec"].key);
+        assert_eq!(
+            &keys.trusted_executor_keys["exec"],
+            &keys.conflicting_executor_keys["exec"].trusted_key
+        );
+
+        // the conflicting key is not approved without --force
+        assert_admin_error(
+            commander_main(approve_key_executor_cmd(), config())
+                .await
+                .expect_err("Conflicting key approved"),
+            AdminErrorCode::InvalidRequest,
         );
+        commander_main(approve_key_cmd("*"), config())
+            .await
+            .expect("Did not approve pending keys");
+        assert!(list_keys()
+            .await
+            .unapproved_executor_keys
+            .contains_key("exec"));
+
+        commander_main(force_approve_key_cmd("exec"), config())
+            .await
+            .expect("Did not force the approval");
+        let keys = list_keys().await;
+        assert_eq!(new_public_key, &keys.trusted_executor_keys["exec"]);
+        assert!(!keys.conflicting_executor_keys.contains_key("exec"));
     }
 }
@@ -1,9 +1,26 @@
-use commander::cmd::{CommandOptions, KeyCmd};
-use commander::{AdminCommandOuputMode, CommanderSyntheticOutput, ExecutorState};
-use executor::executor_main;
-use funtonic::config::{CommanderConfig, ED25519Key, ExecutorConfig, ServerConfig, TlsConfig};
+use commander::cmd::{exit_code, BatchOptions, CommandOptions, KeyCmd, QueryOptions};
+use commander::render::DEFAULT_RENDER_BUFFER_LINES;
+use commander::{
+    AdminCommandError, AdminCommandOuputMode, CommanderSyntheticOutput, ExecutorState,
+};
+use executor::{executor_main, ExecutorExit, ExecutorTasks};
+use funtonic::capabilities::Capabilities;
+use funtonic::config::{
+    CommanderConfig, ED25519Key, ExecutorConfig, KeyProtection, ServerConfig, TlsConfig,
+};
+use funtonic::crypto::signed_payload::encode_and_sign;
+use funtonic::tokio;
+use funtonic::tonic;
+use grpc_service::grpc_protocol::execute_command::Program;
+use grpc_service::grpc_protocol::launch_task_request_payload::Task;
+use grpc_service::grpc_protocol::{
+    AdminErrorCode, ExecuteCommand, LaunchTaskRequest, LaunchTaskRequestPayload,
+};
 use std::collections::BTreeMap;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 pub fn run_cmd_opt(query: &str, command: &str) -> commander::Opt {
     commander::Opt {
@@ -14,8 +31,164 @@ pub fn run_cmd_opt(query: &str, command: &str) -> commander::Opt {
                 group: false,
                 no_progress: false,
                 no_std_process_return: true,
+                render_buffer_lines: DEFAULT_RENDER_BUFFER_LINES,
+                json: false,
+                quiet: false,
+                record: None,
+                require_all: false,
+                yes: false,
+                force: false,
+                align: false,
+                prefix_format: None,
             },
-            query: query.to_string(),
+            batch: BatchOptions::default(),
+            dry_run: false,
+            stdin: false,
+            alias: None,
+            script: None,
+            with_become: false,
+            local: false,
+            query_options: QueryOptions::default(),
+            query: Some(query.to_string()),
+            command: vec![command.into()],
+        }),
+    }
+}
+
+/// Run the script file instead of a command, grouping the output of each executor
+pub fn run_script_cmd_opt(query: &str, script: &Path) -> commander::Opt {
+    let mut opt = run_grouped_cmd_opt(query, "");
+    if let commander::Command::Cmd(commander::cmd::Cmd::Run {
+        script: run_script,
+        command,
+        ..
+    }) = &mut opt.command
+    {
+        *run_script = Some(script.to_path_buf());
+        command.clear();
+    }
+    opt
+}
+
+/// `run_grouped_cmd_opt` run through the `become_command` of the executors
+pub fn run_become_cmd_opt(query: &str, command: &str) -> commander::Opt {
+    let mut opt = run_grouped_cmd_opt(query, command);
+    if let commander::Command::Cmd(commander::cmd::Cmd::Run { with_become, .. }) = &mut opt.command
+    {
+        *with_become = true;
+    }
+    opt
+}
+
+/// `run_cmd_opt` grouping the output of each executor, returned by `commander_main`
+pub fn run_grouped_cmd_opt(query: &str, command: &str) -> commander::Opt {
+    let mut opt = run_cmd_opt(query, command);
+    if let commander::Command::Cmd(commander::cmd::Cmd::Run { options, .. }) = &mut opt.command {
+        options.group = true;
+    }
+    opt
+}
+
+/// `run_cmd_opt` printing a json summary once the command is completed
+pub fn run_json_cmd_opt(query: &str, command: &str) -> commander::Opt {
+    let mut opt = run_cmd_opt(query, command);
+    if let commander::Command::Cmd(commander::cmd::Cmd::Run { options, .. }) = &mut opt.command {
+        options.json = true;
+    }
+    opt
+}
+
+/// `run_json_cmd_opt` run on this machine by the commander, without any taskserver
+pub fn run_local_json_cmd_opt(command: &str) -> commander::Opt {
+    let mut opt = run_json_cmd_opt("", command);
+    if let commander::Command::Cmd(commander::cmd::Cmd::Run { local, query, .. }) = &mut opt.command
+    {
+        *local = true;
+        *query = None;
+    }
+    opt
+}
+
+pub fn ping_cmd_opt(query: &str, measure: bool) -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Cmd(commander::cmd::Cmd::Ping {
+            options: CommandOptions {
+                no_std_process_return: true,
+                ..Default::default()
+            },
+            measure,
+            query_options: QueryOptions::default(),
+            query: Some(query.to_string()),
+        }),
+    }
+}
+
+pub fn run_batched_cmd_opt(query: &str, command: &str, batch_size: usize) -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Cmd(commander::cmd::Cmd::Run {
+            options: CommandOptions {
+                raw: false,
+                group: false,
+                no_progress: false,
+                no_std_process_return: true,
+                render_buffer_lines: DEFAULT_RENDER_BUFFER_LINES,
+                json: false,
+                quiet: false,
+                record: None,
+                require_all: false,
+                yes: false,
+                force: false,
+                align: false,
+                prefix_format: None,
+            },
+            batch: BatchOptions {
+                batch_size: Some(batch_size),
+                batch_delay: 0,
+                fail_fast: true,
+            },
+            dry_run: false,
+            stdin: false,
+            alias: None,
+            script: None,
+            with_become: false,
+            local: false,
+            query_options: QueryOptions::default(),
+            query: Some(query.to_string()),
+            command: vec![command.into()],
+        }),
+    }
+}
+
+pub fn dry_run_cmd_opt(query: &str, command: &str) -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Cmd(commander::cmd::Cmd::Run {
+            options: CommandOptions {
+                raw: false,
+                group: false,
+                no_progress: false,
+                no_std_process_return: true,
+                render_buffer_lines: DEFAULT_RENDER_BUFFER_LINES,
+                json: false,
+                quiet: false,
+                record: None,
+                require_all: false,
+                yes: false,
+                force: false,
+                align: false,
+                prefix_format: None,
+            },
+            batch: BatchOptions::default(),
+            dry_run: true,
+            stdin: false,
+            alias: None,
+            script: None,
+            with_become: false,
+            local: false,
+            query_options: QueryOptions::default(),
+            query: Some(query.to_string()),
             command: vec![command.into()],
         }),
     }
@@ -30,12 +203,23 @@ pub fn authorize_key_cmd_opt(query: &str, key_id: &str, key: &str) -> commander:
                 group: false,
                 no_progress: false,
                 no_std_process_return: true,
+                render_buffer_lines: DEFAULT_RENDER_BUFFER_LINES,
+                json: false,
+                quiet: false,
+                record: None,
+                require_all: false,
+                yes: false,
+                force: false,
+                align: false,
+                prefix_format: None,
             },
-            query: query.to_string(),
+            query_options: QueryOptions::default(),
+            query: Some(query.to_string()),
 
             key_cmd: KeyCmd::Authorize {
                 key_id: key_id.into(),
                 public_key: key.into(),
+                expires_in: None,
             },
         }),
     }
@@ -50,8 +234,18 @@ pub fn revoke_key_cmd_opt(query: &str, key_id: &str) -> commander::Opt {
                 group: false,
                 no_progress: false,
                 no_std_process_return: true,
+                render_buffer_lines: DEFAULT_RENDER_BUFFER_LINES,
+                json: false,
+                quiet: false,
+                record: None,
+                require_all: false,
+                yes: false,
+                force: false,
+                align: false,
+                prefix_format: None,
             },
-            query: query.to_string(),
+            query_options: QueryOptions::default(),
+            query: Some(query.to_string()),
 
             key_cmd: KeyCmd::Revoke {
                 key_id: key_id.into(),
@@ -60,12 +254,161 @@ pub fn revoke_key_cmd_opt(query: &str, key_id: &str) -> commander::Opt {
     }
 }
 
+pub fn rotate_key_cmd_opt(query: &str, new_key_name: &str, out: &Path) -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Cmd(commander::cmd::Cmd::Keys {
+            options: CommandOptions {
+                no_std_process_return: true,
+                ..Default::default()
+            },
+            query_options: QueryOptions::default(),
+            query: Some(query.to_string()),
+
+            key_cmd: KeyCmd::Rotate {
+                new_key_name: new_key_name.into(),
+                revoke_old: true,
+                out: Some(out.to_path_buf()),
+            },
+        }),
+    }
+}
+
 pub fn admin_cmd() -> commander::Opt {
     commander::Opt {
         config: None,
         command: commander::Command::Admin {
             output_mode: AdminCommandOuputMode::Json,
-            command: commander::AdminCommand::ListConnectedExecutors { query: None },
+            command: commander::AdminCommand::ListConnectedExecutors {
+                query: None,
+                fields: vec![],
+                hide_fields: vec![],
+                group_by: vec![],
+                watch: None,
+            },
+        },
+    }
+}
+
+pub fn admin_list_connected_executors_cmd(query: &str) -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Admin {
+            output_mode: AdminCommandOuputMode::Json,
+            command: commander::AdminCommand::ListConnectedExecutors {
+                query: Some(query.to_string()),
+                fields: vec![],
+                hide_fields: vec![],
+                group_by: vec![],
+                watch: None,
+            },
+        },
+    }
+}
+
+pub fn admin_list_running_tasks_cmd() -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Admin {
+            output_mode: AdminCommandOuputMode::Json,
+            command: commander::AdminCommand::ListRunningTasks,
+        },
+    }
+}
+
+pub fn admin_verify_task_cmd(task_id: &str) -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Admin {
+            output_mode: AdminCommandOuputMode::Json,
+            command: commander::AdminCommand::VerifyTask {
+                task_id: task_id.to_string(),
+            },
+        },
+    }
+}
+
+pub fn admin_whoami_cmd() -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Admin {
+            output_mode: AdminCommandOuputMode::Json,
+            command: commander::AdminCommand::WhoAmI,
+        },
+    }
+}
+
+pub fn admin_doctor_cmd(query: &str) -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Admin {
+            output_mode: AdminCommandOuputMode::Json,
+            command: commander::AdminCommand::Doctor {
+                query: query.to_string(),
+                timeout: 10,
+            },
+        },
+    }
+}
+
+pub fn admin_set_tag_cmd(query: &str, path: &str, value: Option<&str>) -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Admin {
+            output_mode: AdminCommandOuputMode::Json,
+            command: commander::AdminCommand::SetTag {
+                query: query.to_string(),
+                path: path.to_string(),
+                value: value.map(str::to_string),
+                yes: true,
+            },
+        },
+    }
+}
+
+pub fn admin_add_authorized_key_cmd(key_id: &str, public_key: &str) -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Admin {
+            output_mode: AdminCommandOuputMode::Json,
+            command: commander::AdminCommand::AddAuthorizedKey {
+                key_id: key_id.to_string(),
+                public_key: public_key.to_string(),
+            },
+        },
+    }
+}
+
+pub fn admin_remove_authorized_key_cmd(key_id: &str) -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Admin {
+            output_mode: AdminCommandOuputMode::Json,
+            command: commander::AdminCommand::RemoveAuthorizedKey {
+                key_id: key_id.to_string(),
+            },
+        },
+    }
+}
+
+pub fn admin_list_authorized_keys_cmd() -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Admin {
+            output_mode: AdminCommandOuputMode::Json,
+            command: commander::AdminCommand::ListAuthorizedKeys,
+        },
+    }
+}
+
+pub fn admin_drop_executor_cmd(query: &str) -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Admin {
+            output_mode: AdminCommandOuputMode::Json,
+            command: commander::AdminCommand::DropExecutor {
+                query: query.to_string(),
+            },
         },
     }
 }
@@ -84,14 +427,35 @@ pub fn taskserver_config<P: AsRef<Path>>(
                 key: "tls/server-key.pem".to_string(),
                 cert: "tls/server.pem".to_string(),
                 server_domain: None,
+                expiry_warning_days: None,
             })
         } else {
             None
         },
         bind_address: format!("127.0.0.1:{}", port),
+        admin_bind_address: None,
         data_directory: task_server_dir.as_ref().to_string_lossy().to_string(),
-        authorized_keys,
+        authorized_keys: authorized_keys
+            .into_iter()
+            .map(|(key_id, key)| (key_id, key.into()))
+            .collect(),
         admin_authorized_keys,
+        retain_signatures: false,
+        task_history_max_entries: None,
+        allowed_clock_skew_secs: None,
+        preflight: Default::default(),
+        max_admin_response_bytes: None,
+        trusted_proxy_header: None,
+        trusted_proxy_cidrs: vec![],
+        verify_peer_identity: false,
+        allowed_commander_cns: vec![],
+        task_sink_ttl_secs: None,
+        stream_buffer_size: None,
+        compression: None,
+        access_log: None,
+        access_log_max_bytes: None,
+        allow_takeover: false,
+        auto_approve_executor_keys: false,
     }
 }
 
@@ -107,6 +471,7 @@ pub fn executor_config(
                 key: "tls/executor-key.pem".to_string(),
                 cert: "tls/executor.pem".to_string(),
                 server_domain: Some("test.funtonic.io".into()),
+                expiry_warning_days: None,
             })
         } else {
             None
@@ -114,21 +479,79 @@ pub fn executor_config(
         client_id: "exec".to_string(),
         tags: Default::default(),
         server_url: format!("http://127.0.0.1:{}", port),
+        server_urls: vec![],
+        last_server_url: None,
         authorized_keys,
+        authorized_keys_expiry: Default::default(),
+        admin_authorized_keys: None,
+        key_protection: KeyProtection::None,
+        tag_refresh_interval_secs: None,
+        grains_file: None,
+        // executors are approved right after being started
+        pending_approval_retry_secs: Some(1),
+        result_buffer_messages: None,
+        result_retry_window_secs: None,
+        result_retry_buffer_bytes: None,
+        max_output_bytes: None,
+        max_line_length: None,
+        output_batch_millis: None,
+        output_batch_bytes: None,
+        shell: None,
+        become_command: None,
+        task_log_directory: None,
+        task_log_max_files: None,
+        drain_timeout_secs: None,
+        monitoring_bind_address: None,
+        serialize_tasks: false,
+        signature_validity_secs: None,
+        compression: None,
+        cli_tags: vec![],
+        instance_id: None,
+        authorized_keys_overridden: false,
     }
 }
 
 pub fn approve_key_executor_cmd() -> commander::Opt {
+    approve_key_cmd("exec")
+}
+
+pub fn approve_key_cmd(executor: &str) -> commander::Opt {
     commander::Opt {
         config: None,
         command: commander::Command::Admin {
             output_mode: AdminCommandOuputMode::Json,
             command: commander::AdminCommand::ApproveExecutorKey {
+                executor: executor.to_string(),
+                force: false,
+            },
+        },
+    }
+}
+
+pub fn force_approve_key_cmd(executor: &str) -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Admin {
+            output_mode: AdminCommandOuputMode::Json,
+            command: commander::AdminCommand::ApproveExecutorKey {
+                executor: executor.to_string(),
+                force: true,
+            },
+        },
+    }
+}
+pub fn revoke_key_executor_cmd() -> commander::Opt {
+    commander::Opt {
+        config: None,
+        command: commander::Command::Admin {
+            output_mode: AdminCommandOuputMode::Json,
+            command: commander::AdminCommand::RevokeExecutorKey {
                 executor: "exec".to_string(),
             },
         },
     }
 }
+
 pub fn list_executors_keys_cmd() -> commander::Opt {
     commander::Opt {
         config: None,
@@ -147,20 +570,71 @@ pub fn commander_config(port: u16, with_tls: bool, ed25519_key: ED25519Key) -> C
                 key: "tls/commander-key.pem".to_string(),
                 cert: "tls/commander.pem".to_string(),
                 server_domain: Some("test.funtonic.io".into()),
+                expiry_warning_days: None,
             })
         } else {
             None
         },
         server_url: format!("http://127.0.0.1:{}", port),
+        admin_server_url: None,
+        servers: Default::default(),
         ed25519_key,
+        signature_validity_secs: None,
+        unsafe_commands: None,
+        safeguard_policies: vec![],
+        confirm_above: None,
+        saved_queries: Default::default(),
+        aliases: Default::default(),
+        max_stdin_bytes: None,
+        max_script_bytes: None,
+        compression: None,
+        delegation: None,
     }
 }
 
+/// Launch request of `command`, to be run with `commander::cmd::do_handle_cmd`
+pub fn launch_request(
+    query: &str,
+    command: &str,
+    key: &ED25519Key,
+) -> tonic::Request<LaunchTaskRequest> {
+    launch_request_with_stdin(query, command, Vec::new(), key)
+}
+
+/// [launch_request] whose command reads `stdin`
+pub fn launch_request_with_stdin(
+    query: &str,
+    command: &str,
+    stdin: Vec<u8>,
+    key: &ED25519Key,
+) -> tonic::Request<LaunchTaskRequest> {
+    tonic::Request::new(LaunchTaskRequest {
+        predicate: query.to_string(),
+        capabilities: Capabilities::local().into(),
+        payload: Some(
+            encode_and_sign(
+                LaunchTaskRequestPayload {
+                    task: Some(Task::ExecuteCommand(ExecuteCommand {
+                        program: Some(Program::Command(command.to_string())),
+                        stdin,
+                        with_become: false,
+                    })),
+                },
+                key,
+                Duration::from_secs(60),
+            )
+            .unwrap(),
+        ),
+        delegation: None,
+    })
+}
+
 pub fn assert_success_of_one_executor(res: CommanderSyntheticOutput) {
     match res {
         CommanderSyntheticOutput::Executor {
             states,
             output: _output,
+            ..
         } => assert_eq!(
             1,
             states
@@ -172,11 +646,106 @@ pub fn assert_success_of_one_executor(res: CommanderSyntheticOutput) {
     }
 }
 
+/// States, output & rejection of the json summary of a command run on a single executor, whose
+/// client_id is replaced by `executor`. The duration varies from one run to another.
+pub fn single_executor_json_summary(
+    res: CommanderSyntheticOutput,
+    client_id: &str,
+) -> serde_json::Value {
+    match res {
+        CommanderSyntheticOutput::Executor {
+            states,
+            output,
+            rejections,
+            ..
+        } => {
+            let states: BTreeMap<String, Vec<&str>> = states
+                .iter()
+                .map(|(state, client_ids)| {
+                    (
+                        format!("{:?}", state),
+                        client_ids
+                            .iter()
+                            .map(|id| if id == client_id { "executor" } else { id })
+                            .collect(),
+                    )
+                })
+                .collect();
+            serde_json::json!({
+                "states": states,
+                "output": output.get(client_id),
+                "rejection": rejections.get(client_id),
+            })
+        }
+        other => panic!("Not an executor result: {:?}", other),
+    }
+}
+
+/// Check the client_ids listed by an admin executor listing
+pub fn assert_listed_executors(res: CommanderSyntheticOutput, expected: &[&str]) {
+    match res {
+        CommanderSyntheticOutput::Admin(json) => {
+            let executors: BTreeMap<String, serde_json::Value> =
+                serde_json::from_str(&json).expect("Invalid executor listing");
+            assert_eq!(expected, executors.keys().collect::<Vec<_>>());
+        }
+        other => panic!("Not an admin result: {:?}", other),
+    }
+}
+
+/// Field of an executor in an admin executor listing
+pub fn listed_executor_field(
+    res: &CommanderSyntheticOutput,
+    client_id: &str,
+    field: &str,
+) -> String {
+    match res {
+        CommanderSyntheticOutput::Admin(json) => {
+            let executors: BTreeMap<String, serde_json::Value> =
+                serde_json::from_str(json).expect("Invalid executor listing");
+            executors[client_id][field]
+                .as_str()
+                .unwrap_or_else(|| panic!("No {} for {}", field, client_id))
+                .to_string()
+        }
+        other => panic!("Not an admin result: {:?}", other),
+    }
+}
+
+/// Whether an executor of an admin executor listing has overridden tags
+pub fn listed_executor_overridden(res: &CommanderSyntheticOutput, client_id: &str) -> bool {
+    match res {
+        CommanderSyntheticOutput::Admin(json) => {
+            let executors: BTreeMap<String, serde_json::Value> =
+                serde_json::from_str(json).expect("Invalid executor listing");
+            executors[client_id]["overridden"]
+                .as_bool()
+                .unwrap_or(false)
+        }
+        other => panic!("Not an admin result: {:?}", other),
+    }
+}
+
+/// Registrations rejected while the executor was connected
+pub fn listed_executor_duplicates(res: &CommanderSyntheticOutput, client_id: &str) -> u64 {
+    match res {
+        CommanderSyntheticOutput::Admin(json) => {
+            let executors: BTreeMap<String, serde_json::Value> =
+                serde_json::from_str(json).expect("Invalid executor listing");
+            executors[client_id]["duplicate_registrations"]
+                .as_u64()
+                .unwrap_or(0)
+        }
+        other => panic!("Not an admin result: {:?}", other),
+    }
+}
+
 pub fn assert_executor_error(res: CommanderSyntheticOutput) {
     match res {
         CommanderSyntheticOutput::Executor {
             states,
             output: _output,
+            ..
         } => assert_eq!(
             1,
             states
@@ -188,11 +757,92 @@ pub fn assert_executor_error(res: CommanderSyntheticOutput) {
     }
 }
 
+/// The executor refused the task, eg: signed by a key it does not know
+pub fn assert_executor_rejected(res: CommanderSyntheticOutput) {
+    match res {
+        CommanderSyntheticOutput::Executor {
+            states, rejections, ..
+        } => {
+            assert_eq!(
+                1,
+                states
+                    .get(&ExecutorState::Rejected)
+                    .expect("Executor must have rejected the task")
+                    .len()
+            );
+            assert_eq!(1, rejections.len(), "{:?}", rejections);
+        }
+        other => panic!("Not an executor result: {:?}", other),
+    }
+}
+
+/// Check the process exit code of a command, had it not been run with `no_std_process_return`
+pub fn assert_exit_code(res: CommanderSyntheticOutput, expected: i32) {
+    match res {
+        CommanderSyntheticOutput::Executor { states, .. } => {
+            assert_eq!(expected, exit_code(&states), "{:?}", states)
+        }
+        other => panic!("Not an executor result: {:?}", other),
+    }
+}
+
+pub fn assert_admin_error(error: Box<dyn std::error::Error>, expected_code: AdminErrorCode) {
+    let admin_error = error
+        .downcast_ref::<AdminCommandError>()
+        .expect("Not an admin error");
+    assert_eq!(expected_code, admin_error.0.code());
+}
+
 pub async fn loop_executor_main(
     mut config: ExecutorConfig,
     signing_key: ED25519Key,
 ) -> anyhow::Result<()> {
+    let tasks = ExecutorTasks::new();
     loop {
-        config = executor_main(config, signing_key.clone()).await?;
+        config = match executor_main(config, signing_key.clone(), tasks.clone()).await? {
+            ExecutorExit::Reconnect(config) => *config,
+            // no configuration file to parse again
+            ExecutorExit::Reload | ExecutorExit::Shutdown => return Ok(()),
+        };
     }
 }
+
+/// Forward the connections accepted on `port` to `target_port`, returns the count of accepted
+/// connections
+pub async fn counting_proxy(port: u16, target_port: u16) -> Arc<AtomicUsize> {
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+        .await
+        .unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((mut inbound, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                if let Ok(mut outbound) =
+                    tokio::net::TcpStream::connect(("127.0.0.1", target_port)).await
+                {
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                }
+            });
+        }
+    });
+    connections
+}
+
+/// Plain http `GET` on localhost, returns the status code & the body
+pub async fn http_get(port: u16, path: &str) -> (u16, String) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    stream
+        .write_all(format!("GET {} HTTP/1.0\r\n\r\n", path).as_bytes())
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
@@ -8,7 +8,7 @@ use nom::multi::many1;
 use nom::sequence::{separated_pair, tuple};
 use nom::{Err, IResult};
 use std::collections::HashMap;
-use std::fmt::Debug;
+use std::fmt::{Debug, Display, Formatter};
 use std::hash::Hash;
 
 use crate::parser::parse_raw;
@@ -52,6 +52,62 @@ pub enum Query<'a> {
     Not(Box<Query<'a>>),
 }
 
+/// Canonical form of the query: keywords are lower case, nested expressions are explicitly
+/// parenthesized. Parsing the output gives back the same query.
+impl<'a> Display for Query<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Query::Pattern(pattern) => {
+                let needs_quotes = pattern.is_empty()
+                    || *pattern == "*"
+                    || pattern.starts_with('!')
+                    || pattern.contains(|c: char| c.is_whitespace() || "():,&|".contains(c))
+                    || ["and", "or", "not"]
+                        .iter()
+                        .any(|keyword| pattern.eq_ignore_ascii_case(keyword));
+                if needs_quotes {
+                    write!(f, "\"{}\"", pattern)
+                } else {
+                    write!(f, "{}", pattern)
+                }
+            }
+            Query::FieldPattern(field, query) => {
+                write!(f, "{}:", field)?;
+                query.fmt_factor(f)
+            }
+            Query::Wildcard => write!(f, "*"),
+            Query::And(clauses) => Self::fmt_clauses(f, clauses, " and "),
+            Query::Or(clauses) => Self::fmt_clauses(f, clauses, " or "),
+            Query::Not(query) => {
+                write!(f, "not ")?;
+                query.fmt_factor(f)
+            }
+        }
+    }
+}
+
+impl<'a> Query<'a> {
+    fn fmt_factor(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Query::And(_) | Query::Or(_) | Query::Not(_) => write!(f, "({})", self),
+            _ => write!(f, "{}", self),
+        }
+    }
+
+    fn fmt_clauses(f: &mut Formatter<'_>, clauses: &[Query], separator: &str) -> std::fmt::Result {
+        for (i, clause) in clauses.iter().enumerate() {
+            if i > 0 {
+                write!(f, "{}", separator)?;
+            }
+            match clause {
+                Query::And(_) | Query::Or(_) => write!(f, "({})", clause)?,
+                _ => write!(f, "{}", clause)?,
+            }
+        }
+        Ok(())
+    }
+}
+
 pub trait QueryMatcher {
     fn qmatches(&self, query: &Query) -> MatchResult;
 }
@@ -319,4 +375,27 @@ mod tests {
         );
         assert_eq!(non_empty.qmatches(&parse("prod or !prod").unwrap()), Match);
     }
+
+    #[test]
+    fn test_display() {
+        for (query, canonical) in [
+            ("*", "*"),
+            ("prod", "prod"),
+            (
+                "env:prod OR env:qa && location:Paris",
+                "env:prod or (env:qa and location:Paris)",
+            ),
+            ("!(a || b), c", "not (a or b) or c"),
+            ("(a or b) and not c", "(a or b) and not c"),
+            (
+                "os:(linux or \"windows nt\")",
+                "os:(linux or \"windows nt\")",
+            ),
+            ("\"or\" and \"*\"", "\"or\" and \"*\""),
+        ] {
+            let parsed = parse(query).unwrap();
+            assert_eq!(canonical, parsed.to_string());
+            assert_eq!(parsed, parse(canonical).unwrap());
+        }
+    }
 }
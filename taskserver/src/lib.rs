@@ -1,19 +1,48 @@
 #[macro_use]
 extern crate log;
 
-use funtonic::config::ServerConfig;
-use funtonic::file_utils::mkdirs;
-use funtonic::task_server::TaskServer;
-use funtonic::tonic;
+use funtonic::config::{compression_encoding, ServerConfig};
+use funtonic::file_utils::mkdirs_private;
+use funtonic::task_server::key_scopes::KeyScopes;
+use funtonic::task_server::peer_identity::PeerIdentity;
+use funtonic::task_server::preflight::{
+    preflight, start_clock_monitor, DEFAULT_TIME_JUMP_THRESHOLD_SECS,
+};
+use funtonic::task_server::task_ids::{RandomTaskIds, TaskIdGenerator};
+use funtonic::task_server::{
+    AccessLog, TaskServer, DEFAULT_ACCESS_LOG_MAX_BYTES, DEFAULT_MAX_ADMIN_RESPONSE_BYTES,
+    DEFAULT_STREAM_BUFFER_SIZE, DEFAULT_TASK_HISTORY_MAX_ENTRIES, DEFAULT_TASK_SINK_TTL_SECS,
+};
+use funtonic::{tokio, tonic};
+use grpc_service::grpc_protocol::admin_service_server::AdminServiceServer;
 use grpc_service::grpc_protocol::commander_service_server::CommanderServiceServer;
 use grpc_service::grpc_protocol::executor_service_server::ExecutorServiceServer;
-use std::path::PathBuf;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use structopt::StructOpt;
 use thiserror::Error;
+use tonic::codec::CompressionEncoding;
+use tonic::transport::server::TcpIncoming;
 use tonic::transport::Server;
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+/// Connections of crashed or unreachable peers (power loss, NAT drop...) are closed once a ping
+/// is not answered within the timeout: the executor task streams would stay open otherwise
+const HTTP2_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const HTTP2_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accept compressed requests, compress the responses when configured & accepted by the client
+macro_rules! compressed {
+    ($service:expr, $compression:expr) => {{
+        let service = $service.accept_compressed(CompressionEncoding::Gzip);
+        match $compression {
+            Some(encoding) => service.send_compressed(encoding),
+            None => service,
+        }
+    }};
+}
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "Funtonic taskserver")]
@@ -27,6 +56,24 @@ pub struct Opt {
 struct InvalidConfig;
 
 pub async fn taskserver_main(server_config: ServerConfig) -> anyhow::Result<()> {
+    taskserver_main_with_task_ids(server_config, RandomTaskIds).await
+}
+
+/// Tests may want predictable task ids, eg: `SequentialTaskIds`
+pub async fn taskserver_main_with_task_ids(
+    server_config: ServerConfig,
+    task_ids: impl TaskIdGenerator + 'static,
+) -> anyhow::Result<()> {
+    taskserver_main_with_shutdown(server_config, task_ids, shutdown_signal()?).await
+}
+
+/// Launch the taskserver, stopping it once `shutdown_signal` completes: the executor streams are
+/// closed & the executor keys and metas are saved
+pub async fn taskserver_main_with_shutdown<S: Future<Output = ()> + Send + 'static>(
+    server_config: ServerConfig,
+    task_ids: impl TaskIdGenerator + 'static,
+    shutdown_signal: S,
+) -> anyhow::Result<()> {
     info!(
         "Taskserver v{}, core v{},  protocol v{}, query parser v{} starting",
         VERSION,
@@ -36,26 +83,206 @@ pub async fn taskserver_main(server_config: ServerConfig) -> anyhow::Result<()>
     );
 
     info!("{:#?}", server_config);
-    let mut server = Server::builder().tcp_keepalive(Some(Duration::from_secs(25)));
-    if let Some(tls_config) = &server_config.tls {
-        server = server.tls_config(tls_config.get_server_config()?)?;
-    }
 
-    let addr = server_config.bind_address.parse().unwrap();
-    let database_directory = mkdirs(&server_config.data_directory)?;
-    let task_server = TaskServer::new(
+    let addr: SocketAddr = server_config.bind_address.parse().unwrap();
+    let database_directory = mkdirs_private(&server_config.data_directory)?;
+    preflight(&server_config.preflight, Path::new(&database_directory))?;
+    let mut task_server = TaskServer::new(
         &database_directory,
-        &server_config.authorized_keys,
+        &server_config.authorized_public_keys(),
         &server_config.admin_authorized_keys,
-    )?;
+        server_config.retain_signatures,
+        Duration::from_secs(server_config.allowed_clock_skew_secs.unwrap_or(0)),
+    )?
+    .with_max_admin_response_bytes(
+        server_config
+            .max_admin_response_bytes
+            .unwrap_or(DEFAULT_MAX_ADMIN_RESPONSE_BYTES),
+    )
+    .with_task_history_max_entries(
+        server_config
+            .task_history_max_entries
+            .unwrap_or(DEFAULT_TASK_HISTORY_MAX_ENTRIES),
+    )
+    .with_task_sink_ttl(Duration::from_secs(
+        server_config
+            .task_sink_ttl_secs
+            .unwrap_or(DEFAULT_TASK_SINK_TTL_SECS),
+    ))
+    .with_stream_buffer_size(
+        server_config
+            .stream_buffer_size
+            .unwrap_or(DEFAULT_STREAM_BUFFER_SIZE),
+    )
+    .with_peer_identity(peer_identity(&server_config)?)
+    .with_key_scopes(KeyScopes::new(server_config.authorized_key_scopes())?)
+    .with_task_id_generator(task_ids);
+    if server_config.admin_bind_address.is_some() {
+        task_server = task_server.with_separate_admin_listener();
+    }
+    if server_config.allow_takeover {
+        task_server = task_server.with_takeover();
+    }
+    if server_config.auto_approve_executor_keys {
+        task_server = task_server.with_auto_approved_executor_keys();
+    }
+    if let Some(access_log) = &server_config.access_log {
+        task_server = task_server.with_access_log(AccessLog::open(
+            access_log,
+            server_config
+                .access_log_max_bytes
+                .unwrap_or(DEFAULT_ACCESS_LOG_MAX_BYTES),
+        )?);
+        #[cfg(unix)]
+        reopen_access_log_on_hangup(task_server.clone())?;
+    }
 
-    task_server.start_heartbeat();
+    let heartbeat = task_server.start_heartbeat();
+    start_clock_monitor(Duration::from_secs(
+        server_config
+            .preflight
+            .time_jump_threshold_secs
+            .unwrap_or(DEFAULT_TIME_JUMP_THRESHOLD_SECS),
+    ));
+    let periodic_flush = task_server.start_flush();
 
-    server
-        .add_service(ExecutorServiceServer::new(task_server.clone()))
-        .add_service(CommanderServiceServer::new(task_server))
-        .serve(addr)
-        .await?;
+    let admin_listener = match &server_config.admin_bind_address {
+        Some(admin_addr) => {
+            let (incoming, local_addr) = bind(admin_addr.parse()?).await?;
+            info!("Admin listening on {}", local_addr);
+            Some((server_builder(&server_config)?, incoming))
+        }
+        None => None,
+    };
+    let (incoming, local_addr) = bind(addr).await?;
+    // readiness signal for supervisors & tests spawning the taskserver
+    println!("READY on {}", local_addr);
+    info!("Listening on {}", local_addr);
 
+    let (shutdown_sender, shutdown_receiver) = tokio::sync::watch::channel(false);
+    tokio::spawn({
+        let task_server = task_server.clone();
+        async move {
+            shutdown_signal.await;
+            info!("Shutting down");
+            task_server.close_executor_streams();
+            let _ = shutdown_sender.send(true);
+        }
+    });
+    let shutdown = || {
+        let mut shutdown_receiver = shutdown_receiver.clone();
+        async move {
+            let _ = shutdown_receiver.wait_for(|shutdown| *shutdown).await;
+        }
+    };
+
+    let compression = compression_encoding(server_config.compression.as_deref())?;
+    let main = server_builder(&server_config)?
+        .add_service(compressed!(
+            ExecutorServiceServer::new(task_server.clone()),
+            compression
+        ))
+        .add_service(compressed!(
+            CommanderServiceServer::new(task_server.clone()),
+            compression
+        ))
+        .add_optional_service(
+            admin_listener
+                .is_none()
+                .then(|| compressed!(AdminServiceServer::new(task_server.clone()), compression)),
+        )
+        .serve_with_incoming_shutdown(incoming, shutdown());
+    let admin = async {
+        match admin_listener {
+            Some((mut server, incoming)) => {
+                server
+                    .add_service(compressed!(
+                        AdminServiceServer::new(task_server.clone()),
+                        compression
+                    ))
+                    .serve_with_incoming_shutdown(incoming, shutdown())
+                    .await
+            }
+            None => Ok(()),
+        }
+    };
+    tokio::try_join!(main, admin)?;
+
+    heartbeat.abort();
+    periodic_flush.abort();
+    // the executor keys & metas are written behind
+    tokio::task::spawn_blocking(move || task_server.flush()).await??;
+    info!("Taskserver stopped");
     Ok(())
 }
+
+/// Ctrl-C or SIGTERM (`systemctl stop`...), registered right away
+#[cfg(unix)]
+fn shutdown_signal() -> anyhow::Result<impl std::future::Future<Output = ()>> {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    Ok(async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    })
+}
+
+#[cfg(not(unix))]
+fn shutdown_signal() -> anyhow::Result<impl std::future::Future<Output = ()>> {
+    Ok(async {
+        let _ = tokio::signal::ctrl_c().await;
+    })
+}
+
+/// logrotate & co signal the moved log files with SIGHUP
+#[cfg(unix)]
+fn reopen_access_log_on_hangup(task_server: TaskServer) -> anyhow::Result<()> {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reopening the access log");
+            task_server.reopen_access_log();
+        }
+    });
+    Ok(())
+}
+
+fn peer_identity(server_config: &ServerConfig) -> anyhow::Result<PeerIdentity> {
+    let checked =
+        server_config.verify_peer_identity || !server_config.allowed_commander_cns.is_empty();
+    if checked && server_config.tls.is_none() && server_config.trusted_proxy_header.is_none() {
+        anyhow::bail!(
+            "verify_peer_identity & allowed_commander_cns require tls or a trusted_proxy_header \
+             to identify the peers"
+        );
+    }
+    let mut peer_identity = PeerIdentity::new(
+        server_config.trusted_proxy_header.as_deref(),
+        &server_config.trusted_proxy_cidrs,
+    )?
+    .with_allowed_commanders(server_config.allowed_commander_cns.clone());
+    if server_config.verify_peer_identity {
+        peer_identity = peer_identity.with_verified_executors();
+    }
+    Ok(peer_identity)
+}
+
+fn server_builder(server_config: &ServerConfig) -> anyhow::Result<Server> {
+    let mut server = Server::builder()
+        .http2_keepalive_interval(Some(HTTP2_KEEPALIVE_INTERVAL))
+        .http2_keepalive_timeout(Some(HTTP2_KEEPALIVE_TIMEOUT));
+    if let Some(tls_config) = &server_config.tls {
+        tls_config.warn_on_expiry();
+        server = server.tls_config(tls_config.get_server_config()?)?;
+    }
+    Ok(server)
+}
+
+async fn bind(addr: SocketAddr) -> anyhow::Result<(TcpIncoming, SocketAddr)> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    let incoming = TcpIncoming::from_listener(listener, false, Some(Duration::from_secs(25)))
+        .map_err(|e| anyhow::anyhow!(e))?;
+    Ok((incoming, local_addr))
+}
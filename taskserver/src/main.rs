@@ -2,17 +2,29 @@ use funtonic::config;
 use funtonic::tokio;
 use structopt::StructOpt;
 use taskserver::{taskserver_main, Opt};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
 
 const LOG4RS_CONFIG: &'static str = "/etc/funtonic/server-log4rs.yaml";
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    log4rs_gelf::init_file(LOG4RS_CONFIG, None).unwrap_or_else(|e| {
-        eprintln!("Cannot initialize logger from {} - {}", LOG4RS_CONFIG, e);
-        eprintln!("Trying with dev assets!");
-        log4rs_gelf::init_file("taskserver/assets/log4rs.yaml", None)
-            .expect("Cannot open taskserver/assets/log4rs.yaml");
-    });
+    if std::env::var_os("RUST_LOG").is_some() {
+        // spans of the tasks & their durations on close, eg: RUST_LOG=debug
+        tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::from_default_env())
+            .with_span_events(FmtSpan::CLOSE)
+            .init();
+    } else {
+        log4rs_gelf::init_file(LOG4RS_CONFIG, None).unwrap_or_else(|e| {
+            eprintln!("Cannot initialize logger from {} - {}", LOG4RS_CONFIG, e);
+            eprintln!("Trying with dev assets!");
+            log4rs_gelf::init_file("taskserver/assets/log4rs.yaml", None)
+                .expect("Cannot open taskserver/assets/log4rs.yaml");
+        });
+        // the task & client ids are fields of the tracing spans
+        funtonic::log_bridge::init().expect("Cannot forward the tracing events to log4rs");
+    }
     let opt = Opt::from_args();
     let (config, _) = config::parse(&opt.config, "server.yml")?;
     taskserver_main(config).await